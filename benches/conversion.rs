@@ -0,0 +1,128 @@
+//! Benchmarks for the event/state conversion pipeline and serialization
+//!
+//! Run with `cargo bench`. Inputs are synthetic recordings of a million events
+//! mixing typing, held modifiers and long mouse movements, which is roughly
+//! what an hour of gameplay or desktop automation looks like.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use evkey::binary;
+use evkey::event::{EventType, InputEvent, RecordedEvent};
+use evkey::state::{events_to_states, states_to_events};
+use evkey::storage::{self, Macro};
+use std::hint::black_box;
+
+const EVENT_COUNT: usize = 1_000_000;
+
+/// Deterministic xorshift generator so every run benchmarks the same input
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Build a recording of at least `count` events
+fn recording(count: usize) -> Vec<RecordedEvent> {
+    const LETTERS: &[u16] = &[16, 17, 18, 19, 20, 30, 31, 32, 33, 34, 44, 45, 46, 47, 48];
+    const SHIFT: u16 = 42;
+
+    let mut rng = Rng(0x5eed_cafe);
+    let mut events = Vec::with_capacity(count + 64);
+    let mut timestamp_us = 0u64;
+    let push = |events: &mut Vec<RecordedEvent>, timestamp_us: u64, event_type: EventType, code: u16, value: i32| {
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(event_type.0, code, value),
+        });
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+        });
+    };
+
+    while events.len() < count {
+        if rng.below(3) == 0 {
+            // Mouse movement at ~1kHz while a key may be held
+            let held = (rng.below(2) == 0).then(|| LETTERS[rng.below(LETTERS.len() as u64) as usize]);
+            if let Some(key) = held {
+                push(&mut events, timestamp_us, EventType::KEY, key, 1);
+            }
+            for _ in 0..rng.below(200) + 50 {
+                timestamp_us += 1000;
+                let axis = rng.below(2) as u16;
+                push(&mut events, timestamp_us, EventType::RELATIVE, axis, rng.below(7) as i32 - 3);
+            }
+            if let Some(key) = held {
+                push(&mut events, timestamp_us, EventType::KEY, key, 0);
+            }
+        } else {
+            // A burst of typing, sometimes with SHIFT held
+            let shifted = rng.below(4) == 0;
+            if shifted {
+                push(&mut events, timestamp_us, EventType::KEY, SHIFT, 1);
+            }
+            for _ in 0..rng.below(20) + 5 {
+                let key = LETTERS[rng.below(LETTERS.len() as u64) as usize];
+                timestamp_us += rng.below(80_000) + 20_000;
+                push(&mut events, timestamp_us, EventType::KEY, key, 1);
+                timestamp_us += rng.below(60_000) + 30_000;
+                push(&mut events, timestamp_us, EventType::KEY, key, 0);
+            }
+            if shifted {
+                timestamp_us += 10_000;
+                push(&mut events, timestamp_us, EventType::KEY, SHIFT, 0);
+            }
+        }
+        timestamp_us += rng.below(2_000_000);
+    }
+
+    events
+}
+
+fn conversion(c: &mut Criterion) {
+    let events = recording(EVENT_COUNT);
+    let states = events_to_states(&events);
+
+    let mut group = c.benchmark_group("conversion");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(events.len() as u64));
+    group.bench_function("events_to_states", |b| b.iter(|| events_to_states(black_box(&events))));
+    group.bench_function("states_to_events", |b| b.iter(|| states_to_events(black_box(&states))));
+    group.finish();
+}
+
+fn serialization(c: &mut Criterion) {
+    let events = recording(EVENT_COUNT);
+    let macro_ = Macro {
+        states: events_to_states(&events),
+        ..Default::default()
+    };
+    let text = storage::format_macro(&macro_);
+    let path = std::env::temp_dir().join(format!("evkey-bench-{}.{}", std::process::id(), binary::EXTENSION));
+    binary::save(&path, &events).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+
+    let mut group = c.benchmark_group("serialization");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(events.len() as u64));
+    group.bench_function("format_macro", |b| b.iter(|| storage::format_macro(black_box(&macro_))));
+    group.bench_function("parse_macro", |b| b.iter(|| storage::parse_macro(black_box(&text)).unwrap()));
+    group.bench_function("binary_save", |b| b.iter(|| binary::save(&path, black_box(&events)).unwrap()));
+    group.bench_function("binary_parse", |b| {
+        b.iter_batched(|| bytes.clone(), |bytes| binary::parse(&bytes).unwrap(), BatchSize::LargeInput)
+    });
+    group.finish();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, conversion, serialization);
+criterion_main!(benches);
@@ -0,0 +1,21 @@
+//! Binary macro files: decoding must fail cleanly, and whatever decodes must
+//! convert to states and text
+
+#![no_main]
+
+use evkey::state::{events_to_states, states_to_events};
+use evkey::{binary, storage};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(events) = binary::parse(data) else {
+        return;
+    };
+
+    let macro_ = storage::Macro {
+        states: events_to_states(&events),
+        ..Default::default()
+    };
+    states_to_events(&macro_.states);
+    storage::format_macro(&macro_);
+});
@@ -0,0 +1,14 @@
+//! evtest/evbug/evemu dumps: importing must fail cleanly, and whatever
+//! imports must convert to states
+
+#![no_main]
+
+use evkey::evtest;
+use evkey::state::events_to_states;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    if let Ok(events) = evtest::parse(text) {
+        events_to_states(&events);
+    }
+});
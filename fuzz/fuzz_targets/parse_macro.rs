@@ -0,0 +1,19 @@
+//! Text macro files: parsing must fail cleanly, and whatever parses must
+//! survive conversion to events and load again once saved
+
+#![no_main]
+
+use evkey::storage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    let Ok(macro_) = storage::parse_macro(text) else {
+        return;
+    };
+
+    macro_.events();
+    macro_.state_starts_us();
+    macro_.timed_actions();
+
+    storage::parse_macro(&storage::format_macro(&macro_)).expect("saved macro doesn't load");
+});
@@ -0,0 +1,152 @@
+/* EvKey C API. Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#ifndef EVKEY_H
+#define EVKEY_H
+
+
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A loaded or recorded macro
+ */
+typedef struct EvkeyMacro EvkeyMacro;
+
+/**
+ * A virtual input device to play macros on
+ */
+typedef struct EvkeyPlayer EvkeyPlayer;
+
+/**
+ * Recording in progress on a background thread
+ */
+typedef struct EvkeyRecorder EvkeyRecorder;
+
+/**
+ * Playback position passed to progress callbacks
+ */
+typedef struct EvkeyProgress {
+  uint64_t position_us;
+  uint64_t total_us;
+  /**
+   * Current state, or -1 if unknown
+   */
+  int64_t state_index;
+  uint64_t elapsed_us;
+} EvkeyProgress;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message for the last error on this thread, or NULL if there was none
+ *
+ * The string stays valid until the next failing call on the same thread.
+ */
+const char *evkey_last_error(void);
+
+/**
+ * Load a macro file (text or binary)
+ *
+ * # Safety
+ * `path` must be a valid NUL-terminated string.
+ */
+struct EvkeyMacro *evkey_macro_load(const char *path);
+
+/**
+ * Save a macro as text
+ *
+ * # Safety
+ * `macro_` must come from this library and `path` must be a valid
+ * NUL-terminated string.
+ */
+int evkey_macro_save(const struct EvkeyMacro *macro_, const char *path);
+
+/**
+ * Number of states in a macro
+ *
+ * # Safety
+ * `macro_` must come from this library.
+ */
+size_t evkey_macro_state_count(const struct EvkeyMacro *macro_);
+
+/**
+ * Total length of a macro in milliseconds
+ *
+ * # Safety
+ * `macro_` must come from this library.
+ */
+uint64_t evkey_macro_duration_ms(const struct EvkeyMacro *macro_);
+
+/**
+ * Release a macro; NULL is ignored
+ *
+ * # Safety
+ * `macro_` must come from this library and not be used afterwards.
+ */
+void evkey_macro_free(struct EvkeyMacro *macro_);
+
+/**
+ * Start recording from every keyboard and mouse right away
+ *
+ * The hotkeys still work: F1 toggles recording (starting again discards what
+ * was recorded so far) and F2 adds a marker.
+ */
+struct EvkeyRecorder *evkey_record_start(void);
+
+/**
+ * Stop recording and return the macro; the recorder is released
+ *
+ * # Safety
+ * `recorder` must come from `evkey_record_start` and not be used afterwards.
+ */
+struct EvkeyMacro *evkey_record_stop(struct EvkeyRecorder *recorder);
+
+/**
+ * Create a player, picking the best available backend
+ *
+ * # Safety
+ * `device_name` must be a valid NUL-terminated string.
+ */
+struct EvkeyPlayer *evkey_player_new(const char *device_name);
+
+/**
+ * Report progress during `evkey_play` to `callback`, or stop reporting if NULL
+ *
+ * `user_data` is passed to the callback unchanged.
+ *
+ * # Safety
+ * `player` must come from this library; `user_data` must stay valid for as
+ * long as the callback is installed.
+ */
+void evkey_player_set_progress(struct EvkeyPlayer *player,
+                               void (*callback)(const struct EvkeyProgress *progress,
+                                                void *user_data),
+                               void *user_data);
+
+/**
+ * Play a macro with its original timing, blocking until it finishes
+ *
+ * # Safety
+ * `player` and `macro_` must come from this library.
+ */
+int evkey_play(struct EvkeyPlayer *player, const struct EvkeyMacro *macro_);
+
+/**
+ * Release a player and its virtual device; NULL is ignored
+ *
+ * # Safety
+ * `player` must come from this library and not be used afterwards.
+ */
+void evkey_player_free(struct EvkeyPlayer *player);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* EVKEY_H */
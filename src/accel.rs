@@ -0,0 +1,249 @@
+//! Pointer acceleration compensation
+//!
+//! The desktop accelerates relative motion from every pointer device,
+//! including the virtual one EvKey plays back through. Because states merge
+//! recorded deltas into fewer, larger moves, replayed motion runs at different
+//! speeds than the original and picks up different gains, so the cursor ends
+//! up in the wrong place.
+//!
+//! An [`AccelCurve`] holds the gain the desktop applies at each pointer speed,
+//! as measured by `evkey calibrate-accel`. [`compensate_events`] uses it to
+//! scale each move down (or up) so that, once accelerated, the cursor moves by
+//! exactly the recorded delta.
+
+use crate::config;
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use std::fmt;
+use std::path::PathBuf;
+
+/// `REL_X` and `REL_Y`
+const REL_X: u16 = 0;
+const REL_Y: u16 = 1;
+
+/// Time assumed since the previous move for the first one, and the longest
+/// gap still treated as continuous motion (in microseconds)
+const DEFAULT_INTERVAL_US: u64 = 8_000;
+const MAX_INTERVAL_US: u64 = 100_000;
+
+/// Gain applied by the desktop as a function of pointer speed
+///
+/// Points are `(speed, gain)` with speed in device counts per millisecond,
+/// sorted by speed. Gains are interpolated linearly between points and held
+/// flat beyond the first and last one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccelCurve {
+    points: Vec<(f64, f64)>,
+}
+
+impl AccelCurve {
+    /// Build a curve from measured `(speed, gain)` points, in any order
+    pub fn new(mut points: Vec<(f64, f64)>) -> Result<Self, String> {
+        if points.is_empty() {
+            return Err("Acceleration curve has no points".to_string());
+        }
+        if let Some((speed, gain)) = points
+            .iter()
+            .find(|(speed, gain)| !speed.is_finite() || *speed < 0.0 || !gain.is_finite() || *gain <= 0.0)
+        {
+            return Err(format!("Invalid acceleration point: speed {} gain {}", speed, gain));
+        }
+
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if points.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err("Acceleration curve has two points at the same speed".to_string());
+        }
+        Ok(Self { points })
+    }
+
+    /// Parse the `speed gain` lines written by `Display`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut points = Vec::new();
+
+        for (line_num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let number = |field: Option<&str>| field.and_then(|f| f.parse::<f64>().ok());
+            let mut fields = line.split_whitespace();
+            match (number(fields.next()), number(fields.next()), fields.next()) {
+                (Some(speed), Some(gain), None) => points.push((speed, gain)),
+                _ => return Err(format!("Line {}: Expected '<speed> <gain>', got '{}'", line_num + 1, line)),
+            }
+        }
+
+        Self::new(points)
+    }
+
+    /// Gain at `speed` (counts per millisecond)
+    pub fn gain(&self, speed: f64) -> f64 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if speed <= first.0 {
+            return first.1;
+        }
+        if speed >= last.0 {
+            return last.1;
+        }
+
+        let next = self.points.iter().position(|&(s, _)| s >= speed).unwrap_or(self.points.len() - 1);
+        let (s0, g0) = self.points[next - 1];
+        let (s1, g1) = self.points[next];
+        g0 + (g1 - g0) * (speed - s0) / (s1 - s0)
+    }
+
+    /// Input speed that the desktop turns into `output_speed`
+    ///
+    /// Solves `speed * gain(speed) = output_speed` by bisection; the result is
+    /// exact as long as faster input never moves the cursor slower.
+    pub fn input_speed(&self, output_speed: f64) -> f64 {
+        if output_speed <= 0.0 {
+            return 0.0;
+        }
+
+        let min_gain = self.points.iter().map(|&(_, gain)| gain).fold(f64::INFINITY, f64::min);
+        let (mut low, mut high) = (0.0, output_speed / min_gain);
+        for _ in 0..64 {
+            let mid = (low + high) / 2.0;
+            if mid * self.gain(mid) < output_speed {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        (low + high) / 2.0
+    }
+}
+
+impl fmt::Display for AccelCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# EvKey pointer acceleration, measured by `evkey calibrate-accel`")?;
+        writeln!(f, "# speed (counts/ms)  gain")?;
+        for (speed, gain) in &self.points {
+            writeln!(f, "{:.4} {:.4}", speed, gain)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the calibrated curve is kept: `$XDG_CONFIG_HOME/evkey/accel`
+pub fn config_path() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("accel"))
+}
+
+/// Rescale pointer motion so it ends up as recorded after acceleration
+///
+/// Each move's speed is taken from the time since the previous move. Rounding
+/// remainders are carried over, so long movements don't drift.
+pub fn compensate_events(events: &[RecordedEvent], curve: &AccelCurve) -> Vec<RecordedEvent> {
+    let mut compensated = Vec::with_capacity(events.len());
+    let mut last_move_us = None;
+    let mut carry = (0.0, 0.0);
+    let mut i = 0;
+
+    while i < events.len() {
+        if !is_motion(&events[i].event) {
+            compensated.push(events[i].clone());
+            i += 1;
+            continue;
+        }
+
+        // REL_X and REL_Y of one move share a timestamp
+        let timestamp_us = events[i].timestamp_us;
+        let mut delta = (0i32, 0i32);
+        while let Some(event) = events.get(i).filter(|e| e.timestamp_us == timestamp_us && is_motion(&e.event)) {
+            match event.event.code() {
+                REL_X => delta.0 = delta.0.saturating_add(event.event.value()),
+                _ => delta.1 = delta.1.saturating_add(event.event.value()),
+            }
+            i += 1;
+        }
+
+        let interval_us = last_move_us
+            .map(|last| timestamp_us.saturating_sub(last))
+            .filter(|&interval| interval > 0 && interval <= MAX_INTERVAL_US)
+            .unwrap_or(DEFAULT_INTERVAL_US);
+        last_move_us = Some(timestamp_us);
+
+        let distance = f64::from(delta.0).hypot(f64::from(delta.1));
+        let output_speed = distance / (interval_us as f64 / 1000.0);
+        let scale = if distance > 0.0 {
+            curve.input_speed(output_speed) / output_speed
+        } else {
+            1.0
+        };
+
+        let x = f64::from(delta.0) * scale + carry.0;
+        let y = f64::from(delta.1) * scale + carry.1;
+        let (out_x, out_y) = (x.round(), y.round());
+        carry = (x - out_x, y - out_y);
+
+        for (code, value) in [(REL_X, out_x), (REL_Y, out_y)] {
+            if value != 0.0 {
+                compensated.push(RecordedEvent {
+                    timestamp_us,
+                    event: InputEvent::new(EventType::RELATIVE.0, code, value as i32),
+                });
+            }
+        }
+    }
+
+    compensated
+}
+
+fn is_motion(event: &InputEvent) -> bool {
+    event.event_type() == EventType::RELATIVE && matches!(event.code(), REL_X | REL_Y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve() -> AccelCurve {
+        AccelCurve::parse("# comment\n2.0 3.0\n0.5 1.0\n").unwrap()
+    }
+
+    #[test]
+    fn test_curve_interpolation() {
+        let curve = curve();
+        assert_eq!(curve.gain(0.1), 1.0);
+        assert_eq!(curve.gain(1.25), 2.0);
+        assert_eq!(curve.gain(10.0), 3.0);
+
+        assert!((curve.input_speed(30.0) - 10.0).abs() < 1e-9);
+        assert!((curve.input_speed(0.25) - 0.25).abs() < 1e-9);
+        assert_eq!(AccelCurve::parse(&curve.to_string()).unwrap(), curve);
+
+        assert!(AccelCurve::parse("1.0\n").unwrap_err().starts_with("Line 1:"));
+        assert!(AccelCurve::parse("1.0 0\n").is_err());
+        assert!(AccelCurve::parse("# nothing\n").is_err());
+    }
+
+    #[test]
+    fn test_compensate_events() {
+        let curve = curve();
+        let motion = |timestamp_us, code, value| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::RELATIVE.0, code, value),
+        };
+        let events = vec![
+            motion(0, REL_X, 240),
+            motion(0, REL_Y, 0),
+            motion(8_000, REL_X, 1),
+            motion(16_000, REL_X, 1),
+            motion(16_000, 8, 1),
+        ];
+
+        let compensated = compensate_events(&events, &curve);
+        // 30 counts/ms needs 10 counts/ms of input at gain 3
+        assert_eq!(compensated[0].event.value(), 80);
+        // Slow moves pass through at gain 1, wheel events untouched
+        let values: Vec<_> = compensated[1..].iter().map(|e| (e.event.code(), e.event.value())).collect();
+        assert_eq!(values, vec![(REL_X, 1), (REL_X, 1), (8, 1)]);
+
+        // Huge deltas in one report don't overflow
+        let crafted = vec![motion(0, REL_X, i32::MAX), motion(0, REL_X, i32::MAX)];
+        assert!(!compensate_events(&crafted, &curve).is_empty());
+    }
+}
@@ -0,0 +1,563 @@
+//! Macro actions that aren't plain input
+//!
+//! Actions sit between states, like markers, and run during playback at the
+//! point they're placed. A blocking action (such as waiting for the screen)
+//! delays everything after it.
+//!
+//!   wait pixel 640 360 #ff8800
+//!   wait pixel 640 360 #ff8800 tolerance 16 timeout 30s
+//!   wait text "Continue" in 800 600 320 80 timeout 60s
+//!   clipboard "Grüße, world"
+//!   paste "Grüße, world"
+//!   paste
+//!   type "Dear Sir or Madam,\n"
+//!   snippet "Dear $|$,\n\n{{date}}"
+//!   move to 640 360
+//!   led CAPSL on
+//!   switch LID off
+//!
+//! `led` and `switch` lines are what the recorded devices reported (a lock
+//! LED lighting up, a lid closing); they're kept so the text format loses
+//! nothing a recording saw, and playback leaves them alone, since the lock
+//! keys pressed already light the LEDs and a switch can't be flipped.
+
+use crate::screen::{Region, Rgb};
+use crate::snippet::Snippet;
+use crate::storage::parse_duration;
+use std::fmt;
+#[cfg(feature = "devices")]
+use {
+    crate::{clipboard, layout, player::Player, screen, snippet, typing::CharMap},
+    std::io,
+    std::thread,
+    std::time::{Duration, Instant},
+};
+
+/// Default allowed per-channel difference for pixel waits
+const DEFAULT_TOLERANCE: u8 = 8;
+/// Default time to wait for a pixel before giving up (in milliseconds)
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+/// Time between screenshots while waiting
+#[cfg(feature = "devices")]
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Time between characters of a `type` action, so slow applications keep up
+#[cfg(feature = "devices")]
+const TYPE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait until a screen pixel is approximately a color
+#[derive(Debug, Clone, PartialEq)]
+pub struct PixelWait {
+    pub x: i32,
+    pub y: i32,
+    pub color: Rgb,
+    /// Largest per-channel difference still considered a match
+    pub tolerance: u8,
+    pub timeout_ms: u64,
+}
+
+/// Wait until text shows up on screen, read by OCR (needs the `ocr` feature)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextWait {
+    pub text: String,
+    /// Part of the screen to read, or all of it
+    pub region: Option<Region>,
+    pub timeout_ms: u64,
+}
+
+/// CTRL+V, pressed in this order and released in reverse
+#[cfg(feature = "devices")]
+const PASTE_COMBO: &[u16] = &[29, 47];
+/// Held for the characters of a `type` action that need it
+#[cfg(feature = "devices")]
+const SHIFT: u16 = 42;
+/// Moves the caret back to a snippet's cursor
+#[cfg(feature = "devices")]
+const LEFT: u16 = 105;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    WaitPixel(PixelWait),
+    WaitText(TextWait),
+    /// Put text on the clipboard
+    SetClipboard(String),
+    /// Press CTRL+V, after putting the text on the clipboard if there is any
+    Paste(Option<String>),
+    /// Type text key by key, with the keys it takes on the keyboard layout
+    /// in use at playback
+    Type(String),
+    /// Type a snippet's text, then move the caret to its cursor marker
+    Snippet(Snippet),
+    /// Put the pointer at absolute screen coordinates
+    MoveTo { x: i32, y: i32 },
+    /// A keyboard LED (`LED_*` code) turned on or off while recording
+    Led { code: u16, on: bool },
+    /// A switch (`SW_*` code) flipped while recording
+    Switch { code: u16, on: bool },
+}
+
+/// Names of the `LED_*` codes, by code
+const LED_NAMES: &[&str] = &["NUML", "CAPSL", "SCROLLL", "COMPOSE", "KANA"];
+
+/// Names of the `SW_*` codes, by code
+const SWITCH_NAMES: &[&str] = &["LID", "TABLET_MODE", "HEADPHONE_INSERT", "RFKILL_ALL", "MICROPHONE_INSERT", "DOCK"];
+
+impl Action {
+    /// Parse a DSL line, or return `None` if it isn't an action
+    pub fn parse(line: &str) -> Option<Result<Action, String>> {
+        let line = line.trim();
+
+        if let Some(rest) = line.strip_prefix("wait pixel ") {
+            return Some(parse_pixel_wait(rest).map(Action::WaitPixel));
+        }
+        if let Some(rest) = line.strip_prefix("wait text ") {
+            return Some(parse_text_wait(rest).map(Action::WaitText));
+        }
+        if let Some(rest) = line.strip_prefix("clipboard ") {
+            return Some(unquote(rest).map(Action::SetClipboard));
+        }
+        if line == "paste" {
+            return Some(Ok(Action::Paste(None)));
+        }
+        if let Some(rest) = line.strip_prefix("paste ") {
+            return Some(unquote(rest).map(|text| Action::Paste(Some(text))));
+        }
+        if let Some(rest) = line.strip_prefix("type ") {
+            return Some(unquote(rest).map(Action::Type));
+        }
+        if let Some(rest) = line.strip_prefix("snippet ") {
+            return Some(unquote(rest).and_then(|source| Snippet::parse(&source)).map(Action::Snippet));
+        }
+        if let Some(rest) = line.strip_prefix("move to ") {
+            return Some(parse_move_to(rest));
+        }
+        if let Some(rest) = line.strip_prefix("led ") {
+            return Some(parse_indicator("led", LED_NAMES, rest).map(|(code, on)| Action::Led { code, on }));
+        }
+        if let Some(rest) = line.strip_prefix("switch ") {
+            return Some(parse_indicator("switch", SWITCH_NAMES, rest).map(|(code, on)| Action::Switch { code, on }));
+        }
+        None
+    }
+
+    /// Run the action during playback
+    #[cfg(feature = "devices")]
+    pub fn perform(&self, player: &mut Player) -> io::Result<()> {
+        match self {
+            Action::WaitPixel(wait) => wait_for_pixel(wait, player),
+            Action::WaitText(wait) => wait_for_text(wait, player),
+            Action::SetClipboard(text) => clipboard::set(text),
+            Action::Paste(text) => {
+                if let Some(text) = text {
+                    clipboard::set(text)?;
+                }
+                player.tap_combo(PASTE_COMBO)
+            }
+            Action::Type(text) => type_text(text, player),
+            Action::Snippet(snippet) => type_snippet(snippet, player),
+            Action::MoveTo { x, y } => player.move_to(*x, *y),
+            Action::Led { .. } | Action::Switch { .. } => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::WaitPixel(wait) => {
+                write!(f, "wait pixel {} {} {}", wait.x, wait.y, wait.color)?;
+                if wait.tolerance != DEFAULT_TOLERANCE {
+                    write!(f, " tolerance {}", wait.tolerance)?;
+                }
+                write_timeout(f, wait.timeout_ms)
+            }
+            Action::WaitText(wait) => {
+                write!(f, "wait text {}", quote(&wait.text))?;
+                if let Some(region) = wait.region {
+                    write!(f, " in {}", region)?;
+                }
+                write_timeout(f, wait.timeout_ms)
+            }
+            Action::SetClipboard(text) => write!(f, "clipboard {}", quote(text)),
+            Action::Paste(Some(text)) => write!(f, "paste {}", quote(text)),
+            Action::Paste(None) => write!(f, "paste"),
+            Action::Type(text) => write!(f, "type {}", quote(text)),
+            Action::Snippet(snippet) => write!(f, "snippet {}", quote(snippet.source())),
+            Action::MoveTo { x, y } => write!(f, "move to {} {}", x, y),
+            Action::Led { code, on } => write_indicator(f, "led", LED_NAMES, *code, *on),
+            Action::Switch { code, on } => write_indicator(f, "switch", SWITCH_NAMES, *code, *on),
+        }
+    }
+}
+
+/// Write "led CAPSL on", by number for codes without a name
+fn write_indicator(f: &mut fmt::Formatter, kind: &str, names: &[&str], code: u16, on: bool) -> fmt::Result {
+    match names.get(code as usize) {
+        Some(name) => write!(f, "{} {}", kind, name)?,
+        None => write!(f, "{} {}", kind, code)?,
+    }
+    write!(f, " {}", if on { "on" } else { "off" })
+}
+
+/// Write a ` timeout` option, unless it's the default
+fn write_timeout(f: &mut fmt::Formatter, timeout_ms: u64) -> fmt::Result {
+    match timeout_ms {
+        DEFAULT_TIMEOUT_MS => Ok(()),
+        ms if ms % 1000 == 0 => write!(f, " timeout {}s", ms / 1000),
+        ms => write!(f, " timeout {}ms", ms),
+    }
+}
+
+/// Write text as a double-quoted string, escaping quotes, backslashes and newlines
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Read a double-quoted string written by `quote`
+fn unquote(s: &str) -> Result<String, String> {
+    let inner = s
+        .trim()
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| format!("Expected text in double quotes: {}", s))?;
+
+    let mut text = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some(escaped @ ('"' | '\\')) => text.push(escaped),
+            other => return Err(format!("Invalid escape in text: \\{}", other.map(String::from).unwrap_or_default())),
+        }
+    }
+    Ok(text)
+}
+
+/// Parse "X Y #rrggbb [tolerance N] [timeout DURATION]"
+fn parse_pixel_wait(rest: &str) -> Result<PixelWait, String> {
+    let parts: Vec<&str> = rest.split_whitespace().collect();
+    if parts.len() < 3 {
+        return Err(format!("Invalid 'wait pixel' syntax: wait pixel {}", rest));
+    }
+
+    let mut wait = PixelWait {
+        x: parts[0].parse().map_err(|_| format!("Invalid X coordinate: {}", parts[0]))?,
+        y: parts[1].parse().map_err(|_| format!("Invalid Y coordinate: {}", parts[1]))?,
+        color: Rgb::parse(parts[2])?,
+        tolerance: DEFAULT_TOLERANCE,
+        timeout_ms: DEFAULT_TIMEOUT_MS,
+    };
+
+    for option in parts[3..].chunks(2) {
+        match option {
+            ["tolerance", value] => {
+                wait.tolerance = value.parse().map_err(|_| format!("Invalid tolerance: {}", value))?;
+            }
+            ["timeout", value] => wait.timeout_ms = parse_duration(value)?,
+            _ => return Err(format!("Unknown 'wait pixel' option: {}", option.join(" "))),
+        }
+    }
+
+    Ok(wait)
+}
+
+/// Parse "\"TEXT\" [in X Y WIDTH HEIGHT] [timeout DURATION]"
+fn parse_text_wait(rest: &str) -> Result<TextWait, String> {
+    let (quoted, options) = split_quoted(rest)?;
+    let mut wait = TextWait {
+        text: unquote(quoted)?,
+        region: None,
+        timeout_ms: DEFAULT_TIMEOUT_MS,
+    };
+    if wait.text.trim().is_empty() {
+        return Err("'wait text' needs some text to wait for".to_string());
+    }
+
+    let mut options = options.split_whitespace();
+    while let Some(option) = options.next() {
+        match option {
+            "in" => {
+                let values: Vec<&str> = options.by_ref().take(4).collect();
+                let [x, y, width, height] = values[..] else {
+                    return Err("Expected 'in X Y WIDTH HEIGHT'".to_string());
+                };
+                let size = |value: &str| value.parse().ok().filter(|&size| size > 0);
+                wait.region = Some(Region {
+                    x: x.parse().map_err(|_| format!("Invalid X coordinate: {}", x))?,
+                    y: y.parse().map_err(|_| format!("Invalid Y coordinate: {}", y))?,
+                    width: size(width).ok_or_else(|| format!("Invalid width: {}", width))?,
+                    height: size(height).ok_or_else(|| format!("Invalid height: {}", height))?,
+                });
+            }
+            "timeout" => {
+                let value = options.next().ok_or("Expected a duration after 'timeout'")?;
+                wait.timeout_ms = parse_duration(value)?;
+            }
+            _ => return Err(format!("Unknown 'wait text' option: {}", option)),
+        }
+    }
+
+    Ok(wait)
+}
+
+/// Split a leading double-quoted string (quotes included) from what follows
+fn split_quoted(s: &str) -> Result<(&str, &str), String> {
+    let s = s.trim_start();
+    if !s.starts_with('"') {
+        return Err(format!("Expected text in double quotes: {}", s));
+    }
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Ok((&s[..=i], &s[i + 1..])),
+            _ => escaped = false,
+        }
+    }
+    Err(format!("Unterminated text: {}", s))
+}
+
+/// Parse "X Y"
+fn parse_move_to(rest: &str) -> Result<Action, String> {
+    let [x, y] = rest.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(format!("Invalid 'move to' syntax: move to {}", rest));
+    };
+    Ok(Action::MoveTo {
+        x: x.parse().map_err(|_| format!("Invalid X coordinate: {}", x))?,
+        y: y.parse().map_err(|_| format!("Invalid Y coordinate: {}", y))?,
+    })
+}
+
+/// Parse "NAME on|off" (or a code for NAME) after `led` or `switch`
+fn parse_indicator(kind: &str, names: &[&str], rest: &str) -> Result<(u16, bool), String> {
+    let [name, state] = rest.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(format!("Invalid '{}' syntax: {} {}", kind, kind, rest));
+    };
+    let code = match names.iter().position(|known| known.eq_ignore_ascii_case(name)) {
+        Some(code) => code as u16,
+        None => name.parse().map_err(|_| format!("Unknown {} '{}', use {} or a number", kind, name, names.join("/")))?,
+    };
+    let on = match state {
+        "on" => true,
+        "off" => false,
+        _ => return Err(format!("Expected on or off after '{} {}', got '{}'", kind, name, state)),
+    };
+    Ok((code, on))
+}
+
+/// Poll the screen until the pixel matches, or fail once the timeout passes
+/// (or playback is stopped)
+#[cfg(feature = "devices")]
+fn wait_for_pixel(wait: &PixelWait, player: &mut Player) -> io::Result<()> {
+    let deadline = Instant::now() + Duration::from_millis(wait.timeout_ms);
+
+    loop {
+        let color = screen::pixel_at(wait.x, wait.y)?;
+        if color.distance(wait.color) <= wait.tolerance {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Pixel at {},{} is {}, still not {} after {}ms",
+                    wait.x, wait.y, color, wait.color, wait.timeout_ms
+                ),
+            ));
+        }
+        player.check_stop()?;
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Poll the screen with OCR until the text shows up, or fail once the
+/// timeout passes (or playback is stopped)
+#[cfg(feature = "ocr")]
+fn wait_for_text(wait: &TextWait, player: &mut Player) -> io::Result<()> {
+    let deadline = Instant::now() + Duration::from_millis(wait.timeout_ms);
+
+    loop {
+        let text = screen::read_text(wait.region)?;
+        if text_appears(&text, &wait.text) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("\"{}\" didn't appear on screen within {}ms", wait.text, wait.timeout_ms),
+            ));
+        }
+        player.check_stop()?;
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(all(feature = "devices", not(feature = "ocr")))]
+fn wait_for_text(_wait: &TextWait, _player: &mut Player) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "'wait text' needs EvKey built with the `ocr` feature",
+    ))
+}
+
+/// Type `text` through the backend if it can type any character, or else
+/// with the current keyboard layout's keys (dead keys and compose sequences
+/// for accented letters it has no key for), failing before typing anything
+/// if it has a character the layout can't type
+#[cfg(feature = "devices")]
+fn type_text(text: &str, player: &mut Player) -> io::Result<()> {
+    match player.type_text(text) {
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+        typed => return typed,
+    }
+
+    let chars = layout::current()
+        .ok()
+        .and_then(|current| CharMap::for_recording(Some(&current)).ok())
+        .unwrap_or_else(CharMap::us);
+    let keys = text
+        .chars()
+        .map(|c| {
+            chars.keystrokes_for(c).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Can't type {:?} with this keyboard layout", c))
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    for (code, shift) in keys.into_iter().flatten() {
+        if shift {
+            player.tap_combo(&[SHIFT, code])?;
+        } else {
+            player.tap_combo(&[code])?;
+        }
+        thread::sleep(TYPE_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Type a snippet with its variables filled in, then press the left arrow
+/// until the caret is back at its cursor marker
+#[cfg(feature = "devices")]
+fn type_snippet(snippet: &Snippet, player: &mut Player) -> io::Result<()> {
+    let rendered = snippet.render(snippet::resolve)?;
+    type_text(&rendered.text, player)?;
+    for _ in 0..rendered.cursor_back {
+        player.tap_combo(&[LEFT])?;
+        thread::sleep(TYPE_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Whether `wanted` is in the OCR output, ignoring case and line breaks
+#[cfg(any(feature = "ocr", test))]
+fn text_appears(recognized: &str, wanted: &str) -> bool {
+    let normalize = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    normalize(recognized).contains(&normalize(wanted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pixel_wait() {
+        let action = Action::parse("wait pixel 640 360 #ff8800 timeout 30s").unwrap().unwrap();
+        assert_eq!(
+            action,
+            Action::WaitPixel(PixelWait {
+                x: 640,
+                y: 360,
+                color: Rgb(255, 136, 0),
+                tolerance: DEFAULT_TOLERANCE,
+                timeout_ms: 30_000,
+            })
+        );
+        assert_eq!(action.to_string(), "wait pixel 640 360 #ff8800 timeout 30s");
+
+        assert!(Action::parse("wait 100ms").is_none());
+        assert!(Action::parse("wait pixel 1 2 #000000 tolerance").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_text_wait() {
+        let action = Action::parse(r#"wait text "Save \"draft\"" in 800 600 320 80 timeout 60s"#).unwrap().unwrap();
+        assert_eq!(
+            action,
+            Action::WaitText(TextWait {
+                text: "Save \"draft\"".to_string(),
+                region: Some(Region { x: 800, y: 600, width: 320, height: 80 }),
+                timeout_ms: 60_000,
+            })
+        );
+        assert_eq!(action.to_string(), r#"wait text "Save \"draft\"" in 800 600 320 80 timeout 60s"#);
+        assert_eq!(Action::parse(r#"wait text "OK""#).unwrap().unwrap().to_string(), r#"wait text "OK""#);
+
+        assert!(Action::parse(r#"wait text "OK" in 1 2 3"#).unwrap().is_err());
+        assert!(Action::parse(r#"wait text "OK" in 1 2 0 4"#).unwrap().is_err());
+        assert!(Action::parse(r#"wait text "OK"#).unwrap().is_err());
+        assert!(Action::parse(r#"wait text """#).unwrap().is_err());
+
+        assert!(text_appears("Click\nContinue  to\nproceed", "continue to"));
+        assert!(!text_appears("Cancel", "Continue"));
+    }
+
+    #[test]
+    fn test_clipboard_text_roundtrip() {
+        let text = "Grüße \"quoted\" C:\\path\nnext line";
+        let action = Action::Paste(Some(text.to_string()));
+        let line = action.to_string();
+
+        assert_eq!(line, r#"paste "Grüße \"quoted\" C:\\path\nnext line""#);
+        assert_eq!(Action::parse(&line), Some(Ok(action)));
+        assert_eq!(Action::parse("paste"), Some(Ok(Action::Paste(None))));
+        assert!(Action::parse("clipboard unquoted").unwrap().is_err());
+
+        let typed = Action::Type("Hi,\tthere\n".to_string());
+        assert_eq!(typed.to_string(), r#"type "Hi,\tthere\n""#);
+        assert_eq!(Action::parse(&typed.to_string()), Some(Ok(typed)));
+
+        let snippet = Action::parse(r#"snippet "Hi $|$,\n{{date}}""#).unwrap().unwrap();
+        assert_eq!(snippet.to_string(), r#"snippet "Hi $|$,\n{{date}}""#);
+        assert!(Action::parse(r#"snippet "{{nope}}""#).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_move_to() {
+        let action = Action::parse("move to 640 360").unwrap().unwrap();
+        assert_eq!(action, Action::MoveTo { x: 640, y: 360 });
+        assert_eq!(action.to_string(), "move to 640 360");
+
+        assert!(Action::parse("move 10 -5").is_none());
+        assert!(Action::parse("move to 640").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_parse_led_and_switch() {
+        let led = Action::parse("led capsl on").unwrap().unwrap();
+        assert_eq!(led, Action::Led { code: 1, on: true });
+        assert_eq!(led.to_string(), "led CAPSL on");
+        let switch = Action::Switch { code: 12, on: false };
+        assert_eq!(switch.to_string(), "switch 12 off");
+        assert_eq!(Action::parse("switch 12 off"), Some(Ok(switch)));
+
+        assert!(Action::parse("led CAPSL").unwrap().is_err());
+        assert!(Action::parse("led BLINK on").unwrap().unwrap_err().contains("Unknown led 'BLINK'"));
+        assert!(Action::parse("switch LID half").unwrap().is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Audit log of injected input
+//!
+//! On shared or production machines it can matter what EvKey typed and when.
+//! Once `evkey audit on` has created the log (`$XDG_STATE_HOME/evkey/audit.log`)
+//! every backend EvKey opens appends what goes through it: one line per
+//! stretch of input, with the local time, the device and what was played on
+//! it, and the input abbreviated by key name (`CTRL down, tap C, CTRL up,
+//! move +120,-40`). Nothing but `evkey audit clear` takes lines out again.
+//!
+//! If the log can't be written to, injecting fails rather than going
+//! unrecorded.
+
+use crate::backend::Backend;
+use crate::capabilities::Capabilities;
+use crate::event::{EventType, InputEvent};
+use crate::input_key::InputKey;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Longest stretch of input one line covers
+const LINE_SPAN: Duration = Duration::from_secs(1);
+
+const REL_X: u16 = 0;
+const REL_Y: u16 = 1;
+const REL_HWHEEL: u16 = 6;
+const REL_WHEEL: u16 = 8;
+
+/// Where the log is kept: `$XDG_STATE_HOME/evkey/audit.log`, or
+/// `~/.local/state/evkey/audit.log`
+pub fn path() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+    Some(state.join("evkey").join("audit.log"))
+}
+
+/// One thing sent, in a log line
+#[derive(Debug, Clone, PartialEq)]
+enum Item {
+    Press(u16),
+    Release(u16),
+    /// A press followed right away by its release
+    Tap(u16),
+    Move(i32, i32),
+    Scroll(i32),
+    HScroll(i32),
+    /// Last value an absolute axis was set to
+    Axis(u16, i32),
+    MoveTo(i32, i32),
+    Type(String),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Item::Press(code) => write!(f, "{} down", InputKey::from(*code)),
+            Item::Release(code) => write!(f, "{} up", InputKey::from(*code)),
+            Item::Tap(code) => write!(f, "tap {}", InputKey::from(*code)),
+            Item::Move(dx, dy) => write!(f, "move {:+},{:+}", dx, dy),
+            Item::Scroll(amount) => write!(f, "scroll {:+}", amount),
+            Item::HScroll(amount) => write!(f, "hscroll {:+}", amount),
+            Item::Axis(code, value) => write!(f, "axis {}={}", code, value),
+            Item::MoveTo(x, y) => write!(f, "move to {},{}", x, y),
+            Item::Type(text) => write!(f, "type {:?}", text),
+        }
+    }
+}
+
+/// Input sent in one stretch, summed up
+///
+/// Motion, wheel turns and axis updates in a row are merged into one item.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Entry {
+    items: Vec<Item>,
+}
+
+impl Entry {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn add(&mut self, event: &InputEvent) {
+        let (code, value) = (event.code(), event.value());
+        let last = self.items.last_mut();
+        match event.event_type() {
+            EventType::KEY => match (value, last) {
+                (1, _) => self.items.push(Item::Press(code)),
+                (0, Some(last)) if *last == Item::Press(code) => *last = Item::Tap(code),
+                (0, _) => self.items.push(Item::Release(code)),
+                // Autorepeat
+                _ => {}
+            },
+            EventType::RELATIVE => match (code, last) {
+                (REL_X, Some(Item::Move(dx, _))) => *dx += value,
+                (REL_Y, Some(Item::Move(_, dy))) => *dy += value,
+                (REL_X, _) => self.items.push(Item::Move(value, 0)),
+                (REL_Y, _) => self.items.push(Item::Move(0, value)),
+                (REL_WHEEL, Some(Item::Scroll(amount))) | (REL_HWHEEL, Some(Item::HScroll(amount))) => *amount += value,
+                (REL_WHEEL, _) => self.items.push(Item::Scroll(value)),
+                (REL_HWHEEL, _) => self.items.push(Item::HScroll(value)),
+                // Hi-res wheel steps come with the notches above
+                _ => {}
+            },
+            EventType::ABSOLUTE => match last {
+                Some(Item::Axis(axis, latest)) if *axis == code => *latest = value,
+                _ => self.items.push(Item::Axis(code, value)),
+            },
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, item: Item) {
+        self.items.push(item);
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+}
+
+/// A backend that logs everything sent through it before passing it on
+pub struct AuditBackend {
+    inner: Box<dyn Backend>,
+    log: File,
+    device: String,
+    /// What's being played, once the player says
+    source: Option<String>,
+    entry: Entry,
+    /// When the entry's first item was sent, and the local time it was sent at
+    started: Option<(Instant, String)>,
+}
+
+impl AuditBackend {
+    /// Log what goes through `backend` (the device `device`) if auditing is
+    /// on; otherwise hand it back as it is
+    pub fn wrap(backend: Box<dyn Backend>, device: &str) -> io::Result<Box<dyn Backend>> {
+        let Some(path) = path().filter(|path| path.exists()) else {
+            return Ok(backend);
+        };
+        let log = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't open the audit log {}: {}", path.display(), e)))?;
+        Ok(Box::new(Self {
+            inner: backend,
+            log,
+            device: device.to_string(),
+            source: None,
+            entry: Entry::default(),
+            started: None,
+        }))
+    }
+
+    /// Note input that just went out, writing the entry so far if it's been
+    /// going on for a while
+    fn note(&mut self, add: impl FnOnce(&mut Entry)) -> io::Result<()> {
+        if self.started.as_ref().is_some_and(|(started, _)| started.elapsed() >= LINE_SPAN) {
+            self.flush()?;
+        }
+        if self.started.is_none() {
+            self.started = Some((Instant::now(), local_time()));
+        }
+        add(&mut self.entry);
+        Ok(())
+    }
+
+    /// Write the entry so far as a line
+    fn flush(&mut self) -> io::Result<()> {
+        let Some((_, time)) = self.started.take() else {
+            return Ok(());
+        };
+        let entry = std::mem::take(&mut self.entry);
+        if entry.is_empty() {
+            return Ok(());
+        }
+        let line = match &self.source {
+            Some(source) => format!("{} [{}] {}: {}\n", time, self.device, source, entry),
+            None => format!("{} [{}]: {}\n", time, self.device, entry),
+        };
+        self.log.write_all(line.as_bytes())
+    }
+}
+
+impl Backend for AuditBackend {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        self.inner.emit(events)?;
+        self.note(|entry| events.iter().for_each(|event| entry.add(event)))
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> io::Result<()> {
+        self.inner.move_to(x, y)?;
+        self.note(|entry| entry.push(Item::MoveTo(x, y)))
+    }
+
+    fn type_text(&mut self, text: &str) -> io::Result<()> {
+        self.inner.type_text(text)?;
+        self.note(|entry| entry.push(Item::Type(text.to_string())))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn set_source(&mut self, source: &str) {
+        if let Err(e) = self.flush() {
+            warn!("Can't write to the audit log: {}", e);
+        }
+        self.source = Some(source.to_string());
+        self.inner.set_source(source);
+    }
+}
+
+impl Drop for AuditBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Can't write to the audit log: {}", e);
+        }
+    }
+}
+
+fn local_time() -> String {
+    crate::snippet::format_local_time("%Y-%m-%d %H:%M:%S").unwrap_or_default()
+}
+
+/// Turn auditing on by creating the log, readable only by its owner
+pub fn enable() -> io::Result<PathBuf> {
+    let path = path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Can't find the state directory (set HOME or XDG_STATE_HOME)"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new().append(true).create(true).mode(0o600).open(&path)?;
+    Ok(path)
+}
+
+/// Empty the log, leaving auditing on
+pub fn clear() -> io::Result<()> {
+    if let Some(path) = path().filter(|path| path.exists()) {
+        File::create(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: EventType, code: u16, value: i32) -> InputEvent {
+        InputEvent::new(event_type.0, code, value)
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut entry = Entry::default();
+        for e in [
+            event(EventType::KEY, 29, 1),
+            event(EventType::KEY, 46, 1),
+            event(EventType::KEY, 46, 0),
+            event(EventType::KEY, 29, 0),
+            event(EventType::RELATIVE, REL_X, 100),
+            event(EventType::RELATIVE, REL_Y, -40),
+            event(EventType::SYNCHRONIZATION, 0, 0),
+            event(EventType::RELATIVE, REL_X, 20),
+            event(EventType::RELATIVE, REL_WHEEL, -1),
+            event(EventType::RELATIVE, REL_WHEEL, -1),
+        ] {
+            entry.add(&e);
+        }
+        entry.push(Item::Type("hi".to_string()));
+        assert_eq!(
+            entry.to_string(),
+            "CTRL down, tap C, CTRL up, move +120,-40, scroll -2, type \"hi\""
+        );
+    }
+}
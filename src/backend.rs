@@ -0,0 +1,504 @@
+//! Injection backends for playback
+//!
+//! The player hands evdev events to a backend, which delivers them to the
+//! system. uinput works everywhere the user can open `/dev/uinput`; the
+//! Wayland backend (built with `--features wayland`) injects through the
+//! compositor instead, for sandboxed or locked-down sessions, and the X11
+//! backend (`--features x11`) fakes input through XTest.
+//!
+//! Text actions go through [`Backend::type_text`] where a backend can type
+//! any character itself (the Wayland one can), and are pressed as keys of the
+//! current layout otherwise.
+//!
+//! [`open_for`] opens a backend for a macro's [`Capabilities`]: uinput then
+//! creates devices with only those, and a backend that lacks any of them is
+//! refused before playback starts. uinput can also put keyboard and pointer
+//! on separate devices ([`DeviceLayout::Split`]), which some compositors and
+//! games handle better.
+
+use crate::audit::AuditBackend;
+use crate::capabilities::Capabilities;
+use crate::event::{EventType, InputEvent};
+use crate::input_key::InputKey;
+use crate::keymap;
+use crate::screen::{self, ScreenSize};
+use evdev::{
+    uinput::VirtualDevice, AbsInfo, AbsoluteAxisCode, AttributeSet, KeyCode, RelativeAxisCode, UinputAbsSetup,
+};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Something that can deliver input events to the system
+pub trait Backend {
+    /// Emit a batch of events, followed by a synchronization report
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()>;
+
+    /// Put the pointer at screen coordinates `x`, `y`
+    fn move_to(&mut self, _x: i32, _y: i32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This backend can't place the pointer at absolute coordinates",
+        ))
+    }
+
+    /// Type `text` as characters rather than as keys of the current layout,
+    /// so any character can be typed
+    fn type_text(&mut self, _text: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "This backend can't type text directly"))
+    }
+
+    /// Kinds of input the backend can deliver
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            keyboard: true,
+            pointer: true,
+            wheel: true,
+            ..Default::default()
+        }
+    }
+
+    /// Say what's played from here on, e.g. a macro file, for the audit log
+    fn set_source(&mut self, _source: &str) {}
+}
+
+/// A backend players on several threads take turns on, e.g. a virtual
+/// device kept open across playbacks
+///
+/// Every call locks it, so one player's batch of events is never split by
+/// another's.
+#[derive(Clone)]
+pub struct SharedBackend(Arc<Mutex<Box<dyn Backend + Send>>>);
+
+impl SharedBackend {
+    pub fn new(backend: Box<dyn Backend + Send>) -> Self {
+        Self(Arc::new(Mutex::new(backend)))
+    }
+}
+
+impl Backend for SharedBackend {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        self.0.lock().unwrap().emit(events)
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> io::Result<()> {
+        self.0.lock().unwrap().move_to(x, y)
+    }
+
+    fn type_text(&mut self, text: &str) -> io::Result<()> {
+        self.0.lock().unwrap().type_text(text)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.0.lock().unwrap().capabilities()
+    }
+
+    fn set_source(&mut self, source: &str) {
+        self.0.lock().unwrap().set_source(source)
+    }
+}
+
+/// Which backend to inject through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// uinput if available, otherwise the best display-server backend
+    Auto,
+    Uinput,
+    Wayland,
+    X11,
+    Portal,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(BackendKind::Auto),
+            "uinput" => Ok(BackendKind::Uinput),
+            "wayland" => Ok(BackendKind::Wayland),
+            "x11" => Ok(BackendKind::X11),
+            "portal" => Ok(BackendKind::Portal),
+            _ => Err(format!("Unknown backend '{}', use auto/uinput/wayland/x11/portal", s)),
+        }
+    }
+}
+
+/// How the uinput backend lays out its virtual devices
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceLayout {
+    /// One device for keyboard and pointer
+    #[default]
+    Combined,
+    /// A keyboard and a mouse of their own
+    Split,
+}
+
+/// Open a backend of the given kind
+pub fn open(kind: BackendKind, device_name: &str) -> io::Result<Box<dyn Backend>> {
+    open_for(kind, device_name, None, DeviceLayout::Combined)
+}
+
+/// Open a backend of the given kind for input that `needs` these
+/// capabilities, if they're known, failing if it can't deliver all of them
+///
+/// With auditing on, what goes through it is logged (see [`crate::audit`]).
+pub fn open_for(
+    kind: BackendKind,
+    device_name: &str,
+    needs: Option<&Capabilities>,
+    layout: DeviceLayout,
+) -> io::Result<Box<dyn Backend>> {
+    let Some(needs) = needs else {
+        let backend = connect(kind, device_name, &UinputBackend::DEFAULT_CAPABILITIES, layout)?;
+        return AuditBackend::wrap(backend, device_name);
+    };
+    let backend = connect(kind, device_name, needs, layout)?;
+    let missing = needs.missing(&backend.capabilities());
+    if !missing.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("This backend can't play {} input (uinput can)", missing),
+        ));
+    }
+    AuditBackend::wrap(backend, device_name)
+}
+
+fn connect(kind: BackendKind, device_name: &str, needs: &Capabilities, layout: DeviceLayout) -> io::Result<Box<dyn Backend>> {
+    match kind {
+        BackendKind::Uinput => Ok(Box::new(UinputBackend::with_capabilities(device_name, needs, layout)?)),
+        BackendKind::Wayland => open_wayland(),
+        BackendKind::X11 => open_x11(),
+        BackendKind::Portal => open_portal(),
+        BackendKind::Auto => {
+            if uinput_available() {
+                connect(BackendKind::Uinput, device_name, needs, layout)
+            } else if cfg!(feature = "portal") && Path::new("/.flatpak-info").exists() {
+                // Flatpak apps get no other way in than the portal
+                connect(BackendKind::Portal, device_name, needs, layout)
+            } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+                connect(BackendKind::Wayland, device_name, needs, layout)
+            } else if std::env::var_os("DISPLAY").is_some() {
+                connect(BackendKind::X11, device_name, needs, layout)
+            } else {
+                connect(BackendKind::Uinput, device_name, needs, layout)
+            }
+        }
+    }
+}
+
+/// Check whether we can write to /dev/uinput
+fn uinput_available() -> bool {
+    OpenOptions::new().write(true).open("/dev/uinput").is_ok()
+}
+
+#[cfg(feature = "wayland")]
+fn open_wayland() -> io::Result<Box<dyn Backend>> {
+    Ok(Box::new(crate::wayland::WaylandBackend::connect()?))
+}
+
+#[cfg(not(feature = "wayland"))]
+fn open_wayland() -> io::Result<Box<dyn Backend>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "This EvKey was built without Wayland support (rebuild with --features wayland)",
+    ))
+}
+
+#[cfg(feature = "x11")]
+fn open_x11() -> io::Result<Box<dyn Backend>> {
+    Ok(Box::new(crate::x11::X11Backend::connect()?))
+}
+
+#[cfg(not(feature = "x11"))]
+fn open_x11() -> io::Result<Box<dyn Backend>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "This EvKey was built without X11 support (rebuild with --features x11)",
+    ))
+}
+
+#[cfg(feature = "portal")]
+fn open_portal() -> io::Result<Box<dyn Backend>> {
+    Ok(Box::new(crate::portal::PortalBackend::connect()?))
+}
+
+#[cfg(not(feature = "portal"))]
+fn open_portal() -> io::Result<Box<dyn Backend>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "This EvKey was built without portal support (rebuild with --features portal)",
+    ))
+}
+
+/// Range of the absolute pointer's axes, mapped onto the whole screen
+const ABS_RANGE: i32 = 65535;
+
+/// Range of a gamepad's sticks and triggers
+const GAMEPAD_AXIS_RANGE: (i32, i32) = (-32768, 32767);
+
+/// Virtual keyboard+mouse created through uinput
+pub struct UinputBackend {
+    device: VirtualDevice,
+    name: String,
+    /// Mouse of its own, with [`DeviceLayout::Split`]
+    pointer: Option<VirtualDevice>,
+    /// Absolute pointer and the screen size it maps to, created on first use
+    absolute: Option<(VirtualDevice, ScreenSize)>,
+    /// Gamepad, if the input needs one
+    gamepad: Option<VirtualDevice>,
+    /// What the devices were created with
+    capabilities: Capabilities,
+}
+
+impl UinputBackend {
+    /// What [`UinputBackend::new`] creates: everything but a gamepad
+    pub const DEFAULT_CAPABILITIES: Capabilities = Capabilities {
+        keyboard: true,
+        pointer: true,
+        wheel: true,
+        hires_wheel: true,
+        absolute: true,
+        gamepad: false,
+    };
+
+    /// Create a virtual device that can emit every key and basic mouse axes
+    pub fn new(device_name: &str) -> io::Result<Self> {
+        Self::with_capabilities(device_name, &Self::DEFAULT_CAPABILITIES, DeviceLayout::Combined)
+    }
+
+    /// Create virtual devices with just the keys and axes `needs` takes: a
+    /// keyboard+mouse, or a keyboard and a mouse with [`DeviceLayout::Split`],
+    /// and a separate gamepad if it takes one
+    ///
+    /// The absolute pointer is left to [`Backend::move_to`], which creates
+    /// it when it's first used.
+    pub fn with_capabilities(device_name: &str, needs: &Capabilities, layout: DeviceLayout) -> io::Result<Self> {
+        let pointing = needs.pointer || needs.wheel || needs.hires_wheel;
+        // Something has to take the keys of actions like `paste`
+        let keyboard = needs.keyboard || !pointing;
+
+        let (device, pointer) = if layout == DeviceLayout::Split && keyboard && pointing {
+            let pointer = Self::input_device(&format!("{} (pointer)", device_name), needs, false)?;
+            (Self::input_device(device_name, &Capabilities::default(), true)?, Some(pointer))
+        } else {
+            (Self::input_device(device_name, needs, keyboard)?, None)
+        };
+        let gamepad = needs.gamepad.then(|| Self::gamepad_device(device_name)).transpose()?;
+
+        Ok(Self {
+            device,
+            name: device_name.to_string(),
+            pointer,
+            absolute: None,
+            gamepad,
+            capabilities: Capabilities {
+                keyboard,
+                absolute: true,
+                ..*needs
+            },
+        })
+    }
+
+    /// Create a device with the keyboard's keys if `keyboard`, and the mouse
+    /// buttons and axes `needs` takes
+    fn input_device(name: &str, needs: &Capabilities, keyboard: bool) -> io::Result<VirtualDevice> {
+        let mut keys = AttributeSet::<KeyCode>::new();
+        for key_code in 0..=keymap::KEY_MAX {
+            let wanted = match InputKey::from(key_code) {
+                InputKey::MouseButton(_) => needs.pointer,
+                InputKey::GamepadButton(_) => false,
+                InputKey::Keyboard(_) | InputKey::Unknown(_) => keyboard,
+            };
+            if wanted {
+                keys.insert(KeyCode(key_code));
+            }
+        }
+
+        // Setup mouse relative axes
+        let mut relative_axes = AttributeSet::<RelativeAxisCode>::new();
+        if needs.pointer {
+            relative_axes.insert(RelativeAxisCode::REL_X);
+            relative_axes.insert(RelativeAxisCode::REL_Y);
+        }
+        if needs.wheel || needs.hires_wheel {
+            relative_axes.insert(RelativeAxisCode::REL_WHEEL);
+            relative_axes.insert(RelativeAxisCode::REL_HWHEEL);
+        }
+        if needs.hires_wheel {
+            relative_axes.insert(RelativeAxisCode::REL_WHEEL_HI_RES);
+            relative_axes.insert(RelativeAxisCode::REL_HWHEEL_HI_RES);
+        }
+
+        let mut builder = VirtualDevice::builder()?.name(name).with_keys(&keys)?;
+        if relative_axes.iter().next().is_some() {
+            builder = builder.with_relative_axes(&relative_axes)?;
+        }
+        builder.build()
+    }
+
+    /// Create a gamepad with the usual buttons, sticks, triggers and d-pad
+    ///
+    /// It's a device of its own for the same reason as the absolute pointer,
+    /// and because games look for gamepads that are nothing else.
+    fn gamepad_device(name: &str) -> io::Result<VirtualDevice> {
+        let mut buttons = AttributeSet::<KeyCode>::new();
+        for code in (0..=keymap::KEY_MAX).filter(|&code| InputKey::from(code).is_gamepad_button()) {
+            buttons.insert(KeyCode(code));
+        }
+
+        let (min, max) = GAMEPAD_AXIS_RANGE;
+        let stick = AbsInfo::new(0, min, max, 16, 128, 0);
+        let hat = AbsInfo::new(0, -1, 1, 0, 0, 0);
+        let name = format!("{} (gamepad)", name);
+        let mut builder = VirtualDevice::builder()?.name(&name).with_keys(&buttons)?;
+        for axis in [
+            AbsoluteAxisCode::ABS_X,
+            AbsoluteAxisCode::ABS_Y,
+            AbsoluteAxisCode::ABS_Z,
+            AbsoluteAxisCode::ABS_RX,
+            AbsoluteAxisCode::ABS_RY,
+            AbsoluteAxisCode::ABS_RZ,
+        ] {
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(axis, stick))?;
+        }
+        for axis in [AbsoluteAxisCode::ABS_HAT0X, AbsoluteAxisCode::ABS_HAT0Y] {
+            builder = builder.with_absolute_axis(&UinputAbsSetup::new(axis, hat))?;
+        }
+        builder.build()
+    }
+
+    /// Create a tablet-like pointer whose axes span the screen
+    ///
+    /// It's a separate device because the desktop treats a device with both
+    /// relative and absolute axes as one or the other, not both.
+    fn absolute_device(name: &str) -> io::Result<VirtualDevice> {
+        let mut buttons = AttributeSet::<KeyCode>::new();
+        buttons.insert(KeyCode::BTN_LEFT);
+        buttons.insert(KeyCode::BTN_RIGHT);
+        buttons.insert(KeyCode::BTN_MIDDLE);
+
+        let axis = AbsInfo::new(0, 0, ABS_RANGE, 0, 0, 0);
+        VirtualDevice::builder()?
+            .name(&format!("{} (absolute)", name))
+            .with_keys(&buttons)?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_X, axis))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_Y, axis))?
+            .build()
+    }
+}
+
+/// Scale a screen coordinate onto an absolute axis
+fn to_abs_axis(value: i32, screen_extent: u32) -> i32 {
+    let last = i64::from(screen_extent.max(2) - 1);
+    (i64::from(value).clamp(0, last) * i64::from(ABS_RANGE) / last) as i32
+}
+
+/// Whether `event` goes to the gamepad rather than the keyboard and mouse
+fn is_gamepad_event(event: &InputEvent) -> bool {
+    match event.event_type() {
+        EventType::KEY => InputKey::from(event.code()).is_gamepad_button(),
+        EventType::ABSOLUTE => true,
+        _ => false,
+    }
+}
+
+/// Whether `event` goes to the mouse when it's a device of its own
+fn is_pointer_event(event: &InputEvent) -> bool {
+    match event.event_type() {
+        EventType::KEY => InputKey::from(event.code()).is_mouse_button(),
+        EventType::RELATIVE => true,
+        _ => false,
+    }
+}
+
+impl Backend for UinputBackend {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        if self.pointer.is_none() && self.gamepad.is_none() {
+            let events: Vec<evdev::InputEvent> = events.iter().map(|&event| event.into()).collect();
+            return self.device.emit(&events);
+        }
+
+        // Each device gets its share, and a sync report of its own from emit
+        let mut batches: [Vec<evdev::InputEvent>; 3] = Default::default();
+        for event in events {
+            let batch = if self.gamepad.is_some() && is_gamepad_event(event) {
+                2
+            } else if self.pointer.is_some() && is_pointer_event(event) {
+                1
+            } else if event.event_type() == EventType::SYNCHRONIZATION {
+                continue;
+            } else {
+                0
+            };
+            batches[batch].push((*event).into());
+        }
+        let devices = [Some(&mut self.device), self.pointer.as_mut(), self.gamepad.as_mut()];
+        for (batch, device) in batches.iter().zip(devices) {
+            if let Some(device) = device
+                && !batch.is_empty()
+            {
+                device.emit(batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> io::Result<()> {
+        let (device, size) = match &mut self.absolute {
+            Some(absolute) => absolute,
+            None => {
+                let size = screen::screen_size()?;
+                let device = Self::absolute_device(&self.name)?;
+                // Give the desktop a moment to pick up the new device
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                self.absolute.insert((device, size))
+            }
+        };
+
+        device.emit(&[
+            evdev::InputEvent::new(EventType::ABSOLUTE.0, AbsoluteAxisCode::ABS_X.0, to_abs_axis(x, size.width)),
+            evdev::InputEvent::new(EventType::ABSOLUTE.0, AbsoluteAxisCode::ABS_Y.0, to_abs_axis(y, size.height)),
+        ])
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_abs_axis() {
+        assert_eq!(to_abs_axis(0, 1920), 0);
+        assert_eq!(to_abs_axis(1919, 1920), ABS_RANGE);
+        assert_eq!(to_abs_axis(5000, 1920), ABS_RANGE);
+        assert_eq!(to_abs_axis(-3, 1920), 0);
+    }
+
+    #[test]
+    fn test_event_routing() {
+        let event = |event_type: EventType, code| InputEvent::new(event_type.0, code, 1);
+        // BTN_LEFT and motion go to the mouse, KEY_A stays on the keyboard
+        assert!(is_pointer_event(&event(EventType::KEY, 0x110)));
+        assert!(is_pointer_event(&event(EventType::RELATIVE, 8)));
+        assert!(!is_pointer_event(&event(EventType::KEY, 30)));
+        // BTN_SOUTH and sticks go to the gamepad
+        assert!(is_gamepad_event(&event(EventType::KEY, 0x130)));
+        assert!(is_gamepad_event(&event(EventType::ABSOLUTE, 0)));
+        assert!(!is_gamepad_event(&event(EventType::SYNCHRONIZATION, 0)));
+    }
+
+    #[test]
+    fn test_parse_backend_kind() {
+        assert_eq!("wayland".parse(), Ok(BackendKind::Wayland));
+        assert_eq!("auto".parse(), Ok(BackendKind::Auto));
+        assert_eq!("x11".parse(), Ok(BackendKind::X11));
+        assert!("x12".parse::<BackendKind>().is_err());
+    }
+}
@@ -0,0 +1,575 @@
+//! Compact binary storage format for large recordings
+//!
+//! Layout (little-endian):
+//!   magic    b"EVKB"
+//!   version  u16
+//!   flags    u16 (FLAG_PACKED, FLAG_ZSTD; other bits must be 0)
+//!   records  [timestamp_us: u64, type: u16, code: u16, value: i32] (16 bytes each)
+//!
+//! Files are memory-mapped on load and only their header is checked then;
+//! events are decoded lazily and checked as they are (timestamps must not go
+//! backwards and key codes must be valid), so playback of very large
+//! recordings starts without reading the whole file, and stops at the first
+//! bad record. [`parse`] checks everything up front instead.
+//!
+//! Long recordings are mostly small, regular mouse motion, which plain records
+//! store very wastefully. Packed files (FLAG_PACKED) follow the header with
+//!   count        u64 (number of events)
+//!   duration_us  u64 (timestamp of the last event)
+//!   records      LEB128 varints: timestamp delta, type, code, zigzag value
+//! and with FLAG_ZSTD those records are also Zstandard-compressed. Both decode
+//! front to back in one pass, so they stream just like plain records.
+
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use crate::keymap;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+/// File extension that selects the binary format when saving
+pub const EXTENSION: &str = "evkb";
+
+const MAGIC: &[u8; 4] = b"EVKB";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 8;
+pub(crate) const RECORD_LEN: usize = 16;
+
+/// Records are delta-encoded varints, after an event count and duration
+const FLAG_PACKED: u16 = 1;
+/// Packed records are Zstandard-compressed
+const FLAG_ZSTD: u16 = 2;
+const PACKED_HEADER_LEN: usize = HEADER_LEN + 16;
+
+/// Zstandard level for compressed files: most of the gain of the higher
+/// levels while still saving an hour-long recording in well under a second
+#[cfg(feature = "zstd")]
+const COMPRESSION_LEVEL: i32 = 9;
+
+/// How events are stored in a binary macro
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Fixed-size records, decoded straight from the mapped file
+    #[default]
+    Plain,
+    /// Delta-encoded varint records
+    Packed,
+    /// Packed records, Zstandard-compressed (needs the `zstd` feature)
+    Compressed,
+}
+
+impl Encoding {
+    /// The most compact encoding this build can write
+    pub fn smallest() -> Self {
+        if cfg!(feature = "zstd") {
+            Encoding::Compressed
+        } else {
+            Encoding::Packed
+        }
+    }
+
+    fn flags(self) -> u16 {
+        match self {
+            Encoding::Plain => 0,
+            Encoding::Packed => FLAG_PACKED,
+            Encoding::Compressed => FLAG_PACKED | FLAG_ZSTD,
+        }
+    }
+}
+
+/// Check whether a file starts with the binary format magic
+pub fn is_binary<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map(|_| &magic == MAGIC)
+        .unwrap_or(false)
+}
+
+/// Save recorded events in the binary format
+pub fn save<P: AsRef<Path>>(path: P, events: &[RecordedEvent]) -> io::Result<()> {
+    save_with(path, events, Encoding::Plain)
+}
+
+/// Save recorded events in the binary format, with the given encoding
+pub fn save_with<P: AsRef<Path>>(path: P, events: &[RecordedEvent], encoding: Encoding) -> io::Result<()> {
+    if encoding == Encoding::Compressed && !cfg!(feature = "zstd") {
+        return Err(no_zstd());
+    }
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&encoding.flags().to_le_bytes())?;
+
+    match encoding {
+        Encoding::Plain => {
+            for recorded in events {
+                writer.write_all(&encode_record(recorded))?;
+            }
+        }
+        Encoding::Packed | Encoding::Compressed => {
+            writer.write_all(&(events.len() as u64).to_le_bytes())?;
+            writer.write_all(&events.last().map_or(0, |e| e.timestamp_us).to_le_bytes())?;
+            if encoding == Encoding::Compressed {
+                write_compressed(&mut writer, events)?;
+            } else {
+                write_packed_records(&mut writer, events)?;
+            }
+        }
+    }
+
+    writer.flush()
+}
+
+/// Write `events` as delta-encoded varints
+fn write_packed_records<W: Write>(writer: &mut W, events: &[RecordedEvent]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(24);
+    let mut last_timestamp_us = 0;
+    for recorded in events {
+        record.clear();
+        let delta_us = recorded.timestamp_us.checked_sub(last_timestamp_us).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Events must be in timestamp order")
+        })?;
+        push_varint(&mut record, delta_us);
+        push_varint(&mut record, u64::from(recorded.event.event_type().0));
+        push_varint(&mut record, u64::from(recorded.event.code()));
+        let value = recorded.event.value();
+        push_varint(&mut record, ((value << 1) ^ (value >> 31)) as u32 as u64);
+        writer.write_all(&record)?;
+        last_timestamp_us = recorded.timestamp_us;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+fn write_compressed<W: Write>(writer: &mut W, events: &[RecordedEvent]) -> io::Result<()> {
+    let mut encoder = zstd::Encoder::new(writer, COMPRESSION_LEVEL)?;
+    write_packed_records(&mut encoder, events)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn write_compressed<W: Write>(_writer: &mut W, _events: &[RecordedEvent]) -> io::Result<()> {
+    Err(no_zstd())
+}
+
+fn push_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// A memory-mapped binary macro file
+pub struct MappedMacro {
+    mmap: Mmap,
+    header: Header,
+}
+
+impl MappedMacro {
+    /// Map a binary macro file and check its header; records are checked as
+    /// [`MappedMacro::iter`] decodes them
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // Safety: the mapping is read-only. Truncating the file while it is
+        // mapped is the caller's problem, same as for any other mmap user.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = read_header(&mmap)?;
+        Ok(Self { mmap, header })
+    }
+
+    /// Number of events in the file
+    pub fn len(&self) -> usize {
+        match self.header {
+            Header::Plain => (self.mmap.len() - HEADER_LEN) / RECORD_LEN,
+            Header::Packed { count, .. } => count as usize,
+        }
+    }
+
+    /// Check if the file contains no events
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Timestamp of the last event (in microseconds)
+    pub fn duration_us(&self) -> u64 {
+        match self.header {
+            Header::Plain => self.mmap[HEADER_LEN..]
+                .chunks_exact(RECORD_LEN)
+                .next_back()
+                .map_or(0, |record| decode_record(record).timestamp_us),
+            Header::Packed { duration_us, .. } => duration_us,
+        }
+    }
+
+    /// Lazily decode events straight from the mapping, ending with an error
+    /// at the first bad record
+    pub fn iter(&self) -> impl Iterator<Item = io::Result<RecordedEvent>> + '_ {
+        records(&self.mmap, self.header)
+    }
+}
+
+/// Decode binary macro data that is already in memory, checking all of it
+pub fn parse(data: &[u8]) -> io::Result<Vec<RecordedEvent>> {
+    let header = read_header(data)?;
+    let events = records(data, header).collect::<io::Result<Vec<_>>>()?;
+    if let Header::Packed { duration_us, .. } = header
+        && duration_us != events.last().map_or(0, |recorded| recorded.timestamp_us)
+    {
+        return Err(invalid_data("Duration doesn't match the last event"));
+    }
+    Ok(events)
+}
+
+/// What the header says about the records that follow it
+#[derive(Debug, Clone, Copy)]
+enum Header {
+    Plain,
+    Packed { count: u64, duration_us: u64, compressed: bool },
+}
+
+/// Check the header of binary macro data, and for plain records that they
+/// fill the rest of it
+fn read_header(data: &[u8]) -> io::Result<Header> {
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+        return Err(invalid_data("Not an EvKey binary macro"));
+    }
+
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported binary format version {}",
+            version
+        )));
+    }
+
+    let flags = u16::from_le_bytes([data[6], data[7]]);
+    if flags == 0 {
+        if (data.len() - HEADER_LEN) % RECORD_LEN != 0 {
+            return Err(invalid_data("Truncated event record"));
+        }
+        return Ok(Header::Plain);
+    }
+
+    if flags != FLAG_PACKED && flags != FLAG_PACKED | FLAG_ZSTD {
+        return Err(invalid_data(format!("Unsupported binary format flags {:#x}", flags)));
+    }
+    if data.len() < PACKED_HEADER_LEN {
+        return Err(invalid_data("Truncated packed header"));
+    }
+    Ok(Header::Packed {
+        count: u64::from_le_bytes(data[HEADER_LEN..HEADER_LEN + 8].try_into().unwrap()),
+        duration_us: u64::from_le_bytes(data[HEADER_LEN + 8..PACKED_HEADER_LEN].try_into().unwrap()),
+        compressed: flags & FLAG_ZSTD != 0,
+    })
+}
+
+/// The records of `data`, decoded and checked one at a time
+fn records(data: &[u8], header: Header) -> Checked<Box<dyn Iterator<Item = io::Result<RecordedEvent>> + '_>> {
+    let records: Box<dyn Iterator<Item = io::Result<RecordedEvent>> + '_> = match header {
+        Header::Plain => Box::new(data[HEADER_LEN..].chunks_exact(RECORD_LEN).map(|record| Ok(decode_record(record)))),
+        Header::Packed { .. } => match unpack(data, header) {
+            Ok(events) => Box::new(events),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        },
+    };
+    Checked {
+        records,
+        index: 0,
+        last_timestamp_us: 0,
+        failed: false,
+    }
+}
+
+/// Records checked as they're decoded, ending after the first bad one
+struct Checked<I> {
+    records: I,
+    index: usize,
+    last_timestamp_us: u64,
+    failed: bool,
+}
+
+impl<I: Iterator<Item = io::Result<RecordedEvent>>> Iterator for Checked<I> {
+    type Item = io::Result<RecordedEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let checked = self.records.next()?.and_then(|recorded| {
+            if recorded.timestamp_us < self.last_timestamp_us {
+                return Err(invalid_data(format!("Event {} goes back in time", self.index)));
+            }
+            check_key_code(self.index, &recorded)?;
+            Ok(recorded)
+        });
+        match &checked {
+            Ok(recorded) => {
+                self.index += 1;
+                self.last_timestamp_us = recorded.timestamp_us;
+            }
+            Err(_) => self.failed = true,
+        }
+        Some(checked)
+    }
+}
+
+fn check_key_code(index: usize, recorded: &RecordedEvent) -> io::Result<()> {
+    if recorded.event.event_type() == EventType::KEY && recorded.event.code() > keymap::KEY_MAX {
+        return Err(invalid_data(format!(
+            "Event {} has invalid key code {}",
+            index,
+            recorded.event.code()
+        )));
+    }
+    Ok(())
+}
+
+/// Decode the packed records of `data`, front to back
+fn unpack(data: &[u8], header: Header) -> io::Result<Unpacker<Box<dyn Read + '_>>> {
+    let Header::Packed { count, compressed, .. } = header else {
+        return Err(invalid_data("Not a packed binary macro"));
+    };
+    let records = &data[PACKED_HEADER_LEN..];
+    let reader: Box<dyn Read + '_> = if compressed { decompress(records)? } else { Box::new(records) };
+    Ok(Unpacker {
+        reader,
+        remaining: count,
+        last_timestamp_us: 0,
+    })
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(records: &[u8]) -> io::Result<Box<dyn Read + '_>> {
+    // Records are read a byte at a time
+    Ok(Box::new(io::BufReader::new(zstd::Decoder::with_buffer(records)?)))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_records: &[u8]) -> io::Result<Box<dyn Read + '_>> {
+    Err(no_zstd())
+}
+
+/// Iterator over packed records
+struct Unpacker<R: Read> {
+    reader: R,
+    remaining: u64,
+    last_timestamp_us: u64,
+}
+
+impl<R: Read> Unpacker<R> {
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8];
+            self.reader.read_exact(&mut byte).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => invalid_data("Truncated event record"),
+                _ => e,
+            })?;
+            value |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("Malformed event record"))
+    }
+
+    fn read_record(&mut self) -> io::Result<RecordedEvent> {
+        let timestamp_us = self
+            .last_timestamp_us
+            .checked_add(self.read_varint()?)
+            .ok_or_else(|| invalid_data("Timestamp out of range"))?;
+        let malformed = |_| invalid_data("Malformed event record");
+        let event_type = u16::try_from(self.read_varint()?).map_err(malformed)?;
+        let code = u16::try_from(self.read_varint()?).map_err(malformed)?;
+        let zigzag = u32::try_from(self.read_varint()?).map_err(malformed)?;
+        let value = (zigzag >> 1) as i32 ^ -((zigzag & 1) as i32);
+
+        self.last_timestamp_us = timestamp_us;
+        Ok(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(event_type, code, value),
+        })
+    }
+}
+
+impl<R: Read> Iterator for Unpacker<R> {
+    type Item = io::Result<RecordedEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let record = self.read_record();
+        // Nothing after a bad record can be trusted
+        self.remaining = if record.is_ok() { self.remaining - 1 } else { 0 };
+        Some(record)
+    }
+}
+
+pub(crate) fn encode_record(recorded: &RecordedEvent) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0..8].copy_from_slice(&recorded.timestamp_us.to_le_bytes());
+    record[8..10].copy_from_slice(&recorded.event.event_type().0.to_le_bytes());
+    record[10..12].copy_from_slice(&recorded.event.code().to_le_bytes());
+    record[12..16].copy_from_slice(&recorded.event.value().to_le_bytes());
+    record
+}
+
+pub(crate) fn decode_record(record: &[u8]) -> RecordedEvent {
+    let timestamp_us = u64::from_le_bytes(record[0..8].try_into().unwrap());
+    let event_type = u16::from_le_bytes([record[8], record[9]]);
+    let code = u16::from_le_bytes([record[10], record[11]]);
+    let value = i32::from_le_bytes(record[12..16].try_into().unwrap());
+
+    RecordedEvent {
+        timestamp_us,
+        event: InputEvent::new(event_type, code, value),
+    }
+}
+
+pub(crate) fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn no_zstd() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Compressed binary macros need EvKey built with the zstd feature",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("evkey-{}-{}.{}", name, std::process::id(), EXTENSION))
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let path = temp_path("roundtrip");
+        let events = vec![
+            RecordedEvent {
+                timestamp_us: 0,
+                event: InputEvent::new(EventType::KEY.0, 17, 1),
+            },
+            RecordedEvent {
+                timestamp_us: 100_000,
+                event: InputEvent::new(EventType::RELATIVE.0, 1, -42),
+            },
+        ];
+
+        save(&path, &events).unwrap();
+        assert!(is_binary(&path));
+
+        let mapped = MappedMacro::open(&path).unwrap();
+        let loaded: Vec<RecordedEvent> = mapped.iter().collect::<io::Result<_>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].timestamp_us, 100_000);
+        assert_eq!(loaded[1].event.code(), 1);
+        assert_eq!(loaded[1].event.value(), -42);
+    }
+
+    #[test]
+    fn test_packed_roundtrip() {
+        // A minute of 1000Hz mouse motion, with a click and a negative value
+        let mut events: Vec<RecordedEvent> = (0..60_000u64)
+            .flat_map(|i| {
+                let motion = InputEvent::new(EventType::RELATIVE.0, (i % 2) as u16, if i % 7 == 0 { -3 } else { 2 });
+                let sync = InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0);
+                [motion, sync].map(|event| RecordedEvent { timestamp_us: i * 1000, event })
+            })
+            .collect();
+        events.push(RecordedEvent {
+            timestamp_us: 60_000_000,
+            event: InputEvent::new(EventType::KEY.0, 272, i32::MIN),
+        });
+
+        let mut encodings = vec![Encoding::Plain, Encoding::Packed];
+        if cfg!(feature = "zstd") {
+            encodings.push(Encoding::Compressed);
+        }
+        let mut sizes = Vec::new();
+        for encoding in encodings {
+            let path = temp_path("packed");
+            save_with(&path, &events, encoding).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            let mapped = MappedMacro::open(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(mapped.len(), events.len());
+            assert_eq!(mapped.duration_us(), 60_000_000);
+            let loaded = mapped.iter().map(|e| e.map(|e| (e.timestamp_us, e.event)).unwrap());
+            assert!(loaded.eq(events.iter().map(|e| (e.timestamp_us, e.event))));
+            assert!(parse(&bytes[..bytes.len() - 1]).is_err());
+            sizes.push(bytes.len());
+        }
+        // Packed records are a quarter of the plain ones here, compressed far smaller still
+        assert!(sizes[1] * 3 < sizes[0]);
+        if let Some(compressed) = sizes.get(2) {
+            assert!(compressed * 10 < sizes[0]);
+        }
+    }
+
+    #[test]
+    fn test_rejects_truncated_file() {
+        let path = temp_path("truncated");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0, 1, 2, 3]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let result = MappedMacro::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_records() {
+        let header = [&MAGIC[..], &VERSION.to_le_bytes(), &[0, 0]].concat();
+        let record = |timestamp_us: u64, code: u16| {
+            encode_record(&RecordedEvent {
+                timestamp_us,
+                event: InputEvent::new(EventType::KEY.0, code, 1),
+            })
+        };
+
+        let valid = [header.clone(), record(5, 30).to_vec(), record(5, 30).to_vec()].concat();
+        assert_eq!(parse(&valid).unwrap().len(), 2);
+
+        let backwards = [header.clone(), record(10, 30).to_vec(), record(5, 30).to_vec()].concat();
+        assert!(parse(&backwards).is_err());
+
+        let bad_key = [header, record(0, keymap::KEY_MAX + 1).to_vec()].concat();
+        assert!(parse(&bad_key).is_err());
+    }
+
+    #[test]
+    fn test_mapped_checks_records_lazily() {
+        let path = temp_path("lazy");
+        let header = [&MAGIC[..], &VERSION.to_le_bytes(), &[0, 0]].concat();
+        let record = |timestamp_us: u64| {
+            encode_record(&RecordedEvent {
+                timestamp_us,
+                event: InputEvent::new(EventType::KEY.0, 30, 1),
+            })
+            .to_vec()
+        };
+        std::fs::write(&path, [header, record(10), record(5), record(20)].concat()).unwrap();
+
+        // Opening only looks at the header; the bad record ends the events
+        let mapped = MappedMacro::open(&path).unwrap();
+        let events: Vec<io::Result<RecordedEvent>> = mapped.iter().collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].as_ref().unwrap().timestamp_us, 10);
+        assert!(events[1].as_ref().unwrap_err().to_string().contains("Event 1 goes back in time"));
+    }
+}
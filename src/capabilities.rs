@@ -0,0 +1,163 @@
+//! What a macro needs from the device that plays it
+//!
+//! A macro that only types needs a keyboard; one that clicks around needs a
+//! pointer too, `move to` an absolute pointer, smoothed scrolling hi-res
+//! wheel axes and a recorded gamepad its buttons and sticks. Playback works
+//! this out with [`Capabilities::of_playback`] to create a uinput device with
+//! just that, and to refuse a backend that can't deliver it before anything
+//! is played rather than halfway through. Saved macros list what they need
+//! in their header, e.g. `# Needs: keyboard, pointer`.
+
+use crate::action::Action;
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use crate::input_key::InputKey;
+use std::fmt;
+
+/// Kinds of input a macro plays
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Keyboard keys
+    pub keyboard: bool,
+    /// Relative motion and mouse buttons
+    pub pointer: bool,
+    /// Wheel notches
+    pub wheel: bool,
+    /// Hi-res wheel steps between notches
+    pub hires_wheel: bool,
+    /// Placing the pointer at screen coordinates
+    pub absolute: bool,
+    /// Gamepad and joystick buttons and axes
+    pub gamepad: bool,
+}
+
+impl Capabilities {
+    /// Everything, for input that isn't known up front
+    pub fn all() -> Self {
+        Self {
+            keyboard: true,
+            pointer: true,
+            wheel: true,
+            hires_wheel: true,
+            absolute: true,
+            gamepad: true,
+        }
+    }
+
+    /// What playing `events` and `actions` takes
+    pub fn of_playback<'a, I>(events: I, actions: &[(u64, Action)]) -> Self
+    where
+        I: IntoIterator<Item = &'a RecordedEvent>,
+    {
+        let mut needs = Capabilities::default();
+        for recorded in events {
+            needs.add_event(&recorded.event);
+        }
+        for (_, action) in actions {
+            needs.add_action(action);
+        }
+        needs
+    }
+
+    pub fn add_event(&mut self, event: &InputEvent) {
+        let code = event.code();
+        match event.event_type() {
+            EventType::KEY => match InputKey::from(code) {
+                InputKey::MouseButton(_) => self.pointer = true,
+                InputKey::GamepadButton(_) => self.gamepad = true,
+                InputKey::Keyboard(_) | InputKey::Unknown(_) => self.keyboard = true,
+            },
+            EventType::RELATIVE => match code {
+                6 | 8 => self.wheel = true,        // REL_HWHEEL, REL_WHEEL
+                11 | 12 => self.hires_wheel = true, // REL_WHEEL_HI_RES, REL_HWHEEL_HI_RES
+                _ => self.pointer = true,
+            },
+            // Sticks, triggers and hats; recordings have no other absolute axes
+            EventType::ABSOLUTE => self.gamepad = true,
+            _ => {}
+        }
+    }
+
+    pub fn add_action(&mut self, action: &Action) {
+        match action {
+            Action::Paste(_) | Action::Type(_) | Action::Snippet(_) => self.keyboard = true,
+            Action::MoveTo { .. } => self.absolute = true,
+            Action::WaitPixel(_)
+            | Action::WaitText(_)
+            | Action::SetClipboard(_)
+            | Action::Led { .. }
+            | Action::Switch { .. } => {}
+        }
+    }
+
+    /// The capabilities of `self` that `available` lacks
+    pub fn missing(&self, available: &Capabilities) -> Capabilities {
+        let flags = self.flags();
+        let available = available.flags();
+        Self::from_flags(std::array::from_fn(|index| flags[index] && !available[index]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags() == [false; NAMES.len()]
+    }
+
+    fn flags(&self) -> [bool; NAMES.len()] {
+        [self.keyboard, self.pointer, self.wheel, self.hires_wheel, self.absolute, self.gamepad]
+    }
+
+    fn from_flags([keyboard, pointer, wheel, hires_wheel, absolute, gamepad]: [bool; NAMES.len()]) -> Self {
+        Self {
+            keyboard,
+            pointer,
+            wheel,
+            hires_wheel,
+            absolute,
+            gamepad,
+        }
+    }
+}
+
+/// Names of the capabilities, in the order of [`Capabilities::flags`]
+const NAMES: [&str; 6] = ["keyboard", "pointer", "wheel", "hi-res wheel", "absolute pointer", "gamepad"];
+
+impl fmt::Display for Capabilities {
+    /// The capabilities by name, e.g. "keyboard, pointer"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = NAMES.iter().zip(self.flags()).filter(|(_, set)| *set).map(|(name, _)| *name).collect();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(event_type: EventType, code: u16, value: i32) -> RecordedEvent {
+        RecordedEvent {
+            timestamp_us: 0,
+            event: InputEvent::new(event_type.0, code, value),
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        // KEY_A, then a click and a scroll
+        let typing = [at(EventType::KEY, 30, 1), at(EventType::SYNCHRONIZATION, 0, 0)];
+        let needs = Capabilities::of_playback(&typing, &[]);
+        assert_eq!(needs, Capabilities { keyboard: true, ..Default::default() });
+        assert_eq!(needs.to_string(), "keyboard");
+
+        let clicking = [at(EventType::KEY, 0x110, 1), at(EventType::RELATIVE, 8, -1), at(EventType::RELATIVE, 11, -60)];
+        let actions = [(0, Action::MoveTo { x: 10, y: 20 })];
+        let needs = Capabilities::of_playback(&clicking, &actions);
+        assert_eq!(needs.to_string(), "pointer, wheel, hi-res wheel, absolute pointer");
+
+        // BTN_SOUTH and a stick
+        let gaming = [at(EventType::KEY, 0x130, 1), at(EventType::ABSOLUTE, 0, 1200)];
+        let needs = Capabilities::of_playback(&gaming, &[(0, Action::Type("gg".into()))]);
+        assert_eq!(needs, Capabilities { keyboard: true, gamepad: true, ..Default::default() });
+
+        let display_server = Capabilities { keyboard: true, pointer: true, wheel: true, absolute: true, ..Default::default() };
+        assert_eq!(needs.missing(&display_server).to_string(), "gamepad");
+        assert!(needs.missing(&Capabilities::all()).is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! Autoclicker: clicking a mouse button over and over
+//!
+//! Clicks go through the player like any macro. Intervals can be jittered so
+//! the rhythm isn't perfectly regular (reproducibly, given the same seed), and
+//! clicking can be limited to while a key is held on a physical keyboard.
+
+use crate::player::Player;
+use crate::recorder;
+use crate::rng::Rng;
+use evdev::{Device, KeyCode};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long each click holds the button down
+const CLICK_HOLD: Duration = Duration::from_millis(10);
+/// How often the stop flag and the trigger key are checked between clicks
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+pub struct ClickOptions {
+    /// BTN_LEFT, BTN_RIGHT or BTN_MIDDLE
+    pub button: u16,
+    pub interval_ms: u64,
+    /// Largest random change to each interval, either way
+    pub jitter_ms: u64,
+    /// Only click while this key is held
+    pub while_held: Option<u16>,
+}
+
+/// Parse a button name: left, right or middle
+pub fn parse_button(name: &str) -> Result<u16, String> {
+    match name {
+        "left" => Ok(KeyCode::BTN_LEFT.code()),
+        "right" => Ok(KeyCode::BTN_RIGHT.code()),
+        "middle" => Ok(KeyCode::BTN_MIDDLE.code()),
+        _ => Err(format!("Unknown button '{}', use left/right/middle", name)),
+    }
+}
+
+/// Click until `stop` is set, drawing the jitter from `rng`; returns the
+/// number of clicks
+pub fn run(player: &mut Player, options: &ClickOptions, rng: &mut Rng, stop: &AtomicBool) -> io::Result<u64> {
+    let trigger = options.while_held.map(Trigger::open).transpose()?;
+    let mut clicks = 0;
+    let mut next_click = Instant::now();
+    let mut was_active = false;
+
+    while !stop.load(Ordering::Relaxed) {
+        let active = match &trigger {
+            Some(trigger) => trigger.is_held(),
+            None => true,
+        };
+        if active != was_active {
+            info!("{}", if active { "Clicking" } else { "Paused" });
+            was_active = active;
+            // Click as soon as the key goes down
+            next_click = Instant::now();
+        }
+
+        let now = Instant::now();
+        if active && now >= next_click {
+            player.click(options.button, CLICK_HOLD)?;
+            clicks += 1;
+            next_click = now + jittered(options.interval_ms, options.jitter_ms, rng);
+            continue;
+        }
+        thread::sleep(next_click.saturating_duration_since(now).min(POLL_INTERVAL));
+    }
+
+    Ok(clicks)
+}
+
+/// `interval_ms` moved by up to `jitter_ms` either way, and never below 1ms
+fn jittered(interval_ms: u64, jitter_ms: u64, rng: &mut Rng) -> Duration {
+    let offset = rng.below(2 * jitter_ms + 1) as i64 - jitter_ms as i64;
+    Duration::from_millis((interval_ms as i64 + offset).max(1) as u64)
+}
+
+/// The keyboards a trigger key is watched on
+struct Trigger {
+    key: KeyCode,
+    keyboards: Vec<Device>,
+}
+
+impl Trigger {
+    fn open(key: u16) -> io::Result<Self> {
+        let mut keyboards = Vec::new();
+        for device in recorder::find_input_devices()? {
+            if device.kind.contains("keyboard") && !device.name.starts_with("evkey") {
+                keyboards.push(Device::open(&device.path)?);
+            }
+        }
+        if keyboards.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No keyboards found to watch the trigger key on (are you allowed to read /dev/input?)",
+            ));
+        }
+        Ok(Self {
+            key: KeyCode::new(key),
+            keyboards,
+        })
+    }
+
+    fn is_held(&self) -> bool {
+        self.keyboards
+            .iter()
+            .any(|keyboard| keyboard.get_key_state().is_ok_and(|keys| keys.contains(self.key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jittered_intervals() {
+        let mut rng = Rng::new(42);
+        let intervals: Vec<u64> = (0..1000).map(|_| jittered(50, 10, &mut rng).as_millis() as u64).collect();
+        assert!(intervals.iter().all(|ms| (40..=60).contains(ms)));
+        assert!(intervals.contains(&40) && intervals.contains(&60));
+        // The same seed gives the same rhythm
+        let mut again = Rng::new(42);
+        assert!(intervals.iter().all(|&ms| jittered(50, 10, &mut again).as_millis() as u64 == ms));
+
+        assert_eq!(jittered(50, 0, &mut rng), Duration::from_millis(50));
+        assert_eq!(jittered(0, 0, &mut rng), Duration::from_millis(1));
+        assert_eq!(parse_button("middle"), Ok(KeyCode::BTN_MIDDLE.code()));
+        assert!(parse_button("back").is_err());
+    }
+}
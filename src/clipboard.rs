@@ -0,0 +1,58 @@
+//! Setting and reading the system clipboard
+//!
+//! Uses `wl-copy`/`wl-paste` (wl-clipboard) on Wayland and `xclip` on X11.
+//! Both keep serving the clipboard in the background after we return.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// Replace the clipboard contents with `text`
+pub fn set(text: &str) -> io::Result<()> {
+    let mut command = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        Command::new("wl-copy")
+    } else {
+        let mut command = Command::new("xclip");
+        command.args(["-selection", "clipboard"]);
+        command
+    };
+
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't run {} to set the clipboard: {}", program, e)))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} failed ({})", program, status)));
+    }
+    Ok(())
+}
+
+/// Current clipboard contents as text
+pub fn get() -> io::Result<String> {
+    let mut command = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        let mut command = Command::new("wl-paste");
+        command.arg("--no-newline");
+        command
+    } else {
+        let mut command = Command::new("xclip");
+        command.args(["-selection", "clipboard", "-o"]);
+        command
+    };
+
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't run {} to read the clipboard: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("{} failed ({})", program, output.status)));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
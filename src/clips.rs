@@ -0,0 +1,73 @@
+//! A library of named clips
+//!
+//! A clip is a stretch of a macro saved under a name for reuse - a login
+//! sequence, a menu path - and inserted into other macros with
+//! `evkey insert-clip` or [`crate::edit::Editor::insert_clip`]. Clips are
+//! ordinary macro files in `$XDG_CONFIG_HOME/evkey/clips`, so they can be
+//! played and edited like any other.
+
+use crate::config;
+use crate::storage::{self, Macro};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Where clips are kept
+pub fn dir() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("clips"))
+}
+
+/// Check that `name` can be a clip name: letters, digits, `-` and `_`
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        Ok(())
+    } else {
+        Err(format!("'{}' isn't a valid clip name (use letters, digits, - and _)", name))
+    }
+}
+
+/// File the clip `name` is stored in
+pub fn path(name: &str) -> io::Result<PathBuf> {
+    validate_name(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let dir = dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No home directory to keep clips in"))?;
+    Ok(dir.join(format!("{}.macro", name)))
+}
+
+/// Save `clip` as `name`, replacing any clip of that name
+pub fn save(name: &str, clip: &Macro) -> io::Result<PathBuf> {
+    let path = path(name)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    storage::save_macro(&path, clip)?;
+    Ok(path)
+}
+
+/// Load the clip `name`
+pub fn load(name: &str) -> io::Result<Macro> {
+    let path = path(name)?;
+    storage::load_macro(&path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => io::Error::new(io::ErrorKind::NotFound, format!("No clip named '{}'", name)),
+        _ => e,
+    })
+}
+
+/// Names of the saved clips, sorted
+pub fn list() -> io::Result<Vec<String>> {
+    let Some(dir) = dir() else {
+        return Ok(Vec::new());
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".macro").map(String::from)
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
@@ -0,0 +1,52 @@
+//! Time as playback and recording see it
+//!
+//! A [`crate::player::Player`] paces events by a [`Clock`] and a
+//! [`crate::recorder::Recorder`] timestamps them by one. The
+//! [`SystemClock`] is real time; [`crate::testing::VirtualClock`] only moves
+//! when something sleeps on it or a test advances it by hand, so a replay in
+//! a test takes no time and comes out the same on every run.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A monotonic clock that can be slept on
+///
+/// It's `Send` so a recorder can be polled on a thread of its own.
+pub trait Clock: Send {
+    /// Time since the clock started; never goes backwards
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+
+    /// Time since `earlier`, a reading of [`Clock::now`]
+    fn since(&self, earlier: Duration) -> Duration {
+        self.now().saturating_sub(earlier)
+    }
+}
+
+/// Real time, from the monotonic system clock
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
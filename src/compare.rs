@@ -0,0 +1,255 @@
+//! Comparing played events with what came out the other end
+//!
+//! `evkey compare` plays a macro while recording the input devices, to check
+//! that whatever sits in between (a driver, a remapper, VM passthrough)
+//! delivers the macro faithfully. [`compare`] lines the two event streams up
+//! and reports events that went missing, events nobody played, and events
+//! that arrived late or early. `evkey play --verify` does the same with the
+//! device it plays on, as an end-to-end check of the whole pipeline.
+//!
+//! Sync and misc events and key autorepeat are left out on both sides: they
+//! depend on the device and kernel, not on the path being tested.
+
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use crate::keymap;
+use std::fmt;
+
+/// How far ahead in the observed events a played event is looked for before
+/// it counts as missing
+const LOOKAHEAD: usize = 64;
+
+/// Discrepancies listed in the report before the rest are summarized
+const REPORT_LIMIT: usize = 20;
+
+/// A played event and the recorded event it turned into
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub expected: RecordedEvent,
+    pub observed: RecordedEvent,
+    /// How much later than expected it arrived, relative to the first match
+    /// (in microseconds, negative when early)
+    pub deviation_us: i64,
+}
+
+/// Result of lining up expected and observed events
+#[derive(Debug, Clone, Default)]
+pub struct Comparison {
+    pub matches: Vec<Match>,
+    /// Played events that never showed up
+    pub missing: Vec<RecordedEvent>,
+    /// Recorded events that weren't played
+    pub unexpected: Vec<RecordedEvent>,
+    /// Largest timing deviation still considered faithful (in microseconds)
+    pub tolerance_us: u64,
+}
+
+impl Comparison {
+    /// Matches that arrived further off than the tolerance
+    pub fn late(&self) -> impl Iterator<Item = &Match> {
+        self.matches.iter().filter(|m| m.deviation_us.unsigned_abs() > self.tolerance_us)
+    }
+
+    /// Largest deviation in either direction (in microseconds)
+    pub fn max_deviation_us(&self) -> u64 {
+        self.matches.iter().map(|m| m.deviation_us.unsigned_abs()).max().unwrap_or(0)
+    }
+
+    /// Average deviation in either direction (in microseconds)
+    pub fn mean_deviation_us(&self) -> u64 {
+        if self.matches.is_empty() {
+            return 0;
+        }
+        let total: u64 = self.matches.iter().map(|m| m.deviation_us.unsigned_abs()).sum();
+        total / self.matches.len() as u64
+    }
+
+    /// Share of events, played or observed, that lined up within tolerance
+    /// (1.0 when nothing was played or observed)
+    pub fn fidelity(&self) -> f64 {
+        let total = self.matches.len() + self.missing.len() + self.unexpected.len();
+        if total == 0 {
+            return 1.0;
+        }
+        let faithful = self.matches.len() - self.late().count();
+        faithful as f64 / total as f64
+    }
+
+    /// Whether every event arrived, nothing else did, and all within tolerance
+    pub fn is_faithful(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty() && self.late().next().is_none()
+    }
+}
+
+/// Line up observed events with the expected ones
+///
+/// Expected events are taken in order and matched to the next identical
+/// observed event within a short lookahead; observed events skipped over are
+/// unexpected. Timestamps are compared relative to the first match, so the
+/// delay before recording picked up the playback doesn't count.
+pub fn compare(expected: &[RecordedEvent], observed: &[RecordedEvent], tolerance_us: u64) -> Comparison {
+    let expected: Vec<RecordedEvent> = expected.iter().filter(|e| is_compared(&e.event)).cloned().collect();
+    let observed: Vec<RecordedEvent> = observed.iter().filter(|e| is_compared(&e.event)).cloned().collect();
+
+    let mut comparison = Comparison {
+        tolerance_us,
+        ..Comparison::default()
+    };
+    let mut offset_us = None;
+    let mut next = 0;
+
+    for event in expected {
+        let found = observed[next..]
+            .iter()
+            .take(LOOKAHEAD)
+            .position(|candidate| candidate.event == event.event);
+        let Some(skipped) = found else {
+            comparison.missing.push(event);
+            continue;
+        };
+
+        comparison.unexpected.extend_from_slice(&observed[next..next + skipped]);
+        let observed_event = observed[next + skipped].clone();
+        next += skipped + 1;
+
+        let offset = observed_event.timestamp_us as i64 - event.timestamp_us as i64;
+        let first_offset = *offset_us.get_or_insert(offset);
+        comparison.matches.push(Match {
+            expected: event,
+            observed: observed_event,
+            deviation_us: offset - first_offset,
+        });
+    }
+    comparison.unexpected.extend_from_slice(&observed[next..]);
+
+    comparison
+}
+
+fn is_compared(event: &InputEvent) -> bool {
+    let event_type = event.event_type();
+    if event_type == EventType::KEY {
+        return event.value() != 2; // Autorepeat
+    }
+    event_type != EventType::SYNCHRONIZATION && event_type != EventType::MISC
+}
+
+/// "A down", "REL 0 -3" and the like
+fn describe(event: &InputEvent) -> String {
+    match event.event_type() {
+        EventType::KEY => {
+            let name = keymap::display_name(event.code()).unwrap_or_else(|| format!("KEY {}", event.code()));
+            let action = match event.value() {
+                0 => "up",
+                _ => "down",
+            };
+            format!("{} {}", name, action)
+        }
+        EventType::RELATIVE => format!("REL {} {}", event.code(), event.value()),
+        other => format!("type {} code {} value {}", other.0, event.code(), event.value()),
+    }
+}
+
+fn format_ms(us: u64) -> String {
+    format!("{:.1}ms", us as f64 / 1000.0)
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let late: Vec<&Match> = self.late().collect();
+        writeln!(f, "Matched:    {}", self.matches.len())?;
+        writeln!(f, "Missing:    {}", self.missing.len())?;
+        writeln!(f, "Unexpected: {}", self.unexpected.len())?;
+        writeln!(
+            f,
+            "Timing:     {} average, {} worst, {} beyond {}",
+            format_ms(self.mean_deviation_us()),
+            format_ms(self.max_deviation_us()),
+            late.len(),
+            format_ms(self.tolerance_us)
+        )?;
+        writeln!(f, "Fidelity:   {:.1}%", self.fidelity() * 100.0)?;
+
+        let mut discrepancies: Vec<(u64, String)> = Vec::new();
+        discrepancies.extend(
+            self.missing
+                .iter()
+                .map(|e| (e.timestamp_us, format!("missing     {}", describe(&e.event)))),
+        );
+        discrepancies.extend(
+            self.unexpected
+                .iter()
+                .map(|e| (e.timestamp_us, format!("unexpected  {}", describe(&e.event)))),
+        );
+        discrepancies.extend(late.iter().map(|m| {
+            let direction = if m.deviation_us > 0 { "late" } else { "early" };
+            let off = format_ms(m.deviation_us.unsigned_abs());
+            (m.expected.timestamp_us, format!("{:<11} {} by {}", direction, describe(&m.expected.event), off))
+        }));
+        if discrepancies.is_empty() {
+            return Ok(());
+        }
+
+        discrepancies.sort_by_key(|(timestamp_us, _)| *timestamp_us);
+        writeln!(f, "\nDiscrepancies:")?;
+        for (timestamp_us, description) in discrepancies.iter().take(REPORT_LIMIT) {
+            writeln!(f, "  {:>9}  {}", format_ms(*timestamp_us), description)?;
+        }
+        if discrepancies.len() > REPORT_LIMIT {
+            writeln!(f, "  ... and {} more", discrepancies.len() - REPORT_LIMIT)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp_us: u64, event_type: EventType, code: u16, value: i32) -> RecordedEvent {
+        RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(event_type.0, code, value),
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let key = |timestamp_us, code, value| event(timestamp_us, EventType::KEY, code, value);
+        let expected = vec![
+            key(0, 30, 1),
+            event(0, EventType::SYNCHRONIZATION, 0, 0),
+            key(50_000, 30, 0),
+            key(100_000, 48, 1),
+            key(150_000, 48, 0),
+            event(200_000, EventType::RELATIVE, 0, 5),
+        ];
+        // Recording picked up 7ms in; B lost, A repeated, a stray C, motion late
+        let observed = vec![
+            key(7_000, 30, 1),
+            key(40_000, 30, 2),
+            key(58_000, 30, 0),
+            key(120_000, 46, 1),
+            event(230_000, EventType::RELATIVE, 0, 5),
+        ];
+
+        let comparison = compare(&expected, &observed, 5_000);
+        let deviations: Vec<i64> = comparison.matches.iter().map(|m| m.deviation_us).collect();
+        assert_eq!(deviations, vec![0, 1_000, 23_000]);
+        let missing: Vec<u16> = comparison.missing.iter().map(|e| e.event.code()).collect();
+        assert_eq!(missing, vec![48, 48]);
+        let unexpected: Vec<(u64, InputEvent)> = comparison.unexpected.iter().map(|e| (e.timestamp_us, e.event)).collect();
+        assert_eq!(unexpected, vec![(120_000, InputEvent::new(EventType::KEY.0, 46, 1))]);
+        assert_eq!(comparison.late().count(), 1);
+        assert!(!comparison.is_faithful());
+        // Two of three matches on time, out of six events in all
+        assert!((comparison.fidelity() - 2.0 / 6.0).abs() < 1e-9);
+
+        let report = comparison.to_string();
+        assert!(report.contains("Matched:    3\nMissing:    2\nUnexpected: 1\n"));
+        assert!(report.contains("Fidelity:   33.3%\n"));
+        assert!(report.contains("late        REL 0 5 by 23.0ms"));
+        assert!(report.contains("unexpected  C down"));
+
+        assert!(compare(&expected, &expected, 0).is_faithful());
+        assert_eq!(compare(&expected, &expected, 0).fidelity(), 1.0);
+    }
+}
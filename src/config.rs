@@ -0,0 +1,1413 @@
+//! Daemon configuration
+//!
+//! `evkey daemon` reads bindings from `~/.config/evkey/daemon.conf`, one
+//! directive per line:
+//!
+//!   # Where macro files are looked up (default: next to this file)
+//!   macros ~/macros
+//!   # How many macros may play at once (default: 1)
+//!   max-running 1
+//!   # Stops every macro and turns hotkeys off until pressed again
+//!   # (default: CTRL+ALT+ESC, `panic none` to have no panic hotkey)
+//!   panic CTRL+ALT+ESC
+//!   # Whether RIGHTCTRL+F9 presses CTRL+F9 and so on (default: either)
+//!   modifier-sides either
+//!   # Switches to the next profile (default: none)
+//!   switch-profile CTRL+ALT+P
+//!   # Create the device macros play on once, at startup, and give it 200ms
+//!   # before the first macro (default: off, a new device per macro)
+//!   keep-device on
+//!   warm-up 200ms
+//!   # Send at most 500 events a second, 20 at once (default: no limit)
+//!   max-rate 500/s burst 20
+//!   # Refuse macros that hold more than 10 keys at once or type more than
+//!   # 500 characters (default: no limit)
+//!   max-keys 10
+//!   max-text 500
+//!   # Serve Prometheus metrics at http://127.0.0.1:9464/metrics (default: off)
+//!   metrics 127.0.0.1:9464
+//!   # Timing of `double` and `long` bindings (defaults: 300ms, 500ms)
+//!   double-tap 300ms
+//!   long-press 500ms
+//!   # Pushing the left stick up past a quarter of the way holds the d-pad up
+//!   axis ABS_Y below -8000 BTN_DPAD_UP
+//!
+//!   profile default
+//!   bind CTRL+ALT+F5 farm.macro
+//!   bind F9 login.macro
+//!
+//!   profile work
+//!   bind F9 standup.macro cooldown 5s max 10/min busy queue
+//!
+//!   profile game extends default
+//!   # Tap F7 to hold W down until F7 is tapped again
+//!   sustain F7 W
+//!   # Scroll down 10 notches a second while KP2 is held, in fine steps
+//!   scroll KP2 down rate 10/s hires
+//!
+//!   profile pad
+//!   bind BTN_SOUTH jump.macro
+//!   bind BTN_TL+BTN_DPAD_UP climb.macro
+//!   # A three-finger swipe up on the touchpad
+//!   gesture 3 swipe-up overview.macro
+//!
+//!   profile away
+//!   # After 5 minutes without input, and again every 5 minutes
+//!   idle 5m nudge.macro repeat
+//!   # After 30 minutes, and once input comes back
+//!   idle 30m lock.macro back unlock.macro
+//!
+//! Bindings before the first `profile` line belong to a profile named
+//! "default". The first profile is active when the daemon starts.
+//!
+//! A profile that `extends` an earlier one gets its bindings, sustains,
+//! scrolls and idle triggers too, except where it has its own for the same
+//! combo (or idle time).
+//!
+//! Options after a binding's macro file limit how often it fires:
+//!
+//!   cooldown <duration>   ignore the hotkey for this long after it fired
+//!   max <n>/min           fire at most n times in any minute
+//!   busy <policy>         when `max-running` macros are playing: `ignore` the
+//!                         hotkey (the default), `queue` the macro until one
+//!                         is done, or `interrupt` those of no higher
+//!                         priority
+//!   priority <n>          higher priorities (default 0) stop lower ones that
+//!                         are in the way and jump ahead of them in the queue
+//!   confirm               the hotkey has to be pressed twice within
+//!                         [`CONFIRM_WINDOW_MS`], for macros that would be
+//!                         costly to set off by accident
+//!   double                the hotkey is tapped twice within `double-tap`
+//!                         (default [`DEFAULT_DOUBLE_TAP_MS`])
+//!   long                  the hotkey is held for `long-press` (default
+//!                         [`DEFAULT_LONG_PRESS_MS`]) before it's released
+//!
+//! and when it applies at all:
+//!
+//!   window <regex>        the active window's title matches (no spaces; use
+//!                         `\s`)
+//!   time <HH:MM-HH:MM>    local time is in this range, which may wrap past
+//!                         midnight
+//!   days <days>           it's one of these days, e.g. `mon-fri` or `sat,sun`
+//!   power <ac|battery>    the machine runs off AC or its battery
+//!   lid <open|closed>     the laptop's lid is open or closed
+//!
+//! When several bindings share a combo, the first one whose conditions hold
+//! plays, so the same hotkey can do different things per app or time of day.
+//! A `double` or `long` binding only competes with others of its kind, so one
+//! key can play three macros: on a tap, a double tap and a long press. Its
+//! single-tap macro then waits until a second tap can't come anymore.
+//!
+//! [`Config::conflicts`] lists triggers that can never fire because an
+//! earlier one takes their combo, and chords that start out as a single-key
+//! binding; the daemon warns about them rather than quietly picking one.
+//!
+//! `scroll <combo> <up|down|left|right>` turns the mouse wheel for as long as
+//! the combo is held, `rate` notches a second (default 10). With `hires` the
+//! notches are split into the 1/120 steps of a hi-res wheel, which scrolls
+//! smoothly in apps that support it.
+//!
+//! Gamepads are watched like keyboards, and their buttons can be bound like
+//! keys. `axis <axis> <above|below> <value> <key>` holds `key` down for as
+//! long as a stick or trigger (`ABS_X`, `ABS_RY`, `ABS_HAT0X`, ... or a
+//! number) is past `value`, so a stick can set off bindings, sustains and
+//! scrolls too. Axes apply in every profile.
+//!
+//! `idle <duration> <macro file>` plays a macro once no keyboard or mouse has
+//! been used for that long (`300s` or `5m`), once per idle stretch unless
+//! `repeat` is given. `back <macro file>` plays another when input resumes
+//! after it fired. The binding options above apply to both, so
+//! `idle 5m nudge.macro power ac lid open` stays quiet on battery or with the
+//! lid closed.
+//!
+//! `gesture <fingers> <gesture> <macro file>` plays a macro on a touchpad
+//! swipe (`swipe-up`, `swipe-down`, `swipe-left`, `swipe-right`; three
+//! fingers or more) or pinch (`pinch-in`, `pinch-out`), and takes the binding
+//! options. The daemon only sees gestures when built with the `gestures`
+//! feature.
+//!
+//! `expand <text> <macro file>` plays a macro when `text` (e.g. `;addr`) is
+//! typed at the start of a word, after erasing it with backspaces; typically
+//! the macro is a single `type "..."` action. It takes the binding options
+//! too.
+
+use crate::gesture::Gesture;
+use crate::guard::Limits;
+use crate::keymap;
+use crate::keyset::KeySet;
+use crate::power::PowerState;
+use crate::storage::parse_duration;
+use crate::throttle::{self, EventRate};
+use regex::Regex;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Name of the profile bindings go to before any `profile` line
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// How soon a `confirm` binding's hotkey has to be pressed again (in
+/// milliseconds)
+pub const CONFIRM_WINDOW_MS: u64 = 2000;
+
+/// Panic hotkey unless the config sets another one
+pub const DEFAULT_PANIC: &str = "CTRL+ALT+ESC";
+
+/// How soon after a tap the second one of a double tap has to come (in
+/// milliseconds), unless the config says `double-tap <duration>`
+pub const DEFAULT_DOUBLE_TAP_MS: u64 = 300;
+
+/// How long a hotkey has to be held for a long press (in milliseconds),
+/// unless the config says `long-press <duration>`
+pub const DEFAULT_LONG_PRESS_MS: u64 = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Directory relative macro paths are resolved against
+    pub macro_dir: PathBuf,
+    /// Most macros playing at the same time
+    pub max_running: usize,
+    /// Combo that stops every macro and toggles hotkeys off and on
+    pub panic: Option<KeySet>,
+    /// Left and right modifiers are different keys to hotkeys
+    pub exact_sides: bool,
+    /// Combo that makes the next profile active
+    pub switch_profile: Option<KeySet>,
+    /// Play every macro on one virtual device created at startup
+    pub keep_device: bool,
+    /// How long a new playback device gets before anything is played on it,
+    /// so the desktop has picked it up
+    pub warm_up_ms: u64,
+    /// Most events a second macros send
+    pub max_rate: Option<EventRate>,
+    /// Bounds macros have to stay within to be played
+    pub limits: Limits,
+    /// Address to serve Prometheus metrics on
+    pub metrics: Option<SocketAddr>,
+    /// Sticks and triggers read as keys
+    pub axes: Vec<AxisKey>,
+    /// Longest wait from a tap to the next one of a double tap
+    pub double_tap_ms: u64,
+    /// Shortest hold that counts as a long press
+    pub long_press_ms: u64,
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// Profile this one inherits from
+    pub extends: Option<String>,
+    pub bindings: Vec<Binding>,
+    pub sustains: Vec<Sustain>,
+    pub scrolls: Vec<Scroll>,
+    pub idles: Vec<Idle>,
+    /// Bindings set off by typing their `sequence`
+    pub expansions: Vec<Binding>,
+    /// Bindings set off by a touchpad `gesture`
+    pub gestures: Vec<Binding>,
+}
+
+impl Profile {
+    fn new(name: &str, extends: Option<String>) -> Self {
+        Profile {
+            name: name.to_string(),
+            extends,
+            bindings: Vec::new(),
+            sustains: Vec::new(),
+            scrolls: Vec::new(),
+            idles: Vec::new(),
+            expansions: Vec::new(),
+            gestures: Vec::new(),
+        }
+    }
+
+    /// Add what `base` has that this profile doesn't override, after its own
+    fn inherit(&mut self, base: &Profile) {
+        let bindings = base
+            .bindings
+            .iter()
+            .filter(|b| !self.bindings.iter().any(|own| own.combo == b.combo && own.press == b.press));
+        self.bindings.extend(bindings.cloned().collect::<Vec<_>>());
+        let sustains = base.sustains.iter().filter(|s| !self.sustains.iter().any(|own| own.toggle == s.toggle));
+        self.sustains.extend(sustains.cloned().collect::<Vec<_>>());
+        let scrolls = base.scrolls.iter().filter(|s| !self.scrolls.iter().any(|own| own.combo == s.combo));
+        self.scrolls.extend(scrolls.cloned().collect::<Vec<_>>());
+        let idles = base.idles.iter().filter(|i| !self.idles.iter().any(|own| own.after_ms == i.after_ms));
+        self.idles.extend(idles.cloned().collect::<Vec<_>>());
+        let expansions = base.expansions.iter().filter(|e| !self.expansions.iter().any(|own| own.sequence == e.sequence));
+        self.expansions.extend(expansions.cloned().collect::<Vec<_>>());
+        let gestures = base.gestures.iter().filter(|g| !self.gestures.iter().any(|own| own.gesture == g.gesture));
+        self.gestures.extend(gestures.cloned().collect::<Vec<_>>());
+    }
+
+    /// Hotkey bindings followed by the macros of idle triggers, expansions
+    /// and gestures
+    pub fn all_bindings(&self) -> impl Iterator<Item = &Binding> {
+        let idles = self.idles.iter().flat_map(|idle| std::iter::once(&idle.binding).chain(&idle.back));
+        self.bindings.iter().chain(idles).chain(&self.expansions).chain(&self.gestures)
+    }
+}
+
+/// A combo that toggles holding down other keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sustain {
+    pub toggle: KeySet,
+    /// Keys held down while the sustain is on
+    pub keys: KeySet,
+    /// Line of the config the sustain is on (1-based)
+    pub line: usize,
+}
+
+/// A combo that turns the mouse wheel while held
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scroll {
+    pub combo: KeySet,
+    pub direction: ScrollDirection,
+    /// Wheel notches per second
+    pub rate: u32,
+    /// Send hi-res wheel steps between notches
+    pub hires: bool,
+    /// Line of the config the scroll is on (1-based)
+    pub line: usize,
+}
+
+/// A stick or trigger pushed past a threshold, read as a key held down
+#[derive(Debug, Clone, PartialEq)]
+pub struct AxisKey {
+    /// `ABS_*` code of the axis
+    pub axis: u16,
+    pub threshold: i32,
+    /// Held above the threshold rather than below it
+    pub above: bool,
+    /// Key held while the axis is past the threshold
+    pub key: u16,
+    /// Line of the config the axis is on (1-based)
+    pub line: usize,
+}
+
+impl AxisKey {
+    /// Whether an axis at `value` holds the key
+    pub fn is_pushed(&self, value: i32) -> bool {
+        if self.above {
+            value > self.threshold
+        } else {
+            value < self.threshold
+        }
+    }
+}
+
+/// Names of the axes gamepads have
+const AXES: &[(&str, u16)] = &[
+    ("ABS_X", 0x00),
+    ("ABS_Y", 0x01),
+    ("ABS_Z", 0x02),
+    ("ABS_RX", 0x03),
+    ("ABS_RY", 0x04),
+    ("ABS_RZ", 0x05),
+    ("ABS_GAS", 0x09),
+    ("ABS_BRAKE", 0x0a),
+    ("ABS_HAT0X", 0x10),
+    ("ABS_HAT0Y", 0x11),
+];
+
+/// An axis by name (`ABS_RY`) or number
+fn parse_axis(name: &str) -> Result<u16, String> {
+    AXES.iter()
+        .find(|(axis, _)| axis.eq_ignore_ascii_case(name))
+        .map(|&(_, code)| code)
+        .or_else(|| name.parse().ok().filter(|&code| code < 0x40))
+        .ok_or_else(|| format!("Unknown axis '{}', e.g. ABS_X, ABS_RY or ABS_HAT0X", name))
+}
+
+/// Triggers that overlap, as found by [`Config::conflicts`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// Line of the trigger that loses out
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+/// Something a combo does, for conflict checks
+struct Trigger<'a> {
+    combo: &'a KeySet,
+    /// "farm.macro", "sustain", "the panic hotkey", ...
+    what: String,
+    /// `None` for the global hotkeys
+    line: Option<usize>,
+    conditions: &'a [Condition],
+    /// Plays once the combo is released, so it can be left pending
+    pends: bool,
+    /// How a binding's combo is pressed; `None` for everything else, which
+    /// takes the combo however it's pressed
+    press: Option<Press>,
+}
+
+impl<'a> Trigger<'a> {
+    /// The panic or switch-profile hotkey, if set
+    fn global(combo: &'a Option<KeySet>, what: &str) -> Option<Self> {
+        combo.as_ref().map(|combo| Trigger {
+            combo,
+            what: what.to_string(),
+            line: None,
+            conditions: &[],
+            pends: false,
+            press: None,
+        })
+    }
+
+    fn describe(&self) -> String {
+        match self.line {
+            Some(line) => format!("{} on line {}", self.what, line),
+            None => self.what.clone(),
+        }
+    }
+}
+
+/// A macro played after a while without input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Idle {
+    /// Time without input before the macro plays (in milliseconds)
+    pub after_ms: u64,
+    /// Play it again every `after_ms` for as long as input stays idle
+    pub repeat: bool,
+    /// The idle macro, with an empty combo
+    pub binding: Binding,
+    /// Played when input resumes after the idle macro played
+    pub back: Option<Binding>,
+}
+
+/// Default for a scroll's `rate`
+pub const DEFAULT_SCROLL_RATE: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScrollDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl FromStr for ScrollDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(ScrollDirection::Up),
+            "down" => Ok(ScrollDirection::Down),
+            "left" => Ok(ScrollDirection::Left),
+            "right" => Ok(ScrollDirection::Right),
+            _ => Err(format!("Unknown scroll direction '{}', use up/down/left/right", s)),
+        }
+    }
+}
+
+impl fmt::Display for ScrollDirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrollDirection::Up => write!(f, "up"),
+            ScrollDirection::Down => write!(f, "down"),
+            ScrollDirection::Left => write!(f, "left"),
+            ScrollDirection::Right => write!(f, "right"),
+        }
+    }
+}
+
+/// A key combo that plays a macro
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub combo: KeySet,
+    /// Macro file as written in the config
+    pub macro_file: String,
+    /// Line of the config the binding is on (1-based)
+    pub line: usize,
+    /// Minimum time between two activations (in milliseconds)
+    pub cooldown_ms: u64,
+    /// Most activations allowed within any minute
+    pub max_per_minute: Option<u32>,
+    pub busy: BusyPolicy,
+    pub priority: i32,
+    /// All must hold for the binding to apply
+    pub conditions: Vec<Condition>,
+    /// Only play on a second press of the hotkey
+    pub confirm: bool,
+    /// Text that sets the binding off when typed, instead of the combo
+    pub sequence: Option<String>,
+    /// Touchpad gesture that sets the binding off, instead of the combo
+    pub gesture: Option<Gesture>,
+    /// How the combo has to be pressed
+    pub press: Press,
+}
+
+impl Binding {
+    /// Whether every condition holds at `now`; `window` gives the title of the
+    /// active window and `power` the power state, each only asked if a
+    /// condition needs it
+    pub fn applies(
+        &self,
+        now: LocalTime,
+        window: &mut impl FnMut() -> Option<String>,
+        power: &mut impl FnMut() -> PowerState,
+    ) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Window(pattern) => window().is_some_and(|title| pattern.is_match(&title)),
+            Condition::Time { start, end } if start <= end => (*start..*end).contains(&now.minutes),
+            Condition::Time { start, end } => now.minutes >= *start || now.minutes < *end,
+            Condition::Days(days) => days & (1 << now.weekday) != 0,
+            Condition::Power { ac } => power().on_ac == *ac,
+            Condition::Lid { open } => power().lid_open == *open,
+        })
+    }
+}
+
+/// How a binding's combo is pressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Press {
+    /// Pressed and released
+    #[default]
+    Single,
+    /// Tapped twice in a row
+    Double,
+    /// Held down a while
+    Long,
+}
+
+impl fmt::Display for Press {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Press::Single => write!(f, "single"),
+            Press::Double => write!(f, "double"),
+            Press::Long => write!(f, "long"),
+        }
+    }
+}
+
+/// When a binding applies
+#[derive(Debug, Clone)]
+pub enum Condition {
+    /// The active window's title matches
+    Window(Regex),
+    /// Local time from `start` up to `end`, in minutes since midnight; past
+    /// midnight if `end` is earlier
+    Time { start: u16, end: u16 },
+    /// Days of the week, Monday in bit 0
+    Days(u8),
+    /// Running off AC, or off the battery
+    Power { ac: bool },
+    /// The lid is open, or closed
+    Lid { open: bool },
+}
+
+impl PartialEq for Condition {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Condition::Window(a), Condition::Window(b)) => a.as_str() == b.as_str(),
+            (Condition::Time { start, end }, Condition::Time { start: s, end: e }) => (start, end) == (s, e),
+            (Condition::Days(a), Condition::Days(b)) => a == b,
+            (Condition::Power { ac: a }, Condition::Power { ac: b }) => a == b,
+            (Condition::Lid { open: a }, Condition::Lid { open: b }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Day and time conditions are checked against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// Minutes since midnight
+    pub minutes: u16,
+    /// 0 for Monday up to 6 for Sunday
+    pub weekday: u8,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// What a binding does when another macro is already playing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BusyPolicy {
+    /// Don't play the macro at all
+    #[default]
+    Ignore,
+    /// Play it once the running macros are done
+    Queue,
+    /// Stop the running macros of the same or lower priority and play it
+    /// right away
+    Interrupt,
+}
+
+impl FromStr for BusyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(BusyPolicy::Ignore),
+            "queue" => Ok(BusyPolicy::Queue),
+            "interrupt" => Ok(BusyPolicy::Interrupt),
+            _ => Err(format!("Unknown busy policy '{}', use ignore/queue/interrupt", s)),
+        }
+    }
+}
+
+impl fmt::Display for BusyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusyPolicy::Ignore => write!(f, "ignore"),
+            BusyPolicy::Queue => write!(f, "queue"),
+            BusyPolicy::Interrupt => write!(f, "interrupt"),
+        }
+    }
+}
+
+impl Config {
+    /// Parse config text; relative paths are resolved against `base_dir`
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Self, String> {
+        let mut config = Config {
+            macro_dir: base_dir.to_path_buf(),
+            max_running: 1,
+            panic: Some(parse_combo(DEFAULT_PANIC)?),
+            exact_sides: false,
+            switch_profile: None,
+            keep_device: false,
+            warm_up_ms: 0,
+            max_rate: None,
+            limits: Limits::default(),
+            metrics: None,
+            axes: Vec::new(),
+            double_tap_ms: DEFAULT_DOUBLE_TAP_MS,
+            long_press_ms: DEFAULT_LONG_PRESS_MS,
+            profiles: Vec::new(),
+        };
+
+        for (line_num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("Line {}: {}", line_num + 1, message);
+
+            let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            match directive {
+                "macros" if !rest.is_empty() => config.macro_dir = base_dir.join(expand_home(rest)),
+                "max-running" => {
+                    config.max_running = rest
+                        .parse()
+                        .ok()
+                        .filter(|&max| max > 0)
+                        .ok_or_else(|| error(format!("Invalid max-running '{}', expected a positive number", rest)))?;
+                }
+                "panic" if rest == "none" => config.panic = None,
+                "panic" if !rest.is_empty() => config.panic = Some(parse_combo(rest).map_err(error)?),
+                "modifier-sides" => {
+                    config.exact_sides = match rest {
+                        "either" => false,
+                        "exact" => true,
+                        _ => return Err(error(format!("Invalid modifier-sides '{}', use either/exact", rest))),
+                    };
+                }
+                "keep-device" => {
+                    config.keep_device = match rest {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(error(format!("Invalid keep-device '{}', use on/off", rest))),
+                    };
+                }
+                "warm-up" => config.warm_up_ms = parse_duration(rest).map_err(error)?,
+                "max-rate" => {
+                    let (rate, burst) = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                        [rate] => (rate, throttle::DEFAULT_BURST),
+                        [rate, "burst", burst] => (
+                            rate,
+                            burst
+                                .parse()
+                                .map_err(|_| error(format!("Invalid burst '{}', expected a number of events", burst)))?,
+                        ),
+                        _ => return Err(error(format!("Expected 'max-rate <events>/s [burst <events>]', got '{}'", line))),
+                    };
+                    config.max_rate = Some(EventRate::parse(rate, burst).map_err(error)?);
+                }
+                "max-keys" | "max-text" => {
+                    let max = rest
+                        .parse()
+                        .map_err(|_| error(format!("Invalid {} '{}', expected a number", directive, rest)))?;
+                    match directive {
+                        "max-keys" => config.limits.max_keys = Some(max),
+                        _ => config.limits.max_text = Some(max),
+                    }
+                }
+                "metrics" => {
+                    config.metrics = Some(rest.parse().map_err(|_| {
+                        error(format!("Invalid metrics address '{}', expected e.g. 127.0.0.1:9464", rest))
+                    })?);
+                }
+                "double-tap" => config.double_tap_ms = parse_duration(rest).map_err(error)?,
+                "long-press" => config.long_press_ms = parse_duration(rest).map_err(error)?,
+                "axis" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    let [axis, side, threshold, key] = fields[..] else {
+                        return Err(error(format!("Expected 'axis <axis> <above|below> <value> <key>', got '{}'", line)));
+                    };
+                    let above = match side {
+                        "above" => true,
+                        "below" => false,
+                        _ => return Err(error(format!("Invalid axis side '{}', use above/below", side))),
+                    };
+                    config.axes.push(AxisKey {
+                        axis: parse_axis(axis).map_err(error)?,
+                        threshold: threshold
+                            .parse()
+                            .map_err(|_| error(format!("Invalid axis value '{}', expected a number", threshold)))?,
+                        above,
+                        key: keymap::name_to_keycode(key).ok_or_else(|| error(format!("Unknown key '{}'", key)))?,
+                        line: line_num + 1,
+                    });
+                }
+                "switch-profile" if !rest.is_empty() => {
+                    config.switch_profile = Some(parse_combo(rest).map_err(error)?);
+                }
+                "profile" if !rest.is_empty() => {
+                    let (name, extends) = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                        [name] => (name, None),
+                        [name, "extends", base] => (name, Some(base)),
+                        _ => return Err(error(format!("Expected 'profile <name> [extends <profile>]', got '{}'", line))),
+                    };
+                    if config.profiles.iter().any(|profile| profile.name == name) {
+                        return Err(error(format!("Duplicate profile '{}'", name)));
+                    }
+                    if let Some(base) = extends
+                        && !config.profiles.iter().any(|profile| profile.name == base)
+                    {
+                        return Err(error(format!("Profile '{}' extends '{}', which isn't defined above it", name, base)));
+                    }
+                    config.profiles.push(Profile::new(name, extends.map(str::to_string)));
+                }
+                "bind" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    let [combo, macro_file, options @ ..] = &fields[..] else {
+                        return Err(error(format!("Expected 'bind <combo> <macro file>', got '{}'", line)));
+                    };
+                    let mut press = Press::Single;
+                    let mut binding_options = Vec::new();
+                    for &option in options {
+                        match option {
+                            "double" => press = Press::Double,
+                            "long" => press = Press::Long,
+                            _ => binding_options.push(option),
+                        }
+                    }
+                    let mut binding = Binding {
+                        combo: parse_combo(combo).map_err(error)?,
+                        macro_file: macro_file.to_string(),
+                        line: line_num + 1,
+                        cooldown_ms: 0,
+                        max_per_minute: None,
+                        busy: BusyPolicy::default(),
+                        priority: 0,
+                        conditions: Vec::new(),
+                        confirm: false,
+                        sequence: None,
+                        gesture: None,
+                        press,
+                    };
+                    parse_binding_options(&mut binding, &binding_options).map_err(error)?;
+                    config.current_profile().bindings.push(binding);
+                }
+                "sustain" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    let [toggle, keys] = fields[..] else {
+                        return Err(error(format!("Expected 'sustain <combo> <keys to hold>', got '{}'", line)));
+                    };
+                    let sustain = Sustain {
+                        toggle: parse_combo(toggle).map_err(error)?,
+                        keys: parse_combo(keys).map_err(error)?,
+                        line: line_num + 1,
+                    };
+                    config.current_profile().sustains.push(sustain);
+                }
+                "scroll" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    let [combo, direction, options @ ..] = &fields[..] else {
+                        return Err(error(format!("Expected 'scroll <combo> <direction>', got '{}'", line)));
+                    };
+                    let mut scroll = Scroll {
+                        combo: parse_combo(combo).map_err(error)?,
+                        direction: direction.parse().map_err(error)?,
+                        rate: DEFAULT_SCROLL_RATE,
+                        hires: false,
+                        line: line_num + 1,
+                    };
+                    parse_scroll_options(&mut scroll, options).map_err(error)?;
+                    config.current_profile().scrolls.push(scroll);
+                }
+                "idle" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    let [after, macro_file, options @ ..] = &fields[..] else {
+                        return Err(error(format!("Expected 'idle <duration> <macro file>', got '{}'", line)));
+                    };
+                    let idle = parse_idle(after, macro_file, options, line_num + 1).map_err(error)?;
+                    config.current_profile().idles.push(idle);
+                }
+                "expand" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    let [sequence, macro_file, options @ ..] = &fields[..] else {
+                        return Err(error(format!("Expected 'expand <text> <macro file>', got '{}'", line)));
+                    };
+                    let mut binding = Binding {
+                        combo: KeySet::new(),
+                        macro_file: macro_file.to_string(),
+                        line: line_num + 1,
+                        cooldown_ms: 0,
+                        max_per_minute: None,
+                        busy: BusyPolicy::default(),
+                        priority: 0,
+                        conditions: Vec::new(),
+                        confirm: false,
+                        sequence: Some(sequence.to_string()),
+                        gesture: None,
+                        press: Press::Single,
+                    };
+                    parse_binding_options(&mut binding, options).map_err(error)?;
+                    config.current_profile().expansions.push(binding);
+                }
+                "gesture" => {
+                    let fields: Vec<&str> = rest.split_whitespace().collect();
+                    let [fingers, motion, macro_file, options @ ..] = &fields[..] else {
+                        return Err(error(format!("Expected 'gesture <fingers> <gesture> <macro file>', got '{}'", line)));
+                    };
+                    let mut binding = Binding {
+                        combo: KeySet::new(),
+                        macro_file: macro_file.to_string(),
+                        line: line_num + 1,
+                        cooldown_ms: 0,
+                        max_per_minute: None,
+                        busy: BusyPolicy::default(),
+                        priority: 0,
+                        conditions: Vec::new(),
+                        confirm: false,
+                        sequence: None,
+                        gesture: Some(Gesture::parse(fingers, motion).map_err(error)?),
+                        press: Press::Single,
+                    };
+                    parse_binding_options(&mut binding, options).map_err(error)?;
+                    config.current_profile().gestures.push(binding);
+                }
+                _ => return Err(error(format!("Unknown setting '{}'", line))),
+            }
+        }
+
+        // Bases come before the profiles extending them, so they're complete
+        // by the time they're inherited from
+        for index in 0..config.profiles.len() {
+            let Some(base) = config.profiles[index].extends.clone() else {
+                continue;
+            };
+            let base = config.profiles.iter().find(|profile| profile.name == base).unwrap().clone();
+            config.profiles[index].inherit(&base);
+        }
+
+        Ok(config)
+    }
+
+    /// Profile the lines being parsed belong to
+    fn current_profile(&mut self) -> &mut Profile {
+        if self.profiles.is_empty() {
+            self.profiles.push(Profile::new(DEFAULT_PROFILE, None));
+        }
+        self.profiles.last_mut().unwrap()
+    }
+
+    /// Overlapping triggers, in the order the daemon checks them: the panic
+    /// and switch-profile hotkeys, then each profile's bindings, sustains and
+    /// scrolls, and expansions whose text another one's starts with
+    ///
+    /// A trigger conflicts with an earlier one for the same combo unless the
+    /// earlier one is a binding with conditions of its own (the usual way to
+    /// give a hotkey several meanings). A chord also conflicts with a binding
+    /// on one of its keys alone: pressing that key first sets the binding off
+    /// whenever the chord doesn't apply.
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        let same = |a: &KeySet, b: &KeySet| keymap::combo_matches(a, b, self.exact_sides);
+        let side = |key: u16| if self.exact_sides { key } else { keymap::either_side(key) };
+        let mut conflicts: Vec<(Option<usize>, Conflict)> = Vec::new();
+
+        for profile in &self.profiles {
+            let triggers: Vec<Trigger> = Trigger::global(&self.panic, "the panic hotkey")
+                .into_iter()
+                .chain(Trigger::global(&self.switch_profile, "the switch-profile hotkey"))
+                .chain(profile.bindings.iter().map(|binding| Trigger {
+                    combo: &binding.combo,
+                    what: binding.macro_file.clone(),
+                    line: Some(binding.line),
+                    conditions: &binding.conditions,
+                    pends: true,
+                    press: Some(binding.press),
+                }))
+                .chain(profile.sustains.iter().map(|sustain| Trigger {
+                    combo: &sustain.toggle,
+                    what: "sustain".to_string(),
+                    line: Some(sustain.line),
+                    conditions: &[],
+                    pends: true,
+                    press: None,
+                }))
+                .chain(profile.scrolls.iter().map(|scroll| Trigger {
+                    combo: &scroll.combo,
+                    what: "scroll".to_string(),
+                    line: Some(scroll.line),
+                    conditions: &[],
+                    pends: false,
+                    press: None,
+                }))
+                .collect();
+
+            // Triggers that never fire don't get in the way of later ones
+            let mut unreachable = vec![false; triggers.len()];
+            for (index, later) in triggers.iter().enumerate() {
+                let Some(line) = later.line else {
+                    continue;
+                };
+                for (earlier_index, earlier) in triggers[..index].iter().enumerate() {
+                    if unreachable[earlier_index] {
+                        continue;
+                    }
+                    let message = if same(earlier.combo, later.combo) {
+                        if !earlier.conditions.is_empty() && earlier.conditions != later.conditions {
+                            continue;
+                        }
+                        // A tap, a double tap and a long press can share a combo
+                        if let (Some(earlier_press), Some(later_press)) = (earlier.press, later.press)
+                            && earlier_press != later_press
+                        {
+                            continue;
+                        }
+                        unreachable[index] = true;
+                        format!(
+                            "{} ({}) never fires in profile '{}': {} takes the combo first",
+                            keymap::format_combo(later.combo),
+                            later.what,
+                            profile.name,
+                            earlier.describe()
+                        )
+                    } else if let Some((key, chord)) = [(earlier, later), (later, earlier)]
+                        .into_iter()
+                        .find(|(key, chord)| key.pends && key.combo.len() == 1 && chord.combo.len() > 1)
+                        && key.combo.codes().all(|key| chord.combo.codes().any(|other| side(other) == side(key)))
+                    {
+                        let (key_combo, chord_combo) = (keymap::format_combo(key.combo), keymap::format_combo(chord.combo));
+                        format!(
+                            "{} ({}) is also how {} ({}) starts in profile '{}': pressing {} first sets it off \
+                             whenever {} doesn't apply",
+                            key_combo,
+                            key.describe(),
+                            chord_combo,
+                            chord.describe(),
+                            profile.name,
+                            key_combo,
+                            chord_combo
+                        )
+                    } else {
+                        continue;
+                    };
+                    // Inherited triggers are only reported for the profile defining them
+                    if !conflicts.iter().any(|(other, conflict)| conflict.line == line && *other == earlier.line) {
+                        conflicts.push((earlier.line, Conflict { line, message }));
+                    }
+                    if unreachable[index] {
+                        break;
+                    }
+                }
+            }
+
+            // Typing an expansion's text passes through any shorter one it
+            // starts with
+            for (index, later) in profile.expansions.iter().enumerate() {
+                let later_text = later.sequence.as_deref().unwrap_or_default();
+                let earlier = profile.expansions.iter().enumerate().find(|&(earlier_index, earlier)| {
+                    let earlier_text = earlier.sequence.as_deref().unwrap_or_default();
+                    let in_the_way = if earlier_text == later_text {
+                        earlier_index < index
+                    } else {
+                        later_text.starts_with(earlier_text)
+                    };
+                    in_the_way && (earlier.conditions.is_empty() || earlier.conditions == later.conditions)
+                });
+                if let Some((_, earlier)) = earlier
+                    && !conflicts.iter().any(|(other, conflict)| conflict.line == later.line && *other == Some(earlier.line))
+                {
+                    let message = format!(
+                        "\"{}\" ({}) never fires in profile '{}': \"{}\" ({} on line {}) fires first",
+                        later_text,
+                        later.macro_file,
+                        profile.name,
+                        earlier.sequence.as_deref().unwrap_or_default(),
+                        earlier.macro_file,
+                        earlier.line
+                    );
+                    conflicts.push((Some(earlier.line), Conflict { line: later.line, message }));
+                }
+            }
+        }
+
+        conflicts.sort_by_key(|(_, conflict)| conflict.line);
+        conflicts.into_iter().map(|(_, conflict)| conflict).collect()
+    }
+
+    /// Full path of a binding's macro file
+    pub fn macro_path(&self, binding: &Binding) -> PathBuf {
+        self.macro_dir.join(expand_home(&binding.macro_file))
+    }
+}
+
+/// An idle trigger from its duration, macro file and `back`, `repeat` and
+/// binding options
+fn parse_idle(after: &str, macro_file: &str, options: &[&str], line: usize) -> Result<Idle, String> {
+    let after_ms = match after.strip_suffix('m').and_then(|minutes| minutes.parse::<u64>().ok()) {
+        Some(minutes) => minutes * 60_000,
+        None => parse_duration(after)?,
+    };
+    if after_ms == 0 {
+        return Err(format!("Idle time must be more than 0: {}", after));
+    }
+
+    let binding = |macro_file: &str| Binding {
+        combo: KeySet::new(),
+        macro_file: macro_file.to_string(),
+        line,
+        cooldown_ms: 0,
+        max_per_minute: None,
+        busy: BusyPolicy::default(),
+        priority: 0,
+        conditions: Vec::new(),
+        confirm: false,
+        sequence: None,
+        gesture: None,
+        press: Press::Single,
+    };
+    let mut idle = Idle {
+        after_ms,
+        repeat: false,
+        binding: binding(macro_file),
+        back: None,
+    };
+
+    let mut binding_options = Vec::new();
+    let mut options = options.iter();
+    while let Some(&option) = options.next() {
+        match option {
+            "repeat" => idle.repeat = true,
+            "back" => {
+                let back = options.next().ok_or("Expected a macro file after 'back'")?;
+                idle.back = Some(binding(back));
+            }
+            _ => binding_options.push(option),
+        }
+    }
+    parse_binding_options(&mut idle.binding, &binding_options)?;
+    if let Some(back) = &mut idle.back {
+        parse_binding_options(back, &binding_options)?;
+    }
+    Ok(idle)
+}
+
+/// Apply `rate 20/s` and `hires` options
+fn parse_scroll_options(scroll: &mut Scroll, options: &[&str]) -> Result<(), String> {
+    let mut options = options.iter();
+    while let Some(&option) = options.next() {
+        match option {
+            "hires" => scroll.hires = true,
+            "rate" => {
+                let value = options.next().copied().unwrap_or_default();
+                scroll.rate = value
+                    .strip_suffix("/s")
+                    .and_then(|rate| rate.parse().ok())
+                    .filter(|&rate| rate > 0)
+                    .ok_or_else(|| format!("Invalid scroll rate '{}', expected e.g. 10/s", value))?;
+            }
+            _ => return Err(format!("Unknown scroll option '{}'", option)),
+        }
+    }
+    Ok(())
+}
+
+/// Apply `cooldown 5s`, `max 10/min`, `busy queue` and `priority 5` style
+/// options, conditions and the `confirm` flag
+fn parse_binding_options(binding: &mut Binding, mut options: &[&str]) -> Result<(), String> {
+    while let [first, rest @ ..] = options {
+        if *first == "confirm" {
+            binding.confirm = true;
+            options = rest;
+            continue;
+        }
+        let (option, rest) = options.split_at(options.len().min(2));
+        options = rest;
+        match option {
+            ["cooldown", value] => binding.cooldown_ms = parse_duration(value)?,
+            ["max", value] => {
+                let count = value
+                    .strip_suffix("/min")
+                    .and_then(|count| count.parse().ok())
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| format!("Invalid rate '{}', expected e.g. 10/min", value))?;
+                binding.max_per_minute = Some(count);
+            }
+            ["busy", value] => binding.busy = value.parse()?,
+            ["priority", value] => {
+                binding.priority = value.parse().map_err(|_| format!("Invalid priority '{}'", value))?;
+            }
+            ["window", pattern] => {
+                let pattern = Regex::new(pattern).map_err(|e| format!("Invalid window pattern '{}': {}", pattern, e))?;
+                binding.conditions.push(Condition::Window(pattern));
+            }
+            ["time", value] => {
+                let invalid = || format!("Invalid time range '{}', expected e.g. 09:00-17:30", value);
+                let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+                let (start, end) = (parse_clock(start).ok_or_else(invalid)?, parse_clock(end).ok_or_else(invalid)?);
+                binding.conditions.push(Condition::Time { start, end });
+            }
+            ["days", value] => binding.conditions.push(Condition::Days(parse_days(value)?)),
+            ["power", value] => {
+                let ac = match *value {
+                    "ac" => true,
+                    "battery" => false,
+                    _ => return Err(format!("Invalid power '{}', use ac/battery", value)),
+                };
+                binding.conditions.push(Condition::Power { ac });
+            }
+            ["lid", value] => {
+                let open = match *value {
+                    "open" => true,
+                    "closed" => false,
+                    _ => return Err(format!("Invalid lid '{}', use open/closed", value)),
+                };
+                binding.conditions.push(Condition::Lid { open });
+            }
+            _ => return Err(format!("Unknown binding option '{}'", option.join(" "))),
+        }
+    }
+    Ok(())
+}
+
+/// Minutes since midnight of `HH:MM`
+fn parse_clock(s: &str) -> Option<u16> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Weekday bits of `mon-fri`, `sat,sun` or a mix like `mon,wed-fri`
+fn parse_days(s: &str) -> Result<u8, String> {
+    let day = |name: &str| {
+        WEEKDAYS
+            .iter()
+            .position(|&day| day == name.to_lowercase())
+            .ok_or_else(|| format!("Unknown day '{}', use mon/tue/wed/thu/fri/sat/sun", name))
+    };
+    let mut days = 0;
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+        // Ranges may wrap around the week, e.g. fri-mon
+        let mut current = first;
+        loop {
+            days |= 1 << current;
+            if current == last {
+                break;
+            }
+            current = (current + 1) % WEEKDAYS.len();
+        }
+    }
+    Ok(days)
+}
+
+/// Parse a combo in shortcut notation, e.g. "CTRL+ALT+F5"
+pub fn parse_combo(combo: &str) -> Result<KeySet, String> {
+    combo
+        .split('+')
+        .map(|name| keymap::name_to_keycode(name.trim()).ok_or_else(|| format!("Unknown key '{}' in '{}'", name, combo)))
+        .collect()
+}
+
+/// EvKey's config directory: `$XDG_CONFIG_HOME/evkey`, or `~/.config/evkey`
+pub fn config_dir() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("evkey"))
+}
+
+/// Where the daemon looks for its config by default
+pub fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("daemon.conf"))
+}
+
+/// Where the system-wide daemon looks for its config by default
+pub const SYSTEM_PATH: &str = "/etc/evkey/daemon.conf";
+
+/// Replace a leading `~/` with the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let text = "\
+# comment
+bind F9 login.macro
+macros /srv/macros
+max-running 2
+panic SHIFT+F12
+
+profile work
+bind CTRL+ALT+F5   /tmp/farm.macro cooldown 2s max 10/min confirm busy interrupt priority -1
+sustain F7 SHIFT+W
+scroll KP8 up
+scroll KP6 right rate 30/s hires
+idle 5m nudge.macro repeat
+idle 90s lock.macro back unlock.macro busy queue
+expand ;addr address.macro cooldown 1s
+";
+        let config = Config::parse(text, Path::new("/etc/evkey")).unwrap();
+        assert_eq!(config.macro_dir, Path::new("/srv/macros"));
+        assert_eq!(config.max_running, 2);
+        assert!(!config.keep_device);
+        let kept = Config::parse("keep-device on\nwarm-up 150ms", Path::new("/")).unwrap();
+        assert_eq!((kept.keep_device, kept.warm_up_ms), (true, 150));
+        assert_eq!(config.max_rate, None);
+        let rate = Config::parse("max-rate 500/s burst 20", Path::new("/")).unwrap().max_rate;
+        assert_eq!(rate, Some(EventRate { per_second: 500, burst: 20 }));
+        assert!(Config::parse("max-rate 500/s burst", Path::new("/")).unwrap_err().starts_with("Line 1:"));
+        let limits = Config::parse("max-keys 10\nmax-text 500", Path::new("/")).unwrap().limits;
+        assert_eq!((limits.max_keys, limits.max_text), (Some(10), Some(500)));
+        assert!(Config::parse("max-text lots", Path::new("/")).unwrap_err().starts_with("Line 1:"));
+        assert_eq!(config.metrics, None);
+        let metrics = Config::parse("metrics 127.0.0.1:9464", Path::new("/")).unwrap().metrics;
+        assert_eq!(metrics.map(|addr| addr.port()), Some(9464));
+        assert!(Config::parse("metrics localhost", Path::new("/")).unwrap_err().starts_with("Line 1:"));
+        let pad = Config::parse("axis ABS_Y below -8000 BTN_DPAD_UP\naxis 5 above 200 F12\nbind BTN_SOUTH+BTN_DPAD_UP jump.macro", Path::new("/")).unwrap();
+        let [up, trigger] = &pad.axes[..] else {
+            panic!("expected two axes");
+        };
+        assert_eq!((up.axis, up.threshold, up.above, up.key), (1, -8000, false, 544));
+        assert!(up.is_pushed(-9000) && !up.is_pushed(-8000) && !up.is_pushed(0));
+        assert_eq!((trigger.axis, trigger.above, trigger.line), (5, true, 2));
+        assert_eq!(keymap::format_combo(&pad.profiles[0].bindings[0].combo), "BTN_DPAD_UP+BTN_SOUTH");
+        let swipe = Config::parse("gesture 4 swipe-left back.macro cooldown 1s", Path::new("/")).unwrap();
+        let back = &swipe.profiles[0].gestures[0];
+        assert_eq!(back.gesture.map(|gesture| gesture.to_string()).as_deref(), Some("4-finger swipe-left"));
+        assert_eq!((back.cooldown_ms, back.combo.is_empty()), (1000, true));
+        assert_eq!(swipe.profiles[0].all_bindings().count(), 1);
+        assert_eq!(keymap::format_combo(config.panic.as_ref().unwrap()), "SHIFT+F12");
+        let names: Vec<_> = config.profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["default", "work"]);
+
+        let login = &config.profiles[0].bindings[0];
+        assert_eq!(login.line, 2);
+        assert_eq!(config.macro_path(login), Path::new("/srv/macros/login.macro"));
+
+        let farm = &config.profiles[1].bindings[0];
+        assert_eq!(keymap::format_combo(&farm.combo), "CTRL+ALT+F5");
+        assert_eq!(config.macro_path(farm), Path::new("/tmp/farm.macro"));
+        assert_eq!((farm.cooldown_ms, farm.max_per_minute, farm.busy), (2000, Some(10), BusyPolicy::Interrupt));
+        assert_eq!((login.cooldown_ms, login.max_per_minute, login.busy), (0, None, BusyPolicy::Ignore));
+        assert_eq!((farm.priority, login.priority), (-1, 0));
+        assert!(farm.conditions.is_empty());
+        assert!(farm.confirm && !login.confirm);
+
+        let sprint = &config.profiles[1].sustains[0];
+        assert_eq!(keymap::format_combo(&sprint.toggle), "F7");
+        assert_eq!(keymap::format_combo(&sprint.keys), "SHIFT+W");
+        assert!(config.profiles[0].sustains.is_empty());
+
+        let [up, right] = &config.profiles[1].scrolls[..] else {
+            panic!("expected two scrolls");
+        };
+        assert_eq!((up.direction, up.rate, up.hires), (ScrollDirection::Up, DEFAULT_SCROLL_RATE, false));
+        assert_eq!((right.direction, right.rate, right.hires, right.line), (ScrollDirection::Right, 30, true, 11));
+
+        let [nudge, lock] = &config.profiles[1].idles[..] else {
+            panic!("expected two idle triggers");
+        };
+        assert_eq!((nudge.after_ms, nudge.repeat, nudge.back.as_ref()), (300_000, true, None));
+        assert!(nudge.binding.combo.is_empty());
+        let unlock = lock.back.as_ref().unwrap();
+        assert_eq!((lock.after_ms, lock.repeat, lock.binding.line), (90_000, false, 13));
+        assert_eq!((unlock.macro_file.as_str(), unlock.busy), ("unlock.macro", BusyPolicy::Queue));
+        assert_eq!(lock.binding.busy, BusyPolicy::Queue);
+
+        let addr = &config.profiles[1].expansions[0];
+        assert_eq!((addr.sequence.as_deref(), addr.macro_file.as_str()), (Some(";addr"), "address.macro"));
+        assert_eq!((addr.cooldown_ms, addr.line), (1000, 14));
+        assert!(addr.combo.is_empty() && login.sequence.is_none());
+    }
+
+    #[test]
+    fn test_profile_inheritance() {
+        let text = "\
+switch-profile CTRL+ALT+P
+bind F9 login.macro
+bind F10 farm.macro
+sustain F7 W
+
+profile game extends default
+bind F10 loadout.macro
+
+profile raid extends game
+sustain F7 SHIFT+W
+";
+        let config = Config::parse(text, Path::new("/")).unwrap();
+        assert_eq!(keymap::format_combo(config.switch_profile.as_ref().unwrap()), "CTRL+ALT+P");
+        let macros = |profile: &Profile| profile.bindings.iter().map(|b| b.macro_file.clone()).collect::<Vec<_>>();
+        assert_eq!(macros(&config.profiles[1]), vec!["loadout.macro", "login.macro"]);
+        assert_eq!(macros(&config.profiles[2]), vec!["loadout.macro", "login.macro"]);
+        assert_eq!(config.profiles[2].extends.as_deref(), Some("game"));
+        assert_eq!(config.profiles[1].sustains[0].line, 4);
+        assert_eq!(config.profiles[2].sustains.len(), 1);
+        assert_eq!(keymap::format_combo(&config.profiles[2].sustains[0].keys), "SHIFT+W");
+
+        let parse = |text| Config::parse(text, Path::new("/"));
+        assert!(parse("profile a extends b").unwrap_err().contains("isn't defined above it"));
+        assert!(parse("profile a b").unwrap_err().contains("Expected 'profile"));
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let text = "\
+panic CTRL+ALT+ESC
+bind F9 login.macro
+bind F9 farm.macro
+bind CTRL+ALT+ESC oops.macro
+bind F8 reply.macro window Mail
+bind F8 other.macro
+sustain F8 W
+bind RIGHTCTRL+F9 chord.macro
+
+expand ;a a.macro
+expand ;ab ab.macro
+expand ;b b.macro window Mail
+expand ;b other.macro
+
+profile game extends default
+scroll F10 down
+";
+        let config = Config::parse(text, Path::new("/")).unwrap();
+        let conflicts: Vec<String> = config.conflicts().iter().map(Conflict::to_string).collect();
+        assert_eq!(
+            conflicts,
+            vec![
+                "Line 3: F9 (farm.macro) never fires in profile 'default': login.macro on line 2 takes the combo first",
+                "Line 4: CTRL+ALT+ESC (oops.macro) never fires in profile 'default': the panic hotkey takes the combo first",
+                "Line 7: F8 (sustain) never fires in profile 'default': other.macro on line 6 takes the combo first",
+                "Line 8: F9 (login.macro on line 2) is also how RIGHTCTRL+F9 (chord.macro on line 8) starts in \
+                 profile 'default': pressing F9 first sets it off whenever RIGHTCTRL+F9 doesn't apply",
+                "Line 11: \";ab\" (ab.macro) never fires in profile 'default': \";a\" (a.macro on line 10) fires first",
+            ]
+        );
+
+        let exact = Config::parse("modifier-sides exact\nbind CTRL+F9 a.macro\nbind RIGHTCTRL+F9 b.macro", Path::new("/"));
+        assert!(exact.unwrap().conflicts().is_empty());
+
+        // A tap, a double tap and a long press on one key; a second long press
+        // doesn't get a turn
+        let taps = "double-tap 250ms\nlong-press 1s\nbind F9 a.macro\nbind F9 b.macro double cooldown 2s\nbind F9 c.macro long\nbind F9 d.macro long";
+        let taps = Config::parse(taps, Path::new("/")).unwrap();
+        assert_eq!((taps.double_tap_ms, taps.long_press_ms), (250, 1000));
+        let presses: Vec<Press> = taps.profiles[0].bindings.iter().map(|binding| binding.press).collect();
+        assert_eq!(presses, vec![Press::Single, Press::Double, Press::Long, Press::Long]);
+        assert_eq!(taps.profiles[0].bindings[1].cooldown_ms, 2000);
+        let conflicts: Vec<usize> = taps.conflicts().iter().map(|conflict| conflict.line).collect();
+        assert_eq!(conflicts, vec![6]);
+    }
+
+    #[test]
+    fn test_binding_conditions() {
+        let text = "bind F9 work.macro window ^Slack\\s time 22:00-06:30 days fri-mon";
+        let config = Config::parse(text, Path::new("/")).unwrap();
+        let binding = &config.profiles[0].bindings[0];
+        assert_eq!(binding.conditions[1], Condition::Time { start: 1320, end: 390 });
+        assert_eq!(binding.conditions[2], Condition::Days(0b111_0001));
+
+        let at = |weekday, hours: u16, minutes| LocalTime { minutes: hours * 60 + minutes, weekday };
+        let mut slack = || Some("Slack | general".to_string());
+        let mut unasked = || -> PowerState { panic!("asked for the power state") };
+        assert!(binding.applies(at(4, 23, 0), &mut slack, &mut unasked));
+        assert!(binding.applies(at(0, 6, 29), &mut slack, &mut unasked));
+        assert!(!binding.applies(at(0, 6, 30), &mut slack, &mut unasked));
+        assert!(!binding.applies(at(2, 23, 0), &mut slack, &mut unasked));
+        assert!(!binding.applies(at(4, 23, 0), &mut || Some("Firefox".to_string()), &mut unasked));
+        assert!(!binding.applies(at(4, 23, 0), &mut || None, &mut unasked));
+
+        // Without conditions the window isn't even asked for
+        let plain = Config::parse("bind F9 a.macro", Path::new("/")).unwrap();
+        let plain = &plain.profiles[0].bindings[0];
+        assert!(plain.applies(at(2, 12, 0), &mut || panic!("asked for the window"), &mut unasked));
+
+        // An idle trigger that keeps quiet on battery or with the lid closed
+        let laptop = Config::parse("idle 5m nudge.macro power ac lid open", Path::new("/")).unwrap();
+        let idle = &laptop.profiles[0].idles[0].binding;
+        assert_eq!(idle.conditions, vec![Condition::Power { ac: true }, Condition::Lid { open: true }]);
+        let state = |on_ac, lid_open| move || PowerState { on_ac, lid_open };
+        assert!(idle.applies(at(2, 12, 0), &mut || None, &mut state(true, true)));
+        assert!(!idle.applies(at(2, 12, 0), &mut || None, &mut state(false, true)));
+        assert!(!idle.applies(at(2, 12, 0), &mut || None, &mut state(true, false)));
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let parse = |text| Config::parse(text, Path::new("/"));
+        assert_eq!(parse("bind CTRL+NOPE x.macro").unwrap_err(), "Line 1: Unknown key 'NOPE' in 'CTRL+NOPE'");
+        assert!(parse("\nbind F9").unwrap_err().starts_with("Line 2: Expected"));
+        assert!(parse("profile a\nprofile a").unwrap_err().contains("Duplicate profile"));
+        assert!(parse("autostart yes").unwrap_err().contains("Unknown setting"));
+        assert!(parse("bind F9 a.macro max 0/min").unwrap_err().contains("Invalid rate"));
+        assert!(parse("bind F9 a.macro busy wait").unwrap_err().contains("Unknown busy policy"));
+        assert!(parse("bind F9 a.macro cooldown").unwrap_err().contains("Unknown binding option"));
+        assert!(parse("bind F9 a.macro priority high").unwrap_err().contains("Invalid priority"));
+        assert!(parse("max-running 0").unwrap_err().contains("Invalid max-running"));
+        assert!(parse("keep-device yes").unwrap_err().contains("Invalid keep-device"));
+        assert!(parse("sustain F7").unwrap_err().contains("Expected 'sustain"));
+        assert!(parse("scroll KP2 sideways").unwrap_err().contains("Unknown scroll direction"));
+        assert!(parse("scroll KP2 down rate 0/s").unwrap_err().contains("Invalid scroll rate"));
+        assert!(parse("scroll KP2 down rate").unwrap_err().contains("Invalid scroll rate"));
+        assert!(parse("scroll KP2 down smooth").unwrap_err().contains("Unknown scroll option"));
+        assert_eq!(parse("").unwrap().panic, Some(parse_combo(DEFAULT_PANIC).unwrap()));
+        assert_eq!(parse("panic none").unwrap().panic, None);
+        assert!(parse("bind F9 a.macro window (").unwrap_err().contains("Invalid window pattern"));
+        assert!(parse("bind F9 a.macro time 9-17").unwrap_err().contains("Invalid time range"));
+        assert!(parse("bind F9 a.macro time 09:00-24:00").unwrap_err().contains("Invalid time range"));
+        assert!(parse("bind F9 a.macro days weekdays").unwrap_err().contains("Unknown day"));
+        assert!(parse("idle 5m").unwrap_err().contains("Expected 'idle"));
+        assert!(parse("idle 0m a.macro").unwrap_err().contains("more than 0"));
+        assert!(parse("idle 5 a.macro").unwrap_err().contains("Duration must end"));
+        assert!(parse("idle 5m a.macro back").unwrap_err().contains("after 'back'"));
+        assert!(parse("idle 5m a.macro forever").unwrap_err().contains("Unknown binding option"));
+        assert!(parse("bind F9 a.macro power mains").unwrap_err().contains("use ac/battery"));
+        assert!(parse("bind F9 a.macro lid shut").unwrap_err().contains("use open/closed"));
+        assert!(parse("modifier-sides left").unwrap_err().contains("Invalid modifier-sides"));
+        assert!(parse("expand ;addr").unwrap_err().contains("Expected 'expand"));
+        assert!(parse("axis ABS_X 8000 F1").unwrap_err().contains("Expected 'axis"));
+        assert!(parse("gesture 3 swipe-up").unwrap_err().contains("Expected 'gesture"));
+        assert!(parse("gesture 1 pinch-in a.macro").unwrap_err().contains("Invalid finger count"));
+        assert!(parse("axis ABS_W above 8000 F1").unwrap_err().contains("Unknown axis"));
+        assert!(parse("axis ABS_X beyond 8000 F1").unwrap_err().contains("Invalid axis side"));
+        assert!(parse("axis ABS_X above far F1").unwrap_err().contains("Invalid axis value"));
+        assert!(parse("modifier-sides exact").unwrap().exact_sides);
+        assert!(!parse("").unwrap().exact_sides);
+    }
+}
@@ -0,0 +1,2355 @@
+//! Background service playing macros on hotkeys
+//!
+//! The daemon watches every keyboard for the combos bound in the active
+//! profile of its [`Config`] and plays the bound macro on a worker thread once
+//! the combo is released, so the hotkey's own keys don't leak into playback.
+//! Held keys are matched with [`keymap::combo_matches`], which by default
+//! doesn't care which Ctrl (Shift, ...) is down or whether caps lock is on.
+//!
+//! Bound macros are loaded up front and reloaded whenever their files change,
+//! as is the config itself; playbacks already running keep the version they
+//! started with. A config with errors is reported and the previous one kept.
+//!
+//! A binding with a `double` or `long` variant on the same combo waits to see
+//! how the combo is pressed: held for `long-press`, it plays the long one on
+//! release; tapped twice within `double-tap`, the double one. A single tap
+//! then plays once the double-tap window has passed, instead of on release.
+//!
+//! A `confirm` binding only plays when its hotkey is pressed a second time
+//! within a couple of seconds; the first press shows a notification asking
+//! for it, and pressing another binding's hotkey instead cancels.
+//!
+//! Each binding's cooldown and rate limit are checked when its combo is
+//! released. At most `max-running` macros play at once, so triggers don't
+//! interleave their events; beyond that, a trigger stops running macros of
+//! lower priority, and otherwise its busy policy decides whether it is
+//! dropped, queued (by priority, then in order) or interrupts them all.
+//! Triggers that are turned away show up in the history as skipped.
+//!
+//! Each macro plays on a virtual device created for it, unless `keep-device`
+//! is on: then one is created at startup and every macro plays on it, so the
+//! desktop doesn't pick up a new device (and maybe miss its first events)
+//! each time. `warm-up` gives a new device a moment before it's played on.
+//!
+//! Gamepads are watched along with the keyboards, and their buttons count as
+//! keys. An `axis` line turns a stick or trigger pushed past a threshold into
+//! a key held down, so it can be part of combos like any button.
+//!
+//! With the `gestures` feature, touchpad swipes and pinches play the macros
+//! bound to them too, read through libinput while a profile has any.
+//!
+//! Sustain combos toggle holding keys down on a virtual device of their own,
+//! e.g. to keep walking in a game without keeping a finger on W. Scroll
+//! combos turn the wheel of that device for as long as they are held.
+//!
+//! Idle triggers play a macro once no keyboard or mouse has sent anything for
+//! a while (playback devices don't count), and optionally another when input
+//! comes back. Mice are watched only for that, and for clicks, which like
+//! Ctrl/Alt shortcuts and keys that don't type forget the text typed so far.
+//!
+//! Expansions play when their text is typed at the start of a word, after
+//! backspacing over it. Typed keys are read as characters of the keyboard
+//! layout in use when the daemon started.
+//!
+//! The `switch-profile` hotkey (or `evkey profile`) changes the active profile,
+//! announcing it with a desktop notification. Sustains, scrolling and idle
+//! state belong to the profile and end with it; running macros carry on.
+//!
+//! The panic hotkey (or `evkey stop-all`) stops every playback, releasing the
+//! keys it held (sustained ones too), drops the queue and turns hotkeys off
+//! until it is pressed again (or `evkey resume`).
+//!
+//! Other EvKey commands talk to it over a Unix socket at
+//! `$XDG_RUNTIME_DIR/evkey.sock`: a client sends one command per connection
+//! (e.g. `status`) and reads the reply until the daemon closes the socket.
+//! With `metrics`, it also answers Prometheus scrapes over HTTP (see
+//! [`crate::metrics`]).
+//!
+//! [`Daemon::system`] runs it once for the whole machine instead, for kiosks
+//! and shared workstations: as root, listening on [`SYSTEM_SOCKET_PATH`],
+//! which any user can reach. Users opt their login session in with `session
+//! start` (see [`crate::logind`]), and macros, sustains and scrolling go to
+//! the seat of the keyboard last pressed only while the session active on it
+//! opted in, on a device of that seat's (see [`crate::seat`]). When another
+//! session takes a seat over, what was playing there stops. Only root and the
+//! user of an active session that opted in may change the daemon's state,
+//! stopping every macro included; anyone may look at it.
+
+use crate::audit::AuditBackend;
+use crate::backend::{self, Backend, BackendKind, SharedBackend, UinputBackend};
+use crate::config::{Binding, BusyPolicy, CONFIRM_WINDOW_MS, Config, LocalTime, Press, Scroll, ScrollDirection, Sustain};
+#[cfg(feature = "gestures")]
+use crate::gesture::GestureReader;
+use crate::guard::Extent;
+use crate::hooks;
+use crate::event::{EventType, InputEvent};
+use crate::keymap;
+use crate::keyset::KeySet;
+use crate::layout;
+use crate::locks::{KEY_CAPSLOCK, LockState};
+use crate::logind;
+use crate::metrics::{self, Metrics};
+use crate::player::{Player, Progress};
+use crate::power;
+use crate::recorder;
+use crate::screen;
+use crate::seat;
+use crate::state::MacroState;
+use crate::storage::{self, Macro};
+use crate::throttle::EventRate;
+use crate::typing::CharMap;
+use crate::watch::Watcher;
+use evdev::{Device, EventSummary};
+use nix::poll::{PollFd, PollFlags, poll};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Name of the virtual device macros are played on; the daemon ignores it
+/// when looking for keyboards
+const PLAYBACK_DEVICE: &str = "evkey-playback";
+/// Name of the virtual device sustained keys are held on and scrolling is
+/// done with
+const OUTPUT_DEVICE: &str = "evkey-daemon";
+
+/// How often a held scroll combo sends wheel events
+const SCROLL_TICK: Duration = Duration::from_millis(10);
+/// Hi-res wheel units in one notch
+const HIRES_PER_NOTCH: i64 = 120;
+
+/// Triggers kept for `evkey status`
+const HISTORY_LEN: usize = 20;
+
+/// How often the system-wide daemon looks at which sessions are active
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the system-wide daemon's control socket lives
+pub const SYSTEM_SOCKET_PATH: &str = "/run/evkey.sock";
+
+/// Window `max <n>/min` rate limits are counted over
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a client may take to send its whole request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longest command a control client may send
+const MAX_COMMAND_BYTES: u64 = 1024;
+
+/// Most characters of recent typing kept for expansions
+const TYPED_LEN: usize = 64;
+/// How long each backspace erasing an expansion's text is held, and the gap
+/// after it (in milliseconds)
+const ERASE_INTERVAL_MS: u64 = 10;
+
+const KEY_BACKSPACE: u16 = 14;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_LEFTALT: u16 = 56;
+const KEY_LEFTMETA: u16 = 125;
+
+/// Where the control socket lives: `$XDG_RUNTIME_DIR/evkey.sock`, or else in
+/// a directory of the user's own under /tmp
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("evkey.sock"),
+        None => {
+            // Safety: getuid can't fail
+            let uid = unsafe { libc::getuid() };
+            PathBuf::from(format!("/tmp/evkey-{}", uid)).join("evkey.sock")
+        }
+    }
+}
+
+/// Make sure only this user can get at the directory the socket at `path` is
+/// in, creating it (private) if need be; another user's directory, or one
+/// others can write to, could have the socket swapped from under us
+fn secure_socket_dir(path: &Path) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(io::Error::new(e.kind(), format!("Can't create {}: {}", dir.display(), e))),
+    }
+    let metadata = std::fs::symlink_metadata(dir)?;
+    // Safety: getuid can't fail
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} isn't a directory only you can use, so the control socket can't go there (set XDG_RUNTIME_DIR)",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Send a command to the running daemon and return its reply: the user's
+/// own, or else the system-wide one
+pub fn request(command: &str) -> io::Result<String> {
+    let path = socket_path();
+    if !path.exists() && Path::new(SYSTEM_SOCKET_PATH).exists() {
+        return request_at(Path::new(SYSTEM_SOCKET_PATH), command);
+    }
+    request_at(&path, command)
+}
+
+/// Send a command to the daemon listening on `path` and return its reply
+pub fn request_at(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("EvKey daemon isn't running (can't connect to {}: {})", path.display(), e),
+        )
+    })?;
+    writeln!(stream, "{}", command)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// How a triggered macro ended up
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Waiting for other macros to finish
+    Queued,
+    Running,
+    Finished(Duration),
+    /// Interrupted by another binding
+    Stopped,
+    Failed(String),
+    /// Turned away by the binding's limits
+    Skipped(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Queued => write!(f, "queued"),
+            Outcome::Running => write!(f, "running"),
+            Outcome::Finished(took) => write!(f, "finished in {:.1}s", took.as_secs_f64()),
+            Outcome::Stopped => write!(f, "stopped"),
+            Outcome::Failed(error) => write!(f, "failed: {}", error),
+            Outcome::Skipped(reason) => write!(f, "skipped: {}", reason),
+        }
+    }
+}
+
+/// A macro being played, as shown by `evkey status`
+#[derive(Debug, Clone)]
+pub struct RunningStatus {
+    pub id: u64,
+    pub combo: String,
+    pub macro_file: String,
+    /// Latest progress report, if playback got far enough to send one
+    pub progress: Option<Progress>,
+}
+
+/// A past trigger, as shown by `evkey status`
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub ago: Duration,
+    pub combo: String,
+    pub macro_file: String,
+    pub outcome: Outcome,
+}
+
+/// A session that opted in to the system-wide daemon, as shown by `evkey
+/// status`
+#[derive(Debug, Clone)]
+pub struct SessionStatus {
+    pub id: String,
+    pub user: String,
+    pub seat: Option<String>,
+    /// Active on its seat, so macros play into it
+    pub active: bool,
+    /// Profile the daemon switches to when the session becomes active
+    pub profile: Option<String>,
+}
+
+/// Snapshot of the daemon, rendered as the reply to `status`
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub config_path: PathBuf,
+    pub profiles: Vec<String>,
+    pub active_profile: usize,
+    /// Keys being held by sustain combos
+    pub sustained: Vec<String>,
+    /// Hotkeys are off after a panic
+    pub disabled: bool,
+    pub panic: Option<String>,
+    /// Combos of the active profile with their macro files
+    pub bindings: Vec<(String, String)>,
+    pub running: Vec<RunningStatus>,
+    /// Most recent first
+    pub history: Vec<HistoryEntry>,
+    /// Config and macro files that failed to load
+    pub problems: Vec<String>,
+    /// Sessions that opted in; `None` unless running system-wide
+    pub sessions: Option<Vec<SessionStatus>>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scope = if self.sessions.is_some() { " (system-wide)" } else { "" };
+        writeln!(f, "EvKey daemon{} - {}", scope, self.config_path.display())?;
+
+        if self.disabled {
+            writeln!(f, "\nHotkeys are off after stop-all; run `evkey resume` or press the panic hotkey")?;
+        }
+
+        if !self.problems.is_empty() {
+            writeln!(f, "\nProblems:")?;
+            for problem in &self.problems {
+                writeln!(f, "  {}", problem)?;
+            }
+        }
+
+        let profiles: Vec<String> = self
+            .profiles
+            .iter()
+            .enumerate()
+            .map(|(index, name)| if index == self.active_profile { format!("[{}]", name) } else { name.clone() })
+            .collect();
+        writeln!(f, "\nProfiles: {}", if profiles.is_empty() { "none".to_string() } else { profiles.join(" ") })?;
+        writeln!(f, "Panic hotkey: {}", self.panic.as_deref().unwrap_or("none"))?;
+        if !self.sustained.is_empty() {
+            writeln!(f, "Sustained: {}", self.sustained.join(", "))?;
+        }
+
+        if let Some(sessions) = &self.sessions {
+            writeln!(f, "\nSessions:")?;
+            for session in sessions {
+                write!(f, "  {}  {}  {}", session.id, session.user, session.seat.as_deref().unwrap_or("no seat"))?;
+                if session.active {
+                    write!(f, "  active")?;
+                }
+                if let Some(profile) = &session.profile {
+                    write!(f, "  profile {}", profile)?;
+                }
+                writeln!(f)?;
+            }
+            if sessions.is_empty() {
+                writeln!(f, "  (none; users opt in with `evkey session start`)")?;
+            }
+        }
+
+        writeln!(f, "\nBindings:")?;
+        let width = self.bindings.iter().map(|(combo, _)| combo.len()).max().unwrap_or(0);
+        for (combo, macro_file) in &self.bindings {
+            writeln!(f, "  {:<width$}  {}", combo, macro_file)?;
+        }
+        if self.bindings.is_empty() {
+            writeln!(f, "  (none)")?;
+        }
+
+        writeln!(f, "\nRunning:")?;
+        for running in &self.running {
+            write!(f, "  #{} {} ({})", running.id, running.macro_file, running.combo)?;
+            if let Some(progress) = running.progress {
+                write!(
+                    f,
+                    "  {:>3.0}%  {} elapsed, {} left",
+                    progress.fraction() * 100.0,
+                    format_duration(progress.elapsed),
+                    format_duration(progress.remaining())
+                )?;
+                if let Some(state) = progress.state_index {
+                    write!(f, ", state {}", state)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        if self.running.is_empty() {
+            writeln!(f, "  (none)")?;
+        }
+
+        writeln!(f, "\nRecent triggers:")?;
+        for entry in &self.history {
+            writeln!(
+                f,
+                "  {:>8}  {}  {}  {}",
+                format!("{} ago", format_duration(entry.ago)),
+                entry.combo,
+                entry.macro_file,
+                entry.outcome
+            )?;
+        }
+        if self.history.is_empty() {
+            writeln!(f, "  (none)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Current local time, for binding conditions
+fn local_time() -> LocalTime {
+    // Safety: `time` accepts a null pointer, and `localtime_r` only writes to
+    // the `tm` it is given
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    LocalTime {
+        minutes: (tm.tm_hour * 60 + tm.tm_min) as u16,
+        // tm_wday counts from Sunday
+        weekday: ((tm.tm_wday + 6) % 7) as u8,
+    }
+}
+
+/// What set off a binding, for logs and `evkey status`: its combo, its
+/// quoted text for expansions, its gesture, or "idle"
+fn trigger_name(binding: &Binding) -> String {
+    if let Some(sequence) = &binding.sequence {
+        format!("\"{}\"", sequence)
+    } else if let Some(gesture) = &binding.gesture {
+        gesture.to_string()
+    } else if binding.combo.is_empty() {
+        "idle".to_string()
+    } else if binding.press != Press::Single {
+        format!("{} ({})", keymap::display_combo(&binding.combo), binding.press)
+    } else {
+        keymap::display_combo(&binding.combo)
+    }
+}
+
+/// Short human duration: "45s", "3m12s", "2h05m"
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// A macro playing on its own thread
+struct Playback {
+    id: u64,
+    combo: String,
+    macro_file: String,
+    /// Seat it plays into
+    seat: String,
+    priority: i32,
+    started: Instant,
+    progress: Arc<Mutex<Option<Progress>>>,
+    /// Set to make the playback stop early
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+/// A combo pressed, waiting for the keys to be released
+enum Pending {
+    Play(Binding),
+    Taps(Box<Taps>),
+    Toggle(Sustain),
+}
+
+/// The bindings a pressed combo can set off, depending on how it's pressed
+struct Taps {
+    single: Option<Binding>,
+    double: Option<Binding>,
+    long: Option<Binding>,
+    /// When the combo went down
+    pressed: Instant,
+    /// The combo was tapped just before, so this is a double tap
+    second: bool,
+}
+
+/// A scroll combo being held
+struct Scrolling {
+    scroll: Scroll,
+    started: Instant,
+    last_tick: Instant,
+    /// Hi-res units and notches sent so far
+    sent: (i64, i64),
+}
+
+struct Trigger {
+    id: u64,
+    at: Instant,
+    combo: String,
+    macro_file: String,
+    outcome: Outcome,
+}
+
+pub struct Daemon {
+    config: Config,
+    config_path: PathBuf,
+    active_profile: usize,
+    keyboards: Vec<Device>,
+    /// Seat of each keyboard
+    keyboard_seats: Vec<String>,
+    /// Seat of the keyboard last pressed, which macros play into
+    input_seat: String,
+    /// Mice, watched only to tell whether the user is idle
+    pointers: Vec<Device>,
+    /// When a keyboard or mouse last sent anything
+    last_input: Instant,
+    /// When each idle trigger that went off since the last input last played,
+    /// by config line
+    idle_fired: HashMap<usize, Instant>,
+    /// Keys held across all keyboards
+    held: KeySet,
+    /// Keys held by sticks and triggers past their `axis` threshold
+    axis_held: KeySet,
+    /// Text typed since the last click, shortcut or key that doesn't type
+    typed: String,
+    /// Characters of the keyboard layout, to read typing with
+    chars: CharMap,
+    pending: Option<Pending>,
+    /// A combo with a double-tap binding, tapped once and released at the
+    /// given time, whose single tap waits for a second one not to come
+    tapped: Option<(Taps, Instant)>,
+    /// `confirm` binding pressed once, by config line, with when
+    armed: Option<(usize, Instant)>,
+    /// Sustains that are on, holding their keys down
+    sustained: Vec<Sustain>,
+    scrolling: Option<Scrolling>,
+    /// Opened the first time a sustain or scroll needs it, with the seat it's
+    /// on
+    output: Option<(String, Box<dyn Backend>)>,
+    /// Device every macro plays on, with `keep-device`
+    playback_device: Option<SharedBackend>,
+    /// Touchpads, opened while a profile binds gestures
+    #[cfg(feature = "gestures")]
+    touchpads: Option<GestureReader>,
+    /// Hotkeys are off after a panic
+    disabled: bool,
+    playbacks: Vec<Playback>,
+    /// Triggers waiting for the running playbacks to end, with their ids
+    queue: VecDeque<(u64, Binding)>,
+    /// Recent activations of each binding, by config line
+    activations: HashMap<usize, VecDeque<Instant>>,
+    history: VecDeque<Trigger>,
+    next_id: u64,
+    /// Bound macros by path, or why they couldn't be loaded
+    library: HashMap<PathBuf, Result<Arc<Macro>, String>>,
+    /// Why the config on disk was rejected, while the previous one stays in use
+    config_error: Option<String>,
+    /// Overlapping triggers in the config in use
+    conflicts: Vec<String>,
+    watcher: Watcher,
+    listener: UnixListener,
+    socket_path: PathBuf,
+    /// Counters for `metrics`, shared with the playback threads
+    metrics: Arc<Mutex<Metrics>>,
+    /// Where metrics are served, with `metrics`
+    metrics_listener: Option<(SocketAddr, TcpListener)>,
+    /// Running system-wide, for the sessions that opted in
+    system: bool,
+    /// Sessions that opted in, by id, with the profile they asked for
+    sessions: BTreeMap<String, (logind::Session, Option<String>)>,
+    /// Session logind has active on each seat, as `(seat, session id)`
+    active_sessions: Vec<(String, String)>,
+    sessions_checked: Option<Instant>,
+}
+
+impl Daemon {
+    /// Load the config, open keyboards and start listening for clients
+    pub fn new(config_path: &Path) -> io::Result<Self> {
+        Self::open(config_path, false)
+    }
+
+    /// The daemon for the whole machine, playing into the sessions that opt
+    /// in, on [`SYSTEM_SOCKET_PATH`]
+    pub fn system(config_path: &Path) -> io::Result<Self> {
+        Self::open(config_path, true)
+    }
+
+    fn open(config_path: &Path, system: bool) -> io::Result<Self> {
+        let config_path = std::path::absolute(config_path)?;
+        let config = load_config(&config_path)?;
+        let mut watcher = Watcher::new()?;
+        watcher.watch_file(&config_path)?;
+
+        let mut keyboards = Vec::new();
+        let mut keyboard_seats = Vec::new();
+        let mut pointers = Vec::new();
+        for device in recorder::find_input_devices()? {
+            if device.name.starts_with("evkey") {
+                continue;
+            }
+            let opened = Device::open(&device.path)?;
+            opened.set_nonblocking(true)?;
+            info!("Watching {} ({})", device.name, device.path.display());
+            if device.kind.contains("keyboard") {
+                keyboards.push(opened);
+                keyboard_seats.push(seat::of_device(&device.path));
+            } else {
+                pointers.push(opened);
+            }
+        }
+        if keyboards.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No keyboards found (are you allowed to read /dev/input?)",
+            ));
+        }
+
+        let current = layout::current().map_err(|e| e.to_string());
+        let chars = match current.and_then(|current| CharMap::for_recording(Some(&current))) {
+            Ok(chars) => chars,
+            Err(e) => {
+                if config.profiles.iter().any(|profile| !profile.expansions.is_empty()) {
+                    warn!("Reading typing for expansions as the US layout: {}", e);
+                }
+                CharMap::us()
+            }
+        };
+
+        let socket_path = if system { PathBuf::from(SYSTEM_SOCKET_PATH) } else { socket_path() };
+        if !system {
+            secure_socket_dir(&socket_path)?;
+        }
+        let listener = bind_socket(&socket_path)?;
+        listener.set_nonblocking(true)?;
+        if system {
+            // Every user talks to it; what they may do is checked per command
+            std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o666))?;
+        }
+        info!("Listening on {}", socket_path.display());
+
+        let mut daemon = Self {
+            config,
+            config_path,
+            active_profile: 0,
+            keyboards,
+            keyboard_seats,
+            input_seat: seat::DEFAULT_SEAT.to_string(),
+            pointers,
+            last_input: Instant::now(),
+            idle_fired: HashMap::new(),
+            held: KeySet::new(),
+            axis_held: KeySet::new(),
+            typed: String::new(),
+            chars,
+            pending: None,
+            tapped: None,
+            armed: None,
+            sustained: Vec::new(),
+            scrolling: None,
+            output: None,
+            playback_device: None,
+            #[cfg(feature = "gestures")]
+            touchpads: None,
+            disabled: false,
+            playbacks: Vec::new(),
+            queue: VecDeque::new(),
+            activations: HashMap::new(),
+            history: VecDeque::new(),
+            next_id: 1,
+            library: HashMap::new(),
+            config_error: None,
+            conflicts: Vec::new(),
+            watcher,
+            listener,
+            socket_path,
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+            metrics_listener: None,
+            system,
+            sessions: BTreeMap::new(),
+            active_sessions: Vec::new(),
+            sessions_checked: None,
+        };
+        daemon.check_conflicts();
+        daemon.load_library();
+        daemon.keep_device();
+        daemon.serve_metrics();
+        daemon.watch_gestures();
+        Ok(daemon)
+    }
+
+    /// Create or drop the device macros play on, as `keep-device` says
+    fn keep_device(&mut self) {
+        if !self.config.keep_device {
+            self.playback_device = None;
+            return;
+        }
+        if self.playback_device.is_some() {
+            return;
+        }
+        match UinputBackend::new(PLAYBACK_DEVICE) {
+            Ok(mut device) => {
+                info!("Keeping {} for every macro", PLAYBACK_DEVICE);
+                // The same pre-roll a fresh device gets before its macro
+                if let Err(e) = device.emit(&[InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)]) {
+                    warn!("Can't sync the kept playback device: {}", e);
+                }
+                thread::sleep(Duration::from_millis(self.config.warm_up_ms));
+                self.playback_device = Some(SharedBackend::new(Box::new(device)));
+            }
+            Err(e) => warn!("Can't keep a playback device, creating one per macro: {}", e),
+        }
+    }
+
+    /// Start or stop serving metrics, as `metrics` says
+    fn serve_metrics(&mut self) {
+        let Some(addr) = self.config.metrics else {
+            self.metrics_listener = None;
+            return;
+        };
+        if self.metrics_listener.as_ref().is_some_and(|(serving, _)| *serving == addr) {
+            return;
+        }
+        self.metrics_listener = None;
+        match TcpListener::bind(addr).and_then(|listener| listener.set_nonblocking(true).map(|()| listener)) {
+            Ok(listener) => {
+                info!("Serving metrics at http://{}/metrics", addr);
+                self.metrics_listener = Some((addr, listener));
+            }
+            Err(e) => warn!("Can't serve metrics on {}: {}", addr, e),
+        }
+    }
+
+    /// Serve hotkeys and clients until SIGINT or SIGTERM
+    pub fn run(&mut self) -> io::Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register(signal, Arc::clone(&stop))?;
+        }
+
+        while !stop.load(Ordering::Relaxed) {
+            self.poll_keyboards();
+            self.check_taps();
+            #[cfg(feature = "gestures")]
+            self.check_gestures();
+            self.check_idle();
+            self.check_sessions();
+            self.scroll();
+            self.reload_changed();
+            self.reap_playbacks();
+            self.serve_clients();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        info!("Shutting down");
+        Ok(())
+    }
+
+    /// Snapshot for `evkey status`
+    pub fn status(&self) -> Status {
+        let bindings = self
+            .config
+            .profiles
+            .get(self.active_profile)
+            .map(|profile| {
+                let mut bindings: Vec<(String, String)> =
+                    profile.bindings.iter().map(|b| (trigger_name(b), b.macro_file.clone())).collect();
+                for idle in &profile.idles {
+                    let after = format_duration(Duration::from_millis(idle.after_ms));
+                    bindings.push((format!("idle {}", after), idle.binding.macro_file.clone()));
+                    if let Some(back) = &idle.back {
+                        bindings.push((format!("back from idle {}", after), back.macro_file.clone()));
+                    }
+                }
+                bindings.extend(profile.expansions.iter().map(|e| (trigger_name(e), e.macro_file.clone())));
+                bindings.extend(profile.gestures.iter().map(|g| (trigger_name(g), g.macro_file.clone())));
+                bindings
+            })
+            .unwrap_or_default();
+
+        Status {
+            config_path: self.config_path.clone(),
+            profiles: self.config.profiles.iter().map(|p| p.name.clone()).collect(),
+            active_profile: self.active_profile,
+            sustained: self.sustained.iter().map(|sustain| keymap::display_combo(&sustain.keys)).collect(),
+            disabled: self.disabled,
+            panic: self.config.panic.as_ref().map(keymap::display_combo),
+            bindings,
+            running: self
+                .playbacks
+                .iter()
+                .map(|playback| RunningStatus {
+                    id: playback.id,
+                    combo: playback.combo.clone(),
+                    macro_file: playback.macro_file.clone(),
+                    progress: *playback.progress.lock().unwrap(),
+                })
+                .collect(),
+            history: self
+                .history
+                .iter()
+                .map(|trigger| HistoryEntry {
+                    ago: trigger.at.elapsed(),
+                    combo: trigger.combo.clone(),
+                    macro_file: trigger.macro_file.clone(),
+                    outcome: trigger.outcome.clone(),
+                })
+                .collect(),
+            problems: self
+                .config_error
+                .iter()
+                .chain(&self.conflicts)
+                .cloned()
+                .chain(self.library.iter().filter_map(|(path, loaded)| {
+                    loaded.as_ref().err().map(|e| format!("{}: {}", path.display(), e))
+                }))
+                .collect(),
+            sessions: self.system.then(|| {
+                self.sessions
+                    .iter()
+                    .map(|(id, (session, profile))| SessionStatus {
+                        id: id.clone(),
+                        user: session.user.clone(),
+                        seat: session.seat.clone(),
+                        active: self.active_sessions.iter().any(|(_, active)| active == id),
+                        profile: profile.clone(),
+                    })
+                    .collect()
+            }),
+        }
+    }
+
+    /// Warn about triggers in the config that get in each other's way
+    fn check_conflicts(&mut self) {
+        self.conflicts = self
+            .config
+            .conflicts()
+            .iter()
+            .map(|conflict| format!("{}: {}", self.config_path.display(), conflict))
+            .collect();
+        for conflict in &self.conflicts {
+            warn!("{}", conflict);
+        }
+    }
+
+    /// Load the macros bound in any profile, keeping ones already loaded
+    fn load_library(&mut self) {
+        let mut paths: Vec<PathBuf> = self
+            .config
+            .profiles
+            .iter()
+            .flat_map(|profile| profile.all_bindings())
+            .map(|binding| self.config.macro_path(binding))
+            .collect();
+        paths.sort();
+        paths.dedup();
+
+        self.library.retain(|path, _| paths.contains(path));
+        for path in paths {
+            if !self.library.contains_key(&path) {
+                self.load_macro(&path);
+            }
+            if let Err(e) = self.watcher.watch_file(&path) {
+                warn!("Can't watch {} for changes: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn load_macro(&mut self, path: &Path) {
+        let loaded = storage::load_macro(path).map_err(|e| e.to_string()).and_then(|macro_| {
+            let extent = Extent::of_playback(&macro_.events(), &macro_.timed_actions());
+            match self.config.limits.check(&extent) {
+                Ok(()) => Ok(Arc::new(macro_)),
+                Err(exceeded) => Err(format!("Refusing to play a macro that {}", exceeded)),
+            }
+        });
+        if let Err(e) = &loaded {
+            warn!("Can't load {}: {}", path.display(), e);
+        }
+        self.library.insert(path.to_path_buf(), loaded);
+    }
+
+    /// Pick up changes to the config and bound macros
+    fn reload_changed(&mut self) {
+        let changed = match self.watcher.changed() {
+            Ok(changed) => changed,
+            Err(e) => {
+                warn!("Can't check for changed files: {}", e);
+                return;
+            }
+        };
+
+        for path in changed {
+            if path == self.config_path {
+                self.reload_config();
+            } else if self.library.contains_key(&path) {
+                info!("Reloading {}", path.display());
+                self.load_macro(&path);
+            }
+        }
+    }
+
+    fn reload_config(&mut self) {
+        let config = match load_config(&self.config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Keeping the previous config: {}", e);
+                self.config_error = Some(e.to_string());
+                return;
+            }
+        };
+
+        info!("Reloading {}", self.config_path.display());
+        // Macros were checked against the old limits
+        if config.limits != self.config.limits {
+            self.library.clear();
+        }
+        // Stay on the same profile if it still exists
+        let active = self.config.profiles.get(self.active_profile).map(|p| p.name.clone());
+        self.active_profile = active
+            .and_then(|name| config.profiles.iter().position(|p| p.name == name))
+            .unwrap_or(0);
+        self.config = config;
+        self.config_error = None;
+        self.check_conflicts();
+        self.pending = None;
+        self.tapped = None;
+        self.typed.clear();
+        self.armed = None;
+        self.release_sustained();
+        self.scrolling = None;
+        self.activations.clear();
+        self.idle_fired.clear();
+        self.keep_device();
+        self.serve_metrics();
+        self.watch_gestures();
+        self.load_library();
+    }
+
+    /// Open the touchpads if a profile binds gestures, and close them if none
+    /// does
+    #[cfg(feature = "gestures")]
+    fn watch_gestures(&mut self) {
+        if !self.config.profiles.iter().any(|profile| !profile.gestures.is_empty()) {
+            self.touchpads = None;
+            return;
+        }
+        if self.touchpads.is_some() {
+            return;
+        }
+        match GestureReader::open() {
+            Ok(touchpads) => self.touchpads = Some(touchpads),
+            Err(e) => warn!("Gestures won't trigger anything: {}", e),
+        }
+    }
+
+    #[cfg(not(feature = "gestures"))]
+    fn watch_gestures(&mut self) {
+        if self.config.profiles.iter().any(|profile| !profile.gestures.is_empty()) {
+            warn!("Gestures won't trigger anything: EvKey was built without the gestures feature");
+        }
+    }
+
+    /// Play the bindings of gestures the touchpads finished
+    #[cfg(feature = "gestures")]
+    fn check_gestures(&mut self) {
+        let Some(touchpads) = &mut self.touchpads else {
+            return;
+        };
+        let gestures = match touchpads.read() {
+            Ok(gestures) => gestures,
+            Err(e) => {
+                warn!("Can't read gestures: {}", e);
+                self.touchpads = None;
+                return;
+            }
+        };
+        for gesture in gestures {
+            debug!("{}", gesture);
+            if self.disabled {
+                continue;
+            }
+            let Some(profile) = self.config.profiles.get(self.active_profile) else {
+                return;
+            };
+            let now = local_time();
+            let binding = profile.gestures.iter().find(|binding| {
+                binding.gesture == Some(gesture)
+                    && binding.applies(now, &mut window_title, &mut power::read)
+            });
+            if let Some(binding) = binding.cloned() {
+                self.released(binding);
+            }
+        }
+    }
+
+    fn poll_keyboards(&mut self) {
+        let mut presses = Vec::new();
+        let mut active = false;
+
+        let mut clicked = false;
+        for pointer in &mut self.pointers {
+            match pointer.fetch_events() {
+                Ok(events) => {
+                    for event in events {
+                        active = true;
+                        clicked |= matches!(event.destructure(), EventSummary::Key(_, _, 1));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => warn!("Device read error: {}", e),
+            }
+        }
+        for (keyboard, seat) in self.keyboards.iter_mut().zip(&self.keyboard_seats) {
+            match keyboard.fetch_events() {
+                Ok(events) => {
+                    for event in events {
+                        active = true;
+                        match event.destructure() {
+                            EventSummary::Key(_, key, 1) => {
+                                presses.push((key.code(), true));
+                                if self.input_seat != *seat {
+                                    self.input_seat = seat.clone();
+                                }
+                            }
+                            EventSummary::Key(_, key, 0) => presses.push((key.code(), false)),
+                            EventSummary::AbsoluteAxis(_, axis, value) => {
+                                // A stick past its threshold presses its key,
+                                // and coming back releases it
+                                for axis_key in self.config.axes.iter().filter(|axis_key| axis_key.axis == axis.0) {
+                                    let pushed = axis_key.is_pushed(value);
+                                    if pushed != self.axis_held.contains(axis_key.key) {
+                                        if pushed {
+                                            self.axis_held.insert(axis_key.key);
+                                        } else {
+                                            self.axis_held.remove(axis_key.key);
+                                        }
+                                        presses.push((axis_key.key, pushed));
+                                    }
+                                }
+                            }
+                            _ => {} // Autorepeat and the rest
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => warn!("Device read error: {}", e),
+            }
+        }
+
+        if active {
+            self.input_resumed();
+        }
+        if clicked {
+            // The caret has likely moved
+            self.typed.clear();
+        }
+        for (code, pressed) in presses {
+            if pressed {
+                self.held.insert(code);
+                self.check_bindings();
+                self.check_typed(code);
+            } else {
+                self.held.remove(code);
+                let exact_sides = self.config.exact_sides;
+                let in_combo = |combo: &KeySet| {
+                    combo.contains(code)
+                        || (!exact_sides && combo.codes().any(|key| keymap::either_side(key) == keymap::either_side(code)))
+                };
+                if self.scrolling.as_ref().is_some_and(|scrolling| in_combo(&scrolling.scroll.combo)) {
+                    self.scrolling = None;
+                }
+                if self.held.is_empty() {
+                    match self.pending.take() {
+                        Some(Pending::Play(binding)) => self.released(binding),
+                        Some(Pending::Taps(taps)) => self.taps_released(*taps),
+                        Some(Pending::Toggle(sustain)) => self.toggle_sustain(sustain),
+                        None => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_bindings(&mut self) {
+        let exact_sides = self.config.exact_sides;
+        let pressed = |combo: &KeySet| keymap::combo_matches(combo, &self.held, exact_sides);
+        if self.config.panic.as_ref().is_some_and(pressed) {
+            if self.disabled {
+                self.resume();
+            } else {
+                self.stop_all();
+            }
+            return;
+        }
+        if self.disabled {
+            return;
+        }
+        if self.config.switch_profile.as_ref().is_some_and(pressed) {
+            if !self.config.profiles.is_empty() {
+                self.switch_profile((self.active_profile + 1) % self.config.profiles.len());
+            }
+            return;
+        }
+
+        let Some(profile) = self.config.profiles.get(self.active_profile) else {
+            return;
+        };
+        let now = local_time();
+        let mut title = None;
+        let mut window = || {
+            title
+                .get_or_insert_with(window_title)
+                .clone()
+        };
+        let mut taps = Taps {
+            single: None,
+            double: None,
+            long: None,
+            pressed: Instant::now(),
+            second: false,
+        };
+        for binding in profile.bindings.iter().filter(|binding| pressed(&binding.combo)) {
+            let slot = match binding.press {
+                Press::Single => &mut taps.single,
+                Press::Double => &mut taps.double,
+                Press::Long => &mut taps.long,
+            };
+            if slot.is_none() && binding.applies(now, &mut window, &mut power::read) {
+                *slot = Some(binding.clone());
+            }
+        }
+        let combo = [&taps.single, &taps.double, &taps.long].into_iter().flatten().next().map(|binding| binding.combo.clone());
+        if let Some(combo) = combo {
+            debug!("{} pressed", keymap::display_combo(&combo));
+            taps.second = self.second_tap(&taps);
+            self.pending = Some(Pending::Taps(Box::new(taps)));
+        } else if let Some(sustain) = profile.sustains.iter().find(|sustain| pressed(&sustain.toggle)) {
+            debug!("{} pressed", keymap::display_combo(&sustain.toggle));
+            self.pending = Some(Pending::Toggle(sustain.clone()));
+        } else if let Some(scroll) = profile.scrolls.iter().find(|scroll| pressed(&scroll.combo)) {
+            debug!("{} pressed, scrolling {}", keymap::display_combo(&scroll.combo), scroll.direction);
+            let now = Instant::now();
+            self.scrolling = Some(Scrolling {
+                scroll: scroll.clone(),
+                started: now,
+                last_tick: now,
+                sent: (0, 0),
+            });
+        }
+    }
+
+    /// Follow the text being typed, and set off the expansion it ends with
+    fn check_typed(&mut self, code: u16) {
+        match keymap::either_side(code) {
+            KEY_LEFTSHIFT | KEY_CAPSLOCK => return,
+            KEY_BACKSPACE => {
+                self.typed.pop();
+                return;
+            }
+            _ => {}
+        }
+        let shortcut = self
+            .held
+            .codes()
+            .any(|key| matches!(keymap::either_side(key), KEY_LEFTCTRL | KEY_LEFTALT | KEY_LEFTMETA));
+        let shift = self.held.codes().any(|key| keymap::either_side(key) == KEY_LEFTSHIFT);
+        let caps_lock = self
+            .keyboards
+            .iter()
+            .any(|keyboard| LockState::from_device(keyboard).is_ok_and(|locks| locks.caps));
+        let Some(c) = self.chars.char_for(code, shift, caps_lock).filter(|_| !shortcut) else {
+            self.typed.clear();
+            return;
+        };
+        self.typed.push(c);
+        if self.typed.chars().count() > TYPED_LEN {
+            self.typed.remove(0);
+        }
+
+        if self.disabled || self.pending.is_some() {
+            return;
+        }
+        let Some(profile) = self.config.profiles.get(self.active_profile) else {
+            return;
+        };
+        let now = local_time();
+        let binding = profile.expansions.iter().find(|binding| {
+            binding.sequence.as_deref().is_some_and(|sequence| typed_at_word_start(&self.typed, sequence))
+                && binding.applies(now, &mut window_title, &mut power::read)
+        });
+        if let Some(binding) = binding {
+            debug!("{} typed", trigger_name(binding));
+            self.pending = Some(Pending::Play(binding.clone()));
+            self.typed.clear();
+        }
+    }
+
+    /// Whether `taps` is the second tap of the combo tapped just before; if
+    /// it's another combo, the one before plays its single tap now
+    fn second_tap(&mut self, taps: &Taps) -> bool {
+        let Some((waiting, released)) = self.tapped.take() else {
+            return false;
+        };
+        let double_line = |taps: &Taps| taps.double.as_ref().map(|binding| binding.line);
+        if double_line(&waiting) == double_line(taps) && released.elapsed() <= Duration::from_millis(self.config.double_tap_ms) {
+            return true;
+        }
+        if let Some(single) = waiting.single {
+            self.released(single);
+        }
+        false
+    }
+
+    /// Play what a released combo sets off: its long press if it was held
+    /// long enough, its double tap on the second tap, and otherwise its
+    /// single tap, once a second tap can't come anymore
+    fn taps_released(&mut self, taps: Taps) {
+        let held = taps.pressed.elapsed();
+        if let Some(long) = &taps.long
+            && held >= Duration::from_millis(self.config.long_press_ms)
+        {
+            self.released(long.clone());
+        } else if taps.second {
+            if let Some(double) = taps.double {
+                self.released(double);
+            }
+        } else if taps.double.is_some() {
+            self.tapped = Some((taps, Instant::now()));
+        } else if let Some(single) = taps.single {
+            self.released(single);
+        }
+    }
+
+    /// Play the single tap of a combo once it's too late for a second one
+    fn check_taps(&mut self) {
+        let window = Duration::from_millis(self.config.double_tap_ms);
+        if self.tapped.as_ref().is_none_or(|(_, released)| released.elapsed() <= window) {
+            return;
+        }
+        if let Some((Taps { single: Some(single), .. }, _)) = self.tapped.take() {
+            self.released(single);
+        }
+    }
+
+    /// Play a binding whose hotkey was released, on the second press if it
+    /// needs confirming
+    fn released(&mut self, binding: Binding) {
+        let armed = self.armed.take();
+        if binding.confirm {
+            let window = Duration::from_millis(CONFIRM_WINDOW_MS);
+            if !armed.is_some_and(|(line, at)| line == binding.line && at.elapsed() <= window) {
+                let combo = trigger_name(&binding);
+                info!("{} needs confirming: press it again to play {}", combo, binding.macro_file);
+                if let Err(e) = hooks::notify_confirm(&combo, &binding.macro_file, window.as_secs()) {
+                    debug!("Couldn't show notification: {}", e);
+                }
+                self.armed = Some((binding.line, Instant::now()));
+                return;
+            }
+        }
+        self.trigger(&binding);
+    }
+
+    /// Make the profile at `index` the active one
+    fn switch_profile(&mut self, index: usize) {
+        let name = self.config.profiles[index].name.clone();
+        info!("Switching to profile {}", name);
+        self.active_profile = index;
+        self.pending = None;
+        self.tapped = None;
+        self.typed.clear();
+        self.armed = None;
+        self.release_sustained();
+        self.scrolling = None;
+        self.idle_fired.clear();
+        if let Err(e) = hooks::notify_profile(&name) {
+            debug!("Couldn't show notification: {}", e);
+        }
+    }
+
+    /// Reply to `profile`, `profile next` and `profile <name>`
+    fn profile_command(&mut self, argument: &str) -> String {
+        let index = match argument {
+            "" => None,
+            "next" if !self.config.profiles.is_empty() => Some((self.active_profile + 1) % self.config.profiles.len()),
+            name => match self.config.profiles.iter().position(|profile| profile.name == name) {
+                Some(index) => Some(index),
+                None => return format!("error: No profile named '{}'\n", name),
+            },
+        };
+        if let Some(index) = index {
+            self.switch_profile(index);
+        }
+        match self.config.profiles.get(self.active_profile) {
+            Some(profile) => format!("Active profile: {}\n", profile.name),
+            None => "No profiles\n".to_string(),
+        }
+    }
+
+    /// Play the idle macros that are due
+    fn check_idle(&mut self) {
+        if self.disabled {
+            return;
+        }
+        let Some(profile) = self.config.profiles.get(self.active_profile) else {
+            return;
+        };
+        let idle_for = self.last_input.elapsed();
+        let due: Vec<Binding> = profile
+            .idles
+            .iter()
+            .filter(|idle| {
+                let after = Duration::from_millis(idle.after_ms);
+                match self.idle_fired.get(&idle.binding.line) {
+                    None => idle_for >= after,
+                    Some(fired) => idle.repeat && fired.elapsed() >= after,
+                }
+            })
+            .map(|idle| idle.binding.clone())
+            .collect();
+
+        let now = local_time();
+        for binding in due {
+            // Counts as fired even when its conditions don't hold, so they're
+            // checked once per idle stretch (or repeat) rather than constantly
+            self.idle_fired.insert(binding.line, Instant::now());
+            if binding.applies(now, &mut window_title, &mut power::read) {
+                info!("No input for {}", format_duration(idle_for));
+                self.trigger(&binding);
+            }
+        }
+    }
+
+    /// Note that the user is back, playing the `back` macros of idle triggers
+    /// that went off
+    fn input_resumed(&mut self) {
+        self.last_input = Instant::now();
+        if self.idle_fired.is_empty() {
+            return;
+        }
+        let fired = std::mem::take(&mut self.idle_fired);
+        let Some(profile) = self.config.profiles.get(self.active_profile) else {
+            return;
+        };
+        let backs: Vec<Binding> = profile
+            .idles
+            .iter()
+            .filter(|idle| fired.contains_key(&idle.binding.line))
+            .filter_map(|idle| idle.back.clone())
+            .collect();
+        if self.disabled {
+            return;
+        }
+        for back in backs {
+            self.trigger(&back);
+        }
+    }
+
+    /// Send the wheel events due for the scroll combo being held
+    fn scroll(&mut self) {
+        let Some(scrolling) = &mut self.scrolling else {
+            return;
+        };
+        let now = Instant::now();
+        if now - scrolling.last_tick < SCROLL_TICK {
+            return;
+        }
+        scrolling.last_tick = now;
+
+        let events = scroll_events(&scrolling.scroll, now - scrolling.started, &mut scrolling.sent);
+        if events.is_empty() {
+            return;
+        }
+        if let Err(e) = self.output().and_then(|output| output.emit(&events)) {
+            warn!("Can't scroll: {}", e);
+            self.scrolling = None;
+        }
+    }
+
+    /// Start or stop holding a sustain's keys
+    fn toggle_sustain(&mut self, sustain: Sustain) {
+        let keys = keymap::display_combo(&sustain.keys);
+        let result = match self.sustained.iter().position(|on| on.line == sustain.line) {
+            Some(index) => {
+                info!("Releasing {}", keys);
+                let released = self.sustained.remove(index);
+                self.emit_sustained(&released.keys, false)
+            }
+            None => {
+                info!("Holding {}", keys);
+                let result = self.emit_sustained(&sustain.keys, true);
+                self.sustained.push(sustain);
+                result
+            }
+        };
+        if let Err(e) = result {
+            warn!("Can't sustain {}: {}", keys, e);
+        }
+    }
+
+    /// Release every sustained key
+    fn release_sustained(&mut self) {
+        for sustain in std::mem::take(&mut self.sustained) {
+            if let Err(e) = self.emit_sustained(&sustain.keys, false) {
+                warn!("Can't release {}: {}", keymap::display_combo(&sustain.keys), e);
+            }
+        }
+    }
+
+    /// Press or release keys on the sustain keyboard; keys another sustain
+    /// still holds stay down
+    fn emit_sustained(&mut self, keys: &KeySet, pressed: bool) -> io::Result<()> {
+        let still_held: KeySet = self.sustained.iter().flat_map(|sustain| sustain.keys.iter()).collect();
+        let events: Vec<InputEvent> = keys
+            .codes()
+            .filter(|&key| !still_held.contains(key))
+            .map(|key| InputEvent::new(EventType::KEY.0, key, pressed as i32))
+            .collect();
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        // Let go where they were pressed, wherever the keyboard is now
+        if !pressed && let Some((_, output)) = &mut self.output {
+            return output.emit(&events);
+        }
+        self.output()?.emit(&events)
+    }
+
+    /// The daemon's own virtual device on the seat [`Self::route`] picks,
+    /// opened on first use
+    fn output(&mut self) -> io::Result<&mut Box<dyn Backend>> {
+        let seat = self.route().map_err(io::Error::other)?;
+        if self.output.as_ref().is_none_or(|(on, _)| *on != seat) {
+            // Destroying the old seat's device lets go of what it held there
+            if self.output.take().is_some() && !self.sustained.is_empty() {
+                info!("Releasing what was sustained, the keyboard moved to {}", seat);
+                self.sustained.clear();
+            }
+            let device = backend::open(BackendKind::Auto, &seat::device_name(OUTPUT_DEVICE, &seat))?;
+            self.output = Some((seat, device));
+        }
+        Ok(&mut self.output.as_mut().unwrap().1)
+    }
+
+    /// Seat macros, sustains and scrolling go to: the seat of the keyboard
+    /// last pressed, as long as (system-wide) the session active on it opted
+    /// in; or why nothing may go there
+    fn route(&self) -> Result<String, String> {
+        if !self.system {
+            return Ok(seat::DEFAULT_SEAT.to_string());
+        }
+        let seat = &self.input_seat;
+        let Some((_, active)) = self.active_sessions.iter().find(|(on, _)| on == seat) else {
+            return Err(format!("no session is active on {}", seat));
+        };
+        if !self.sessions.contains_key(active) {
+            return Err(format!("session {} on {} hasn't opted in with `evkey session start`", active, seat));
+        }
+        seat::check(seat)?;
+        Ok(seat.clone())
+    }
+
+    /// Follow which session is active on each seat, system-wide: when another
+    /// one takes a seat over, whatever played into the old one there stops,
+    /// and a session that opted in with a profile switches to it
+    fn check_sessions(&mut self) {
+        if !self.system || self.sessions_checked.is_some_and(|checked| checked.elapsed() < SESSION_CHECK_INTERVAL) {
+            return;
+        }
+        self.sessions_checked = Some(Instant::now());
+        let active = match logind::active_sessions() {
+            Ok(active) => active,
+            Err(e) => {
+                warn!("Can't read the active sessions from logind: {}", e);
+                Vec::new()
+            }
+        };
+        // Logged out sessions don't come back
+        self.sessions.retain(|id, (session, _)| {
+            let exists = Path::new(logind::SESSIONS_DIR).join(id).exists();
+            if !exists {
+                info!("Session {} ({}) ended", id, session.user);
+            }
+            exists
+        });
+
+        let previous = std::mem::replace(&mut self.active_sessions, active);
+        for (seat, old) in previous {
+            let now = self.active_sessions.iter().find(|(on, _)| *on == seat).map(|(_, id)| id.clone());
+            if now.as_ref() == Some(&old) {
+                continue;
+            }
+            info!("Session {} is active on {} now", now.as_deref().unwrap_or("none"), seat);
+            self.close_seat(&seat);
+            if let Some(id) = now {
+                self.follow_profile(&id);
+            }
+        }
+    }
+
+    /// Stop playing, sustaining and scrolling into `seat`
+    fn close_seat(&mut self, seat: &str) {
+        for playback in self.playbacks.iter().filter(|playback| playback.seat == seat) {
+            info!("Stopping {} (#{}), its session is gone from {}", playback.macro_file, playback.id, seat);
+            playback.stop.store(true, Ordering::Relaxed);
+        }
+        // Destroying the device lets go of what it held, without sending
+        // anything to whoever has the seat now
+        if self.output.as_ref().is_some_and(|(on, _)| on == seat) {
+            self.output = None;
+            self.sustained.clear();
+            self.scrolling = None;
+        }
+    }
+
+    /// Switch to the profile session `id` opted in with, if it asked for one
+    fn follow_profile(&mut self, id: &str) {
+        let Some((_, Some(profile))) = self.sessions.get(id) else {
+            return;
+        };
+        if let Some(index) = self.config.profiles.iter().position(|p| p.name == *profile)
+            && index != self.active_profile
+        {
+            self.switch_profile(index);
+        }
+    }
+
+    /// Play a binding's macro, unless its limits or busy policy say otherwise
+    fn trigger(&mut self, binding: &Binding) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let combo = trigger_name(binding);
+        let now = Instant::now();
+
+        let full = self.playbacks.len() >= self.config.max_running;
+        let preempts = full && self.playbacks.iter().any(|playback| playback.priority < binding.priority);
+        let unroutable = self.route().err();
+        let activations = self.activations.entry(binding.line).or_default();
+        let skipped = match unroutable.or_else(|| rate_limit(binding, activations, now)) {
+            Some(reason) => Some(reason),
+            None if full && !preempts && binding.busy == BusyPolicy::Ignore => {
+                Some("another macro is playing".to_string())
+            }
+            None => None,
+        };
+        if skipped.is_none() {
+            activations.push_back(now);
+        }
+
+        let outcome = match skipped {
+            Some(reason) => {
+                info!("{} skipped {} (#{}): {}", combo, binding.macro_file, id, reason);
+                Outcome::Skipped(reason)
+            }
+            None if !full && self.queue.is_empty() => Outcome::Running,
+            None => {
+                info!("{} queued {} (#{})", combo, binding.macro_file, id);
+                if binding.busy == BusyPolicy::Interrupt {
+                    interrupt(&self.playbacks, binding.priority);
+                } else if preempts {
+                    self.preempt(binding.priority);
+                }
+                enqueue(&mut self.queue, id, binding.clone());
+                Outcome::Queued
+            }
+        };
+
+        let counted = match outcome {
+            Outcome::Running => "started",
+            Outcome::Queued => "queued",
+            _ => "skipped",
+        };
+        self.metrics.lock().unwrap().triggered(&combo, counted);
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_back();
+        }
+        self.history.push_front(Trigger {
+            id,
+            at: now,
+            combo,
+            macro_file: binding.macro_file.clone(),
+            outcome: outcome.clone(),
+        });
+        if outcome == Outcome::Running {
+            self.start(id, binding);
+        }
+    }
+
+    /// Stop every playback, drop the queue and turn hotkeys off; returns how
+    /// many playbacks were stopped
+    fn stop_all(&mut self) -> usize {
+        warn!("Stopping all macros, hotkeys are off until resumed");
+        for playback in &self.playbacks {
+            playback.stop.store(true, Ordering::Relaxed);
+        }
+        for (id, _) in self.queue.drain(..) {
+            if let Some(trigger) = self.history.iter_mut().find(|trigger| trigger.id == id) {
+                trigger.outcome = Outcome::Stopped;
+            }
+        }
+        self.pending = None;
+        self.tapped = None;
+        self.armed = None;
+        self.release_sustained();
+        self.scrolling = None;
+        self.disabled = true;
+        self.playbacks.len()
+    }
+
+    fn resume(&mut self) {
+        info!("Hotkeys back on");
+        self.disabled = false;
+    }
+
+    /// Stop the lowest priority playback below `priority` to make room
+    fn preempt(&mut self, priority: i32) {
+        let victim = self
+            .playbacks
+            .iter()
+            .filter(|playback| playback.priority < priority && !playback.stop.load(Ordering::Relaxed))
+            .min_by_key(|playback| playback.priority);
+        if let Some(playback) = victim {
+            info!("Stopping {} (#{}) for a higher priority macro", playback.macro_file, playback.id);
+            playback.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Start playing a binding's macro on a new thread
+    fn start(&mut self, id: u64, binding: &Binding) {
+        let combo = trigger_name(binding);
+        // Queued macros go where the keyboard is now, if anywhere
+        let seat = match self.route() {
+            Ok(seat) => seat,
+            Err(reason) => {
+                info!("{} skipped {} (#{}): {}", combo, binding.macro_file, id, reason);
+                if let Some(trigger) = self.history.iter_mut().find(|trigger| trigger.id == id) {
+                    trigger.outcome = Outcome::Skipped(reason);
+                }
+                return;
+            }
+        };
+        info!("{} triggered {} (#{})", combo, binding.macro_file, id);
+
+        let path = self.config.macro_path(binding);
+        let loaded = self
+            .library
+            .get(&path)
+            .cloned()
+            .unwrap_or_else(|| Err(format!("{} isn't loaded", path.display())));
+        let progress = Arc::new(Mutex::new(None));
+        let reported = Arc::clone(&progress);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::clone(&stop);
+        // The kept device is on the default seat
+        let device = self.playback_device.clone().filter(|_| seat == seat::DEFAULT_SEAT);
+        let name = seat::device_name(PLAYBACK_DEVICE, &seat);
+        let warm_up = Duration::from_millis(self.config.warm_up_ms);
+        let max_rate = self.config.max_rate;
+        let metrics = Arc::clone(&self.metrics);
+        // An expansion's text is still on screen
+        let erase = binding.sequence.as_ref().map_or(0, |sequence| sequence.chars().count());
+        let source = binding.macro_file.clone();
+        let thread = thread::spawn(move || {
+            // A kept device was warmed up when it was created
+            let (mut backend, pre_roll) = match device {
+                Some(device) => (AuditBackend::wrap(Box::new(device), &name)?, Duration::ZERO),
+                None => (backend::open(BackendKind::Auto, &name)?, warm_up),
+            };
+            backend.set_source(&source);
+            let macro_ = loaded.map_err(io::Error::other)?;
+            if erase == 0 {
+                return play(backend, pre_roll, max_rate, &macro_, reported, stop_flag, metrics);
+            }
+            let mut expanded = Macro::clone(&macro_);
+            expanded.insert_clip(0, &backspaces(erase));
+            play(backend, pre_roll, max_rate, &expanded, reported, stop_flag, metrics)
+        });
+
+        if let Some(trigger) = self.history.iter_mut().find(|trigger| trigger.id == id) {
+            trigger.outcome = Outcome::Running;
+        }
+        self.playbacks.push(Playback {
+            id,
+            combo,
+            macro_file: binding.macro_file.clone(),
+            seat,
+            priority: binding.priority,
+            started: Instant::now(),
+            progress,
+            stop,
+            thread,
+        });
+    }
+
+    /// Collect finished playbacks, record how they went and start queued
+    /// ones in the free slots
+    fn reap_playbacks(&mut self) {
+        let (finished, running) = std::mem::take(&mut self.playbacks)
+            .into_iter()
+            .partition(|playback| playback.thread.is_finished());
+        self.playbacks = running;
+
+        for playback in finished {
+            let outcome = match playback.thread.join() {
+                Ok(Ok(())) => Outcome::Finished(playback.started.elapsed()),
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => Outcome::Stopped,
+                Ok(Err(e)) => Outcome::Failed(e.to_string()),
+                Err(_) => Outcome::Failed("playback thread panicked".to_string()),
+            };
+            match &outcome {
+                Outcome::Failed(error) => {
+                    warn!("{} (#{}) failed: {}", playback.macro_file, playback.id, error);
+                    self.metrics.lock().unwrap().failed(&playback.macro_file);
+                }
+                _ => info!("{} (#{}) finished", playback.macro_file, playback.id),
+            }
+            if let Outcome::Finished(_) = outcome {
+                self.metrics.lock().unwrap().played(&playback.macro_file);
+            }
+            if let Some(trigger) = self.history.iter_mut().find(|trigger| trigger.id == playback.id) {
+                trigger.outcome = outcome;
+            }
+        }
+
+        while self.playbacks.len() < self.config.max_running {
+            let Some((id, binding)) = self.queue.pop_front() else {
+                break;
+            };
+            self.start(id, &binding);
+        }
+    }
+
+    /// Answer any clients waiting on the control socket
+    fn serve_clients(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.serve(stream) {
+                        debug!("Client error: {}", e);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Control socket error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        while let Some((_, listener)) = &self.metrics_listener {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.serve_metrics_client(stream) {
+                        debug!("Metrics client error: {}", e);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Metrics socket error: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn serve_metrics_client(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        let request = BufReader::new(Deadline::new(&stream, CLIENT_TIMEOUT));
+        metrics::respond(request, &stream, || {
+            let mut metrics = self.metrics.lock().unwrap();
+            metrics.running = self.playbacks.len();
+            metrics.devices = vec![("keyboard", self.keyboards.len()), ("pointer", self.pointers.len())];
+            metrics.to_string()
+        })
+    }
+
+    fn serve(&mut self, mut stream: UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+
+        let command = read_command(&stream, CLIENT_TIMEOUT)?;
+        // Anyone can reach the system-wide daemon
+        let peer = if self.system { Some(logind::peer(&stream)?) } else { None };
+        let reply = self.reply(command.trim(), peer);
+        stream.write_all(reply.as_bytes())
+    }
+
+    /// Answer a control command from the client `peer` (process and user
+    /// id), which only the system-wide daemon knows
+    fn reply(&mut self, command: &str, peer: Option<(u32, u32)>) -> String {
+        match command {
+            "status" => self.status().to_string(),
+            "session" => self.session_command("", peer),
+            other if other.starts_with("session ") => self.session_command(other["session ".len()..].trim(), peer),
+            _ if peer.is_some_and(|(_, uid)| !self.may_control(uid)) => {
+                "error: Only root or the user of the active session can do that\n".to_string()
+            }
+            "stop-all" => {
+                let stopped = self.stop_all();
+                format!("Stopped {} macro(s); hotkeys are off until `evkey resume`\n", stopped)
+            }
+            "resume" => {
+                self.resume();
+                "Hotkeys are back on\n".to_string()
+            }
+            "profile" => self.profile_command(""),
+            other => match other.strip_prefix("profile ") {
+                Some(argument) => self.profile_command(argument.trim()),
+                None => format!("error: Unknown command '{}'\n", other),
+            },
+        }
+    }
+
+    /// Whether user `uid` may change the system-wide daemon's state: root,
+    /// or the user of a session that's active and opted in
+    fn may_control(&self, uid: u32) -> bool {
+        uid == 0
+            || self.active_sessions.iter().any(|(_, id)| {
+                self.sessions.get(id).is_some_and(|(session, _)| session.uid == uid)
+            })
+    }
+
+    /// Reply to `session`, `session start [profile]` and `session stop` from
+    /// the client `peer` (process and user id)
+    fn session_command(&mut self, argument: &str, peer: Option<(u32, u32)>) -> String {
+        let Some((pid, uid)) = peer else {
+            return "error: Sessions are for the system-wide daemon (`evkey daemon --system`)\n".to_string();
+        };
+        let id = match logind::session_of(pid) {
+            Ok(Some(id)) => id,
+            Ok(None) => return "error: Not in a login session\n".to_string(),
+            Err(e) => return format!("error: Can't find your session: {}\n", e),
+        };
+        let (command, profile) = match argument.split_once(' ') {
+            Some((command, profile)) => (command, Some(profile.trim().to_string())),
+            None => (argument, None),
+        };
+        match command {
+            "" => match self.sessions.get(&id) {
+                Some((_, Some(profile))) => format!("Session {} opted in, with profile {}\n", id, profile),
+                Some(_) => format!("Session {} opted in\n", id),
+                None => format!("Session {} hasn't opted in\n", id),
+            },
+            "start" => {
+                let session = match logind::session(&id) {
+                    Ok(session) => session,
+                    Err(e) => return format!("error: {}\n", e),
+                };
+                // Root may opt anyone's session in, others only their own
+                if uid != 0 && uid != session.uid {
+                    return format!("error: Session {} isn't yours\n", id);
+                }
+                if let Some(name) = &profile
+                    && !self.config.profiles.iter().any(|p| p.name == *name)
+                {
+                    return format!("error: No profile named '{}'\n", name);
+                }
+                info!("Session {} ({}) opted in", id, session.user);
+                self.sessions.insert(id.clone(), (session, profile));
+                if self.active_sessions.iter().any(|(_, active)| *active == id) {
+                    self.follow_profile(&id);
+                }
+                format!("Session {} opted in\n", id)
+            }
+            "stop" => match self.sessions.remove(&id) {
+                Some((session, _)) => {
+                    info!("Session {} ({}) opted out", id, session.user);
+                    if let Some(seat) = session.seat
+                        && self.active_sessions.iter().any(|(on, active)| *on == seat && *active == id)
+                    {
+                        self.close_seat(&seat);
+                    }
+                    format!("Session {} opted out\n", id)
+                }
+                None => format!("Session {} hadn't opted in\n", id),
+            },
+            other => format!("error: Unknown session command '{}'\n", other),
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.release_sustained();
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Wheel events bringing a scroll held for `elapsed` up to date
+///
+/// `sent` counts the hi-res units and notches already sent. Notches go out as
+/// `REL_WHEEL`/`REL_HWHEEL` either way, for apps (and backends) without hi-res
+/// support; with `hires` the steps in between go out on the hi-res axis.
+fn scroll_events(scroll: &Scroll, elapsed: Duration, sent: &mut (i64, i64)) -> Vec<InputEvent> {
+    let (code, hires_code, sign) = match scroll.direction {
+        ScrollDirection::Up => (8, 11, 1), // REL_WHEEL, REL_WHEEL_HI_RES
+        ScrollDirection::Down => (8, 11, -1),
+        ScrollDirection::Left => (6, 12, -1), // REL_HWHEEL, REL_HWHEEL_HI_RES
+        ScrollDirection::Right => (6, 12, 1),
+    };
+    let units = (elapsed.as_micros() * u128::from(scroll.rate) * HIRES_PER_NOTCH as u128 / 1_000_000) as i64;
+    let notches = units / HIRES_PER_NOTCH;
+
+    let mut events = Vec::new();
+    if scroll.hires && units > sent.0 {
+        events.push(InputEvent::new(EventType::RELATIVE.0, hires_code, sign * (units - sent.0) as i32));
+        sent.0 = units;
+    }
+    if notches > sent.1 {
+        events.push(InputEvent::new(EventType::RELATIVE.0, code, sign * (notches - sent.1) as i32));
+        sent.1 = notches;
+    }
+    events
+}
+
+/// Read and parse the config file
+fn load_config(path: &Path) -> io::Result<Config> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e)))?;
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+    Config::parse(&text, base_dir)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+/// Listen on the control socket, replacing a stale one left by a crash
+fn bind_socket(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Another EvKey daemon is already running ({})", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Title of the focused window for `if window` conditions, `None` if it
+/// can't be told; why not is logged the first time
+fn window_title() -> Option<String> {
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+    screen::active_window_title()
+        .inspect_err(|e| {
+            if !REPORTED.swap(true, Ordering::Relaxed) {
+                warn!("Can't tell which window has focus, so `if window` conditions won't hold: {}", e);
+            }
+        })
+        .ok()
+}
+
+/// A client connection whose reads fail once its time is up, however slowly
+/// the client trickles its request in, so it can't hold up the main loop
+struct Deadline<S> {
+    stream: S,
+    until: Instant,
+}
+
+impl<S> Deadline<S> {
+    fn new(stream: S, timeout: Duration) -> Self {
+        Self { stream, until: Instant::now() + timeout }
+    }
+}
+
+impl<S: Read + AsFd> Read for Deadline<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        let timeout = u16::try_from(left.as_millis()).unwrap_or(u16::MAX);
+        let mut fds = [PollFd::new(self.stream.as_fd(), PollFlags::POLLIN)];
+        if timeout == 0 || poll(&mut fds, timeout)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Client took too long to send its request"));
+        }
+        self.stream.read(buf)
+    }
+}
+
+/// The command line a control client sends, cut off at [`MAX_COMMAND_BYTES`]
+/// and failing once `timeout` is up
+fn read_command<S: Read + AsFd>(stream: S, timeout: Duration) -> io::Result<String> {
+    let mut command = String::new();
+    BufReader::new(Deadline::new(stream, timeout)).take(MAX_COMMAND_BYTES).read_line(&mut command)?;
+    Ok(command)
+}
+
+/// Queue a trigger behind everything of the same or higher priority
+fn enqueue(queue: &mut VecDeque<(u64, Binding)>, id: u64, binding: Binding) {
+    let at = queue
+        .iter()
+        .position(|(_, queued)| queued.priority < binding.priority)
+        .unwrap_or(queue.len());
+    queue.insert(at, (id, binding));
+}
+
+/// Stop the playbacks a trigger of `priority` may interrupt: those of the
+/// same or lower priority. Returns how many were told to stop.
+fn interrupt(playbacks: &[Playback], priority: i32) -> usize {
+    let mut stopped = 0;
+    for playback in playbacks.iter().filter(|playback| playback.priority <= priority) {
+        playback.stop.store(true, Ordering::Relaxed);
+        stopped += 1;
+    }
+    stopped
+}
+
+/// Why a binding can't fire at `now`, given when it last fired
+///
+/// Activations older than the rate window are dropped from `activations`.
+fn rate_limit(binding: &Binding, activations: &mut VecDeque<Instant>, now: Instant) -> Option<String> {
+    while activations.front().is_some_and(|&at| now.duration_since(at) >= RATE_WINDOW) {
+        activations.pop_front();
+    }
+
+    let cooldown = Duration::from_millis(binding.cooldown_ms);
+    if let Some(&last) = activations.back() {
+        let since = now.duration_since(last);
+        if since < cooldown {
+            return Some(format!("cooldown, {:.1}s left", (cooldown - since).as_secs_f64()));
+        }
+    }
+    match binding.max_per_minute {
+        Some(max) if activations.len() >= max as usize => Some(format!("limit of {}/min reached", max)),
+        _ => None,
+    }
+}
+
+/// Whether `typed` ends with `sequence` typed at the start of a word
+fn typed_at_word_start(typed: &str, sequence: &str) -> bool {
+    typed
+        .strip_suffix(sequence)
+        .is_some_and(|before| before.chars().next_back().is_none_or(|c| !c.is_alphanumeric()))
+}
+
+/// Taps of backspace erasing `count` characters
+fn backspaces(count: usize) -> Macro {
+    let mut states = Vec::new();
+    for _ in 0..count {
+        let mut pressed = MacroState::new(ERASE_INTERVAL_MS);
+        pressed.keys_pressed.insert(KEY_BACKSPACE);
+        states.push(pressed);
+        states.push(MacroState::new(ERASE_INTERVAL_MS));
+    }
+    Macro {
+        states,
+        ..Default::default()
+    }
+}
+
+/// Play a macro on `backend` after a `pre_roll`, to the end (or until `stop`
+/// is set), at no more than `max_rate`, publishing progress as it goes
+fn play(
+    backend: Box<dyn Backend>,
+    pre_roll: Duration,
+    max_rate: Option<EventRate>,
+    macro_: &Macro,
+    progress: Arc<Mutex<Option<Progress>>>,
+    stop: Arc<AtomicBool>,
+    metrics: Arc<Mutex<Metrics>>,
+) -> io::Result<()> {
+    if let Some(window) = &macro_.metadata.window {
+        window
+            .before_playing(&mut screen::active_window_title, || stop.load(Ordering::Relaxed))
+            .map_err(io::Error::other)?;
+    }
+    let mut player = Player::new(backend);
+    player.set_max_rate(max_rate);
+    player.pre_roll(pre_roll)?;
+    if let Some(window) = &macro_.metadata.window {
+        player.pause_while(window.unfocused(screen::active_window_title, Arc::clone(&stop)));
+    }
+    player.stop_on(stop);
+    player.on_progress(move |p| *progress.lock().unwrap() = Some(*p));
+    player.on_lateness(move |late_us| metrics.lock().unwrap().latency.observe(late_us));
+    player.set_state_starts(macro_.state_starts_us());
+    player.play_with_actions(&macro_.events(), &macro_.timed_actions())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(192)), "3m12s");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h05m");
+    }
+
+    fn binding(line: &str) -> Binding {
+        Config::parse(line, Path::new("/")).unwrap().profiles[0].bindings[0].clone()
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut binding = binding("bind F9 a.macro cooldown 1s max 2/min");
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut activations = VecDeque::new();
+
+        assert_eq!(rate_limit(&binding, &mut activations, at(0)), None);
+        activations.push_back(at(0));
+        assert!(rate_limit(&binding, &mut activations, start + Duration::from_millis(300)).unwrap().starts_with("cooldown"));
+        assert_eq!(rate_limit(&binding, &mut activations, at(2)), None);
+        activations.push_back(at(2));
+        assert_eq!(rate_limit(&binding, &mut activations, at(30)).unwrap(), "limit of 2/min reached");
+        // The first activation has left the window
+        assert_eq!(rate_limit(&binding, &mut activations, at(61)), None);
+        assert_eq!(activations.len(), 1);
+
+        binding.max_per_minute = None;
+        binding.cooldown_ms = 0;
+        assert_eq!(rate_limit(&binding, &mut activations, at(61)), None);
+    }
+
+    #[test]
+    fn test_scroll_events() {
+        let scroll = |line| Config::parse(line, Path::new("/")).unwrap().profiles[0].scrolls[0].clone();
+        let values = |events: Vec<InputEvent>| events.iter().map(|e| (e.code(), e.value())).collect::<Vec<_>>();
+
+        // 10 notches a second: one every 100ms, nothing in between
+        let down = scroll("scroll KP2 down");
+        let mut sent = (0, 0);
+        assert!(scroll_events(&down, Duration::from_millis(50), &mut sent).is_empty());
+        assert_eq!(values(scroll_events(&down, Duration::from_millis(100), &mut sent)), vec![(8, -1)]);
+        assert!(scroll_events(&down, Duration::from_millis(150), &mut sent).is_empty());
+        assert_eq!(values(scroll_events(&down, Duration::from_millis(310), &mut sent)), vec![(8, -2)]);
+
+        // Hi-res steps every tick, with the notch once 120 units add up
+        let right = scroll("scroll KP6 right rate 20/s hires");
+        let mut sent = (0, 0);
+        assert_eq!(values(scroll_events(&right, Duration::from_millis(10), &mut sent)), vec![(12, 24)]);
+        assert_eq!(values(scroll_events(&right, Duration::from_millis(50), &mut sent)), vec![(12, 96), (6, 1)]);
+        assert_eq!(sent, (120, 1));
+    }
+
+    #[test]
+    fn test_expansion_typing() {
+        assert!(typed_at_word_start(";addr", ";addr"));
+        assert!(typed_at_word_start("see ;addr", ";addr"));
+        assert!(typed_at_word_start("(;addr", ";addr"));
+        assert!(!typed_at_word_start("x;addr", ";addr"));
+        assert!(!typed_at_word_start(";add", ";addr"));
+
+        let binding = Config::parse("expand ;addr a.macro", Path::new("/")).unwrap().profiles[0].expansions[0].clone();
+        assert_eq!(trigger_name(&binding), "\";addr\"");
+
+        let erase = backspaces(2);
+        assert_eq!(erase.states.len(), 4);
+        let keys: Vec<(u16, i32)> = erase
+            .events()
+            .iter()
+            .filter(|e| e.event.event_type() == EventType::KEY)
+            .map(|e| (e.event.code(), e.event.value()))
+            .collect();
+        assert_eq!(keys, vec![(14, 1), (14, 0), (14, 1), (14, 0)]);
+    }
+
+    #[test]
+    fn test_socket_dir() {
+        let dir = std::env::temp_dir().join(format!("evkey-socket-{}", std::process::id()));
+        let socket = dir.join("evkey.sock");
+        secure_socket_dir(&socket).unwrap();
+        assert_eq!(std::fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        // Squatted or opened up, it's refused
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(secure_socket_dir(&socket).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_deadline() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let started = Instant::now();
+        let mut reader = BufReader::new(Deadline::new(&server, Duration::from_millis(200)));
+        // Trickling it in doesn't buy more time
+        let trickle = thread::spawn(move || {
+            for _ in 0..10 {
+                let _ = client.write_all(b"s");
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(400));
+        trickle.join().unwrap();
+    }
+
+    /// A system-wide daemon with no devices, listening on a socket of its own
+    fn system_daemon(name: &str) -> Daemon {
+        let socket_path = std::env::temp_dir().join(format!("evkey-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        Daemon {
+            config: Config::parse("bind F9 farm.macro", Path::new("/")).unwrap(),
+            config_path: PathBuf::from("/etc/evkey/daemon.conf"),
+            active_profile: 0,
+            keyboards: Vec::new(),
+            keyboard_seats: Vec::new(),
+            input_seat: seat::DEFAULT_SEAT.to_string(),
+            pointers: Vec::new(),
+            last_input: Instant::now(),
+            idle_fired: HashMap::new(),
+            held: KeySet::new(),
+            axis_held: KeySet::new(),
+            typed: String::new(),
+            chars: CharMap::us(),
+            pending: None,
+            tapped: None,
+            armed: None,
+            sustained: Vec::new(),
+            scrolling: None,
+            output: None,
+            playback_device: None,
+            #[cfg(feature = "gestures")]
+            touchpads: None,
+            disabled: false,
+            playbacks: Vec::new(),
+            queue: VecDeque::new(),
+            activations: HashMap::new(),
+            history: VecDeque::new(),
+            next_id: 1,
+            library: HashMap::new(),
+            config_error: None,
+            conflicts: Vec::new(),
+            watcher: Watcher::new().unwrap(),
+            listener: UnixListener::bind(&socket_path).unwrap(),
+            socket_path,
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+            metrics_listener: None,
+            system: true,
+            sessions: BTreeMap::new(),
+            active_sessions: Vec::new(),
+            sessions_checked: None,
+        }
+    }
+
+    #[test]
+    fn test_only_controlling_users_stop_and_resume() {
+        let mut daemon = system_daemon("control");
+        let refusal = "error: Only root or the user of the active session can do that\n";
+        // uid 1000 has no active session that opted in
+        let user = Some((4242, 1000));
+
+        assert!(daemon.reply("status", user).starts_with("EvKey daemon (system-wide)"));
+        assert_eq!(daemon.reply("stop-all", user), refusal);
+        assert!(!daemon.disabled);
+        daemon.disabled = true;
+        assert_eq!(daemon.reply("resume", user), refusal);
+        assert!(daemon.disabled);
+
+        // Root may, and so may the user once their session is active
+        assert_eq!(daemon.reply("resume", Some((1, 0))), "Hotkeys are back on\n");
+        let session = logind::Session {
+            id: "3".to_string(),
+            uid: 1000,
+            user: "alex".to_string(),
+            seat: Some("seat0".to_string()),
+        };
+        daemon.sessions.insert("3".to_string(), (session, None));
+        daemon.active_sessions.push(("seat0".to_string(), "3".to_string()));
+        assert!(daemon.reply("stop-all", user).starts_with("Stopped 0 macro(s)"));
+        assert!(daemon.disabled);
+    }
+
+    #[test]
+    fn test_command_length_is_bounded() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        // Far more than a command, with no newline to stop at
+        let flood = thread::spawn(move || {
+            let _ = client.write_all(&vec![b'x'; 64 * 1024]);
+        });
+        let command = read_command(&server, Duration::from_secs(1)).unwrap();
+        assert_eq!(command.len() as u64, MAX_COMMAND_BYTES);
+        drop(server);
+        flood.join().unwrap();
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"status\nstop-all\n").unwrap();
+        assert_eq!(read_command(&server, Duration::from_secs(1)).unwrap(), "status\n");
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let mut queue = VecDeque::new();
+        enqueue(&mut queue, 1, binding("bind F1 loop.macro"));
+        enqueue(&mut queue, 2, binding("bind F2 urgent.macro priority 5"));
+        enqueue(&mut queue, 3, binding("bind F3 other.macro"));
+        enqueue(&mut queue, 4, binding("bind F4 cleanup.macro priority 5"));
+        enqueue(&mut queue, 5, binding("bind F5 idle.macro priority -1"));
+
+        let ids: Vec<u64> = queue.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![2, 4, 1, 3, 5]);
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let playback = |id, priority| Playback {
+            id,
+            combo: String::new(),
+            macro_file: String::new(),
+            seat: seat::DEFAULT_SEAT.to_string(),
+            priority,
+            started: Instant::now(),
+            progress: Arc::new(Mutex::new(None)),
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: thread::spawn(|| Ok(())),
+        };
+        // A low priority interrupt arriving while a high priority macro plays
+        let playbacks = vec![playback(1, 5), playback(2, 0)];
+        let loop_ = binding("bind F1 loop.macro busy interrupt");
+        assert_eq!(interrupt(&playbacks, loop_.priority), 1);
+        assert!(!playbacks[0].stop.load(Ordering::Relaxed));
+        assert!(playbacks[1].stop.load(Ordering::Relaxed));
+
+        // and it waits behind the high priority ones queued
+        let mut queue = VecDeque::new();
+        enqueue(&mut queue, 3, binding("bind F2 urgent.macro priority 5"));
+        enqueue(&mut queue, 4, loop_);
+        let ids: Vec<u64> = queue.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_status_report() {
+        let status = Status {
+            config_path: PathBuf::from("/home/me/.config/evkey/daemon.conf"),
+            profiles: vec!["default".to_string(), "work".to_string()],
+            active_profile: 1,
+            sustained: vec!["W".to_string()],
+            disabled: true,
+            panic: Some("CTRL+ALT+ESC".to_string()),
+            bindings: vec![("CTRL+ALT+F5".to_string(), "farm.macro".to_string()), ("F9".to_string(), "login.macro".to_string())],
+            running: vec![RunningStatus {
+                id: 3,
+                combo: "F9".to_string(),
+                macro_file: "login.macro".to_string(),
+                progress: Some(Progress {
+                    position_us: 2_500_000,
+                    total_us: 10_000_000,
+                    state_index: Some(4),
+                    elapsed: Duration::from_secs(2),
+                }),
+            }],
+            history: vec![HistoryEntry {
+                ago: Duration::from_secs(90),
+                combo: "CTRL+ALT+F5".to_string(),
+                macro_file: "farm.macro".to_string(),
+                outcome: Outcome::Failed("No such file".to_string()),
+            }],
+            problems: vec!["/home/me/macros/farm.macro: No such file".to_string()],
+            sessions: Some(vec![SessionStatus {
+                id: "3".to_string(),
+                user: "alex".to_string(),
+                seat: Some("seat0".to_string()),
+                active: true,
+                profile: Some("work".to_string()),
+            }]),
+        };
+
+        let report = status.to_string();
+        assert!(report.contains("Profiles: default [work]\nPanic hotkey: CTRL+ALT+ESC\nSustained: W\n"));
+        assert!(report.contains("Hotkeys are off after stop-all"));
+        assert!(report.starts_with("EvKey daemon (system-wide) - "));
+        assert!(report.contains("\nSessions:\n  3  alex  seat0  active  profile work\n"));
+        assert!(report.contains("  CTRL+ALT+F5  farm.macro\n  F9           login.macro\n"));
+        assert!(report.contains("  #3 login.macro (F9)   25%  2s elapsed, 7s left, state 4\n"));
+        assert!(report.contains("Problems:\n  /home/me/macros/farm.macro: No such file\n"));
+        assert!(report.contains("1m30s ago  CTRL+ALT+F5  farm.macro  failed: No such file\n"));
+    }
+}
@@ -0,0 +1,122 @@
+//! Easing curves for pointer movement
+//!
+//! When playback spreads a state's mouse movement over several reports, an
+//! easing curve decides how far along the pointer is at each one. `linear`
+//! moves at constant speed; `ease-in-out` speeds up and slows down again
+//! like a hand does; `bezier(x1,y1,x2,y2)` is any CSS-style cubic Bézier
+//! from (0,0) to (1,1).
+
+use std::fmt;
+use std::str::FromStr;
+
+/// How far along a movement the pointer is over time
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseInOut,
+    /// Control points of a cubic Bézier from (0,0) to (1,1)
+    Bezier(f64, f64, f64, f64),
+}
+
+/// Control points `ease-in-out` stands for, as in CSS
+const EASE_IN_OUT: (f64, f64, f64, f64) = (0.42, 0.0, 0.58, 1.0);
+
+impl Easing {
+    /// Fraction of the movement done at `t`, the fraction of its time passed
+    pub fn progress(&self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match *self {
+            Easing::Linear => t,
+            Easing::EaseInOut => {
+                let (x1, y1, x2, y2) = EASE_IN_OUT;
+                bezier_progress(x1, y1, x2, y2, t)
+            }
+            Easing::Bezier(x1, y1, x2, y2) => bezier_progress(x1, y1, x2, y2, t),
+        }
+    }
+}
+
+/// One coordinate of a cubic Bézier from 0 to 1 at parameter `s`
+fn bezier(p1: f64, p2: f64, s: f64) -> f64 {
+    let r = 1.0 - s;
+    3.0 * r * r * s * p1 + 3.0 * r * s * s * p2 + s * s * s
+}
+
+/// The curve's y where its x is `t`
+fn bezier_progress(x1: f64, y1: f64, x2: f64, y2: f64, t: f64) -> f64 {
+    // x grows monotonically with the parameter while x1 and x2 are in 0..=1,
+    // so bisection finds it
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..40 {
+        let mid = (low + high) / 2.0;
+        if bezier(x1, x2, mid) < t {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    bezier(y1, y2, (low + high) / 2.0)
+}
+
+impl FromStr for Easing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => return Ok(Easing::Linear),
+            "ease-in-out" => return Ok(Easing::EaseInOut),
+            _ => {}
+        }
+        let points = s
+            .strip_prefix("bezier(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .ok_or_else(|| format!("Unknown easing '{}', use linear, ease-in-out or bezier(x1,y1,x2,y2)", s))?;
+        let values = points
+            .split(',')
+            .map(|value| value.trim().parse::<f64>().ok().filter(|value| value.is_finite()))
+            .collect::<Option<Vec<f64>>>();
+        let Some(&[x1, y1, x2, y2]) = values.as_deref() else {
+            return Err(format!("Expected four numbers in '{}'", s));
+        };
+        if !(0.0..=1.0).contains(&x1) || !(0.0..=1.0).contains(&x2) {
+            return Err(format!("The x values of '{}' have to be between 0 and 1", s));
+        }
+        Ok(Easing::Bezier(x1, y1, x2, y2))
+    }
+}
+
+impl fmt::Display for Easing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Easing::Linear => write!(f, "linear"),
+            Easing::EaseInOut => write!(f, "ease-in-out"),
+            Easing::Bezier(x1, y1, x2, y2) => write!(f, "bezier({},{},{},{})", x1, y1, x2, y2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_easing_curves() {
+        for easing in [Easing::Linear, Easing::EaseInOut, "bezier(0.1,0.7,0.3,1)".parse().unwrap()] {
+            assert!(easing.progress(0.0).abs() < 1e-6);
+            assert!((easing.progress(1.0) - 1.0).abs() < 1e-6);
+            assert_eq!(easing.to_string().parse::<Easing>(), Ok(easing));
+        }
+        assert_eq!(Easing::Linear.progress(0.25), 0.25);
+
+        // Slow at both ends, symmetric around the middle
+        let ease = Easing::EaseInOut;
+        assert!(ease.progress(0.1) < 0.05);
+        assert!((ease.progress(0.5) - 0.5).abs() < 1e-6);
+        assert!((ease.progress(0.9) - 0.98).abs() < 0.02);
+
+        assert!("bounce".parse::<Easing>().unwrap_err().contains("Unknown easing"));
+        assert!("bezier(0,1)".parse::<Easing>().unwrap_err().contains("four numbers"));
+        assert!("bezier(2,0,0.5,1)".parse::<Easing>().unwrap_err().contains("between 0 and 1"));
+    }
+}
@@ -0,0 +1,482 @@
+//! Undoable editing of a [`Macro`]
+//!
+//! Editors change a macro through an [`Editor`], which applies each change
+//! as an [`Edit`] - a command that knows how to revert itself - and keeps
+//! them on an undo stack. Operations made of many small changes, like
+//! retiming a range of states, are applied as one [`Group`] so a single undo
+//! reverts all of it.
+//!
+//! Edits only change states and the markers, actions and click positions
+//! placed at them; undoing one assumes the macro is as the edit left it,
+//! which the undo stack guarantees as long as all changes go through it.
+
+use crate::keyset::KeySet;
+use crate::preview::{Prefix, PrefixCache};
+use crate::state::MacroState;
+use crate::storage::{ActionStep, ClickPosition, Macro, Marker};
+
+/// Most edits kept for undoing; older ones are forgotten
+pub const HISTORY_LIMIT: usize = 1000;
+
+/// A change to a macro that can be reverted
+pub trait Edit {
+    /// Make the change, or leave the macro untouched and explain why not
+    fn apply(&mut self, macro_: &mut Macro) -> Result<(), String>;
+
+    /// Undo the change, on the macro as `apply` left it
+    fn revert(&mut self, macro_: &mut Macro);
+
+    /// What the edit does, for an undo menu ("Remove state 4")
+    fn describe(&self) -> String;
+
+    /// First state the edit can change; the ones before it play the same
+    fn first_state(&self) -> usize {
+        0
+    }
+}
+
+/// Replace the state at `index`
+pub struct SetState {
+    pub index: usize,
+    pub state: MacroState,
+}
+
+impl Edit for SetState {
+    fn apply(&mut self, macro_: &mut Macro) -> Result<(), String> {
+        let current = state_mut(macro_, self.index)?;
+        std::mem::swap(current, &mut self.state);
+        Ok(())
+    }
+
+    fn revert(&mut self, macro_: &mut Macro) {
+        std::mem::swap(&mut macro_.states[self.index], &mut self.state);
+    }
+
+    fn describe(&self) -> String {
+        format!("Change state {}", self.index)
+    }
+
+    fn first_state(&self) -> usize {
+        self.index
+    }
+}
+
+/// Insert a state before `index`; markers and actions there stay before the
+/// state they were before
+pub struct InsertState {
+    pub index: usize,
+    pub state: MacroState,
+}
+
+impl Edit for InsertState {
+    fn apply(&mut self, macro_: &mut Macro) -> Result<(), String> {
+        if self.index > macro_.states.len() {
+            return Err(format!("No state {} to insert before", self.index));
+        }
+        macro_.states.insert(self.index, self.state.clone());
+        shift_indices(macro_, |index| if index >= self.index { index + 1 } else { index });
+        Ok(())
+    }
+
+    fn revert(&mut self, macro_: &mut Macro) {
+        macro_.states.remove(self.index);
+        shift_indices(macro_, |index| if index > self.index { index - 1 } else { index });
+    }
+
+    fn describe(&self) -> String {
+        format!("Insert state {}", self.index)
+    }
+
+    fn first_state(&self) -> usize {
+        self.index
+    }
+}
+
+/// Remove the state at `index`, along with its click position; markers and
+/// actions before it move to the state after
+pub struct RemoveState {
+    pub index: usize,
+    /// What's needed to put it back, once applied
+    removed: Option<Removed>,
+}
+
+struct Removed {
+    state: MacroState,
+    markers: Vec<Marker>,
+    actions: Vec<ActionStep>,
+    clicks: Vec<ClickPosition>,
+}
+
+impl RemoveState {
+    pub fn new(index: usize) -> Self {
+        Self { index, removed: None }
+    }
+}
+
+impl Edit for RemoveState {
+    fn apply(&mut self, macro_: &mut Macro) -> Result<(), String> {
+        state_mut(macro_, self.index)?;
+        // Indices after the removal can't tell what was before the state
+        // from what was after it, so the originals are kept whole
+        self.removed = Some(Removed {
+            state: macro_.states.remove(self.index),
+            markers: macro_.markers.clone(),
+            actions: macro_.actions.clone(),
+            clicks: macro_.clicks.clone(),
+        });
+        macro_.clicks.retain(|click| click.index != self.index);
+        shift_indices(macro_, |index| if index > self.index { index - 1 } else { index });
+        Ok(())
+    }
+
+    fn revert(&mut self, macro_: &mut Macro) {
+        let removed = self.removed.take().expect("reverting a RemoveState that wasn't applied");
+        macro_.states.insert(self.index, removed.state);
+        macro_.markers = removed.markers;
+        macro_.actions = removed.actions;
+        macro_.clicks = removed.clicks;
+    }
+
+    fn describe(&self) -> String {
+        format!("Remove state {}", self.index)
+    }
+
+    fn first_state(&self) -> usize {
+        self.index
+    }
+}
+
+/// Insert a clip before the state at `index`, see [`Macro::insert_clip`]
+pub struct InsertClip {
+    pub index: usize,
+    pub clip: Macro,
+    /// Markers, actions and click positions from before, once applied
+    before: Option<(Vec<Marker>, Vec<ActionStep>, Vec<ClickPosition>)>,
+}
+
+impl InsertClip {
+    pub fn new(index: usize, clip: Macro) -> Self {
+        Self { index, clip, before: None }
+    }
+}
+
+impl Edit for InsertClip {
+    fn apply(&mut self, macro_: &mut Macro) -> Result<(), String> {
+        if self.index > macro_.states.len() {
+            return Err(format!("No state {} to insert before", self.index));
+        }
+        self.before = Some((macro_.markers.clone(), macro_.actions.clone(), macro_.clicks.clone()));
+        macro_.insert_clip(self.index, &self.clip);
+        Ok(())
+    }
+
+    fn revert(&mut self, macro_: &mut Macro) {
+        let (markers, actions, clicks) = self.before.take().expect("reverting an InsertClip that wasn't applied");
+        macro_.states.drain(self.index..self.index + self.clip.states.len());
+        macro_.markers = markers;
+        macro_.actions = actions;
+        macro_.clicks = clicks;
+    }
+
+    fn describe(&self) -> String {
+        format!("Insert clip at state {}", self.index)
+    }
+
+    fn first_state(&self) -> usize {
+        self.index
+    }
+}
+
+/// Any change at all, made by a function and reverted by restoring a copy
+/// of the macro from before; for operations like [`Macro::anchor_clicks`]
+/// that touch too much to revert piece by piece
+pub struct Transform<F> {
+    name: String,
+    change: F,
+    before: Option<Macro>,
+}
+
+impl<F: FnMut(&mut Macro) -> Result<(), String>> Transform<F> {
+    pub fn new(name: &str, change: F) -> Self {
+        Self {
+            name: name.to_string(),
+            change,
+            before: None,
+        }
+    }
+}
+
+impl<F: FnMut(&mut Macro) -> Result<(), String>> Edit for Transform<F> {
+    fn apply(&mut self, macro_: &mut Macro) -> Result<(), String> {
+        let before = macro_.clone();
+        if let Err(e) = (self.change)(macro_) {
+            *macro_ = before;
+            return Err(e);
+        }
+        self.before = Some(before);
+        Ok(())
+    }
+
+    fn revert(&mut self, macro_: &mut Macro) {
+        *macro_ = self.before.take().expect("reverting a Transform that wasn't applied");
+    }
+
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Several edits undone and redone as one
+pub struct Group {
+    pub name: String,
+    pub edits: Vec<Box<dyn Edit>>,
+}
+
+impl Edit for Group {
+    fn apply(&mut self, macro_: &mut Macro) -> Result<(), String> {
+        for (applied, edit) in self.edits.iter_mut().enumerate() {
+            if let Err(e) = edit.apply(macro_) {
+                // All or nothing
+                for edit in self.edits[..applied].iter_mut().rev() {
+                    edit.revert(macro_);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn revert(&mut self, macro_: &mut Macro) {
+        for edit in self.edits.iter_mut().rev() {
+            edit.revert(macro_);
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+
+    fn first_state(&self) -> usize {
+        self.edits.iter().map(|edit| edit.first_state()).min().unwrap_or(0)
+    }
+}
+
+/// A macro being edited, with its undo and redo stacks
+pub struct Editor {
+    macro_: Macro,
+    undo: Vec<Box<dyn Edit>>,
+    redo: Vec<Box<dyn Edit>>,
+    /// Kept up to date with every change, for previewing from a state
+    prefixes: PrefixCache,
+}
+
+impl Editor {
+    pub fn new(macro_: Macro) -> Self {
+        Self {
+            macro_,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            prefixes: PrefixCache::new(),
+        }
+    }
+
+    pub fn macro_(&self) -> &Macro {
+        &self.macro_
+    }
+
+    pub fn into_macro(self) -> Macro {
+        self.macro_
+    }
+
+    /// Apply an edit and put it on the undo stack; whatever was undone can't
+    /// be redone anymore
+    pub fn apply(&mut self, mut edit: Box<dyn Edit>) -> Result<(), String> {
+        edit.apply(&mut self.macro_)?;
+        self.prefixes.invalidate(edit.first_state());
+        self.redo.clear();
+        self.undo.push(edit);
+        if self.undo.len() > HISTORY_LIMIT {
+            self.undo.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Apply the edits `build` makes as one [`Group`]
+    pub fn group<F>(&mut self, name: &str, build: F) -> Result<(), String>
+    where
+        F: FnOnce(&Macro) -> Vec<Box<dyn Edit>>,
+    {
+        let edits = build(&self.macro_);
+        self.apply(Box::new(Group {
+            name: name.to_string(),
+            edits,
+        }))
+    }
+
+    /// Revert the last edit; returns what it did, or `None` if there's
+    /// nothing to undo
+    pub fn undo(&mut self) -> Option<String> {
+        let mut edit = self.undo.pop()?;
+        edit.revert(&mut self.macro_);
+        self.prefixes.invalidate(edit.first_state());
+        let name = edit.describe();
+        self.redo.push(edit);
+        Some(name)
+    }
+
+    /// Apply the last undone edit again
+    pub fn redo(&mut self) -> Option<String> {
+        let mut edit = self.redo.pop()?;
+        // It applied to this very macro before, so it applies again
+        edit.apply(&mut self.macro_).ok()?;
+        self.prefixes.invalidate(edit.first_state());
+        let name = edit.describe();
+        self.undo.push(edit);
+        Some(name)
+    }
+
+    /// Where playback stands on reaching the state at `index` (see
+    /// [`crate::preview`]), or the end at `states.len()`
+    pub fn prefix(&mut self, index: usize) -> Result<&Prefix, String> {
+        self.prefixes.at(&self.macro_, index).ok_or_else(|| format!("No state {}", index))
+    }
+
+    /// What undo would revert
+    pub fn undo_name(&self) -> Option<String> {
+        self.undo.last().map(|edit| edit.describe())
+    }
+
+    /// What redo would apply
+    pub fn redo_name(&self) -> Option<String> {
+        self.redo.last().map(|edit| edit.describe())
+    }
+
+    /// Set how long the state at `index` lasts
+    pub fn set_duration(&mut self, index: usize, duration_ms: u64) -> Result<(), String> {
+        let mut state = self.state(index)?.clone();
+        state.duration_ms = duration_ms;
+        self.apply(Box::new(SetState { index, state }))
+    }
+
+    /// Set which keys the state at `index` holds
+    pub fn set_keys(&mut self, index: usize, keys: KeySet) -> Result<(), String> {
+        let mut state = self.state(index)?.clone();
+        state.keys_pressed = keys;
+        self.apply(Box::new(SetState { index, state }))
+    }
+
+    pub fn insert_state(&mut self, index: usize, state: MacroState) -> Result<(), String> {
+        self.apply(Box::new(InsertState { index, state }))
+    }
+
+    pub fn remove_state(&mut self, index: usize) -> Result<(), String> {
+        self.apply(Box::new(RemoveState::new(index)))
+    }
+
+    /// Insert `clip` (see [`crate::clips`]) before the state at `index`
+    pub fn insert_clip(&mut self, index: usize, clip: Macro) -> Result<(), String> {
+        self.apply(Box::new(InsertClip::new(index, clip)))
+    }
+
+    /// Scale how long each state in `from..to` lasts by `factor`, as one
+    /// undoable edit
+    pub fn retime_range(&mut self, from: usize, to: usize, factor: f64) -> Result<(), String> {
+        if !(factor.is_finite() && factor >= 0.0) {
+            return Err(format!("Invalid retiming factor {}", factor));
+        }
+        if from > to || to > self.macro_.states.len() {
+            return Err(format!("No states {}..{} to retime", from, to));
+        }
+        self.group(&format!("Retime states {}-{}", from, to), |macro_| {
+            macro_.states[from..to]
+                .iter()
+                .enumerate()
+                .map(|(offset, state)| {
+                    let mut state = state.clone();
+                    state.duration_ms = (state.duration_ms as f64 * factor).round() as u64;
+                    Box::new(SetState { index: from + offset, state }) as Box<dyn Edit>
+                })
+                .collect()
+        })
+    }
+
+    fn state(&self, index: usize) -> Result<&MacroState, String> {
+        self.macro_.states.get(index).ok_or_else(|| format!("No state {}", index))
+    }
+}
+
+fn state_mut(macro_: &mut Macro, index: usize) -> Result<&mut MacroState, String> {
+    let count = macro_.states.len();
+    macro_
+        .states
+        .get_mut(index)
+        .ok_or_else(|| format!("No state {} (the macro has {})", index, count))
+}
+
+/// Move markers, actions and click positions to the state `map` gives
+fn shift_indices(macro_: &mut Macro, map: impl Fn(usize) -> usize) {
+    for marker in &mut macro_.markers {
+        marker.index = map(marker.index);
+    }
+    for step in &mut macro_.actions {
+        step.index = map(step.index);
+    }
+    for click in &mut macro_.clicks {
+        click.index = map(click.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{format_macro, parse_macro};
+
+    #[test]
+    fn test_undo_redo() {
+        let original = parse_macro("hold A for 40ms\nmark next\nwait 100ms\nat 10 20\ntap BTN_LEFT\nwait 60ms\n").unwrap();
+        let text = format_macro(&original);
+        let mut editor = Editor::new(original);
+
+        editor.remove_state(1).unwrap();
+        assert_eq!(editor.macro_().marker("next"), Some(1));
+        editor.insert_state(0, MacroState::new(5)).unwrap();
+        assert_eq!(editor.macro_().clicks[0].index, 2);
+        editor.retime_range(0, 4, 2.0).unwrap();
+        assert_eq!(editor.macro_().states[3].duration_ms, 120);
+        assert!(editor.retime_range(2, 9, 2.0).is_err());
+        editor
+            .apply(Box::new(Transform::new("Anchor clicks", |macro_: &mut Macro| {
+                macro_.anchor_clicks();
+                Ok(())
+            })))
+            .unwrap();
+
+        assert_eq!(editor.undo_name().as_deref(), Some("Anchor clicks"));
+        let edited = format_macro(editor.macro_());
+        for _ in 0..4 {
+            assert!(editor.undo().is_some());
+        }
+        assert_eq!(editor.undo(), None);
+        assert_eq!(format_macro(editor.macro_()), text);
+
+        for _ in 0..4 {
+            assert!(editor.redo().is_some());
+        }
+        assert_eq!(format_macro(editor.macro_()), edited);
+
+        editor.undo();
+        assert_eq!(editor.prefix(1).unwrap().start_us, 10_000);
+        editor.set_duration(0, 1).unwrap();
+        assert_eq!(editor.redo_name(), None);
+        // Previews see the edit
+        assert_eq!(editor.prefix(1).unwrap().start_us, 1000);
+        assert!(editor.prefix(99).is_err());
+
+        let before = format_macro(editor.macro_());
+        let clip = parse_macro("mark clip\nhold W for 10ms\n").unwrap();
+        editor.insert_clip(1, clip.clip(0, 1)).unwrap();
+        assert_eq!(editor.macro_().marker("clip"), Some(1));
+        assert_eq!(editor.undo().as_deref(), Some("Insert clip at state 1"));
+        assert_eq!(format_macro(editor.macro_()), before);
+    }
+}
@@ -0,0 +1,92 @@
+//! Input events, independent of the device layer
+//!
+//! Mirrors the parts of evdev's event API the format and state code need, so
+//! that code builds without evdev (for example for a wasm32 macro editor).
+//! With the `devices` feature, events convert to and from evdev's types.
+
+/// Kind of input event, using the kernel's `EV_*` numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EventType(pub u16);
+
+impl EventType {
+    pub const SYNCHRONIZATION: EventType = EventType(0x00);
+    pub const KEY: EventType = EventType(0x01);
+    pub const RELATIVE: EventType = EventType(0x02);
+    pub const ABSOLUTE: EventType = EventType(0x03);
+    pub const MISC: EventType = EventType(0x04);
+    pub const SWITCH: EventType = EventType(0x05);
+    pub const LED: EventType = EventType(0x11);
+    pub const SOUND: EventType = EventType(0x12);
+    pub const REPEAT: EventType = EventType(0x14);
+}
+
+/// A single input event (type, code, value)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    event_type: u16,
+    code: u16,
+    value: i32,
+}
+
+impl InputEvent {
+    pub const fn new(event_type: u16, code: u16, value: i32) -> Self {
+        Self {
+            event_type,
+            code,
+            value,
+        }
+    }
+
+    pub fn event_type(&self) -> EventType {
+        EventType(self.event_type)
+    }
+
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn value(&self) -> i32 {
+        self.value
+    }
+}
+
+#[cfg(feature = "devices")]
+impl From<evdev::InputEvent> for InputEvent {
+    fn from(event: evdev::InputEvent) -> Self {
+        Self::new(event.event_type().0, event.code(), event.value())
+    }
+}
+
+#[cfg(feature = "devices")]
+impl From<InputEvent> for evdev::InputEvent {
+    fn from(event: InputEvent) -> Self {
+        evdev::InputEvent::new(event.event_type, event.code, event.value)
+    }
+}
+
+/// Recorded event with relative timestamp
+#[derive(Debug, Clone)]
+pub struct RecordedEvent {
+    /// Time since recording started (in microseconds)
+    pub timestamp_us: u64,
+    /// The actual input event
+    pub event: InputEvent,
+}
+
+/// Pointer position read when a mouse button was pressed during recording
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordedClick {
+    /// Time since recording started (in microseconds)
+    pub timestamp_us: u64,
+    /// Screen coordinates
+    pub x: i32,
+    pub y: i32,
+}
+
+/// Named marker inserted with the annotation hotkey during recording
+#[derive(Debug, Clone)]
+pub struct RecordedMarker {
+    /// Time since recording started (in microseconds)
+    pub timestamp_us: u64,
+    pub name: String,
+}
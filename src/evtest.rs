@@ -0,0 +1,213 @@
+//! Importing event dumps from bug reports
+//!
+//! Reads the text that common tools print for input events, so a recording can
+//! be rebuilt from what a user pasted:
+//!
+//!   evtest:      Event: time 1700000000.250000, type 1 (EV_KEY), code 30 (KEY_A), value 1
+//!                Event: time 1700000000.250000, -------------- SYN_REPORT ------------
+//!   evbug/dmesg: [ 1234.567890] evbug: Event. Dev: input3, Type: 1, Code: 30, Value: 1
+//!   evemu:       E: 0.250000 0001 001e 0001
+//!
+//! Formats can be mixed and anything else (device info, headers, chatter in
+//! the log) is skipped. Timestamps are rebased so the first event is at zero.
+
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use crate::keymap;
+
+/// `MSC_RAW` and `MSC_SCAN`, whose values evtest prints in hex
+const HEX_MSC_CODES: &[u16] = &[3, 4];
+
+/// Parse a dump into events, in the order they appear
+pub fn parse(text: &str) -> Result<Vec<RecordedEvent>, String> {
+    let mut events = Vec::new();
+    let mut origin_us = None;
+    let mut last_us = 0;
+
+    for (line_num, line) in text.lines().enumerate() {
+        let parsed = parse_evtest_line(line)
+            .or_else(|| parse_evbug_line(line))
+            .or_else(|| parse_evemu_line(line));
+        let Some(parsed) = parsed else {
+            continue;
+        };
+        let (time_us, event) = parsed.map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
+
+        if event.event_type() == EventType::KEY && event.code() > keymap::KEY_MAX {
+            return Err(format!("Line {}: Invalid key code {}", line_num + 1, event.code()));
+        }
+
+        // Logs from several sources can be slightly out of order; never go back
+        let origin_us = *origin_us.get_or_insert(time_us);
+        last_us = time_us.saturating_sub(origin_us).max(last_us);
+        events.push(RecordedEvent {
+            timestamp_us: last_us,
+            event,
+        });
+    }
+
+    if events.is_empty() {
+        return Err("No input events found (expected evtest, evbug or evemu output)".to_string());
+    }
+    Ok(events)
+}
+
+/// `Event: time 1700000000.250000, type 1 (EV_KEY), code 30 (KEY_A), value 1`
+fn parse_evtest_line(line: &str) -> Option<Result<(u64, InputEvent), String>> {
+    let rest = line.trim().strip_prefix("Event: time ")?;
+    Some(evtest_event(line, rest))
+}
+
+fn evtest_event(line: &str, rest: &str) -> Result<(u64, InputEvent), String> {
+    let (time, rest) = rest
+        .split_once(',')
+        .ok_or_else(|| format!("Invalid evtest event: {}", line.trim()))?;
+    let time_us = parse_time(time.trim())?;
+
+    if rest.contains("SYN_REPORT") {
+        return Ok((time_us, InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)));
+    }
+
+    let fields: Vec<&str> = rest.split(',').map(str::trim).collect();
+    let [event_type, code, value] = fields[..] else {
+        return Err(format!("Invalid evtest event: {}", line.trim()));
+    };
+    let event_type = number_field(event_type, "type ")?;
+    let code = number_field(code, "code ")?;
+    let value = value
+        .strip_prefix("value ")
+        .ok_or_else(|| format!("Missing value: {}", line.trim()))?;
+    let value = if event_type == EventType::MISC.0 && HEX_MSC_CODES.contains(&code) {
+        u32::from_str_radix(value, 16).map(|v| v as i32).ok()
+    } else {
+        value.parse().ok()
+    }
+    .ok_or_else(|| format!("Invalid value: {}", value))?;
+
+    Ok((time_us, InputEvent::new(event_type, code, value)))
+}
+
+/// `[ 1234.567890] evbug: Event. Dev: input3, Type: 1, Code: 30, Value: 1`
+fn parse_evbug_line(line: &str) -> Option<Result<(u64, InputEvent), String>> {
+    let (prefix, rest) = line.split_once("evbug: Event.")?;
+    Some(evbug_event(line, prefix, rest))
+}
+
+fn evbug_event(line: &str, prefix: &str, rest: &str) -> Result<(u64, InputEvent), String> {
+    let time = prefix
+        .split_once('[')
+        .and_then(|(_, rest)| rest.split_once(']'))
+        .map(|(time, _)| time.trim())
+        .ok_or_else(|| "evbug line has no [timestamp] (copy it from dmesg)".to_string())?;
+    let time_us = parse_time(time)?;
+
+    let mut fields = rest.split(',').map(str::trim).skip(1);
+    let mut field = |name: &str| {
+        fields
+            .next()
+            .and_then(|f| f.strip_prefix(name))
+            .map(str::trim)
+            .ok_or_else(|| format!("Missing {} in: {}", name.trim_end_matches(':'), line.trim()))
+    };
+    let event_type = field("Type:")?;
+    let code = field("Code:")?;
+    let value = field("Value:")?;
+
+    Ok((
+        time_us,
+        InputEvent::new(
+            event_type.parse().map_err(|_| format!("Invalid type: {}", event_type))?,
+            code.parse().map_err(|_| format!("Invalid code: {}", code))?,
+            value.parse().map_err(|_| format!("Invalid value: {}", value))?,
+        ),
+    ))
+}
+
+/// `E: 0.250000 0001 001e 0001` (type and code in hex)
+fn parse_evemu_line(line: &str) -> Option<Result<(u64, InputEvent), String>> {
+    let rest = line.trim().strip_prefix("E: ")?;
+    Some(evemu_event(line, rest))
+}
+
+fn evemu_event(line: &str, rest: &str) -> Result<(u64, InputEvent), String> {
+    // Anything after '#' is evemu's human-readable annotation
+    let rest = rest.split('#').next().unwrap_or_default();
+    let [time, event_type, code, value] = rest.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(format!("Invalid evemu event: {}", line.trim()));
+    };
+
+    Ok((
+        parse_time(time)?,
+        InputEvent::new(
+            u16::from_str_radix(event_type, 16).map_err(|_| format!("Invalid type: {}", event_type))?,
+            u16::from_str_radix(code, 16).map_err(|_| format!("Invalid code: {}", code))?,
+            value.parse().map_err(|_| format!("Invalid value: {}", value))?,
+        ),
+    ))
+}
+
+/// Parse `type 1 (EV_KEY)` style fields
+fn number_field(field: &str, name: &str) -> Result<u16, String> {
+    field
+        .strip_prefix(name)
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| format!("Invalid {}: {}", name.trim(), field))
+}
+
+/// Parse `seconds.fraction` into microseconds
+fn parse_time(time: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid timestamp: {}", time);
+    let (secs, fraction) = time.split_once('.').unwrap_or((time, ""));
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    // Microsecond precision; pad or cut the fraction to six digits
+    let micros: String = fraction.chars().chain(std::iter::repeat('0')).take(6).collect();
+    let secs: u64 = secs.parse().map_err(|_| invalid())?;
+    secs.checked_mul(1_000_000)
+        .and_then(|us| us.checked_add(micros.parse::<u64>().ok()?))
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mixed_dump() {
+        let dump = "\
+Input driver version is 1.0.1
+Event code 30 (KEY_A)
+Event: time 1700000000.250000, type 4 (EV_MSC), code 4 (MSC_SCAN), value 7001e
+Event: time 1700000000.250000, type 1 (EV_KEY), code 30 (KEY_A), value 1
+Event: time 1700000000.250000, -------------- SYN_REPORT ------------
+Event: time 1700000000.350000, type 1 (EV_KEY), code 30 (KEY_A), value 0
+[ 1234.5] evbug: Event. Dev: input3, Type: 2, Code: 0, Value: -4
+E: 0.300000 0001 001e 0000\t# EV_KEY / KEY_A 0
+";
+        let events = parse(dump).unwrap();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.timestamp_us, e.event.event_type().0, e.event.code(), e.event.value()))
+            .collect();
+
+        assert_eq!(summary[0], (0, 4, 4, 0x7001e));
+        assert_eq!(summary[1], (0, 1, 30, 1));
+        assert_eq!(summary[2], (0, 0, 0, 0));
+        assert_eq!(summary[3], (100_000, 1, 30, 0));
+        // Timestamps from other sources that would go backwards are clamped
+        assert_eq!(summary[4], (100_000, 2, 0, -4));
+        assert_eq!(summary[5], (100_000, 1, 30, 0));
+    }
+
+    #[test]
+    fn test_rejects_bad_events() {
+        assert_eq!(parse("Event code 30 (KEY_A)\n").unwrap_err(), "No input events found (expected evtest, evbug or evemu output)");
+        assert!(parse("E: 0.1 0001 0300 0001\n").unwrap_err().contains("Invalid key code"));
+        assert!(parse("Event: time 99999999999999999999.0, type 1 (EV_KEY), code 30 (KEY_A), value 1\n")
+            .unwrap_err()
+            .starts_with("Line 1: Invalid timestamp"));
+        assert!(parse("[ 1.0] evbug: Event. Dev: input3, Type: 1\n").is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Export macros as standalone replay programs, or into keyboard firmware
+//!
+//! The generated C source talks to uinput directly, so a macro can be
+//! replayed on a machine without EvKey installed:
+//!   cc -o replay replay.c && sudo ./replay
+//!
+//! Simple keyboard macros can also go into the keyboard itself, as a QMK
+//! `SEND_STRING` snippet ([`to_qmk`]) or a VIA macro ([`to_via`]). Firmware
+//! only has key presses and delays, so macros that use the mouse or run
+//! actions are refused.
+
+use crate::event::RecordedEvent;
+use crate::input_key::InputKey;
+use crate::keyset::KeySet;
+use crate::storage::Macro;
+use std::fmt::Write;
+
+/// Generate a self-contained C program that replays `events` via uinput
+pub fn to_c_source(events: &[RecordedEvent], source_name: &str) -> String {
+    let mut out = String::new();
+
+    // Writing to a String can't fail
+    let _ = writeln!(out, "/*");
+    let _ = writeln!(out, " * Generated by EvKey from {}", source_name.replace("*/", "* /"));
+    let _ = writeln!(out, " * Build: cc -o replay replay.c");
+    let _ = writeln!(out, " * Run:   sudo ./replay   (needs write access to /dev/uinput)");
+    let _ = writeln!(out, " */");
+    out.push_str(C_PRELUDE);
+
+    out.push_str("static const struct replay_event events[] = {\n");
+    for recorded in events {
+        let _ = writeln!(
+            out,
+            "    {{{}ULL, {}, {}, {}}},",
+            recorded.timestamp_us,
+            recorded.event.event_type().0,
+            recorded.event.code(),
+            recorded.event.value()
+        );
+    }
+    if events.is_empty() {
+        // Empty initializer lists aren't valid C
+        out.push_str("    {0ULL, EV_SYN, SYN_REPORT, 0},\n");
+    }
+    out.push_str("};\n");
+
+    out.push_str(C_MAIN);
+    out
+}
+
+const C_PRELUDE: &str = r#"
+#include <fcntl.h>
+#include <linux/uinput.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/ioctl.h>
+#include <time.h>
+#include <unistd.h>
+
+struct replay_event {
+    unsigned long long timestamp_us;
+    unsigned short type;
+    unsigned short code;
+    int value;
+};
+
+"#;
+
+const C_MAIN: &str = r#"
+static void emit(int fd, unsigned short type, unsigned short code, int value)
+{
+    struct input_event ie;
+    memset(&ie, 0, sizeof(ie));
+    ie.type = type;
+    ie.code = code;
+    ie.value = value;
+    if (write(fd, &ie, sizeof(ie)) != sizeof(ie))
+        perror("write");
+}
+
+static void sleep_us(unsigned long long us)
+{
+    struct timespec ts;
+    ts.tv_sec = us / 1000000ULL;
+    ts.tv_nsec = (us % 1000000ULL) * 1000ULL;
+    nanosleep(&ts, NULL);
+}
+
+int main(void)
+{
+    struct uinput_setup setup;
+    unsigned long long last = 0;
+    size_t i;
+    int key;
+    int fd = open("/dev/uinput", O_WRONLY | O_NONBLOCK);
+
+    if (fd < 0) {
+        perror("open /dev/uinput");
+        return 1;
+    }
+
+    ioctl(fd, UI_SET_EVBIT, EV_KEY);
+    for (key = 0; key <= KEY_MAX; key++)
+        ioctl(fd, UI_SET_KEYBIT, key);
+
+    ioctl(fd, UI_SET_EVBIT, EV_REL);
+    ioctl(fd, UI_SET_RELBIT, REL_X);
+    ioctl(fd, UI_SET_RELBIT, REL_Y);
+    ioctl(fd, UI_SET_RELBIT, REL_WHEEL);
+    ioctl(fd, UI_SET_RELBIT, REL_HWHEEL);
+
+    memset(&setup, 0, sizeof(setup));
+    setup.id.bustype = BUS_VIRTUAL;
+    strcpy(setup.name, "evkey-replay");
+
+    if (ioctl(fd, UI_DEV_SETUP, &setup) < 0 || ioctl(fd, UI_DEV_CREATE) < 0) {
+        perror("create uinput device");
+        close(fd);
+        return 1;
+    }
+
+    /* Give the desktop time to pick up the new device */
+    sleep(1);
+
+    for (i = 0; i < sizeof(events) / sizeof(events[0]); i++) {
+        if (events[i].timestamp_us > last)
+            sleep_us(events[i].timestamp_us - last);
+        emit(fd, events[i].type, events[i].code, events[i].value);
+        last = events[i].timestamp_us;
+    }
+
+    ioctl(fd, UI_DEV_DESTROY);
+    close(fd);
+    return 0;
+}
+"#;
+
+/// Longest hold exported as a tap (in milliseconds); firmware taps keys at
+/// its own speed
+pub const TAP_MAX_MS: u64 = 50;
+
+/// QMK's short names for the keys it can send, by evdev code
+const QMK_KEYS: &[(u16, &str)] = &[
+    (1, "ESC"), (2, "1"), (3, "2"), (4, "3"), (5, "4"), (6, "5"), (7, "6"), (8, "7"), (9, "8"), (10, "9"),
+    (11, "0"), (12, "MINS"), (13, "EQL"), (14, "BSPC"), (15, "TAB"), (16, "Q"), (17, "W"), (18, "E"),
+    (19, "R"), (20, "T"), (21, "Y"), (22, "U"), (23, "I"), (24, "O"), (25, "P"), (26, "LBRC"), (27, "RBRC"),
+    (28, "ENT"), (29, "LCTL"), (30, "A"), (31, "S"), (32, "D"), (33, "F"), (34, "G"), (35, "H"), (36, "J"),
+    (37, "K"), (38, "L"), (39, "SCLN"), (40, "QUOT"), (41, "GRV"), (42, "LSFT"), (43, "BSLS"), (44, "Z"),
+    (45, "X"), (46, "C"), (47, "V"), (48, "B"), (49, "N"), (50, "M"), (51, "COMM"), (52, "DOT"),
+    (53, "SLSH"), (54, "RSFT"), (55, "PAST"), (56, "LALT"), (57, "SPC"), (58, "CAPS"), (59, "F1"),
+    (60, "F2"), (61, "F3"), (62, "F4"), (63, "F5"), (64, "F6"), (65, "F7"), (66, "F8"), (67, "F9"),
+    (68, "F10"), (69, "NUM"), (70, "SCRL"), (71, "P7"), (72, "P8"), (73, "P9"), (74, "PMNS"), (75, "P4"),
+    (76, "P5"), (77, "P6"), (78, "PPLS"), (79, "P1"), (80, "P2"), (81, "P3"), (82, "P0"), (83, "PDOT"),
+    (86, "NUBS"), (87, "F11"), (88, "F12"), (96, "PENT"), (97, "RCTL"), (98, "PSLS"), (99, "PSCR"),
+    (100, "RALT"), (102, "HOME"), (103, "UP"), (104, "PGUP"), (105, "LEFT"), (106, "RGHT"), (107, "END"),
+    (108, "DOWN"), (109, "PGDN"), (110, "INS"), (111, "DEL"), (113, "MUTE"), (114, "VOLD"), (115, "VOLU"),
+    (117, "PEQL"), (119, "PAUS"), (121, "PCMM"), (125, "LGUI"), (126, "RGUI"), (127, "APP"), (183, "F13"),
+    (184, "F14"), (185, "F15"), (186, "F16"), (187, "F17"), (188, "F18"), (189, "F19"), (190, "F20"),
+    (191, "F21"), (192, "F22"), (193, "F23"), (194, "F24"),
+];
+
+/// One thing a firmware macro does, naming keys by their QMK short name
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Down(&'static str),
+    Up(&'static str),
+    Tap(&'static str),
+    DelayMs(u64),
+}
+
+/// `macro_` as key presses and delays, or why it can't be
+fn firmware_steps(macro_: &Macro) -> Result<Vec<Step>, String> {
+    if let Some(step) = macro_.actions.first() {
+        return Err(format!("Firmware can't run actions like '{}'", step.action));
+    }
+    let name = |code: u16| {
+        QMK_KEYS
+            .iter()
+            .find(|(key, _)| *key == code)
+            .map(|(_, name)| *name)
+            .ok_or_else(|| format!("Firmware macros can't send {}", InputKey::from(code)))
+    };
+
+    let mut steps = Vec::new();
+    let mut held = KeySet::new();
+    for (state, start_us) in macro_.states.iter().zip(macro_.state_starts_us()) {
+        let at = start_us as f64 / 1_000_000.0;
+        if state.mouse_delta != (0, 0) || state.scroll_delta != (0, 0) {
+            return Err(format!("Firmware macros can't move the mouse or scroll (at {:.3}s)", at));
+        }
+        if let Some(button) = state.keys_pressed.iter().find(|key| !key.is_keyboard()) {
+            return Err(format!("Firmware macros can't press {} (at {:.3}s)", button, at));
+        }
+        for code in held.difference(&state.keys_pressed).map(|key| key.code()) {
+            steps.push(Step::Up(name(code)?));
+        }
+        for code in state.keys_pressed.difference(&held).map(|key| key.code()) {
+            steps.push(Step::Down(name(code)?));
+        }
+        if state.duration_ms > 0 {
+            steps.push(Step::DelayMs(state.duration_ms));
+        }
+        held = state.keys_pressed.clone();
+    }
+    for code in held.codes() {
+        steps.push(Step::Up(name(code)?));
+    }
+
+    // Short holds become taps, and delays in a row one delay
+    let mut merged: Vec<Step> = Vec::with_capacity(steps.len());
+    for step in steps {
+        match (&merged[..], step) {
+            ([.., Step::Down(down), Step::DelayMs(ms)], Step::Up(up)) if *down == up && *ms <= TAP_MAX_MS => {
+                merged.truncate(merged.len() - 2);
+                merged.push(Step::Tap(up));
+            }
+            ([.., Step::Down(down)], Step::Up(up)) if *down == up => {
+                merged.pop();
+                merged.push(Step::Tap(up));
+            }
+            ([.., Step::DelayMs(before)], Step::DelayMs(ms)) => {
+                let total = before + ms;
+                merged.pop();
+                merged.push(Step::DelayMs(total));
+            }
+            _ => merged.push(step),
+        }
+    }
+    // Nothing to wait for at the end
+    while let Some(Step::DelayMs(_)) = merged.last() {
+        merged.pop();
+    }
+    Ok(merged)
+}
+
+/// A QMK `process_record_user` case sending `macro_` with `SEND_STRING`,
+/// bound to the custom keycode `keycode`
+pub fn to_qmk(macro_: &Macro, source_name: &str, keycode: &str) -> Result<String, String> {
+    let steps = firmware_steps(macro_)?;
+    let mut out = String::new();
+
+    // Writing to a String can't fail
+    let _ = writeln!(out, "// Generated by EvKey from {}", source_name.replace('\n', " "));
+    let _ = writeln!(out, "// Add {} to the custom keycodes enum in keymap.c, put it on a key", keycode);
+    let _ = writeln!(out, "// and add this case to the switch in process_record_user():");
+    let _ = writeln!(out, "case {}:", keycode);
+    let _ = writeln!(out, "    if (record->event.pressed) {{");
+    let _ = writeln!(out, "        SEND_STRING(");
+    if steps.is_empty() {
+        let _ = writeln!(out, "            \"\"");
+    }
+    for step in steps {
+        let _ = match step {
+            Step::Down(key) => writeln!(out, "            SS_DOWN(X_{})", key),
+            Step::Up(key) => writeln!(out, "            SS_UP(X_{})", key),
+            Step::Tap(key) => writeln!(out, "            SS_TAP(X_{})", key),
+            Step::DelayMs(ms) => writeln!(out, "            SS_DELAY({})", ms),
+        };
+    }
+    let _ = writeln!(out, "        );");
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out, "    return false;");
+    Ok(out)
+}
+
+/// `macro_` as a VIA macro, in VIA's text syntax (`{KC_A}` taps,
+/// `{+KC_A}`/`{-KC_A}` press and release, `{100}` waits 100ms) inside the
+/// `macros` list of a VIA layout file
+pub fn to_via(macro_: &Macro) -> Result<String, String> {
+    let mut text = String::new();
+    for step in firmware_steps(macro_)? {
+        let _ = match step {
+            Step::Down(key) => write!(text, "{{+KC_{}}}", key),
+            Step::Up(key) => write!(text, "{{-KC_{}}}", key),
+            Step::Tap(key) => write!(text, "{{KC_{}}}", key),
+            Step::DelayMs(ms) => write!(text, "{{{}}}", ms),
+        };
+    }
+    Ok(format!("{{\n  \"macros\": [\n    \"{}\"\n  ]\n}}\n", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{EventType, InputEvent};
+
+    #[test]
+    fn test_c_source_contains_events() {
+        let events = vec![
+            RecordedEvent {
+                timestamp_us: 0,
+                event: InputEvent::new(EventType::KEY.0, 17, 1),
+            },
+            RecordedEvent {
+                timestamp_us: 100_000,
+                event: InputEvent::new(EventType::KEY.0, 17, 0),
+            },
+        ];
+
+        let source = to_c_source(&events, "walk.macro");
+        assert!(source.contains("Generated by EvKey from walk.macro"));
+        assert!(source.contains("{0ULL, 1, 17, 1},"));
+        assert!(source.contains("{100000ULL, 1, 17, 0},"));
+        assert!(source.contains("int main(void)"));
+    }
+
+    #[test]
+    fn test_c_source_empty_macro() {
+        let source = to_c_source(&[], "empty.macro");
+        assert!(source.contains("{0ULL, EV_SYN, SYN_REPORT, 0},"));
+    }
+
+    #[test]
+    fn test_firmware_macros() {
+        use crate::storage::parse_macro;
+
+        let macro_ = parse_macro("hold CTRL for 10ms\nhold CTRL+C for 20ms\nhold CTRL for 5ms\nwait 100ms\nwait 50ms\nhold ENTER for 300ms\nwait 40ms\n").unwrap();
+        let qmk = to_qmk(&macro_, "copy.macro", "EVKEY_COPY").unwrap();
+        assert!(qmk.contains("case EVKEY_COPY:\n"));
+        assert!(qmk.contains(
+            "SS_DOWN(X_LCTL)\n            SS_DELAY(10)\n            SS_TAP(X_C)\n            SS_DELAY(5)\n            SS_UP(X_LCTL)\n            SS_DELAY(150)\n            SS_DOWN(X_ENT)\n            SS_DELAY(300)\n            SS_UP(X_ENT)\n        );"
+        ));
+        assert_eq!(
+            to_via(&macro_).unwrap(),
+            "{\n  \"macros\": [\n    \"{+KC_LCTL}{10}{KC_C}{5}{-KC_LCTL}{150}{+KC_ENT}{300}{-KC_ENT}\"\n  ]\n}\n"
+        );
+
+        assert!(to_via(&parse_macro("move 10 0\n").unwrap()).unwrap_err().contains("mouse"));
+        assert!(to_via(&parse_macro("tap BTN_LEFT\n").unwrap()).unwrap_err().contains("BTN_LEFT"));
+        assert!(to_via(&parse_macro("paste \"hi\"\n").unwrap()).unwrap_err().contains("actions"));
+    }
+}
@@ -0,0 +1,371 @@
+//! C API for embedding EvKey
+//!
+//! The header is generated with cbindgen into `include/evkey.h`:
+//!
+//!   cbindgen --config cbindgen.toml --output include/evkey.h
+//!
+//! Functions returning `int` give 0 on success and -1 on failure; functions
+//! returning a pointer give NULL on failure. In both cases
+//! `evkey_last_error()` describes what went wrong on the calling thread. A
+//! panic inside the library is caught and reported the same way, instead of
+//! unwinding into C.
+//! Objects are owned by the caller and released with their `_free` function.
+
+use crate::backend::{self, BackendKind};
+use crate::player::Player;
+use crate::recorder::{self, Recorder};
+use crate::storage::{self, Macro, Metadata};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: impl ToString) {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Run an API function's body, turning a panic into `failed` with the panic
+/// message as the last error
+fn catch_panic<T>(failed: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        set_last_error(format!("EvKey panicked: {}", message));
+        failed
+    })
+}
+
+/// Turn a result into the C return convention, recording the error
+fn status<E: ToString>(result: Result<(), E>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Box a result for C, recording the error and returning NULL on failure
+fn into_raw<T, E: ToString>(result: Result<T, E>) -> *mut T {
+    match result {
+        Ok(value) => Box::into_raw(Box::new(value)),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Read a C string argument
+///
+/// # Safety
+/// `s` must be NULL or a valid NUL-terminated string.
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL", what));
+    }
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map_err(|_| format!("{} is not valid UTF-8", what))
+}
+
+/// Message for the last error on this thread, or NULL if there was none
+///
+/// The string stays valid until the next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn evkey_last_error() -> *const c_char {
+    catch_panic(ptr::null(), || {
+        LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+    })
+}
+
+/// A loaded or recorded macro
+pub struct EvkeyMacro {
+    inner: Macro,
+}
+
+/// Load a macro file (text or binary)
+///
+/// # Safety
+/// `path` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_macro_load(path: *const c_char) -> *mut EvkeyMacro {
+    catch_panic(ptr::null_mut(), || {
+        into_raw(
+            unsafe { str_arg(path, "path") }
+                .and_then(|path| storage::load_macro(path).map_err(|e| e.to_string()))
+                .map(|inner| EvkeyMacro { inner }),
+        )
+    })
+}
+
+/// Save a macro as text
+///
+/// # Safety
+/// `macro_` must come from this library and `path` must be a valid
+/// NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_macro_save(macro_: *const EvkeyMacro, path: *const c_char) -> c_int {
+    catch_panic(-1, || {
+        let Some(macro_) = (unsafe { macro_.as_ref() }) else {
+            return status(Err("macro is NULL"));
+        };
+        status(
+            unsafe { str_arg(path, "path") }
+                .and_then(|path| storage::save_macro(path, &macro_.inner).map_err(|e| e.to_string())),
+        )
+    })
+}
+
+/// Number of states in a macro
+///
+/// # Safety
+/// `macro_` must come from this library.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_macro_state_count(macro_: *const EvkeyMacro) -> usize {
+    catch_panic(0, || unsafe { macro_.as_ref() }.map_or(0, |m| m.inner.states.len()))
+}
+
+/// Total length of a macro in milliseconds
+///
+/// # Safety
+/// `macro_` must come from this library.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_macro_duration_ms(macro_: *const EvkeyMacro) -> u64 {
+    catch_panic(0, || {
+        unsafe { macro_.as_ref() }.map_or(0, |m| m.inner.states.iter().map(|s| s.duration_ms).sum())
+    })
+}
+
+/// Release a macro; NULL is ignored
+///
+/// # Safety
+/// `macro_` must come from this library and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_macro_free(macro_: *mut EvkeyMacro) {
+    catch_panic((), || {
+        if !macro_.is_null() {
+            drop(unsafe { Box::from_raw(macro_) });
+        }
+    })
+}
+
+/// Recording in progress on a background thread
+pub struct EvkeyRecorder {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Recorder>,
+}
+
+/// Start recording from every keyboard and mouse right away
+///
+/// The hotkeys still work: F1 toggles recording (starting again discards what
+/// was recorded so far) and F2 adds a marker.
+#[unsafe(no_mangle)]
+pub extern "C" fn evkey_record_start() -> *mut EvkeyRecorder {
+    catch_panic(ptr::null_mut(), || into_raw(start_recording()))
+}
+
+fn start_recording() -> io::Result<EvkeyRecorder> {
+    let mut recorder = Recorder::new();
+    for device in recorder::find_input_devices()? {
+        recorder.add_device(&device.path)?;
+    }
+    recorder.start();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_flag = Arc::clone(&stop);
+    let thread = thread::spawn(move || {
+        while !stop_flag.load(Ordering::Relaxed) {
+            // Read errors are logged by the recorder; keep going
+            let _ = recorder.poll();
+            thread::sleep(Duration::from_millis(1));
+        }
+        recorder
+    });
+
+    Ok(EvkeyRecorder { stop, thread })
+}
+
+/// Stop recording and return the macro; the recorder is released
+///
+/// # Safety
+/// `recorder` must come from `evkey_record_start` and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_record_stop(recorder: *mut EvkeyRecorder) -> *mut EvkeyMacro {
+    catch_panic(ptr::null_mut(), || {
+        if recorder.is_null() {
+            return into_raw::<EvkeyMacro, _>(Err("recorder is NULL"));
+        }
+        let recording = unsafe { Box::from_raw(recorder) };
+        recording.stop.store(true, Ordering::Relaxed);
+
+        into_raw(
+            recording
+                .thread
+                .join()
+                .map_err(|_| "recording thread panicked")
+                .map(|mut recorder| {
+                    let events = recorder.stop();
+                    let metadata = Metadata {
+                        locks: recorder.lock_state(),
+                        polling_hz: recorder.polling_hz(),
+                        environment: recorder.environment(),
+                        ..Default::default()
+                    };
+                    EvkeyMacro {
+                        inner: Macro::from_recording(&events, recorder.markers(), metadata),
+                    }
+                }),
+        )
+    })
+}
+
+/// A virtual input device to play macros on
+pub struct EvkeyPlayer {
+    inner: Player,
+}
+
+/// Playback position passed to progress callbacks
+#[repr(C)]
+pub struct EvkeyProgress {
+    pub position_us: u64,
+    pub total_us: u64,
+    /// Current state, or -1 if unknown
+    pub state_index: i64,
+    pub elapsed_us: u64,
+}
+
+/// Create a player, picking the best available backend
+///
+/// # Safety
+/// `device_name` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_player_new(device_name: *const c_char) -> *mut EvkeyPlayer {
+    catch_panic(ptr::null_mut(), || {
+        into_raw(unsafe { str_arg(device_name, "device_name") }.and_then(|name| {
+            backend::open(BackendKind::Auto, name)
+                .map(|backend| EvkeyPlayer { inner: Player::new(backend) })
+                .map_err(|e| e.to_string())
+        }))
+    })
+}
+
+/// Report progress during `evkey_play` to `callback`, or stop reporting if NULL
+///
+/// `user_data` is passed to the callback unchanged.
+///
+/// # Safety
+/// `player` must come from this library; `user_data` must stay valid for as
+/// long as the callback is installed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_player_set_progress(
+    player: *mut EvkeyPlayer,
+    callback: Option<extern "C" fn(progress: *const EvkeyProgress, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) {
+    catch_panic((), || {
+        let Some(player) = (unsafe { player.as_mut() }) else {
+            return;
+        };
+        let Some(callback) = callback else {
+            player.inner.on_progress(|_| {});
+            return;
+        };
+
+        player.inner.on_progress(move |progress| {
+            let progress = EvkeyProgress {
+                position_us: progress.position_us,
+                total_us: progress.total_us,
+                state_index: progress.state_index.map_or(-1, |i| i as i64),
+                elapsed_us: progress.elapsed.as_micros() as u64,
+            };
+            callback(&progress, user_data);
+        });
+    })
+}
+
+/// Play a macro with its original timing, blocking until it finishes
+///
+/// # Safety
+/// `player` and `macro_` must come from this library.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_play(player: *mut EvkeyPlayer, macro_: *const EvkeyMacro) -> c_int {
+    catch_panic(-1, || {
+        let (Some(player), Some(macro_)) = (unsafe { player.as_mut() }, unsafe { macro_.as_ref() }) else {
+            return status(Err("player or macro is NULL"));
+        };
+
+        player.inner.set_state_starts(macro_.inner.state_starts_us());
+        status(
+            player
+                .inner
+                .play_with_actions(&macro_.inner.events(), &macro_.inner.timed_actions()),
+        )
+    })
+}
+
+/// Release a player and its virtual device; NULL is ignored
+///
+/// # Safety
+/// `player` must come from this library and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn evkey_player_free(player: *mut EvkeyPlayer) {
+    catch_panic((), || {
+        if !player.is_null() {
+            drop(unsafe { Box::from_raw(player) });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_error_is_reported() {
+        let path = CString::new("/nonexistent/evkey.macro").unwrap();
+        let loaded = unsafe { evkey_macro_load(path.as_ptr()) };
+
+        assert!(loaded.is_null());
+        let error = unsafe { CStr::from_ptr(evkey_last_error()) };
+        assert!(error.to_str().unwrap().contains("No such file"));
+    }
+
+    #[test]
+    fn test_macro_roundtrip() {
+        let path = std::env::temp_dir().join(format!("evkey-ffi-{}.macro", std::process::id()));
+        std::fs::write(&path, "hold A for 10ms\nwait 20ms\n").unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let macro_ = unsafe { evkey_macro_load(c_path.as_ptr()) };
+        assert!(!macro_.is_null());
+        assert_eq!(unsafe { evkey_macro_state_count(macro_) }, 2);
+        assert_eq!(unsafe { evkey_macro_duration_ms(macro_) }, 30);
+        assert_eq!(unsafe { evkey_macro_save(macro_, c_path.as_ptr()) }, 0);
+
+        unsafe { evkey_macro_free(macro_) };
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_panic_is_reported() {
+        assert_eq!(catch_panic(-1, || panic!("state index out of range")), -1);
+        let error = unsafe { CStr::from_ptr(evkey_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "EvKey panicked: state index out of range");
+        assert_eq!(catch_panic(0, || 7), 7);
+    }
+}
@@ -0,0 +1,119 @@
+//! Dropping garbage events from flaky hardware
+//!
+//! Worn switches chatter, sending a press and a release a millisecond apart,
+//! and some devices report keys nobody pressed. An [`EventFilter`] takes
+//! those out of a recording's raw events, before they're turned into states,
+//! so they never show up as taps of their own.
+
+use crate::event::{EventType, RecordedEvent};
+use crate::keyset::KeySet;
+use std::time::Duration;
+
+/// What counts as garbage in a recording
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    /// Presses released sooner than this are dropped along with their release
+    pub min_press: Duration,
+    /// Keys whose events are always dropped
+    pub phantom_keys: KeySet,
+}
+
+impl EventFilter {
+    /// Whether the filter lets everything through
+    pub fn is_empty(&self) -> bool {
+        self.min_press.is_zero() && self.phantom_keys.is_empty()
+    }
+
+    /// Which of `events` to keep, one flag per event
+    ///
+    /// A press is only dropped once its release is among `events`; one
+    /// released later is kept.
+    pub fn keep(&self, events: &[RecordedEvent]) -> Vec<bool> {
+        let min_press_us = self.min_press.as_micros() as u64;
+        let mut keep = vec![true; events.len()];
+
+        for (i, recorded) in events.iter().enumerate() {
+            if recorded.event.event_type() != EventType::KEY {
+                continue;
+            }
+            let code = recorded.event.code();
+            if self.phantom_keys.contains(code) {
+                keep[i] = false;
+                continue;
+            }
+            if recorded.event.value() != 1 || min_press_us == 0 {
+                continue;
+            }
+
+            let next = events[i + 1..]
+                .iter()
+                .position(|later| later.event.event_type() == EventType::KEY && later.event.code() == code)
+                .map(|offset| i + 1 + offset);
+            if let Some(j) = next
+                && events[j].event.value() == 0
+                && events[j].timestamp_us.saturating_sub(recorded.timestamp_us) < min_press_us
+            {
+                keep[i] = false;
+                keep[j] = false;
+            }
+        }
+
+        keep
+    }
+
+    /// `events` without the garbage
+    pub fn apply(&self, events: Vec<RecordedEvent>) -> Vec<RecordedEvent> {
+        let keep = self.keep(&events);
+        events.into_iter().zip(keep).filter(|(_, keep)| *keep).map(|(event, _)| event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::key;
+
+    fn kept(filter: &EventFilter, events: Vec<RecordedEvent>) -> Vec<(u64, u16, i32)> {
+        filter
+            .apply(events)
+            .iter()
+            .map(|recorded| (recorded.timestamp_us, recorded.event.code(), recorded.event.value()))
+            .collect()
+    }
+
+    #[test]
+    fn test_chattered_presses_are_dropped() {
+        let filter = EventFilter {
+            min_press: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let events = vec![
+            key(0, 30, 1),
+            key(1_000, 30, 0), // A chattered
+            key(10_000, 30, 1),
+            key(40_000, 30, 0),
+            key(50_000, 48, 1), // B, still held
+        ];
+        assert_eq!(kept(&filter, events), vec![(10_000, 30, 1), (40_000, 30, 0), (50_000, 48, 1)]);
+    }
+
+    #[test]
+    fn test_phantom_keys_are_dropped() {
+        let filter = EventFilter {
+            phantom_keys: [240u16].into_iter().collect(), // KEY_UNKNOWN
+            ..Default::default()
+        };
+        let events = vec![key(0, 240, 1), key(10_000, 30, 1), key(10_000, 240, 0), key(40_000, 30, 0)];
+        assert_eq!(kept(&filter, events), vec![(10_000, 30, 1), (40_000, 30, 0)]);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert!(EventFilter::default().is_empty());
+        let filter = EventFilter {
+            min_press: Duration::from_millis(5),
+            ..Default::default()
+        };
+        assert!(!filter.is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+//! Forwarding local input to EvKey on another machine
+//!
+//! `evkey forward --to host` grabs the local keyboards and mice and sends
+//! their events over TCP, in the [`crate::stream`] format, to `evkey receive`
+//! on the other machine, which injects each one the moment it arrives - a
+//! minimal software KVM. A toggle hotkey hands input back and forth between
+//! the two machines without disconnecting.
+//!
+//! Events are sent one poll batch at a time with Nagle's algorithm off, so
+//! on a local network they arrive well within a few milliseconds.
+//!
+//! Before any events, the forwarding side sends a secret shared by both
+//! machines, and the receiving side drops the connection unless it matches,
+//! so nobody else who can reach the port can type on the machine. The secret
+//! and events are not encrypted; beyond a trusted network they go through an
+//! SSH tunnel.
+
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use crate::keyset::KeySet;
+use crate::player::Player;
+use crate::stream::{StreamReader, StreamWriter};
+use evdev::Device;
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, poll};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Port `evkey receive` listens on and `evkey forward` connects to by default
+pub const DEFAULT_PORT: u16 = 4715;
+
+/// Hotkey that switches forwarding on and off by default
+pub const DEFAULT_TOGGLE: &str = "SCROLLLOCK";
+
+/// How long the receiving side gives a sender to send the secret
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest secret accepted
+const MAX_SECRET_LEN: usize = 1024;
+
+/// What the receiving side replies once the secret matches
+const ACCEPTED: u8 = b'+';
+
+/// How long [`Forwarder::pump`] waits for events before returning (in
+/// milliseconds), so callers can check for Ctrl+C
+const POLL_TIMEOUT_MS: u16 = 100;
+
+/// Local devices whose events are sent to a remote `evkey receive`
+pub struct Forwarder {
+    devices: Vec<Device>,
+    stream: StreamWriter<TcpStream>,
+    toggle: KeySet,
+    /// Whether events currently go to the remote machine
+    active: bool,
+    grabbed: bool,
+    /// Keys held on the local devices
+    held: KeySet,
+    /// Keys pressed on the remote machine through the stream
+    remote_held: KeySet,
+    /// Toggle keys whose release is kept from the remote machine too
+    swallowed: KeySet,
+    started: Instant,
+}
+
+impl Forwarder {
+    /// Connect to `address` ("host" or "host:port"), prove we know `secret`
+    /// and start forwarding the events of `devices`; pressing all of `toggle`
+    /// pauses and resumes
+    pub fn connect(address: &str, secret: &[u8], mut devices: Vec<Device>, toggle: KeySet) -> io::Result<Self> {
+        let mut socket = TcpStream::connect(with_default_port(address))?;
+        socket.set_nodelay(true)?;
+        send_secret(&mut socket, secret)?;
+        for device in &mut devices {
+            device.set_nonblocking(true)?;
+        }
+
+        let mut forwarder = Self {
+            devices,
+            stream: StreamWriter::new(socket)?,
+            toggle,
+            active: false,
+            grabbed: false,
+            held: KeySet::new(),
+            remote_held: KeySet::new(),
+            swallowed: KeySet::new(),
+            started: Instant::now(),
+        };
+        forwarder.set_active(true)?;
+        Ok(forwarder)
+    }
+
+    /// Whether input currently goes to the remote machine
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Wait briefly for events and forward them, unless paused; returns
+    /// whether the toggle hotkey was pressed
+    pub fn pump(&mut self) -> io::Result<bool> {
+        {
+            let mut fds: Vec<PollFd> = self
+                .devices
+                .iter()
+                .map(|device| PollFd::new(device.as_fd(), PollFlags::POLLIN))
+                .collect();
+            match poll(&mut fds, POLL_TIMEOUT_MS) {
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut events: Vec<InputEvent> = Vec::new();
+        for device in &mut self.devices {
+            match device.fetch_events() {
+                // The receiving side adds its own SYN_REPORT to each event
+                Ok(fetched) => events.extend(
+                    fetched
+                        .filter(|event| event.event_type() != evdev::EventType::SYNCHRONIZATION)
+                        .map(InputEvent::from),
+                ),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut toggled = false;
+        let mut outgoing = Vec::new();
+        for event in events {
+            if self.is_toggle(event) {
+                self.send(std::mem::take(&mut outgoing))?;
+                self.set_active(!self.active)?;
+                toggled = true;
+                continue;
+            }
+            if self.active {
+                outgoing.push(event);
+            }
+        }
+        self.send(outgoing)?;
+
+        // Grabbing while keys are down would leave them stuck on this machine
+        if self.active && !self.grabbed && self.held.is_empty() {
+            self.set_grab(true)?;
+        }
+        Ok(toggled)
+    }
+
+    /// Release whatever is still held on the remote machine and end the
+    /// stream
+    pub fn finish(mut self) -> io::Result<()> {
+        self.release_remote()?;
+        self.set_grab(false)?;
+        self.stream.finish()
+    }
+
+    /// Keep track of held keys, and check whether `event` completes the
+    /// toggle hotkey (or releases a key that did)
+    fn is_toggle(&mut self, event: InputEvent) -> bool {
+        if event.event_type() != EventType::KEY {
+            return false;
+        }
+        let code = event.code();
+        match event.value() {
+            0 => {
+                self.held.remove(code);
+                self.swallowed.remove(code)
+            }
+            1 => {
+                self.held.insert(code);
+                let complete = self.toggle.contains(code) && self.toggle.iter().all(|key| self.held.contains(key));
+                if complete {
+                    self.swallowed.insert(code);
+                }
+                complete
+            }
+            _ => self.swallowed.contains(code),
+        }
+    }
+
+    fn set_active(&mut self, active: bool) -> io::Result<()> {
+        self.active = active;
+        if active {
+            info!("Forwarding input");
+            // The devices are grabbed once the toggle keys are let go
+            return Ok(());
+        }
+        info!("Forwarding paused, input stays on this machine");
+        self.release_remote()?;
+        self.set_grab(false)
+    }
+
+    fn set_grab(&mut self, grab: bool) -> io::Result<()> {
+        if self.grabbed == grab {
+            return Ok(());
+        }
+        for device in &mut self.devices {
+            if grab {
+                device.grab()?;
+            } else {
+                device.ungrab()?;
+            }
+        }
+        self.grabbed = grab;
+        Ok(())
+    }
+
+    fn release_remote(&mut self) -> io::Result<()> {
+        let releases: Vec<InputEvent> = self
+            .remote_held
+            .codes()
+            .map(|code| InputEvent::new(EventType::KEY.0, code, 0))
+            .collect();
+        self.send(releases)
+    }
+
+    fn send(&mut self, events: Vec<InputEvent>) -> io::Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let timestamp_us = self.started.elapsed().as_micros() as u64;
+        let recorded: Vec<RecordedEvent> = events
+            .into_iter()
+            .map(|event| {
+                if event.event_type() == EventType::KEY {
+                    match event.value() {
+                        0 => {
+                            self.remote_held.remove(event.code());
+                        }
+                        1 => {
+                            self.remote_held.insert(event.code());
+                        }
+                        _ => {}
+                    }
+                }
+                RecordedEvent { timestamp_us, event }
+            })
+            .collect();
+        debug!("Forwarding {} events", recorded.len());
+        self.stream.send(&recorded)
+    }
+}
+
+/// Inject the events streamed by a [`Forwarder`] as they arrive, until it
+/// disconnects; keys it left held are released either way. Nothing is
+/// injected unless it sends `secret` first.
+pub fn receive(mut socket: TcpStream, secret: &[u8], player: &mut Player) -> io::Result<()> {
+    socket.set_nodelay(true)?;
+    check_secret(&mut socket, secret)?;
+    let result = StreamReader::new(socket).and_then(|reader| {
+        for recorded in reader {
+            player.inject(recorded?.event)?;
+        }
+        Ok(())
+    });
+    player.release_all()?;
+    result
+}
+
+/// The secret shared by both sides, read from `path`: its contents without
+/// surrounding whitespace
+pub fn read_secret(path: &Path) -> io::Result<Vec<u8>> {
+    let text = std::fs::read(path)?;
+    let secret = text.trim_ascii();
+    if secret.is_empty() || secret.len() > MAX_SECRET_LEN {
+        let message = format!("{} needs to hold a secret of 1 to {} bytes", path.display(), MAX_SECRET_LEN);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(secret.to_vec())
+}
+
+/// Send `secret` and wait for the receiving side to accept it
+fn send_secret(socket: &mut TcpStream, secret: &[u8]) -> io::Result<()> {
+    let mut message = (secret.len() as u16).to_le_bytes().to_vec();
+    message.extend_from_slice(secret);
+    socket.write_all(&message)?;
+    let mut reply = [0u8];
+    match socket.read_exact(&mut reply) {
+        Ok(()) if reply[0] == ACCEPTED => Ok(()),
+        Ok(()) => Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected reply to the secret")),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "The other machine refused the secret"))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the secret a sender sends, within [`HANDSHAKE_TIMEOUT`] all told,
+/// and accept it if it's `secret`
+fn check_secret(socket: &mut TcpStream, secret: &[u8]) -> io::Result<()> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut length = [0u8; 2];
+    read_exact_by(socket, &mut length, deadline)?;
+    let length = u16::from_le_bytes(length) as usize;
+    if length > MAX_SECRET_LEN {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Wrong secret"));
+    }
+    let mut sent = vec![0u8; length];
+    read_exact_by(socket, &mut sent, deadline)?;
+    socket.set_read_timeout(None)?;
+    // Compared in full whatever differs, so timing doesn't give it away
+    let differs = sent.len() != secret.len() || sent.iter().zip(secret).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0;
+    if differs {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Wrong secret"));
+    }
+    socket.write_all(&[ACCEPTED])
+}
+
+/// Fill `buf` from `socket`, failing once `deadline` passes however the data
+/// trickles in
+fn read_exact_by(socket: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for the secret"));
+        }
+        socket.set_read_timeout(Some(left))?;
+        match socket.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// `address` with [`DEFAULT_PORT`] added if it has no port
+pub fn with_default_port(address: &str) -> String {
+    match address.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => address.to_string(),
+        _ => format!("{}:{}", address, DEFAULT_PORT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_default_port() {
+        assert_eq!(with_default_port("desk"), "desk:4715");
+        assert_eq!(with_default_port("10.0.0.2:9000"), "10.0.0.2:9000");
+        assert_eq!(with_default_port("0.0.0.0"), "0.0.0.0:4715");
+    }
+
+    #[test]
+    fn test_secret() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiver = std::thread::spawn(move || {
+            let check = |socket: io::Result<TcpStream>| check_secret(&mut socket.unwrap(), b"hunter2").is_ok();
+            listener.incoming().take(2).map(check).collect::<Vec<_>>()
+        });
+
+        let mut right = TcpStream::connect(address).unwrap();
+        send_secret(&mut right, b"hunter2").unwrap();
+        let mut wrong = TcpStream::connect(address).unwrap();
+        assert_eq!(send_secret(&mut wrong, b"hunter3").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(receiver.join().unwrap(), [true, false]);
+    }
+}
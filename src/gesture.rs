@@ -0,0 +1,255 @@
+//! Touchpad gestures as triggers
+//!
+//! A `gesture` line in the daemon config plays a macro on a swipe or pinch,
+//! e.g. `gesture 3 swipe-up overview.macro`. With the `gestures` feature the
+//! daemon reads them from the touchpads through libinput, which says when a
+//! gesture starts, how it moves and when it ends, but not what it was:
+//! [`Gesture::from_swipe`] and [`Gesture::from_pinch`] decide that once it's
+//! over.
+//!
+//! Two-finger swipes are scrolling to libinput, so swipes take three or more
+//! fingers; pinches take two or more.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Unaccelerated distance (in 1/1000 inch) a swipe has to cover, so a
+/// nudge of three resting fingers isn't taken for one
+pub const MIN_SWIPE_DISTANCE: f64 = 300.0;
+
+/// How far the fingers have to spread (or the inverse, close) for a pinch
+pub const MIN_PINCH_SCALE: f64 = 1.3;
+
+/// Which way a gesture went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    SwipeUp,
+    SwipeDown,
+    SwipeLeft,
+    SwipeRight,
+    /// Fingers moving together
+    PinchIn,
+    /// Fingers moving apart
+    PinchOut,
+}
+
+impl Motion {
+    fn is_swipe(self) -> bool {
+        !matches!(self, Motion::PinchIn | Motion::PinchOut)
+    }
+}
+
+impl FromStr for Motion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "swipe-up" => Ok(Motion::SwipeUp),
+            "swipe-down" => Ok(Motion::SwipeDown),
+            "swipe-left" => Ok(Motion::SwipeLeft),
+            "swipe-right" => Ok(Motion::SwipeRight),
+            "pinch-in" => Ok(Motion::PinchIn),
+            "pinch-out" => Ok(Motion::PinchOut),
+            _ => Err(format!(
+                "Unknown gesture '{}', use swipe-up/swipe-down/swipe-left/swipe-right/pinch-in/pinch-out",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Motion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Motion::SwipeUp => write!(f, "swipe-up"),
+            Motion::SwipeDown => write!(f, "swipe-down"),
+            Motion::SwipeLeft => write!(f, "swipe-left"),
+            Motion::SwipeRight => write!(f, "swipe-right"),
+            Motion::PinchIn => write!(f, "pinch-in"),
+            Motion::PinchOut => write!(f, "pinch-out"),
+        }
+    }
+}
+
+/// A finished swipe or pinch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gesture {
+    pub fingers: u8,
+    pub motion: Motion,
+}
+
+impl Gesture {
+    /// Parse a finger count and a motion, as in `3 swipe-up`
+    pub fn parse(fingers: &str, motion: &str) -> Result<Self, String> {
+        let motion: Motion = motion.parse()?;
+        let fewest = if motion.is_swipe() { 3 } else { 2 };
+        let fingers = fingers
+            .parse()
+            .ok()
+            .filter(|fingers| (fewest..=5).contains(fingers))
+            .ok_or_else(|| format!("Invalid finger count '{}' for {}, expected {} to 5", fingers, motion, fewest))?;
+        Ok(Gesture { fingers, motion })
+    }
+
+    /// What a swipe that moved by `dx`, `dy` all told was, if it went far
+    /// enough
+    pub fn from_swipe(fingers: i32, dx: f64, dy: f64) -> Option<Self> {
+        if dx.hypot(dy) < MIN_SWIPE_DISTANCE {
+            return None;
+        }
+        let motion = match (dx.abs() > dy.abs(), dx > 0.0, dy > 0.0) {
+            (true, true, _) => Motion::SwipeRight,
+            (true, false, _) => Motion::SwipeLeft,
+            (false, _, true) => Motion::SwipeDown,
+            (false, _, false) => Motion::SwipeUp,
+        };
+        Some(Gesture {
+            fingers: u8::try_from(fingers).ok()?,
+            motion,
+        })
+    }
+
+    /// What a pinch that ended at `scale` (the fingers' spread over where
+    /// they started) was, if they moved far enough
+    pub fn from_pinch(fingers: i32, scale: f64) -> Option<Self> {
+        let motion = if scale >= MIN_PINCH_SCALE {
+            Motion::PinchOut
+        } else if scale > 0.0 && scale <= 1.0 / MIN_PINCH_SCALE {
+            Motion::PinchIn
+        } else {
+            return None;
+        };
+        Some(Gesture {
+            fingers: u8::try_from(fingers).ok()?,
+            motion,
+        })
+    }
+}
+
+impl fmt::Display for Gesture {
+    /// "3-finger swipe-up"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-finger {}", self.fingers, self.motion)
+    }
+}
+
+/// Opens devices for libinput with plain file permissions, as the rest of
+/// EvKey does
+#[cfg(feature = "gestures")]
+struct Interface;
+
+#[cfg(feature = "gestures")]
+impl input::LibinputInterface for Interface {
+    fn open_restricted(&mut self, path: &std::path::Path, flags: i32) -> Result<std::os::fd::OwnedFd, i32> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let access = flags & libc::O_ACCMODE;
+        std::fs::OpenOptions::new()
+            .custom_flags(flags)
+            .read(access == libc::O_RDONLY || access == libc::O_RDWR)
+            .write(access == libc::O_WRONLY || access == libc::O_RDWR)
+            .open(path)
+            .map(std::os::fd::OwnedFd::from)
+            .map_err(|e| -e.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    fn close_restricted(&mut self, fd: std::os::fd::OwnedFd) {
+        drop(std::fs::File::from(fd));
+    }
+}
+
+/// The touchpads under /dev/input, read through libinput
+#[cfg(feature = "gestures")]
+pub struct GestureReader {
+    libinput: input::Libinput,
+    /// Unaccelerated motion of the swipe in progress
+    swipe: (f64, f64),
+}
+
+#[cfg(feature = "gestures")]
+impl GestureReader {
+    /// Open every device that can do gestures
+    pub fn open() -> std::io::Result<Self> {
+        let mut libinput = input::Libinput::new_from_path(Interface);
+        let mut touchpads = 0;
+        for entry in std::fs::read_dir("/dev/input")? {
+            let path = entry?.path();
+            if !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("event")) {
+                continue;
+            }
+            let Some(device) = libinput.path_add_device(&path.to_string_lossy()) else {
+                continue;
+            };
+            if device.has_capability(input::DeviceCapability::Gesture) {
+                tracing::info!("Reading gestures from {} ({})", device.name(), path.display());
+                touchpads += 1;
+            } else {
+                libinput.path_remove_device(device);
+            }
+        }
+        if touchpads == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No touchpads found (are you allowed to read /dev/input?)",
+            ));
+        }
+        Ok(Self {
+            libinput,
+            swipe: (0.0, 0.0),
+        })
+    }
+
+    /// Gestures that ended since the last call; doesn't wait for any
+    pub fn read(&mut self) -> std::io::Result<Vec<Gesture>> {
+        use input::event::gesture::{
+            GestureEndEvent, GestureEventCoordinates, GestureEventTrait, GesturePinchEvent, GesturePinchEventTrait,
+            GestureSwipeEvent,
+        };
+        use input::event::{Event, GestureEvent};
+
+        self.libinput.dispatch()?;
+        let mut gestures = Vec::new();
+        for event in &mut self.libinput {
+            let Event::Gesture(event) = event else {
+                continue;
+            };
+            match event {
+                GestureEvent::Swipe(GestureSwipeEvent::Begin(_)) => self.swipe = (0.0, 0.0),
+                GestureEvent::Swipe(GestureSwipeEvent::Update(update)) => {
+                    self.swipe.0 += update.dx_unaccelerated();
+                    self.swipe.1 += update.dy_unaccelerated();
+                }
+                GestureEvent::Swipe(GestureSwipeEvent::End(end)) if !end.cancelled() => {
+                    gestures.extend(Gesture::from_swipe(end.finger_count(), self.swipe.0, self.swipe.1));
+                }
+                GestureEvent::Pinch(GesturePinchEvent::End(end)) if !end.cancelled() => {
+                    gestures.extend(Gesture::from_pinch(end.finger_count(), end.scale()));
+                }
+                _ => {}
+            }
+        }
+        Ok(gestures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gestures() {
+        let up = Gesture::parse("3", "swipe-up").unwrap();
+        assert_eq!(up.to_string(), "3-finger swipe-up");
+        assert_eq!(Gesture::parse("2", "pinch-in").unwrap().motion, Motion::PinchIn);
+        assert!(Gesture::parse("2", "swipe-up").unwrap_err().contains("expected 3 to 5"));
+        assert!(Gesture::parse("3", "swipe-around").unwrap_err().contains("Unknown gesture"));
+
+        // Mostly up, with some drift to the right
+        assert_eq!(Gesture::from_swipe(3, 80.0, -400.0), Some(up));
+        assert_eq!(Gesture::from_swipe(4, -500.0, 100.0).unwrap().motion, Motion::SwipeLeft);
+        assert_eq!(Gesture::from_swipe(3, 100.0, 100.0), None);
+
+        assert_eq!(Gesture::from_pinch(2, 0.5).unwrap().motion, Motion::PinchIn);
+        assert_eq!(Gesture::from_pinch(4, 1.8).unwrap().motion, Motion::PinchOut);
+        assert_eq!(Gesture::from_pinch(2, 1.1), None);
+    }
+}
@@ -0,0 +1,309 @@
+//! Refusing macros that look corrupted or malicious
+//!
+//! A damaged file, or one from someone else, can hold a state pressing half
+//! the keyboard at once or pages of text to type into whatever has focus.
+//! [`Limits`] bound how many keys a macro may hold at once and how much it
+//! may type; playback works out a macro's [`Extent`] before anything is
+//! played and refuses one beyond them (unless forced), the way it refuses a
+//! backend that lacks [`crate::capabilities::Capabilities`].
+//!
+//! A macro made for one app can also name it in a [`WindowGuard`], so that
+//! setting it off by accident elsewhere (game movement into a root shell)
+//! doesn't type into the wrong window: it's refused, or waits, unless a
+//! window with a matching title has focus, and pauses whenever focus moves
+//! to another one. Where the focused window can't be told (a compositor
+//! [`crate::screen`] can't ask), such a macro fails rather than guess.
+
+use crate::action::Action;
+use crate::event::{EventType, RecordedEvent};
+use crate::keyset::KeySet;
+use regex::Regex;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How often a guarded playback looks at which window has focus
+pub const FOCUS_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Keys that type a character: the digit row, letters, punctuation, space,
+/// enter and the keypad
+const CHARACTER_KEYS: &[std::ops::RangeInclusive<u16>] = &[
+    2..=13,
+    16..=28,
+    30..=41,
+    43..=53,
+    55..=55,
+    57..=57,
+    71..=83,
+    86..=86,
+    96..=96,
+    98..=98,
+];
+
+fn types_character(code: u16) -> bool {
+    CHARACTER_KEYS.iter().any(|keys| keys.contains(&code))
+}
+
+/// How far a macro goes, in the ways [`Limits`] bound
+#[derive(Debug, Clone, Default)]
+pub struct Extent {
+    held: KeySet,
+    /// Most keys and buttons held at once
+    pub most_keys: usize,
+    /// When (in microseconds) `most_keys` were first held
+    pub most_keys_at_us: u64,
+    /// Characters typed, by keys and by `type`, `snippet` and `paste`
+    pub text: usize,
+}
+
+impl Extent {
+    /// How far playing `events` and `actions` goes
+    pub fn of_playback<'a, I>(events: I, actions: &[(u64, Action)]) -> Self
+    where
+        I: IntoIterator<Item = &'a RecordedEvent>,
+    {
+        let mut extent = Extent::default();
+        for recorded in events {
+            extent.add_event(recorded);
+        }
+        for (_, action) in actions {
+            extent.add_action(action);
+        }
+        extent
+    }
+
+    pub fn add_event(&mut self, recorded: &RecordedEvent) {
+        let event = recorded.event;
+        if event.event_type() != EventType::KEY {
+            return;
+        }
+        match event.value() {
+            1 => {
+                if self.held.insert(event.code()) && types_character(event.code()) {
+                    self.text += 1;
+                }
+                if self.held.len() > self.most_keys {
+                    self.most_keys = self.held.len();
+                    self.most_keys_at_us = recorded.timestamp_us;
+                }
+            }
+            0 => {
+                self.held.remove(event.code());
+            }
+            _ => {}
+        }
+    }
+
+    pub fn add_action(&mut self, action: &Action) {
+        self.text += match action {
+            Action::Type(text) | Action::Paste(Some(text)) => text.chars().count(),
+            Action::Snippet(snippet) => snippet.text_len(),
+            _ => 0,
+        };
+    }
+}
+
+/// Bounds on what a macro may do; `None` leaves it unbounded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Most keys and buttons held at once
+    pub max_keys: Option<usize>,
+    /// Most characters typed over the whole macro
+    pub max_text: Option<usize>,
+}
+
+impl Limits {
+    /// Where `extent` goes beyond the limits, if it does
+    pub fn check(&self, extent: &Extent) -> Result<(), Exceeded> {
+        let exceeded = Exceeded {
+            keys: self
+                .max_keys
+                .filter(|&max| extent.most_keys > max)
+                .map(|max| (extent.most_keys, extent.most_keys_at_us, max)),
+            text: self.max_text.filter(|&max| extent.text > max).map(|max| (extent.text, max)),
+        };
+        if exceeded.keys.is_none() && exceeded.text.is_none() {
+            return Ok(());
+        }
+        Err(exceeded)
+    }
+}
+
+/// What a macro does beyond its [`Limits`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exceeded {
+    /// Keys held at once, when (in microseconds) and the limit
+    pub keys: Option<(usize, u64, usize)>,
+    /// Characters typed and the limit
+    pub text: Option<(usize, usize)>,
+}
+
+impl fmt::Display for Exceeded {
+    /// "holds 14 keys at once at 2.500s (limit 10), types 812 characters (limit 500)"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((keys, at_us, max)) = self.keys {
+            write!(f, "holds {} keys at once at {:.3}s (limit {})", keys, at_us as f64 / 1_000_000.0, max)?;
+            if self.text.is_some() {
+                write!(f, ", ")?;
+            }
+        }
+        if let Some((text, max)) = self.text {
+            write!(f, "types {} characters (limit {})", text, max)?;
+        }
+        Ok(())
+    }
+}
+
+/// The window a macro plays into, from its `# Window:` header
+#[derive(Debug, Clone)]
+pub struct WindowGuard {
+    /// Matched against the focused window's title
+    pub pattern: Regex,
+    /// Wait for the window to get focus rather than refusing to start
+    /// (`# Unfocused: wait`)
+    pub wait: bool,
+}
+
+impl PartialEq for WindowGuard {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern.as_str() == other.pattern.as_str() && self.wait == other.wait
+    }
+}
+
+impl WindowGuard {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        Ok(Self {
+            pattern: Regex::new(pattern).map_err(|e| format!("Invalid window pattern '{}': {}", pattern, e))?,
+            wait: false,
+        })
+    }
+
+    /// Whether the window titled `title` may be played into
+    pub fn allows(&self, title: &str) -> bool {
+        self.pattern.is_match(title)
+    }
+
+    /// Fail unless the window `title` tells of has focus, or with `wait`,
+    /// wait until it does or `stopped` says to give up; fails right away if
+    /// `title` can't tell
+    pub fn before_playing(
+        &self,
+        title: &mut impl FnMut() -> io::Result<String>,
+        stopped: impl Fn() -> bool,
+    ) -> Result<(), String> {
+        let mut title = || title().map_err(|e| format!("Can't tell which window has focus: {}", e));
+        let focused = title()?;
+        if self.allows(&focused) {
+            return Ok(());
+        }
+        if !self.wait {
+            return Err(format!(
+                "Refusing to play into '{}': the macro's Window header asks for a window matching '{}'",
+                focused, self.pattern
+            ));
+        }
+        info!("Waiting for a window matching '{}' to have focus", self.pattern);
+        while !self.allows(&title()?) {
+            if stopped() {
+                return Err("Stopped while waiting for the macro's window".to_string());
+            }
+            thread::sleep(FOCUS_CHECK_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// A pause callback for [`crate::player::Player::pause_while`], saying
+    /// whether another window than this one has focus, as `title` tells;
+    /// `title` is asked at most every [`FOCUS_CHECK_INTERVAL`]
+    ///
+    /// Once `title` can't tell, the error is logged and `stop` set, so a
+    /// player stopping on it fails instead of staying paused.
+    pub fn unfocused(
+        &self,
+        mut title: impl FnMut() -> io::Result<String> + 'static,
+        stop: Arc<AtomicBool>,
+    ) -> impl FnMut() -> bool + 'static {
+        let guard = self.clone();
+        let mut checked: Option<Instant> = None;
+        let mut unfocused = false;
+        move || {
+            if checked.is_none_or(|at| at.elapsed() >= FOCUS_CHECK_INTERVAL) {
+                unfocused = match title() {
+                    Ok(title) => !guard.allows(&title),
+                    Err(e) => {
+                        if !stop.swap(true, Ordering::Relaxed) {
+                            warn!("Can't tell which window has focus any more, stopping: {}", e);
+                        }
+                        true
+                    }
+                };
+                checked = Some(Instant::now());
+            }
+            unfocused
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::parse_macro;
+
+    #[test]
+    fn test_limits() {
+        let macro_ = parse_macro("hold A+B+C for 10ms\nwait 2490ms\ntap SPACE\ntype \"hello\"\nhold SHIFT+CTRL+ALT+F4 for 10ms\n").unwrap();
+        let extent = Extent::of_playback(&macro_.events(), &macro_.timed_actions());
+        assert_eq!((extent.most_keys, extent.most_keys_at_us), (4, 2_500_000));
+        // A, B, C, space and "hello"
+        assert_eq!(extent.text, 9);
+
+        assert!(Limits::default().check(&extent).is_ok());
+        let limits = Limits {
+            max_keys: Some(3),
+            max_text: Some(9),
+        };
+        let exceeded = limits.check(&extent).unwrap_err();
+        assert_eq!(exceeded.to_string(), "holds 4 keys at once at 2.500s (limit 3)");
+        let limits = Limits {
+            max_keys: Some(2),
+            max_text: Some(5),
+        };
+        assert!(limits.check(&extent).unwrap_err().to_string().ends_with(", types 9 characters (limit 5)"));
+
+        let guard = parse_macro("# Window: ^Minecraft\\b\n\ntap W\n").unwrap().metadata.window.unwrap();
+        assert!(guard.allows("Minecraft 1.21"));
+        assert!(!guard.allows("root@host: ~"));
+        let error = guard.before_playing(&mut || Ok("root@host: ~".to_string()), || false).unwrap_err();
+        assert!(error.contains("Refusing to play into 'root@host: ~'"));
+        assert!(guard.before_playing(&mut || Ok("Minecraft".to_string()), || false).is_ok());
+        let waiting = WindowGuard { wait: true, ..guard.clone() };
+        assert!(waiting.before_playing(&mut || Ok("bash".to_string()), || true).unwrap_err().contains("Stopped"));
+        // A title that can't be read fails at once, waiting or not, with why
+        let unsupported = || Err(io::Error::new(io::ErrorKind::Unsupported, "no way to ask this compositor"));
+        let error = waiting.before_playing(&mut unsupported.clone(), || false).unwrap_err();
+        assert!(error.starts_with("Can't tell which window has focus"));
+        assert!(error.ends_with("no way to ask this compositor"));
+
+        // Focus is looked at again once the interval is up
+        let titles = std::rc::Rc::new(std::cell::RefCell::new(vec!["Minecraft", "bash"]));
+        let remaining = std::rc::Rc::clone(&titles);
+        let stop = Arc::new(AtomicBool::new(false));
+        let next = move || Ok(remaining.borrow_mut().remove(0).to_string());
+        let mut unfocused = guard.unfocused(next, Arc::clone(&stop));
+        assert!(!unfocused());
+        assert!(!unfocused());
+        thread::sleep(FOCUS_CHECK_INTERVAL);
+        assert!(unfocused());
+        assert!(titles.borrow().is_empty());
+        assert!(!stop.load(Ordering::Relaxed));
+
+        // and once it can't be, playback is stopped rather than paused for good
+        let mut unfocused = guard.unfocused(unsupported, Arc::clone(&stop));
+        assert!(unfocused());
+        assert!(stop.load(Ordering::Relaxed));
+    }
+}
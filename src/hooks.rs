@@ -0,0 +1,183 @@
+//! Notifications and user commands around playback
+//!
+//! Hooks fire when a macro starts, finishes, is aborted (SIGINT/SIGTERM) or
+//! fails. Each can show a desktop notification through `notify-send` and/or
+//! run a shell command, which gets `EVKEY_MACRO`, `EVKEY_EVENT` and (for
+//! errors) `EVKEY_ERROR` in its environment.
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::fmt;
+use std::io;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Point in playback at which hooks fire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Start,
+    Finish,
+    Abort,
+    Error,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HookEvent::Start => "start",
+            HookEvent::Finish => "finish",
+            HookEvent::Abort => "abort",
+            HookEvent::Error => "error",
+        })
+    }
+}
+
+/// What to do on each playback event
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    /// Show desktop notifications
+    pub notify: bool,
+    pub on_start: Option<String>,
+    pub on_finish: Option<String>,
+    pub on_abort: Option<String>,
+    pub on_error: Option<String>,
+}
+
+impl Hooks {
+    /// Whether any hook is configured
+    pub fn is_empty(&self) -> bool {
+        !self.notify
+            && self.on_start.is_none()
+            && self.on_finish.is_none()
+            && self.on_abort.is_none()
+            && self.on_error.is_none()
+    }
+
+    fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Start => self.on_start.as_deref(),
+            HookEvent::Finish => self.on_finish.as_deref(),
+            HookEvent::Abort => self.on_abort.as_deref(),
+            HookEvent::Error => self.on_error.as_deref(),
+        }
+    }
+
+    /// Fire the hooks for `event`; failures are reported but never fatal
+    pub fn fire(&self, event: HookEvent, macro_name: &str, error: Option<&str>) {
+        if self.notify {
+            if let Err(e) = notify(event, macro_name, error) {
+                warn!("Couldn't show notification: {}", e);
+            }
+        }
+
+        if let Some(command) = self.command(event) {
+            info!("Running {} hook: {}", event, command);
+            let status = Command::new("sh")
+                .args(["-c", command])
+                .env("EVKEY_MACRO", macro_name)
+                .env("EVKEY_EVENT", event.to_string())
+                .env("EVKEY_ERROR", error.unwrap_or(""))
+                .status();
+            match status {
+                Ok(status) if !status.success() => {
+                    warn!("{} hook exited with {}", event, status);
+                }
+                Err(e) => warn!("Couldn't run {} hook: {}", event, e),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Set `stop` when playback is interrupted (SIGINT/SIGTERM), so the
+    /// player lets go of held keys before the caller fires the abort hooks
+    ///
+    /// The returned signal number stays 0 until then. Without hooks, the
+    /// signals are left alone.
+    pub fn watch_for_abort(&self, stop: &Arc<AtomicBool>) -> io::Result<Arc<AtomicUsize>> {
+        let signal = Arc::new(AtomicUsize::new(0));
+        if self.is_empty() {
+            return Ok(signal);
+        }
+
+        for number in [SIGINT, SIGTERM] {
+            signal_hook::flag::register(number, Arc::clone(stop))?;
+            signal_hook::flag::register_usize(number, Arc::clone(&signal), number as usize)?;
+        }
+        Ok(signal)
+    }
+}
+
+/// Show a desktop notification through libnotify's `notify-send`
+fn notify(event: HookEvent, macro_name: &str, error: Option<&str>) -> io::Result<()> {
+    let (urgency, summary) = match event {
+        HookEvent::Start => ("low", "Macro started"),
+        HookEvent::Finish => ("normal", "Macro finished"),
+        HookEvent::Abort => ("normal", "Macro aborted"),
+        HookEvent::Error => ("critical", "Macro failed"),
+    };
+    let body = match error {
+        Some(error) => format!("{}: {}", macro_name, error),
+        None => macro_name.to_string(),
+    };
+    send_notification(urgency, summary, &body)
+}
+
+/// Show which daemon profile just became active
+pub fn notify_profile(name: &str) -> io::Result<()> {
+    send_notification("low", "EvKey profile", name)
+}
+
+/// Ask for the second press of a hotkey whose macro needs confirming
+pub fn notify_confirm(combo: &str, macro_name: &str, within_secs: u64) -> io::Result<()> {
+    let body = format!("Press {} again within {}s to play {}", combo, within_secs, macro_name);
+    send_notification("critical", "Confirm macro", &body)
+}
+
+fn send_notification(urgency: &str, summary: &str, body: &str) -> io::Result<()> {
+    Command::new("notify-send")
+        .args(["--app-name", "EvKey", "--urgency", urgency, summary, body])
+        .status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_for_each_event() {
+        let hooks = Hooks {
+            on_start: Some("echo start".to_string()),
+            on_abort: Some("echo abort".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(hooks.command(HookEvent::Start), Some("echo start"));
+        assert_eq!(hooks.command(HookEvent::Finish), None);
+        assert_eq!(hooks.command(HookEvent::Abort), Some("echo abort"));
+        assert_eq!(hooks.command(HookEvent::Error), None);
+        assert!(!hooks.is_empty());
+        assert!(Hooks::default().is_empty());
+    }
+
+    #[test]
+    fn test_fire_passes_environment() {
+        let path = std::env::temp_dir().join(format!("evkey-hook-env-{}", std::process::id()));
+        let hooks = Hooks {
+            on_error: Some(format!("env > '{}'", path.display())),
+            ..Default::default()
+        };
+
+        hooks.fire(HookEvent::Error, "farm.macro", Some("No such file"));
+        let env = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = env.lines().collect();
+        assert!(lines.contains(&"EVKEY_MACRO=farm.macro"));
+        assert!(lines.contains(&"EVKEY_EVENT=error"));
+        assert!(lines.contains(&"EVKEY_ERROR=No such file"));
+
+        // Events without a command run nothing
+        hooks.fire(HookEvent::Finish, "farm.macro", None);
+        assert!(!path.exists());
+    }
+}
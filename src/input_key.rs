@@ -0,0 +1,145 @@
+//! What a keycode is
+//!
+//! Linux numbers keyboard keys, mouse buttons and gamepad buttons in one
+//! keycode space, and a recording can hold any of them. [`InputKey`] says
+//! which one a code is, so states, devices and file formats can treat each
+//! kind as what it is instead of checking code ranges themselves.
+
+use crate::keymap::KEY_MAX;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A keycode and the kind of button it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputKey {
+    /// A key of a keyboard (`KEY_*`)
+    Keyboard(u16),
+    /// BTN_LEFT up to BTN_TASK
+    MouseButton(u16),
+    /// Joystick and gamepad buttons (BTN_TRIGGER up to BTN_THUMBR), the
+    /// d-pad and the extra BTN_TRIGGER_HAPPY ones
+    GamepadButton(u16),
+    /// KEY_RESERVED, codes past `KEY_MAX` and the buttons of devices EvKey
+    /// doesn't play (BTN_0..BTN_9, tablet pens, wheels)
+    Unknown(u16),
+}
+
+impl InputKey {
+    /// Classify `code`; codes outside the keycode range become
+    /// [`InputKey::Unknown`]
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            0x110..0x120 => InputKey::MouseButton(code),
+            0x120..0x140 | 0x220..0x224 | 0x2c0..0x2e8 => InputKey::GamepadButton(code),
+            0 | 0x100..0x110 | 0x140..0x160 => InputKey::Unknown(code),
+            code if code > KEY_MAX => InputKey::Unknown(code),
+            code => InputKey::Keyboard(code),
+        }
+    }
+
+    /// Classify `code`, refusing KEY_RESERVED and codes past `KEY_MAX`
+    pub fn new(code: u16) -> Result<Self, String> {
+        if code == 0 || code > KEY_MAX {
+            return Err(format!("Keycode {} is out of range (1-{})", code, KEY_MAX));
+        }
+        Ok(Self::from_code(code))
+    }
+
+    pub fn code(self) -> u16 {
+        match self {
+            InputKey::Keyboard(code)
+            | InputKey::MouseButton(code)
+            | InputKey::GamepadButton(code)
+            | InputKey::Unknown(code) => code,
+        }
+    }
+
+    pub fn is_keyboard(self) -> bool {
+        matches!(self, InputKey::Keyboard(_))
+    }
+
+    pub fn is_mouse_button(self) -> bool {
+        matches!(self, InputKey::MouseButton(_))
+    }
+
+    pub fn is_gamepad_button(self) -> bool {
+        matches!(self, InputKey::GamepadButton(_))
+    }
+
+    /// How a file writes the key when it has no name: `BTN_<code>` for mouse
+    /// and gamepad buttons, `KEY_<code>` for anything else
+    pub fn numbered_name(self) -> String {
+        match self {
+            InputKey::MouseButton(code) | InputKey::GamepadButton(code) => format!("BTN_{}", code),
+            InputKey::Keyboard(code) | InputKey::Unknown(code) => format!("KEY_{}", code),
+        }
+    }
+}
+
+impl From<u16> for InputKey {
+    fn from(code: u16) -> Self {
+        Self::from_code(code)
+    }
+}
+
+impl From<InputKey> for u16 {
+    fn from(key: InputKey) -> Self {
+        key.code()
+    }
+}
+
+impl PartialEq<u16> for InputKey {
+    fn eq(&self, code: &u16) -> bool {
+        self.code() == *code
+    }
+}
+
+/// Keys order by code; the kind follows from it
+impl Ord for InputKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.code().cmp(&other.code())
+    }
+}
+
+impl PartialOrd for InputKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for InputKey {
+    /// The key's name, e.g. "CTRL" or "MOUSE_LEFT", or its
+    /// [`InputKey::numbered_name`]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match crate::keymap::keycode_to_name(self.code()) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{}", self.numbered_name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classification() {
+        // A, BTN_LEFT, BTN_SOUTH, BTN_DPAD_UP, BTN_0 and past KEY_MAX
+        assert_eq!(InputKey::from(30), InputKey::Keyboard(30));
+        assert_eq!(InputKey::from(0x110), InputKey::MouseButton(0x110));
+        assert_eq!(InputKey::from(0x130), InputKey::GamepadButton(0x130));
+        assert!(InputKey::from(0x220).is_gamepad_button());
+        assert_eq!(InputKey::from(0x100), InputKey::Unknown(0x100));
+        assert_eq!(InputKey::from(0x300), InputKey::Unknown(0x300));
+        assert!(InputKey::from(0x1d0).is_keyboard()); // KEY_FN
+
+        assert_eq!(u16::from(InputKey::from(0x111)), 0x111);
+        assert!(InputKey::new(0).unwrap_err().contains("out of range"));
+        assert!(InputKey::new(KEY_MAX + 1).is_err());
+        assert!(InputKey::new(KEY_MAX).is_ok());
+
+        assert_eq!(InputKey::from(0x2c5).numbered_name(), "BTN_709");
+        assert_eq!(InputKey::from(0x2a0).to_string(), "KEY_672");
+        assert!(InputKey::from(48) < InputKey::from(0x110));
+    }
+}
@@ -0,0 +1,228 @@
+//! Crash-safe recording
+//!
+//! A recording is kept in memory until it's saved, so a crash or OOM kill
+//! during a long one would lose all of it. While `evkey record` runs, every
+//! event also goes to a journal next to the output file (`my.macro.journal`),
+//! in the [`crate::stream`] format, synced to disk every [`SYNC_INTERVAL`].
+//! Saving the macro deletes the journal; one left behind is an unfinished
+//! recording, which `evkey recover` turns into a macro, losing at most the
+//! last couple of seconds.
+//!
+//! Markers, click positions and the metadata only exist once the recording is
+//! saved, so a recovered macro has the events alone.
+
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use crate::keyset::KeySet;
+use crate::stream::{StreamReader, StreamWriter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Extension added to the output file's name
+pub const EXTENSION: &str = "journal";
+
+/// How long after the journal's last event keys still held are released
+/// (in microseconds)
+const RELEASE_AFTER_US: u64 = 1_000;
+
+/// How often the journal is synced to disk
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The journal of a recording into `output`
+pub fn path_for(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Fail if a recording into `output` was left unfinished, so it isn't
+/// overwritten before it's recovered
+pub fn check_unfinished(output: &Path) -> io::Result<()> {
+    let path = path_for(output);
+    if !path.exists() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!(
+            "An unfinished recording was left in {0}; run `evkey recover {0}` to save it, or delete it",
+            path.display()
+        ),
+    ))
+}
+
+/// Events of a recording in progress, as written so far
+pub struct Journal {
+    path: PathBuf,
+    writer: StreamWriter<BufWriter<File>>,
+    /// Events of the recording already in the journal
+    written: usize,
+    synced: Instant,
+}
+
+impl Journal {
+    /// Start the journal of a recording into `output`, unless one is
+    /// already there (see [`check_unfinished`])
+    pub fn create(output: &Path) -> io::Result<Self> {
+        check_unfinished(output)?;
+        let path = path_for(output);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't create {}: {}", path.display(), e)))?;
+        Ok(Self {
+            path,
+            writer: StreamWriter::new(BufWriter::new(file))?,
+            written: 0,
+            synced: Instant::now(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the events of `recorded` (everything recorded so far) that
+    /// aren't in the journal yet, syncing it if it's been a while
+    pub fn catch_up(&mut self, recorded: &[RecordedEvent]) -> io::Result<()> {
+        let new = recorded.get(self.written..).unwrap_or_default();
+        if !new.is_empty() {
+            self.writer.send(new)?;
+            self.written = recorded.len();
+        }
+        if self.synced.elapsed() >= SYNC_INTERVAL {
+            self.writer.get_ref().get_ref().sync_data()?;
+            self.synced = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// The recording was saved, so the journal isn't needed any more
+    pub fn remove(self) -> io::Result<()> {
+        drop(self.writer);
+        std::fs::remove_file(&self.path)
+    }
+}
+
+/// What was read back from a journal
+#[derive(Debug, Clone, Default)]
+pub struct Recovered {
+    pub events: Vec<RecordedEvent>,
+    /// Keys and buttons still held where the journal ends, released at the
+    /// end of `events`, just after the last one
+    pub released: usize,
+}
+
+/// Read back the events of a journal, up to where it was cut off
+pub fn recover(path: &Path) -> io::Result<Recovered> {
+    let reader = StreamReader::new(BufReader::new(File::open(path)?))?;
+    // A journal left behind never got its end frame, and may end halfway
+    // through an event; everything before that is kept
+    let mut events: Vec<RecordedEvent> = reader.map_while(Result::ok).collect();
+
+    let mut held = KeySet::new();
+    for recorded in &events {
+        if recorded.event.event_type() == EventType::KEY {
+            match recorded.event.value() {
+                0 => {
+                    held.remove(recorded.event.code());
+                }
+                _ => {
+                    held.insert(recorded.event.code());
+                }
+            }
+        }
+    }
+    // Just after the last event, so the keys' last state isn't empty
+    let timestamp_us = events.last().map_or(0, |last| last.timestamp_us + RELEASE_AFTER_US);
+    let released = held.len();
+    for code in held.codes() {
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::KEY.0, code, 0),
+        });
+    }
+    if released > 0 {
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+        });
+    }
+    Ok(Recovered { events, released })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::key;
+
+    fn temp_output(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("evkey-journal-{}-{}.macro", name, std::process::id()))
+    }
+
+    fn codes(events: &[RecordedEvent]) -> Vec<(u64, u16, i32)> {
+        events
+            .iter()
+            .map(|e| (e.timestamp_us, e.event.code(), e.event.value()))
+            .collect()
+    }
+
+    #[test]
+    fn test_path_for() {
+        let path = path_for(&temp_output("path"));
+        assert!(path.to_string_lossy().ends_with(".macro.journal"));
+    }
+
+    #[test]
+    fn test_create_refuses_a_leftover_journal() {
+        let output = temp_output("leftover");
+        let journal = Journal::create(&output).unwrap();
+        assert!(Journal::create(&output).err().unwrap().to_string().contains("evkey recover"));
+
+        journal.remove().unwrap();
+        assert!(!path_for(&output).exists());
+    }
+
+    #[test]
+    fn test_catch_up_writes_only_new_events() {
+        let output = temp_output("catch-up");
+        let mut recorded = vec![key(0, 30, 1)];
+        let mut journal = Journal::create(&output).unwrap();
+        journal.catch_up(&recorded).unwrap();
+        recorded.push(key(50_000, 30, 0));
+        journal.catch_up(&recorded).unwrap();
+        journal.catch_up(&recorded).unwrap();
+        drop(journal);
+
+        let path = path_for(&output);
+        let recovered = recover(&path).unwrap();
+        assert_eq!(recovered.released, 0);
+        assert_eq!(codes(&recovered.events), [(0, 30, 1), (50_000, 30, 0)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recover_releases_held_keys() {
+        let output = temp_output("recover");
+        let mut journal = Journal::create(&output).unwrap();
+        journal.catch_up(&[key(0, 29, 1), key(10_000, 30, 1), key(50_000, 30, 0)]).unwrap();
+        // Killed here: no end frame, and half an event at the end
+        drop(journal);
+        let path = path_for(&output);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[crate::stream::FRAME_EVENT, 1, 2, 3]);
+        std::fs::write(&path, bytes).unwrap();
+
+        let recovered = recover(&path).unwrap();
+        // CTRL was still held, so it's let go at the end
+        assert_eq!(recovered.released, 1);
+        assert_eq!(
+            codes(&recovered.events),
+            [(0, 29, 1), (10_000, 30, 1), (50_000, 30, 0), (51_000, 29, 0), (51_000, 0, 0)]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,24 +1,346 @@
 //! Keyboard layout mappings for converting between keycodes and human-readable names
 //!
 //! Currently supports QWERTY layout. Future: XKB integration for multi-layout support.
+//!
+//! A [`CustomKeymap`] installed with [`set_custom`] names keycodes the
+//! built-in table doesn't know (foot pedals, vendor keys) or renames ones it
+//! does, and adds aliases. It is written in a small subset of TOML:
+//!
+//!   [names]
+//!   183 = "PEDAL_LEFT"
+//!   184 = "PEDAL_RIGHT"
+//!
+//!   [aliases]
+//!   FOOT = "PEDAL_LEFT"
+//!   RETURN = "ENTER"
+//!
+//! That is, the two tables, each with one `key = "NAME"` per line and `#`
+//! comments on lines of their own. Inline tables, escapes and other TOML
+//! aren't read; names can't need them anyway. With the `devices` feature, the
+//! same tables can be written as JSON instead ([`CustomKeymap::parse_json`]),
+//! in a `.json` file.
+//!
+//! Custom names are what macros get written with; built-in names and aliases
+//! are still understood when reading.
 
+use crate::config;
+use crate::input_key::InputKey;
+use crate::keyset::KeySet;
+use crate::locale;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Highest keycode the kernel defines (`KEY_MAX`)
+pub const KEY_MAX: u16 = 0x2ff;
 
 /// Get human-readable name for a Linux keycode (QWERTY layout)
 pub fn keycode_to_name(keycode: u16) -> Option<String> {
+    if let Some(name) = custom().and_then(|custom| custom.names.get(&keycode)) {
+        return Some(name.clone());
+    }
     let map = get_qwerty_map();
     map.get(&keycode).map(|s| s.to_string())
 }
 
 /// Get Linux keycode from human-readable name (QWERTY layout)
 pub fn name_to_keycode(name: &str) -> Option<u16> {
+    if let Some(code) = custom().and_then(|custom| custom.lookup(name)) {
+        return Some(code);
+    }
     let map = get_qwerty_reverse_map();
-    map.get(name.to_uppercase().as_str()).copied()
+    // Names are usually written in upper case already; skip the allocation then
+    map.get(name)
+        .or_else(|| map.get(name.to_uppercase().as_str()))
+        .copied()
+        .or_else(|| parse_keycode(name))
+}
+
+/// A key by number, for keys without a name: `KEY_183`, `KEY_0xB7` or `183`,
+/// and `BTN_709` for buttons
+///
+/// Names win, so `1` is the 1 key rather than keycode 1.
+fn parse_keycode(name: &str) -> Option<u16> {
+    let number = ["KEY_", "key_", "BTN_", "btn_"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+    let code = match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+        None => number.parse().ok()?,
+    };
+    InputKey::new(code).ok().map(InputKey::code)
+}
+
+/// Name to show a user for a keycode: a custom name, else the built-in name
+/// in the display language (see [`locale`]); files use [`keycode_to_name`]
+pub fn display_name(keycode: u16) -> Option<String> {
+    if let Some(name) = custom().and_then(|custom| custom.names.get(&keycode)) {
+        return Some(name.clone());
+    }
+    let name = *get_qwerty_map().get(&keycode)?;
+    Some(locale::translate(name).unwrap_or(name).to_string())
+}
+
+/// Keycode names and aliases from a user's keymap file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CustomKeymap {
+    /// Upper case, like the built-in names
+    names: HashMap<u16, String>,
+    /// Every name accepted when reading, custom names included
+    codes: HashMap<String, u16>,
+}
+
+impl CustomKeymap {
+    /// Parse the `[names]` and `[aliases]` tables of a keymap file
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut keymap = CustomKeymap::default();
+        let mut aliases = Vec::new();
+        let mut section = None;
+
+        for (line_num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("Line {}: {}", line_num + 1, message);
+
+            if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                match name.trim() {
+                    "names" | "aliases" => section = Some(name.trim()),
+                    other => return Err(error(format!("Unknown table [{}], expected [names] or [aliases]", other))),
+                }
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(error(format!("Expected 'key = \"NAME\"', got '{}'", line)));
+            };
+            if value.trim_start().starts_with('{') {
+                let table = section.unwrap_or("names");
+                return Err(error(format!("Inline tables aren't supported, write one entry per line in [{}]", table)));
+            }
+            if line.contains('\\') {
+                return Err(error("Escapes aren't supported, names are letters, digits and _".to_string()));
+            }
+            let key = unquote(key.trim());
+            let value = unquote(value.trim());
+            match section {
+                Some("names") => keymap.add_name(key, value).map_err(error)?,
+                Some(_) => aliases.push((line_num, key_name(key).map_err(error)?, value.to_string())),
+                None => return Err(error("Names go in a [names] or [aliases] table".to_string())),
+            }
+        }
+
+        // Aliases may point at custom names, built-in names or keycodes
+        for (line_num, alias, target) in aliases {
+            keymap.add_alias(alias, &target).map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
+        }
+
+        Ok(keymap)
+    }
+
+    /// Parse a keymap written as JSON, with the same two tables as objects:
+    ///
+    ///   {"names": {"183": "PEDAL_LEFT"}, "aliases": {"FOOT": "PEDAL_LEFT"}}
+    #[cfg(feature = "devices")]
+    pub fn parse_json(text: &str) -> Result<Self, String> {
+        use serde_json::Value;
+
+        let root: Value = serde_json::from_str(text).map_err(|e| format!("Invalid JSON: {}", e))?;
+        let root = root.as_object().ok_or("Expected an object with \"names\" and/or \"aliases\"")?;
+        let table = |name: &str| -> Result<Vec<(String, String)>, String> {
+            let Some(table) = root.get(name) else {
+                return Ok(Vec::new());
+            };
+            let table = table.as_object().ok_or_else(|| format!("\"{}\" has to be an object", name))?;
+            table
+                .iter()
+                .map(|(key, value)| match value.as_str() {
+                    Some(value) => Ok((key.clone(), value.to_string())),
+                    None => Err(format!("{}.{}: expected a name in quotes", name, key)),
+                })
+                .collect()
+        };
+        if let Some(other) = root.keys().find(|key| *key != "names" && *key != "aliases") {
+            return Err(format!("Unknown table \"{}\", expected \"names\" or \"aliases\"", other));
+        }
+
+        let mut keymap = CustomKeymap::default();
+        for (key, value) in table("names")? {
+            keymap.add_name(&key, &value).map_err(|e| format!("names.{}: {}", key, e))?;
+        }
+        for (alias, target) in table("aliases")? {
+            let context = |e: String| format!("aliases.{}: {}", alias, e);
+            keymap.add_alias(key_name(&alias).map_err(context)?, &target).map_err(context)?;
+        }
+        Ok(keymap)
+    }
+
+    /// Name keycode `key` (a number) `value`
+    fn add_name(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let code = key
+            .parse::<u16>()
+            .ok()
+            .filter(|&code| code <= KEY_MAX)
+            .ok_or_else(|| format!("Invalid keycode '{}'", key))?;
+        let name = key_name(value)?;
+        if self.codes.insert(name.clone(), code).is_some() {
+            return Err(format!("Duplicate name '{}'", name));
+        }
+        self.names.insert(code, name);
+        Ok(())
+    }
+
+    /// Accept `alias` for `target`, a custom name, built-in name or keycode
+    fn add_alias(&mut self, alias: String, target: &str) -> Result<(), String> {
+        let code = self
+            .lookup(target)
+            .or_else(|| get_qwerty_reverse_map().get(target.to_uppercase().as_str()).copied())
+            .or_else(|| target.parse().ok().filter(|&code| code <= KEY_MAX))
+            .ok_or_else(|| format!("Unknown key '{}' for alias '{}'", target, alias))?;
+        self.codes.insert(alias, code);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Option<u16> {
+        self.codes
+            .get(name)
+            .or_else(|| self.codes.get(name.to_uppercase().as_str()))
+            .copied()
+    }
+}
+
+/// Strip TOML string quotes, if any
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Upper-case `name`, checking it can be written in a combo
+pub(crate) fn key_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid key name '{}': use letters, digits and _", name));
+    }
+    Ok(name.to_ascii_uppercase())
+}
+
+/// Where a custom keymap is picked up from: `$XDG_CONFIG_HOME/evkey/keymap.toml`
+pub fn config_path() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("keymap.toml"))
+}
+
+static CUSTOM: OnceLock<CustomKeymap> = OnceLock::new();
+
+/// Use `keymap` on top of the built-in names from now on; returns `false` if
+/// a custom keymap was already set
+pub fn set_custom(keymap: CustomKeymap) -> bool {
+    CUSTOM.set(keymap).is_ok()
+}
+
+fn custom() -> Option<&'static CustomKeymap> {
+    CUSTOM.get()
+}
+
+/// Modifier keys in the order they're conventionally written ("CTRL+SHIFT+P")
+const MODIFIER_ORDER: &[u16] = &[
+    29,  // CTRL
+    97,  // RIGHTCTRL
+    42,  // SHIFT
+    54,  // RIGHTSHIFT
+    56,  // ALT
+    100, // RIGHTALT
+    125, // META
+    126, // RIGHTMETA
+];
+
+/// Order keys as a shortcut: modifiers first in CTRL, SHIFT, ALT, META order,
+/// then the remaining keys by name (unnamed keys last, by code)
+pub fn sort_combo<I: IntoIterator<Item = u16>>(keys: I) -> Vec<u16> {
+    let mut keys: Vec<u16> = keys.into_iter().collect();
+
+    keys.sort_by_cached_key(|code| {
+        let modifier_rank = MODIFIER_ORDER
+            .iter()
+            .position(|m| m == code)
+            .unwrap_or(MODIFIER_ORDER.len());
+        let name = keycode_to_name(*code);
+        (modifier_rank, name.is_none(), name, *code)
+    });
+
+    keys
+}
+
+/// Render a key set in conventional shortcut notation, e.g. "CTRL+SHIFT+P";
+/// unnamed keys are written as [`InputKey::numbered_name`]s
+pub fn format_combo<I: IntoIterator<Item = u16>>(keys: I) -> String {
+    sort_combo(keys)
+        .into_iter()
+        .map(|code| InputKey::from(code).to_string())
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// [`format_combo`] with [`display_name`]s, e.g. "Strg+Umschalt+P", for
+/// showing to the user
+pub fn display_combo<I: IntoIterator<Item = u16>>(keys: I) -> String {
+    sort_combo(keys)
+        .into_iter()
+        .map(|code| display_name(code).unwrap_or_else(|| InputKey::from(code).numbered_name()))
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// Right-hand modifiers and the key they match when sides don't matter
+const RIGHT_MODIFIERS: &[(u16, u16)] = &[
+    (97, 29),   // RIGHTCTRL -> CTRL
+    (54, 42),   // RIGHTSHIFT -> SHIFT
+    (100, 56),  // RIGHTALT -> ALT
+    (126, 125), // RIGHTMETA -> META
+];
+
+/// CAPSLOCK, NUMLOCK and SCROLLLOCK
+const LOCK_KEYS: &[u16] = &[58, 69, 70];
+
+/// The left-hand (unprefixed) variant of a right-hand modifier, or `code`
+pub fn either_side(code: u16) -> u16 {
+    RIGHT_MODIFIERS
+        .iter()
+        .find(|(right, _)| *right == code)
+        .map_or(code, |(_, left)| *left)
+}
+
+/// Whether holding `held` presses the hotkey `combo`
+///
+/// Unless `exact_sides`, either Ctrl (Shift, Alt, Meta) counts as the other.
+/// Lock keys held alongside are ignored unless the combo has them, so a combo
+/// fires the same with or without caps lock.
+pub fn combo_matches(combo: &KeySet, held: &KeySet, exact_sides: bool) -> bool {
+    let normalize = |code: u16| if exact_sides { code } else { either_side(code) };
+    let combo: KeySet = combo.codes().map(normalize).collect();
+    let held: KeySet = held
+        .codes()
+        .filter(|&code| !LOCK_KEYS.contains(&code) || combo.contains(code))
+        .map(normalize)
+        .collect();
+    combo == held
+}
+
+/// X servers using the evdev XKB rules offset Linux keycodes by 8
+const X11_KEYCODE_OFFSET: u16 = 8;
+
+/// Convert a Linux keycode to the X11 keycode of the same physical key
+#[cfg_attr(not(feature = "x11"), allow(dead_code))]
+pub fn to_x11_keycode(keycode: u16) -> Option<u8> {
+    u8::try_from(keycode.checked_add(X11_KEYCODE_OFFSET)?).ok()
 }
 
-/// QWERTY layout keycode to name mapping
-fn get_qwerty_map() -> HashMap<u16, &'static str> {
-    HashMap::from([
+/// QWERTY layout keycode to name mapping, built on first use
+fn get_qwerty_map() -> &'static HashMap<u16, &'static str> {
+    static MAP: OnceLock<HashMap<u16, &'static str>> = OnceLock::new();
+    MAP.get_or_init(|| HashMap::from([
         // Letters (QWERTY physical layout)
         (16, "Q"),
         (17, "W"),
@@ -86,8 +408,12 @@ fn get_qwerty_map() -> HashMap<u16, &'static str> {
         (56, "ALT"),
         (57, "SPACE"),
         (58, "CAPSLOCK"),
+        (69, "NUMLOCK"),
+        (70, "SCROLLLOCK"),
         (97, "RIGHTCTRL"),
         (100, "RIGHTALT"),
+        (125, "META"),
+        (126, "RIGHTMETA"),
 
         // Navigation
         (102, "HOME"),
@@ -134,12 +460,32 @@ fn get_qwerty_map() -> HashMap<u16, &'static str> {
         (272, "BTN_LEFT"),
         (273, "BTN_RIGHT"),
         (274, "BTN_MIDDLE"),
-    ])
+
+        // Gamepad buttons
+        (304, "BTN_SOUTH"),
+        (305, "BTN_EAST"),
+        (307, "BTN_NORTH"),
+        (308, "BTN_WEST"),
+        (310, "BTN_TL"),
+        (311, "BTN_TR"),
+        (312, "BTN_TL2"),
+        (313, "BTN_TR2"),
+        (314, "BTN_SELECT"),
+        (315, "BTN_START"),
+        (316, "BTN_MODE"),
+        (317, "BTN_THUMBL"),
+        (318, "BTN_THUMBR"),
+        (544, "BTN_DPAD_UP"),
+        (545, "BTN_DPAD_DOWN"),
+        (546, "BTN_DPAD_LEFT"),
+        (547, "BTN_DPAD_RIGHT"),
+    ]))
 }
 
 /// Reverse mapping: name to keycode
-fn get_qwerty_reverse_map() -> HashMap<&'static str, u16> {
-    get_qwerty_map().into_iter().map(|(k, v)| (v, k)).collect()
+fn get_qwerty_reverse_map() -> &'static HashMap<&'static str, u16> {
+    static MAP: OnceLock<HashMap<&'static str, u16>> = OnceLock::new();
+    MAP.get_or_init(|| get_qwerty_map().iter().map(|(&k, &v)| (v, k)).collect())
 }
 
 #[cfg(test)]
@@ -159,6 +505,87 @@ mod tests {
         assert_eq!(name_to_keycode("w"), Some(17)); // Case insensitive
         assert_eq!(name_to_keycode("SPACE"), Some(57));
         assert_eq!(name_to_keycode("INVALID"), None);
+
+        // Numbers for keys without names; digits are still the digit keys
+        assert_eq!(name_to_keycode("KEY_183"), Some(183));
+        assert_eq!(name_to_keycode("KEY_0x2A"), Some(42));
+        assert_eq!(name_to_keycode("42"), Some(42));
+        assert_eq!(name_to_keycode("1"), Some(2));
+        assert_eq!(name_to_keycode("KEY_0x300"), None);
+        assert_eq!(name_to_keycode("KEY_"), None);
+    }
+
+    #[test]
+    fn test_format_combo() {
+        // P, SHIFT, CTRL in arbitrary order
+        assert_eq!(format_combo([25, 42, 29]), "CTRL+SHIFT+P");
+        assert_eq!(format_combo([30, 17]), "A+W");
+        assert_eq!(format_combo([]), "");
+    }
+
+    #[test]
+    fn test_combo_matches() {
+        let combo = |names: &str| -> KeySet { names.split('+').map(|name| name_to_keycode(name).unwrap()).collect() };
+        let ctrl_f9 = combo("CTRL+F9");
+
+        assert!(combo_matches(&ctrl_f9, &combo("CTRL+F9"), false));
+        assert!(combo_matches(&ctrl_f9, &combo("RIGHTCTRL+F9"), false));
+        assert!(!combo_matches(&ctrl_f9, &combo("RIGHTCTRL+F9"), true));
+        assert!(combo_matches(&ctrl_f9, &combo("CTRL+F9+CAPSLOCK"), true));
+        assert!(!combo_matches(&ctrl_f9, &combo("CTRL+SHIFT+F9"), false));
+        assert!(!combo_matches(&ctrl_f9, &combo("F9"), false));
+
+        // Bound on the right side only, either side still presses it
+        assert!(combo_matches(&combo("RIGHTALT+A"), &combo("ALT+A"), false));
+        // Lock keys in the combo itself must be held
+        assert!(combo_matches(&combo("CAPSLOCK+A"), &combo("CAPSLOCK+A"), false));
+        assert!(!combo_matches(&combo("CAPSLOCK+A"), &combo("A"), false));
+    }
+
+    #[test]
+    fn test_to_x11_keycode() {
+        assert_eq!(to_x11_keycode(30), Some(38)); // A
+        assert_eq!(to_x11_keycode(248), None);
+    }
+
+    #[test]
+    fn test_custom_keymap() {
+        let keymap = CustomKeymap::parse(
+            "# Foot pedals\n[names]\n183 = \"Pedal_Left\"\n184 = \"PEDAL_RIGHT\"\n\n[aliases]\nfoot = \"pedal_left\"\nRETURN = \"ENTER\"\nF13 = \"183\"\n",
+        )
+        .unwrap();
+        assert_eq!(keymap.names[&183], "PEDAL_LEFT");
+        assert_eq!(keymap.lookup("pedal_left"), Some(183));
+        assert_eq!(keymap.lookup("FOOT"), Some(183));
+        assert_eq!(keymap.lookup("RETURN"), Some(28));
+        assert_eq!(keymap.lookup("F13"), Some(183));
+
+        assert!(CustomKeymap::parse("183 = \"X\"").unwrap_err().contains("[names] or [aliases]"));
+        assert!(CustomKeymap::parse("[names]\n999 = \"X\"").unwrap_err().starts_with("Line 2: Invalid keycode"));
+        assert!(CustomKeymap::parse("[names]\n183 = \"LEFT PEDAL\"").unwrap_err().contains("Invalid key name"));
+        assert!(CustomKeymap::parse("[names]\n183 = \"P\"\n184 = \"P\"").unwrap_err().contains("Duplicate"));
+        assert!(CustomKeymap::parse("[aliases]\nX = \"NOPE\"").unwrap_err().contains("Unknown key 'NOPE'"));
+        assert!(CustomKeymap::parse("[keys]").unwrap_err().contains("Unknown table"));
+        // Only the subset of TOML described above
+        assert!(CustomKeymap::parse("[names]\nkeys = { 183 = \"P\" }").unwrap_err().contains("Inline tables"));
+        assert!(CustomKeymap::parse("[aliases]\nX = \"\\u0041\"").unwrap_err().contains("Escapes"));
+        assert!(CustomKeymap::parse("{\"names\": {\"183\": \"P\"}}").is_err());
+    }
+
+    #[cfg(feature = "devices")]
+    #[test]
+    fn test_json_keymap() {
+        let json = r#"{"names": {"183": "Pedal_Left"}, "aliases": {"foot": "pedal_left", "RETURN": "ENTER"}}"#;
+        let keymap = CustomKeymap::parse_json(json).unwrap();
+        let toml = "[names]\n183 = \"PEDAL_LEFT\"\n[aliases]\nFOOT = \"PEDAL_LEFT\"\nRETURN = \"ENTER\"\n";
+        assert_eq!(keymap, CustomKeymap::parse(toml).unwrap());
+
+        let error = |json| CustomKeymap::parse_json(json).unwrap_err();
+        assert!(error(r#"{"names": {"999": "X"}}"#).starts_with("names.999: Invalid keycode"));
+        assert!(error(r#"{"names": {"183": 5}}"#).contains("expected a name"));
+        assert!(error(r#"{"aliases": {"X": "NOPE"}}"#).contains("Unknown key 'NOPE'"));
+        assert!(error(r#"{"keys": {}}"#).contains("Unknown table"));
+        assert!(error("[names]").starts_with("Invalid JSON"));
     }
 
     #[test]
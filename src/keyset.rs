@@ -0,0 +1,159 @@
+//! Compact set of pressed keys
+//!
+//! Macros rarely hold more than a handful of keys at once, so a small sorted
+//! vector beats a hash set: up to `INLINE_KEYS` keys are stored inline, so
+//! cloning doesn't allocate, equality is a slice comparison, and differences
+//! are one linear merge. Keys are [`InputKey`]s, classified as they go in;
+//! methods that take a key take a bare keycode too.
+
+use crate::input_key::InputKey;
+use smallvec::SmallVec;
+use std::cmp::Ordering;
+use std::iter::Peekable;
+
+/// Keys stored without a heap allocation
+const INLINE_KEYS: usize = 8;
+
+/// Keys kept sorted by code and without duplicates
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct KeySet(SmallVec<[InputKey; INLINE_KEYS]>);
+
+impl KeySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn contains(&self, key: impl Into<InputKey>) -> bool {
+        self.0.binary_search(&key.into()).is_ok()
+    }
+
+    /// Add a key; returns whether it wasn't in the set yet
+    pub fn insert(&mut self, key: impl Into<InputKey>) -> bool {
+        let key = key.into();
+        match self.0.binary_search(&key) {
+            Ok(_) => false,
+            Err(index) => {
+                self.0.insert(index, key);
+                true
+            }
+        }
+    }
+
+    /// Remove a key; returns whether it was in the set
+    pub fn remove(&mut self, key: impl Into<InputKey>) -> bool {
+        match self.0.binary_search(&key.into()) {
+            Ok(index) => {
+                self.0.remove(index);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Keys in ascending keycode order
+    pub fn iter(&self) -> impl Iterator<Item = InputKey> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Keycodes in ascending order
+    pub fn codes(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().map(|key| key.code())
+    }
+
+    /// Keys in `self` but not in `other`, in ascending order
+    pub fn difference<'a>(&'a self, other: &'a KeySet) -> Difference<'a> {
+        Difference {
+            keys: self.0.iter(),
+            other: other.0.iter().peekable(),
+        }
+    }
+}
+
+impl FromIterator<InputKey> for KeySet {
+    fn from_iter<I: IntoIterator<Item = InputKey>>(iter: I) -> Self {
+        let mut keys: SmallVec<[InputKey; INLINE_KEYS]> = iter.into_iter().collect();
+        keys.sort_unstable();
+        keys.dedup();
+        Self(keys)
+    }
+}
+
+impl FromIterator<u16> for KeySet {
+    fn from_iter<I: IntoIterator<Item = u16>>(iter: I) -> Self {
+        iter.into_iter().map(InputKey::from).collect()
+    }
+}
+
+/// Keycodes, like [`KeySet::codes`], so a set can be handed to functions
+/// taking codes (e.g. [`crate::keymap::format_combo`])
+impl<'a> IntoIterator for &'a KeySet {
+    type Item = u16;
+    type IntoIter = std::iter::Map<std::slice::Iter<'a, InputKey>, fn(&InputKey) -> u16>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter().map(|key| key.code())
+    }
+}
+
+/// Iterator returned by [`KeySet::difference`]
+pub struct Difference<'a> {
+    keys: std::slice::Iter<'a, InputKey>,
+    other: Peekable<std::slice::Iter<'a, InputKey>>,
+}
+
+impl Iterator for Difference<'_> {
+    type Item = InputKey;
+
+    fn next(&mut self) -> Option<InputKey> {
+        'keys: for &key in self.keys.by_ref() {
+            while let Some(&&other) = self.other.peek() {
+                match other.cmp(&key) {
+                    Ordering::Less => {
+                        self.other.next();
+                    }
+                    Ordering::Equal => continue 'keys,
+                    Ordering::Greater => break,
+                }
+            }
+            return Some(key);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove_keeps_order() {
+        let mut keys = KeySet::new();
+        assert!(keys.insert(42));
+        assert!(keys.insert(17));
+        assert!(!keys.insert(42));
+        assert_eq!(keys.iter().collect::<Vec<_>>(), vec![17, 42]);
+
+        assert!(keys.remove(17));
+        assert!(!keys.remove(17));
+        assert!(keys.contains(42) && !keys.contains(17));
+        assert_eq!(keys, [42, 42].into_iter().collect());
+    }
+
+    #[test]
+    fn test_difference() {
+        let a: KeySet = [1, 3, 5, 7].into_iter().collect();
+        let b: KeySet = [0, 3, 4, 7, 9].into_iter().collect();
+
+        assert_eq!(a.difference(&b).collect::<Vec<_>>(), vec![1, 5]);
+        assert_eq!(b.difference(&a).collect::<Vec<_>>(), vec![0, 4, 9]);
+        assert_eq!(a.difference(&KeySet::new()).count(), 4);
+    }
+}
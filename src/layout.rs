@@ -0,0 +1,224 @@
+//! The keyboard layout a macro was recorded with
+//!
+//! Macros store keys, not characters, so a macro that types text only types
+//! the same text under the same XKB layout: recorded on a German layout and
+//! played on a US one, "Grüße" comes out as "Gr[-e". Recording notes the
+//! active layout in the macro header (`# Keyboard: layout=de ...`), and
+//! `evkey play` warns when the current one differs, or switches to the
+//! recorded one for the playback with `--match-layout`.
+//!
+//! The layout is read with `setxkbmap` on X11 and `hyprctl` on Hyprland, and
+//! otherwise taken from the system default `localectl` reports.
+
+use std::fmt;
+#[cfg(feature = "devices")]
+use std::io;
+#[cfg(feature = "devices")]
+use std::process::Command;
+#[cfg(feature = "devices")]
+use tracing::warn;
+
+/// XKB layout, variant and options, each possibly a comma-separated list
+/// when several layouts are configured
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XkbLayout {
+    pub layout: String,
+    pub variant: String,
+    pub options: String,
+}
+
+impl XkbLayout {
+    /// Parse `layout=de variant=nodeadkeys options=caps:escape`, as written
+    /// in the macro header; only the layout is required
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parsed = XkbLayout::default();
+        for field in s.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid keyboard layout field '{}', expected e.g. layout=us", field))?;
+            match key {
+                "layout" => parsed.layout = value.to_string(),
+                "variant" => parsed.variant = value.to_string(),
+                "options" => parsed.options = value.to_string(),
+                _ => return Err(format!("Unknown keyboard layout field '{}'", key)),
+            }
+        }
+        if parsed.layout.is_empty() {
+            return Err(format!("Keyboard layout '{}' has no layout=", s.trim()));
+        }
+        Ok(parsed)
+    }
+
+    /// Short description like "de (nodeadkeys)", for messages
+    pub fn describe(&self) -> String {
+        let mut text = self.layout.clone();
+        if !self.variant.trim_matches(',').is_empty() {
+            text.push_str(&format!(" ({})", self.variant));
+        }
+        if !self.options.is_empty() {
+            text.push_str(&format!(" with {}", self.options));
+        }
+        text
+    }
+}
+
+impl fmt::Display for XkbLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "layout={}", self.layout)?;
+        if !self.variant.is_empty() {
+            write!(f, " variant={}", self.variant)?;
+        }
+        if !self.options.is_empty() {
+            write!(f, " options={}", self.options)?;
+        }
+        Ok(())
+    }
+}
+
+/// The layout active in the current session
+#[cfg(feature = "devices")]
+pub fn current() -> io::Result<XkbLayout> {
+    let layout = if std::env::var_os("WAYLAND_DISPLAY").is_none() && std::env::var_os("DISPLAY").is_some() {
+        parse_setxkbmap(&run("setxkbmap", &["-query"])?)
+    } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        let option = |name: &str| run("hyprctl", &["getoption", name]).map(|output| parse_hyprctl_option(&output));
+        Some(XkbLayout {
+            layout: option("input:kb_layout")?,
+            variant: option("input:kb_variant")?,
+            options: option("input:kb_options")?,
+        })
+        .filter(|layout| !layout.layout.is_empty())
+    } else {
+        parse_localectl(&run("localectl", &["status"])?)
+    };
+    layout.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Can't tell the keyboard layout"))
+}
+
+/// Make `layout` the active one, where that's possible (X11 and Hyprland)
+#[cfg(feature = "devices")]
+pub fn activate(layout: &XkbLayout) -> io::Result<()> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_none() && std::env::var_os("DISPLAY").is_some() {
+        // An empty -option first clears the current options
+        let args = ["-layout", &layout.layout, "-variant", &layout.variant, "-option", "", "-option", &layout.options];
+        run("setxkbmap", &args).map(drop)
+    } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        run("hyprctl", &["keyword", "input:kb_layout", &layout.layout])?;
+        run("hyprctl", &["keyword", "input:kb_variant", &layout.variant])?;
+        run("hyprctl", &["keyword", "input:kb_options", &layout.options]).map(drop)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Switching the keyboard layout is only supported on X11 and Hyprland",
+        ))
+    }
+}
+
+/// A layout switched to for playback, switched back when dropped
+#[cfg(feature = "devices")]
+pub struct Switched {
+    previous: XkbLayout,
+}
+
+#[cfg(feature = "devices")]
+impl Switched {
+    /// Activate `layout`, remembering the current one
+    pub fn to(layout: &XkbLayout) -> io::Result<Self> {
+        let previous = current()?;
+        activate(layout)?;
+        Ok(Self { previous })
+    }
+}
+
+#[cfg(feature = "devices")]
+impl Drop for Switched {
+    fn drop(&mut self) {
+        if let Err(e) = activate(&self.previous) {
+            warn!("Can't switch back to keyboard layout {}: {}", self.previous.describe(), e);
+        }
+    }
+}
+
+#[cfg(feature = "devices")]
+fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// `rules: evdev\nmodel: pc105\nlayout: de\nvariant: nodeadkeys\noptions: caps:escape`
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn parse_setxkbmap(output: &str) -> Option<XkbLayout> {
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(|value| value.trim().to_string())
+    };
+    Some(XkbLayout {
+        layout: field("layout")?,
+        variant: field("variant").unwrap_or_default(),
+        options: field("options").unwrap_or_default(),
+    })
+}
+
+/// `str: de\nset: true`; an unset option reads `str: [[EMPTY]]`
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn parse_hyprctl_option(output: &str) -> String {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("str:"))
+        .map(str::trim)
+        .filter(|value| *value != "[[EMPTY]]")
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// `X11 Layout: de` and friends, among other `localectl status` lines
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn parse_localectl(output: &str) -> Option<XkbLayout> {
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(name)?.strip_prefix(':'))
+            .map(|value| value.trim().to_string())
+    };
+    Some(XkbLayout {
+        layout: field("X11 Layout")?,
+        variant: field("X11 Variant").unwrap_or_default(),
+        options: field("X11 Options").unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_roundtrip_and_tools() {
+        let layout = XkbLayout::parse("layout=de variant=nodeadkeys options=caps:escape").unwrap();
+        assert_eq!(layout.to_string(), "layout=de variant=nodeadkeys options=caps:escape");
+        assert_eq!(layout.describe(), "de (nodeadkeys) with caps:escape");
+        assert_eq!(XkbLayout::parse("layout=us").unwrap().to_string(), "layout=us");
+        assert!(XkbLayout::parse("variant=intl").is_err());
+        assert!(XkbLayout::parse("layout=us keys=all").is_err());
+
+        let setxkbmap = "rules:      evdev\nmodel:      pc105\nlayout:     de\nvariant:    nodeadkeys\noptions:    caps:escape\n";
+        assert_eq!(parse_setxkbmap(setxkbmap), Some(layout));
+        assert_eq!(parse_setxkbmap("rules: evdev\n").map(|l| l.layout), None);
+
+        assert_eq!(parse_hyprctl_option("str: us,de\nset: true\n"), "us,de");
+        assert_eq!(parse_hyprctl_option("str: [[EMPTY]]\nset: false\n"), "");
+
+        let localectl = "   System Locale: LANG=fr_FR.UTF-8\n       VC Keymap: fr\n      X11 Layout: fr\n       X11 Model: pc105\n";
+        assert_eq!(parse_localectl(localectl).unwrap().describe(), "fr");
+    }
+}
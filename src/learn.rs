@@ -0,0 +1,258 @@
+//! Learning the buttons of a device EvKey has no names for
+//!
+//! Foot pedals, presenter remotes and macro pads often send keycodes the
+//! built-in table doesn't name (`KEY_183`), or pass themselves off as a
+//! keyboard sending ordinary keys. `evkey learn-device` has the user press
+//! each button in turn and then name it: codes without a name go in the
+//! custom keymap's `[names]` (see [`crate::keymap::CustomKeymap`]), and ones
+//! a keyboard sends too get an alias instead, so the keyboard's keys keep
+//! their names. A file of suggested `bind` lines for the daemon is written
+//! alongside.
+//!
+//! A button is everything pressed until all of it is let go again, so one
+//! sending a combo (remotes sending `CTRL+C`) is learned as that combo, which
+//! can be bound but not named.
+
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use evdev::{Device, EventType};
+
+use crate::keymap::{self, CustomKeymap};
+use crate::keyset::KeySet;
+use crate::recorder::InputDevice;
+
+/// How long to wait for the next button before taking it that there are no
+/// more
+pub const PRESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often devices are checked for presses
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The keys of one button, from its first press until all are let go
+#[derive(Debug, Default)]
+struct Press {
+    keys: Vec<u16>,
+    held: KeySet,
+}
+
+impl Press {
+    /// Take in a key event; `true` once the button is let go
+    fn feed(&mut self, code: u16, value: i32) -> bool {
+        match value {
+            1 => {
+                if !self.keys.contains(&code) {
+                    self.keys.push(code);
+                }
+                self.held.insert(code);
+            }
+            // Let go of something held from before, or a press
+            0 => {
+                self.held.remove(code);
+            }
+            // Autorepeat
+            _ => {}
+        }
+        !self.keys.is_empty() && self.held.is_empty()
+    }
+
+    /// The keys, in the order a combo is written
+    fn into_keys(self) -> Vec<u16> {
+        keymap::sort_combo(self.keys)
+    }
+}
+
+/// Read what `device` has; `true` once `press` is complete
+fn read_press(device: &mut Device, press: &mut Press) -> io::Result<bool> {
+    let events = match device.fetch_events() {
+        Ok(events) => events,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    for event in events {
+        if event.event_type() == EventType::KEY && press.feed(event.code(), event.value()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The first of `devices` a button is pressed and let go on, opened, with the
+/// button's keys
+pub fn wait_for_device(devices: &[InputDevice]) -> io::Result<(&InputDevice, Device, Vec<u16>)> {
+    let mut opened = Vec::new();
+    for info in devices {
+        // Ones we can't open can't be learned either
+        if let Ok(device) = Device::open(&info.path) {
+            device.set_nonblocking(true)?;
+            opened.push((info, device, Press::default()));
+        }
+    }
+    if opened.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No input devices could be opened"));
+    }
+
+    loop {
+        for (index, (_, device, press)) in opened.iter_mut().enumerate() {
+            if read_press(device, press)? {
+                let (info, device, press) = opened.swap_remove(index);
+                return Ok((info, device, press.into_keys()));
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The keys of the next button pressed on `device`, or `None` if nothing is
+/// pressed within `timeout`
+pub fn next_button(device: &mut Device, timeout: Duration) -> io::Result<Option<Vec<u16>>> {
+    device.set_nonblocking(true)?;
+    let started = Instant::now();
+    let mut press = Press::default();
+    loop {
+        if read_press(device, &mut press)? {
+            return Ok(Some(press.into_keys()));
+        }
+        // A button held down gets all the time it needs
+        if press.keys.is_empty() && started.elapsed() >= timeout {
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// A name for the `n`th button that `taken` says is free: `BUTTON_<n>`, or
+/// the next free number after it
+pub fn suggest_name(n: usize, taken: impl Fn(&str) -> bool) -> String {
+    (n..)
+        .map(|n| format!("BUTTON_{}", n))
+        .find(|name| !taken(name))
+        .expect("some number is free")
+}
+
+/// `name` in upper case, if it can name a key
+pub fn check_name(name: &str) -> Result<String, String> {
+    keymap::key_name(name)
+}
+
+/// The keymap file `text` with `names` added to its `[names]` table and
+/// `aliases` to its `[aliases]` one, creating them as needed
+///
+/// The result is checked like any keymap file, so a name used twice is an
+/// error rather than a file that no longer loads.
+pub fn add_to_keymap(text: &str, names: &[(u16, String)], aliases: &[(String, String)]) -> Result<String, String> {
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let tables = [
+        ("[names]", names.iter().map(|(code, name)| format!("{} = \"{}\"", code, name)).collect::<Vec<_>>()),
+        ("[aliases]", aliases.iter().map(|(alias, name)| format!("{} = \"{}\"", alias, name)).collect()),
+    ];
+    for (header, entries) in tables {
+        if entries.is_empty() {
+            continue;
+        }
+        match lines.iter().position(|line| line.trim() == header) {
+            Some(at) => {
+                // After the table's last entry, before the next table
+                let mut end = lines[at + 1..]
+                    .iter()
+                    .position(|line| line.trim().starts_with('['))
+                    .map_or(lines.len(), |offset| at + 1 + offset);
+                while end > at + 1 && lines[end - 1].trim().is_empty() {
+                    end -= 1;
+                }
+                lines.splice(end..end, entries);
+            }
+            None => {
+                if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                lines.push(header.to_string());
+                lines.extend(entries);
+            }
+        }
+    }
+
+    let text = lines.join("\n") + "\n";
+    CustomKeymap::parse(&text)?;
+    Ok(text)
+}
+
+/// Name of the bindings file suggested for a device when its name gives none
+pub const FALLBACK_BINDINGS_NAME: &str = "learned-device.conf";
+
+/// Files in the config directory a device's bindings mustn't be named after
+const RESERVED_NAMES: &[&str] = &["daemon"];
+
+/// File name for the suggested bindings of the device called `device_name`:
+/// the words of its name, lower case and joined by `-`, or
+/// [`FALLBACK_BINDINGS_NAME`] if that leaves nothing or names a file EvKey
+/// uses itself
+pub fn bindings_file_name(device_name: &str) -> String {
+    let slug = device_name
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if slug.is_empty() || RESERVED_NAMES.contains(&slug.as_str()) {
+        return FALLBACK_BINDINGS_NAME.to_string();
+    }
+    format!("{}.conf", slug)
+}
+
+/// A daemon config snippet binding each of `combos` (as written in a config)
+/// to a macro named after it
+pub fn bindings(device_name: &str, combos: &[String]) -> String {
+    let mut text = format!(
+        "# Bindings for {}, suggested by `evkey learn-device`.\n\
+         # Copy the ones you want into daemon.conf and record the macros.\n",
+        device_name
+    );
+    for combo in combos {
+        let file = combo.to_lowercase().replace('+', "_");
+        text.push_str(&format!("bind {} {}.macro\n", combo, file));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learning() {
+        // A remote sending CTRL+C, after ENTER (pressed before) is let go
+        let mut press = Press::default();
+        assert!(!press.feed(28, 0));
+        assert!(!press.feed(46, 1));
+        assert!(!press.feed(29, 1));
+        assert!(!press.feed(46, 2));
+        assert!(!press.feed(46, 0));
+        assert!(press.feed(29, 0));
+        assert_eq!(press.into_keys(), [29, 46]);
+
+        assert_eq!(suggest_name(1, |name| name == "BUTTON_1" || name == "BUTTON_2"), "BUTTON_3");
+
+        let text = "# Pedals\n[names]\n183 = \"PEDAL_LEFT\"\n\n[aliases]\nFOOT = \"PEDAL_LEFT\"\n";
+        let names = [(184, "PEDAL_RIGHT".to_string())];
+        let aliases = [("NEXT".to_string(), "PAGEDOWN".to_string())];
+        let added = add_to_keymap(text, &names, &aliases).unwrap();
+        assert_eq!(
+            added,
+            "# Pedals\n[names]\n183 = \"PEDAL_LEFT\"\n184 = \"PEDAL_RIGHT\"\n\n\
+             [aliases]\nFOOT = \"PEDAL_LEFT\"\nNEXT = \"PAGEDOWN\"\n"
+        );
+        assert_eq!(add_to_keymap("", &names, &[]).unwrap(), "[names]\n184 = \"PEDAL_RIGHT\"\n");
+        let taken = [(185, "PEDAL_LEFT".to_string())];
+        assert!(add_to_keymap(text, &taken, &[]).unwrap_err().contains("Duplicate name 'PEDAL_LEFT'"));
+
+        assert_eq!(bindings_file_name("PCsensor FootSwitch"), "pcsensor-footswitch.conf");
+        assert_eq!(bindings_file_name("Педаль"), FALLBACK_BINDINGS_NAME);
+        assert_eq!(bindings_file_name("Daemon"), FALLBACK_BINDINGS_NAME);
+
+        let snippet = bindings("PCsensor FootSwitch", &["PEDAL_LEFT".to_string(), "CTRL+C".to_string()]);
+        assert!(snippet.starts_with("# Bindings for PCsensor FootSwitch"));
+        assert!(snippet.ends_with("bind PEDAL_LEFT pedal_left.macro\nbind CTRL+C ctrl_c.macro\n"));
+    }
+}
@@ -0,0 +1,92 @@
+//! EvKey - AutoHotkey-style macro recording and playback for Linux
+//!
+//! The `evkey` binary is a thin command-line wrapper around this library.
+//! Record with [`recorder::Recorder`], store macros with [`storage`], and play
+//! them back through a [`player::Player`] on any [`backend::Backend`].
+//!
+//! Everything that touches devices sits behind the default `devices` feature.
+//! Without it the core - [`event`], [`state`], [`storage`], [`keymap`] and the
+//! other format modules - has no evdev dependency and builds for wasm32, so a
+//! web editor can parse, render and edit macro files with the same code.
+
+pub mod accel;
+pub mod action;
+#[cfg(feature = "devices")]
+pub mod audit;
+#[cfg(feature = "devices")]
+pub mod backend;
+pub mod binary;
+pub mod capabilities;
+#[cfg(feature = "devices")]
+pub mod clicker;
+pub mod clips;
+#[cfg(feature = "devices")]
+pub mod clipboard;
+pub mod clock;
+pub mod compare;
+pub mod config;
+#[cfg(feature = "devices")]
+pub mod daemon;
+pub mod easing;
+pub mod edit;
+pub mod event;
+pub mod evtest;
+pub mod export;
+pub mod filter;
+#[cfg(feature = "devices")]
+pub mod forward;
+#[cfg(feature = "devices")]
+pub mod ffi;
+pub mod gesture;
+pub mod guard;
+#[cfg(feature = "devices")]
+pub mod hooks;
+pub mod input_key;
+pub mod journal;
+pub mod keymap;
+pub mod keyset;
+pub mod layout;
+#[cfg(feature = "devices")]
+pub mod learn;
+pub mod locale;
+pub mod locks;
+#[cfg(feature = "devices")]
+pub mod logind;
+pub mod loops;
+pub mod metrics;
+pub mod migrations;
+pub mod mix;
+pub mod nkro;
+#[cfg(feature = "devices")]
+pub mod player;
+#[cfg(feature = "portal")]
+pub mod portal;
+pub mod postprocess;
+pub mod power;
+pub mod preview;
+#[cfg(feature = "devices")]
+pub mod proxy;
+#[cfg(feature = "devices")]
+pub mod recorder;
+pub mod rng;
+#[cfg(feature = "devices")]
+pub mod rpc;
+pub mod screen;
+#[cfg(feature = "devices")]
+pub mod seat;
+pub mod snippet;
+pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod stream;
+pub mod svg;
+pub mod templates;
+pub mod testing;
+pub mod throttle;
+pub mod typing;
+#[cfg(feature = "devices")]
+pub mod watch;
+#[cfg(feature = "wayland")]
+pub mod wayland;
+#[cfg(feature = "x11")]
+pub mod x11;
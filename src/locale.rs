@@ -0,0 +1,98 @@
+//! Key names in the user's language, for display
+//!
+//! `inspect`, `evkey status` and messages show keys like ENTER or PAGEUP by
+//! their usual name in the language of the locale (`LC_ALL`, `LC_MESSAGES`
+//! or `LANG`), e.g. "Entrée" in French. Files always use the English names,
+//! so macros and configs work the same whatever the locale of whoever wrote
+//! them; see [`crate::keymap::display_name`].
+//!
+//! Only keys whose names are words are translated; letters, digits and F
+//! keys stay as they are.
+
+use std::sync::OnceLock;
+
+/// Languages with key names, by their ISO 639-1 code
+pub const LANGUAGES: &[&str] = &["de", "es", "fr"];
+
+/// English key name and its translations, in [`LANGUAGES`] order
+const NAMES: &[(&str, [&str; 3])] = &[
+    ("ESC", ["Esc", "Esc", "Échap"]),
+    ("BACKSPACE", ["Rücktaste", "Retroceso", "Retour arrière"]),
+    ("TAB", ["Tab", "Tab", "Tab"]),
+    ("ENTER", ["Eingabe", "Intro", "Entrée"]),
+    ("CTRL", ["Strg", "Ctrl", "Ctrl"]),
+    ("RIGHTCTRL", ["Strg rechts", "Ctrl der.", "Ctrl droite"]),
+    ("SHIFT", ["Umschalt", "Mayús", "Maj"]),
+    ("RIGHTSHIFT", ["Umschalt rechts", "Mayús der.", "Maj droite"]),
+    ("ALT", ["Alt", "Alt", "Alt"]),
+    ("RIGHTALT", ["Alt Gr", "Alt Gr", "Alt Gr"]),
+    ("META", ["Super", "Super", "Super"]),
+    ("RIGHTMETA", ["Super rechts", "Super der.", "Super droite"]),
+    ("SPACE", ["Leertaste", "Espacio", "Espace"]),
+    ("CAPSLOCK", ["Feststell", "Bloq Mayús", "Verr. Maj"]),
+    ("NUMLOCK", ["Num", "Bloq Num", "Verr. Num"]),
+    ("SCROLLLOCK", ["Rollen", "Bloq Despl", "Arrêt défil"]),
+    ("HOME", ["Pos1", "Inicio", "Début"]),
+    ("END", ["Ende", "Fin", "Fin"]),
+    ("PAGEUP", ["Bild auf", "Re Pág", "Page préc."]),
+    ("PAGEDOWN", ["Bild ab", "Av Pág", "Page suiv."]),
+    ("INSERT", ["Einfg", "Insert", "Inser"]),
+    ("DELETE", ["Entf", "Supr", "Suppr"]),
+    ("UP", ["Hoch", "Arriba", "Haut"]),
+    ("DOWN", ["Runter", "Abajo", "Bas"]),
+    ("LEFT", ["Links", "Izquierda", "Gauche"]),
+    ("RIGHT", ["Rechts", "Derecha", "Droite"]),
+    ("BTN_LEFT", ["Linksklick", "Clic izq.", "Clic gauche"]),
+    ("BTN_RIGHT", ["Rechtsklick", "Clic der.", "Clic droit"]),
+    ("BTN_MIDDLE", ["Mittelklick", "Clic central", "Clic milieu"]),
+];
+
+static LANGUAGE: OnceLock<Option<usize>> = OnceLock::new();
+
+/// Show key names in `language` (e.g. "fr", or "en" for the file names) from
+/// now on, instead of the locale's; returns `false` if names were already
+/// shown in some language
+pub fn set_language(language: &str) -> bool {
+    LANGUAGE.set(language_index(language)).is_ok()
+}
+
+/// Name of `key` (an English key name) in the display language, if it has one
+pub fn translate(key: &str) -> Option<&'static str> {
+    let language = (*LANGUAGE.get_or_init(language_from_env))?;
+    NAMES.iter().find(|(english, _)| *english == key).map(|(_, names)| names[language])
+}
+
+/// Index into [`LANGUAGES`] of a locale like "de_AT.UTF-8"
+fn language_index(locale: &str) -> Option<usize> {
+    let language = locale.split(['_', '.', '@']).next()?;
+    LANGUAGES.iter().position(|&known| known == language)
+}
+
+fn language_from_env() -> Option<usize> {
+    // Tests expect the English names whatever the developer's locale
+    if cfg!(test) {
+        return None;
+    }
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()))
+        .and_then(|locale| language_index(&locale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_language_index() {
+        assert_eq!(language_index("fr_CA.UTF-8"), Some(2));
+        assert_eq!(language_index("de"), Some(0));
+        assert_eq!(language_index("en_US.UTF-8"), None);
+        assert_eq!(language_index("C"), None);
+
+        let french = language_index("fr").unwrap();
+        let enter = NAMES.iter().find(|(english, _)| *english == "ENTER").unwrap();
+        assert_eq!(enter.1[french], "Entrée");
+        assert_eq!(translate("ENTER"), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Lock key (capslock/numlock/scrolllock) state tracking
+//!
+//! Keypad and text macros depend on lock state, so the state at record time is
+//! stored in the macro header and can be restored before playback. LED changes
+//! during a recording are kept as `led` lines, which playback leaves alone.
+
+#[cfg(feature = "devices")]
+use evdev::{Device, LedCode};
+use std::fmt;
+#[cfg(feature = "devices")]
+use std::io;
+
+/// Keycodes of the lock keys that toggle each LED
+pub const KEY_CAPSLOCK: u16 = 58;
+pub const KEY_NUMLOCK: u16 = 69;
+pub const KEY_SCROLLLOCK: u16 = 70;
+
+/// Which lock LEDs are lit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockState {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+impl LockState {
+    /// Read the LED state of a keyboard device
+    #[cfg(feature = "devices")]
+    pub fn from_device(device: &Device) -> io::Result<Self> {
+        let leds = device.get_led_state()?;
+        Ok(Self {
+            caps: leds.contains(LedCode::LED_CAPSL),
+            num: leds.contains(LedCode::LED_NUML),
+            scroll: leds.contains(LedCode::LED_SCROLLL),
+        })
+    }
+
+    /// Parse a header value like "NUMLOCK+CAPSLOCK" or "none"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut state = Self::default();
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("none") {
+            return Ok(state);
+        }
+
+        for name in s.split('+') {
+            match name.trim().to_uppercase().as_str() {
+                "CAPSLOCK" => state.caps = true,
+                "NUMLOCK" => state.num = true,
+                "SCROLLLOCK" => state.scroll = true,
+                other => return Err(format!("Unknown lock key: {}", other)),
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Lock keys that must be tapped to turn `self` into `target`
+    pub fn keys_to_reach(&self, target: &LockState) -> Vec<u16> {
+        let mut keys = Vec::new();
+        if self.caps != target.caps {
+            keys.push(KEY_CAPSLOCK);
+        }
+        if self.num != target.num {
+            keys.push(KEY_NUMLOCK);
+        }
+        if self.scroll != target.scroll {
+            keys.push(KEY_SCROLLLOCK);
+        }
+        keys
+    }
+}
+
+impl fmt::Display for LockState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = Vec::new();
+        if self.caps {
+            names.push("CAPSLOCK");
+        }
+        if self.num {
+            names.push("NUMLOCK");
+        }
+        if self.scroll {
+            names.push("SCROLLLOCK");
+        }
+
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join("+"))
+        }
+    }
+}
+
+/// Read the current lock state from the first keyboard that exposes LEDs
+#[cfg(feature = "devices")]
+pub fn current_lock_state() -> io::Result<Option<LockState>> {
+    for entry in std::fs::read_dir("/dev/input")? {
+        let path = entry?.path();
+        let is_event_node = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("event"));
+        if !is_event_node {
+            continue;
+        }
+
+        if let Ok(device) = Device::open(&path) {
+            let has_lock_leds = device
+                .supported_leds()
+                .is_some_and(|leds| leds.contains(LedCode::LED_NUML));
+            if has_lock_leds {
+                return LockState::from_device(&device).map(Some);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let state = LockState::parse("NUMLOCK+capslock").unwrap();
+        assert!(state.num && state.caps && !state.scroll);
+        assert_eq!(state.to_string(), "CAPSLOCK+NUMLOCK");
+
+        assert_eq!(LockState::parse("none").unwrap(), LockState::default());
+        assert!(LockState::parse("SHIFT").is_err());
+    }
+
+    #[test]
+    fn test_keys_to_reach() {
+        let current = LockState::default();
+        let target = LockState {
+            num: true,
+            ..Default::default()
+        };
+
+        assert_eq!(current.keys_to_reach(&target), vec![KEY_NUMLOCK]);
+        assert!(target.keys_to_reach(&target).is_empty());
+    }
+}
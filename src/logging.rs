@@ -0,0 +1,55 @@
+//! Log output setup
+//!
+//! Logs go to stderr. Verbosity flags raise EvKey's own level (`-v` for
+//! per-event debug logs with timing, `-vv` for everything); `RUST_LOG`
+//! overrides the filter entirely. `--log-json` emits one JSON object per
+//! line for machine consumption.
+
+use tracing_subscriber::EnvFilter;
+
+/// Install the global subscriber
+pub fn init(verbosity: u8, json: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter(verbosity)));
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false);
+
+    if json {
+        builder.json().init();
+    } else if verbosity == 0 {
+        // Plain messages for normal CLI use
+        builder.without_time().init();
+    } else {
+        builder.init();
+    }
+}
+
+/// Filter used without `RUST_LOG`: warnings from dependencies, and EvKey's
+/// own logs down to the level `verbosity` asks for
+fn default_filter(verbosity: u8) -> String {
+    let level = match verbosity {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    format!("warn,evkey={}", level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_filter() {
+        assert_eq!(default_filter(0), "warn,evkey=info");
+        assert_eq!(default_filter(1), "warn,evkey=debug");
+        assert_eq!(default_filter(2), "warn,evkey=trace");
+        assert_eq!(default_filter(5), "warn,evkey=trace");
+        // Each parses as a filter
+        for verbosity in 0..3 {
+            assert!(EnvFilter::try_new(default_filter(verbosity)).is_ok());
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Login sessions, for the system-wide daemon
+//!
+//! `evkey daemon --system` runs once for the whole machine, as root from a
+//! system service, instead of once per user. Users opt their login session in
+//! over the control socket (`evkey session start`), and the daemon only
+//! plays into a seat while the session logind has active on it is one that
+//! opted in: another user switched to, the greeter or a session that never
+//! asked gets nothing.
+//!
+//! Sessions and seats are read from the state logind keeps in
+//! [`SESSIONS_DIR`] and [`crate::seat::SEATS_DIR`], and the session a client
+//! belongs to from its process's cgroup (`.../session-3.scope`), with the
+//! process and user taken from the socket's peer credentials.
+
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::seat;
+
+/// One entry per session, maintained by logind
+pub const SESSIONS_DIR: &str = "/run/systemd/sessions";
+
+/// A logind session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// Session id, e.g. "3" or "c1"
+    pub id: String,
+    pub uid: u32,
+    pub user: String,
+    /// Seat the session is on; `None` for remote and background sessions
+    pub seat: Option<String>,
+}
+
+/// Values of a logind state file's `KEY=value` lines
+fn field<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    text.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .filter(|value| !value.is_empty())
+}
+
+/// A session from its id and the text of its state file
+fn parse_session(id: &str, text: &str) -> Option<Session> {
+    Some(Session {
+        id: id.to_string(),
+        uid: field(text, "UID")?.parse().ok()?,
+        user: field(text, "USER").unwrap_or_default().to_string(),
+        seat: field(text, "SEAT").map(str::to_string),
+    })
+}
+
+/// The session with id `id`
+pub fn session(id: &str) -> io::Result<Session> {
+    let text = fs::read_to_string(Path::new(SESSIONS_DIR).join(id))?;
+    parse_session(id, &text)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Can't read logind session {}", id)))
+}
+
+/// The session logind has active on each seat, as `(seat, session id)`
+pub fn active_sessions() -> io::Result<Vec<(String, String)>> {
+    let mut active = Vec::new();
+    for seat in seat::seats()? {
+        let text = fs::read_to_string(Path::new(seat::SEATS_DIR).join(&seat)).unwrap_or_default();
+        if let Some(session) = field(&text, "ACTIVE") {
+            active.push((seat, session.to_string()));
+        }
+    }
+    Ok(active)
+}
+
+/// The session id in a `/proc/<pid>/cgroup`, from its `session-<id>.scope`
+fn parse_cgroup(text: &str) -> Option<String> {
+    text.lines()
+        .flat_map(|line| line.split('/'))
+        .find_map(|part| part.strip_prefix("session-")?.strip_suffix(".scope"))
+        .map(str::to_string)
+}
+
+/// The session process `pid` runs in, if any
+pub fn session_of(pid: u32) -> io::Result<Option<String>> {
+    Ok(parse_cgroup(&fs::read_to_string(format!("/proc/{}/cgroup", pid))?))
+}
+
+/// Process and user id at the other end of `stream`
+pub fn peer(stream: &UnixStream) -> io::Result<(u32, u32)> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // Safety: `cred` and `len` are valid for writes, and `len` is the size of
+    // `cred`
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((cred.pid as u32, cred.uid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_logind_state() {
+        let text = "# This is private data. Do not parse.\nUID=1000\nUSER=alex\nACTIVE=1\nTYPE=wayland\nSEAT=seat0\nVTNR=2\n";
+        let session = parse_session("3", text).unwrap();
+        assert_eq!(session.uid, 1000);
+        assert_eq!(session.user, "alex");
+        assert_eq!(session.seat.as_deref(), Some("seat0"));
+        // SSH sessions have no seat
+        assert_eq!(parse_session("c1", "UID=1001\nUSER=kim\nSEAT=\n").unwrap().seat, None);
+        assert_eq!(parse_session("4", "USER=nobody\n"), None);
+        assert_eq!(field("ACTIVE=3\nACTIVE_UID=1000\n", "ACTIVE"), Some("3"));
+
+        let cgroup = "0::/user.slice/user-1000.slice/session-3.scope\n";
+        assert_eq!(parse_cgroup(cgroup).as_deref(), Some("3"));
+        assert_eq!(parse_cgroup("0::/user.slice/user-1000.slice/user@1000.service/app.slice\n"), None);
+
+        let (ours, _) = UnixStream::pair().unwrap();
+        let (pid, uid) = peer(&ours).unwrap();
+        assert_eq!(pid, std::process::id());
+        assert_eq!(uid, unsafe { libc::getuid() });
+    }
+}
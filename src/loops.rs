@@ -0,0 +1,98 @@
+//! Finding repeated stretches of a recording
+//!
+//! Recordings of repetitive work (the same crafting cycle done forty times)
+//! are mostly copies of one stretch of states. [`find`] looks for a stretch
+//! followed straight away by more copies of itself, so the macro can write it
+//! once in a `repeat N` block (see [`crate::storage::Repeat`]) and be edited
+//! as "do this N times".
+//!
+//! No two recorded copies are quite the same, so states count as copies of
+//! each other when they hold the same keys and scroll the same way, their
+//! durations are within a tolerance and the pointer moves within
+//! [`MOTION_TOLERANCE`] of the same amount.
+
+use crate::state::MacroState;
+
+/// Timing difference allowed between copies by default (in milliseconds)
+pub const DEFAULT_TOLERANCE_MS: u64 = 20;
+
+/// Pointer movement difference allowed between copies (per axis)
+pub const MOTION_TOLERANCE: i32 = 5;
+
+/// Longest stretch looked for (in states)
+pub const MAX_LEN: usize = 256;
+
+/// Fewest states folding a stretch has to save for it to be reported
+pub const MIN_SAVED: usize = 6;
+
+/// Whether `a` and `b` are recordings of the same thing
+pub fn similar(a: &MacroState, b: &MacroState, tolerance_ms: u64) -> bool {
+    a.keys_pressed == b.keys_pressed
+        && a.scroll_delta == b.scroll_delta
+        && a.easing == b.easing
+        && a.duration_ms.abs_diff(b.duration_ms) <= tolerance_ms
+        && (a.mouse_delta.0 - b.mouse_delta.0).abs() <= MOTION_TOLERANCE
+        && (a.mouse_delta.1 - b.mouse_delta.1).abs() <= MOTION_TOLERANCE
+}
+
+/// Repeated stretches among `count` states, as `(start, len, times)`
+///
+/// `same(a, b)` says whether the state at `b` is a copy of the one at `a`
+/// (`a < b`); later copies are compared with the first, so they can't drift
+/// away from it a little at a time. Scanning from the start, the stretch
+/// saving the most states wins and the scan carries on after its last copy.
+pub fn find(count: usize, same: impl Fn(usize, usize) -> bool) -> Vec<(usize, usize, usize)> {
+    let mut found = Vec::new();
+    let mut start = 0;
+
+    while start < count {
+        // (states saved, len, times)
+        let mut best = (0, 0, 0);
+        for len in 1..=MAX_LEN.min((count - start) / 2) {
+            let mut times = 1;
+            while start + (times + 1) * len <= count && (0..len).all(|i| same(start + i, start + times * len + i)) {
+                times += 1;
+            }
+            let saved = len * (times - 1);
+            if times > 1 && saved > best.0 {
+                best = (saved, len, times);
+            }
+        }
+
+        let (saved, len, times) = best;
+        if saved >= MIN_SAVED {
+            found.push((start, len, times));
+            start += len * times;
+        } else {
+            start += 1;
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        // An intro, then "abc" four times, then an outro
+        let states: Vec<char> = "xyabcabcabcabcz".chars().collect();
+        assert_eq!(find(states.len(), |a, b| states[a] == states[b]), [(2, 3, 4)]);
+
+        // Saving fewer than MIN_SAVED states isn't worth a block
+        let states: Vec<char> = "abcabcz".chars().collect();
+        assert!(find(states.len(), |a, b| states[a] == states[b]).is_empty());
+
+        let mut a = MacroState::new(100);
+        a.keys_pressed.insert(30);
+        a.mouse_delta = (40, 0);
+        let mut b = a.clone();
+        b.duration_ms = 100 + DEFAULT_TOLERANCE_MS;
+        b.mouse_delta = (40 + MOTION_TOLERANCE, -MOTION_TOLERANCE);
+        assert!(similar(&a, &b, DEFAULT_TOLERANCE_MS));
+        b.keys_pressed.insert(29);
+        assert!(!similar(&a, &b, DEFAULT_TOLERANCE_MS));
+    }
+}
@@ -1,20 +1,68 @@
 use std::env;
 use std::error::Error;
-use std::path::Path;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-mod recorder;
-mod player;
-mod storage;
-mod state;
-mod keymap;
+mod logging;
+mod progress_bar;
 
-use recorder::Recorder;
-use player::Player;
+use evkey::hooks::{HookEvent, Hooks};
+use evkey::action::Action;
+use evkey::capabilities::Capabilities;
+use evkey::player::{self, Player};
+use progress_bar::ProgressBar;
+use evkey::recorder::{self, Recorder};
+use evkey::event::{EventType, InputEvent, RecordedEvent};
+use evkey::daemon::{self, Daemon};
+use evkey::edit::Editor;
+use evkey::rng::Rng;
+use evkey::{accel, audit, backend, binary, easing, clicker, clips, compare, config, proxy, evtest, export, filter, forward, keymap, keyset, layout, learn, locks, loops, migrations, mix, nkro, postprocess, rpc, screen, seat, state, stats, storage, stream, svg, templates, throttle, guard, journal};
+use tracing::{info, warn};
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // Logging flags are accepted anywhere on the command line
+    let verbosity = args
+        .iter()
+        .map(|a| match a.as_str() {
+            "-v" | "--verbose" => 1,
+            "-vv" => 2,
+            _ => 0,
+        })
+        .sum();
+    let log_json = args.iter().any(|a| a == "--log-json");
+    args.retain(|a| !matches!(a.as_str(), "-v" | "-vv" | "--verbose" | "--log-json"));
+    logging::init(verbosity, log_json);
+
+    // So is a custom keymap, which has to be in place before anything is parsed
+    let keymap_file = args.iter().position(|a| a == "--keymap").map(|i| {
+        let file = args.get(i + 1).cloned();
+        args.drain(i..(i + 2).min(args.len()));
+        file
+    });
+    let keymap_path = match keymap_file {
+        Some(Some(file)) => {
+            load_keymap(Path::new(&file))?;
+            Some(PathBuf::from(file))
+        }
+        Some(None) => return Err("--keymap needs a file".into()),
+        None => {
+            // keymap.toml, or else keymap.json next to it
+            let path = keymap::config_path().map(|path| match path.with_extension("json") {
+                json if !path.exists() && json.exists() => json,
+                _ => path,
+            });
+            if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+                load_keymap(path)?;
+            }
+            path
+        }
+    };
 
     if args.len() < 2 {
         print_usage();
@@ -23,38 +71,481 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     match args[1].as_str() {
         "record" => {
-            if args.len() < 3 {
-                eprintln!("Usage: evkey record <output_file>");
+            let value_options = ["--max-idle", "--device", "--debounce", "--ignore-key", "-o", "--output"];
+            let positional = positional_args(&args[2..], &value_options);
+            // A single-dash option we don't know would otherwise become the file name
+            if let Some(option) = positional.iter().find(|arg| arg.starts_with('-') && **arg != "-") {
+                return Err(format!("Unknown option '{}'", option).into());
+            }
+            let output_file = option_value(&args, "-o").or_else(|| option_value(&args, "--output"));
+            let Some(output_file) = output_file.or(positional.first().copied()) else {
+                eprintln!("Usage: evkey record [--max-idle <duration>] [--idle-marker] [--tracks] [--click-positions] [--compress] [--review] [--debounce <duration>] [--ignore-key <key>]... [--device <path>]... [-o] <output_file|->");
                 return Ok(());
+            };
+            let devices = option_values(&args, "--device");
+            let filter = filter::EventFilter {
+                min_press: Duration::from_millis(option_value(&args, "--debounce").map(storage::parse_duration).transpose()?.unwrap_or(0)),
+                phantom_keys: option_values(&args, "--ignore-key")
+                    .into_iter()
+                    .map(|key| keymap::name_to_keycode(key).ok_or_else(|| format!("Unknown key '{}'", key)))
+                    .collect::<Result<_, _>>()?,
+            };
+            if output_file == "-" {
+                return stream_recording(&devices, filter);
             }
-            record_macro(&args[2])?;
+            let options = RecordOptions {
+                max_idle_ms: option_value(&args, "--max-idle")
+                    .map(storage::parse_duration)
+                    .transpose()?,
+                idle_marker: args.iter().any(|a| a == "--idle-marker"),
+                tracks: args.iter().any(|a| a == "--tracks"),
+                click_positions: args.iter().any(|a| a == "--click-positions"),
+                review: args.iter().any(|a| a == "--review"),
+                encoding: if args.iter().any(|a| a == "--compress") {
+                    binary::Encoding::smallest()
+                } else {
+                    binary::Encoding::Plain
+                },
+                filter,
+            };
+            record_macro(output_file, &devices, options)?;
         }
         "play" => {
-            if args.len() < 3 {
-                eprintln!("Usage: evkey play [--loop] <input_file>");
-                return Ok(());
-            }
+            let positional = positional_args(&args[2..], PLAY_VALUE_OPTIONS);
 
-            // Find the input file (first arg that isn't --loop)
-            let input_file = args[2..]
-                .iter()
-                .find(|arg| arg.as_str() != "--loop")
-                .map(|s| s.as_str());
-
-            let loop_flag = args.iter().any(|a| a == "--loop");
+            match positional.first() {
+                Some(file) => {
+                    // Options from the file's header fill in what the command
+                    // line leaves out
+                    let args: Vec<String> = if positional.len() == 1
+                        && !args.iter().any(|a| a == "--at" || a == "--no-file-options")
+                        && *file != "-"
+                        && Path::new(file).exists()
+                    {
+                        let defaults = file_play_options(&storage::load_metadata(file)?.play, &args)?;
+                        if !defaults.is_empty() {
+                            println!("Options from {}: {}", file, defaults.join(" "));
+                        }
+                        args.iter().cloned().chain(defaults).collect()
+                    } else {
+                        args.clone()
+                    };
+                    let options = PlayOptions {
+                        loop_forever: args.iter().any(|a| a == "--loop"),
+                        sync_locks: args.iter().any(|a| a == "--sync-locks"),
+                        match_layout: args.iter().any(|a| a == "--match-layout"),
+                        from: option_value(&args, "--from").map(String::from),
+                        to: option_value(&args, "--to").map(String::from),
+                        tracks: option_list(&args, "--tracks"),
+                        skip_tracks: option_list(&args, "--skip-tracks"),
+                        easing: option_value(&args, "--easing").map(str::parse).transpose()?,
+                        scroll_rate_hz: option_value(&args, "--scroll-rate")
+                            .map(|rate| state::parse_hz(rate).ok_or_else(|| format!("Invalid scroll rate '{}', expected e.g. 60Hz", rate)))
+                            .transpose()?,
+                        scroll_hires: args.iter().any(|a| a == "--scroll-hires"),
+                        split_devices: args.iter().any(|a| a == "--split-devices"),
+                        accel_compensate: args.iter().any(|a| a == "--accel-compensate"),
+                        scale_to_screen: args.iter().any(|a| a == "--scale-to-screen"),
+                        params: option_values(&args, "--param")
+                            .into_iter()
+                            .map(|param| {
+                                param
+                                    .split_once('=')
+                                    .map(|(name, value)| (name.trim().to_string(), value.to_string()))
+                                    .ok_or_else(|| format!("Expected --param NAME=VALUE, got '{}'", param))
+                            })
+                            .collect::<Result<_, _>>()?,
+                        min_hold_ms: option_value(&args, "--min-hold")
+                            .map(storage::parse_duration)
+                            .transpose()?,
+                        speed: option_value(&args, "--speed").map(parse_speed).transpose()?.unwrap_or(1.0),
+                        repeat: option_value(&args, "--repeat").map(parse_repeat).transpose()?.unwrap_or(1),
+                        humanize_ms: option_value(&args, "--humanize")
+                            .map(storage::parse_duration)
+                            .transpose()?,
+                        seed: option_value(&args, "--seed")
+                            .map(|seed| seed.parse().map_err(|_| format!("Invalid seed '{}'", seed)))
+                            .transpose()?,
+                        pre_roll: option_value(&args, "--pre-roll")
+                            .map(storage::parse_duration)
+                            .transpose()?
+                            .map_or(player::DEFAULT_PRE_ROLL, Duration::from_millis),
+                        backend: option_value(&args, "--backend")
+                            .unwrap_or("auto")
+                            .parse()?,
+                        seat: option_value(&args, "--seat").map(String::from),
+                        timing_report: if args.iter().any(|a| a == "--timing-report") {
+                            Some(option_value(&args, "--tolerance").map(storage::parse_duration).transpose()?.unwrap_or(2) * 1000)
+                        } else {
+                            None
+                        },
+                        hooks: Hooks {
+                            notify: args.iter().any(|a| a == "--notify"),
+                            on_start: option_value(&args, "--on-start").map(String::from),
+                            on_finish: option_value(&args, "--on-finish").map(String::from),
+                            on_abort: option_value(&args, "--on-abort").map(String::from),
+                            on_error: option_value(&args, "--on-error").map(String::from),
+                        },
+                        stop: Arc::new(AtomicBool::new(false)),
+                        progress: !args.iter().any(|a| a == "--no-progress") && io::stderr().is_terminal(),
+                        max_rate: option_value(&args, "--max-rate")
+                            .map(|rate| {
+                                let burst = option_value(&args, "--burst")
+                                    .map(|burst| burst.parse().map_err(|_| format!("Invalid burst '{}', expected a number of events", burst)))
+                                    .transpose()?
+                                    .unwrap_or(throttle::DEFAULT_BURST);
+                                throttle::EventRate::parse(rate, burst)
+                            })
+                            .transpose()?,
+                        recover: option_value(&args, "--recover").map(str::parse).transpose()?.unwrap_or_default(),
+                        limits: guard::Limits {
+                            max_keys: option_value(&args, "--max-keys").map(|n| parse_limit(n, "key count")).transpose()?,
+                            max_text: option_value(&args, "--max-text").map(|n| parse_limit(n, "character count")).transpose()?,
+                        },
+                        force: args.iter().any(|a| a == "--force"),
+                        verify: if args.iter().any(|a| a == "--verify") {
+                            Some(option_value(&args, "--tolerance").map(storage::parse_duration).transpose()?.unwrap_or(10) * 1000)
+                        } else {
+                            None
+                        },
+                    };
+                    if options.verify.is_some() && (options.loop_forever || options.repeat > 1) {
+                        return Err("--verify plays the macro once, it can't be used with --loop or --repeat".into());
+                    }
 
-            match input_file {
-                Some(file) => play_macro(file, loop_flag)?,
+                    // Several files, or any --at, mix the macros into one playback
+                    let mix = positional.len() > 1 || args.iter().any(|a| a == "--at");
+                    let name = positional.join(" + ");
+                    let signal = options.hooks.watch_for_abort(&options.stop)?;
+                    let result = if mix {
+                        play_mix(&mix_files(&args[2..])?, &options)
+                    } else {
+                        play_macro(file, &options)
+                    };
+                    // Interrupted: the player has let go of its keys by now
+                    let signal = signal.load(Ordering::Relaxed);
+                    if signal != 0 {
+                        options.hooks.fire(HookEvent::Abort, &name, None);
+                        std::process::exit(128 + signal as i32);
+                    }
+                    match result {
+                        Ok(()) => options.hooks.fire(HookEvent::Finish, &name, None),
+                        Err(e) => {
+                            options.hooks.fire(HookEvent::Error, &name, Some(&e.to_string()));
+                            return Err(e);
+                        }
+                    }
+                }
                 None => {
                     eprintln!("Error: No input file specified");
-                    eprintln!("Usage: evkey play [--loop] <input_file>");
+                    eprintln!("Usage: evkey play [options] <input_file>");
                     return Ok(());
                 }
             }
         }
+        "compress" => {
+            let (Some(input), Some(output)) = (args.get(2), args.get(3)) else {
+                eprintln!("Usage: evkey compress <input_file> <output.{}>", binary::EXTENSION);
+                return Ok(());
+            };
+            compress_macro(input, output)?;
+        }
+        "seat-rule" => {
+            let Some(seat) = args.get(2) else {
+                eprintln!("Usage: evkey seat-rule <seat>");
+                return Ok(());
+            };
+            if seat::install_rule(seat)? {
+                println!("Added to {}: {}", seat::RULES_PATH, seat::udev_rule(seat));
+            } else {
+                println!("{} already has the rule for {}", seat::RULES_PATH, seat);
+            }
+        }
+        "inspect" => {
+            if args.len() < 3 {
+                eprintln!("Usage: evkey inspect <input_file>");
+                return Ok(());
+            }
+            inspect_macro(&args[2])?;
+        }
+        "cap-idle" => {
+            let positional = positional_args(&args[2..], &["--max"]);
+            let max = option_value(&args, "--max").map(storage::parse_duration).transpose()?;
+            let (Some(input), Some(output), Some(max_ms)) = (positional.first(), positional.get(1), max) else {
+                eprintln!("Usage: evkey cap-idle <input_file> <output_file> --max <duration> [--marker]");
+                return Ok(());
+            };
+            cap_idle_file(input, output, max_ms, args.iter().any(|a| a == "--marker"))?;
+        }
+        "click" => {
+            let options = clicker::ClickOptions {
+                button: clicker::parse_button(option_value(&args, "--button").unwrap_or("left"))?,
+                interval_ms: storage::parse_duration(option_value(&args, "--interval").unwrap_or("100ms"))?,
+                jitter_ms: option_value(&args, "--jitter").map(storage::parse_duration).transpose()?.unwrap_or(0),
+                while_held: option_value(&args, "--while-held")
+                    .map(|key| keymap::name_to_keycode(key).ok_or_else(|| format!("Unknown key '{}'", key)))
+                    .transpose()?,
+            };
+            let seed = option_value(&args, "--seed")
+                .map(|seed| seed.parse().map_err(|_| format!("Invalid seed '{}'", seed)))
+                .transpose()?;
+            let backend = option_value(&args, "--backend").unwrap_or("auto").parse()?;
+            autoclick(&options, seed, backend)?;
+        }
+        "compare" => {
+            let positional = positional_args(&args[2..], &["--device", "--tolerance", "--backend"]);
+            let Some(file) = positional.first() else {
+                eprintln!("Usage: evkey compare <input_file> [--device <path>]... [--tolerance <duration>]");
+                return Ok(());
+            };
+            let tolerance_ms = option_value(&args, "--tolerance").map(storage::parse_duration).transpose()?.unwrap_or(10);
+            let backend = option_value(&args, "--backend").unwrap_or("auto").parse()?;
+            compare_playback(file, &option_values(&args, "--device"), tolerance_ms, backend)?;
+        }
+        "nkro-test" => {
+            let max_keys = match option_value(&args, "--max") {
+                Some(max) => max
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|max| (1..=nkro::KEYS.len()).contains(max))
+                    .ok_or_else(|| format!("--max takes a number of keys from 1 to {}", nkro::KEYS.len()))?,
+                None => nkro::KEYS.len(),
+            };
+            let hold_ms = option_value(&args, "--hold").map(storage::parse_duration).transpose()?.unwrap_or(50);
+            nkro_test(&option_values(&args, "--device"), max_keys, Duration::from_millis(hold_ms))?;
+        }
+        "proxy" => {
+            let positional = positional_args(&args[2..], &["--report-every"]);
+            let Some(device) = positional.first() else {
+                eprintln!("Usage: evkey proxy <device> [--report-every <duration>]");
+                return Ok(());
+            };
+            let report_every_ms = option_value(&args, "--report-every").map(storage::parse_duration).transpose()?.unwrap_or(10_000);
+            run_proxy(device, Duration::from_millis(report_every_ms))?;
+        }
+        "forward" => {
+            let secret_file = option_value(&args, "--secret-file");
+            let (Some(address), Some(secret_file)) = (option_value(&args, "--to"), secret_file) else {
+                eprintln!(
+                    "Usage: evkey forward --to <host[:port]> --secret-file <file> [--device <path>]... [--toggle <combo>]"
+                );
+                return Ok(());
+            };
+            let secret = forward::read_secret(Path::new(secret_file))?;
+            let toggle = config::parse_combo(option_value(&args, "--toggle").unwrap_or(forward::DEFAULT_TOGGLE))?;
+            run_forward(address, &secret, &option_values(&args, "--device"), toggle)?;
+        }
+        "receive" => {
+            let Some(secret_file) = option_value(&args, "--secret-file") else {
+                eprintln!("Usage: evkey receive --secret-file <file> [--listen <address[:port]>] [--backend <name>]");
+                return Ok(());
+            };
+            let secret = forward::read_secret(Path::new(secret_file))?;
+            let address = option_value(&args, "--listen")
+                .map(forward::with_default_port)
+                .unwrap_or_else(|| format!("127.0.0.1:{}", forward::DEFAULT_PORT));
+            let backend = option_value(&args, "--backend").unwrap_or("auto").parse()?;
+            run_receive(&address, &secret, backend)?;
+        }
+        "new" => {
+            let positional = positional_args(&args[2..], &["--template"]);
+            let (Some(template), Some(output)) = (option_value(&args, "--template"), positional.first()) else {
+                eprintln!("Usage: evkey new --template <{}> <output_file>", templates::NAMES.join("|"));
+                return Ok(());
+            };
+            new_from_template(template, output)?;
+        }
+        "anchor-clicks" => {
+            if args.len() < 4 {
+                eprintln!("Usage: evkey anchor-clicks <input_file> <output_file>");
+                return Ok(());
+            }
+            anchor_clicks_file(&args[2], &args[3])?;
+        }
+        "find-loops" => {
+            let positional = positional_args(&args[2..], &["--tolerance"]);
+            let Some(input_file) = positional.first() else {
+                eprintln!("Usage: evkey find-loops <input_file> [output_file] [--tolerance <duration>]");
+                return Ok(());
+            };
+            let tolerance_ms = option_value(&args, "--tolerance")
+                .map(storage::parse_duration)
+                .transpose()?
+                .unwrap_or(loops::DEFAULT_TOLERANCE_MS);
+            find_loops_file(input_file, positional.get(1).copied(), tolerance_ms)?;
+        }
+        "text" => {
+            let positional = positional_args(&args[2..], &["--output"]);
+            let Some(input_file) = positional.first() else {
+                eprintln!("Usage: evkey text <input_file> [--output <file.txt>]");
+                return Ok(());
+            };
+            show_typed_text(input_file, option_value(&args, "--output"))?;
+        }
+        "type-text" => {
+            let positional = positional_args(&args[2..], &["--min-chars"]);
+            if positional.len() < 2 {
+                eprintln!("Usage: evkey type-text <input_file> <output_file> [--min-chars N]");
+                return Ok(());
+            }
+            let min_chars = option_value(&args, "--min-chars")
+                .map(|n| n.parse().map_err(|_| format!("Invalid --min-chars '{}'", n)))
+                .transpose()?
+                .unwrap_or(DEFAULT_MIN_CHARS);
+            type_text_file(positional[0], positional[1], min_chars)?;
+        }
+        "trim" => {
+            let positional = positional_args(&args[2..], &["--from", "--to"]);
+            if positional.len() < 2 {
+                eprintln!("Usage: evkey trim <input_file> <output_file> [--from MARKER] [--to MARKER]");
+                return Ok(());
+            }
+            trim_macro(
+                positional[0],
+                positional[1],
+                option_value(&args, "--from"),
+                option_value(&args, "--to"),
+            )?;
+        }
+        "save-clip" => {
+            let positional = positional_args(&args[2..], &["--from", "--to"]);
+            if positional.len() < 2 {
+                eprintln!("Usage: evkey save-clip <input_file> <name> [--from MARKER] [--to MARKER]");
+                return Ok(());
+            }
+            save_clip(positional[0], positional[1], option_value(&args, "--from"), option_value(&args, "--to"))?;
+        }
+        "insert-clip" => {
+            let positional = positional_args(&args[2..], &["--at"]);
+            if positional.len() < 3 {
+                eprintln!("Usage: evkey insert-clip <name> <input_file> <output_file> [--at MARKER]");
+                return Ok(());
+            }
+            insert_clip(positional[0], positional[1], positional[2], option_value(&args, "--at"))?;
+        }
+        "clips" => {
+            let names = clips::list()?;
+            if names.is_empty() {
+                println!("No clips yet (save one with `evkey save-clip`)");
+            }
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        "stats" => {
+            let positional = positional_args(&args[2..], &["--heatmap"]);
+            let Some(input_file) = positional.first() else {
+                eprintln!("Usage: evkey stats <input_file> [--heatmap <output.svg>]");
+                return Ok(());
+            };
+            show_stats(input_file, option_value(&args, "--heatmap"))?;
+        }
+        "plot-mouse" => {
+            if args.len() < 4 {
+                eprintln!("Usage: evkey plot-mouse <input_file> <output.svg>");
+                return Ok(());
+            }
+            plot_mouse(&args[2], &args[3])?;
+        }
+        "export" => {
+            let positional = positional_args(&args[2..], &["--format", "--keycode"]);
+            let (Some(input_file), Some(output_file)) = (positional.first(), positional.get(1)) else {
+                eprintln!("Usage: evkey export <input_file> <output_file> [--format c|qmk|via] [--keycode <name>]");
+                return Ok(());
+            };
+            match option_value(&args, "--format").unwrap_or("c") {
+                "c" => export_macro(input_file, output_file)?,
+                format @ ("qmk" | "via") => {
+                    let keycode = option_value(&args, "--keycode").unwrap_or("EVKEY_MACRO");
+                    export_firmware(input_file, output_file, format, keycode)?;
+                }
+                other => return Err(format!("Unknown export format '{}' (c, qmk or via)", other).into()),
+            }
+        }
+        "recover" => {
+            let Some(journal_file) = args.get(2) else {
+                eprintln!("Usage: evkey recover <journal> [output_file]");
+                return Ok(());
+            };
+            recover_journal(journal_file, args.get(3).map(String::as_str))?;
+        }
+        "import" => {
+            if args.len() < 4 {
+                eprintln!("Usage: evkey import <dump.txt> <output_file>");
+                return Ok(());
+            }
+            import_dump(&args[2], &args[3])?;
+        }
+        "daemon" => {
+            let system = args.iter().any(|a| a == "--system");
+            let path = match option_value(&args, "--config") {
+                Some(path) => std::path::PathBuf::from(path),
+                None if system => std::path::PathBuf::from(config::SYSTEM_PATH),
+                None => config::default_path().ok_or("Can't find the config directory (set HOME or XDG_CONFIG_HOME)")?,
+            };
+            if system {
+                Daemon::system(&path)?.run()?;
+            } else {
+                Daemon::new(&path)?.run()?;
+            }
+        }
+        "session" => {
+            let command = match (args.get(2).map(String::as_str), option_value(&args, "--profile")) {
+                (Some("start"), Some(profile)) => format!("session start {}", profile),
+                (Some(command), _) => format!("session {}", command),
+                (None, _) => "session".to_string(),
+            };
+            let socket = std::path::Path::new(daemon::SYSTEM_SOCKET_PATH);
+            print!("{}", daemon::request_at(socket, &command)?);
+        }
+        "status" => {
+            show_status(args.iter().any(|a| a == "--watch"))?;
+        }
+        "stop-all" | "resume" => {
+            print!("{}", daemon::request(&args[1])?);
+        }
+        "profile" => {
+            let command = match args.get(2) {
+                Some(name) => format!("profile {}", name),
+                None => "profile".to_string(),
+            };
+            print!("{}", daemon::request(&command)?);
+        }
+        "calibrate-accel" => {
+            let backend = option_value(&args, "--backend").unwrap_or("auto").parse()?;
+            calibrate_accel(backend)?;
+        }
+        "upgrade-file" => {
+            if args.len() < 3 {
+                eprintln!("Usage: evkey upgrade-file <file>");
+                return Ok(());
+            }
+            upgrade_file(&args[2])?;
+        }
         "list-devices" => {
             list_devices()?;
         }
+        "learn-device" => {
+            let positional = positional_args(&args[2..], &["--bindings"]);
+            let keymap_path = keymap_path.ok_or("Can't find the config directory (set HOME or XDG_CONFIG_HOME)")?;
+            let force = args.iter().any(|a| a == "--force");
+            learn_device(positional.first().copied(), &keymap_path, option_value(&args, "--bindings"), force)?;
+        }
+        "rpc" => {
+            rpc::serve(io::stdin().lock(), io::stdout())?;
+        }
+        "audit" => match args.get(2).map(String::as_str) {
+            Some("on") => println!("Logging injected input to {}", audit::enable()?.display()),
+            Some("show") => match audit::path().filter(|path| path.exists()) {
+                Some(path) => print!("{}", std::fs::read_to_string(path)?),
+                None => println!("Auditing is off (turn it on with `evkey audit on`)"),
+            },
+            Some("clear") => {
+                audit::clear()?;
+                println!("Audit log cleared");
+            }
+            _ => eprintln!("Usage: evkey audit <on|show|clear>"),
+        },
         _ => {
             print_usage();
         }
@@ -63,13 +554,292 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// `evkey play` options that take a value
+const PLAY_VALUE_OPTIONS: &[&str] = &[
+    "--at",
+    "--from",
+    "--to",
+    "--tracks",
+    "--skip-tracks",
+    "--min-hold",
+    "--speed",
+    "--repeat",
+    "--humanize",
+    "--seed",
+    "--pre-roll",
+    "--easing",
+    "--scroll-rate",
+    "--backend",
+    "--on-start",
+    "--on-finish",
+    "--on-abort",
+    "--on-error",
+    "--param",
+    "--seat",
+    "--tolerance",
+    "--max-rate",
+    "--burst",
+    "--recover",
+    "--max-keys",
+    "--max-text",
+];
+
+/// Switches a macro's `# Play:` header may turn on
+const FILE_PLAY_FLAGS: &[&str] = &[
+    "--loop",
+    "--sync-locks",
+    "--match-layout",
+    "--scroll-hires",
+    "--split-devices",
+    "--accel-compensate",
+    "--scale-to-screen",
+];
+
+/// Options with a value a macro's `# Play:` header may set
+///
+/// Hooks and the seat are up to whoever plays the macro, not the file:
+/// someone else's macro shouldn't get to run commands.
+const FILE_PLAY_VALUE_OPTIONS: &[&str] = &[
+    "--from",
+    "--to",
+    "--tracks",
+    "--skip-tracks",
+    "--min-hold",
+    "--speed",
+    "--repeat",
+    "--humanize",
+    "--seed",
+    "--pre-roll",
+    "--scroll-rate",
+    "--backend",
+    "--max-rate",
+    "--burst",
+    "--recover",
+];
+
+/// The options in a macro's `# Play:` header that `args` doesn't give
+///
+/// A header switch is left out by its `--no-` form too, e.g. `--no-loop`.
+fn file_play_options(play: &[String], args: &[String]) -> Result<Vec<String>, String> {
+    let mut options = Vec::new();
+    let mut play = play.iter();
+    while let Some(option) = play.next() {
+        let value = if FILE_PLAY_VALUE_OPTIONS.contains(&option.as_str()) {
+            Some(play.next().ok_or_else(|| format!("{} in the Play header needs a value", option))?)
+        } else if FILE_PLAY_FLAGS.contains(&option.as_str()) {
+            None
+        } else {
+            return Err(format!("'{}' can't be set in a macro's Play header", option));
+        };
+        let negated = format!("--no-{}", &option[2..]);
+        if !args.contains(option) && !args.contains(&negated) {
+            options.push(option.clone());
+            options.extend(value.cloned());
+        }
+    }
+    Ok(options)
+}
+
+/// Macro files for `evkey play` with their start offsets (in microseconds)
+///
+/// Each `--at` applies to the file after it; files without one start at 0.
+fn mix_files(args: &[String]) -> Result<Vec<(u64, &str)>, String> {
+    let mut files = Vec::new();
+    let mut offset_us = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--at" {
+            let value = args.next().ok_or("--at needs an offset, e.g. --at 2.5s")?;
+            offset_us = Some(mix::parse_offset(value)?);
+        } else if PLAY_VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if !arg.starts_with("--") {
+            files.push((offset_us.take().unwrap_or(0), arg.as_str()));
+        }
+    }
+    if offset_us.is_some() {
+        return Err("--at goes before the macro file it applies to".into());
+    }
+    Ok(files)
+}
+
+/// Value following `--name` on the command line
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
+/// Values following every `--name`, for options that may be repeated
+fn option_values<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
+
+/// Comma-separated values following `--name`, e.g. `--tracks keyboard,mouse`
+fn option_list(args: &[String], name: &str) -> Vec<String> {
+    option_value(args, name)
+        .map(|value| value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Arguments that are neither flags nor values of the given options
+fn positional_args<'a>(args: &'a [String], value_options: &[&str]) -> Vec<&'a str> {
+    let mut positional = Vec::new();
+    let mut skip_next = false;
+
+    for arg in args {
+        if skip_next {
+            skip_next = false;
+        } else if value_options.contains(&arg.as_str()) {
+            skip_next = true;
+        } else if !arg.starts_with("--") {
+            positional.push(arg.as_str());
+        }
+    }
+
+    positional
+}
+
 fn print_usage() {
     println!("EvKey - AutoHotkey-style macro recorder for Linux\n");
     println!("Usage:");
     println!("  evkey record <output_file>       Record a macro to file");
-    println!("  evkey play [--loop] <input_file> Play back a recorded macro");
+    println!("    --max-idle <duration>          Cap pauses longer than this, e.g. 10s");
+    println!("    --idle-marker                  Leave a marker where a pause was capped");
+    println!("    --tracks                       Keep keyboard and mouse input in separate tracks");
+    println!("    --click-positions              Note where the pointer was on screen for each click");
+    println!("    --compress                     Pack (and with zstd, compress) .evkb recordings");
+    println!("    --review                       Look the recording over, trim, rename or discard it before saving");
+    println!("    --debounce <duration>          Drop presses released sooner than this, e.g. 5ms");
+    println!("    --ignore-key <key>             Drop a key the hardware reports by itself (repeatable)");
+    println!("    --device <path>                Record only this device (repeatable; default: all keyboards and mice)");
+    println!("    -o, --output <file|->          Where to record, instead of naming it last");
+    println!("  evkey record -                   Stream events to stdout as they happen, until Ctrl+C");
+    println!("  evkey play -                     Play an event stream from stdin as it arrives");
+    println!("  evkey play [options] <input>     Play back a recorded macro");
+    println!("    --loop                         Repeat until interrupted");
+    println!("    --at <offset> <input>          Mix in another macro starting at this offset, e.g. 2.5s (repeatable)");
+    println!("    --sync-locks                   Match capslock/numlock to the recording first");
+    println!("    --match-layout                 Switch to the recorded keyboard layout while playing");
+    println!("    --from <time|marker>           Start partway in, e.g. --from 10s or --from M1");
+    println!("    --to <time|marker>             Stop early at a time or marker");
+    println!("    --tracks <a,b>                 Only play these tracks, e.g. --tracks keyboard");
+    println!("    --skip-tracks <a,b>            Play all tracks but these");
+    println!("    --min-hold <duration>          Keep every key down at least this long, e.g. 16ms");
+    println!("    --speed <factor>               Play this many times as fast, e.g. 2 or 0.5");
+    println!("    --repeat <n>                   Play the macro n times");
+    println!("    --humanize <duration>          Vary the length of each step randomly by up to this much");
+    println!("    --seed <n>                     Seed for --humanize, to repeat an earlier run's timing exactly");
+    println!("    --easing <curve>               Glide the pointer along linear, ease-in-out or bezier(x1,y1,x2,y2)");
+    println!("    --scroll-rate <hz>             Spread each state's scrolling over it, at most this many reports a second");
+    println!("    --scroll-hires                 Smooth scrolling in hi-res steps between notches");
+    println!("    --backend <name>               Inject via auto, uinput, wayland, x11 or portal (default: auto)");
+    println!("    --split-devices                Play keyboard and mouse input through separate uinput devices");
+    println!("    --pre-roll <duration>          Sync the new device and wait this long before the first event (default: 50ms)");
+    println!("    --max-rate <events/s>          Send at most this many events a second, stretching the timing to fit");
+    println!("    --burst <events>               Events --max-rate lets through at once (default: 10)");
+    println!("    --recover <retry|skip|abort>   When injecting fails: reopen the device and retry, skip the event or stop (default: abort)");
+    println!("    --max-keys <n>                 Refuse macros that hold more than n keys at once");
+    println!("    --max-text <n>                 Refuse macros that type more than n characters");
+    println!("    --force                        Play macros beyond --max-keys/--max-text anyway");
+    println!("    --accel-compensate             Undo pointer acceleration (run calibrate-accel first)");
+    println!("    --scale-to-screen              Scale mouse movement and coordinates to this screen's size");
+    println!("    --param <name=value>           Set a macro parameter (repeatable)");
+    println!("    --timing-report                Show how late each state played compared to the macro");
+    println!("    --verify                       Record the playback device and score how faithfully the macro came out");
+    println!("    --tolerance <duration>         Lateness the report lets pass (default: 2ms, 10ms with --verify)");
+    println!("    --seat <seat>                  Play on this seat of a multi-seat machine (see seat-rule)");
+    println!("    --no-progress                  Don't draw the progress bar");
+    println!("    --no-file-options              Ignore the options in the macro's # Play: header");
+    println!("    --no-<switch>                  Turn off a switch the header sets, e.g. --no-loop");
+    println!("    --notify                       Show desktop notifications on start/finish/abort/error");
+    println!("    --on-start|--on-finish|--on-abort|--on-error <command>");
+    println!("                                   Run a shell command when playback hits that point");
+    println!("  evkey click [options]            Click a mouse button over and over until Ctrl+C");
+    println!("    --button <left|right|middle>   Button to click (default: left)");
+    println!("    --interval <duration>          Time between clicks (default: 100ms)");
+    println!("    --jitter <duration>            Vary each interval randomly by up to this much");
+    println!("    --seed <n>                     Seed for the jitter, to repeat an earlier run's rhythm exactly");
+    println!("    --while-held <key>             Only click while this key is held, e.g. F8");
+    println!("    --backend <name>               Inject via auto, uinput, wayland, x11 or portal (default: auto)");
+    println!("  evkey compare <input_file>       Play a macro while recording, and report events lost, added or mistimed");
+    println!("    --device <path>                Record this device, e.g. a remapper's output (repeatable; default: all)");
+    println!("    --tolerance <duration>         Timing drift allowed per event (default: 10ms)");
+    println!("    --backend <name>               Inject via auto, uinput, wayland, x11 or portal (default: auto)");
+    println!("  evkey nkro-test                  Hold more and more keys at once and report how many come through");
+    println!("    --device <path>                Record this device, e.g. a remapper's output (repeatable; default: EvKey's own)");
+    println!("    --max <n>                      Most keys to hold at once (default: {})", nkro::KEYS.len());
+    println!("    --hold <duration>              How long each round holds its keys (default: 50ms)");
+    println!("  evkey proxy <device>             Grab a device and forward its events through a virtual copy, timing each");
+    println!("    --report-every <duration>      How often to print latency percentiles (default: 10s)");
+    println!("  evkey forward --to <host[:port]> Send this machine's keyboard and mouse to `evkey receive` on another");
+    println!("    --secret-file <file>           Secret shared with the receiving side (required)");
+    println!("    --device <path>                Forward only this device (repeatable; default: all keyboards and mice)");
+    println!("    --toggle <combo>               Hotkey that hands input back and forth (default: {})", forward::DEFAULT_TOGGLE);
+    println!("  evkey receive                    Inject input forwarded from another machine");
+    println!("    --secret-file <file>           Secret a sender has to send before anything is injected (required)");
+    println!(
+        "    --listen <address[:port]>      Where to listen (default: 127.0.0.1:{}, reached through SSH)",
+        forward::DEFAULT_PORT
+    );
+    println!("    --backend <name>               Inject via auto, uinput, wayland, x11 or portal (default: auto)");
+    println!("  evkey new --template <name> <output_file>");
+    println!("                                   Start a macro from a template: {}", templates::NAMES.join(", "));
+    println!("  evkey inspect <input_file>       Show a macro's states in shortcut notation");
+    println!("  evkey seat-rule <seat>           Install the udev rule that lets `play --seat` reach a seat");
+    println!("  evkey compress <in> <out.evkb>   Save any macro as a packed/compressed binary macro");
+    println!("  evkey cap-idle <in> <out> --max <duration> [--marker]");
+    println!("                                   Cap pauses in an existing macro");
+    println!("  evkey anchor-clicks <in> <out>   Replace pointer travel before clicks with 'move to' their positions");
+    println!("  evkey find-loops <in> [out]      List stretches done several times over; with <out>, fold them into repeat blocks");
+    println!("    --tolerance <duration>         Timing difference allowed between copies (default: {}ms)", loops::DEFAULT_TOLERANCE_MS);
+    println!("  evkey text <input_file>          Show the text a macro types (--output <file> to save it)");
+    println!("  evkey type-text <in> <out>       Replace recorded typing with 'type \"...\"' actions");
+    println!("    --min-chars <n>                Leave shorter stretches of typing as keystrokes (default: {})", DEFAULT_MIN_CHARS);
+    println!("  evkey trim <input> <output>      Keep only the part between --from/--to markers");
+    println!("  evkey save-clip <input> <name>   Save the part between --from/--to markers as a reusable clip");
+    println!("  evkey insert-clip <name> <input> <output> [--at MARKER]");
+    println!("                                   Insert a clip at a marker (default: at the end)");
+    println!("  evkey clips                      List saved clips");
+    println!("  evkey stats <input_file>         Show key usage, APM and mouse travel");
+    println!("    --heatmap <output.svg>         Also write a keyboard heatmap");
+    println!("  evkey plot-mouse <input> <out.svg> Draw the mouse path, colored by time");
+    println!("  evkey export <input> <output.c>  Export a standalone C replay program");
+    println!("    --format <c|qmk|via>           Or a QMK SEND_STRING snippet or VIA macro of a keyboard-only macro");
+    println!("    --keycode <name>               Custom keycode the QMK snippet is bound to (default: EVKEY_MACRO)");
+    println!("  evkey import <dump.txt> <output> Rebuild a macro from evtest, evbug (dmesg) or evemu output");
+    println!("  evkey recover <journal> [output] Save what a crashed or killed recording left in its journal");
+    println!("  evkey upgrade-file <file>        Rewrite a macro in the current format version");
+    println!("  evkey calibrate-accel [--backend <name>]");
+    println!("                                   Measure the desktop's pointer acceleration");
     println!("  evkey list-devices               List available input devices");
-    println!("\nNote: You may need to run with sudo to access input devices");
+    println!("  evkey learn-device [<device>]    Name a pedal's, remote's or pad's buttons, suggest bindings");
+    println!("    --bindings <file>              Where the bindings go (default: ~/.config/evkey/<device>.conf)");
+    println!("    --force                        Replace the bindings file if it exists");
+    println!("  evkey rpc                        Serve JSON-RPC on stdin/stdout, for editor plugins and GUIs");
+    println!("  evkey audit <on|show|clear>      Log all injected input, show the log or empty it");
+    println!("  evkey daemon [--config <file>]   Play macros on hotkeys (default: ~/.config/evkey/daemon.conf)");
+    println!("  evkey daemon --system [--config <file>]");
+    println!("                                   Run once for every user, as root (default: /etc/evkey/daemon.conf)");
+    println!("  evkey session [start [--profile <name>]|stop]");
+    println!("                                   Opt this session in to the system-wide daemon, or out");
+    println!("  evkey status [--watch]           Show the daemon's profiles, bindings, playbacks and triggers");
+    println!("  evkey stop-all                   Stop every macro the daemon plays and turn its hotkeys off");
+    println!("  evkey resume                     Turn the daemon's hotkeys back on");
+    println!("  evkey profile [<name>|next]      Show or switch the daemon's active profile");
+    println!("\nKey names (any command):");
+    println!("  --keymap <file>                  Extra key names and aliases (default: ~/.config/evkey/keymap.toml)");
+    println!("                                   A .json file is read as JSON, anything else as TOML");
+    println!("\nLogging (any command):");
+    println!("  -v, --verbose                    Debug logs, including every event with its timing");
+    println!("  -vv                              Trace logs");
+    println!("  --log-json                       Write logs as JSON lines (RUST_LOG overrides the filter)");
+    println!("\nFiles ending in .evkb are saved in the compact binary format.");
+    println!("Note: You may need to run with sudo to access input devices");
 }
 
 fn list_devices() -> Result<(), Box<dyn Error>> {
@@ -100,58 +870,186 @@ fn list_devices() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn record_macro(output_file: &str) -> Result<(), Box<dyn Error>> {
-    println!("EvKey Recorder");
-    println!("==============\n");
-
-    println!("Auto-detecting keyboards and mice...\n");
-
-    let mut recorder = Recorder::new();
-    let mut device_count = 0;
-
-    // Enumerate all devices and add keyboards/mice
-    for entry in std::fs::read_dir("/dev/input")? {
-        let entry = entry?;
-        let path = entry.path();
+/// Have the user press each button of a device and name it, then add the
+/// names to the keymap at `keymap_path` and write suggested bindings
+fn learn_device(
+    device: Option<&str>,
+    keymap_path: &Path,
+    bindings_path: Option<&str>,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    if keymap_path.extension().is_some_and(|ext| ext == "json") {
+        return Err(format!("learn writes TOML, pass --keymap <file.toml> instead of {}", keymap_path.display()).into());
+    }
+    let mut devices = recorder::find_input_devices()?;
+    if let Some(wanted) = device {
+        devices.retain(|info| info.path == Path::new(wanted) || info.name == wanted);
+        if devices.is_empty() {
+            return Err(format!("No input device '{}' (see `evkey list-devices`)", wanted).into());
+        }
+        println!("Press any button on {}", devices[0].name);
+    } else {
+        println!("Press any button on the device to learn");
+    }
+    let (info, mut device, first) = learn::wait_for_device(&devices)?;
+    let bindings_path = match bindings_path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let dir = config::config_dir().ok_or("Can't find the config directory (set HOME or XDG_CONFIG_HOME)")?;
+            dir.join(learn::bindings_file_name(&info.name))
+        }
+    };
+    // Checked before the user spends time naming buttons
+    if !force && bindings_path.exists() {
+        let path = bindings_path.display();
+        return Err(format!("{} already exists (pass --force to replace it, or --bindings <file>)", path).into());
+    }
+    println!("Learning {} ({})", info.name, info.path.display());
+    println!("Press each of its buttons once, then the first one again to finish\n");
+    // So its buttons don't type into the terminal meanwhile
+    if let Err(e) = device.grab() {
+        warn!("Can't grab {}: {}", info.name, e);
+    }
+    println!("  Button 1: {}", keymap::format_combo(first.iter().copied()));
+    let mut buttons = vec![first];
+    loop {
+        match learn::next_button(&mut device, learn::PRESS_TIMEOUT)? {
+            None => {
+                println!("Nothing pressed for {}s, that's all then", learn::PRESS_TIMEOUT.as_secs());
+                break;
+            }
+            Some(keys) if keys == buttons[0] => break,
+            Some(keys) if buttons.contains(&keys) => println!("  (already learned)"),
+            Some(keys) => {
+                println!("  Button {}: {}", buttons.len() + 1, keymap::format_combo(keys.iter().copied()));
+                buttons.push(keys);
+            }
+        }
+    }
+    drop(device);
 
-        if let Some(name) = path.file_name() {
-            let name_str = name.to_string_lossy();
-            if name_str.starts_with("event") {
-                match evdev::Device::open(&path) {
-                    Ok(device) => {
-                        // Check if device has keys (keyboard) or relative axes (mouse)
-                        let has_keys = device.supported_keys().map_or(false, |keys| keys.iter().len() > 0);
-                        let has_relative = device.supported_relative_axes().map_or(false, |axes| axes.iter().len() > 0);
-
-                        if has_keys || has_relative {
-                            let device_type = match (has_keys, has_relative) {
-                                (true, true) => "keyboard+mouse",
-                                (true, false) => "keyboard",
-                                (false, true) => "mouse",
-                                _ => continue,
-                            };
-
-                            println!("  {} - {} ({})",
-                                path.display(),
-                                device.name().unwrap_or("unknown"),
-                                device_type
-                            );
-
-                            drop(device); // Close device before reopening in recorder
-                            match recorder.add_device(&path) {
-                                Ok(_) => device_count += 1,
-                                Err(e) => eprintln!("    Warning: Could not add device: {}", e),
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        // Skip devices we can't open (permission issues, etc.)
-                    }
+    println!("\nName the buttons (letters, digits and _; Enter takes the suggestion):");
+    let mut names = Vec::new();
+    let mut aliases = Vec::new();
+    let mut combos = Vec::new();
+    let taken = |name: &str, names: &[(u16, String)], aliases: &[(String, String)]| {
+        keymap::name_to_keycode(name).is_some()
+            || names.iter().any(|(_, taken)| taken == name)
+            || aliases.iter().any(|(taken, _)| taken == name)
+    };
+    for (index, keys) in buttons.iter().enumerate() {
+        let [code] = keys[..] else {
+            let combo = keymap::format_combo(keys.iter().copied());
+            println!("  Button {} sends {}, which can be bound but not named", index + 1, combo);
+            combos.push(combo);
+            continue;
+        };
+        let current = keymap::keycode_to_name(code);
+        let suggestion = match &current {
+            Some(name) => name.clone(),
+            None => learn::suggest_name(index + 1, |name| taken(name, &names, &aliases)),
+        };
+        let name = loop {
+            match &current {
+                Some(name) => print!("  Button {} ({}, or an alias for it) [{}]: ", index + 1, name, suggestion),
+                None => print!("  Button {} (KEY_{}) [{}]: ", index + 1, code, suggestion),
+            }
+            io::stdout().flush()?;
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            let name = match learn::check_name(line.trim()) {
+                _ if line.trim().is_empty() => break suggestion.clone(),
+                Ok(name) if current.as_ref() == Some(&name) => break name,
+                Ok(name) => name,
+                Err(e) => {
+                    println!("  {}", e);
+                    continue;
                 }
+            };
+            if taken(&name, &names, &aliases) {
+                println!("  {} is already a key", name);
+            } else {
+                break name;
             }
+        };
+        match &current {
+            Some(current) if *current != name => aliases.push((name.clone(), current.clone())),
+            Some(_) => {}
+            None => names.push((code, name.clone())),
+        }
+        combos.push(name);
+    }
+
+    if !names.is_empty() || !aliases.is_empty() {
+        let text = match std::fs::read_to_string(keymap_path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Can't read {}: {}", keymap_path.display(), e).into()),
+        };
+        let text =
+            learn::add_to_keymap(&text, &names, &aliases).map_err(|e| format!("{}: {}", keymap_path.display(), e))?;
+        if let Some(dir) = keymap_path.parent() {
+            std::fs::create_dir_all(dir)?;
         }
+        std::fs::write(keymap_path, text)?;
+        println!("\nAdded {} name(s) and {} alias(es) to {}", names.len(), aliases.len(), keymap_path.display());
+    }
+
+    if let Some(dir) = bindings_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&bindings_path, learn::bindings(&info.name, &combos))?;
+    println!("Suggested bindings are in {}; restart the daemon to use the new names", bindings_path.display());
+    Ok(())
+}
+
+/// Options for `evkey record` to a file
+struct RecordOptions {
+    /// Cap pauses longer than this
+    max_idle_ms: Option<u64>,
+    /// Leave a marker where a pause was capped
+    idle_marker: bool,
+    /// Keep each kind of device in a track of its own
+    tracks: bool,
+    click_positions: bool,
+    /// Let the user look the recording over before it's saved
+    review: bool,
+    encoding: binary::Encoding,
+    /// Garbage dropped from the raw events
+    filter: filter::EventFilter,
+}
+
+fn record_macro(output_file: &str, devices: &[&str], options: RecordOptions) -> Result<(), Box<dyn Error>> {
+    let RecordOptions {
+        max_idle_ms,
+        idle_marker,
+        tracks,
+        click_positions,
+        review,
+        encoding,
+        filter,
+    } = options;
+    let is_binary = Path::new(output_file).extension().is_some_and(|ext| ext == binary::EXTENSION);
+    if tracks && is_binary {
+        eprintln!("Error: --tracks needs a text macro, not a .{} file", binary::EXTENSION);
+        return Ok(());
+    }
+    if review && (tracks || is_binary) {
+        eprintln!("Error: --review needs a text macro without --tracks");
+        return Ok(());
     }
 
+    let pipeline = load_postprocess()?;
+    journal::check_unfinished(Path::new(output_file))?;
+
+    println!("EvKey Recorder");
+    println!("==============\n");
+
+    let mut recorder = Recorder::new();
+    recorder.capture_click_positions(click_positions);
+    recorder.set_filter(filter);
+    let device_count = add_recorded_devices(&mut recorder, devices, |line| println!("{}", line))?;
+
     if device_count == 0 {
         eprintln!("\nError: No keyboard or mouse devices found!");
         eprintln!("Make sure you're running with sudo or have appropriate permissions.");
@@ -163,10 +1061,20 @@ fn record_macro(output_file: &str) -> Result<(), Box<dyn Error>> {
     println!("\n=== HOTKEY CONTROLS ===");
     println!("Press F1 to START recording");
     println!("Press F1 again to STOP recording");
+    println!("Press F2 while recording to add a marker");
+    println!("Or signal process {}: USR2 starts or resumes, USR1 pauses,", std::process::id());
+    println!("TSTP stops and saves");
     println!("========================\n");
     println!("Waiting for F1 to start...");
 
+    let request = control_signals(&[
+        (signal_hook::consts::SIGUSR1, SIGNAL_PAUSE),
+        (signal_hook::consts::SIGUSR2, SIGNAL_RESUME),
+        (signal_hook::consts::SIGTSTP, SIGNAL_STOP),
+    ])?;
+
     // Poll for events until recording starts and stops
+    let mut journal = None;
     loop {
         match recorder.poll() {
             Ok(state_changed) => {
@@ -181,46 +1089,1844 @@ fn record_macro(output_file: &str) -> Result<(), Box<dyn Error>> {
             },
             Err(e) => eprintln!("Error polling: {}", e),
         }
+        match request.swap(0, Ordering::Relaxed) {
+            SIGNAL_PAUSE if recorder.is_recording() && !recorder.is_paused() => {
+                recorder.pause();
+                println!(">>> Recording paused");
+            }
+            SIGNAL_RESUME if recorder.is_paused() => {
+                recorder.resume();
+                println!(">>> Recording resumed");
+            }
+            SIGNAL_RESUME if !recorder.is_recording() => {
+                recorder.start();
+                println!("\n>>> Recording started! Perform your macro actions...");
+            }
+            SIGNAL_STOP if recorder.is_recording() => {
+                println!(">>> Recording stopped!");
+                break;
+            }
+            SIGNAL_STOP => {
+                println!("Stopped before recording started, nothing to save");
+                return Ok(());
+            }
+            _ => {}
+        }
+        if recorder.is_recording() {
+            update_journal(&mut journal, output_file, recorder.events());
+        }
         thread::sleep(Duration::from_millis(1));
     }
 
+    let metadata = storage::Metadata {
+        locks: recorder.lock_state(),
+        screen: screen::screen_size()
+            .inspect_err(|e| warn!("Not storing the screen size: {}", e))
+            .ok(),
+        polling_hz: recorder.polling_hz(),
+        keyboard: layout::current()
+            .inspect_err(|e| warn!("Not storing the keyboard layout: {}", e))
+            .ok(),
+        easing: None,
+        scroll: None,
+        environment: recorder.environment(),
+        play: Vec::new(),
+        window: None,
+    };
+    if let Some(hz) = metadata.polling_hz {
+        println!("Mouse polling rate: {}Hz", hz);
+    }
+    if let Some(keyboard) = &metadata.keyboard {
+        println!("Keyboard layout: {}", keyboard.describe());
+    }
+
+    if tracks {
+        let recorded = recorder.stop_tracks();
+        let event_count: usize = recorded.iter().map(|(_, events)| events.len()).sum();
+        println!("\nSaving {} events in {} track(s) to {}...", event_count, recorded.len(), output_file);
+
+        let mut session = storage::Session::from_recording(&recorded, recorder.markers(), metadata);
+        session.place_clicks(recorder.clicks());
+        if let Some(pipeline) = &pipeline {
+            println!("Post-processing: {}", pipeline.names().join(", "));
+            for track in &mut session.tracks {
+                track.macro_.postprocess(pipeline);
+            }
+        }
+        if let Some(max_ms) = max_idle_ms {
+            let capped: usize = session
+                .tracks
+                .iter_mut()
+                .map(|track| track.macro_.cap_idle(max_ms, idle_marker))
+                .sum();
+            if capped > 0 {
+                println!("Capped {} pause(s) longer than {}ms", capped, max_ms);
+            }
+        }
+        storage::save_session(output_file, &session)?;
+        println!("Macro saved successfully!");
+        return remove_journal(journal);
+    }
+
     let events = recorder.stop();
 
-    println!("\nSaving {} events to {}...", events.len(), output_file);
-    storage::save(output_file, &events)?;
+    if review {
+        println!("\nRecorded {} events", events.len());
+    } else {
+        println!("\nSaving {} events to {}...", events.len(), output_file);
+    }
+    if is_binary {
+        if max_idle_ms.is_some() {
+            warn!("--max-idle only applies to text macros, ignoring it");
+        }
+        if click_positions {
+            warn!("--click-positions only applies to text macros, ignoring it");
+        }
+        if pipeline.is_some() {
+            warn!("Post-processing only applies to text macros, skipping it");
+        }
+        binary::save_with(output_file, &events, encoding)?;
+    } else {
+        if encoding != binary::Encoding::Plain {
+            warn!("--compress only applies to .{} files, ignoring it", binary::EXTENSION);
+        }
+        let mut macro_ = storage::Macro::from_recording(&events, recorder.markers(), metadata);
+        macro_.place_clicks(recorder.clicks());
+        if let Some(pipeline) = &pipeline {
+            println!("Post-processing: {}", pipeline.names().join(", "));
+            macro_.postprocess(pipeline);
+        }
+        if let Some(max_ms) = max_idle_ms {
+            let capped = macro_.cap_idle(max_ms, idle_marker);
+            if capped > 0 {
+                println!("Capped {} pause(s) longer than {}ms", capped, max_ms);
+            }
+        }
+        if review {
+            match review_recording(&mut macro_, output_file)? {
+                Reviewed::Save(output_file) => {
+                    storage::save_macro(&output_file, &macro_)?;
+                    println!("Macro saved to {}", output_file);
+                }
+                Reviewed::Clip(name) => {
+                    let path = clips::save(&name, &macro_)?;
+                    println!("Saved as clip '{}' ({})", name, path.display());
+                }
+                Reviewed::Discard => println!("Recording discarded"),
+            }
+            return remove_journal(journal);
+        }
+        storage::save_macro(output_file, &macro_)?;
+    }
     println!("Macro saved successfully!");
 
-    Ok(())
+    remove_journal(journal)
 }
 
-fn play_macro(input_file: &str, loop_forever: bool) -> Result<(), Box<dyn Error>> {
-    println!("EvKey Player");
-    println!("============\n");
+/// Where a reviewed recording goes
+enum Reviewed {
+    /// To this file
+    Save(String),
+    /// Into the clip library, under this name
+    Clip(String),
+    Discard,
+}
 
-    if !Path::new(input_file).exists() {
-        eprintln!("Error: File '{}' not found", input_file);
+const REVIEW_HELP: &str = "\
+  save (or Enter)     Save to the output file
+  show                List the states
+  trim-start [n]      Drop the first n states (default: the idle ones)
+  trim-end [n]        Drop the last n states (default: the idle ones)
+  rename <file>       Save to another file instead
+  library <name>      Save as a clip in the library instead of a file
+  discard             Throw the recording away";
+
+/// Show what was recorded and let the user tidy it up before it's saved
+///
+/// Reads commands from stdin until one decides where the recording goes;
+/// the end of input saves it as it is.
+fn review_recording(macro_: &mut storage::Macro, output_file: &str) -> Result<Reviewed, Box<dyn Error>> {
+    let mut output_file = output_file.to_string();
+    print_review_summary(macro_);
+    println!("\nReview before saving to {}:\n{}", output_file, REVIEW_HELP);
+
+    loop {
+        print!("review> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(Reviewed::Save(output_file));
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] | ["save"] => return Ok(Reviewed::Save(output_file)),
+            ["show"] => print_states(macro_),
+            ["trim-start" | "trim-end", ..] if words.len() <= 2 => {
+                let at_start = words[0] == "trim-start";
+                let (leading, trailing) = macro_.idle_ends();
+                let count = match words.get(1) {
+                    Some(count) => match count.parse::<usize>() {
+                        Ok(count) => count.min(macro_.states.len()),
+                        Err(_) => {
+                            println!("'{}' isn't a number of states", count);
+                            continue;
+                        }
+                    },
+                    None if at_start => leading,
+                    None => trailing,
+                };
+                let len = macro_.states.len();
+                let (from, to) = if at_start { (count, len) } else { (0, len - count) };
+                let dropped_ms: u64 = macro_.states[..from].iter().chain(&macro_.states[to..]).map(|s| s.duration_ms).sum();
+                *macro_ = macro_.slice(from, to);
+                println!(
+                    "Dropped {} state(s) ({:.3}s) from the {}",
+                    count,
+                    dropped_ms as f64 / 1000.0,
+                    if at_start { "start" } else { "end" }
+                );
+                print_review_summary(macro_);
+            }
+            ["rename", file] => {
+                if Path::new(file).extension().is_some_and(|ext| ext == binary::EXTENSION) {
+                    println!("Reviewed recordings are saved as text macros, not .{} files", binary::EXTENSION);
+                } else {
+                    output_file = file.to_string();
+                    println!("Will save to {}", output_file);
+                }
+            }
+            ["library", name] => match clips::validate_name(name) {
+                Ok(()) => return Ok(Reviewed::Clip(name.to_string())),
+                Err(e) => println!("{}", e),
+            },
+            ["discard"] => return Ok(Reviewed::Discard),
+            _ => println!("{}", REVIEW_HELP),
+        }
+    }
+}
+
+/// How long the recording is, what it does, and the idle time at each end
+fn print_review_summary(macro_: &storage::Macro) {
+    let duration_ms = |states: &[state::MacroState]| -> u64 { states.iter().map(|s| s.duration_ms).sum() };
+    let total_ms = duration_ms(&macro_.states);
+    let (leading, trailing) = macro_.idle_ends();
+    let stats = stats::analyze(&macro_.events());
+
+    println!("\n  States:   {} over {:.3}s", macro_.states.len(), total_ms as f64 / 1000.0);
+    println!("  Markers:  {}", macro_.markers.len());
+    let top_keys: Vec<String> = stats
+        .top_keys()
+        .into_iter()
+        .take(5)
+        .map(|(code, key)| format!("{} x{}", keymap::display_name(code).unwrap_or_else(|| format!("KEY_{}", code)), key.presses))
+        .collect();
+    println!("  Presses:  {} ({})", stats.total_presses(), if top_keys.is_empty() { "none".to_string() } else { top_keys.join(", ") });
+    println!("  Mouse:    {:.0}px, {} scroll detent(s)", stats.mouse_travel, stats.scroll_ticks);
+    println!(
+        "  Idle:     {:.3}s at the start, {:.3}s at the end",
+        duration_ms(&macro_.states[..leading]) as f64 / 1000.0,
+        duration_ms(&macro_.states[macro_.states.len() - trailing..]) as f64 / 1000.0
+    );
+}
+
+/// Bring the journal up to date with what's been recorded, starting it the
+/// first time
+///
+/// Without a journal the recording carries on, only not crash-safe.
+fn update_journal(journal: &mut Option<Option<journal::Journal>>, output_file: &str, recorded: &[RecordedEvent]) {
+    let writing = journal.get_or_insert_with(|| {
+        journal::Journal::create(Path::new(output_file))
+            .inspect_err(|e| warn!("Recording without a journal: {}", e))
+            .ok()
+    });
+    if let Some(writer) = writing.as_mut()
+        && let Err(e) = writer.catch_up(recorded)
+    {
+        warn!("Can't write {}, recording on without it: {}", writer.path().display(), e);
+        *writing = None;
+    }
+}
+
+/// Delete the journal of a recording that was saved
+fn remove_journal(journal: Option<Option<journal::Journal>>) -> Result<(), Box<dyn Error>> {
+    if let Some(Some(journal)) = journal {
+        journal.remove()?;
+    }
+    Ok(())
+}
+
+/// Turn the journal of a recording that didn't finish into a macro
+fn recover_journal(journal_file: &str, output_file: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(journal_file);
+    if !path.exists() {
+        eprintln!("Error: File '{}' not found", journal_file);
         return Ok(());
     }
+    let output = match output_file {
+        Some(output) => Path::new(output),
+        None => {
+            let output = journal_file.strip_suffix(&format!(".{}", journal::EXTENSION));
+            Path::new(output.ok_or("Give the file to save the recording to: evkey recover <journal> <output_file>")?)
+        }
+    };
+    if output.exists() {
+        return Err(format!("{} already exists; give another file to save the recording to", output.display()).into());
+    }
 
-    println!("Loading macro from {}...", input_file);
-    let events = storage::load(input_file)?;
+    let recovered = journal::recover(path)?;
+    if recovered.events.is_empty() {
+        return Err(format!("{} holds no events", journal_file).into());
+    }
+    storage::save(output, &recovered.events, &[], &storage::Metadata::default())?;
+    let duration_us = recovered.events.last().map_or(0, |last| last.timestamp_us);
+    println!(
+        "Recovered {} events ({:.1}s) into {}",
+        recovered.events.len(),
+        duration_us as f64 / 1_000_000.0,
+        output.display()
+    );
+    if recovered.released > 0 {
+        println!("Released {} key(s) still held where the recording was cut off", recovered.released);
+    }
+    println!("Markers and click positions aren't kept in the journal; delete {} once you're happy with the macro", journal_file);
+    Ok(())
+}
 
-    println!("Loaded {} events", events.len());
-    println!("\nStarting playback in 3 seconds...");
+/// What a signal asked a foreground `record` or `play` to do
+const SIGNAL_PAUSE: usize = 1;
+const SIGNAL_RESUME: usize = 2;
+const SIGNAL_STOP: usize = 3;
 
-    thread::sleep(Duration::from_secs(3));
+/// Set the returned value to each signal's request as it comes in
+fn control_signals(signals: &[(i32, usize)]) -> io::Result<Arc<AtomicUsize>> {
+    let request = Arc::new(AtomicUsize::new(0));
+    for &(signal, value) in signals {
+        signal_hook::flag::register_usize(signal, Arc::clone(&request), value)?;
+    }
+    Ok(request)
+}
 
-    let mut player = Player::new("evkey-playback")?;
+/// Pause playback on SIGUSR1 and resume it on SIGUSR2, and while another
+/// window than the macro's `window` has focus; stop it once `stop` is set
+fn pause_on_signals(
+    player: &mut Player,
+    window: Option<&guard::WindowGuard>,
+    stop: &Arc<AtomicBool>,
+) -> io::Result<()> {
+    let request = control_signals(&[
+        (signal_hook::consts::SIGUSR1, SIGNAL_PAUSE),
+        (signal_hook::consts::SIGUSR2, SIGNAL_RESUME),
+    ])?;
+    let mut paused = false;
+    player.stop_on(Arc::clone(stop));
+    let mut unfocused = window.map(|window| window.unfocused(screen::active_window_title, Arc::clone(stop)));
+    player.pause_while(move || {
+        match request.swap(0, Ordering::Relaxed) {
+            SIGNAL_PAUSE => paused = true,
+            SIGNAL_RESUME => paused = false,
+            _ => {}
+        }
+        paused || unfocused.as_mut().is_some_and(|unfocused| unfocused())
+    });
+    Ok(())
+}
 
-    loop {
-        player.play(&events)?;
+/// Add the given devices to `recorder`, or every keyboard and mouse if none
+/// are given, and return how many were added; `say` shows progress
+fn add_recorded_devices(recorder: &mut Recorder, devices: &[&str], say: impl Fn(&str)) -> io::Result<usize> {
+    let mut device_count = 0;
+    if !devices.is_empty() {
+        for device in devices {
+            say(&format!("  {}", device));
+            recorder.add_device(device)?;
+            device_count += 1;
+        }
+        return Ok(device_count);
+    }
 
-        if loop_forever {
-            println!("\nFinished macro, starting again...");
-        } else {
-            break;
+    say("Auto-detecting keyboards and mice...\n");
+    // Enumerate all devices and add keyboards/mice
+    for device in recorder::find_input_devices()? {
+        say(&format!("  {} - {} ({})", device.path.display(), device.name, device.kind));
+
+        match recorder.add_device(&device.path) {
+            Ok(_) => device_count += 1,
+            Err(e) => eprintln!("    Warning: Could not add device: {}", e),
         }
     }
+    Ok(device_count)
+}
+
+/// Record straight to stdout as a live event stream, until Ctrl+C
+///
+/// Everything meant for the user goes to stderr, so the stream can be piped
+/// into `evkey play -` on this or another machine.
+fn stream_recording(devices: &[&str], filter: filter::EventFilter) -> Result<(), Box<dyn Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+
+    let mut recorder = Recorder::new();
+    recorder.disable_hotkeys();
+    recorder.set_filter(filter);
+    let device_count = add_recorded_devices(&mut recorder, devices, |line| eprintln!("{}", line))?;
+    if device_count == 0 {
+        return Err("No keyboard or mouse devices found".into());
+    }
+
+    let mut stream = stream::StreamWriter::new(io::stdout().lock())?;
+    recorder.start();
+    eprintln!("Streaming from {} device(s), Ctrl+C to stop", device_count);
+
+    while !stop.load(Ordering::Relaxed) {
+        if let Err(e) = recorder.poll() {
+            eprintln!("Error polling: {}", e);
+        }
+        match stream.send(&recorder.take_events()) {
+            Ok(()) => {}
+            // Whoever was reading went away
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    recorder.stop();
+    stream.finish()?;
+    eprintln!("Stream ended");
+    Ok(())
+}
+
+/// Options for `evkey play`
+struct PlayOptions {
+    loop_forever: bool,
+    sync_locks: bool,
+    /// Switch to the recorded keyboard layout for the playback
+    match_layout: bool,
+    /// Start boundary: a duration like "10s" or a marker name
+    from: Option<String>,
+    /// End boundary: a duration like "45s" or a marker name
+    to: Option<String>,
+    /// Tracks to play; all of them if empty
+    tracks: Vec<String>,
+    /// Tracks to leave out
+    skip_tracks: Vec<String>,
+    /// Minimum time each key stays pressed
+    min_hold_ms: Option<u64>,
+    /// How many times as fast to play
+    speed: f64,
+    /// Times to play the macro, unless --loop plays it until interrupted
+    repeat: u32,
+    /// Vary each state's length randomly by up to this much
+    humanize_ms: Option<u64>,
+    /// Seed for --humanize, to repeat an earlier run's timing exactly
+    seed: Option<u64>,
+    /// Time between the device's first sync report and the macro's first
+    /// event
+    pre_roll: Duration,
+    /// Easing curve for pointer movement, for states without their own
+    easing: Option<easing::Easing>,
+    /// Rate to smooth scrolling at, overriding the macro's
+    scroll_rate_hz: Option<u32>,
+    /// Smooth scrolling with hi-res wheel steps
+    scroll_hires: bool,
+    /// Play through a virtual keyboard and a virtual mouse of their own
+    split_devices: bool,
+    /// Scale pointer motion by the calibrated acceleration curve
+    accel_compensate: bool,
+    /// Rescale movement and coordinates to the current screen size
+    scale_to_screen: bool,
+    /// Macro parameters to override, as `(name, value)`
+    params: Vec<(String, String)>,
+    backend: backend::BackendKind,
+    /// Seat to play on, on a multi-seat machine
+    seat: Option<String>,
+    /// Print how late each state played, counting states later than this
+    /// (in microseconds) as late
+    timing_report: Option<u64>,
+    hooks: Hooks,
+    /// Stops playback once set, letting go of held keys
+    stop: Arc<AtomicBool>,
+    /// Draw a progress bar on stderr
+    progress: bool,
+    /// Most events a second to send
+    max_rate: Option<throttle::EventRate>,
+    /// What to do when the backend fails to take an event
+    recover: player::Recovery,
+    /// Bounds a macro has to stay within to be played
+    limits: guard::Limits,
+    /// Play macros beyond the limits anyway
+    force: bool,
+    /// Record the playback device and compare what came out with the macro,
+    /// counting events further off than this (in microseconds) as late
+    verify: Option<u64>,
+}
+
+fn parse_speed(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|speed: &f64| speed.is_finite() && *speed > 0.0)
+        .ok_or_else(|| format!("Invalid speed '{}', expected a factor like 1.5 or 0.5", value))
+}
+
+fn parse_repeat(value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|&times: &u32| times > 0)
+        .ok_or_else(|| format!("Invalid repeat count '{}', expected a number from 1", value))
+}
+
+fn parse_limit(value: &str, what: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| format!("Invalid {} '{}', expected a number", what, value))
+}
+
+/// Refuse a macro beyond `--max-keys`/`--max-text`, or warn about it with
+/// `--force`
+fn check_limits(extent: &guard::Extent, options: &PlayOptions) -> Result<(), String> {
+    match options.limits.check(extent) {
+        Ok(()) => Ok(()),
+        Err(exceeded) if options.force => {
+            warn!("Playing a macro that {} because of --force", exceeded);
+            Ok(())
+        }
+        Err(exceeded) => Err(format!("Refusing to play a macro that {} (pass --force to play it anyway)", exceeded)),
+    }
+}
+
+/// Show how faithfully the last playback kept its timing, with `--timing-report`
+fn print_timing_report(player: &Player, options: &PlayOptions) {
+    if let Some(tolerance_us) = options.timing_report {
+        println!("\nTiming report\n{}", player.timing_report(tolerance_us));
+    }
+}
+
+/// Open the backend `evkey play` injects through, on the requested seat,
+/// for input that `needs` these capabilities if it's known up front
+fn playback_backend(options: &PlayOptions, needs: Option<&Capabilities>) -> Result<Box<dyn backend::Backend>, Box<dyn Error>> {
+    let (kind, name, layout) = playback_device(options)?;
+    Ok(backend::open_for(kind, &name, needs, layout)?)
+}
+
+/// The backend, device name and device layout `options` play on
+fn playback_device(options: &PlayOptions) -> Result<(backend::BackendKind, String, backend::DeviceLayout), Box<dyn Error>> {
+    let layout = if options.split_devices {
+        if !matches!(options.backend, backend::BackendKind::Auto | backend::BackendKind::Uinput) {
+            warn!("--split-devices only applies to uinput, ignoring it");
+        }
+        backend::DeviceLayout::Split
+    } else {
+        backend::DeviceLayout::Combined
+    };
+    let Some(seat) = &options.seat else {
+        return Ok((options.backend, "evkey-playback".to_string(), layout));
+    };
+    if !matches!(options.backend, backend::BackendKind::Auto | backend::BackendKind::Uinput) {
+        return Err("--seat injects through uinput, it can't be combined with another --backend".into());
+    }
+    seat::check(seat)?;
+    Ok((backend::BackendKind::Uinput, seat::device_name("evkey-playback", seat), layout))
+}
+
+/// Set `player` up to recover from injection errors as `--recover` says,
+/// opening the backend again for each retry
+fn set_recovery(player: &mut Player, options: &PlayOptions, needs: Option<&Capabilities>) -> Result<(), Box<dyn Error>> {
+    player.set_recovery(options.recover);
+    if options.recover == player::Recovery::Retry {
+        let (kind, name, layout) = playback_device(options)?;
+        let needs = needs.copied();
+        player.reopen_with(move || backend::open_for(kind, &name, needs.as_ref(), layout));
+    }
+    Ok(())
+}
+
+/// Start recording the device `options` play on, for `--verify`
+///
+/// Done once the player is set up, so lock syncing and the like aren't
+/// recorded.
+fn start_verify(options: &PlayOptions, actions: &[(u64, Action)]) -> Result<Option<Capture>, Box<dyn Error>> {
+    if options.verify.is_none() {
+        return Ok(None);
+    }
+    let (_, name, _) = playback_device(options)?;
+    // With --split-devices the pointer is a device of its own
+    let pointer = format!("{} (", name);
+    let mut recorder = Recorder::new();
+    recorder.disable_hotkeys();
+    let mut found = false;
+    for device in recorder::find_input_devices()? {
+        if device.name == name || device.name.starts_with(&pointer) {
+            recorder.add_device(&device.path)?;
+            found = true;
+        }
+    }
+    if !found {
+        return Err(format!("Can't find the {} device to record it (--verify needs the uinput backend)", name).into());
+    }
+    if !actions.is_empty() {
+        warn!("What the macro's actions type and move isn't part of its events, so it counts as unexpected");
+    }
+    Ok(Some(Capture::start(recorder)))
+}
+
+/// Compare what `capture` recorded with the `expected` events, for `--verify`
+fn finish_verify(capture: Capture, expected: &[RecordedEvent], tolerance_us: u64) -> Result<(), Box<dyn Error>> {
+    let observed = capture.finish()?;
+    let comparison = compare::compare(expected, &observed, tolerance_us);
+    println!("\nVerification\n{}", comparison);
+    if !comparison.is_faithful() {
+        return Err("Playback wasn't reproduced faithfully".into());
+    }
+    println!("Playback was reproduced faithfully");
+    Ok(())
+}
+
+/// Resolve a --from/--to value to microseconds: a duration, or a marker name
+fn resolve_boundary(value: &str, macro_: Option<&storage::Macro>) -> Result<u64, String> {
+    if let Ok(ms) = storage::parse_duration(value) {
+        return Ok(ms * 1000);
+    }
+
+    macro_
+        .and_then(|m| m.marker_time_ms(value))
+        .map(|ms| ms * 1000)
+        .ok_or_else(|| format!("'{}' is neither a duration nor a marker name", value))
+}
+
+fn play_macro(input_file: &str, options: &PlayOptions) -> Result<(), Box<dyn Error>> {
+    if input_file == "-" {
+        return play_stream(options);
+    }
+
+    println!("EvKey Player");
+    println!("============\n");
+
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    if binary::is_binary(input_file) {
+        // Large binary recordings are mapped and decoded lazily during playback
+        if !options.tracks.is_empty() || !options.skip_tracks.is_empty() {
+            eprintln!("Error: Binary macros have no tracks");
+            return Ok(());
+        }
+        if !options.params.is_empty() {
+            eprintln!("Error: Binary macros have no parameters");
+            return Ok(());
+        }
+
+        println!("Mapping binary macro from {}...", input_file);
+        let mapped = binary::MappedMacro::open(input_file)?;
+
+        println!("Loaded {} events", mapped.len());
+        if mapped.is_empty() {
+            println!("No events to play");
+            return Ok(());
+        }
+
+        // Binary macros have no markers, so only durations are valid boundaries
+        let from = options.from.as_deref().map(|v| resolve_boundary(v, None)).transpose()?;
+        let to = options.to.as_deref().map(|v| resolve_boundary(v, None)).transpose()?;
+        let mut bad_record = None;
+        let window = (from.is_some() || to.is_some()).then(|| {
+            let events = mapped.iter().map_while(|recorded| recorded.map_err(|e| bad_record = Some(e)).ok());
+            state::slice_events(events, from.unwrap_or(0), to)
+        });
+        if let Some(e) = bad_record {
+            return Err(e.into());
+        }
+        let mut needs = Capabilities::default();
+        let mut extent = guard::Extent::default();
+        let mut add = |recorded: &RecordedEvent| {
+            needs.add_event(&recorded.event);
+            extent.add_event(recorded);
+        };
+        match &window {
+            Some(events) => events.iter().for_each(add),
+            None => mapped.iter().try_for_each(|recorded| recorded.map(|recorded| add(&recorded)))?,
+        }
+        check_limits(&extent, options)?;
+        println!("Needs: {}", needs);
+        // Created ahead of the countdown, so the desktop has picked the device
+        // up by the time it's played on
+        let mut player = Player::new(playback_backend(options, Some(&needs))?);
+
+        println!("\nStarting playback in 3 seconds...");
+
+        thread::sleep(Duration::from_secs(3));
+        player.set_source(input_file);
+        player.set_max_rate(options.max_rate);
+        set_recovery(&mut player, options, Some(&needs))?;
+        player.pre_roll(options.pre_roll)?;
+
+        if io::stdin().is_terminal() {
+            player.on_suspend(ask_to_resume);
+        }
+        pause_on_signals(&mut player, None, &options.stop)?;
+        if options.progress {
+            let mut bar = ProgressBar::new(0);
+            player.on_progress(move |progress| bar.update(progress));
+        }
+        options.hooks.fire(HookEvent::Start, input_file, None);
+        if options.sync_locks {
+            warn!("Binary macros don't store lock state, skipping --sync-locks");
+        }
+        if options.match_layout {
+            warn!("Binary macros don't store the keyboard layout, skipping --match-layout");
+        }
+        if options.min_hold_ms.is_some() {
+            warn!("--min-hold only applies to text macros, ignoring it");
+        }
+        if options.accel_compensate {
+            warn!("--accel-compensate only applies to text macros, ignoring it");
+        }
+        if options.easing.is_some() {
+            warn!("--easing only applies to text macros, ignoring it");
+        }
+        if options.speed != 1.0 || options.humanize_ms.is_some() {
+            warn!("--speed and --humanize only apply to text macros, ignoring them");
+        }
+        if options.scroll_rate_hz.is_some() || options.scroll_hires {
+            warn!("Scroll smoothing only applies to text macros, ignoring it");
+        }
+        if options.scale_to_screen {
+            warn!("Binary macros don't store the screen size, skipping --scale-to-screen");
+        }
+        if options.verify.is_some() {
+            warn!("--verify only applies to text macros, ignoring it");
+        }
+
+        for round in 1.. {
+            match &window {
+                Some(events) => player.play(events)?,
+                None => {
+                    // A bad record stops playback there, with its error
+                    let mut error = None;
+                    let events = mapped.iter().map_while(|recorded| recorded.map_err(|e| error = Some(e)).ok());
+                    player.play_iter(events, mapped.duration_us())?;
+                    if let Some(e) = error {
+                        return Err(e.into());
+                    }
+                }
+            }
+            print_timing_report(&player, options);
+
+            if options.loop_forever || round < options.repeat {
+                println!("\nFinished macro, starting again...");
+            } else {
+                break;
+            }
+        }
+
+        return Ok(());
+    }
+
+    let (macro_, layer) = load_for_playback(input_file, options)?;
+    let mix::Layer { events, actions, state_starts_us, .. } = layer;
+
+    println!("Loaded {} events", events.len());
+    check_limits(&guard::Extent::of_playback(&events, &actions), options)?;
+    let needs = playback_needs(&events, &actions, options);
+    println!("Needs: {}", needs);
+    let _layout = check_layout(&macro_.metadata, options.match_layout)?;
+    let mut player = Player::new(playback_backend(options, Some(&needs))?);
+    println!("\nStarting playback in 3 seconds...");
+
+    thread::sleep(Duration::from_secs(3));
+    check_window(&macro_.metadata)?;
+    player.set_source(input_file);
+    player.set_max_rate(options.max_rate);
+    set_recovery(&mut player, options, Some(&needs))?;
+    player.pre_roll(options.pre_roll)?;
+
+    if io::stdin().is_terminal() {
+        player.on_suspend(ask_to_resume);
+    }
+    pause_on_signals(&mut player, macro_.metadata.window.as_ref(), &options.stop)?;
+    if options.progress {
+        let mut bar = ProgressBar::new(macro_.states.len());
+        player.on_progress(move |progress| bar.update(progress));
+    }
+    player.set_state_starts(state_starts_us);
+    options.hooks.fire(HookEvent::Start, input_file, None);
+
+    if options.sync_locks {
+        sync_lock_state(&mut player, &macro_.metadata)?;
+    }
+
+    let mut capture = start_verify(options, &actions)?;
+    for round in 1.. {
+        player.play_with_actions(&events, &actions)?;
+        print_timing_report(&player, options);
+        if let (Some(capture), Some(tolerance_us)) = (capture.take(), options.verify) {
+            finish_verify(capture, &events, tolerance_us)?;
+        }
+
+        if options.loop_forever || round < options.repeat {
+            println!("\nFinished macro, starting again...");
+        } else {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Play several macros at once, each from its own start offset
+fn play_mix(files: &[(u64, &str)], options: &PlayOptions) -> Result<(), Box<dyn Error>> {
+    if files.iter().any(|&(_, file)| file == "-") {
+        return Err("A stream from stdin can't be mixed with other macros".into());
+    }
+    if options.from.is_some() || options.to.is_some() {
+        return Err("--from and --to can't be used when mixing macros".into());
+    }
+
+    println!("EvKey Player");
+    println!("============\n");
+
+    let mut macros = Vec::new();
+    let mut layers = Vec::new();
+    for &(offset_us, file) in files {
+        if !Path::new(file).exists() {
+            return Err(format!("File '{}' not found", file).into());
+        }
+        let (macro_, mut layer) = load_for_playback(file, options)?;
+        println!("  {} events, starting at {}ms", layer.events.len(), offset_us / 1000);
+        layer.offset_us = offset_us;
+        macros.push(macro_);
+        layers.push(layer);
+    }
+    let mix::Mix { events, actions, state_starts_us } = mix::mix(&layers);
+
+    println!("Mixed {} macros into {} events", files.len(), events.len());
+    check_limits(&guard::Extent::of_playback(&events, &actions), options)?;
+    let needs = playback_needs(&events, &actions, options);
+    println!("Needs: {}", needs);
+    // Lock state and layout come from the first macro that records them
+    let metadata = |recorded: fn(&storage::Metadata) -> bool| {
+        macros.iter().map(|m| &m.metadata).find(|metadata| recorded(metadata)).unwrap_or(&macros[0].metadata)
+    };
+    let _layout = check_layout(metadata(|m| m.keyboard.is_some()), options.match_layout)?;
+    let mut player = Player::new(playback_backend(options, Some(&needs))?);
+    println!("\nStarting playback in 3 seconds...");
+
+    thread::sleep(Duration::from_secs(3));
+    // The first macro naming a window decides which one the mix plays into
+    let window = metadata(|m| m.window.is_some());
+    check_window(window)?;
+    player.set_source(&files.iter().map(|&(_, file)| file).collect::<Vec<_>>().join(" + "));
+    player.set_max_rate(options.max_rate);
+    set_recovery(&mut player, options, Some(&needs))?;
+    player.pre_roll(options.pre_roll)?;
+
+    if io::stdin().is_terminal() {
+        player.on_suspend(ask_to_resume);
+    }
+    pause_on_signals(&mut player, window.window.as_ref(), &options.stop)?;
+    if options.progress {
+        let mut bar = ProgressBar::new(state_starts_us.len());
+        player.on_progress(move |progress| bar.update(progress));
+    }
+    player.set_state_starts(state_starts_us);
+    let name = files.iter().map(|&(_, file)| file).collect::<Vec<_>>().join(" + ");
+    options.hooks.fire(HookEvent::Start, &name, None);
+
+    if options.sync_locks {
+        sync_lock_state(&mut player, metadata(|m| m.locks.is_some()))?;
+    }
+
+    let mut capture = start_verify(options, &actions)?;
+    for round in 1.. {
+        player.play_with_actions(&events, &actions)?;
+        print_timing_report(&player, options);
+        if let (Some(capture), Some(tolerance_us)) = (capture.take(), options.verify) {
+            finish_verify(capture, &events, tolerance_us)?;
+        }
+
+        if options.loop_forever || round < options.repeat {
+            println!("\nFinished mix, starting again...");
+        } else {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Refuse to play into the wrong window, or wait for the right one, as a
+/// macro's `# Window:` header says
+fn check_window(metadata: &storage::Metadata) -> Result<(), Box<dyn Error>> {
+    if let Some(window) = &metadata.window {
+        window.before_playing(&mut screen::active_window_title, || false)?;
+    }
+    Ok(())
+}
+
+/// What playing `events` and `actions` takes, with `--sync-locks` tapping
+/// lock keys on top
+fn playback_needs(events: &[RecordedEvent], actions: &[(u64, Action)], options: &PlayOptions) -> Capabilities {
+    let mut needs = Capabilities::of_playback(events, actions);
+    needs.keyboard |= options.sync_locks;
+    needs
+}
+
+/// Load a text macro (or a binary one, as states) and turn it into timed
+/// events, with the track, parameter, --from/--to and adjustment options applied
+fn load_for_playback(input_file: &str, options: &PlayOptions) -> Result<(storage::Macro, mix::Layer), Box<dyn Error>> {
+    println!("Loading macro from {}...", input_file);
+    let session = storage::load_session_with_params(input_file, &options.params)?;
+    let include: Vec<&str> = options.tracks.iter().map(String::as_str).collect();
+    let exclude: Vec<&str> = options.skip_tracks.iter().map(String::as_str).collect();
+    let mut macro_ = session.select(&include, &exclude)?;
+    if options.scale_to_screen {
+        let recorded = macro_.metadata.screen;
+        let current = screen::screen_size()?;
+        match recorded {
+            Some(recorded) if macro_.scale_to_screen(current) => {
+                println!("Scaling from the recorded {} screen to {}", recorded, current)
+            }
+            Some(_) => {}
+            None => warn!("Macro doesn't record its screen size, skipping --scale-to-screen"),
+        }
+    }
+    if let Some(max_ms) = options.humanize_ms {
+        let mut rng = Rng::from_seed_or_clock(options.seed);
+        println!("Humanizing by up to {}ms (seed {})", max_ms, rng.seed());
+        macro_.states = state::humanize(&macro_.states, max_ms, &mut rng);
+    }
+    if let Some(min_ms) = options.min_hold_ms {
+        macro_.states = state::enforce_min_hold(&macro_.states, min_ms);
+    }
+    if options.easing.is_some() {
+        macro_.metadata.easing = options.easing;
+    }
+    if options.scroll_rate_hz.is_some() || options.scroll_hires {
+        let scroll = macro_.metadata.scroll.unwrap_or_default();
+        macro_.metadata.scroll = Some(state::ScrollPacing {
+            rate_hz: options.scroll_rate_hz.unwrap_or(scroll.rate_hz),
+            hires: options.scroll_hires || scroll.hires,
+        });
+    }
+    let mut events = macro_.events();
+    let mut actions = macro_.timed_actions();
+    let mut state_starts_us = macro_.state_starts_us();
+
+    if options.from.is_some() || options.to.is_some() {
+        let from = options.from.as_deref().map(|v| resolve_boundary(v, Some(&macro_))).transpose()?;
+        let to = options.to.as_deref().map(|v| resolve_boundary(v, Some(&macro_))).transpose()?;
+        let from = from.unwrap_or(0);
+        events = state::slice_events(events, from, to);
+        actions.retain(|(at_us, _)| *at_us >= from && to.is_none_or(|to| *at_us < to));
+        for (at_us, _) in &mut actions {
+            *at_us -= from;
+        }
+        for start_us in &mut state_starts_us {
+            *start_us = start_us.saturating_sub(from);
+        }
+    }
+
+    if options.accel_compensate {
+        events = accel::compensate_events(&events, &load_accel_curve()?);
+    }
+
+    let mut layer = mix::Layer {
+        offset_us: 0,
+        events,
+        actions,
+        state_starts_us,
+    };
+    if options.speed != 1.0 {
+        layer.speed_up(options.speed);
+    }
+    Ok((macro_, layer))
+}
+
+/// Play a live event stream from stdin, as written by `evkey record -`
+fn play_stream(options: &PlayOptions) -> Result<(), Box<dyn Error>> {
+    if options.from.is_some() || options.to.is_some() || options.loop_forever || options.repeat > 1 {
+        return Err("--from, --to, --loop and --repeat need a macro file, not a stream".into());
+    }
+    if options.speed != 1.0 || options.humanize_ms.is_some() {
+        return Err("A stream plays as it arrives, --speed and --humanize need a macro file".into());
+    }
+    if options.verify.is_some() {
+        return Err("--verify needs a macro file to compare with, not a stream".into());
+    }
+    if options.limits != guard::Limits::default() {
+        warn!("A stream can't be checked before it's played, ignoring --max-keys and --max-text");
+    }
+
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&options.stop))?;
+    let reader = stream::StreamReader::new(io::stdin().lock())?;
+    let mut player = Player::new(playback_backend(options, None)?);
+    player.set_source("stdin");
+    player.set_max_rate(options.max_rate);
+    set_recovery(&mut player, options, None)?;
+    player.pre_roll(options.pre_roll)?;
+    player.stop_on(Arc::clone(&options.stop));
+    eprintln!("Playing events from stdin as they arrive...");
+
+    let mut error = None;
+    let result = player.play_stream(reader.map_while(|event| event.map_err(|e| error = Some(e)).ok()));
+    // Keys the sender was holding when it stopped (or died) are let go here
+    player.release_all()?;
+    result?;
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+    eprintln!("Stream ended");
+    Ok(())
+}
+
+/// Ask on the terminal whether to carry on with a playback the system
+/// suspended in the middle of
+fn ask_to_resume(suspended: Duration) -> bool {
+    eprint!(
+        "\nThe system was suspended for {}s during playback. Keys were released. Continue? [y/N] ",
+        suspended.as_secs()
+    );
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+/// Print the daemon's status, refreshing every second with `--watch`
+fn show_status(watch: bool) -> Result<(), Box<dyn Error>> {
+    if !watch {
+        print!("{}", daemon::request("status")?);
+        return Ok(());
+    }
+
+    loop {
+        let status = daemon::request("status")?;
+        // Clear the screen and draw from the top-left corner
+        print!("\x1b[2J\x1b[H{}", status);
+        io::Write::flush(&mut io::stdout())?;
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Load the post-processing pipeline recordings go through, if one is set up
+fn load_postprocess() -> Result<Option<postprocess::Pipeline>, Box<dyn Error>> {
+    let Some(path) = postprocess::config_path().filter(|path| path.exists()) else {
+        return Ok(None);
+    };
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let pipeline = postprocess::Registry::builtin()
+        .parse_pipeline(&text)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Some(pipeline).filter(|pipeline| !pipeline.is_empty()))
+}
+
+/// Load the curve saved by `evkey calibrate-accel`
+fn load_accel_curve() -> Result<accel::AccelCurve, Box<dyn Error>> {
+    let path = accel::config_path().ok_or("Can't find the config directory (set HOME or XDG_CONFIG_HOME)")?;
+    let text = std::fs::read_to_string(&path).map_err(|e| {
+        format!("Can't read acceleration calibration {} ({}); run `evkey calibrate-accel` first", path.display(), e)
+    })?;
+    Ok(accel::AccelCurve::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+/// Counts per event at each measured speed; events are sent every 8ms
+const CALIBRATION_STEPS: &[i32] = &[1, 2, 4, 8, 16, 32];
+const CALIBRATION_INTERVAL_US: u64 = 8_000;
+/// Roughly how far (in counts) the pointer is pushed at each speed
+const CALIBRATION_TRAVEL: i32 = 200;
+
+/// Move the pointer at several speeds and save how far it actually went
+fn calibrate_accel(backend: backend::BackendKind) -> Result<(), Box<dyn Error>> {
+    let path = accel::config_path().ok_or("Can't find the config directory (set HOME or XDG_CONFIG_HOME)")?;
+
+    println!("EvKey Acceleration Calibration");
+    println!("==============================\n");
+    println!("Move the pointer to the middle of the screen and leave the mouse alone.");
+    println!("The pointer will move left and right for a few seconds.");
+    println!("\nStarting in 3 seconds...");
+    thread::sleep(Duration::from_secs(3));
+
+    let mut player = Player::new(backend::open(backend, "evkey-playback")?);
+    // Give the desktop a moment to pick up the new device
+    thread::sleep(Duration::from_millis(500));
+
+    let mut points = Vec::new();
+    for &step in CALIBRATION_STEPS {
+        let count = (CALIBRATION_TRAVEL / step).max(6);
+        let mut moved = 0;
+
+        // Out and back, so the pointer stays clear of the screen edges
+        for direction in [1, -1] {
+            let before = screen::pointer_position()?;
+            let moves: Vec<RecordedEvent> = (0..count)
+                .map(|i| RecordedEvent {
+                    timestamp_us: i as u64 * CALIBRATION_INTERVAL_US,
+                    event: InputEvent::new(EventType::RELATIVE.0, 0, step * direction), // REL_X
+                })
+                .collect();
+            player.play(&moves)?;
+            thread::sleep(Duration::from_millis(100));
+            moved += (screen::pointer_position()?.0 - before.0).abs();
+        }
+
+        if moved == 0 {
+            return Err("The pointer didn't move; is playback reaching the desktop?".into());
+        }
+        let speed = f64::from(step) / (CALIBRATION_INTERVAL_US as f64 / 1000.0);
+        let gain = f64::from(moved) / f64::from(2 * step * count);
+        println!("  {:>6.3} counts/ms: gain {:.3}", speed, gain);
+        points.push((speed, gain));
+    }
+
+    let curve = accel::AccelCurve::new(points)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, curve.to_string())?;
+    println!("\nSaved calibration to {}", path.display());
+    println!("Use `evkey play --accel-compensate` to apply it.");
+
+    Ok(())
+}
+
+/// Tap lock keys until the system lock state matches the recording
+/// Warn if the keyboard layout differs from the recorded one, or with
+/// `--match-layout` switch to it until the returned guard is dropped
+fn check_layout(metadata: &storage::Metadata, match_layout: bool) -> Result<Option<layout::Switched>, Box<dyn Error>> {
+    let Some(recorded) = &metadata.keyboard else {
+        if match_layout {
+            println!("Macro doesn't record its keyboard layout, skipping --match-layout");
+        }
+        return Ok(None);
+    };
+    let current = match layout::current() {
+        Ok(current) => current,
+        Err(e) => {
+            warn!("Can't compare with the recorded keyboard layout {}: {}", recorded.describe(), e);
+            return Ok(None);
+        }
+    };
+    if current == *recorded {
+        return Ok(None);
+    }
+    if match_layout {
+        println!("Switching keyboard layout from {} to the recorded {}", current.describe(), recorded.describe());
+        return Ok(Some(layout::Switched::to(recorded)?));
+    }
+    warn!(
+        "Macro was recorded with keyboard layout {} but {} is active, so typed text may come out wrong; \
+         play with --match-layout to switch for the playback",
+        recorded.describe(),
+        current.describe()
+    );
+    Ok(None)
+}
+
+fn sync_lock_state(player: &mut Player, metadata: &storage::Metadata) -> Result<(), Box<dyn Error>> {
+    let Some(target) = metadata.locks else {
+        println!("Macro doesn't record lock state, skipping --sync-locks");
+        return Ok(());
+    };
+
+    let Some(current) = locks::current_lock_state()? else {
+        warn!("No keyboard with lock LEDs found, skipping --sync-locks");
+        return Ok(());
+    };
+
+    for key_code in current.keys_to_reach(&target) {
+        println!("Toggling {} to match recording ({})",
+            keymap::display_name(key_code).unwrap_or_default(),
+            target
+        );
+        player.tap_key(key_code)?;
+    }
+
+    Ok(())
+}
+
+fn upgrade_file(file: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(file).exists() {
+        eprintln!("Error: File '{}' not found", file);
+        return Ok(());
+    }
+
+    match storage::upgrade(file)? {
+        Some(from) => println!(
+            "Upgraded {} from format version {} to {}",
+            file,
+            from,
+            migrations::CURRENT_VERSION
+        ),
+        None => println!("{} is already at the current format version", file),
+    }
+
+    Ok(())
+}
+
+fn inspect_macro(input_file: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let session = storage::load_session(input_file)?;
+    let macro_ = session.merged();
+    let total_ms: u64 = macro_.states.iter().map(|s| s.duration_ms).sum();
+
+    println!("{}", input_file);
+    println!("  States:   {}", macro_.states.len());
+    println!("  Duration: {}ms", total_ms);
+    if let Some(locks) = macro_.metadata.locks {
+        println!("  Locks:    {}", locks);
+    }
+    if let Some(screen) = macro_.metadata.screen {
+        println!("  Screen:   {}", screen);
+    }
+    if let Some(keyboard) = &macro_.metadata.keyboard {
+        println!("  Keyboard: {}", keyboard.describe());
+    }
+    if let Some(window) = &macro_.metadata.window {
+        println!("  Window:   {}{}", window.pattern, if window.wait { " (waits for it)" } else { "" });
+    }
+    let environment = &macro_.metadata.environment;
+    if let Some(evkey) = &environment.evkey {
+        println!("  EvKey:    {}", evkey);
+    }
+    if let Some(kernel) = &environment.kernel {
+        println!("  Kernel:   {}", kernel);
+    }
+    for device in &environment.devices {
+        println!("  Device:   {}", device);
+    }
+    let track_names = session.track_names();
+    if !track_names.is_empty() {
+        println!("  Tracks:   {}", track_names.join(", "));
+    }
+    println!();
+    print_states(&macro_);
+
+    Ok(())
+}
+
+/// List the states with their index and start time, and the markers and
+/// actions between them
+fn print_states(macro_: &storage::Macro) {
+    let mut offset_ms = 0u64;
+    for index in 0..=macro_.states.len() {
+        for marker in macro_.markers.iter().filter(|m| m.index == index) {
+            println!("{:>6}  {:>9}  --- mark {} ---", "", format!("{}ms", offset_ms), marker.name);
+        }
+        for step in macro_.actions.iter().filter(|a| a.index == index) {
+            println!("{:>6}  {:>9}  {}", "", format!("{}ms", offset_ms), step.action);
+        }
+        if let Some(state) = macro_.states.get(index) {
+            println!("{:>6}  {:>9}  {}", index, format!("{}ms", offset_ms), state);
+            offset_ms += state.duration_ms;
+        }
+    }
+}
+
+fn cap_idle_file(
+    input_file: &str,
+    output_file: &str,
+    max_ms: u64,
+    mark: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let mut macro_ = storage::load_macro(input_file)?;
+    let capped = macro_.cap_idle(max_ms, mark);
+    storage::save_macro(output_file, &macro_)?;
+    println!("Capped {} pause(s) longer than {}ms, saved to {}", capped, max_ms, output_file);
+
+    Ok(())
+}
+
+fn autoclick(options: &clicker::ClickOptions, seed: Option<u64>, backend: backend::BackendKind) -> Result<(), Box<dyn Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&stop))?;
+
+    let mut player = Player::new(backend::open(backend, "evkey-clicker")?);
+    let mut rng = Rng::from_seed_or_clock(seed);
+    let mut rhythm = format!("every {}ms", options.interval_ms);
+    if options.jitter_ms > 0 {
+        rhythm.push_str(&format!(" (±{}ms, seed {})", options.jitter_ms, rng.seed()));
+        info!("Jitter seed: {0} (--seed {0} repeats this rhythm)", rng.seed());
+    }
+    match options.while_held {
+        Some(key) => println!("Clicking {} while {} is held, Ctrl+C to quit", rhythm, keymap::display_combo([key])),
+        None => println!("Clicking {}, Ctrl+C to stop", rhythm),
+    }
+
+    let clicks = clicker::run(&mut player, options, &mut rng, &stop)?;
+    println!("\nClicked {} time(s)", clicks);
+    Ok(())
+}
+
+/// Install a custom keymap on top of the built-in key names, read as JSON
+/// from a `.json` file and as TOML otherwise
+fn load_keymap(path: &Path) -> Result<(), Box<dyn Error>> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let custom = if path.extension().is_some_and(|ext| ext == "json") {
+        keymap::CustomKeymap::parse_json(&text)
+    } else {
+        keymap::CustomKeymap::parse(&text)
+    };
+    let custom = custom.map_err(|e| format!("{}: {}", path.display(), e))?;
+    keymap::set_custom(custom);
+    Ok(())
+}
+
+fn compare_playback(
+    input_file: &str,
+    devices: &[&str],
+    tolerance_ms: u64,
+    backend: backend::BackendKind,
+) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+    let expected = storage::load(input_file)?;
+
+    let mut player = Player::new(backend::open(backend, "evkey-playback")?);
+    // Give the desktop a moment to pick up the new device
+    thread::sleep(Duration::from_millis(500));
+
+    // Listed after the playback device exists, so it's recorded too
+    let mut recorder = Recorder::new();
+    recorder.disable_hotkeys();
+    if devices.is_empty() {
+        for device in recorder::find_input_devices()? {
+            if let Err(e) = recorder.add_device(&device.path) {
+                warn!("Not recording {}: {}", device.name, e);
+            }
+        }
+    } else {
+        for device in devices {
+            recorder.add_device(device)?;
+        }
+    }
+
+    println!("Playing {} while recording; keep your hands off the keyboard and mouse...", input_file);
+    let capture = Capture::start(recorder);
+    let result = player.play(&expected);
+    let observed = capture.finish()?;
+    result?;
+
+    let comparison = compare::compare(&expected, &observed, tolerance_ms * 1000);
+    println!("\n{}", comparison);
+    if !comparison.is_faithful() {
+        return Err("Playback wasn't reproduced faithfully".into());
+    }
+    println!("Playback was reproduced faithfully");
+    Ok(())
+}
+
+/// Input recorded on a background thread while a macro plays
+struct Capture {
+    played: Arc<AtomicBool>,
+    recording: thread::JoinHandle<io::Result<Vec<RecordedEvent>>>,
+}
+
+impl Capture {
+    fn start(mut recorder: Recorder) -> Self {
+        recorder.start();
+        let played = Arc::new(AtomicBool::new(false));
+        let recording = {
+            let played = Arc::clone(&played);
+            thread::spawn(move || -> io::Result<Vec<RecordedEvent>> {
+                while !played.load(Ordering::Relaxed) {
+                    recorder.poll()?;
+                    thread::sleep(Duration::from_millis(1));
+                }
+                // Catch events still on their way
+                let settle = std::time::Instant::now();
+                while settle.elapsed() < Duration::from_millis(200) {
+                    recorder.poll()?;
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Ok(recorder.stop())
+            })
+        };
+        Self { played, recording }
+    }
+
+    /// Stop once the playback is over, returning what was recorded
+    fn finish(self) -> Result<Vec<RecordedEvent>, Box<dyn Error>> {
+        self.played.store(true, Ordering::Relaxed);
+        Ok(self.recording.join().map_err(|_| "Recording thread panicked")??)
+    }
+}
+
+/// Name of the device `evkey nkro-test` plays through
+const NKRO_DEVICE: &str = "evkey-nkro-test";
+
+fn nkro_test(devices: &[&str], max_keys: usize, hold: Duration) -> Result<(), Box<dyn Error>> {
+    let mut player = Player::new(backend::open(backend::BackendKind::Uinput, NKRO_DEVICE)?);
+    // Give the desktop a moment to pick up the new device
+    thread::sleep(Duration::from_millis(500));
+
+    let mut recorder = Recorder::new();
+    recorder.disable_hotkeys();
+    if devices.is_empty() {
+        let Some(device) = recorder::find_input_devices()?.into_iter().find(|device| device.name == NKRO_DEVICE) else {
+            return Err(format!("Can't find the {} device to record it", NKRO_DEVICE).into());
+        };
+        recorder.add_device(&device.path)?;
+    } else {
+        for device in devices {
+            recorder.add_device(device)?;
+        }
+    }
+    // Dozens of keys held at once shouldn't reach the desktop
+    recorder.grab_devices()?;
+
+    let poll_for = |recorder: &mut Recorder, duration: Duration| -> io::Result<()> {
+        let start = std::time::Instant::now();
+        while start.elapsed() < duration {
+            recorder.poll()?;
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    };
+
+    println!("Holding 1 to {} keys at once...\n", max_keys);
+    let mut report = nkro::Report::default();
+    for count in 1..=max_keys {
+        let keys = &nkro::KEYS[..count];
+        recorder.start();
+        player.play_instant(&nkro::report(keys, 1))?;
+        poll_for(&mut recorder, hold)?;
+        player.play_instant(&nkro::report(keys, 0))?;
+        // Catch events still on their way
+        poll_for(&mut recorder, Duration::from_millis(100))?;
+        let result = nkro::check(keys, &recorder.stop());
+        println!("  {}", result);
+        report.rounds.push(result);
+    }
+
+    if report.is_faithful() {
+        println!("\nAll {} keys were delivered at once", max_keys);
+        return Ok(());
+    }
+    Err(format!("Only {} keys at once were delivered faithfully", report.rollover()).into())
+}
+
+fn run_proxy(device: &str, report_every: Duration) -> Result<(), Box<dyn Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+
+    println!("Release all keys to start forwarding {}", device);
+    let mut proxy = proxy::Proxy::open(device)?;
+    println!("Forwarding, Ctrl+C to stop\n");
+
+    let mut last_report = std::time::Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        proxy.forward()?;
+        if last_report.elapsed() >= report_every {
+            last_report = std::time::Instant::now();
+            let interval = proxy.take_interval();
+            if interval.count() > 0 {
+                println!("  {}", interval);
+            }
+        }
+    }
+
+    println!("\nTotal: {}", proxy.total);
+    Ok(())
+}
+
+fn run_forward(address: &str, secret: &[u8], devices: &[&str], toggle: keyset::KeySet) -> Result<(), Box<dyn Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+
+    let paths: Vec<std::path::PathBuf> = if devices.is_empty() {
+        recorder::find_input_devices()?.into_iter().map(|device| device.path).collect()
+    } else {
+        devices.iter().map(std::path::PathBuf::from).collect()
+    };
+    let devices = paths
+        .iter()
+        .map(|path| evdev::Device::open(path).map_err(|e| format!("Can't open {}: {}", path.display(), e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if devices.is_empty() {
+        return Err("No keyboard or mouse devices found".into());
+    }
+
+    let toggle_name = keymap::display_combo(toggle.codes());
+    let mut forwarder = forward::Forwarder::connect(address, secret, devices, toggle)?;
+    println!("Forwarding {} device(s) to {}", paths.len(), address);
+    println!("Press {} to switch between this machine and the other, Ctrl+C to stop\n", toggle_name);
+
+    while !stop.load(Ordering::Relaxed) {
+        if forwarder.pump()? {
+            if forwarder.is_active() {
+                println!("Input goes to {}", address);
+            } else {
+                println!("Input stays on this machine");
+            }
+        }
+    }
+    forwarder.finish()?;
+    Ok(())
+}
+
+fn run_receive(address: &str, secret: &[u8], backend: backend::BackendKind) -> Result<(), Box<dyn Error>> {
+    let listener = std::net::TcpListener::bind(address)?;
+    let mut player = Player::new(backend::open(backend, "evkey-forwarded")?);
+    println!("Waiting for `evkey forward` on {}, Ctrl+C to stop", address);
+
+    for socket in listener.incoming() {
+        let socket = socket?;
+        let peer = socket.peer_addr().map_or_else(|_| "unknown".to_string(), |peer| peer.to_string());
+        println!("Connection from {}", peer);
+        match forward::receive(socket, secret, &mut player) {
+            Ok(()) => println!("{} stopped forwarding", peer),
+            Err(e) => eprintln!("Lost {}: {}", peer, e),
+        }
+    }
+    Ok(())
+}
+
+fn compress_macro(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+    if Path::new(output_file).extension().is_none_or(|ext| ext != binary::EXTENSION) {
+        return Err(format!("The output needs to be a .{} file", binary::EXTENSION).into());
+    }
+
+    let events = storage::load(input_file)?;
+    let encoding = binary::Encoding::smallest();
+    binary::save_with(output_file, &events, encoding)?;
+
+    let before = std::fs::metadata(input_file)?.len();
+    let after = std::fs::metadata(output_file)?.len();
+    println!(
+        "Saved {} events {} to {}: {} -> {} bytes ({:.1}x smaller)",
+        events.len(),
+        if encoding == binary::Encoding::Compressed { "compressed" } else { "packed" },
+        output_file,
+        before,
+        after,
+        before as f64 / after.max(1) as f64
+    );
+    Ok(())
+}
+
+fn new_from_template(name: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+    let Some(text) = templates::render(name) else {
+        eprintln!("Error: No template named '{}' (templates: {})", name, templates::NAMES.join(", "));
+        return Ok(());
+    };
+    if Path::new(output_file).exists() {
+        eprintln!("Error: {} already exists", output_file);
+        return Ok(());
+    }
+
+    std::fs::write(output_file, text)?;
+    println!("Created {} from the {} template", output_file, name);
+    println!("Adjust its `param` lines, then play it with `evkey play {}`", output_file);
+    Ok(())
+}
+
+fn anchor_clicks_file(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let mut session = storage::load_session(input_file)?;
+    let anchored: usize = session.tracks.iter_mut().map(|track| track.macro_.anchor_clicks()).sum();
+    if anchored == 0 {
+        println!("No click positions in {} (record with --click-positions)", input_file);
+        return Ok(());
+    }
+    storage::save_session(output_file, &session)?;
+    println!("Anchored {} click(s) to their screen positions, saved to {}", anchored, output_file);
+
+    Ok(())
+}
+
+fn find_loops_file(input_file: &str, output_file: Option<&str>, tolerance_ms: u64) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let mut session = storage::load_session(input_file)?;
+    let mut found = 0;
+    let mut saved = 0;
+    for track in &mut session.tracks {
+        let macro_ = &mut track.macro_;
+        let repeats = macro_.find_loops(tolerance_ms);
+        let starts_us = macro_.state_starts_us();
+        for repeat in &repeats {
+            let body_ms: u64 = macro_.states[repeat.index..repeat.index + repeat.len].iter().map(|s| s.duration_ms).sum();
+            println!(
+                "{}{} state(s) ({:.3}s) done {} times, starting at {:.3}s",
+                if track.name.is_empty() { String::new() } else { format!("[{}] ", track.name) },
+                repeat.len,
+                body_ms as f64 / 1000.0,
+                repeat.times,
+                starts_us[repeat.index] as f64 / 1_000_000.0
+            );
+            saved += repeat.len * (repeat.times - 1);
+        }
+        found += repeats.len();
+        macro_.fold_loops(&repeats);
+    }
+
+    if found == 0 {
+        println!("No repeated stretches in {}", input_file);
+        return Ok(());
+    }
+    match output_file {
+        Some(output_file) => {
+            storage::save_session(output_file, &session)?;
+            println!("Folded {} stretch(es) into repeat blocks, {} fewer states, saved to {}", found, saved, output_file);
+        }
+        None => println!("Run `evkey find-loops {} <output_file>` to fold them into repeat blocks", input_file),
+    }
+
+    Ok(())
+}
+
+/// Shortest stretch of typing `evkey type-text` replaces by default
+const DEFAULT_MIN_CHARS: usize = 3;
+
+fn show_typed_text(input_file: &str, output_file: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let session = storage::load_session(input_file)?;
+    let mut text = String::new();
+    for track in &session.tracks {
+        text.push_str(&track.macro_.typed_text()?);
+    }
+    match output_file {
+        Some(output_file) => {
+            std::fs::write(output_file, &text)?;
+            println!("Saved {} line(s) of typed text to {}", text.lines().count(), output_file);
+        }
+        None if text.is_empty() => println!("{} doesn't type any text", input_file),
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+fn type_text_file(input_file: &str, output_file: &str, min_chars: usize) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let mut session = storage::load_session(input_file)?;
+    let mut replaced = 0;
+    for track in &mut session.tracks {
+        replaced += track.macro_.replace_typing(min_chars)?;
+    }
+    if replaced == 0 {
+        println!("No typing of {} or more characters in {}", min_chars, input_file);
+        return Ok(());
+    }
+    storage::save_session(output_file, &session)?;
+    println!("Replaced {} stretch(es) of typing with 'type' actions, saved to {}", replaced, output_file);
+
+    Ok(())
+}
+
+fn trim_macro(
+    input_file: &str,
+    output_file: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let macro_ = storage::load_macro(input_file)?;
+
+    let find = |name: &str| {
+        macro_
+            .marker(name)
+            .ok_or_else(|| format!("No marker named '{}' in {}", name, input_file))
+    };
+    let from_index = from.map(find).transpose()?.unwrap_or(0);
+    let to_index = to.map(find).transpose()?.unwrap_or(macro_.states.len());
+
+    if from_index > to_index {
+        eprintln!("Error: --from marker comes after --to marker");
+        return Ok(());
+    }
+
+    let trimmed = macro_.slice(from_index, to_index);
+    storage::save_macro(output_file, &trimmed)?;
+    println!("Saved {} of {} states to {}", trimmed.states.len(), macro_.states.len(), output_file);
+
+    Ok(())
+}
+
+fn save_clip(input_file: &str, name: &str, from: Option<&str>, to: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let macro_ = storage::load_macro(input_file)?;
+    let find = |name: &str| {
+        macro_
+            .marker(name)
+            .ok_or_else(|| format!("No marker named '{}' in {}", name, input_file))
+    };
+    let from_index = from.map(find).transpose()?.unwrap_or(0);
+    let to_index = to.map(find).transpose()?.unwrap_or(macro_.states.len());
+    if from_index > to_index {
+        eprintln!("Error: --from marker comes after --to marker");
+        return Ok(());
+    }
+
+    let clip = macro_.clip(from_index, to_index);
+    let path = clips::save(name, &clip)?;
+    println!("Saved {} states as clip '{}' ({})", to_index - from_index, name, path.display());
+    Ok(())
+}
+
+fn insert_clip(name: &str, input_file: &str, output_file: &str, at: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let clip = clips::load(name)?;
+    let mut editor = Editor::new(storage::load_macro(input_file)?);
+    let index = match at {
+        Some(marker) => editor
+            .macro_()
+            .marker(marker)
+            .ok_or_else(|| format!("No marker named '{}' in {}", marker, input_file))?,
+        None => editor.macro_().states.len(),
+    };
+    editor.insert_clip(index, clip)?;
+    storage::save_macro(output_file, editor.macro_())?;
+    println!("Inserted clip '{}' at state {}, saved to {}", name, index, output_file);
+    Ok(())
+}
+
+fn show_stats(input_file: &str, heatmap_file: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let events = storage::load(input_file)?;
+    let stats = stats::analyze(&events);
+
+    println!("{}", input_file);
+    println!("  Duration:     {:.1}s", stats.duration_ms as f64 / 1000.0);
+    println!("  Presses:      {}", stats.total_presses());
+    println!("  Average APM:  {:.0}", stats.average_apm());
+    println!("  Mouse travel: {:.0}px", stats.mouse_travel);
+    println!("  Scroll:       {} detents", stats.scroll_ticks);
+
+    println!("\nTop keys:");
+    for (code, key) in stats.top_keys().into_iter().take(10) {
+        println!("  {:<12} {:>6} presses   avg hold {}ms",
+            keymap::display_name(code).unwrap_or_else(|| format!("KEY_{}", code)),
+            key.presses,
+            key.average_hold_ms()
+        );
+    }
+
+    if stats.actions_per_minute.len() > 1 {
+        println!("\nActions per minute:");
+        let peak = stats.actions_per_minute.iter().copied().max().unwrap_or(1).max(1);
+        for (minute, count) in stats.actions_per_minute.iter().enumerate() {
+            let bar = "#".repeat(count * 40 / peak);
+            println!("  {:>4}m  {:<40} {}", minute, bar, count);
+        }
+    }
+
+    if let Some(heatmap_file) = heatmap_file {
+        let counts = stats.keys.iter().map(|(&code, key)| (code, key.presses)).collect();
+        std::fs::write(heatmap_file, svg::keyboard_heatmap(&counts))?;
+        println!("\nHeatmap written to {}", heatmap_file);
+    }
+
+    Ok(())
+}
+
+fn plot_mouse(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let events = storage::load(input_file)?;
+    let path = stats::mouse_path(&events);
+    std::fs::write(output_file, svg::mouse_path(&path))?;
+
+    println!("Plotted {} mouse reports to {}", path.len() - 1, output_file);
+    Ok(())
+}
+
+fn export_macro(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let events = storage::load(input_file)?;
+    let source = export::to_c_source(&events, input_file);
+    std::fs::write(output_file, source)?;
+
+    println!("Exported {} events to {}", events.len(), output_file);
+    println!("Build it with: cc -o replay {}", output_file);
+
+    Ok(())
+}
+
+fn export_firmware(input_file: &str, output_file: &str, format: &str, keycode: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let macro_ = storage::load_macro(input_file)?;
+    let source = match format {
+        "qmk" => export::to_qmk(&macro_, input_file, keycode),
+        _ => export::to_via(&macro_),
+    }
+    .map_err(|e| format!("Can't export {}: {}", input_file, e))?;
+    std::fs::write(output_file, source)?;
+
+    match format {
+        "qmk" => println!("Exported {} to {}; paste it into process_record_user() in keymap.c", input_file, output_file),
+        _ => println!("Exported {} to {}; add its entry to the macros in a VIA layout file, or paste the quoted text into VIA's macro editor", input_file, output_file),
+    }
+    Ok(())
+}
+
+fn import_dump(input_file: &str, output_file: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(input_file).exists() {
+        eprintln!("Error: File '{}' not found", input_file);
+        return Ok(());
+    }
+
+    let events = evtest::parse(&std::fs::read_to_string(input_file)?)?;
+    storage::save(output_file, &events, &[], &storage::Metadata::default())?;
 
+    println!("Imported {} events to {}", events.len(), output_file);
     Ok(())
 }
@@ -0,0 +1,206 @@
+//! Prometheus metrics for the daemon
+//!
+//! With a `metrics 127.0.0.1:9464` line in its config the daemon answers
+//! `GET /metrics` on that address in the Prometheus text format, so kiosk and
+//! test machines running it can be watched like any other service:
+//!
+//!   evkey_macros_played_total{macro}          playbacks that finished
+//!   evkey_macro_failures_total{macro}         playbacks that failed
+//!   evkey_triggers_total{trigger,outcome}     triggers by what became of
+//!                                             them: started, queued, skipped
+//!   evkey_injection_latency_seconds           how late each event went out
+//!                                             compared to the macro (histogram)
+//!   evkey_macros_running                      playbacks in progress
+//!   evkey_devices_online{kind}                keyboards (gamepads too) and
+//!                                             mice being watched
+//!
+//! Counters start from zero when the daemon does.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// Upper bounds of the latency histogram's buckets (in microseconds)
+pub const LATENCY_BUCKETS_US: &[u64] = &[100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000];
+
+/// Events sorted into [`LATENCY_BUCKETS_US`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Events in each bucket, not counting the ones below it
+    buckets: [u64; LATENCY_BUCKETS_US.len()],
+    count: u64,
+    sum_us: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value_us: u64) {
+        if let Some(bucket) = LATENCY_BUCKETS_US.iter().position(|&bound| value_us <= bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum_us += value_us;
+    }
+}
+
+/// What the daemon has done since it started, and what it's doing now
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Finished playbacks by macro file
+    pub played: BTreeMap<String, u64>,
+    /// Failed playbacks by macro file
+    pub failed: BTreeMap<String, u64>,
+    /// Triggers by name and outcome
+    pub triggers: BTreeMap<(String, &'static str), u64>,
+    /// Lateness of every event played
+    pub latency: Histogram,
+    pub running: usize,
+    /// Devices watched, by kind
+    pub devices: Vec<(&'static str, usize)>,
+}
+
+impl Metrics {
+    pub fn played(&mut self, macro_file: &str) {
+        *self.played.entry(macro_file.to_string()).or_default() += 1;
+    }
+
+    pub fn failed(&mut self, macro_file: &str) {
+        *self.failed.entry(macro_file.to_string()).or_default() += 1;
+    }
+
+    /// A trigger went off and was `outcome` ("started", "queued" or "skipped")
+    pub fn triggered(&mut self, trigger: &str, outcome: &'static str) {
+        *self.triggers.entry((trigger.to_string(), outcome)).or_default() += 1;
+    }
+}
+
+/// A label value with `\`, `"` and newlines escaped
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(f: &mut fmt::Formatter, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(f, "# HELP {} {}", name, help)?;
+    writeln!(f, "# TYPE {} {}", name, kind)
+}
+
+impl fmt::Display for Metrics {
+    /// The Prometheus text exposition format
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        header(f, "evkey_macros_played_total", "counter", "Macro playbacks that finished.")?;
+        for (macro_file, count) in &self.played {
+            writeln!(f, "evkey_macros_played_total{{macro=\"{}\"}} {}", escape(macro_file), count)?;
+        }
+        header(f, "evkey_macro_failures_total", "counter", "Macro playbacks that failed.")?;
+        for (macro_file, count) in &self.failed {
+            writeln!(f, "evkey_macro_failures_total{{macro=\"{}\"}} {}", escape(macro_file), count)?;
+        }
+        header(f, "evkey_triggers_total", "counter", "Triggers that went off, by outcome.")?;
+        for ((trigger, outcome), count) in &self.triggers {
+            writeln!(f, "evkey_triggers_total{{trigger=\"{}\",outcome=\"{}\"}} {}", escape(trigger), outcome, count)?;
+        }
+
+        header(
+            f,
+            "evkey_injection_latency_seconds",
+            "histogram",
+            "How late events were injected compared to the macro's timing.",
+        )?;
+        let mut cumulative = 0;
+        for (bound_us, count) in LATENCY_BUCKETS_US.iter().zip(self.latency.buckets) {
+            cumulative += count;
+            writeln!(
+                f,
+                "evkey_injection_latency_seconds_bucket{{le=\"{}\"}} {}",
+                *bound_us as f64 / 1_000_000.0,
+                cumulative
+            )?;
+        }
+        writeln!(f, "evkey_injection_latency_seconds_bucket{{le=\"+Inf\"}} {}", self.latency.count)?;
+        writeln!(f, "evkey_injection_latency_seconds_sum {}", self.latency.sum_us as f64 / 1_000_000.0)?;
+        writeln!(f, "evkey_injection_latency_seconds_count {}", self.latency.count)?;
+
+        header(f, "evkey_macros_running", "gauge", "Macros playing right now.")?;
+        writeln!(f, "evkey_macros_running {}", self.running)?;
+        header(f, "evkey_devices_online", "gauge", "Input devices the daemon is watching.")?;
+        for (kind, count) in &self.devices {
+            writeln!(f, "evkey_devices_online{{kind=\"{}\"}} {}", kind, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Most bytes of a request read before answering it
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// Answer one HTTP request read from `request` on `response`: `body` for
+/// `GET /metrics`, 404 for anything else
+///
+/// Reading stops after [`MAX_REQUEST_BYTES`]; how long it may take is up to
+/// the caller.
+pub fn respond(request: impl BufRead, mut response: impl Write, body: impl FnOnce() -> String) -> io::Result<()> {
+    let mut reader = request.take(MAX_REQUEST_BYTES);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Read the headers, so closing doesn't reset the connection
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && !line.trim().is_empty() {
+        line.clear();
+    }
+
+    let (status, body) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", ..] => ("200 OK", body()),
+        _ => ("404 Not Found", "Not found; metrics are at /metrics\n".to_string()),
+    };
+    write!(
+        response,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let mut metrics = Metrics::default();
+        metrics.played("farm.macro");
+        metrics.played("farm.macro");
+        metrics.failed("say \"hi\".macro");
+        metrics.triggered("CTRL+F5", "started");
+        metrics.triggered("CTRL+F5", "skipped");
+        for late_us in [50, 300, 300, 200_000] {
+            metrics.latency.observe(late_us);
+        }
+        metrics.running = 1;
+        metrics.devices = vec![("keyboard", 2), ("pointer", 1)];
+
+        let text = metrics.to_string();
+        assert!(text.contains("evkey_macros_played_total{macro=\"farm.macro\"} 2\n"));
+        assert!(text.contains("evkey_macro_failures_total{macro=\"say \\\"hi\\\".macro\"} 1\n"));
+        assert!(text.contains("evkey_triggers_total{trigger=\"CTRL+F5\",outcome=\"skipped\"} 1\n"));
+        // Buckets count everything up to their bound
+        assert!(text.contains("evkey_injection_latency_seconds_bucket{le=\"0.0001\"} 1\n"));
+        assert!(text.contains("evkey_injection_latency_seconds_bucket{le=\"0.0005\"} 3\n"));
+        assert!(text.contains("evkey_injection_latency_seconds_bucket{le=\"0.1\"} 3\n"));
+        assert!(text.contains("evkey_injection_latency_seconds_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("evkey_injection_latency_seconds_sum 0.20065\n"));
+        assert!(text.contains("evkey_devices_online{kind=\"pointer\"} 1\n"));
+
+        let request: &[u8] = b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut response = Vec::new();
+        respond(request, &mut response, || "evkey_macros_running 0\n".to_string()).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.contains("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nevkey_macros_running 0\n"));
+
+        // A header that never ends is only read so far
+        let endless = [b"GET /metrics HTTP/1.1\r\nX: ".as_slice(), &[b'a'; 1 << 20]].concat();
+        let mut response = Vec::new();
+        respond(endless.as_slice(), &mut response, String::new).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+}
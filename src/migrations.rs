@@ -0,0 +1,172 @@
+//! Format versioning for macro files
+//!
+//! Every saved macro records the DSL version it was written with in its
+//! header (`# Version: N`). When the DSL changes, bump `CURRENT_VERSION` and
+//! append a step to `MIGRATIONS` that rewrites lines from the previous
+//! version, so macros saved by older EvKey releases keep loading.
+
+/// Version written by this build
+pub const CURRENT_VERSION: u32 = 13;
+
+/// Files saved before the version header existed
+pub const UNVERSIONED: u32 = 0;
+
+/// A migration rewrites the lines of a file from one version to the next
+type Migration = fn(Vec<String>) -> Result<Vec<String>, String>;
+
+/// Migration steps, indexed by the version they upgrade from
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1, migrate_v1_to_v2, migrate_v2_to_v3, migrate_v3_to_v4, migrate_v4_to_v5, migrate_v5_to_v6, migrate_v6_to_v7, migrate_v7_to_v8, migrate_v8_to_v9, migrate_v9_to_v10, migrate_v10_to_v11, migrate_v11_to_v12, migrate_v12_to_v13];
+
+/// Read the format version from a header line like `# Version: 1`
+pub fn parse_version_line(line: &str) -> Option<Result<u32, String>> {
+    let rest = line.trim().strip_prefix('#')?.trim_start();
+    let value = rest.strip_prefix("Version:")?.trim();
+
+    Some(
+        value
+            .parse()
+            .map_err(|_| format!("Invalid format version: {}", value)),
+    )
+}
+
+/// Detect the format version declared in the leading comment block
+pub fn detect_version(lines: &[String]) -> Result<u32, String> {
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if !trimmed.starts_with('#') {
+            // Header ended without declaring a version
+            break;
+        }
+        if let Some(version) = parse_version_line(trimmed) {
+            return version;
+        }
+    }
+
+    Ok(UNVERSIONED)
+}
+
+/// Upgrade file lines from `from` to `CURRENT_VERSION`
+pub fn migrate(mut lines: Vec<String>, from: u32) -> Result<Vec<String>, String> {
+    if from > CURRENT_VERSION {
+        return Err(format!(
+            "Macro uses format version {}, but this EvKey only supports up to version {}. \
+             Please upgrade EvKey.",
+            from, CURRENT_VERSION
+        ));
+    }
+
+    for step in &MIGRATIONS[from as usize..CURRENT_VERSION as usize] {
+        lines = step(lines)?;
+    }
+
+    Ok(lines)
+}
+
+/// Version 0 (unversioned) files use the same commands as version 1; the
+/// only difference is the missing header, which the loader doesn't require.
+fn migrate_v0_to_v1(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 2 added `mark` lines; version 1 files need no changes, but older
+/// EvKey builds must not try to read files that contain markers.
+fn migrate_v1_to_v2(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 3 added action lines (`wait pixel`, `clipboard`, `paste`); like
+/// markers, they only need keeping away from older builds.
+fn migrate_v2_to_v3(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 4 added `track` lines splitting a recording by device.
+fn migrate_v3_to_v4(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 5 added the `move to` action, which older builds read as a broken
+/// relative move.
+fn migrate_v4_to_v5(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 6 added `at X Y` lines recording where the pointer was for a click.
+fn migrate_v5_to_v6(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 7 added the `wait text` action.
+fn migrate_v6_to_v7(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 8 added `param` lines, filled in wherever `$name` appears.
+fn migrate_v7_to_v8(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 9 writes keys without a name by number (`KEY_183`), which older
+/// builds dropped instead.
+fn migrate_v8_to_v9(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 10 added the `type` action.
+fn migrate_v9_to_v10(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 11 added the `snippet` action.
+fn migrate_v10_to_v11(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 12 added `over` and easing curves to `move`, and the `# Easing`
+/// header.
+fn migrate_v11_to_v12(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+/// Version 13 added `repeat` blocks.
+fn migrate_v12_to_v13(lines: Vec<String>) -> Result<Vec<String>, String> {
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_detect_version() {
+        let versioned = lines("# EvKey Macro\n# Version: 1\n\nwait 10ms");
+        assert_eq!(detect_version(&versioned), Ok(1));
+
+        let legacy = lines("# EvKey Macro\n# Layout: QWERTY\n\nwait 10ms");
+        assert_eq!(detect_version(&legacy), Ok(UNVERSIONED));
+    }
+
+    #[test]
+    fn test_version_after_header_ignored() {
+        let text = lines("wait 10ms\n# Version: 7");
+        assert_eq!(detect_version(&text), Ok(UNVERSIONED));
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let legacy = lines("hold W for 10ms");
+        assert_eq!(migrate(legacy.clone(), UNVERSIONED).unwrap(), legacy);
+    }
+
+    #[test]
+    fn test_rejects_newer_version() {
+        assert!(migrate(Vec::new(), CURRENT_VERSION + 1).is_err());
+    }
+}
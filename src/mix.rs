@@ -0,0 +1,173 @@
+//! Playing several macros at once
+//!
+//! `evkey play --at 0s walk.macro --at 2.5s abilities.macro` mixes macros into
+//! one playback, each starting at its own offset. Their events are merged by
+//! time. A key that more than one macro holds is pressed when the first one
+//! presses it and released when the last one lets go, so one macro can't cut
+//! another's key short.
+
+use crate::action::Action;
+use crate::event::{EventType, RecordedEvent};
+use std::collections::HashMap;
+
+/// One macro in a mix, already turned into timed events
+#[derive(Debug, Clone, Default)]
+pub struct Layer {
+    /// When the macro starts, from the start of the mix (in microseconds)
+    pub offset_us: u64,
+    pub events: Vec<RecordedEvent>,
+    pub actions: Vec<(u64, Action)>,
+    /// Start of each state (in microseconds), for progress and timing reports
+    pub state_starts_us: Vec<u64>,
+}
+
+impl Layer {
+    /// Play `factor` times as fast (or slower, below 1)
+    pub fn speed_up(&mut self, factor: f64) {
+        let scale = |at_us: u64| (at_us as f64 / factor).round() as u64;
+        for event in &mut self.events {
+            event.timestamp_us = scale(event.timestamp_us);
+        }
+        for (at_us, _) in &mut self.actions {
+            *at_us = scale(*at_us);
+        }
+        for start_us in &mut self.state_starts_us {
+            *start_us = scale(*start_us);
+        }
+    }
+}
+
+/// Layers merged into a single playback
+#[derive(Debug, Clone, Default)]
+pub struct Mix {
+    pub events: Vec<RecordedEvent>,
+    pub actions: Vec<(u64, Action)>,
+    pub state_starts_us: Vec<u64>,
+}
+
+/// Merge `layers` by time, counting key presses across them
+pub fn mix(layers: &[Layer]) -> Mix {
+    let mut events = Vec::new();
+    let mut actions = Vec::new();
+    let mut state_starts_us = Vec::new();
+    for layer in layers {
+        events.extend(layer.events.iter().map(|event| RecordedEvent {
+            timestamp_us: event.timestamp_us + layer.offset_us,
+            event: event.event,
+        }));
+        actions.extend(layer.actions.iter().map(|(at_us, action)| (at_us + layer.offset_us, action.clone())));
+        state_starts_us.extend(layer.state_starts_us.iter().map(|start_us| start_us + layer.offset_us));
+    }
+
+    // Stable sorts, so things at the same time keep their layer order
+    events.sort_by_key(|event| event.timestamp_us);
+    actions.sort_by_key(|(at_us, _)| *at_us);
+    state_starts_us.sort_unstable();
+
+    let mut held: HashMap<u16, u32> = HashMap::new();
+    events.retain(|event| {
+        if event.event.event_type() != EventType::KEY {
+            return true;
+        }
+        let count = held.entry(event.event.code()).or_default();
+        match event.event.value() {
+            1 => {
+                *count += 1;
+                *count == 1
+            }
+            // A release nobody pressed still goes out, it can't hurt
+            0 if *count == 0 => true,
+            0 => {
+                *count -= 1;
+                *count == 0
+            }
+            // Auto-repeat, only while some layer holds the key
+            _ => *count > 0,
+        }
+    });
+
+    Mix {
+        events,
+        actions,
+        state_starts_us,
+    }
+}
+
+/// Parse a start offset like "500ms", "2s" or "2.5s" to microseconds
+pub fn parse_offset(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let invalid = || format!("Invalid offset '{}' (expected e.g. 500ms, 2s or 2.5s)", s);
+
+    if let Some(ms) = s.strip_suffix("ms") {
+        return ms.parse::<u64>().map(|ms| ms * 1000).map_err(|_| invalid());
+    }
+    let secs = s.strip_suffix('s').ok_or_else(invalid)?;
+    let (whole, fraction) = secs.split_once('.').unwrap_or((secs, ""));
+    if fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let whole: u64 = whole.parse().map_err(|_| invalid())?;
+    let fraction: u64 = format!("{:0<6}", fraction).parse().map_err(|_| invalid())?;
+    Ok(whole * 1_000_000 + fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::key;
+
+    #[test]
+    fn test_mix_counts_shared_keys() {
+        // Both layers hold W; the first lets go while the second still holds it
+        let walk = Layer {
+            offset_us: 0,
+            events: vec![key(0, 17, 1), key(3_000_000, 17, 0)],
+            ..Default::default()
+        };
+        let abilities = Layer {
+            offset_us: 2_500_000,
+            events: vec![key(0, 17, 1), key(0, 30, 1), key(100_000, 30, 0), key(1_000_000, 17, 0)],
+            actions: vec![(100_000, Action::Type("go".into()))],
+            state_starts_us: vec![0, 100_000],
+        };
+
+        let mixed = mix(&[walk, abilities]);
+        let keys: Vec<(u64, u16, i32)> = mixed
+            .events
+            .iter()
+            .map(|e| (e.timestamp_us, e.event.code(), e.event.value()))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![(0, 17, 1), (2_500_000, 30, 1), (2_600_000, 30, 0), (3_500_000, 17, 0)]
+        );
+        assert_eq!(mixed.actions[0].0, 2_600_000);
+        assert_eq!(mixed.state_starts_us, vec![2_500_000, 2_600_000]);
+    }
+
+    #[test]
+    fn test_parse_offset() {
+        assert_eq!(parse_offset("0s"), Ok(0));
+        assert_eq!(parse_offset("2.5s"), Ok(2_500_000));
+        assert_eq!(parse_offset("250ms"), Ok(250_000));
+        assert!(parse_offset("2.5").is_err());
+        assert!(parse_offset("-1s").is_err());
+    }
+
+    #[test]
+    fn test_speed_up() {
+        let mut layer = Layer {
+            offset_us: 0,
+            events: vec![key(0, 30, 1), key(100_000, 30, 0)],
+            actions: vec![(300_000, Action::Type("go".into()))],
+            state_starts_us: vec![0, 100_000],
+        };
+
+        layer.speed_up(2.0);
+        assert_eq!(layer.events[1].timestamp_us, 50_000);
+        assert_eq!(layer.actions[0].0, 150_000);
+        assert_eq!(layer.state_starts_us, [0, 50_000]);
+        layer.speed_up(0.5);
+        assert_eq!(layer.events[1].timestamp_us, 100_000);
+    }
+}
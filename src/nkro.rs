@@ -0,0 +1,162 @@
+//! N-key rollover testing
+//!
+//! `evkey nkro-test` holds down one key, then two, then more and more at once
+//! through a virtual device, records what comes out the other end and checks
+//! how many of them were seen down together. Remappers, VMs and KVM switches
+//! often pass on only a handful of keys at a time; the first round that comes
+//! out short shows where the limit is.
+//!
+//! Each round presses all its keys in one report and releases them in
+//! another, the way a keyboard reports keys that go down together.
+
+use crate::event::{EventType, InputEvent, RecordedEvent};
+use crate::input_key::InputKey;
+use crate::keyset::KeySet;
+use std::fmt;
+
+/// Keys pressed, in this order: the digit row, the letters and the keypad
+pub const KEYS: &[u16] = &[
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 11, // 1-0
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, // Q-P
+    30, 31, 32, 33, 34, 35, 36, 37, 38, // A-L
+    44, 45, 46, 47, 48, 49, 50, // Z-M
+    71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, // keypad
+];
+
+/// One report setting every key in `keys` to `value` (1 presses, 0 releases)
+pub fn report(keys: &[u16], value: i32) -> Vec<RecordedEvent> {
+    keys.iter()
+        .map(|&code| InputEvent::new(EventType::KEY.0, code, value))
+        .chain([InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)])
+        .map(|event| RecordedEvent { timestamp_us: 0, event })
+        .collect()
+}
+
+/// What came out of a round
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundResult {
+    /// Keys pressed in the round
+    pub pressed: usize,
+    /// Most of them seen down at the same time
+    pub held_at_once: usize,
+    /// Keys that never came through pressed
+    pub missing: Vec<u16>,
+    /// Keys still down when the round ended
+    pub stuck: Vec<u16>,
+}
+
+impl RoundResult {
+    /// Whether all keys were down together and came back up
+    pub fn is_faithful(&self) -> bool {
+        self.held_at_once == self.pressed && self.stuck.is_empty()
+    }
+}
+
+impl fmt::Display for RoundResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>2} keys: ", self.pressed)?;
+        if self.is_faithful() {
+            return write!(f, "all delivered");
+        }
+        write!(f, "{} held at once", self.held_at_once)?;
+        if !self.missing.is_empty() {
+            write!(f, ", missing {}", names(&self.missing))?;
+        }
+        if !self.stuck.is_empty() {
+            write!(f, ", stuck {}", names(&self.stuck))?;
+        }
+        Ok(())
+    }
+}
+
+fn names(codes: &[u16]) -> String {
+    codes.iter().map(|&code| InputKey::from(code).to_string()).collect::<Vec<_>>().join(" ")
+}
+
+/// Line up the events recorded during a round with the `keys` it pressed
+///
+/// Other keys (someone typing, a remapper's own output) are left out.
+pub fn check(keys: &[u16], observed: &[RecordedEvent]) -> RoundResult {
+    let wanted: KeySet = keys.iter().copied().collect();
+    let mut held = KeySet::new();
+    let mut seen = KeySet::new();
+    let mut held_at_once = 0;
+
+    for recorded in observed {
+        let event = recorded.event;
+        if event.event_type() != EventType::KEY || !wanted.contains(event.code()) {
+            continue;
+        }
+        match event.value() {
+            1 => {
+                held.insert(event.code());
+                seen.insert(event.code());
+                held_at_once = held_at_once.max(held.len());
+            }
+            0 => {
+                held.remove(event.code());
+            }
+            _ => {}
+        }
+    }
+
+    RoundResult {
+        pressed: keys.len(),
+        held_at_once,
+        missing: keys.iter().copied().filter(|&code| !seen.contains(code)).collect(),
+        stuck: held.codes().collect(),
+    }
+}
+
+/// Results of all rounds, fewest keys first
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub rounds: Vec<RoundResult>,
+}
+
+impl Report {
+    /// Most keys delivered together before the first round that came out
+    /// short
+    pub fn rollover(&self) -> usize {
+        self.rounds
+            .iter()
+            .take_while(|round| round.is_faithful())
+            .last()
+            .map_or(0, |round| round.pressed)
+    }
+
+    pub fn is_faithful(&self) -> bool {
+        self.rounds.iter().all(RoundResult::is_faithful)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let keys = &KEYS[..4];
+        assert_eq!(report(keys, 1).len(), 5);
+
+        // Only 1, 2 and 3 get through, and 3 is never let go
+        let mut observed = report(&keys[..3], 1);
+        observed.extend(report(&keys[..2], 0));
+        let result = check(keys, &observed);
+        assert_eq!(result.held_at_once, 3);
+        assert_eq!(result.missing, vec![5]);
+        assert_eq!(result.stuck, vec![4]);
+        assert_eq!(result.to_string(), " 4 keys: 3 held at once, missing 4, stuck 3");
+
+        let mut observed = report(keys, 1);
+        observed.extend(report(keys, 0));
+        let faithful = check(keys, &observed);
+        assert!(faithful.is_faithful());
+
+        let report = Report {
+            rounds: vec![check(&keys[..3], &observed), result, faithful],
+        };
+        assert_eq!(report.rollover(), 3);
+        assert!(!report.is_faithful());
+    }
+}
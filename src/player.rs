@@ -1,39 +1,445 @@
 //! Playing back recorded events
+//!
+//! If the system suspends mid-playback, the player notices when it resumes
+//! (the monotonic clock it paces events with stops while suspended, the boot
+//! clock doesn't), releases the keys it holds and stops, unless an
+//! [`Player::on_suspend`] callback says to carry on.
+//!
+//! [`Player::pause_while`] holds playback, with its keys let go, for as long
+//! as a callback says so, e.g. after a signal asked for a pause.
+//!
+//! When the backend fails to take an event (a uinput write error, a
+//! compositor going away), playback stops with the error unless
+//! [`Player::set_recovery`] says to skip the event or to retry it, on a
+//! backend opened again by [`Player::reopen_with`] if there is one.
+//!
+//! Events are paced by a [`Clock`], real time unless [`Player::set_clock`]
+//! says otherwise (see [`crate::testing`]). [`Player::set_max_rate`] holds
+//! them back further for applications that can't take the recorded density
+//! (see [`crate::throttle`]).
 
-use crate::recorder::RecordedEvent;
-use evdev::{uinput::VirtualDevice, AttributeSet, KeyCode, RelativeAxisCode};
+use crate::event::RecordedEvent;
+use crate::action::Action;
+use crate::backend::Backend;
+use crate::clock::{Clock, SystemClock};
+use crate::event::{EventType, InputEvent};
+use crate::keyset::KeySet;
+use crate::throttle::{EventRate, Throttle};
+use std::fmt;
 use std::io;
-use std::thread;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use nix::time::{ClockId, clock_gettime};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, warn};
+
+/// How often progress is reported while waiting between events
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// How often the stop flag is checked while waiting between events
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Late states listed in a timing report before the rest are summarized
+const REPORT_LIMIT: usize = 20;
+/// Pre-roll `evkey play` gives a new device before its first event
+pub const DEFAULT_PRE_ROLL: Duration = Duration::from_millis(50);
+/// How far the boot clock may run ahead of the monotonic clock before the
+/// system counts as having been suspended
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(2);
+/// Times [`Recovery::Retry`] tries an event again before giving up
+const RETRY_ATTEMPTS: u32 = 5;
+/// Wait before each retry, for a device or compositor to come back
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What playback does when the backend fails to take an event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Recovery {
+    /// Stop with the error
+    #[default]
+    Abort,
+    /// Leave the event out and carry on
+    Skip,
+    /// Wait, open the backend again, press the held keys on it again and
+    /// send the event again, giving up after a few tries
+    Retry,
+}
+
+impl FromStr for Recovery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(Recovery::Abort),
+            "skip" => Ok(Recovery::Skip),
+            "retry" => Ok(Recovery::Retry),
+            _ => Err(format!("Unknown recovery '{}', use retry/skip/abort", s)),
+        }
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recovery::Abort => write!(f, "abort"),
+            Recovery::Skip => write!(f, "skip"),
+            Recovery::Retry => write!(f, "retry"),
+        }
+    }
+}
+
+/// Playback position, as reported to progress callbacks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Position in the macro's timeline (in microseconds)
+    pub position_us: u64,
+    /// Length of the macro (in microseconds)
+    pub total_us: u64,
+    /// State being played, if the player was given the macro's state times
+    pub state_index: Option<usize>,
+    /// Wall-clock time since playback started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Portion of the macro played so far, from 0.0 to 1.0
+    pub fn fraction(&self) -> f64 {
+        if self.total_us == 0 {
+            return 1.0;
+        }
+        (self.position_us as f64 / self.total_us as f64).min(1.0)
+    }
+
+    /// Estimated time left, not counting time spent in blocking actions
+    pub fn remaining(&self) -> Duration {
+        Duration::from_micros(self.total_us.saturating_sub(self.position_us))
+    }
+
+    /// Whether playback reached the end of the macro
+    pub fn is_done(&self) -> bool {
+        self.position_us >= self.total_us
+    }
+}
+
+/// How late the events of one state went out, compared to the macro's timing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StateTiming {
+    /// State the events belong to, if the player was given the macro's state times
+    pub state_index: Option<usize>,
+    pub events: usize,
+    /// Latest event (in microseconds)
+    pub max_late_us: u64,
+    /// Lateness of all events added up (in microseconds)
+    pub total_late_us: u64,
+}
+
+impl StateTiming {
+    /// Average lateness (in microseconds)
+    pub fn mean_late_us(&self) -> u64 {
+        self.total_late_us.checked_div(self.events as u64).unwrap_or(0)
+    }
+}
+
+/// Intended against actual emission times of the last playback, per state
+///
+/// Each event's intended time is the playback start plus its timestamp,
+/// plus time spent in blocking actions and suspend prompts before it, so
+/// drift accumulating over the macro shows up as well as single late events.
+#[derive(Debug, Clone, Default)]
+pub struct TimingReport {
+    pub states: Vec<StateTiming>,
+    /// Lateness beyond which a state counts as late (in microseconds)
+    pub tolerance_us: u64,
+}
+
+impl TimingReport {
+    fn record(&mut self, state_index: Option<usize>, late_us: u64) {
+        let state = match self.states.last_mut() {
+            Some(state) if state.state_index == state_index => state,
+            _ => {
+                self.states.push(StateTiming {
+                    state_index,
+                    ..StateTiming::default()
+                });
+                self.states.last_mut().unwrap()
+            }
+        };
+        state.events += 1;
+        state.max_late_us = state.max_late_us.max(late_us);
+        state.total_late_us += late_us;
+    }
+
+    /// Number of events played
+    pub fn events(&self) -> usize {
+        self.states.iter().map(|state| state.events).sum()
+    }
+
+    /// Latest event of the whole playback (in microseconds)
+    pub fn max_late_us(&self) -> u64 {
+        self.states.iter().map(|state| state.max_late_us).max().unwrap_or(0)
+    }
+
+    /// Average lateness over all events (in microseconds)
+    pub fn mean_late_us(&self) -> u64 {
+        let total: u64 = self.states.iter().map(|state| state.total_late_us).sum();
+        total.checked_div(self.events() as u64).unwrap_or(0)
+    }
+
+    /// States with an event later than the tolerance
+    pub fn late_states(&self) -> impl Iterator<Item = &StateTiming> {
+        self.states.iter().filter(|state| state.max_late_us > self.tolerance_us)
+    }
+
+    /// Whether every event went out within the tolerance
+    pub fn is_faithful(&self) -> bool {
+        self.late_states().next().is_none()
+    }
+}
+
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |us: u64| format!("{:.2}ms", us as f64 / 1000.0);
+        let late: Vec<&StateTiming> = self.late_states().collect();
+        writeln!(f, "Events:      {}", self.events())?;
+        writeln!(f, "Lateness:    {} average, {} worst", ms(self.mean_late_us()), ms(self.max_late_us()))?;
+        writeln!(f, "Late states: {} of {} beyond {}", late.len(), self.states.len(), ms(self.tolerance_us))?;
+
+        for state in late.iter().take(REPORT_LIMIT) {
+            let name = state.state_index.map_or("before the first state".to_string(), |i| format!("state {}", i + 1));
+            writeln!(
+                f,
+                "  {:<10} {} worst, {} average over {} event(s)",
+                name,
+                ms(state.max_late_us),
+                ms(state.mean_late_us()),
+                state.events
+            )?;
+        }
+        if late.len() > REPORT_LIMIT {
+            writeln!(f, "  ... and {} more", late.len() - REPORT_LIMIT)?;
+        }
+        Ok(())
+    }
+}
+
+/// Callback receiving playback progress
+pub type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+/// Callback receiving how late each event went out (in microseconds)
+pub type LatenessCallback = Box<dyn FnMut(u64)>;
+
+/// Callback deciding whether to carry on after the system was suspended for
+/// the given time
+pub type SuspendCallback = Box<dyn FnMut(Duration) -> bool>;
+
+/// Callback saying whether playback should be paused right now
+pub type PauseCallback = Box<dyn FnMut() -> bool>;
+
+/// Callback opening the backend again after it failed
+pub type ReopenCallback = Box<dyn FnMut() -> io::Result<Box<dyn Backend>>>;
+
+/// Notices system suspends between calls to [`SuspendWatch::check`]
+struct SuspendWatch {
+    monotonic: Instant,
+    boot: Duration,
+}
+
+impl SuspendWatch {
+    fn new() -> Self {
+        Self {
+            monotonic: Instant::now(),
+            boot: boot_time(),
+        }
+    }
+
+    /// How long the system was suspended since the last check, if it was
+    fn check(&mut self) -> Option<Duration> {
+        let (monotonic, boot) = (Instant::now(), boot_time());
+        let suspended = boot
+            .saturating_sub(self.boot)
+            .saturating_sub(monotonic - self.monotonic);
+        self.monotonic = monotonic;
+        self.boot = boot;
+        (suspended > SUSPEND_THRESHOLD).then_some(suspended)
+    }
+}
+
+/// Time since boot, including time spent suspended
+fn boot_time() -> Duration {
+    clock_gettime(ClockId::CLOCK_BOOTTIME).map_or(Duration::ZERO, Duration::from)
+}
 
 pub struct Player {
-    device: VirtualDevice,
+    backend: Box<dyn Backend>,
+    clock: Box<dyn Clock>,
+    progress: Option<ProgressCallback>,
+    /// Start time of each state (in microseconds), for `Progress::state_index`
+    state_starts_us: Vec<u64>,
+    /// Set from another thread to stop playback early
+    stop: Option<Arc<AtomicBool>>,
+    /// Keys (and buttons) currently pressed by the player
+    held: KeySet,
+    suspend: Option<SuspendCallback>,
+    suspend_watch: SuspendWatch,
+    pause: Option<PauseCallback>,
+    /// Lateness of the events of the current (or last) playback
+    timing: TimingReport,
+    lateness: Option<LatenessCallback>,
+    /// Time spent in blocking actions, suspend prompts, pauses and waits
+    /// for the throttle during playback, which pushes back the intended time
+    /// of the events after it
+    paused: Duration,
+    throttle: Option<Throttle>,
+    recovery: Recovery,
+    reopen: Option<ReopenCallback>,
+    /// What's being played, for the audit log
+    source: Option<String>,
 }
 
 impl Player {
-    /// Create a new player with a virtual device
-    pub fn new(device_name: &str) -> io::Result<Self> {
-        // Setup all keyboard keys
-        let mut keys = AttributeSet::<KeyCode>::new();
-        // KEY_MAX is 0x2ff (767) - we register all possible keycodes
-        for key_code in 0..=0x2ff {
-            keys.insert(KeyCode(key_code));
+    /// Create a new player that injects through the given backend
+    pub fn new(backend: Box<dyn Backend>) -> Self {
+        Self {
+            backend,
+            clock: Box::new(SystemClock::new()),
+            progress: None,
+            state_starts_us: Vec::new(),
+            stop: None,
+            held: KeySet::new(),
+            suspend: None,
+            suspend_watch: SuspendWatch::new(),
+            pause: None,
+            timing: TimingReport::default(),
+            lateness: None,
+            paused: Duration::ZERO,
+            throttle: None,
+            recovery: Recovery::Abort,
+            reopen: None,
+            source: None,
+        }
+    }
+
+    /// Pace playback by `clock` instead of real time
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Send at most `rate` events a second, or as many as the macro has with
+    /// `None`
+    ///
+    /// Events over the rate wait their turn and the rest of the macro waits
+    /// with them. Sync reports don't count, and releasing held keys (on a
+    /// stop, pause or suspend) is never held back.
+    pub fn set_max_rate(&mut self, rate: Option<EventRate>) {
+        self.throttle = rate.map(Throttle::new);
+    }
+
+    /// What to do when the backend fails to take an event (by default, stop)
+    ///
+    /// Time spent retrying pushes back the rest of the macro.
+    pub fn set_recovery(&mut self, recovery: Recovery) {
+        self.recovery = recovery;
+    }
+
+    /// Open a new backend with `reopen` before each retry, instead of trying
+    /// the one that failed again
+    ///
+    /// The keys the player holds are pressed on it before the event that
+    /// failed is sent.
+    pub fn reopen_with<F: FnMut() -> io::Result<Box<dyn Backend>> + 'static>(&mut self, reopen: F) {
+        self.reopen = Some(Box::new(reopen));
+    }
+
+    /// Name what's being played (e.g. the macro file) in the audit log
+    pub fn set_source(&mut self, source: &str) {
+        self.source = Some(source.to_string());
+        self.backend.set_source(source);
+    }
+
+    /// Stop playing as soon as `flag` is set, releasing any keys still held
+    ///
+    /// Playback then fails with `ErrorKind::Interrupted`.
+    pub fn stop_on(&mut self, flag: Arc<AtomicBool>) {
+        self.stop = Some(flag);
+    }
+
+    /// Release every key and button the player is holding down
+    pub fn release_all(&mut self) -> io::Result<()> {
+        for key_code in std::mem::take(&mut self.held).codes() {
+            self.backend.emit(&[InputEvent::new(EventType::KEY.0, key_code, 0)])?;
+        }
+        Ok(())
+    }
+
+    /// Emit one event right away, outside of any macro's timing
+    ///
+    /// Keys pressed this way are tracked like the ones a macro presses, so
+    /// [`Player::release_all`] lets go of them.
+    pub fn inject(&mut self, event: InputEvent) -> io::Result<()> {
+        self.emit(event)
+    }
+
+    /// Emit an empty sync report and wait `delay`, before the first event of
+    /// a playback
+    ///
+    /// A compositor still attaching a device that was just created can
+    /// swallow the first keystroke played on it; the report gets it reading
+    /// from the device and the delay lets it catch up. Nothing happens with
+    /// a zero `delay`.
+    pub fn pre_roll(&mut self, delay: Duration) -> io::Result<()> {
+        if delay.is_zero() {
+            return Ok(());
         }
+        debug!(delay_ms = delay.as_millis() as u64, "Pre-roll");
+        self.backend.emit(&[InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)])?;
+        self.clock.sleep(delay);
+        Ok(())
+    }
+
+    /// Ask `callback` whether to continue when the system was suspended
+    /// during playback, e.g. to prompt the user
+    ///
+    /// The held keys are released while it decides and pressed again if it
+    /// returns `true`; otherwise playback fails with `ErrorKind::Interrupted`,
+    /// as it always does without a callback.
+    pub fn on_suspend<F: FnMut(Duration) -> bool + 'static>(&mut self, callback: F) {
+        self.suspend = Some(Box::new(callback));
+    }
+
+    /// Hold playback whenever `paused` returns `true`, until it returns
+    /// `false` again
+    ///
+    /// It's asked between events and while waiting for the next one. Held
+    /// keys are let go for the pause and pressed again after it, and the rest
+    /// of the macro is pushed back by the time spent paused.
+    pub fn pause_while<F: FnMut() -> bool + 'static>(&mut self, paused: F) {
+        self.pause = Some(Box::new(paused));
+    }
 
-        // Setup mouse relative axes
-        let mut relative_axes = AttributeSet::<RelativeAxisCode>::new();
-        relative_axes.insert(RelativeAxisCode::REL_X);
-        relative_axes.insert(RelativeAxisCode::REL_Y);
-        relative_axes.insert(RelativeAxisCode::REL_WHEEL);
-        relative_axes.insert(RelativeAxisCode::REL_HWHEEL);
+    /// Call `callback` as playback advances (at least every 100ms of macro time)
+    pub fn on_progress<F: FnMut(&Progress) + 'static>(&mut self, callback: F) {
+        self.progress = Some(Box::new(callback));
+    }
 
-        let device = VirtualDevice::builder()?
-            .name(device_name)
-            .with_keys(&keys)?
-            .with_relative_axes(&relative_axes)?
-            .build()?;
+    /// Call `callback` with how late each event goes out, as counted in the
+    /// timing report
+    pub fn on_lateness<F: FnMut(u64) + 'static>(&mut self, callback: F) {
+        self.lateness = Some(Box::new(callback));
+    }
 
-        Ok(Self { device })
+    /// Timing of the last playback's events, with states later than
+    /// `tolerance_us` counted as late
+    ///
+    /// Only playback with original timing is measured, not streams.
+    pub fn timing_report(&self, tolerance_us: u64) -> TimingReport {
+        TimingReport {
+            tolerance_us,
+            ..self.timing.clone()
+        }
+    }
+
+    /// Tell the player when each state starts, so progress and the timing
+    /// report can name the current one
+    pub fn set_state_starts(&mut self, state_starts_us: Vec<u64>) {
+        self.state_starts_us = state_starts_us;
     }
 
     /// Play back recorded events with original timing
@@ -48,46 +454,686 @@ impl Player {
     ///   separate events with their own timestamps
     pub fn play(&mut self, events: &[RecordedEvent]) -> io::Result<()> {
         if events.is_empty() {
-            println!("No events to play");
+            info!("No events to play");
             return Ok(());
         }
 
-        println!("Playing {} events...", events.len());
+        info!("Playing {} events...", events.len());
+        self.play_iter(events.iter().cloned(), end_time_us(events, &[]))
+    }
+
+    /// Play back events, running each action at its time (in microseconds)
+    ///
+    /// Actions run before events with the same timestamp. Time spent in an
+    /// action (e.g. waiting for the screen) delays the rest of the macro.
+    pub fn play_with_actions(&mut self, events: &[RecordedEvent], actions: &[(u64, Action)]) -> io::Result<()> {
+        if actions.is_empty() {
+            return self.play(events);
+        }
+
+        info!("Playing {} events and {} actions...", events.len(), actions.len());
+        let total_us = end_time_us(events, actions);
+        self.play_timed(events.iter().cloned(), actions, total_us)
+    }
+
+    /// Play back events from any source with original timing
+    ///
+    /// Events are consumed one at a time, so lazily decoded sources (such as a
+    /// memory-mapped binary macro) are never materialized in memory. `total_us`
+    /// is the timestamp of the last event, used only for progress reporting.
+    pub fn play_iter<I>(&mut self, events: I, total_us: u64) -> io::Result<()>
+    where
+        I: IntoIterator<Item = RecordedEvent>,
+    {
+        self.play_timed(events, &[], total_us)
+    }
 
+    /// Play back events as they arrive from a live source, such as a stream
+    /// on stdin
+    ///
+    /// Each event goes out at its timestamp relative to the first one, or as
+    /// soon as it arrives if that's later: time spent waiting for an event
+    /// already counts towards its delay, instead of adding to it.
+    pub fn play_stream<I>(&mut self, events: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = RecordedEvent>,
+    {
+        let mut first: Option<(Duration, u64)> = None;
+        for recorded in events {
+            let (started, first_us) = *first.get_or_insert((self.clock.now(), recorded.timestamp_us));
+            let due = started + Duration::from_micros(recorded.timestamp_us.saturating_sub(first_us));
+            let wait = due.saturating_sub(self.clock.now());
+            if !wait.is_zero() {
+                self.clock.sleep(wait);
+            }
+            self.check_stop()?;
+            self.emit(recorded.event)?;
+        }
+        info!("Playback complete");
+        Ok(())
+    }
+
+    fn play_timed<I>(&mut self, events: I, actions: &[(u64, Action)], total_us: u64) -> io::Result<()>
+    where
+        I: IntoIterator<Item = RecordedEvent>,
+    {
+        let _span = info_span!("playback", total_us).entered();
+        let started = self.clock.now();
+        self.suspend_watch = SuspendWatch::new();
+        self.timing = TimingReport::default();
+        self.paused = Duration::ZERO;
+        let mut pending = actions.iter().peekable();
         let mut last_timestamp = 0u64;
+        let mut last_emit = self.clock.now();
 
         for recorded in events {
+            while let Some((at_us, action)) = pending.next_if(|(at_us, _)| *at_us <= recorded.timestamp_us) {
+                self.wait_until(last_timestamp, *at_us, total_us, started)?;
+                last_timestamp = last_timestamp.max(*at_us);
+                self.perform(action)?;
+                last_emit = self.clock.now();
+            }
+
             // Calculate delay from last event
             let delay_us = recorded.timestamp_us.saturating_sub(last_timestamp);
             if delay_us > 0 {
-                thread::sleep(Duration::from_micros(delay_us));
+                self.wait_until(last_timestamp, recorded.timestamp_us, total_us, started)?;
             }
+            self.check_stop()?;
+            self.check_pause()?;
 
             // TODO: For better accuracy, could batch events with identical timestamps
             // and emit them together in a single call
-            self.device.emit(&[recorded.event])?;
+            self.emit(recorded.event)?;
+            let intended = started + Duration::from_micros(recorded.timestamp_us) + self.paused;
+            let behind_us = self.clock.since(intended).as_micros() as u64;
+            self.timing.record(self.state_index(recorded.timestamp_us), behind_us);
+            if let Some(lateness) = &mut self.lateness {
+                lateness(behind_us);
+            }
+
+            // How much later than intended this event went out, from sleep overshoot
+            let late_us = (self.clock.since(last_emit).as_micros() as u64).saturating_sub(delay_us);
+            last_emit = self.clock.now();
+            debug!(
+                timestamp_us = recorded.timestamp_us,
+                late_us,
+                "{:?} code={} value={}",
+                recorded.event.event_type(),
+                recorded.event.code(),
+                recorded.event.value()
+            );
 
             last_timestamp = recorded.timestamp_us;
         }
 
-        println!("Playback complete");
+        for (at_us, action) in pending {
+            self.wait_until(last_timestamp, *at_us, total_us, started)?;
+            last_timestamp = last_timestamp.max(*at_us);
+            self.perform(action)?;
+        }
+
+        self.report(total_us.max(last_timestamp), total_us.max(last_timestamp), started);
+        info!("Playback complete");
         Ok(())
     }
 
+    /// Sleep from `from_us` to `to_us` in macro time, reporting progress and
+    /// checking the stop flag on the way
+    fn wait_until(&mut self, from_us: u64, to_us: u64, total_us: u64, started: Duration) -> io::Result<()> {
+        let step_limit = match (&self.progress, self.stop.is_some() || self.pause.is_some()) {
+            (None, false) => {
+                self.clock.sleep(Duration::from_micros(to_us.saturating_sub(from_us)));
+                return self.check_suspend();
+            }
+            (_, true) => STOP_POLL_INTERVAL,
+            (Some(_), false) => PROGRESS_INTERVAL,
+        };
+
+        let mut position_us = from_us;
+        let mut reported_us = from_us;
+        while position_us < to_us {
+            let step_us = (to_us - position_us).min(step_limit.as_micros() as u64);
+            self.clock.sleep(Duration::from_micros(step_us));
+            position_us += step_us;
+            self.check_stop()?;
+            self.check_pause()?;
+            self.check_suspend()?;
+            if position_us - reported_us >= PROGRESS_INTERVAL.as_micros() as u64 || position_us >= to_us {
+                self.report(position_us, total_us, started);
+                reported_us = position_us;
+            }
+        }
+        Ok(())
+    }
+
+    /// Release held keys and bail out if the stop flag is set
+    pub(crate) fn check_stop(&mut self) -> io::Result<()> {
+        if !self.stop.as_ref().is_some_and(|stop| stop.load(Ordering::Relaxed)) {
+            return Ok(());
+        }
+        info!("Playback stopped");
+        self.release_all()?;
+        Err(io::Error::new(io::ErrorKind::Interrupted, "Playback stopped"))
+    }
+
+    /// Let go of held keys while the pause callback says so, then press them
+    /// again
+    fn check_pause(&mut self) -> io::Result<()> {
+        if !self.pause.as_mut().is_some_and(|paused| paused()) {
+            return Ok(());
+        }
+        info!("Playback paused");
+        let held = self.held.clone();
+        self.release_all()?;
+        let pausing = self.clock.now();
+        while self.pause.as_mut().is_some_and(|paused| paused()) {
+            self.clock.sleep(STOP_POLL_INTERVAL);
+            self.check_stop()?;
+        }
+        self.paused += self.clock.since(pausing);
+
+        info!("Resuming playback");
+        for key_code in held.codes() {
+            self.emit(InputEvent::new(EventType::KEY.0, key_code, 1))?;
+        }
+        Ok(())
+    }
+
+    /// Let go of held keys if the system was suspended since the last check,
+    /// and stop unless the suspend callback says to carry on
+    fn check_suspend(&mut self) -> io::Result<()> {
+        let Some(suspended) = self.suspend_watch.check() else {
+            return Ok(());
+        };
+        warn!("System was suspended for {}s during playback", suspended.as_secs());
+
+        let held = self.held.clone();
+        self.release_all()?;
+        let deciding = self.clock.now();
+        let resume = self.suspend.as_mut().is_some_and(|callback| callback(suspended));
+        self.paused += self.clock.since(deciding);
+        // Don't count time spent deciding as another suspend
+        self.suspend_watch = SuspendWatch::new();
+        if !resume {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "System suspended during playback"));
+        }
+
+        info!("Resuming playback");
+        for key_code in held.codes() {
+            self.emit(InputEvent::new(EventType::KEY.0, key_code, 1))?;
+        }
+        Ok(())
+    }
+
+    /// Emit one event, keeping track of held keys
+    fn emit(&mut self, event: InputEvent) -> io::Result<()> {
+        if event.event_type() != EventType::SYNCHRONIZATION
+            && let Some(throttle) = &mut self.throttle
+        {
+            let wait = throttle.delay(self.clock.now());
+            if !wait.is_zero() {
+                self.clock.sleep(wait);
+                self.paused += wait;
+            }
+        }
+        if let Err(e) = self.backend.emit(&[event])
+            && !self.recover(event, e)?
+        {
+            return Ok(());
+        }
+        if event.event_type() == EventType::KEY {
+            match event.value() {
+                0 => {
+                    self.held.remove(event.code());
+                }
+                1 => {
+                    self.held.insert(event.code());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Deal with the backend failing to take `event` as the recovery policy
+    /// says, returning whether the event went out in the end
+    fn recover(&mut self, event: InputEvent, error: io::Error) -> io::Result<bool> {
+        match self.recovery {
+            Recovery::Abort => Err(error),
+            Recovery::Skip => {
+                warn!("Skipping an event the backend didn't take: {}", error);
+                Ok(false)
+            }
+            Recovery::Retry => {
+                let failed = self.clock.now();
+                let mut error = error;
+                for attempt in 1..=RETRY_ATTEMPTS {
+                    warn!(
+                        "Injection failed: {}, retrying in {}s ({} of {})",
+                        error,
+                        RETRY_DELAY.as_secs(),
+                        attempt,
+                        RETRY_ATTEMPTS
+                    );
+                    self.clock.sleep(RETRY_DELAY);
+                    self.check_stop()?;
+                    match self.resend(event) {
+                        Ok(()) => {
+                            info!("Injection recovered, resuming playback");
+                            self.paused += self.clock.since(failed);
+                            return Ok(true);
+                        }
+                        Err(e) => error = e,
+                    }
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Send `event` again, on a backend opened again if there's a way to,
+    /// with the held keys pressed on it first
+    fn resend(&mut self, event: InputEvent) -> io::Result<()> {
+        if let Some(reopen) = &mut self.reopen {
+            self.backend = reopen()?;
+            if let Some(source) = &self.source {
+                self.backend.set_source(source);
+            }
+            for key_code in self.held.codes() {
+                self.backend.emit(&[InputEvent::new(EventType::KEY.0, key_code, 1)])?;
+            }
+        }
+        self.backend.emit(&[event])
+    }
+
+    fn state_index(&self, position_us: u64) -> Option<usize> {
+        match self.state_starts_us.partition_point(|&start| start <= position_us) {
+            0 => None,
+            after => Some(after - 1),
+        }
+    }
+
+    fn report(&mut self, position_us: u64, total_us: u64, started: Duration) {
+        let state_index = self.state_index(position_us);
+        if let Some(callback) = &mut self.progress {
+            callback(&Progress {
+                position_us,
+                total_us,
+                state_index,
+                elapsed: self.clock.since(started),
+            });
+        }
+    }
+
+    fn perform(&mut self, action: &Action) -> io::Result<()> {
+        debug!("Running action: {}", action);
+        let started = self.clock.now();
+        action.perform(self)?;
+        let took = self.clock.since(started);
+        self.paused += took;
+        debug!(took_ms = took.as_millis() as u64, "Action done");
+        Ok(())
+    }
+
+    /// Press and release a single key
+    pub fn tap_key(&mut self, key_code: u16) -> io::Result<()> {
+        self.backend.emit(&[InputEvent::new(EventType::KEY.0, key_code, 1)])?;
+        self.backend.emit(&[InputEvent::new(EventType::KEY.0, key_code, 0)])?;
+        Ok(())
+    }
+
+    /// Press a key or mouse button, hold it for `hold` and release it
+    pub fn click(&mut self, code: u16, hold: Duration) -> io::Result<()> {
+        self.emit(InputEvent::new(EventType::KEY.0, code, 1))?;
+        self.clock.sleep(hold);
+        self.emit(InputEvent::new(EventType::KEY.0, code, 0))
+    }
+
+    /// Type `text` through the backend, if it can type characters directly;
+    /// fails with `ErrorKind::Unsupported` otherwise
+    pub fn type_text(&mut self, text: &str) -> io::Result<()> {
+        self.backend.type_text(text)
+    }
+
+    /// Press keys in order, then release them in reverse (e.g. CTRL+V)
+    pub fn tap_combo(&mut self, key_codes: &[u16]) -> io::Result<()> {
+        for &key_code in key_codes {
+            self.backend.emit(&[InputEvent::new(EventType::KEY.0, key_code, 1)])?;
+        }
+        for &key_code in key_codes.iter().rev() {
+            self.backend.emit(&[InputEvent::new(EventType::KEY.0, key_code, 0)])?;
+        }
+        Ok(())
+    }
+
+    /// Put the pointer at screen coordinates `x`, `y`
+    pub fn move_to(&mut self, x: i32, y: i32) -> io::Result<()> {
+        self.backend.move_to(x, y)
+    }
+
     /// Play back events instantly without timing delays
     pub fn play_instant(&mut self, events: &[RecordedEvent]) -> io::Result<()> {
         if events.is_empty() {
-            println!("No events to play");
+            info!("No events to play");
             return Ok(());
         }
 
-        println!("Playing {} events (instant mode)...", events.len());
+        info!("Playing {} events (instant mode)...", events.len());
 
         for recorded in events {
-            self.device.emit(&[recorded.event])?;
+            self.emit(recorded.event)?;
         }
 
-        println!("Playback complete");
+        info!("Playback complete");
         Ok(())
     }
 }
+
+/// Time of the last event or action
+fn end_time_us(events: &[RecordedEvent], actions: &[(u64, Action)]) -> u64 {
+    let last_event = events.last().map_or(0, |e| e.timestamp_us);
+    let last_action = actions.last().map_or(0, |(at_us, _)| *at_us);
+    last_event.max(last_action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use crate::testing::{MockBackend, VirtualClock};
+    use std::rc::Rc;
+    use std::thread;
+
+    struct NullBackend;
+
+    impl Backend for NullBackend {
+        fn emit(&mut self, _events: &[InputEvent]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_progress_reports_states_and_completion() {
+        let events: Vec<RecordedEvent> = [0, 20_000, 30_000]
+            .into_iter()
+            .map(|timestamp_us| RecordedEvent {
+                timestamp_us,
+                event: InputEvent::new(EventType::KEY.0, 30, 1),
+            })
+            .collect();
+
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let mut player = Player::new(Box::new(NullBackend));
+        let sink = Rc::clone(&reports);
+        player.on_progress(move |progress| sink.borrow_mut().push(*progress));
+        player.set_state_starts(vec![0, 20_000]);
+        player.play(&events).unwrap();
+
+        let reports = reports.borrow();
+        assert_eq!(reports.first().unwrap().state_index, Some(1));
+        let last = reports.last().unwrap();
+        assert!(last.is_done());
+        assert_eq!(last.total_us, 30_000);
+        assert_eq!(last.remaining(), Duration::ZERO);
+    }
+
+    /// Takes 10ms to emit B
+    struct SlowBackend;
+
+    impl Backend for SlowBackend {
+        fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+            if events.iter().any(|event| event.code() == 48) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_timing_report_counts_drift() {
+        let events: Vec<RecordedEvent> = [(0, 30), (20_000, 48), (30_000, 30)]
+            .into_iter()
+            .map(|(timestamp_us, code)| RecordedEvent {
+                timestamp_us,
+                event: InputEvent::new(EventType::KEY.0, code, 1),
+            })
+            .collect();
+
+        let mut player = Player::new(Box::new(SlowBackend));
+        player.set_state_starts(vec![0, 20_000]);
+        player.play(&events).unwrap();
+
+        let report = player.timing_report(5_000);
+        assert_eq!(report.events(), 3);
+        let counts: Vec<(Option<usize>, usize)> = report.states.iter().map(|s| (s.state_index, s.events)).collect();
+        assert_eq!(counts, vec![(Some(0), 1), (Some(1), 2)]);
+        // The slow emit delays B and, through it, the event after it
+        let late: Vec<Option<usize>> = report.late_states().map(|s| s.state_index).collect();
+        assert_eq!(late, vec![Some(1)]);
+        assert!(report.states[1].mean_late_us() >= 10_000);
+        assert!(!report.is_faithful());
+        assert!(report.to_string().contains("Late states: 1 of 2 beyond 5.00ms\n  state 2"));
+    }
+
+    /// Takes 25ms of virtual time to emit B
+    struct SlowVirtualBackend(MockBackend, VirtualClock);
+
+    impl Backend for SlowVirtualBackend {
+        fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+            if events.iter().any(|event| event.code() == 48) {
+                self.1.advance(Duration::from_millis(25));
+            }
+            self.0.emit(events)
+        }
+    }
+
+    #[test]
+    fn test_virtual_clock_timing() {
+        let key = |timestamp_us, code| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::KEY.0, code, 1),
+        };
+        let events = vec![key(0, 48), key(20_000, 30), key(30_000, 30)];
+        let sent_at = |backend: &MockBackend| backend.events().iter().map(|e| e.timestamp_us).collect::<Vec<_>>();
+
+        // Streams go by the first event's time, so one slow emit doesn't
+        // push back everything after it
+        let clock = VirtualClock::new();
+        let backend = MockBackend::new(&clock);
+        let mut player = Player::new(Box::new(SlowVirtualBackend(backend.clone(), clock.clone())));
+        player.set_clock(clock.clone());
+        player.play_stream(events.clone()).unwrap();
+        assert_eq!(sent_at(&backend), vec![25_000, 25_000, 30_000]);
+
+        // Timed playback waits out each gap, so it drifts, and reports it
+        let clock = VirtualClock::new();
+        let backend = MockBackend::new(&clock);
+        let mut player = Player::new(Box::new(SlowVirtualBackend(backend.clone(), clock.clone())));
+        player.set_clock(clock.clone());
+        player.set_state_starts(vec![0, 20_000]);
+        player.play(&events).unwrap();
+        assert_eq!(sent_at(&backend), vec![25_000, 45_000, 55_000]);
+        let report = player.timing_report(5_000);
+        assert_eq!(report.max_late_us(), 25_000);
+        assert_eq!(report.states[1].mean_late_us(), 25_000);
+    }
+
+    #[test]
+    fn test_max_rate_stretches_timing() {
+        let key = |timestamp_us, value| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::KEY.0, 30, value),
+        };
+        // Four events at once, then one 50ms later
+        let events = vec![key(0, 1), key(0, 0), key(0, 1), key(0, 0), key(50_000, 1)];
+        let clock = VirtualClock::new();
+        let backend = MockBackend::new(&clock);
+        let mut player = Player::new(Box::new(backend.clone()));
+        player.set_clock(clock.clone());
+        player.set_max_rate(Some(EventRate { per_second: 100, burst: 2 }));
+        player.play(&events).unwrap();
+
+        let sent_at: Vec<_> = backend.events().iter().map(|e| e.timestamp_us).collect();
+        assert_eq!(sent_at, vec![0, 0, 10_000, 20_000, 70_000]);
+        // Waiting for the throttle isn't lateness
+        assert!(player.timing_report(1_000).is_faithful());
+    }
+
+    /// Logs events, but fails the first time it's given the key in `fail_on`
+    #[derive(Clone)]
+    struct FlakyBackend {
+        log: Rc<RefCell<Vec<InputEvent>>>,
+        fail_on: Rc<RefCell<Option<u16>>>,
+    }
+
+    impl Backend for FlakyBackend {
+        fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+            if events.iter().any(|event| Some(event.code()) == *self.fail_on.borrow()) {
+                self.fail_on.borrow_mut().take();
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "device went away"));
+            }
+            self.log.borrow_mut().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_recovery() {
+        let press = |timestamp_us, code| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::KEY.0, code, 1),
+        };
+        let events = vec![press(0, 30), press(10_000, 48)];
+        let play = |recovery: Recovery| {
+            let backend = FlakyBackend {
+                log: Rc::new(RefCell::new(Vec::new())),
+                fail_on: Rc::new(RefCell::new(Some(48))),
+            };
+            let reopened = backend.clone();
+            let mut player = Player::new(Box::new(backend.clone()));
+            player.set_clock(VirtualClock::new());
+            player.set_recovery(recovery);
+            player.reopen_with(move || Ok(Box::new(reopened.clone()) as Box<dyn Backend>));
+            let result = player.play(&events);
+            let codes: Vec<_> = backend.log.borrow().iter().map(|event| event.code()).collect();
+            (result, codes, player.held.codes().collect::<Vec<_>>())
+        };
+
+        let (result, codes, _) = play(Recovery::Abort);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(codes, vec![30]);
+
+        let (result, codes, held) = play(Recovery::Skip);
+        assert!(result.is_ok());
+        assert_eq!((codes, held), (vec![30], vec![30]));
+
+        // A is pressed again on the new device before B
+        let (result, codes, held) = play(Recovery::Retry);
+        assert!(result.is_ok());
+        assert_eq!((codes, held), (vec![30, 30, 48], vec![30, 48]));
+        assert_eq!("retry".parse::<Recovery>(), Ok(Recovery::Retry));
+    }
+
+    /// Records every event it's given
+    struct LogBackend(Rc<RefCell<Vec<InputEvent>>>);
+
+    impl Backend for LogBackend {
+        fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+            self.0.borrow_mut().extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pre_roll() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut player = Player::new(Box::new(LogBackend(Rc::clone(&log))));
+        player.pre_roll(Duration::ZERO).unwrap();
+        assert!(log.borrow().is_empty());
+
+        let started = Instant::now();
+        player.pre_roll(Duration::from_millis(5)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(5));
+        assert_eq!(*log.borrow(), vec![InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)]);
+    }
+
+    #[test]
+    fn test_stop_releases_held_keys() {
+        let key = |timestamp_us, value| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::KEY.0, 30, value),
+        };
+        let events = vec![key(0, 1), key(60_000, 0)];
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut player = Player::new(Box::new(LogBackend(Rc::clone(&log))));
+        player.stop_on(Arc::clone(&stop));
+
+        let setter = Arc::clone(&stop);
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            setter.store(true, Ordering::Relaxed);
+        });
+        let error = player.play(&events).unwrap_err();
+        stopper.join().unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+        let values: Vec<_> = log.borrow().iter().map(|e| (e.code(), e.value())).collect();
+        assert_eq!(values, vec![(30, 1), (30, 0)]);
+    }
+
+    #[test]
+    fn test_pause_releases_and_resumes() {
+        let key = |timestamp_us, value| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::KEY.0, 30, value),
+        };
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut player = Player::new(Box::new(LogBackend(Rc::clone(&log))));
+        // Not paused before the press, then paused for two checks
+        let mut checks = 0;
+        player.pause_while(move || {
+            checks += 1;
+            (2..=3).contains(&checks)
+        });
+
+        let started = Instant::now();
+        player.play(&[key(0, 1), key(30_000, 0)]).unwrap();
+
+        let values: Vec<_> = log.borrow().iter().map(|e| (e.code(), e.value())).collect();
+        assert_eq!(values, vec![(30, 1), (30, 0), (30, 1), (30, 0)]);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_suspend_releases_and_resumes() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut player = Player::new(Box::new(LogBackend(Rc::clone(&log))));
+        player.emit(InputEvent::new(EventType::KEY.0, 30, 1)).unwrap();
+        assert!(player.check_suspend().is_ok());
+
+        // Pretend the boot clock went on for a minute without the monotonic one
+        let suspend = |player: &mut Player| {
+            player.suspend_watch.boot = player.suspend_watch.boot.saturating_sub(Duration::from_secs(60));
+        };
+        let asked = Rc::new(RefCell::new(Vec::new()));
+        let answers = Rc::clone(&asked);
+        player.on_suspend(move |suspended| {
+            answers.borrow_mut().push(suspended.as_secs_f64().round());
+            true
+        });
+        suspend(&mut player);
+        player.check_suspend().unwrap();
+        assert_eq!(*asked.borrow(), vec![60.0]);
+
+        player.suspend = None;
+        suspend(&mut player);
+        let error = player.check_suspend().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+
+        let values: Vec<_> = log.borrow().iter().map(|e| (e.code(), e.value())).collect();
+        assert_eq!(values, vec![(30, 1), (30, 0), (30, 1), (30, 0)]);
+    }
+}
@@ -0,0 +1,289 @@
+//! XDG RemoteDesktop portal injection backend
+//!
+//! Inside Flatpak and similar sandboxes there's neither `/dev/uinput` nor,
+//! usually, the compositor's virtual input protocols, but an app can ask the
+//! desktop for remote control through `org.freedesktop.portal.RemoteDesktop`.
+//! The desktop asks the user once; the permission is then remembered with a
+//! restore token kept in `$XDG_CONFIG_HOME/evkey/portal-token`, so later
+//! sessions start without a dialog.
+//!
+//! Events become NotifyKeyboardKeycode, NotifyPointerMotion,
+//! NotifyPointerButton and NotifyPointerAxisDiscrete calls. The portal can
+//! only place the pointer on a shared screencast stream, so `move to` isn't
+//! supported, and it only lets input in, so it can't be used for recording.
+
+use crate::backend::Backend;
+use crate::config;
+use crate::event::{EventType, InputEvent};
+use crate::input_key::InputKey;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use tracing::{info, warn};
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+
+const DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PATH: &str = "/org/freedesktop/portal/desktop";
+const INTERFACE: &str = "org.freedesktop.portal.RemoteDesktop";
+
+/// Device types asked for in SelectDevices
+const DEVICE_KEYBOARD: u32 = 1;
+const DEVICE_POINTER: u32 = 2;
+/// SelectDevices persist mode: keep the permission until it's revoked
+const PERSIST_UNTIL_REVOKED: u32 = 2;
+
+/// Axes of NotifyPointerAxisDiscrete
+const AXIS_VERTICAL: u32 = 0;
+const AXIS_HORIZONTAL: u32 = 1;
+
+/// Options argument of portal calls (a{sv})
+type Options<'a> = HashMap<&'a str, Value<'a>>;
+
+/// Results of a portal request
+type Results = HashMap<String, OwnedValue>;
+
+/// A RemoteDesktop session the user allowed to control keyboard and pointer
+pub struct PortalBackend {
+    connection: Connection,
+    portal: Proxy<'static>,
+    session: OwnedObjectPath,
+    /// Requests made so far, to give each a unique token
+    requests: u32,
+}
+
+impl PortalBackend {
+    /// Open a remote desktop session, asking the user unless a saved
+    /// permission is still valid
+    pub fn connect() -> io::Result<Self> {
+        let connection = Connection::session().map_err(io::Error::other)?;
+        let portal = Proxy::new(&connection, DESTINATION, PATH, INTERFACE).map_err(io::Error::other)?;
+        let mut backend = Self {
+            connection,
+            portal,
+            session: OwnedObjectPath::default(),
+            requests: 0,
+        };
+
+        let created = backend.request(|portal, token| {
+            let options = Options::from([
+                ("handle_token", Value::from(token)),
+                ("session_handle_token", Value::from(token)),
+            ]);
+            portal.call("CreateSession", &(options,))
+        })?;
+        let session = created
+            .get("session_handle")
+            .and_then(|handle| handle.downcast_ref::<&str>().ok())
+            .ok_or_else(|| io::Error::other("Portal didn't return a session"))?;
+        backend.session = OwnedObjectPath::try_from(session).map_err(io::Error::other)?;
+
+        let restore_token = std::fs::read_to_string(token_path().unwrap_or_default()).ok();
+        let session = backend.session.clone();
+        backend.request(|portal, token| {
+            let mut options = Options::from([
+                ("handle_token", Value::from(token)),
+                ("types", Value::from(DEVICE_KEYBOARD | DEVICE_POINTER)),
+                ("persist_mode", Value::from(PERSIST_UNTIL_REVOKED)),
+            ]);
+            if let Some(restore_token) = restore_token.as_deref() {
+                options.insert("restore_token", Value::from(restore_token.trim()));
+            }
+            portal.call("SelectDevices", &(&session, options))
+        })?;
+
+        let started = backend.request(|portal, token| {
+            let options = Options::from([("handle_token", Value::from(token))]);
+            portal.call("Start", &(&session, "", options))
+        })?;
+        if let Some(restore_token) = started.get("restore_token").and_then(|t| t.downcast_ref::<&str>().ok()) {
+            save_token(restore_token);
+        }
+
+        info!("Remote desktop portal session started");
+        Ok(backend)
+    }
+
+    /// Make a portal request and wait for its response
+    ///
+    /// The response arrives as a signal on a request object whose path
+    /// follows from our bus name and the request's token, so we listen there
+    /// before making the call, or a quick answer could be missed.
+    fn request<F>(&mut self, call: F) -> io::Result<Results>
+    where
+        F: FnOnce(&Proxy<'static>, &str) -> zbus::Result<OwnedObjectPath>,
+    {
+        self.requests += 1;
+        let token = format!("evkey{}_{}", std::process::id(), self.requests);
+        let sender = self
+            .connection
+            .unique_name()
+            .ok_or_else(|| io::Error::other("Not connected to the session bus"))?
+            .trim_start_matches(':')
+            .replace('.', "_");
+        let path = format!("{}/request/{}/{}", PATH, sender, token);
+        let request = Proxy::new(&self.connection, DESTINATION, path, "org.freedesktop.portal.Request")
+            .map_err(io::Error::other)?;
+        let mut responses = request.receive_signal("Response").map_err(io::Error::other)?;
+
+        call(&self.portal, &token).map_err(io::Error::other)?;
+
+        let message = responses
+            .next()
+            .ok_or_else(|| io::Error::other("Portal went away without answering"))?;
+        let (response, results): (u32, Results) = message.body().deserialize().map_err(io::Error::other)?;
+        match response {
+            0 => Ok(results),
+            1 => Err(io::Error::new(io::ErrorKind::PermissionDenied, "Remote control wasn't allowed")),
+            _ => Err(io::Error::other("Portal request failed")),
+        }
+    }
+}
+
+impl Backend for PortalBackend {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let session = &self.session;
+        for notify in notifications(events) {
+            let sent = match notify {
+                Notify::Button(code, state) => self
+                    .portal
+                    .call_method("NotifyPointerButton", &(session, Options::new(), code, state)),
+                Notify::Keycode(code, state) => self
+                    .portal
+                    .call_method("NotifyKeyboardKeycode", &(session, Options::new(), code, state)),
+                Notify::AxisDiscrete(axis, steps) => self
+                    .portal
+                    .call_method("NotifyPointerAxisDiscrete", &(session, Options::new(), axis, steps)),
+                Notify::Motion(dx, dy) => self
+                    .portal
+                    .call_method("NotifyPointerMotion", &(session, Options::new(), dx, dy)),
+            };
+            sent.map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+/// One RemoteDesktop call, with its arguments after the session and options
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Notify {
+    Button(i32, u32),
+    Keycode(i32, u32),
+    AxisDiscrete(u32, i32),
+    Motion(f64, f64),
+}
+
+/// The portal calls that replay one batch of events. Relative moves are summed into a
+/// single motion sent last, key repeats are left to the compositor.
+fn notifications(events: &[InputEvent]) -> Vec<Notify> {
+    let mut calls = Vec::new();
+    let (mut dx, mut dy) = (0.0, 0.0);
+
+    for event in events {
+        let code = event.code();
+        let value = event.value();
+
+        match event.event_type() {
+            EventType::KEY if InputKey::from(code).is_mouse_button() => {
+                calls.push(Notify::Button(i32::from(code), u32::from(value != 0)));
+            }
+            EventType::KEY if value != 2 => {
+                calls.push(Notify::Keycode(i32::from(code), u32::from(value == 1)));
+            }
+            EventType::RELATIVE => match code {
+                0 => dx += value as f64, // REL_X
+                1 => dy += value as f64, // REL_Y
+                6 | 8 => {
+                    // REL_HWHEEL, REL_WHEEL: wheel up is a negative scroll
+                    let (axis, steps) = if code == 8 { (AXIS_VERTICAL, -value) } else { (AXIS_HORIZONTAL, value) };
+                    calls.push(Notify::AxisDiscrete(axis, steps));
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    if dx != 0.0 || dy != 0.0 {
+        calls.push(Notify::Motion(dx, dy));
+    }
+    calls
+}
+
+impl Drop for PortalBackend {
+    fn drop(&mut self) {
+        let closed = Proxy::new(
+            &self.connection,
+            DESTINATION,
+            self.session.as_str(),
+            "org.freedesktop.portal.Session",
+        )
+        .and_then(|session| session.call_method("Close", &()));
+        if let Err(e) = closed {
+            warn!("Can't close the portal session: {}", e);
+        }
+    }
+}
+
+/// Where the permission to restore is kept
+fn token_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("portal-token"))
+}
+
+fn save_token(token: &str) {
+    let Some(path) = token_path() else {
+        return;
+    };
+    let saved = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, token));
+    if let Err(e) = saved {
+        warn!("Can't save the portal permission to {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_and_button_notifications() {
+        let events = [
+            InputEvent::new(EventType::KEY.0, 30, 1),
+            InputEvent::new(EventType::KEY.0, 30, 2),
+            InputEvent::new(EventType::KEY.0, 30, 0),
+            InputEvent::new(EventType::KEY.0, 0x110, 1), // BTN_LEFT
+            InputEvent::new(EventType::KEY.0, 0x110, 0),
+        ];
+        assert_eq!(
+            notifications(&events),
+            [
+                Notify::Keycode(30, 1),
+                Notify::Keycode(30, 0),
+                Notify::Button(0x110, 1),
+                Notify::Button(0x110, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_moves_are_summed_and_wheels_flipped() {
+        let events = [
+            InputEvent::new(EventType::RELATIVE.0, 0, 3),
+            InputEvent::new(EventType::RELATIVE.0, 8, 1),
+            InputEvent::new(EventType::RELATIVE.0, 1, -2),
+            InputEvent::new(EventType::RELATIVE.0, 0, 4),
+            InputEvent::new(EventType::RELATIVE.0, 6, 1),
+            InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+        ];
+        assert_eq!(
+            notifications(&events),
+            [
+                Notify::AxisDiscrete(AXIS_VERTICAL, -1),
+                Notify::AxisDiscrete(AXIS_HORIZONTAL, 1),
+                Notify::Motion(7.0, -2.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Recording post-processing passes
+//!
+//! A [`PostProcessor`] takes a recording's states and returns cleaned-up
+//! ones. Passes are looked up by name in a [`Registry`] and chained into a
+//! [`Pipeline`]; `evkey record` runs the one listed in
+//! `~/.config/evkey/postprocess`, one pass per line, top to bottom:
+//!
+//!   # Fold pointer jitter of up to 2 counts into real moves
+//!   noise-filter 2
+//!   # Join the states that leaves with the same keys held
+//!   merge
+//!   # Put every state boundary on a 10ms grid
+//!   quantize 10ms
+//!   # Shorten pauses longer than 5s
+//!   idle-cap 5s
+//!
+//! `min-hold <duration>` is built in too. Applications using EvKey as a
+//! library can [`Registry::register`] passes of their own and name them in
+//! the same file.
+
+use crate::config;
+use crate::state::{self, MacroState};
+use crate::storage::parse_duration;
+use std::fmt;
+use std::path::PathBuf;
+
+/// A pass over a recording's states
+pub trait PostProcessor {
+    /// Name the pass goes by in the pipeline config
+    fn name(&self) -> &str;
+    fn process(&self, states: Vec<MacroState>) -> Vec<MacroState>;
+}
+
+/// Builds a pass from the argument after its name, if any
+pub type Factory = Box<dyn Fn(Option<&str>) -> Result<Box<dyn PostProcessor>, String>>;
+
+/// Passes that can be named in a pipeline
+pub struct Registry {
+    factories: Vec<(String, Factory)>,
+}
+
+impl Registry {
+    /// A registry without any passes
+    pub fn empty() -> Self {
+        Registry { factories: Vec::new() }
+    }
+
+    /// The passes EvKey comes with
+    pub fn builtin() -> Self {
+        let mut registry = Registry::empty();
+        registry.register("noise-filter", |arg| {
+            let counts = required(arg, "noise-filter", "a number of counts")?;
+            let max_counts = counts.parse().map_err(|_| format!("Invalid count '{}'", counts))?;
+            Ok(Box::new(NoiseFilter { max_counts }))
+        });
+        registry.register("merge", |arg| {
+            no_argument(arg, "merge")?;
+            Ok(Box::new(Merge))
+        });
+        registry.register("quantize", |arg| {
+            let grid_ms = parse_duration(required(arg, "quantize", "a grid, e.g. 10ms")?)?;
+            if grid_ms == 0 {
+                return Err("The quantize grid has to be at least 1ms".to_string());
+            }
+            Ok(Box::new(Quantize { grid_ms }))
+        });
+        registry.register("idle-cap", |arg| {
+            let max_ms = parse_duration(required(arg, "idle-cap", "a duration, e.g. 5s")?)?;
+            Ok(Box::new(IdleCap { max_ms }))
+        });
+        registry.register("min-hold", |arg| {
+            let min_ms = parse_duration(required(arg, "min-hold", "a duration, e.g. 16ms")?)?;
+            Ok(Box::new(MinHold { min_ms }))
+        });
+        registry
+    }
+
+    /// Make a pass available as `name`, replacing one registered before
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(Option<&str>) -> Result<Box<dyn PostProcessor>, String> + 'static,
+    {
+        self.factories.retain(|(existing, _)| existing != name);
+        self.factories.push((name.to_string(), Box::new(factory)));
+    }
+
+    /// Names of the registered passes, in registration order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Build the pass registered as `name`
+    pub fn create(&self, name: &str, arg: Option<&str>) -> Result<Box<dyn PostProcessor>, String> {
+        let (_, factory) = self.factories.iter().find(|(existing, _)| existing == name).ok_or_else(|| {
+            format!(
+                "Unknown pass '{}' (available: {})",
+                name,
+                self.names().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        factory(arg)
+    }
+
+    /// Parse a pipeline config: one pass per line, with its argument after
+    /// the name, and `#` comments
+    pub fn parse_pipeline(&self, text: &str) -> Result<Pipeline, String> {
+        let mut pipeline = Pipeline::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, arg) = match line.split_once(char::is_whitespace) {
+                Some((name, arg)) => (name, Some(arg.trim())),
+                None => (line, None),
+            };
+            let pass = self.create(name, arg).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            pipeline.push(pass);
+        }
+        Ok(pipeline)
+    }
+}
+
+fn required<'a>(arg: Option<&'a str>, name: &str, what: &str) -> Result<&'a str, String> {
+    arg.ok_or_else(|| format!("{} needs {}", name, what))
+}
+
+fn no_argument(arg: Option<&str>, name: &str) -> Result<(), String> {
+    match arg {
+        Some(arg) => Err(format!("{} takes no argument, got '{}'", name, arg)),
+        None => Ok(()),
+    }
+}
+
+/// Passes run one after another
+#[derive(Default)]
+pub struct Pipeline {
+    passes: Vec<Box<dyn PostProcessor>>,
+}
+
+impl Pipeline {
+    pub fn push(&mut self, pass: Box<dyn PostProcessor>) {
+        self.passes.push(pass);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    /// Names of the passes in the order they run
+    pub fn names(&self) -> Vec<&str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    pub fn run(&self, states: Vec<MacroState>) -> Vec<MacroState> {
+        self.passes.iter().fold(states, |states, pass| pass.process(states))
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+/// Where `evkey record` reads its pipeline: `$XDG_CONFIG_HOME/evkey/postprocess`
+pub fn config_path() -> Option<PathBuf> {
+    Some(config::config_dir()?.join("postprocess"))
+}
+
+/// Drops pointer jitter: moves of at most `max_counts` on both axes are
+/// taken out and added up until they amount to more, so the pointer still
+/// ends where it did
+pub struct NoiseFilter {
+    pub max_counts: u32,
+}
+
+impl PostProcessor for NoiseFilter {
+    fn name(&self) -> &str {
+        "noise-filter"
+    }
+
+    fn process(&self, mut states: Vec<MacroState>) -> Vec<MacroState> {
+        let small = |(dx, dy): (i32, i32)| dx.unsigned_abs() <= self.max_counts && dy.unsigned_abs() <= self.max_counts;
+        let mut pending = (0, 0);
+        let mut last_dropped = None;
+
+        for (index, state) in states.iter_mut().enumerate() {
+            if state.mouse_delta == (0, 0) {
+                continue;
+            }
+            let total = (pending.0 + state.mouse_delta.0, pending.1 + state.mouse_delta.1);
+            if small(state.mouse_delta) && small(total) {
+                pending = total;
+                state.mouse_delta = (0, 0);
+                last_dropped = Some(index);
+            } else {
+                state.mouse_delta = total;
+                pending = (0, 0);
+            }
+        }
+        // Whatever is left goes where the last of it was taken from
+        if let Some(index) = last_dropped.filter(|_| pending != (0, 0)) {
+            states[index].mouse_delta = pending;
+        }
+        states
+    }
+}
+
+/// Joins consecutive states with the same keys held and no movement
+pub struct Merge;
+
+impl PostProcessor for Merge {
+    fn name(&self) -> &str {
+        "merge"
+    }
+
+    fn process(&self, states: Vec<MacroState>) -> Vec<MacroState> {
+        state::merge_consecutive_states(states)
+    }
+}
+
+/// Moves every state boundary to the nearest multiple of `grid_ms` from the
+/// start, which keeps the total length within half a grid step
+pub struct Quantize {
+    pub grid_ms: u64,
+}
+
+impl PostProcessor for Quantize {
+    fn name(&self) -> &str {
+        "quantize"
+    }
+
+    fn process(&self, mut states: Vec<MacroState>) -> Vec<MacroState> {
+        let snap = |ms: u64| (ms + self.grid_ms / 2) / self.grid_ms * self.grid_ms;
+        let (mut end_ms, mut snapped_ms) = (0u64, 0u64);
+        for state in &mut states {
+            end_ms = end_ms.saturating_add(state.duration_ms);
+            let snapped_end_ms = snap(end_ms);
+            state.duration_ms = snapped_end_ms - snapped_ms;
+            snapped_ms = snapped_end_ms;
+        }
+        states
+    }
+}
+
+/// Shortens pauses longer than `max_ms`
+pub struct IdleCap {
+    pub max_ms: u64,
+}
+
+impl PostProcessor for IdleCap {
+    fn name(&self) -> &str {
+        "idle-cap"
+    }
+
+    fn process(&self, mut states: Vec<MacroState>) -> Vec<MacroState> {
+        state::cap_idle(&mut states, self.max_ms);
+        states
+    }
+}
+
+/// Keeps every key pressed for at least `min_ms`
+pub struct MinHold {
+    pub min_ms: u64,
+}
+
+impl PostProcessor for MinHold {
+    fn name(&self) -> &str {
+        "min-hold"
+    }
+
+    fn process(&self, states: Vec<MacroState>) -> Vec<MacroState> {
+        state::enforce_min_hold(&states, self.min_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Macro, Marker};
+
+    fn moving(duration_ms: u64, mouse_delta: (i32, i32)) -> MacroState {
+        MacroState {
+            mouse_delta,
+            ..MacroState::new(duration_ms)
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let mut registry = Registry::builtin();
+        let config = "# clean up\nnoise-filter 1\nmerge\n\nquantize 10ms # grid\n";
+        let pipeline = registry.parse_pipeline(config).unwrap();
+        assert_eq!(pipeline.names(), vec!["noise-filter", "merge", "quantize"]);
+
+        let states = vec![moving(4, (1, 0)), moving(4, (0, -1)), moving(3, (0, 1)), moving(8, (30, 2))];
+        let cleaned = pipeline.run(states);
+        // Jitter goes into the real move, the still states merge, and the
+        // boundaries land on the grid
+        assert_eq!(cleaned.len(), 2);
+        assert_eq!((cleaned[0].duration_ms, cleaned[0].mouse_delta), (10, (0, 0)));
+        assert_eq!((cleaned[1].duration_ms, cleaned[1].mouse_delta), (10, (31, 2)));
+
+        struct Halve;
+        impl PostProcessor for Halve {
+            fn name(&self) -> &str {
+                "halve"
+            }
+            fn process(&self, states: Vec<MacroState>) -> Vec<MacroState> {
+                states.into_iter().map(|state| MacroState::new(state.duration_ms / 2)).collect()
+            }
+        }
+        registry.register("halve", |_| Ok(Box::new(Halve)));
+        let custom = registry.parse_pipeline("halve\nidle-cap 3ms").unwrap();
+        let run: Vec<u64> = custom.run(vec![MacroState::new(20), MacroState::new(4)]).iter().map(|s| s.duration_ms).collect();
+        assert_eq!(run, vec![3, 2]);
+
+        // Markers split a macro into stretches the passes can't merge across
+        let mut macro_ = Macro {
+            states: vec![MacroState::new(5); 4],
+            markers: vec![Marker { index: 2, name: "half".into() }],
+            ..Default::default()
+        };
+        macro_.postprocess(&registry.parse_pipeline("merge").unwrap());
+        assert_eq!(macro_.states, vec![MacroState::new(10); 2]);
+        assert_eq!(macro_.marker("half"), Some(1));
+
+        assert!(registry.parse_pipeline("merge\nsmooth").unwrap_err().starts_with("Line 2: Unknown pass 'smooth'"));
+        assert!(registry.parse_pipeline("quantize").unwrap_err().contains("needs a grid"));
+        assert!(registry.parse_pipeline("merge 2").unwrap_err().contains("takes no argument"));
+    }
+}
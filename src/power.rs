@@ -0,0 +1,137 @@
+//! Power supply and lid state
+//!
+//! For the `power` and `lid` binding conditions (see [`crate::config`]), so
+//! idle and timed macros can stay quiet on battery or with the laptop's lid
+//! closed. A supply under [`POWER_SUPPLY_DIR`] that isn't a battery (mains,
+//! USB-C) being online means AC, and the `state` files under [`LID_DIR`] say
+//! whether the lid is open. Where those aren't there, `upower -d` is asked
+//! instead. A machine with neither battery nor lid, like a desktop, counts as
+//! on AC with its lid open.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Where the kernel lists power supplies
+pub const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Where ACPI lists lid switches
+pub const LID_DIR: &str = "/proc/acpi/button/lid";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerState {
+    pub on_ac: bool,
+    pub lid_open: bool,
+}
+
+/// The machine's power state right now
+pub fn read() -> PowerState {
+    let mut on_ac = ac_online(Path::new(POWER_SUPPLY_DIR));
+    let mut lid_open = lid_open(Path::new(LID_DIR));
+    if (on_ac.is_none() || lid_open.is_none())
+        && let Ok(output) = Command::new("upower").arg("-d").output()
+    {
+        let (upower_ac, upower_lid) = parse_upower(&String::from_utf8_lossy(&output.stdout));
+        on_ac = on_ac.or(upower_ac);
+        lid_open = lid_open.or(upower_lid);
+    }
+    PowerState {
+        on_ac: on_ac.unwrap_or(true),
+        lid_open: lid_open.unwrap_or(true),
+    }
+}
+
+/// Whether the supplies in `dir` run the machine off AC; `None` without any
+fn ac_online(dir: &Path) -> Option<bool> {
+    let read = |supply: &Path, file: &str| fs::read_to_string(supply.join(file)).ok().map(|s| s.trim().to_string());
+    let mut external = None;
+    let mut discharging = None;
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let supply = entry.path();
+        match read(&supply, "type").as_deref() {
+            // Peripherals (mice, headsets) report their batteries too
+            Some("Battery") if read(&supply, "scope").as_deref() == Some("Device") => {}
+            Some("Battery") => {
+                let status = read(&supply, "status");
+                discharging = Some(discharging.unwrap_or(false) || status.as_deref() == Some("Discharging"));
+            }
+            Some(_) => {
+                let online = read(&supply, "online").as_deref() == Some("1");
+                external = Some(external.unwrap_or(false) || online);
+            }
+            None => {}
+        }
+    }
+    external.or(discharging.map(|discharging| !discharging))
+}
+
+/// Whether every lid switch in `dir` is open; `None` without any
+fn lid_open(dir: &Path) -> Option<bool> {
+    let mut open = None;
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        if let Ok(state) = fs::read_to_string(entry.path().join("state")) {
+            // "state:      open"
+            let closed = state.split_whitespace().any(|word| word == "closed");
+            open = Some(open.unwrap_or(true) && !closed);
+        }
+    }
+    open
+}
+
+/// Whether on AC and whether the lid is open, from `upower -d`'s daemon
+/// section
+fn parse_upower(text: &str) -> (Option<bool>, Option<bool>) {
+    let mut on_ac = None;
+    let mut lid_open = None;
+    let mut lid_present = true;
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let yes = value.trim() == "yes";
+        match key.trim() {
+            "on-battery" => on_ac = Some(!yes),
+            "lid-is-closed" => lid_open = Some(!yes),
+            "lid-is-present" => lid_present = yes,
+            _ => {}
+        }
+    }
+    (on_ac, lid_open.filter(|_| lid_present))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_state() {
+        let dir = std::env::temp_dir().join(format!("evkey-power-{}", std::process::id()));
+        let supply = |name: &str, files: &[(&str, &str)]| {
+            let path = dir.join("power_supply").join(name);
+            fs::create_dir_all(&path).unwrap();
+            for (file, value) in files {
+                fs::write(path.join(file), format!("{}\n", value)).unwrap();
+            }
+        };
+        supply("BAT0", &[("type", "Battery"), ("status", "Discharging")]);
+        supply("hidpp_battery_0", &[("type", "Battery"), ("scope", "Device"), ("status", "Charging")]);
+        assert_eq!(ac_online(&dir.join("power_supply")), Some(false));
+        supply("AC", &[("type", "Mains"), ("online", "0")]);
+        assert_eq!(ac_online(&dir.join("power_supply")), Some(false));
+        supply("ucsi-source-psy-USBC000:001", &[("type", "USB"), ("online", "1")]);
+        assert_eq!(ac_online(&dir.join("power_supply")), Some(true));
+        assert_eq!(ac_online(&dir.join("nothing")), None);
+
+        fs::create_dir_all(dir.join("lid/LID0")).unwrap();
+        fs::write(dir.join("lid/LID0/state"), "state:      open\n").unwrap();
+        assert_eq!(lid_open(&dir.join("lid")), Some(true));
+        fs::write(dir.join("lid/LID0/state"), "state:      closed\n").unwrap();
+        assert_eq!(lid_open(&dir.join("lid")), Some(false));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let upower = "Daemon:\n  daemon-version:  1.90.2\n  on-battery:      yes\n  lid-is-closed:   no\n  lid-is-present:  yes\n";
+        assert_eq!(parse_upower(upower), (Some(false), Some(true)));
+        let desktop = "Daemon:\n  on-battery:      no\n  lid-is-closed:   no\n  lid-is-present:  no\n";
+        assert_eq!(parse_upower(desktop), (Some(true), None));
+    }
+}
@@ -0,0 +1,150 @@
+//! Playing the end of a macro on its own
+//!
+//! Editors iterating on the end of a long macro want to hear just that part
+//! ("preview from here") rather than sit through everything before it. The
+//! states from an index onward play back like that part of the whole macro
+//! as long as they start from what the states before them left behind: the
+//! keys held going in, and lock keys tapped an odd number of times.
+//!
+//! A [`PrefixCache`] works that out for each index in one pass over the
+//! states before it, and keeps the answers. An edit only changes what comes
+//! after the first state it touches, so [`PrefixCache::invalidate`] drops just
+//! those, and asking again after editing the end of the macro only goes over
+//! the states from there on.
+
+use crate::keyset::KeySet;
+use crate::locks::{KEY_CAPSLOCK, KEY_NUMLOCK, KEY_SCROLLLOCK, LockState};
+use crate::storage::Macro;
+
+/// Where playing a macro stands when it reaches a state
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prefix {
+    /// When the state starts, in microseconds
+    pub start_us: u64,
+    /// Keys held going into it
+    pub held: KeySet,
+    /// Lock keys tapped an odd number of times before it
+    pub toggled: LockState,
+}
+
+impl Prefix {
+    /// Lock state going into the state, if the macro says what it started
+    /// from
+    pub fn locks(&self, macro_: &Macro) -> Option<LockState> {
+        macro_.metadata.locks.map(|start| LockState {
+            caps: start.caps != self.toggled.caps,
+            num: start.num != self.toggled.num,
+            scroll: start.scroll != self.toggled.scroll,
+        })
+    }
+}
+
+/// [`Prefix`]es of the states of one macro, worked out as far as they've
+/// been asked for
+#[derive(Debug, Clone, Default)]
+pub struct PrefixCache {
+    /// The one for state `i` at `i`, for as many states as are known
+    prefixes: Vec<Prefix>,
+}
+
+impl PrefixCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget what changing the state at `index` (or inserting or removing
+    /// one there) can change: the prefixes of the states after it
+    pub fn invalidate(&mut self, index: usize) {
+        self.prefixes.truncate(index + 1);
+    }
+
+    /// The prefix of the state at `index` in `macro_`, or of its end at
+    /// `states.len()`; `None` past that
+    pub fn at(&mut self, macro_: &Macro, index: usize) -> Option<&Prefix> {
+        if index > macro_.states.len() {
+            return None;
+        }
+        if self.prefixes.is_empty() {
+            self.prefixes.push(Prefix::default());
+        }
+        while self.prefixes.len() <= index {
+            let at = self.prefixes.len() - 1;
+            let before = &self.prefixes[at];
+            let state = &macro_.states[at];
+            let mut toggled = before.toggled;
+            for key in state.keys_pressed.codes().filter(|&key| !before.held.contains(key)) {
+                match key {
+                    KEY_CAPSLOCK => toggled.caps = !toggled.caps,
+                    KEY_NUMLOCK => toggled.num = !toggled.num,
+                    KEY_SCROLLLOCK => toggled.scroll = !toggled.scroll,
+                    _ => {}
+                }
+            }
+            let next = Prefix {
+                start_us: before.start_us + state.duration_ms * 1000,
+                held: state.keys_pressed.clone(),
+                toggled,
+            };
+            self.prefixes.push(next);
+        }
+        self.prefixes.get(index)
+    }
+}
+
+/// The states of `macro_` from `index` on, to play as they would at the end
+/// of the whole macro
+///
+/// Keys held going in that the first state keeps holding are pressed as it
+/// starts, and the ones it lets go of were never pressed, so the tail only
+/// differs from the whole in what it doesn't repeat.
+pub fn tail(macro_: &Macro, index: usize) -> Macro {
+    macro_.slice(index, macro_.states.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MacroState;
+
+    #[test]
+    fn test_prefix_cache() {
+        let state = |duration_ms, keys: &[u16]| {
+            let mut state = MacroState::new(duration_ms);
+            for &key in keys {
+                state.keys_pressed.insert(key);
+            }
+            state
+        };
+        let mut macro_ = Macro {
+            states: vec![
+                state(100, &[42]),
+                state(50, &[42, 30]),
+                state(20, &[KEY_CAPSLOCK]),
+                state(30, &[]),
+                state(40, &[17]),
+            ],
+            ..Default::default()
+        };
+        macro_.metadata.locks = Some(LockState { num: true, ..Default::default() });
+
+        let mut cache = PrefixCache::new();
+        let prefix = cache.at(&macro_, 2).unwrap().clone();
+        assert_eq!(prefix.start_us, 150_000);
+        assert_eq!(prefix.held.codes().collect::<Vec<_>>(), [30, 42]);
+        assert_eq!(prefix.toggled, LockState::default());
+        let end = cache.at(&macro_, 5).unwrap().clone();
+        assert_eq!(end.start_us, 240_000);
+        assert_eq!(end.locks(&macro_), Some(LockState { caps: true, num: true, scroll: false }));
+        assert_eq!(cache.at(&macro_, 6), None);
+
+        // Editing state 4 leaves what comes before it alone
+        macro_.states[4].keys_pressed.insert(KEY_CAPSLOCK);
+        cache.invalidate(4);
+        assert_eq!(cache.at(&macro_, 2), Some(&prefix));
+        assert_eq!(cache.at(&macro_, 5).unwrap().toggled, LockState::default());
+
+        let rest = tail(&macro_, 4);
+        assert_eq!(rest.states.len(), 1);
+        assert!(rest.states[0].keys_pressed.contains(17));
+    }
+}
@@ -0,0 +1,90 @@
+//! Terminal progress bar for `evkey play`
+
+use evkey::player::Progress;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+/// Minimum time between redraws
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Draws a single updating line on stderr
+pub struct ProgressBar {
+    state_count: usize,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressBar {
+    pub fn new(state_count: usize) -> Self {
+        Self {
+            state_count,
+            last_draw: None,
+        }
+    }
+
+    /// Redraw for the latest progress, at most every `REDRAW_INTERVAL`
+    pub fn update(&mut self, progress: &Progress) {
+        let done = progress.is_done();
+        if !done && self.last_draw.is_some_and(|t| t.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let mut stderr = io::stderr().lock();
+        // Progress output is best-effort
+        let _ = write!(stderr, "\r{}", render(progress, self.state_count));
+        if done {
+            let _ = writeln!(stderr);
+            self.last_draw = None;
+        }
+        let _ = stderr.flush();
+    }
+}
+
+/// Format one progress line, e.g. `[#####-----]  50%  0:05 / 0:10  ETA 0:05`
+fn render(progress: &Progress, state_count: usize) -> String {
+    let filled = (progress.fraction() * BAR_WIDTH as f64).round() as usize;
+    let mut line = format!(
+        "[{}{}] {:>3}%  {} / {}  ETA {}",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        (progress.fraction() * 100.0).round() as u32,
+        clock(Duration::from_micros(progress.position_us)),
+        clock(Duration::from_micros(progress.total_us)),
+        clock(progress.remaining()),
+    );
+    if let (Some(index), true) = (progress.state_index, state_count > 0) {
+        line.push_str(&format!("  state {}/{}", index + 1, state_count));
+    }
+    line
+}
+
+/// Format a duration as `m:ss`, or `h:mm:ss` past an hour
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let progress = Progress {
+            position_us: 5_000_000,
+            total_us: 10_000_000,
+            state_index: Some(2),
+            elapsed: Duration::from_secs(5),
+        };
+        let line = render(&progress, 12);
+
+        assert!(line.starts_with(&format!("[{}{}]", "#".repeat(15), "-".repeat(15))));
+        assert!(line.contains(" 50%  0:05 / 0:10  ETA 0:05  state 3/12"));
+        assert_eq!(clock(Duration::from_secs(3725)), "1:02:05");
+    }
+}
@@ -0,0 +1,230 @@
+//! Forwarding a grabbed device through a virtual copy of it
+//!
+//! `evkey proxy` grabs a physical device, so nothing else sees its events,
+//! and re-emits every event on a uinput device with the same capabilities.
+//! The time from the kernel stamping an event to EvKey having forwarded it is
+//! kept in [`Latencies`], to put numbers on the overhead of going through
+//! EvKey (and, later, of remapping on the way).
+
+use evdev::uinput::VirtualDevice;
+use evdev::{Device, EventType, UinputAbsSetup};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, poll};
+use std::fmt;
+use std::io;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Width of a latency bucket, and the latency beyond which they stop (in
+/// microseconds); slower events all land in the last bucket
+const BUCKET_US: u64 = 10;
+const MAX_BUCKETED_US: u64 = 100_000;
+
+/// How long [`Proxy::forward`] waits for events before returning (in
+/// milliseconds), so callers can check for Ctrl+C
+const POLL_TIMEOUT_MS: u16 = 100;
+
+/// Distribution of forwarding latencies, in constant memory
+#[derive(Debug, Clone)]
+pub struct Latencies {
+    buckets: Vec<u64>,
+    count: u64,
+    total_us: u64,
+    max_us: u64,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Latencies {
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![0; (MAX_BUCKETED_US / BUCKET_US) as usize + 1],
+            count: 0,
+            total_us: 0,
+            max_us: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u128::from(u64::MAX)) as u64;
+        let bucket = (us / BUCKET_US).min(self.buckets.len() as u64 - 1);
+        self.buckets[bucket as usize] += 1;
+        self.count += 1;
+        self.total_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(self.total_us.checked_div(self.count).unwrap_or(0))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us)
+    }
+
+    /// Latency that `percent`% of events stayed within, to the bucket width
+    pub fn percentile(&self, percent: f64) -> Duration {
+        let rank = ((percent / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && bucket + 1 < self.buckets.len() {
+                let upper_us = (bucket as u64 + 1) * BUCKET_US;
+                return Duration::from_micros(upper_us.min(self.max_us));
+            }
+        }
+        self.max()
+    }
+}
+
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |latency: Duration| format!("{:.2}ms", latency.as_secs_f64() * 1000.0);
+        write!(
+            f,
+            "{} events, mean {}, p50 {}, p90 {}, p99 {}, max {}",
+            self.count,
+            ms(self.mean()),
+            ms(self.percentile(50.0)),
+            ms(self.percentile(90.0)),
+            ms(self.percentile(99.0)),
+            ms(self.max())
+        )
+    }
+}
+
+/// A grabbed device and the virtual device its events are forwarded to
+pub struct Proxy {
+    source: Device,
+    output: VirtualDevice,
+    /// Latencies since the proxy started
+    pub total: Latencies,
+    /// Latencies since [`Proxy::take_interval`] was last called
+    interval: Latencies,
+}
+
+impl Proxy {
+    /// Grab the device at `path` and create its virtual copy
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut source = Device::open(path)?;
+        let name = format!("evkey-proxy ({})", source.name().unwrap_or("unknown"));
+        let output = mirror(&source, &name)?;
+
+        // Let go of any keys held when grabbing, or they'd never be released
+        // for the desktop
+        while source.get_key_state()?.iter().next().is_some() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        source.grab()?;
+        source.set_nonblocking(true)?;
+        info!("Forwarding {} as {}", source.name().unwrap_or("unknown"), name);
+
+        Ok(Self {
+            source,
+            output,
+            total: Latencies::new(),
+            interval: Latencies::new(),
+        })
+    }
+
+    /// Wait briefly for events and forward them; returns how many were
+    /// forwarded (zero on timeout)
+    pub fn forward(&mut self) -> io::Result<usize> {
+        let mut fds = [PollFd::new(self.source.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, POLL_TIMEOUT_MS) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let events: Vec<evdev::InputEvent> = match self.source.fetch_events() {
+            // The virtual device adds its own SYN_REPORT to each batch
+            Ok(events) => events.filter(|event| event.event_type() != EventType::SYNCHRONIZATION).collect(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        self.output.emit(&events)?;
+        let now = SystemTime::now();
+        for event in &events {
+            let latency = now.duration_since(event.timestamp()).unwrap_or_default();
+            self.total.record(latency);
+            self.interval.record(latency);
+        }
+        Ok(events.len())
+    }
+
+    /// Latencies since the last call, starting a new interval
+    pub fn take_interval(&mut self) -> Latencies {
+        std::mem::take(&mut self.interval)
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        if let Err(e) = self.source.ungrab() {
+            warn!("Can't release the grabbed device: {}", e);
+        }
+    }
+}
+
+/// A virtual device with the same capabilities and ids as `source`
+fn mirror(source: &Device, name: &str) -> io::Result<VirtualDevice> {
+    let mut builder = VirtualDevice::builder()?
+        .name(name)
+        .input_id(source.input_id())
+        .with_properties(source.properties())?;
+    if let Some(keys) = source.supported_keys() {
+        builder = builder.with_keys(keys)?;
+    }
+    if let Some(axes) = source.supported_relative_axes() {
+        builder = builder.with_relative_axes(axes)?;
+    }
+    for (axis, info) in source.get_absinfo()? {
+        builder = builder.with_absolute_axis(&UinputAbsSetup::new(axis, info))?;
+    }
+    if let Some(switches) = source.supported_switches() {
+        builder = builder.with_switches(switches)?;
+    }
+    if let Some(misc) = source.misc_properties() {
+        builder = builder.with_msc(misc)?;
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut latencies = Latencies::new();
+        assert_eq!(latencies.percentile(99.0), Duration::ZERO);
+
+        for us in 1..=100 {
+            latencies.record(Duration::from_micros(us * 20));
+        }
+        latencies.record(Duration::from_secs(1));
+
+        assert_eq!(latencies.count(), 101);
+        // Upper edge of the 10µs bucket
+        assert_eq!(latencies.percentile(50.0), Duration::from_micros(1_030));
+        assert_eq!(latencies.percentile(99.0), Duration::from_micros(2_010));
+        assert_eq!(latencies.percentile(100.0), Duration::from_secs(1));
+        assert_eq!(latencies.max(), Duration::from_secs(1));
+        assert_eq!(latencies.mean(), Duration::from_micros(1_101_000 / 101));
+        assert!(latencies.to_string().starts_with("101 events, mean 10.90ms, p50 1.03ms"));
+    }
+}
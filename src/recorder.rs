@@ -1,68 +1,233 @@
 //! Recording input events from keyboard and mouse
 
-use evdev::{Device, InputEvent, EventSummary, KeyCode};
+use crate::clock::{Clock, SystemClock};
+use crate::event::{EventType, InputEvent};
+use crate::filter::EventFilter;
+use crate::input_key::InputKey;
+use crate::locks::LockState;
+use crate::screen;
+use crate::stats;
+use crate::storage::{Environment, RecordedDevice};
+use evdev::{Device, EventSummary, KeyCode, RelativeAxisCode};
 use std::io;
-use std::path::Path;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
 
-/// Recorded event with relative timestamp
+pub use crate::event::{RecordedClick, RecordedEvent, RecordedMarker};
+
+/// A keyboard or mouse found under /dev/input
 #[derive(Debug, Clone)]
-pub struct RecordedEvent {
-    /// Time since recording started (in microseconds)
-    pub timestamp_us: u64,
-    /// The actual input event
-    pub event: InputEvent,
+pub struct InputDevice {
+    pub path: PathBuf,
+    pub name: String,
+    /// "keyboard", "mouse" or "keyboard+mouse"
+    pub kind: &'static str,
+}
+
+/// Find all keyboards and mice we can open
+///
+/// Devices we lack permission for are skipped silently.
+pub fn find_input_devices() -> io::Result<Vec<InputDevice>> {
+    let mut found = Vec::new();
+
+    for entry in std::fs::read_dir("/dev/input")? {
+        let path = entry?.path();
+        if !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("event")) {
+            continue;
+        }
+        let Ok(device) = Device::open(&path) else {
+            // Skip devices we can't open (permission issues, etc.)
+            continue;
+        };
+
+        let Some(kind) = device_kind(&device) else {
+            continue;
+        };
+
+        found.push(InputDevice {
+            name: device.name().unwrap_or("unknown").to_string(),
+            path,
+            kind,
+        });
+    }
+
+    Ok(found)
+}
+
+/// "keyboard", "mouse" or "keyboard+mouse", or `None` for anything else
+fn device_kind(device: &Device) -> Option<&'static str> {
+    // Check if device has keys (keyboard) or relative axes (mouse)
+    let has_keys = device.supported_keys().is_some_and(|keys| keys.iter().len() > 0);
+    let has_relative = device.supported_relative_axes().is_some_and(|axes| axes.iter().len() > 0);
+    match (has_keys, has_relative) {
+        (true, true) => Some("keyboard+mouse"),
+        (true, false) => Some("keyboard"),
+        (false, true) => Some("mouse"),
+        _ => None,
+    }
+}
+
+/// Time into a recording (in microseconds) without its pauses, or `None`
+/// when not recording or paused
+fn recording_time_us(
+    clock: &dyn Clock,
+    start_time: Option<Duration>,
+    paused_at: Option<Duration>,
+    paused_total: Duration,
+) -> Option<u64> {
+    if paused_at.is_some() {
+        return None;
+    }
+    let elapsed = clock.since(start_time?).saturating_sub(paused_total);
+    Some(elapsed.as_micros() as u64)
 }
 
 pub struct Recorder {
+    /// Timestamps events and pauses
+    clock: Box<dyn Clock>,
     devices: Vec<Device>,
-    start_time: Option<Instant>,
+    /// Track each device's events go to, named after its kind
+    device_tracks: Vec<&'static str>,
+    /// Clock reading the recording started at
+    start_time: Option<Duration>,
+    /// When the recording was paused, if it is
+    paused_at: Option<Duration>,
+    /// Time spent paused so far, left out of the timestamps
+    paused_total: Duration,
     events: Vec<RecordedEvent>,
+    /// Index into `devices` of the device each event came from
+    sources: Vec<usize>,
+    markers: Vec<RecordedMarker>,
+    /// Whether to read the pointer position on every click
+    click_positions: bool,
+    clicks: Vec<RecordedClick>,
+    /// Lock key state captured when recording started
+    locks: Option<LockState>,
+    /// Whether F1 and F2 control the recording instead of being recorded
+    hotkeys: bool,
+    /// Garbage taken out of the events before they're handed over
+    filter: EventFilter,
+    /// Kernel timestamp of each device's last motion report, and the
+    /// intervals between its reports (in microseconds)
+    last_motion: Vec<Option<SystemTime>>,
+    motion_intervals: Vec<Vec<u64>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Recorder {
     pub fn new() -> Self {
         Self {
+            clock: Box::new(SystemClock::new()),
             devices: Vec::new(),
+            device_tracks: Vec::new(),
             start_time: None,
+            paused_at: None,
+            paused_total: Duration::ZERO,
             events: Vec::new(),
+            sources: Vec::new(),
+            markers: Vec::new(),
+            click_positions: false,
+            clicks: Vec::new(),
+            locks: None,
+            hotkeys: true,
+            filter: EventFilter::default(),
+            last_motion: Vec::new(),
+            motion_intervals: Vec::new(),
         }
     }
 
+    /// Timestamp events by `clock` instead of real time
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Record F1 and F2 like any other key, for recordings started and
+    /// stopped by the caller
+    pub fn disable_hotkeys(&mut self) {
+        self.hotkeys = false;
+    }
+
+    /// Read the absolute pointer position from the display server whenever a
+    /// mouse button is pressed while recording
+    pub fn capture_click_positions(&mut self, enabled: bool) {
+        self.click_positions = enabled;
+    }
+
+    /// Drop chattering presses and phantom keys before handing events over
+    pub fn set_filter(&mut self, filter: EventFilter) {
+        self.filter = filter;
+    }
+
     /// Add a device to record from
     pub fn add_device<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
         let device = Device::open(path)?;
         device.set_nonblocking(true)?;
-        println!("Added device: {}", device.name().unwrap_or("unknown"));
+        info!("Added device: {}", device.name().unwrap_or("unknown"));
+        self.device_tracks.push(device_kind(&device).unwrap_or("other"));
+        self.last_motion.push(None);
+        self.motion_intervals.push(Vec::new());
         self.devices.push(device);
         Ok(())
     }
 
-    /// Start recording
+    /// Take the devices added so far for ourselves, so what's recorded
+    /// doesn't also reach the desktop
+    pub fn grab_devices(&mut self) -> io::Result<()> {
+        for device in &mut self.devices {
+            device.grab()?;
+        }
+        Ok(())
+    }
+
+    /// Start recording without waiting for the F1 hotkey
     pub fn start(&mut self) {
-        self.start_time = Some(Instant::now());
+        self.start_time = Some(self.clock.now());
+        self.paused_at = None;
+        self.paused_total = Duration::ZERO;
         self.events.clear();
-        println!("Recording started...");
+        self.sources.clear();
+        self.markers.clear();
+        self.clicks.clear();
+        self.clear_motion_intervals();
+        self.locks = self.read_lock_state();
+        info!("Recording started...");
     }
 
     /// Poll all devices and record events
     /// Returns true if recording state changed (started or stopped)
     pub fn poll(&mut self) -> io::Result<bool> {
         let mut state_changed = false;
+        let mut start_requested = false;
 
-        for device in &mut self.devices {
+        for (device_index, device) in self.devices.iter_mut().enumerate() {
             match device.fetch_events() {
                 Ok(events) => {
                     for event in events {
-                        if let EventSummary::Key(_, key, value) = event.destructure() {
+                        if let EventSummary::Key(_, key, value) = event.destructure()
+                            && self.hotkeys
+                        {
                             if key == KeyCode::KEY_F1 && value == 1 {
                                 // F1 pressed - toggle recording state
-                                println!("F1 key pressed!");
+                                debug!("F1 key pressed");
                                 if self.start_time.is_none() {
                                     // Start recording
-                                    self.start_time = Some(Instant::now());
+                                    self.start_time = Some(self.clock.now());
+                                    self.paused_at = None;
+                                    self.paused_total = Duration::ZERO;
                                     self.events.clear();
+                                    self.sources.clear();
+                                    self.markers.clear();
+                                    self.clicks.clear();
+                                    self.last_motion.fill(None);
+                                    self.motion_intervals.iter_mut().for_each(Vec::clear);
                                     state_changed = true;
+                                    start_requested = true;
                                 } else {
                                     // Stop recording
                                     self.start_time = None;
@@ -70,16 +235,58 @@ impl Recorder {
                                 }
                                 continue; // Don't record the F1 press itself
                             }
+
+                            if key == KeyCode::KEY_F2 {
+                                // F2 is the annotation key - never recorded itself
+                                if value == 1 {
+                                    if let Some(timestamp_us) = recording_time_us(self.clock.as_ref(), self.start_time, self.paused_at, self.paused_total) {
+                                        let name = format!("M{}", self.markers.len() + 1);
+                                        info!("Marker {} added", name);
+                                        self.markers.push(RecordedMarker { timestamp_us, name });
+                                    }
+                                }
+                                continue;
+                            }
                         }
                         // Only record events if we're currently recording
-                        if let Some(start_time) = self.start_time {
-                            let elapsed = start_time.elapsed();
-                            let timestamp_us = elapsed.as_micros() as u64;
+                        if let Some(timestamp_us) = recording_time_us(self.clock.as_ref(), self.start_time, self.paused_at, self.paused_total) {
+                            debug!(
+                                timestamp_us,
+                                "{:?} code={} value={}",
+                                event.event_type(),
+                                event.code(),
+                                event.value()
+                            );
 
                             self.events.push(RecordedEvent {
                                 timestamp_us,
-                                event,
+                                event: event.into(),
                             });
+                            self.sources.push(device_index);
+
+                            // REL_X and REL_Y of one report share a timestamp
+                            if let EventSummary::RelativeAxis(_, axis, _) = event.destructure()
+                                && matches!(axis, RelativeAxisCode::REL_X | RelativeAxisCode::REL_Y)
+                                && self.last_motion[device_index] != Some(event.timestamp())
+                            {
+                                if let Some(interval) = self.last_motion[device_index]
+                                    .and_then(|last| event.timestamp().duration_since(last).ok())
+                                {
+                                    self.motion_intervals[device_index].push(interval.as_micros() as u64);
+                                }
+                                self.last_motion[device_index] = Some(event.timestamp());
+                            }
+
+                            let is_click = matches!(event.destructure(), EventSummary::Key(_, key, 1) if InputKey::from(key.code()).is_mouse_button());
+                            if is_click && self.click_positions {
+                                match screen::pointer_position() {
+                                    Ok((x, y)) => self.clicks.push(RecordedClick { timestamp_us, x, y }),
+                                    Err(e) => {
+                                        warn!("Not capturing click positions: {}", e);
+                                        self.click_positions = false;
+                                    }
+                                }
+                            }
                         }
                     }
                 }
@@ -88,15 +295,124 @@ impl Recorder {
                     continue;
                 }
                 Err(e) => {
-                    eprintln!("Device read error: {}", e);
+                    warn!("Device read error: {}", e);
                 }
 
             }
         }
 
+        if start_requested {
+            self.locks = self.read_lock_state();
+        }
+
         Ok(state_changed)
     }
 
+    /// Stop taking in events until [`Recorder::resume`], letting go of the
+    /// keys held so far so none stays down over the gap
+    pub fn pause(&mut self) {
+        let Some(timestamp_us) = recording_time_us(self.clock.as_ref(), self.start_time, self.paused_at, self.paused_total) else {
+            return;
+        };
+        let mut held: Vec<(u16, usize)> = Vec::new();
+        for (recorded, &source) in self.events.iter().zip(&self.sources) {
+            if recorded.event.event_type() != EventType::KEY {
+                continue;
+            }
+            let code = recorded.event.code();
+            held.retain(|&(key, _)| key != code);
+            if recorded.event.value() != 0 {
+                held.push((code, source));
+            }
+        }
+        for (code, source) in held {
+            for event in [InputEvent::new(EventType::KEY.0, code, 0), InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0)] {
+                self.events.push(RecordedEvent { timestamp_us, event });
+                self.sources.push(source);
+            }
+        }
+        self.paused_at = Some(self.clock.now());
+        info!("Recording paused");
+    }
+
+    /// Take in events again after [`Recorder::pause`]; the recording carries
+    /// on from where it was paused
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.paused_total += self.clock.since(paused_at);
+            // The gap isn't a polling interval
+            self.last_motion.fill(None);
+            info!("Recording resumed");
+        }
+    }
+
+    /// Time into the recording (in microseconds) without its pauses, or
+    /// `None` when not recording or paused
+    pub fn position_us(&self) -> Option<u64> {
+        recording_time_us(self.clock.as_ref(), self.start_time, self.paused_at, self.paused_total)
+    }
+
+    /// Check if the recording is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Markers added during the current (or last) recording
+    pub fn markers(&self) -> &[RecordedMarker] {
+        &self.markers
+    }
+
+    /// Pointer positions of the clicks in the current (or last) recording
+    pub fn clicks(&self) -> &[RecordedClick] {
+        &self.clicks
+    }
+
+    /// Polling rate of the mouse that moved the most in the current (or last)
+    /// recording, if it moved enough to tell
+    pub fn polling_hz(&self) -> Option<u32> {
+        let busiest = self.motion_intervals.iter().max_by_key(|intervals| intervals.len())?;
+        stats::polling_hz(busiest)
+    }
+
+    fn clear_motion_intervals(&mut self) {
+        self.last_motion.fill(None);
+        self.motion_intervals.iter_mut().for_each(Vec::clear);
+    }
+
+    /// EvKey's version, the kernel release and the devices recorded from,
+    /// for the macro's header
+    pub fn environment(&self) -> Environment {
+        Environment {
+            evkey: Some(env!("CARGO_PKG_VERSION").to_string()),
+            kernel: std::fs::read_to_string("/proc/sys/kernel/osrelease")
+                .inspect_err(|e| warn!("Not storing the kernel release: {}", e))
+                .ok()
+                .map(|release| release.trim().to_string()),
+            devices: self
+                .devices
+                .iter()
+                .map(|device| RecordedDevice {
+                    name: device.name().unwrap_or("unknown").to_string(),
+                    vendor: device.input_id().vendor(),
+                    product: device.input_id().product(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Lock key state when the current (or last) recording started
+    pub fn lock_state(&self) -> Option<LockState> {
+        self.locks
+    }
+
+    /// Read lock LEDs from the first recorded device that has them
+    fn read_lock_state(&self) -> Option<LockState> {
+        self.devices
+            .iter()
+            .filter(|device| device.supported_leds().is_some())
+            .find_map(|device| LockState::from_device(device).ok())
+    }
+
     /// Check if currently recording
     pub fn is_recording(&self) -> bool {
         self.start_time.is_some()
@@ -105,12 +421,86 @@ impl Recorder {
     /// Stop recording and return recorded events
     pub fn stop(&mut self) -> Vec<RecordedEvent> {
         self.start_time = None;
-        println!("Recording stopped. Recorded {} events", self.events.len());
+        self.paused_at = None;
+        self.drop_garbage();
+        info!("Recording stopped. Recorded {} events", self.events.len());
+        self.sources.clear();
         std::mem::take(&mut self.events)
     }
 
+    /// Stop recording and return the events split into one track per kind of
+    /// device ("keyboard", "mouse", ...), in the order the tracks first appear
+    pub fn stop_tracks(&mut self) -> Vec<(String, Vec<RecordedEvent>)> {
+        self.drop_garbage();
+        let sources = std::mem::take(&mut self.sources);
+        let mut tracks: Vec<(String, Vec<RecordedEvent>)> = Vec::new();
+
+        for (event, source) in self.stop().into_iter().zip(sources) {
+            let name = self.device_tracks[source];
+            match tracks.iter_mut().find(|(track, _)| track == name) {
+                Some((_, events)) => events.push(event),
+                None => tracks.push((name.to_string(), vec![event])),
+            }
+        }
+
+        tracks
+    }
+
     /// Get currently recorded events without stopping
     pub fn events(&self) -> &[RecordedEvent] {
         &self.events
     }
+
+    /// Hand over the events recorded since the last call and keep recording,
+    /// for streaming them out as they happen
+    ///
+    /// A chattering press only gets filtered out when its release comes in
+    /// the same batch.
+    pub fn take_events(&mut self) -> Vec<RecordedEvent> {
+        self.drop_garbage();
+        self.sources.clear();
+        std::mem::take(&mut self.events)
+    }
+
+    /// Run the filter over the events recorded so far, keeping `sources` in
+    /// step
+    fn drop_garbage(&mut self) {
+        if self.filter.is_empty() {
+            return;
+        }
+        let keep = self.filter.keep(&self.events);
+        let dropped = keep.iter().filter(|keep| !**keep).count();
+        if dropped > 0 {
+            debug!("Filtered out {} garbage events", dropped);
+        }
+        let mut kept = keep.iter();
+        self.events.retain(|_| *kept.next().unwrap());
+        let mut kept = keep.iter();
+        self.sources.retain(|_| *kept.next().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::VirtualClock;
+
+    #[test]
+    fn test_position_leaves_out_pauses() {
+        let clock = VirtualClock::new();
+        let mut recorder = Recorder::new();
+        recorder.set_clock(clock.clone());
+        assert_eq!(recorder.position_us(), None);
+
+        recorder.start();
+        clock.advance(Duration::from_millis(120));
+        assert_eq!(recorder.position_us(), Some(120_000));
+
+        recorder.pause();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(recorder.position_us(), None);
+        recorder.resume();
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(recorder.position_us(), Some(125_000));
+    }
 }
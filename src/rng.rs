@@ -0,0 +1,75 @@
+//! Seeded randomness
+//!
+//! Everything EvKey randomizes (so far the autoclicker's `--jitter`) draws
+//! from an [`Rng`] built from a seed. Left to itself the seed comes from the
+//! clock, but it's always logged, and passing the same seed back (`--seed`)
+//! makes a run draw exactly the same numbers again, e.g. to reproduce a
+//! problem.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// xorshift64*, plenty for timing jitter
+#[derive(Debug, Clone)]
+pub struct Rng {
+    seed: u64,
+    state: u64,
+}
+
+impl Rng {
+    /// The numbers `seed` stands for; every seed is fine, 0 included
+    pub fn new(seed: u64) -> Self {
+        // splitmix64, so nearby seeds start far apart (and never at 0,
+        // where xorshift would stay)
+        let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        state ^= state >> 31;
+        Self {
+            seed,
+            state: state.max(1),
+        }
+    }
+
+    /// Seeded from `seed` if given, or else from the clock
+    pub fn from_seed_or_clock(seed: Option<u64>) -> Self {
+        Self::new(seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+        }))
+    }
+
+    /// The seed this generator started from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform-ish value in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded() {
+        let draw = |rng: &mut Rng| (0..100).map(|_| rng.below(1000)).collect::<Vec<_>>();
+        let mut first = Rng::new(42);
+        assert_eq!(draw(&mut first), draw(&mut Rng::new(42)));
+        assert_ne!(draw(&mut Rng::new(42)), draw(&mut Rng::new(43)));
+        assert_eq!(first.seed(), 42);
+
+        // 0 is a seed like any other
+        let zeros = draw(&mut Rng::new(0));
+        assert!(zeros.iter().any(|&n| n != 0));
+        assert_eq!(Rng::from_seed_or_clock(Some(7)).seed(), 7);
+    }
+}
@@ -0,0 +1,557 @@
+//! JSON-RPC over stdio, for editor plugins and GUIs
+//!
+//! `evkey rpc` reads JSON-RPC 2.0 requests from stdin, one per line, and
+//! answers each on a line of stdout. Macros are opened by path and edited in
+//! memory until saved:
+//!
+//!   load      {"path"}                  open a macro; returns what `inspect` does
+//!   inspect   {"path"}                  states, markers, actions and undo state
+//!   edit      {"path", "op", ...}       set_duration {index, duration_ms},
+//!                                       set_keys {index, keys}, insert_state
+//!                                       {index, duration_ms, keys?}, remove_state
+//!                                       {index}, retime_range {from, to, factor},
+//!                                       undo, redo
+//!   save      {"path", "to"?}           write the macro, to its own path or `to`
+//!   close     {"path"}                  forget the macro, unsaved edits included
+//!   play      {"path", "backend"?,      start playing it in the background;
+//!              "from"?}                  with `from`, only the states from that
+//!                                       index on, starting with what the ones
+//!                                       before leave held and the lock keys
+//!                                       tapped to match ("preview from here")
+//!   record    {"path"}                  start recording from every keyboard and
+//!                                       mouse into a new macro at `path`
+//!   stop      {}                        stop playback, or finish the recording
+//!                                       (which is then open, not yet saved)
+//!   shutdown  {}                        answer, then exit
+//!
+//! While playing, `progress` notifications carry `position_us`, `total_us`
+//! and `state_index`, and a `finished` notification (with an `error` if it
+//! failed) follows the end of playback. Previews count those in the whole
+//! macro, and `play` answers them with `start_ms`, the keys `held` going into
+//! the first state and the `locks` lit there (see [`crate::preview`]).
+
+use crate::backend::{self, Backend, BackendKind};
+use crate::config;
+use crate::edit::Editor;
+use crate::event::RecordedEvent;
+use crate::keymap;
+use crate::locks::{self, LockState};
+use crate::player::Player;
+use crate::preview;
+use crate::recorder::{self, Recorder};
+use crate::state::MacroState;
+use crate::storage::{self, Macro, Metadata};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Error codes from the JSON-RPC 2.0 spec
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Anything that went wrong doing what was asked
+const SERVER_ERROR: i64 = -32000;
+
+/// Where responses and notifications go, shared with the playback thread
+type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        RpcError {
+            code: INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<String> for RpcError {
+    fn from(message: String) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message,
+        }
+    }
+}
+
+impl From<io::Error> for RpcError {
+    fn from(error: io::Error) -> Self {
+        error.to_string().into()
+    }
+}
+
+/// Playback running on a background thread
+struct Playback {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+/// Recording running on a background thread, for the macro at `path`
+struct Recording {
+    path: String,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Recorder>,
+}
+
+/// Serve requests from `input` until it ends or `shutdown` is called
+pub fn serve<R: BufRead, W: Write + Send + 'static>(input: R, output: W) -> io::Result<()> {
+    let mut server = Server::new(Box::new(output));
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = server.handle_line(&line) {
+            send(&server.out, &response)?;
+        }
+        if server.shutting_down {
+            break;
+        }
+    }
+    server.stop_all();
+    Ok(())
+}
+
+fn send(out: &Output, message: &Value) -> io::Result<()> {
+    let mut out = out.lock().unwrap_or_else(|e| e.into_inner());
+    writeln!(out, "{}", message)?;
+    out.flush()
+}
+
+fn notify(out: &Output, method: &str, params: Value) {
+    // A client that went away can't be told anything anymore
+    let _ = send(out, &json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+}
+
+/// Opens the device a playback goes to, on the playback's thread
+type OpenBackend = Arc<dyn Fn(BackendKind) -> io::Result<Box<dyn Backend>> + Send + Sync>;
+
+struct Server {
+    out: Output,
+    documents: HashMap<String, Editor>,
+    playback: Option<Playback>,
+    recording: Option<Recording>,
+    shutting_down: bool,
+    open_backend: OpenBackend,
+    /// Lock state of the keyboards now, which previews tap lock keys from
+    current_locks: fn() -> io::Result<Option<LockState>>,
+}
+
+impl Server {
+    fn new(out: Box<dyn Write + Send>) -> Self {
+        Server {
+            out: Arc::new(Mutex::new(out)),
+            documents: HashMap::new(),
+            playback: None,
+            recording: None,
+            shutting_down: false,
+            open_backend: Arc::new(|kind| backend::open(kind, "evkey-playback")),
+            current_locks: locks::current_lock_state,
+        }
+    }
+
+    /// Answer one line of input; notifications (requests without an id)
+    /// get no answer
+    fn handle_line(&mut self, line: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, PARSE_ERROR, &e.to_string())),
+        };
+        let id = request.get("id").cloned();
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return Some(error_response(id.unwrap_or(Value::Null), INVALID_REQUEST, "Missing method"));
+        };
+        let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+
+        let result = self.call(method, &params);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, e.code, &e.message),
+        })
+    }
+
+    fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "load" => {
+                let path = str_param(params, "path")?;
+                let macro_ = storage::load_macro(path)?;
+                self.documents.insert(path.to_string(), Editor::new(macro_));
+                self.inspect(path)
+            }
+            "inspect" => self.inspect(str_param(params, "path")?),
+            "edit" => self.edit(params),
+            "save" => {
+                let path = str_param(params, "path")?;
+                let to = params.get("to").and_then(Value::as_str).unwrap_or(path);
+                storage::save_macro(to, self.document(path)?.macro_())?;
+                Ok(json!({ "path": to }))
+            }
+            "close" => {
+                let path = str_param(params, "path")?;
+                self.documents.remove(path).ok_or_else(|| not_open(path))?;
+                Ok(Value::Null)
+            }
+            "play" => self.play(params),
+            "record" => self.record(str_param(params, "path")?),
+            "stop" => self.stop(),
+            "shutdown" => {
+                self.shutting_down = true;
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method '{}'", method),
+            }),
+        }
+    }
+
+    fn document(&mut self, path: &str) -> Result<&mut Editor, RpcError> {
+        self.documents.get_mut(path).ok_or_else(|| not_open(path))
+    }
+
+    fn inspect(&mut self, path: &str) -> Result<Value, RpcError> {
+        let editor = self.document(path)?;
+        let mut summary = describe(editor.macro_());
+        summary["undo"] = json!(editor.undo_name());
+        summary["redo"] = json!(editor.redo_name());
+        Ok(summary)
+    }
+
+    fn edit(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let path = str_param(params, "path")?;
+        let op = str_param(params, "op")?;
+        let editor = self.document(path)?;
+        let index = || u64_param(params, "index").map(|index| index as usize);
+        match op {
+            "set_duration" => editor.set_duration(index()?, u64_param(params, "duration_ms")?)?,
+            "set_keys" => editor.set_keys(index()?, config::parse_combo(str_param(params, "keys")?)?)?,
+            "insert_state" => {
+                let mut state = MacroState::new(u64_param(params, "duration_ms")?);
+                if let Some(keys) = params.get("keys").and_then(Value::as_str).filter(|keys| !keys.is_empty()) {
+                    state.keys_pressed = config::parse_combo(keys)?;
+                }
+                editor.insert_state(index()?, state)?
+            }
+            "remove_state" => editor.remove_state(index()?)?,
+            "retime_range" => {
+                let factor = params
+                    .get("factor")
+                    .and_then(Value::as_f64)
+                    .ok_or_else(|| RpcError::invalid_params("Missing number 'factor'"))?;
+                editor.retime_range(
+                    u64_param(params, "from")? as usize,
+                    u64_param(params, "to")? as usize,
+                    factor,
+                )?
+            }
+            "undo" => {
+                editor.undo().ok_or("Nothing to undo".to_string())?;
+            }
+            "redo" => {
+                editor.redo().ok_or("Nothing to redo".to_string())?;
+            }
+            _ => return Err(RpcError::invalid_params(format!("Unknown edit '{}'", op))),
+        }
+        self.inspect(path)
+    }
+
+    fn play(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let path = str_param(params, "path")?;
+        self.reap();
+        if self.playback.is_some() || self.recording.is_some() {
+            return Err("Already playing or recording".to_string().into());
+        }
+        let kind: BackendKind = match params.get("backend").and_then(Value::as_str) {
+            Some(kind) => kind.parse().map_err(RpcError::invalid_params)?,
+            None => BackendKind::Auto,
+        };
+        if !self.documents.contains_key(path) {
+            let macro_ = storage::load_macro(path)?;
+            self.documents.insert(path.to_string(), Editor::new(macro_));
+        }
+        let current_locks = self.current_locks;
+        let editor = self.document(path)?;
+        // Where a preview starts in the whole macro
+        let (macro_, from, start_us, lock_taps, reply) = match params.get("from") {
+            Some(_) => {
+                let from = u64_param(params, "from")? as usize;
+                let prefix = editor.prefix(from)?.clone();
+                let target = prefix.locks(editor.macro_());
+                // Lock keys to tap first, like `--sync-locks`, so the tail
+                // starts with the locks lit that the whole macro would have
+                let lock_taps = match target {
+                    Some(target) => current_locks()?.map(|current| current.keys_to_reach(&target)),
+                    None => None,
+                };
+                let reply = json!({
+                    "start_ms": prefix.start_us / 1000,
+                    "held": keymap::format_combo(prefix.held.codes()),
+                    "locks": target.map(|locks| locks.to_string()),
+                });
+                (preview::tail(editor.macro_(), from), from, prefix.start_us, lock_taps, reply)
+            }
+            None => (editor.macro_().clone(), 0, 0, None, Value::Null),
+        };
+        let events: Vec<RecordedEvent> = macro_.events();
+        let actions = macro_.timed_actions();
+        let state_starts_us = macro_.state_starts_us();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::clone(&stop);
+        let out = Arc::clone(&self.out);
+        let name = path.to_string();
+        let open_backend = Arc::clone(&self.open_backend);
+        // The player has to be made on its thread; whether its backend opened
+        // comes back, so that fails this request
+        let (opened, opening) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut player = match open_backend(kind) {
+                Ok(backend) => Player::new(backend),
+                Err(e) => {
+                    let _ = opened.send(Err(e));
+                    return;
+                }
+            };
+            let _ = opened.send(Ok(()));
+            player.stop_on(stop_flag);
+            player.set_state_starts(state_starts_us);
+            let progress_out = Arc::clone(&out);
+            player.on_progress(move |progress| {
+                let params = json!({
+                    "position_us": start_us + progress.position_us,
+                    "total_us": start_us + progress.total_us,
+                    "state_index": progress.state_index.map(|index| from + index),
+                });
+                notify(&progress_out, "progress", params);
+            });
+
+            let result = lock_taps
+                .into_iter()
+                .flatten()
+                .try_for_each(|key| player.tap_key(key))
+                .and_then(|()| player.play_with_actions(&events, &actions));
+            let mut params = json!({ "path": name });
+            if let Err(e) = result {
+                params["error"] = json!(e.to_string());
+            }
+            notify(&out, "finished", params);
+        });
+        opening.recv().map_err(|_| "Playback thread panicked".to_string())??;
+
+        self.playback = Some(Playback { stop, thread });
+        Ok(reply)
+    }
+
+    fn record(&mut self, path: &str) -> Result<Value, RpcError> {
+        self.reap();
+        if self.playback.is_some() || self.recording.is_some() {
+            return Err("Already playing or recording".to_string().into());
+        }
+        let mut recorder = Recorder::new();
+        recorder.disable_hotkeys();
+        for device in recorder::find_input_devices()? {
+            recorder.add_device(&device.path)?;
+        }
+        recorder.start();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            while !stop_flag.load(Ordering::Relaxed) {
+                // Read errors are logged by the recorder; keep going
+                let _ = recorder.poll();
+                thread::sleep(Duration::from_millis(1));
+            }
+            recorder
+        });
+
+        self.recording = Some(Recording {
+            path: path.to_string(),
+            stop,
+            thread,
+        });
+        Ok(Value::Null)
+    }
+
+    /// Stop playback, or finish the recording and open it
+    fn stop(&mut self) -> Result<Value, RpcError> {
+        if let Some(recording) = self.recording.take() {
+            recording.stop.store(true, Ordering::Relaxed);
+            let mut recorder = recording.thread.join().map_err(|_| "Recording thread panicked".to_string())?;
+            let events = recorder.stop();
+            let metadata = Metadata {
+                locks: recorder.lock_state(),
+                polling_hz: recorder.polling_hz(),
+                environment: recorder.environment(),
+                ..Default::default()
+            };
+            let macro_ = Macro::from_recording(&events, recorder.markers(), metadata);
+            self.documents.insert(recording.path.clone(), Editor::new(macro_));
+            return self.inspect(&recording.path);
+        }
+        if let Some(playback) = self.playback.take() {
+            playback.stop.store(true, Ordering::Relaxed);
+            let _ = playback.thread.join();
+            return Ok(Value::Null);
+        }
+        Err("Nothing is playing or recording".to_string().into())
+    }
+
+    /// Forget playback that already ended
+    fn reap(&mut self) {
+        if self.playback.as_ref().is_some_and(|playback| playback.thread.is_finished()) {
+            self.playback = None;
+        }
+    }
+
+    fn stop_all(&mut self) {
+        if self.playback.is_some() || self.recording.is_some() {
+            let _ = self.stop();
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn not_open(path: &str) -> RpcError {
+    format!("'{}' isn't open, load it first", path).into()
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(format!("Missing string '{}'", name)))
+}
+
+fn u64_param(params: &Value, name: &str) -> Result<u64, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| RpcError::invalid_params(format!("Missing number '{}'", name)))
+}
+
+/// A macro as JSON, in the terms of the text format
+fn describe(macro_: &Macro) -> Value {
+    let starts = macro_.state_starts_us();
+    let states: Vec<Value> = macro_
+        .states
+        .iter()
+        .zip(starts)
+        .map(|(state, start_us)| {
+            json!({
+                "start_ms": start_us / 1000,
+                "duration_ms": state.duration_ms,
+                "keys": keymap::format_combo(state.keys_pressed.codes()),
+                "mouse": [state.mouse_delta.0, state.mouse_delta.1],
+                "scroll": [state.scroll_delta.0, state.scroll_delta.1],
+            })
+        })
+        .collect();
+    let markers: Vec<Value> = macro_
+        .markers
+        .iter()
+        .map(|marker| json!({ "index": marker.index, "name": marker.name }))
+        .collect();
+    let actions: Vec<Value> = macro_
+        .actions
+        .iter()
+        .map(|step| json!({ "index": step.index, "action": step.action.to_string() }))
+        .collect();
+    json!({
+        "duration_ms": macro_.states.iter().map(|state| state.duration_ms).sum::<u64>(),
+        "states": states,
+        "markers": markers,
+        "actions": actions,
+        "text": storage::format_macro(macro_),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_session() {
+        let path = std::env::temp_dir().join(format!("evkey-rpc-{}.macro", std::process::id()));
+        std::fs::write(&path, "hold W for 50ms\nwait 100ms\nmark end\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut server = Server::new(Box::new(io::sink()));
+        let mut call = |method: &str, params: Value| {
+            let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+            server.handle_line(&request.to_string()).unwrap()
+        };
+
+        let loaded = call("load", json!({ "path": path }));
+        assert_eq!(loaded["result"]["states"][0]["keys"], "W");
+        assert_eq!(loaded["result"]["duration_ms"], 150);
+        assert_eq!(loaded["result"]["markers"][0]["name"], "end");
+
+        let edited = call("edit", json!({ "path": path, "op": "set_duration", "index": 0, "duration_ms": 80 }));
+        assert_eq!(edited["result"]["duration_ms"], 180);
+        assert_eq!(edited["result"]["undo"], "Change state 0");
+        let undone = call("edit", json!({ "path": path, "op": "undo" }));
+        assert_eq!(undone["result"]["duration_ms"], 150);
+        let preview = call("play", json!({ "path": path, "from": 9 }));
+        assert_eq!(preview["error"]["message"], "No state 9");
+
+        assert_eq!(call("save", json!({ "path": path }))["result"]["path"], path);
+        assert_eq!(call("close", json!({ "path": path }))["result"], Value::Null);
+        assert!(call("inspect", json!({ "path": path }))["error"]["message"].as_str().unwrap().contains("isn't open"));
+        assert_eq!(call("edit", json!({ "path": path }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(call("fly", json!({}))["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(server.handle_line("{not json").unwrap()["error"]["code"], PARSE_ERROR);
+        // Notifications get no answer
+        assert_eq!(server.handle_line(r#"{"jsonrpc":"2.0","method":"shutdown"}"#), None);
+        assert!(server.shutting_down);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_preview_taps_lock_keys_first() {
+        use crate::event::EventType;
+        use crate::testing::{MockBackend, VirtualClock};
+
+        let path = std::env::temp_dir().join(format!("evkey-rpc-locks-{}.macro", std::process::id()));
+        // Caps lock tapped three times before state 3, so it's on there
+        let text = "# Locks: none\ntap CAPSLOCK\ntap CAPSLOCK\ntap CAPSLOCK\nhold A for 10ms\n";
+        std::fs::write(&path, text).unwrap();
+        let path = path.to_str().unwrap();
+
+        let backend = MockBackend::new(&VirtualClock::new());
+        let mut server = Server::new(Box::new(io::sink()));
+        let device = backend.clone();
+        server.open_backend = Arc::new(move |_| Ok(Box::new(device.clone())));
+        server.current_locks = || Ok(Some(LockState::default()));
+
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "play", "params": { "path": path, "from": 3 } });
+        let reply = server.handle_line(&request.to_string()).unwrap();
+        assert_eq!(reply["result"]["locks"], "CAPSLOCK");
+        server.playback.take().unwrap().thread.join().unwrap();
+
+        let keys: Vec<(u16, i32)> = backend
+            .events()
+            .iter()
+            .filter(|recorded| recorded.event.event_type() == EventType::KEY)
+            .map(|recorded| (recorded.event.code(), recorded.event.value()))
+            .collect();
+        assert_eq!(keys, [(58, 1), (58, 0), (30, 1), (30, 0)]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
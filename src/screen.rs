@@ -0,0 +1,412 @@
+//! Reading pixels and the pointer position from the screen
+//!
+//! Screenshots are taken with `grim` on Wayland and ImageMagick's `import` on
+//! X11, cropped to the part we need and read back as PPM. With the `ocr`
+//! feature, text is read from them by `tesseract`. The pointer position and
+//! the active window's title come from `xdotool` on X11 and `hyprctl` on
+//! Hyprland.
+//!
+//! All coordinates span the whole screen, across every monitor.
+
+use std::fmt;
+#[cfg(feature = "devices")]
+use std::io;
+#[cfg(feature = "devices")]
+use std::process::Command;
+
+/// An sRGB color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    /// Parse `#rrggbb`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let hex = s.trim().strip_prefix('#').unwrap_or(s.trim());
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(format!("Invalid color '{}', expected #rrggbb", s));
+        }
+
+        let channel = |i: usize| {
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid color '{}', expected #rrggbb", s))
+        };
+        Ok(Rgb(channel(0)?, channel(2)?, channel(4)?))
+    }
+
+    /// Largest difference between any channel of the two colors
+    pub fn distance(self, other: Rgb) -> u8 {
+        self.0
+            .abs_diff(other.0)
+            .max(self.1.abs_diff(other.1))
+            .max(self.2.abs_diff(other.2))
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+/// Size of the whole screen in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ScreenSize {
+    /// Parse `WIDTHxHEIGHT`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid screen size '{}', expected e.g. 1920x1080", s.trim());
+        let (width, height) = s.trim().split_once('x').ok_or_else(invalid)?;
+        let size = ScreenSize {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+        };
+        if size.width == 0 || size.height == 0 {
+            return Err(invalid());
+        }
+        Ok(size)
+    }
+
+    /// Factors taking coordinates on `self` to the same spot on `other`
+    pub fn scale_to(self, other: ScreenSize) -> (f64, f64) {
+        (
+            f64::from(other.width) / f64::from(self.width),
+            f64::from(other.height) / f64::from(self.height),
+        )
+    }
+}
+
+impl fmt::Display for ScreenSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// A rectangle on screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl fmt::Display for Region {
+    /// `X Y WIDTH HEIGHT`, as written in macros
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Take a screenshot of `region` (or the whole screen) as a PPM image
+#[cfg(feature = "devices")]
+pub fn capture(region: Option<Region>) -> io::Result<Vec<u8>> {
+    let output = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        let mut grim = Command::new("grim");
+        if let Some(r) = region {
+            grim.args(["-g", &format!("{},{} {}x{}", r.x, r.y, r.width, r.height)]);
+        }
+        grim.args(["-t", "ppm", "-"]).output()
+    } else {
+        let mut import = Command::new("import");
+        import.args(["-window", "root"]);
+        if let Some(r) = region {
+            import.args(["-crop", &format!("{}x{}+{}+{}", r.width, r.height, r.x, r.y)]);
+        }
+        import.arg("ppm:-").output()
+    }
+    .map_err(|e| io::Error::new(e.kind(), format!("Can't take a screenshot (is grim/ImageMagick installed?): {}", e)))?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Screenshot failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Read the color of the pixel at screen coordinates `x`, `y`
+#[cfg(feature = "devices")]
+pub fn pixel_at(x: i32, y: i32) -> io::Result<Rgb> {
+    let image = capture(Some(Region { x, y, width: 1, height: 1 }))?;
+    first_ppm_pixel(&image).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unreadable screenshot"))
+}
+
+/// Recognize the text shown in `region` (or on the whole screen)
+#[cfg(feature = "ocr")]
+pub fn read_text(region: Option<Region>) -> io::Result<String> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let image = capture(region)?;
+    let mut tesseract = Command::new("tesseract")
+        .args(["stdin", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't run tesseract (is it installed?): {}", e)))?;
+    // tesseract reads the whole image before writing anything
+    tesseract.stdin.take().expect("stdin is piped").write_all(&image)?;
+
+    let output = tesseract.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "tesseract failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Reads `(x, y)` from a tool's output
+#[cfg(feature = "devices")]
+type PositionParser = fn(&str) -> Option<(i32, i32)>;
+
+/// Current pointer position in screen coordinates
+#[cfg(feature = "devices")]
+pub fn pointer_position() -> io::Result<(i32, i32)> {
+    let (program, args, parse): (_, &[&str], PositionParser) =
+        if std::env::var_os("WAYLAND_DISPLAY").is_none() {
+            ("xdotool", &["getmouselocation", "--shell"], parse_xdotool_location)
+        } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+            ("hyprctl", &["cursorpos"], parse_hyprctl_cursorpos)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Reading the pointer position is only supported on X11 and Hyprland",
+            ));
+        };
+
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    parse(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected output from {}", program)))
+}
+
+/// `X=640\nY=360\nSCREEN=0\nWINDOW=...`
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn parse_xdotool_location(output: &str) -> Option<(i32, i32)> {
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .and_then(|value| value.trim().parse().ok())
+    };
+    Some((field("X")?, field("Y")?))
+}
+
+/// Reads a window title from a tool's output
+#[cfg(feature = "devices")]
+type TitleParser = fn(&str) -> Option<String>;
+
+/// Title of the window that has the keyboard focus
+#[cfg(feature = "devices")]
+pub fn active_window_title() -> io::Result<String> {
+    let (program, args, parse): (_, &[&str], TitleParser) =
+        if std::env::var_os("WAYLAND_DISPLAY").is_none() {
+            ("xdotool", &["getactivewindow", "getwindowname"], |output| {
+                Some(output.trim_end_matches('\n').to_string())
+            })
+        } else if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+            ("hyprctl", &["activewindow"], parse_hyprctl_title)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Reading the active window is only supported on X11 and Hyprland",
+            ));
+        };
+
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    parse(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected output from {}", program)))
+}
+
+/// `Window 55d0 -> ...:` followed by indented `key: value` lines
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn parse_hyprctl_title(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.trim_start().strip_prefix("title: "))
+        .map(str::to_string)
+}
+
+/// `640, 360`
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn parse_hyprctl_cursorpos(output: &str) -> Option<(i32, i32)> {
+    let (x, y) = output.trim().split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+/// Size of the whole screen, for mapping coordinates onto absolute devices
+///
+/// Wayland has no generic way to ask, so there it takes a full screenshot.
+#[cfg(feature = "devices")]
+pub fn screen_size() -> io::Result<ScreenSize> {
+    let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let output = if wayland {
+        Command::new("grim").args(["-t", "ppm", "-"]).output()
+    } else {
+        Command::new("xdotool").arg("getdisplaygeometry").output()
+    }
+    .map_err(|e| io::Error::new(e.kind(), format!("Can't get the screen size (is grim/xdotool installed?): {}", e)))?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "Getting the screen size failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let size = if wayland {
+        ppm_header(&output.stdout).map(|header| (header.width, header.height))
+    } else {
+        parse_display_geometry(&String::from_utf8_lossy(&output.stdout))
+    };
+    size.filter(|&(width, height)| width > 0 && height > 0)
+        .map(|(width, height)| ScreenSize { width, height })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unreadable screen size"))
+}
+
+/// `1920 1080`
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn parse_display_geometry(output: &str) -> Option<(u32, u32)> {
+    let (width, height) = output.trim().split_once(' ')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+/// Dimensions of a binary (P6) PPM image, and where its pixels start
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+struct PpmHeader {
+    width: u32,
+    height: u32,
+    maxval: u32,
+    data_offset: usize,
+}
+
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn ppm_header(data: &[u8]) -> Option<PpmHeader> {
+    // Header: magic, width, height, maxval, separated by whitespace/comments
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while data.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        if data[pos] == b'#' {
+            while *data.get(pos)? != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while !data.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        fields.push(std::str::from_utf8(&data[start..pos]).ok()?);
+    }
+    // Exactly one whitespace byte separates the header from the pixels
+    pos += 1;
+
+    if fields[0] != "P6" {
+        return None;
+    }
+    Some(PpmHeader {
+        width: fields[1].parse().ok()?,
+        height: fields[2].parse().ok()?,
+        maxval: fields[3].parse().ok()?,
+        data_offset: pos,
+    })
+}
+
+/// Decode the first pixel of a binary (P6) PPM image
+#[cfg_attr(not(feature = "devices"), allow(dead_code))]
+fn first_ppm_pixel(data: &[u8]) -> Option<Rgb> {
+    let PpmHeader { maxval, data_offset, .. } = ppm_header(data)?;
+    let pixel = data.get(data_offset..)?;
+    let scale = |v: u32| (v * 255 / maxval.max(1)) as u8;
+
+    if maxval < 256 {
+        let [r, g, b] = *pixel.get(..3)? else { return None };
+        Some(Rgb(scale(r.into()), scale(g.into()), scale(b.into())))
+    } else {
+        let sample = |i: usize| Some(u32::from(u16::from_be_bytes([*pixel.get(i)?, *pixel.get(i + 1)?])));
+        Some(Rgb(scale(sample(0)?), scale(sample(2)?), scale(sample(4)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(Rgb::parse("#ff8000"), Ok(Rgb(255, 128, 0)));
+        assert_eq!(Rgb(255, 128, 0).to_string(), "#ff8000");
+        assert!(Rgb::parse("#ff80").is_err());
+        assert_eq!(Rgb(10, 20, 30).distance(Rgb(15, 18, 30)), 5);
+    }
+
+    #[test]
+    fn test_parse_screen_size() {
+        let size = ScreenSize::parse("1920x1080").unwrap();
+        assert_eq!(size, ScreenSize { width: 1920, height: 1080 });
+        assert_eq!(size.to_string(), "1920x1080");
+        assert_eq!(size.scale_to(ScreenSize { width: 3840, height: 2160 }), (2.0, 2.0));
+        assert!(ScreenSize::parse("1920").is_err());
+        assert!(ScreenSize::parse("0x1080").is_err());
+    }
+
+    #[test]
+    fn test_first_ppm_pixel() {
+        let mut ppm = b"P6\n# grim\n1 1\n255\n".to_vec();
+        ppm.extend([12, 34, 56]);
+        assert_eq!(first_ppm_pixel(&ppm), Some(Rgb(12, 34, 56)));
+
+        let mut deep = b"P6 1 1 65535\n".to_vec();
+        deep.extend([0xff, 0xff, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(first_ppm_pixel(&deep), Some(Rgb(255, 127, 0)));
+
+        assert_eq!(first_ppm_pixel(b"P3 1 1 255\n1 2 3"), None);
+        assert_eq!(ppm_header(b"P6 3840 1080 255\n").map(|h| (h.width, h.height)), Some((3840, 1080)));
+        assert_eq!(parse_display_geometry("1920 1080\n"), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn test_parse_pointer_position() {
+        assert_eq!(parse_xdotool_location("X=640\nY=360\nSCREEN=0\nWINDOW=123\n"), Some((640, 360)));
+        assert_eq!(parse_hyprctl_cursorpos("1920, 1080\n"), Some((1920, 1080)));
+        assert_eq!(parse_xdotool_location("SCREEN=0\n"), None);
+    }
+
+    #[test]
+    fn test_parse_hyprctl_title() {
+        let output = "Window 55d0c8 -> Inbox - Mail:\n\tmapped: 1\n\tclass: thunderbird\n\ttitle: Inbox - Mail\n";
+        assert_eq!(parse_hyprctl_title(output).as_deref(), Some("Inbox - Mail"));
+        assert_eq!(parse_hyprctl_title("Invalid\n"), None);
+    }
+}
@@ -0,0 +1,146 @@
+//! Injecting on a particular seat of a multi-seat machine
+//!
+//! logind gives each seat its own devices, telling them apart by the
+//! `ID_SEAT` udev property (devices without it belong to seat0). A uinput
+//! device gets no such property by itself, so for another seat EvKey names
+//! its device after the seat ("evkey-playback@seat1") and a udev rule,
+//! installed once with `evkey seat-rule seat1`, assigns devices with that name
+//! to it. The macro then goes to whichever session is active on that seat.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+
+/// The seat every device belongs to unless told otherwise
+pub const DEFAULT_SEAT: &str = "seat0";
+
+/// Where the rules assigning EvKey's devices to seats go; numbered like the
+/// rules `loginctl attach` writes, between udev's seat rules and logind's
+pub const RULES_PATH: &str = "/etc/udev/rules.d/72-evkey-seats.rules";
+
+/// One entry per seat, maintained by logind
+pub const SEATS_DIR: &str = "/run/systemd/seats";
+
+/// Where udev keeps the properties of each device, by type and number
+const UDEV_DATA_DIR: &str = "/run/udev/data";
+
+/// Name for a virtual device meant for `seat`
+pub fn device_name(base: &str, seat: &str) -> String {
+    if seat == DEFAULT_SEAT {
+        base.to_string()
+    } else {
+        format!("{}@{}", base, seat)
+    }
+}
+
+/// Udev rule assigning EvKey's devices for `seat` to it
+pub fn udev_rule(seat: &str) -> String {
+    format!(
+        "SUBSYSTEM==\"input\", ATTRS{{name}}==\"evkey-*@{}*\", ENV{{ID_SEAT}}=\"{}\", TAG+=\"seat\"",
+        seat, seat
+    )
+}
+
+/// Seats logind knows about, sorted by name
+pub fn seats() -> io::Result<Vec<String>> {
+    let mut seats: Vec<String> = fs::read_dir(SEATS_DIR)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    seats.sort();
+    Ok(seats)
+}
+
+/// The seat the device node at `path` belongs to
+pub fn of_device(path: &Path) -> String {
+    let seat = fs::metadata(path).ok().and_then(|metadata| {
+        let rdev = metadata.rdev();
+        let data = format!("{}/c{}:{}", UDEV_DATA_DIR, libc::major(rdev), libc::minor(rdev));
+        parse_udev_seat(&fs::read_to_string(data).ok()?)
+    });
+    seat.unwrap_or_else(|| DEFAULT_SEAT.to_string())
+}
+
+/// The `ID_SEAT` property in a udev database entry
+fn parse_udev_seat(text: &str) -> Option<String> {
+    text.lines().find_map(|line| line.strip_prefix("E:ID_SEAT=")).map(str::to_string)
+}
+
+/// Check that `seat` is a valid seat name (as logind requires: "seat"
+/// followed by letters, digits, `-` or `_`)
+pub fn validate_name(seat: &str) -> Result<(), String> {
+    let valid = seat.strip_prefix("seat").is_some_and(|rest| {
+        rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("'{}' isn't a seat name (like seat0 or seat1)", seat))
+    }
+}
+
+/// Check that devices named with [`device_name`] will land on `seat`
+pub fn check(seat: &str) -> Result<(), String> {
+    validate_name(seat)?;
+    if seat == DEFAULT_SEAT {
+        return Ok(());
+    }
+
+    let seats = seats().map_err(|_| "logind isn't running, so there are no other seats".to_string())?;
+    if !seats.iter().any(|known| known == seat) {
+        return Err(format!("No seat named '{}' (seats: {})", seat, seats.join(", ")));
+    }
+    let rules = fs::read_to_string(RULES_PATH).unwrap_or_default();
+    if !rules.lines().any(|line| line == udev_rule(seat)) {
+        return Err(format!(
+            "EvKey's devices aren't assigned to {} yet, run `sudo evkey seat-rule {}` once",
+            seat, seat
+        ));
+    }
+    Ok(())
+}
+
+/// Add the rule for `seat` to [`RULES_PATH`] and have udev reload its rules;
+/// returns `false` if it was already there
+pub fn install_rule(seat: &str) -> io::Result<bool> {
+    validate_name(seat).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let rule = udev_rule(seat);
+    let rules = fs::read_to_string(RULES_PATH).unwrap_or_default();
+    if rules.lines().any(|line| line == rule) {
+        return Ok(false);
+    }
+
+    if !Path::new(RULES_PATH).exists() {
+        fs::write(RULES_PATH, "# Virtual devices EvKey creates for other seats (evkey seat-rule)\n")?;
+    }
+    let mut file = fs::OpenOptions::new().append(true).open(RULES_PATH)?;
+    writeln!(file, "{}", rule)?;
+
+    let status = Command::new("udevadm").args(["control", "--reload"]).status()?;
+    if !status.success() {
+        return Err(io::Error::other("udevadm control --reload failed"));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seat_names() {
+        assert_eq!(device_name("evkey-playback", "seat0"), "evkey-playback");
+        assert_eq!(device_name("evkey-playback", "seat-2"), "evkey-playback@seat-2");
+        assert!(udev_rule("seat1").contains("ATTRS{name}==\"evkey-*@seat1*\", ENV{ID_SEAT}=\"seat1\""));
+
+        assert!(validate_name("seat_left").is_ok());
+        assert!(validate_name("seat1\"").is_err());
+        assert!(validate_name("tty2").is_err());
+        assert!(check("seat0").is_ok());
+
+        assert_eq!(parse_udev_seat("I:1234\nE:ID_INPUT=1\nE:ID_SEAT=seat1\nG:seat\n").as_deref(), Some("seat1"));
+        assert_eq!(parse_udev_seat("E:ID_INPUT=1\n"), None);
+        assert_eq!(of_device(Path::new("/nonexistent")), DEFAULT_SEAT);
+    }
+}
@@ -0,0 +1,196 @@
+//! Snippets: text with variables and a cursor position
+//!
+//! A `snippet` action types text like a `type` action, filling in variables
+//! as it goes, and then moves the caret back to the cursor marker:
+//!
+//!   snippet "Hi $|$,\n\nsent {{date:%d.%m.%Y}} at {{time}}"
+//!
+//! Variables are `{{date}}`, `{{time}}` (both take a strftime format after a
+//! colon) and `{{clipboard}}`. `$|$` marks where the caret ends up; the
+//! arrow keys take it there, so it works in any application. Placeholders
+//! filled per macro or per playback are the macro's parameters (`$name`).
+
+use std::fmt;
+
+/// Marks where the caret goes once the snippet is typed
+pub const CURSOR: &str = "$|$";
+
+/// Format of `{{date}}` without one of its own
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+/// Format of `{{time}}` without one of its own
+const DEFAULT_TIME_FORMAT: &str = "%H:%M";
+
+/// Something filled in when the snippet is typed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Variable {
+    /// Local date and time, as a strftime format
+    LocalTime(String),
+    Clipboard,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Variable(Variable),
+    Cursor,
+}
+
+/// A parsed snippet, keeping its source to write it back out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snippet {
+    source: String,
+    parts: Vec<Part>,
+}
+
+/// A snippet with its variables filled in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendered {
+    pub text: String,
+    /// Characters typed after the cursor marker, which the caret moves back
+    /// over
+    pub cursor_back: usize,
+}
+
+impl Snippet {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while !rest.is_empty() {
+            let variable = rest.find("{{");
+            let cursor = rest.find(CURSOR);
+            let next = match (variable, cursor) {
+                (Some(v), Some(c)) => v.min(c),
+                (Some(at), None) | (None, Some(at)) => at,
+                (None, None) => rest.len(),
+            };
+            if next > 0 {
+                parts.push(Part::Text(rest[..next].to_string()));
+                rest = &rest[next..];
+                continue;
+            }
+
+            if let Some(after) = rest.strip_prefix(CURSOR) {
+                if parts.contains(&Part::Cursor) {
+                    return Err(format!("A snippet can only have one {} cursor", CURSOR));
+                }
+                parts.push(Part::Cursor);
+                rest = after;
+            } else {
+                let end = rest.find("}}").ok_or_else(|| format!("Unclosed {{{{ in snippet: {}", rest))?;
+                parts.push(Part::Variable(parse_variable(&rest[2..end])?));
+                rest = &rest[end + 2..];
+            }
+        }
+        Ok(Snippet {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    /// The snippet as written
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Characters the snippet types, not counting what its variables fill in
+    pub fn text_len(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.chars().count(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Fill the variables in with `resolve`
+    pub fn render<E>(&self, mut resolve: impl FnMut(&Variable) -> Result<String, E>) -> Result<Rendered, E> {
+        let mut text = String::new();
+        let mut cursor = None;
+        for part in &self.parts {
+            match part {
+                Part::Text(chunk) => text.push_str(chunk),
+                Part::Variable(variable) => text.push_str(&resolve(variable)?),
+                Part::Cursor => cursor = Some(text.chars().count()),
+            }
+        }
+        let cursor_back = cursor.map_or(0, |at| text.chars().count() - at);
+        Ok(Rendered { text, cursor_back })
+    }
+}
+
+impl fmt::Display for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Parse what is between `{{` and `}}`
+fn parse_variable(inner: &str) -> Result<Variable, String> {
+    let (name, format) = match inner.split_once(':') {
+        Some((name, format)) => (name.trim(), Some(format)),
+        None => (inner.trim(), None),
+    };
+    match (name, format) {
+        ("date", format) => Ok(Variable::LocalTime(format.unwrap_or(DEFAULT_DATE_FORMAT).to_string())),
+        ("time", format) => Ok(Variable::LocalTime(format.unwrap_or(DEFAULT_TIME_FORMAT).to_string())),
+        ("clipboard", None) => Ok(Variable::Clipboard),
+        _ => Err(format!("Unknown snippet variable '{{{{{}}}}}' (use date, time or clipboard)", inner)),
+    }
+}
+
+/// Look a variable up: the local time, or the clipboard
+#[cfg(feature = "devices")]
+pub fn resolve(variable: &Variable) -> std::io::Result<String> {
+    match variable {
+        Variable::LocalTime(format) => format_local_time(format),
+        Variable::Clipboard => crate::clipboard::get(),
+    }
+}
+
+/// The local time in a strftime format
+#[cfg(feature = "devices")]
+pub(crate) fn format_local_time(format: &str) -> std::io::Result<String> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid time format '{}'", format));
+    let format = std::ffi::CString::new(format).map_err(|_| invalid())?;
+    let mut buffer = [0u8; 256];
+    // Safety: `time` accepts a null pointer, `localtime_r` only writes to the
+    // `tm` it is given, and `strftime` writes at most `buffer.len()` bytes
+    let written = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(buffer.as_mut_ptr().cast(), buffer.len(), format.as_ptr(), &tm)
+    };
+    if written == 0 && !format.as_bytes().is_empty() {
+        return Err(invalid());
+    }
+    Ok(String::from_utf8_lossy(&buffer[..written]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet_render() {
+        let snippet = Snippet::parse("Hi $|$,\nsent {{date:%d.%m.%Y}} {{ time }} {{clipboard}}").unwrap();
+        let rendered = snippet
+            .render(|variable| match variable {
+                Variable::LocalTime(format) if format == "%d.%m.%Y" => Ok::<_, String>("16.10.2026".to_string()),
+                Variable::LocalTime(format) => Ok(format!("<{}>", format)),
+                Variable::Clipboard => Ok("pasted".to_string()),
+            })
+            .unwrap();
+        assert_eq!(rendered.text, "Hi ,\nsent 16.10.2026 <%H:%M> pasted");
+        assert_eq!(rendered.cursor_back, rendered.text.chars().count() - 3);
+        assert_eq!(snippet.to_string(), snippet.source());
+
+        let plain = Snippet::parse("no cursor, {single} braces").unwrap();
+        assert_eq!(plain.render(|_| Err("no variables")).unwrap().cursor_back, 0);
+
+        assert!(Snippet::parse("{{weather}}").unwrap_err().contains("Unknown snippet variable"));
+        assert!(Snippet::parse("{{date").unwrap_err().contains("Unclosed"));
+        assert!(Snippet::parse("$|$ and $|$").unwrap_err().contains("one $|$ cursor"));
+    }
+}
@@ -3,30 +3,40 @@
 //! Converts low-level input events into high-level "states" representing
 //! which keys are pressed for how long. This enables human-readable macros.
 
-use crate::recorder::RecordedEvent;
-use evdev::{EventType, InputEvent};
-use std::collections::HashSet;
+use crate::easing::Easing;
+use crate::keymap;
+use crate::keyset::KeySet;
+use crate::event::RecordedEvent;
+use crate::event::{EventType, InputEvent};
+use crate::rng::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
 /// A macro state: which keys are held and for how long
 #[derive(Debug, Clone, PartialEq)]
 pub struct MacroState {
     /// Duration this state lasts (in milliseconds)
     pub duration_ms: u64,
-    /// Keys that are pressed during this state (Linux keycodes)
-    pub keys_pressed: HashSet<u16>,
+    /// Keys and buttons that are pressed during this state
+    pub keys_pressed: KeySet,
     /// Mouse movement during this state (relative x, y)
     pub mouse_delta: (i32, i32),
     /// Mouse scroll during this state (vertical, horizontal)
     pub scroll_delta: (i32, i32),
+    /// How paced mouse movement speeds up and slows down over the state;
+    /// the macro's default if `None`
+    pub easing: Option<Easing>,
 }
 
 impl MacroState {
     pub fn new(duration_ms: u64) -> Self {
         Self {
             duration_ms,
-            keys_pressed: HashSet::new(),
+            keys_pressed: KeySet::new(),
             mouse_delta: (0, 0),
             scroll_delta: (0, 0),
+            easing: None,
         }
     }
 
@@ -38,6 +48,105 @@ impl MacroState {
     }
 }
 
+impl fmt::Display for MacroState {
+    /// Render in conventional shortcut notation, e.g. "CTRL+SHIFT+P (held 80ms)"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+
+        if !self.keys_pressed.is_empty() {
+            let combo = keymap::display_combo(self.keys_pressed.codes());
+            if self.duration_ms > 0 {
+                parts.push(format!("{} (held {}ms)", combo, self.duration_ms));
+            } else {
+                parts.push(format!("{} (tap)", combo));
+            }
+        }
+
+        if self.mouse_delta != (0, 0) {
+            parts.push(format!("move {} {}", self.mouse_delta.0, self.mouse_delta.1));
+        }
+
+        let (vertical, horizontal) = self.scroll_delta;
+        if vertical != 0 {
+            let direction = if vertical > 0 { "up" } else { "down" };
+            parts.push(format!("scroll {} {}", direction, vertical.unsigned_abs()));
+        }
+        if horizontal != 0 {
+            let direction = if horizontal > 0 { "right" } else { "left" };
+            parts.push(format!("scroll {} {}", direction, horizontal.unsigned_abs()));
+        }
+
+        if parts.is_empty() {
+            return write!(f, "wait {}ms", self.duration_ms);
+        }
+        if self.keys_pressed.is_empty() && self.duration_ms > 0 {
+            parts.push(format!("wait {}ms", self.duration_ms));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Hi-res wheel units in one notch, as the kernel counts them
+pub const HIRES_PER_NOTCH: i32 = 120;
+
+/// Report rate scroll is smoothed at when only hi-res is asked for
+pub const DEFAULT_SCROLL_HZ: u32 = 60;
+
+/// How playback spreads a state's scrolling over the state, written
+/// `60Hz` or `60Hz hires`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrollPacing {
+    /// Most wheel reports a second
+    pub rate_hz: u32,
+    /// Scroll in hi-res steps between notches, for apps that scroll smoothly
+    pub hires: bool,
+}
+
+impl Default for ScrollPacing {
+    fn default() -> Self {
+        Self {
+            rate_hz: DEFAULT_SCROLL_HZ,
+            hires: false,
+        }
+    }
+}
+
+impl FromStr for ScrollPacing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let rate = words.next().unwrap_or_default();
+        let rate_hz = parse_hz(rate).ok_or_else(|| format!("Invalid scroll rate '{}', expected e.g. 60Hz", rate))?;
+        let hires = match words.next() {
+            None => false,
+            Some("hires") => true,
+            Some(other) => return Err(format!("Unknown scroll option '{}'", other)),
+        };
+        if let Some(extra) = words.next() {
+            return Err(format!("Unexpected '{}' after the scroll options", extra));
+        }
+        Ok(Self { rate_hz, hires })
+    }
+}
+
+impl fmt::Display for ScrollPacing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}Hz", self.rate_hz)?;
+        if self.hires {
+            write!(f, " hires")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a rate like `60Hz` (or a bare `60`), which has to be above zero
+pub fn parse_hz(value: &str) -> Option<u32> {
+    let value = value.trim();
+    value.strip_suffix("Hz").unwrap_or(value).parse().ok().filter(|&hz| hz > 0)
+}
+
 /// Convert recorded events into state-based representation
 pub fn events_to_states(events: &[RecordedEvent]) -> Vec<MacroState> {
     if events.is_empty() {
@@ -45,7 +154,7 @@ pub fn events_to_states(events: &[RecordedEvent]) -> Vec<MacroState> {
     }
 
     let mut states = Vec::new();
-    let mut current_keys: HashSet<u16> = HashSet::new();
+    let mut current_keys = KeySet::new();
     let mut last_timestamp_us = 0u64;
     let mut accumulated_mouse = (0i32, 0i32);
     let mut accumulated_scroll = (0i32, 0i32);
@@ -57,11 +166,7 @@ pub fn events_to_states(events: &[RecordedEvent]) -> Vec<MacroState> {
         if elapsed_us > 0 {
             let duration_ms = elapsed_us / 1000; // Convert microseconds to milliseconds
             if duration_ms > 0 {
-                let mut state = MacroState::new(duration_ms);
-                state.keys_pressed = current_keys.clone();
-                state.mouse_delta = accumulated_mouse;
-                state.scroll_delta = accumulated_scroll;
-                states.push(state);
+                push_merged(&mut states, &current_keys, duration_ms, accumulated_mouse, accumulated_scroll);
 
                 // Reset mouse and scroll accumulators after saving
                 accumulated_mouse = (0, 0);
@@ -82,7 +187,7 @@ pub fn events_to_states(events: &[RecordedEvent]) -> Vec<MacroState> {
                     }
                     0 => {
                         // Key release
-                        current_keys.remove(&key_code);
+                        current_keys.remove(key_code);
                     }
                     _ => {
                         // Ignore key repeat (value 2)
@@ -95,15 +200,16 @@ pub fn events_to_states(events: &[RecordedEvent]) -> Vec<MacroState> {
                 let value = event.event.value();
 
                 match axis_code {
-                    0 => accumulated_mouse.0 += value,   // REL_X
-                    1 => accumulated_mouse.1 += value,   // REL_Y
-                    8 => accumulated_scroll.0 += value,  // REL_WHEEL (vertical)
-                    6 => accumulated_scroll.1 += value,  // REL_HWHEEL (horizontal)
+                    0 => accumulated_mouse.0 = accumulated_mouse.0.saturating_add(value),   // REL_X
+                    1 => accumulated_mouse.1 = accumulated_mouse.1.saturating_add(value),   // REL_Y
+                    8 => accumulated_scroll.0 = accumulated_scroll.0.saturating_add(value), // REL_WHEEL (vertical)
+                    6 => accumulated_scroll.1 = accumulated_scroll.1.saturating_add(value), // REL_HWHEEL (horizontal)
                     _ => {}
                 }
             }
             _ => {
-                // Ignore sync and other event types for state tracking
+                // Ignore sync, LED, switch and other event types for state tracking
+                // (LED and switch changes become actions in Macro::from_recording)
             }
         }
 
@@ -112,77 +218,129 @@ pub fn events_to_states(events: &[RecordedEvent]) -> Vec<MacroState> {
 
     // Add final state if keys are still pressed or actions remain
     if !current_keys.is_empty() || accumulated_mouse != (0, 0) || accumulated_scroll != (0, 0) {
-        let mut state = MacroState::new(0); // Final state with no duration
-        state.keys_pressed = current_keys;
-        state.mouse_delta = accumulated_mouse;
-        state.scroll_delta = accumulated_scroll;
-        states.push(state);
+        // Final state with no duration
+        push_merged(&mut states, &current_keys, 0, accumulated_mouse, accumulated_scroll);
     }
 
-    // Filter out small mouse movements (< 5px) from all states
-    for state in &mut states {
-        let distance = state.mouse_delta.0.abs() + state.mouse_delta.1.abs();
-        if distance < 5 {
-            state.mouse_delta = (0, 0);
+    states
+}
+
+/// Append a state, or extend the previous one if the two would be merged
+///
+/// Small mouse movements (< 5px) are dropped first. Merging as states are
+/// produced means the key set is only cloned for states that are kept.
+fn push_merged(
+    states: &mut Vec<MacroState>,
+    keys: &KeySet,
+    duration_ms: u64,
+    mouse_delta: (i32, i32),
+    scroll_delta: (i32, i32),
+) {
+    let distance = mouse_delta.0.unsigned_abs().saturating_add(mouse_delta.1.unsigned_abs());
+    let mouse_delta = if distance < 5 { (0, 0) } else { mouse_delta };
+
+    if let Some(previous) = states.last_mut() {
+        if mergeable(previous, keys, mouse_delta, scroll_delta) {
+            previous.duration_ms = previous.duration_ms.saturating_add(duration_ms);
+            return;
         }
     }
 
-    // Merge consecutive identical states
-    merge_consecutive_states(states)
+    states.push(MacroState {
+        duration_ms,
+        keys_pressed: keys.clone(),
+        mouse_delta,
+        scroll_delta,
+        easing: None,
+    });
 }
 
-/// Merge consecutive states that have the same keys pressed
-fn merge_consecutive_states(states: Vec<MacroState>) -> Vec<MacroState> {
-    if states.is_empty() {
-        return states;
-    }
+/// Whether a state can be folded into `previous`: same keys pressed and no
+/// mouse/scroll movement in either
+fn mergeable(previous: &MacroState, keys: &KeySet, mouse_delta: (i32, i32), scroll_delta: (i32, i32)) -> bool {
+    previous.mouse_delta == (0, 0)
+        && mouse_delta == (0, 0)
+        && previous.scroll_delta == (0, 0)
+        && scroll_delta == (0, 0)
+        && previous.keys_pressed == *keys
+}
 
-    let mut merged = Vec::new();
-    let mut current = states[0].clone();
+/// Merge consecutive states that have the same keys pressed, e.g. after editing
+pub fn merge_consecutive_states(states: Vec<MacroState>) -> Vec<MacroState> {
+    let mut merged: Vec<MacroState> = Vec::with_capacity(states.len());
 
-    for state in states.into_iter().skip(1) {
-        // Only merge if keys match and no mouse/scroll movement in either
-        // (small movements already filtered to (0, 0) before merging)
-        if current.keys_pressed == state.keys_pressed
-            && current.mouse_delta == (0, 0)
-            && state.mouse_delta == (0, 0)
-            && current.scroll_delta == (0, 0)
-            && state.scroll_delta == (0, 0)
-        {
-            current.duration_ms += state.duration_ms;
-        } else {
-            merged.push(current);
-            current = state;
+    for state in states {
+        match merged.last_mut() {
+            Some(previous) if mergeable(previous, &state.keys_pressed, state.mouse_delta, state.scroll_delta) => {
+                previous.duration_ms = previous.duration_ms.saturating_add(state.duration_ms);
+            }
+            _ => merged.push(state),
         }
     }
 
-    merged.push(current);
     merged
 }
 
 /// Convert state-based representation back to events
 pub fn states_to_events(states: &[MacroState]) -> Vec<RecordedEvent> {
-    let mut events = Vec::new();
+    to_events(states, None, None)
+}
+
+/// Convert states back to events, moving the mouse like a device polled every
+/// `poll_interval_us`
+///
+/// A state's movement is split into reports one poll apart from the start of
+/// the state, as many as fit in the state but no more than one per count on
+/// the longer axis. Recorded movement, already one report per state, plays
+/// as before; a long hand-written `move` glides instead of jumping.
+pub fn states_to_paced_events(states: &[MacroState], poll_interval_us: u64) -> Vec<RecordedEvent> {
+    to_events(states, Some((poll_interval_us.max(1), Easing::Linear)), None)
+}
+
+/// Like [`states_to_paced_events`], with the reports of each state's movement
+/// following its easing curve, or `easing` for states without one
+pub fn states_to_eased_events(states: &[MacroState], poll_interval_us: u64, easing: Easing) -> Vec<RecordedEvent> {
+    to_events(states, Some((poll_interval_us.max(1), easing)), None)
+}
+
+/// Convert states back to events, pacing movement as
+/// [`states_to_eased_events`] does if `motion` (poll interval and easing) is
+/// given, and spreading each state's scrolling over the state if `scroll` is
+///
+/// Smoothed scroll goes out in reports no closer than `scroll.rate_hz`
+/// allows, evenly over the state: a notch each, or with `hires` hi-res steps
+/// with the notches they add up to sent along for apps without hi-res
+/// support.
+pub fn states_to_smoothed_events(
+    states: &[MacroState],
+    motion: Option<(u64, Easing)>,
+    scroll: Option<ScrollPacing>,
+) -> Vec<RecordedEvent> {
+    to_events(states, motion.map(|(interval_us, easing)| (interval_us.max(1), easing)), scroll)
+}
+
+fn to_events(states: &[MacroState], pacing: Option<(u64, Easing)>, scroll: Option<ScrollPacing>) -> Vec<RecordedEvent> {
+    let joined;
+    let states = match scroll {
+        Some(_) => {
+            joined = join_scroll_waits(states);
+            &joined[..]
+        }
+        None => states,
+    };
+    let poll_interval_us = pacing.map(|(interval_us, _)| interval_us);
+    // Typically a press or release plus a sync per state
+    let mut events = Vec::with_capacity(states.len() * 2);
     let mut timestamp_us = 0u64;
-    let mut current_keys: HashSet<u16> = HashSet::new();
+    let no_keys = KeySet::new();
+    let mut current_keys = &no_keys;
 
     for state in states {
-        // Determine which keys need to be pressed and released
-        let keys_to_press: Vec<u16> = state
-            .keys_pressed
-            .difference(&current_keys)
-            .copied()
-            .collect();
-        let keys_to_release: Vec<u16> = current_keys
-            .difference(&state.keys_pressed)
-            .copied()
-            .collect();
-
         // Release keys that are no longer pressed
-        for key_code in keys_to_release {
+        for key_code in current_keys.difference(&state.keys_pressed) {
             events.push(RecordedEvent {
                 timestamp_us,
-                event: InputEvent::new(EventType::KEY.0, key_code, 0),
+                event: InputEvent::new(EventType::KEY.0, key_code.code(), 0),
             });
             events.push(RecordedEvent {
                 timestamp_us,
@@ -191,10 +349,10 @@ pub fn states_to_events(states: &[MacroState]) -> Vec<RecordedEvent> {
         }
 
         // Press new keys
-        for key_code in &keys_to_press {
+        for key_code in state.keys_pressed.difference(current_keys) {
             events.push(RecordedEvent {
                 timestamp_us,
-                event: InputEvent::new(EventType::KEY.0, *key_code, 1),
+                event: InputEvent::new(EventType::KEY.0, key_code.code(), 1),
             });
             events.push(RecordedEvent {
                 timestamp_us,
@@ -202,51 +360,47 @@ pub fn states_to_events(states: &[MacroState]) -> Vec<RecordedEvent> {
             });
         }
 
-        // Add mouse movement if any
-        if state.mouse_delta != (0, 0) {
-            if state.mouse_delta.0 != 0 {
-                events.push(RecordedEvent {
-                    timestamp_us,
-                    event: InputEvent::new(EventType::RELATIVE.0, 0, state.mouse_delta.0),
-                });
-            }
-            if state.mouse_delta.1 != 0 {
-                events.push(RecordedEvent {
-                    timestamp_us,
-                    event: InputEvent::new(EventType::RELATIVE.0, 1, state.mouse_delta.1),
-                });
-            }
-            events.push(RecordedEvent {
-                timestamp_us,
-                event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
-            });
+        // Add mouse movement if any, the first report now and the rest after
+        // this state's scrolling
+        let reports = match pacing {
+            Some((interval_us, easing)) => pace_motion(state, interval_us, state.easing.unwrap_or(easing)),
+            None => vec![state.mouse_delta],
+        };
+        let mut moves = reports
+            .into_iter()
+            .enumerate()
+            .filter(|(_, delta)| *delta != (0, 0))
+            .map(|(step, delta)| (timestamp_us + step as u64 * poll_interval_us.unwrap_or(0), delta));
+        if let Some((at_us, delta)) = moves.next() {
+            push_motion(&mut events, at_us, delta);
         }
 
-        // Add scroll events if any
-        if state.scroll_delta != (0, 0) {
-            if state.scroll_delta.0 != 0 {
-                events.push(RecordedEvent {
-                    timestamp_us,
-                    event: InputEvent::new(EventType::RELATIVE.0, 8, state.scroll_delta.0), // REL_WHEEL
-                });
-            }
-            if state.scroll_delta.1 != 0 {
-                events.push(RecordedEvent {
-                    timestamp_us,
-                    event: InputEvent::new(EventType::RELATIVE.0, 6, state.scroll_delta.1), // REL_HWHEEL
-                });
-            }
-            events.push(RecordedEvent {
-                timestamp_us,
-                event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
-            });
+        // Add scroll events if any, likewise
+        let scrolls = match scroll {
+            Some(pacing) => pace_scroll(state, pacing),
+            None => vec![(0, scroll_events(state.scroll_delta, None))],
+        };
+        let mut scrolls = scrolls.into_iter().filter(|(_, report)| !report.is_empty());
+        if let Some((_, report)) = scrolls.next() {
+            push_report(&mut events, timestamp_us, report);
+        }
+
+        // The later reports of both, in order
+        let mut later = Vec::new();
+        for (at_us, delta) in moves {
+            push_motion(&mut later, at_us, delta);
+        }
+        for (offset_us, report) in scrolls {
+            push_report(&mut later, timestamp_us + offset_us, report);
         }
+        later.sort_by_key(|event| event.timestamp_us);
+        events.append(&mut later);
 
         // Update current state
-        current_keys = state.keys_pressed.clone();
+        current_keys = &state.keys_pressed;
 
         // Advance time
-        timestamp_us += state.duration_ms * 1000; // Convert ms to microseconds
+        timestamp_us = timestamp_us.saturating_add(state.duration_ms.saturating_mul(1000)); // Convert ms to microseconds
     }
 
     // Release all remaining keys at the end
@@ -264,6 +418,288 @@ pub fn states_to_events(states: &[MacroState]) -> Vec<RecordedEvent> {
     events
 }
 
+/// A state's mouse movement split into reports one poll interval apart,
+/// covering the distance as `easing` says
+fn pace_motion(state: &MacroState, interval_us: u64, easing: Easing) -> Vec<(i32, i32)> {
+    let (dx, dy) = state.mouse_delta;
+    let longest = u64::from(dx.unsigned_abs().max(dy.unsigned_abs()));
+    let fitting = state.duration_ms.saturating_mul(1000) / interval_us;
+    let steps = fitting.min(longest).max(1) as i64;
+
+    // Cumulative rounding, so the reports add up to the exact delta
+    let position = |total: i32, step: i64| match easing {
+        Easing::Linear => i64::from(total) * step / steps,
+        _ if step >= steps => i64::from(total),
+        _ => (f64::from(total) * easing.progress(step as f64 / steps as f64)).round() as i64,
+    };
+    let share = |total: i32, step: i64| (position(total, step + 1) - position(total, step)) as i32;
+    (0..steps).map(|step| (share(dx, step), share(dy, step))).collect()
+}
+
+/// Join each scroll that takes no time with the wait after it, which is how
+/// `scroll down 10` and then `wait 500ms` read: one scroll over the wait
+fn join_scroll_waits(states: &[MacroState]) -> Vec<MacroState> {
+    let mut joined: Vec<MacroState> = Vec::with_capacity(states.len());
+    for state in states {
+        if let Some(previous) = joined.last_mut()
+            && previous.scroll_delta != (0, 0)
+            && previous.mouse_delta == (0, 0)
+            && previous.duration_ms == 0
+            && state.mouse_delta == (0, 0)
+            && state.scroll_delta == (0, 0)
+            && state.keys_pressed == previous.keys_pressed
+        {
+            previous.duration_ms = state.duration_ms;
+            continue;
+        }
+        joined.push(state.clone());
+    }
+    joined
+}
+
+/// A state's scrolling split into reports evenly over the state, each with
+/// its offset from the state's start
+fn pace_scroll(state: &MacroState, pacing: ScrollPacing) -> Vec<(u64, Vec<InputEvent>)> {
+    let scale = if pacing.hires { HIRES_PER_NOTCH } else { 1 };
+    let (vertical, horizontal) = state.scroll_delta;
+    let (vertical, horizontal) = (i64::from(vertical) * i64::from(scale), i64::from(horizontal) * i64::from(scale));
+    let duration_us = state.duration_ms.saturating_mul(1000);
+    let fitting = duration_us.saturating_mul(u64::from(pacing.rate_hz)) / 1_000_000;
+    let steps = fitting.min(vertical.unsigned_abs().max(horizontal.unsigned_abs())).max(1) as i64;
+
+    // Cumulative rounding, so the reports add up to the exact delta
+    let position = |total: i64, step: i64| total * step / steps;
+    let share = |total: i64, step: i64| (position(total, step + 1) - position(total, step)) as i32;
+    let notches = |total: i64, step: i64| {
+        let scale = i64::from(scale);
+        (position(total, step + 1) / scale - position(total, step) / scale) as i32
+    };
+    (0..steps)
+        .map(|step| {
+            let offset_us = duration_us * step as u64 / steps as u64;
+            let report = if pacing.hires {
+                scroll_events(
+                    (notches(vertical, step), notches(horizontal, step)),
+                    Some((share(vertical, step), share(horizontal, step))),
+                )
+            } else {
+                scroll_events((share(vertical, step), share(horizontal, step)), None)
+            };
+            (offset_us, report)
+        })
+        .collect()
+}
+
+/// Wheel events for `notches`, and `hires` units on the hi-res axes if given
+fn scroll_events(notches: (i32, i32), hires: Option<(i32, i32)>) -> Vec<InputEvent> {
+    let mut events = Vec::new();
+    if let Some((vertical, horizontal)) = hires {
+        if vertical != 0 {
+            events.push(InputEvent::new(EventType::RELATIVE.0, 11, vertical)); // REL_WHEEL_HI_RES
+        }
+        if horizontal != 0 {
+            events.push(InputEvent::new(EventType::RELATIVE.0, 12, horizontal)); // REL_HWHEEL_HI_RES
+        }
+    }
+    if notches.0 != 0 {
+        events.push(InputEvent::new(EventType::RELATIVE.0, 8, notches.0)); // REL_WHEEL
+    }
+    if notches.1 != 0 {
+        events.push(InputEvent::new(EventType::RELATIVE.0, 6, notches.1)); // REL_HWHEEL
+    }
+    events
+}
+
+/// Push a report's events and the sync ending it
+fn push_report(events: &mut Vec<RecordedEvent>, timestamp_us: u64, report: Vec<InputEvent>) {
+    for event in report {
+        events.push(RecordedEvent { timestamp_us, event });
+    }
+    events.push(RecordedEvent {
+        timestamp_us,
+        event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+    });
+}
+
+fn push_motion(events: &mut Vec<RecordedEvent>, timestamp_us: u64, (dx, dy): (i32, i32)) {
+    if dx != 0 {
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::RELATIVE.0, 0, dx),
+        });
+    }
+    if dy != 0 {
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::RELATIVE.0, 1, dy),
+        });
+    }
+    events.push(RecordedEvent {
+        timestamp_us,
+        event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+    });
+}
+
+/// Shorten idle gaps (states with no keys held) longer than `max_ms`
+///
+/// Returns the indices and original durations of the capped states.
+pub fn cap_idle(states: &mut [MacroState], max_ms: u64) -> Vec<(usize, u64)> {
+    let mut capped = Vec::new();
+
+    for (index, state) in states.iter_mut().enumerate() {
+        if state.keys_pressed.is_empty() && state.duration_ms > max_ms {
+            capped.push((index, state.duration_ms));
+            state.duration_ms = max_ms;
+        }
+    }
+
+    capped
+}
+
+/// Stretch states so every key stays pressed for at least `min_ms`
+///
+/// Some applications ignore presses shorter than a frame. Time added to keep a
+/// key down is paid back by shortening the following states where possible,
+/// so the total duration stays as close to the original as it can.
+pub fn enforce_min_hold(states: &[MacroState], min_ms: u64) -> Vec<MacroState> {
+    let mut result = Vec::with_capacity(states.len());
+    let mut press_times: HashMap<u16, u64> = HashMap::new();
+    let no_keys = KeySet::new();
+    let mut now_ms = 0u64;
+    // Time added so far that hasn't been taken back out yet
+    let mut debt_ms = 0u64;
+
+    for (index, state) in states.iter().enumerate() {
+        press_times.retain(|&key, _| state.keys_pressed.contains(key));
+        for key in &state.keys_pressed {
+            press_times.entry(key).or_insert(now_ms);
+        }
+
+        // Keys released when this state ends need it to last long enough
+        let next_keys = states
+            .get(index + 1)
+            .map_or(&no_keys, |next| &next.keys_pressed);
+        let required_ms = state
+            .keys_pressed
+            .difference(next_keys)
+            .map(|key| (press_times[&key.code()] + min_ms).saturating_sub(now_ms))
+            .max()
+            .unwrap_or(0);
+
+        let duration_ms = state.duration_ms.saturating_sub(debt_ms).max(required_ms);
+        if duration_ms >= state.duration_ms {
+            debt_ms += duration_ms - state.duration_ms;
+        } else {
+            debt_ms -= state.duration_ms - duration_ms;
+        }
+
+        let mut stretched = state.clone();
+        stretched.duration_ms = duration_ms;
+        result.push(stretched);
+        now_ms += duration_ms;
+    }
+
+    result
+}
+
+/// Move each state's length by up to `max_ms` either way, drawn from `rng`,
+/// so repeated playbacks don't keep the exact same rhythm
+///
+/// Taps (states with no length) stay taps, and nothing gets shorter than 1ms.
+pub fn humanize(states: &[MacroState], max_ms: u64, rng: &mut Rng) -> Vec<MacroState> {
+    states
+        .iter()
+        .map(|state| {
+            let mut varied = state.clone();
+            if state.duration_ms > 0 && max_ms > 0 {
+                let offset = rng.below(2 * max_ms + 1) as i64 - max_ms as i64;
+                varied.duration_ms = (state.duration_ms as i64 + offset).max(1) as u64;
+            }
+            varied
+        })
+        .collect()
+}
+
+/// Cut events to the window `from_us..to_us`, rebased to start at zero
+///
+/// Keys already held at `from_us` are pressed at the start of the window and
+/// keys still held at `to_us` are released at its end, so the slice plays back
+/// exactly like that part of the original. Events are consumed lazily and only
+/// the window is collected.
+pub fn slice_events<I>(events: I, from_us: u64, to_us: Option<u64>) -> Vec<RecordedEvent>
+where
+    I: IntoIterator<Item = RecordedEvent>,
+{
+    let mut sliced = Vec::new();
+    let mut held_keys = KeySet::new();
+    let mut entered = false;
+    let end_us = to_us.unwrap_or(u64::MAX);
+
+    for recorded in events {
+        if recorded.timestamp_us >= end_us {
+            break;
+        }
+
+        if recorded.timestamp_us >= from_us && !entered {
+            press_keys(&mut sliced, &held_keys, 0);
+            entered = true;
+        }
+
+        if recorded.event.event_type() == EventType::KEY {
+            match recorded.event.value() {
+                1 => {
+                    held_keys.insert(recorded.event.code());
+                }
+                0 => {
+                    held_keys.remove(recorded.event.code());
+                }
+                _ => {}
+            }
+        }
+
+        if entered {
+            sliced.push(RecordedEvent {
+                timestamp_us: recorded.timestamp_us - from_us,
+                event: recorded.event,
+            });
+        }
+    }
+
+    if entered {
+        // Release whatever is still held when the window closes
+        let end_us = to_us.map_or_else(
+            || sliced.last().map_or(0, |e| e.timestamp_us),
+            |to| to - from_us,
+        );
+        for key_code in &held_keys {
+            sliced.push(RecordedEvent {
+                timestamp_us: end_us,
+                event: InputEvent::new(EventType::KEY.0, key_code, 0),
+            });
+            sliced.push(RecordedEvent {
+                timestamp_us: end_us,
+                event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+            });
+        }
+    }
+
+    sliced
+}
+
+/// Append press events for `keys` at `timestamp_us`
+fn press_keys(events: &mut Vec<RecordedEvent>, keys: &KeySet, timestamp_us: u64) {
+    for key_code in keys {
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::KEY.0, key_code, 1),
+        });
+        events.push(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(EventType::SYNCHRONIZATION.0, 0, 0),
+        });
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -290,7 +726,37 @@ mod tests {
         let states = events_to_states(&events);
         assert_eq!(states.len(), 1);
         assert_eq!(states[0].duration_ms, 100);
-        assert!(states[0].keys_pressed.contains(&17));
+        assert!(states[0].keys_pressed.contains(17));
+    }
+
+    #[test]
+    fn test_led_and_switch_events_dont_change_states() {
+        // Caps lock tapped (its LED follows), then the lid closed: the
+        // states only see the key
+        let events = vec![
+            RecordedEvent {
+                timestamp_us: 0,
+                event: InputEvent::new(EventType::KEY.0, 58, 1),
+            },
+            RecordedEvent {
+                timestamp_us: 0,
+                event: InputEvent::new(EventType::LED.0, 1, 1), // LED_CAPSL
+            },
+            RecordedEvent {
+                timestamp_us: 50_000,
+                event: InputEvent::new(EventType::KEY.0, 58, 0),
+            },
+            RecordedEvent {
+                timestamp_us: 80_000,
+                event: InputEvent::new(EventType::SWITCH.0, 0, 1), // SW_LID
+            },
+        ];
+
+        let states = events_to_states(&events);
+        assert_eq!(states.len(), 2);
+        assert!(states[0].keys_pressed.contains(58));
+        assert!(states[1].keys_pressed.is_empty());
+        assert_eq!(states.iter().map(|state| state.duration_ms).sum::<u64>(), 80);
     }
 
     #[test]
@@ -301,12 +767,14 @@ mod tests {
                 keys_pressed: [17].iter().copied().collect(),
                 mouse_delta: (0, 0),
                 scroll_delta: (0, 0),
+                easing: None,
             },
             MacroState {
                 duration_ms: 20,
                 keys_pressed: [17].iter().copied().collect(),
                 mouse_delta: (0, 0),
                 scroll_delta: (0, 0),
+                easing: None,
             },
         ];
 
@@ -315,6 +783,214 @@ mod tests {
         assert_eq!(merged[0].duration_ms, 30);
     }
 
+    #[test]
+    fn test_states_merge_as_events_come() {
+        let event = |timestamp_us, kind: EventType, code, value| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(kind.0, code, value),
+        };
+        // A held for 40ms with syncs and a 2px jitter in between, then a real move
+        let events = [
+            event(0, EventType::KEY, 30, 1),
+            event(10_000, EventType::SYNCHRONIZATION, 0, 0),
+            event(20_000, EventType::RELATIVE, 0, 2),
+            event(30_000, EventType::SYNCHRONIZATION, 0, 0),
+            event(40_000, EventType::KEY, 30, 0),
+            event(50_000, EventType::RELATIVE, 0, 40),
+            event(60_000, EventType::SYNCHRONIZATION, 0, 0),
+        ];
+
+        let states = events_to_states(&events);
+        let summary: Vec<(u64, Vec<u16>, (i32, i32))> = states
+            .iter()
+            .map(|state| {
+                let keys = state.keys_pressed.iter().map(|key| key.code()).collect();
+                (state.duration_ms, keys, state.mouse_delta)
+            })
+            .collect();
+        assert_eq!(summary, [(40, vec![30], (0, 0)), (10, vec![], (0, 0)), (10, vec![], (40, 0))]);
+    }
+
+    #[test]
+    fn test_cap_idle_only_touches_waits() {
+        let mut held = MacroState::new(60_000);
+        held.keys_pressed.insert(17);
+        let mut states = vec![MacroState::new(5_000), MacroState::new(95_000), held];
+
+        let capped = cap_idle(&mut states, 10_000);
+
+        assert_eq!(capped, vec![(1, 95_000)]);
+        assert_eq!(states[0].duration_ms, 5_000);
+        assert_eq!(states[1].duration_ms, 10_000);
+        assert_eq!(states[2].duration_ms, 60_000); // Held keys aren't idle
+    }
+
+    #[test]
+    fn test_min_hold_stretches_taps() {
+        let mut tap = MacroState::new(0);
+        tap.keys_pressed.insert(17);
+        let states = vec![tap, MacroState::new(100)];
+
+        let stretched = enforce_min_hold(&states, 16);
+
+        assert_eq!(stretched[0].duration_ms, 16);
+        // Added time is taken back out of the following wait
+        assert_eq!(stretched[1].duration_ms, 84);
+    }
+
+    #[test]
+    fn test_min_hold_keeps_long_presses() {
+        let mut hold = MacroState::new(50);
+        hold.keys_pressed.insert(30);
+        let states = vec![hold, MacroState::new(10)];
+
+        assert_eq!(enforce_min_hold(&states, 16), states);
+    }
+
+    #[test]
+    fn test_humanize_stays_within_bounds() {
+        let mut tap = MacroState::new(0);
+        tap.keys_pressed.insert(17);
+        let states = vec![tap, MacroState::new(100), MacroState::new(3)];
+
+        let varied = humanize(&states, 10, &mut Rng::new(1));
+        assert_eq!(varied[0].duration_ms, 0);
+        assert!((90..=110).contains(&varied[1].duration_ms));
+        assert!((1..=13).contains(&varied[2].duration_ms));
+        // The same seed varies them the same way
+        assert_eq!(humanize(&states, 10, &mut Rng::new(1)), varied);
+        assert_eq!(humanize(&states, 0, &mut Rng::new(1)), states);
+    }
+
+    #[test]
+    fn test_slice_presses_keys_held_at_entry() {
+        // W held 0-500ms, A tapped at 300ms
+        let events = vec![
+            RecordedEvent {
+                timestamp_us: 0,
+                event: InputEvent::new(EventType::KEY.0, 17, 1),
+            },
+            RecordedEvent {
+                timestamp_us: 300_000,
+                event: InputEvent::new(EventType::KEY.0, 30, 1),
+            },
+            RecordedEvent {
+                timestamp_us: 350_000,
+                event: InputEvent::new(EventType::KEY.0, 30, 0),
+            },
+            RecordedEvent {
+                timestamp_us: 500_000,
+                event: InputEvent::new(EventType::KEY.0, 17, 0),
+            },
+        ];
+
+        let sliced = slice_events(events, 200_000, Some(400_000));
+        let keys: Vec<(u64, u16, i32)> = sliced
+            .iter()
+            .filter(|e| e.event.event_type() == EventType::KEY)
+            .map(|e| (e.timestamp_us, e.event.code(), e.event.value()))
+            .collect();
+
+        assert_eq!(
+            keys,
+            vec![
+                (0, 17, 1),        // W already held at entry
+                (100_000, 30, 1),  // A press, rebased
+                (150_000, 30, 0),  // A release
+                (200_000, 17, 0),  // W released at window end
+            ]
+        );
+    }
+
+    #[test]
+    fn test_display_shortcut_notation() {
+        let mut state = MacroState::new(80);
+        state.keys_pressed = [25, 42, 29].iter().copied().collect(); // P, SHIFT, CTRL
+        assert_eq!(state.to_string(), "CTRL+SHIFT+P (held 80ms)");
+
+        let mut state = MacroState::new(20);
+        state.mouse_delta = (10, -5);
+        assert_eq!(state.to_string(), "move 10 -5, wait 20ms");
+
+        assert_eq!(MacroState::new(100).to_string(), "wait 100ms");
+    }
+
+    #[test]
+    fn test_paced_motion() {
+        let mut glide = MacroState::new(10);
+        glide.mouse_delta = (100, -7);
+        let mut nudge = MacroState::new(1000);
+        nudge.mouse_delta = (3, 0);
+        let states = vec![glide, nudge];
+
+        let moves = |events: &[RecordedEvent]| -> Vec<(u64, u16, i32)> {
+            events
+                .iter()
+                .filter(|e| e.event.event_type() == EventType::RELATIVE)
+                .map(|e| (e.timestamp_us, e.event.code(), e.event.value()))
+                .collect()
+        };
+
+        // Unpaced: each state's movement in one go
+        assert_eq!(moves(&states_to_events(&states)), vec![(0, 0, 100), (0, 1, -7), (10_000, 0, 3)]);
+
+        // 500Hz: five reports over the 10ms glide, and the nudge isn't
+        // stretched over its whole second
+        let paced = moves(&states_to_paced_events(&states, 2_000));
+        let glide: Vec<i32> = paced.iter().filter(|m| m.0 < 10_000 && m.1 == 0).map(|m| m.2).collect();
+        assert_eq!(glide, vec![20, 20, 20, 20, 20]);
+        let glide_y: i32 = paced.iter().filter(|m| m.0 < 10_000 && m.1 == 1).map(|m| m.2).sum();
+        assert_eq!(glide_y, -7);
+        assert_eq!(paced.iter().filter(|m| m.1 == 0).map(|m| m.0).collect::<Vec<_>>()[..5], [0, 2_000, 4_000, 6_000, 8_000]);
+        assert_eq!(&paced[paced.len() - 3..], &[(10_000, 0, 1), (12_000, 0, 1), (14_000, 0, 1)]);
+
+        // Eased: slow at both ends, covering the same distance
+        let mut eased = MacroState::new(10);
+        eased.mouse_delta = (100, 0);
+        eased.easing = Some(Easing::EaseInOut);
+        let steps: Vec<i32> = moves(&states_to_paced_events(&[eased], 2_000)).iter().map(|m| m.2).collect();
+        assert_eq!(steps.iter().sum::<i32>(), 100);
+        assert!(steps[0] < 20 && steps[2] > 20 && steps[4] < 20, "{:?}", steps);
+    }
+
+    #[test]
+    fn test_smoothed_scroll() {
+        let mut flick = MacroState::new(100);
+        flick.scroll_delta = (-4, 0);
+        let wheel = |events: &[RecordedEvent]| -> Vec<(u64, u16, i32)> {
+            events
+                .iter()
+                .filter(|e| e.event.event_type() == EventType::RELATIVE)
+                .map(|e| (e.timestamp_us, e.event.code(), e.event.value()))
+                .collect()
+        };
+
+        // A notch each, evenly over the state
+        let spread = wheel(&states_to_smoothed_events(&[flick.clone()], None, Some("60Hz".parse().unwrap())));
+        assert_eq!(spread, vec![(0, 8, -1), (25_000, 8, -1), (50_000, 8, -1), (75_000, 8, -1)]);
+
+        // Hi-res steps at the rate, with the notches they add up to
+        let hires = wheel(&states_to_smoothed_events(&[flick.clone()], None, Some("60Hz hires".parse().unwrap())));
+        let steps: Vec<i32> = hires.iter().filter(|e| e.1 == 11).map(|e| e.2).collect();
+        assert_eq!(steps, vec![-80; 6]);
+        let notches: Vec<(u64, i32)> = hires.iter().filter(|e| e.1 == 8).map(|e| (e.0, e.2)).collect();
+        assert_eq!(notches, vec![(16_666, -1), (33_333, -1), (66_666, -1), (83_333, -1)]);
+
+        // Too short a state for more than one report
+        flick.duration_ms = 10;
+        let burst = wheel(&states_to_smoothed_events(&[flick.clone()], None, Some(ScrollPacing::default())));
+        assert_eq!(burst, vec![(0, 8, -4)]);
+
+        // A scroll and the wait after it scroll over the wait
+        flick.duration_ms = 0;
+        let written = wheel(&states_to_smoothed_events(&[flick, MacroState::new(100)], None, Some("60Hz".parse().unwrap())));
+        assert_eq!(written, spread);
+
+        assert_eq!("60Hz hires".parse::<ScrollPacing>().unwrap().to_string(), "60Hz hires");
+        assert!("0Hz".parse::<ScrollPacing>().is_err());
+        assert!("60Hz smooth".parse::<ScrollPacing>().unwrap_err().contains("Unknown scroll option"));
+    }
+
     #[test]
     fn test_wait_gap_between_keys() {
         // Simulate: Press W, hold for 100ms, release, wait 6000ms, press A
@@ -347,7 +1023,7 @@ mod tests {
 
         // First state: W held
         assert_eq!(states[0].duration_ms, 100);
-        assert!(states[0].keys_pressed.contains(&17));
+        assert!(states[0].keys_pressed.contains(17));
 
         // Second state: Wait (no keys)
         assert_eq!(states[1].duration_ms, 6000);
@@ -355,6 +1031,6 @@ mod tests {
 
         // Third state: A held
         assert_eq!(states[2].duration_ms, 100);
-        assert!(states[2].keys_pressed.contains(&30));
+        assert!(states[2].keys_pressed.contains(30));
     }
 }
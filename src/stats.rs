@@ -0,0 +1,275 @@
+//! Input statistics for recordings
+//!
+//! Computes per-key press counts and hold times, actions per minute, and mouse
+//! travel, so long recorded sessions can be analyzed for input habits.
+
+use crate::event::RecordedEvent;
+use crate::event::EventType;
+use std::collections::{BTreeMap, HashMap};
+
+/// Per-key usage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyStats {
+    pub presses: usize,
+    /// Sum of all hold durations (in milliseconds)
+    pub total_hold_ms: u64,
+}
+
+impl KeyStats {
+    /// Average time the key was held per press
+    pub fn average_hold_ms(&self) -> u64 {
+        if self.presses == 0 {
+            0
+        } else {
+            self.total_hold_ms / self.presses as u64
+        }
+    }
+}
+
+/// Summary of a recording
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Time from the first to the last event (in milliseconds)
+    pub duration_ms: u64,
+    /// Usage per keycode (keyboard keys and mouse buttons)
+    pub keys: BTreeMap<u16, KeyStats>,
+    /// Presses in each minute of the recording
+    pub actions_per_minute: Vec<usize>,
+    /// Total pointer travel (in pixels of relative movement)
+    pub mouse_travel: f64,
+    /// Total wheel detents, both axes
+    pub scroll_ticks: u64,
+}
+
+impl Stats {
+    /// Total number of presses across all keys
+    pub fn total_presses(&self) -> usize {
+        self.keys.values().map(|k| k.presses).sum()
+    }
+
+    /// Average actions per minute over the whole recording
+    pub fn average_apm(&self) -> f64 {
+        if self.duration_ms == 0 {
+            return 0.0;
+        }
+        self.total_presses() as f64 * 60_000.0 / self.duration_ms as f64
+    }
+
+    /// Keys ordered by press count, most used first
+    pub fn top_keys(&self) -> Vec<(u16, &KeyStats)> {
+        let mut keys: Vec<(u16, &KeyStats)> = self.keys.iter().map(|(&k, s)| (k, s)).collect();
+        keys.sort_by(|a, b| b.1.presses.cmp(&a.1.presses).then(a.0.cmp(&b.0)));
+        keys
+    }
+}
+
+/// Cursor position relative to where the recording started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathPoint {
+    pub timestamp_us: u64,
+    pub x: i64,
+    pub y: i64,
+}
+
+/// Accumulate relative mouse motion into a path, one point per report
+pub fn mouse_path(events: &[RecordedEvent]) -> Vec<PathPoint> {
+    let mut path = vec![PathPoint {
+        timestamp_us: events.first().map_or(0, |e| e.timestamp_us),
+        x: 0,
+        y: 0,
+    }];
+    let (mut x, mut y) = (0i64, 0i64);
+    let mut moved = false;
+
+    for recorded in events {
+        match recorded.event.event_type() {
+            EventType::RELATIVE if recorded.event.code() == 0 => {
+                x += recorded.event.value() as i64;
+                moved = true;
+            }
+            EventType::RELATIVE if recorded.event.code() == 1 => {
+                y += recorded.event.value() as i64;
+                moved = true;
+            }
+            EventType::SYNCHRONIZATION if moved => {
+                path.push(PathPoint {
+                    timestamp_us: recorded.timestamp_us,
+                    x,
+                    y,
+                });
+                moved = false;
+            }
+            _ => {}
+        }
+    }
+
+    if moved {
+        let timestamp_us = events.last().map_or(0, |e| e.timestamp_us);
+        path.push(PathPoint { timestamp_us, x, y });
+    }
+
+    path
+}
+
+/// Analyze a recording
+pub fn analyze(events: &[RecordedEvent]) -> Stats {
+    let mut stats = Stats::default();
+    let mut press_started: HashMap<u16, u64> = HashMap::new();
+    let mut frame_motion = (0i64, 0i64);
+
+    let first_us = events.first().map_or(0, |e| e.timestamp_us);
+    let last_us = events.last().map_or(0, |e| e.timestamp_us);
+    stats.duration_ms = last_us.saturating_sub(first_us) / 1000;
+
+    for recorded in events {
+        let code = recorded.event.code();
+        let value = recorded.event.value();
+
+        match recorded.event.event_type() {
+            EventType::KEY => match value {
+                1 => {
+                    stats.keys.entry(code).or_default().presses += 1;
+                    press_started.insert(code, recorded.timestamp_us);
+
+                    let minute = (recorded.timestamp_us.saturating_sub(first_us) / 60_000_000) as usize;
+                    if stats.actions_per_minute.len() <= minute {
+                        stats.actions_per_minute.resize(minute + 1, 0);
+                    }
+                    stats.actions_per_minute[minute] += 1;
+                }
+                0 => {
+                    if let Some(started) = press_started.remove(&code) {
+                        let held_ms = recorded.timestamp_us.saturating_sub(started) / 1000;
+                        stats.keys.entry(code).or_default().total_hold_ms += held_ms;
+                    }
+                }
+                _ => {}
+            },
+            EventType::RELATIVE => match code {
+                0 => frame_motion.0 += value as i64, // REL_X
+                1 => frame_motion.1 += value as i64, // REL_Y
+                6 | 8 => stats.scroll_ticks += value.unsigned_abs() as u64, // REL_HWHEEL, REL_WHEEL
+                _ => {}
+            },
+            EventType::SYNCHRONIZATION => {
+                // X and Y of one report arrive together; measure the diagonal
+                stats.mouse_travel += (frame_motion.0 as f64).hypot(frame_motion.1 as f64);
+                frame_motion = (0, 0);
+            }
+            _ => {}
+        }
+    }
+
+    stats.mouse_travel += (frame_motion.0 as f64).hypot(frame_motion.1 as f64);
+    stats
+}
+
+/// Fewest report intervals [`polling_hz`] estimates a rate from
+const MIN_POLL_SAMPLES: usize = 50;
+/// Gaps longer than this are pauses in movement, not polls (in microseconds)
+const MAX_POLL_INTERVAL_US: u64 = 50_000;
+/// Rates mice are commonly polled at, which estimates within 10% snap to
+const COMMON_POLLING_HZ: &[u32] = &[125, 250, 500, 1000, 2000, 4000, 8000];
+
+/// Polling rate of a mouse, from the intervals between its motion reports
+/// while moving (in microseconds)
+///
+/// Takes the median interval, so dropped or coalesced reports don't skew it.
+/// Returns `None` without enough movement to tell.
+pub fn polling_hz(intervals_us: &[u64]) -> Option<u32> {
+    let mut polls: Vec<u64> = intervals_us
+        .iter()
+        .copied()
+        .filter(|&interval| interval > 0 && interval <= MAX_POLL_INTERVAL_US)
+        .collect();
+    if polls.len() < MIN_POLL_SAMPLES {
+        return None;
+    }
+
+    polls.sort_unstable();
+    let hz = (1_000_000.0 / polls[polls.len() / 2] as f64).round() as u32;
+    let common = COMMON_POLLING_HZ
+        .iter()
+        .copied()
+        .find(|&common| hz.abs_diff(common) * 10 <= common);
+    Some(common.unwrap_or(hz))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::InputEvent;
+
+    fn event(timestamp_us: u64, event_type: EventType, code: u16, value: i32) -> RecordedEvent {
+        RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(event_type.0, code, value),
+        }
+    }
+
+    #[test]
+    fn test_key_counts_and_holds() {
+        let events = vec![
+            event(0, EventType::KEY, 17, 1),
+            event(100_000, EventType::KEY, 17, 0),
+            event(200_000, EventType::KEY, 17, 1),
+            event(500_000, EventType::KEY, 17, 0),
+            event(600_000, EventType::KEY, 30, 1),
+            event(650_000, EventType::KEY, 30, 0),
+        ];
+
+        let stats = analyze(&events);
+        assert_eq!(stats.duration_ms, 650);
+        assert_eq!(stats.keys[&17].presses, 2);
+        assert_eq!(stats.keys[&17].average_hold_ms(), 200);
+        assert_eq!(stats.top_keys()[0].0, 17);
+        assert_eq!(stats.actions_per_minute, vec![3]);
+
+        // Imported files can have timestamps out of order
+        let shuffled = vec![event(500_000, EventType::KEY, 17, 1), event(100_000, EventType::KEY, 30, 1)];
+        assert_eq!(analyze(&shuffled).actions_per_minute, vec![2]);
+    }
+
+    #[test]
+    fn test_mouse_path_accumulates() {
+        let events = vec![
+            event(0, EventType::RELATIVE, 0, 10),
+            event(0, EventType::SYNCHRONIZATION, 0, 0),
+            event(5000, EventType::RELATIVE, 0, -3),
+            event(5000, EventType::RELATIVE, 1, 7),
+            event(5000, EventType::SYNCHRONIZATION, 0, 0),
+        ];
+
+        let path = mouse_path(&events);
+        assert_eq!(path.len(), 3);
+        assert_eq!((path[1].x, path[1].y), (10, 0));
+        assert_eq!((path[2].x, path[2].y), (7, 7));
+        assert_eq!(path[2].timestamp_us, 5000);
+    }
+
+    #[test]
+    fn test_polling_hz() {
+        // A 1000Hz mouse with jitter, a dropped report and pauses
+        let mut intervals: Vec<u64> = (0..60).map(|i| 960 + (i % 5) * 20).collect();
+        intervals.extend([2_000, 800_000, 3_000_000]);
+        assert_eq!(polling_hz(&intervals), Some(1000));
+
+        assert_eq!(polling_hz(&[6_250; 60]), Some(160));
+        assert_eq!(polling_hz(&[8_000; 10]), None);
+    }
+
+    #[test]
+    fn test_mouse_travel_and_scroll() {
+        let events = vec![
+            event(0, EventType::RELATIVE, 0, 3),
+            event(0, EventType::RELATIVE, 1, 4),
+            event(0, EventType::SYNCHRONIZATION, 0, 0),
+            event(1000, EventType::RELATIVE, 8, -2),
+            event(1000, EventType::SYNCHRONIZATION, 0, 0),
+        ];
+
+        let stats = analyze(&events);
+        assert_eq!(stats.mouse_travel, 5.0);
+        assert_eq!(stats.scroll_ticks, 2);
+    }
+}
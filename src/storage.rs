@@ -5,43 +5,1184 @@
 //!   hold W+A for 4ms
 //!   wait 100ms
 //!   move 10 -5
+//!   move 400 -120 over 300ms ease-in-out
+//!   mark checkpoint
+//!   wait pixel 640 360 #ff8800
+//!   paste "some text"
+//!   type "some text"
+//!   at 640 360          (where the pointer was for the click on the next line)
+//!
+//! Parameters make a macro adjustable without editing it: `param NAME VALUE`
+//! declares one with its default, and `$NAME` anywhere else is replaced by
+//! its value, which `evkey play --param NAME=VALUE` can override:
+//!
+//!   param interval 50ms
+//!   tap BTN_LEFT
+//!   wait $interval
+//!
+//! A stretch done several times over is written once in a `repeat` block,
+//! which loading expands again (see [`Repeat`]):
+//!
+//!   repeat 40
+//!   tap E
+//!   wait 1200ms
+//!   end
 
+use crate::action::{Action, TextWait};
+use crate::binary;
+use crate::easing::Easing;
+use crate::guard::WindowGuard;
 use crate::keymap;
-use crate::recorder::RecordedEvent;
-use crate::state::{events_to_states, states_to_events, MacroState};
-use std::collections::HashSet;
+use crate::input_key::InputKey;
+use crate::keyset::KeySet;
+use crate::layout::XkbLayout;
+use crate::locks::LockState;
+use crate::loops;
+use crate::capabilities::Capabilities;
+use crate::migrations;
+use crate::postprocess::Pipeline;
+use crate::screen::{Region, ScreenSize};
+use crate::event::{EventType, InputEvent, RecordedClick, RecordedEvent, RecordedMarker};
+use crate::state::{self, events_to_states, MacroState, ScrollPacing};
+use crate::typing::{self, CharMap};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Write};
 use std::path::Path;
 
-/// Save recorded events as human-readable DSL
-pub fn save<P: AsRef<Path>>(path: P, events: &[RecordedEvent]) -> io::Result<()> {
-    let mut file = File::create(path)?;
+/// Longest duration accepted in a macro file, for a single line and in total
+///
+/// Keeps every timestamp derived from a file far away from overflowing.
+pub const MAX_DURATION_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+/// Most states `repeat` blocks may expand a track to, so a small file can't
+/// take up gigabytes once loaded
+pub const MAX_REPEATED_STATES: usize = 1_000_000;
+
+/// Rate eased movement is paced at when the macro doesn't record its mouse's
+/// polling rate
+const EASED_POLLING_HZ: u64 = 125;
+
+/// Macro metadata stored in the file header as `# Key: value` comments
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// Lock key state when recording started
+    pub locks: Option<LockState>,
+    /// Screen the macro was recorded on
+    pub screen: Option<ScreenSize>,
+    /// How often the recorded mouse reported movement; playback moves the
+    /// pointer at the same rate
+    pub polling_hz: Option<u32>,
+    /// Keyboard layout the macro was recorded with, which decides what
+    /// characters its keys type
+    pub keyboard: Option<XkbLayout>,
+    /// Easing curve for movement of states without one of their own
+    pub easing: Option<Easing>,
+    /// How scrolling is spread over each state; all at once if `None`
+    pub scroll: Option<ScrollPacing>,
+    /// The machine the macro was recorded on
+    pub environment: Environment,
+    /// `evkey play` options the macro plays with unless given on the command
+    /// line, e.g. `--min-hold 20ms --loop`
+    pub play: Vec<String>,
+    /// The only window the macro may play into
+    pub window: Option<WindowGuard>,
+}
+
+/// What a macro was recorded with, for making sense of it on another machine
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Environment {
+    /// EvKey version, e.g. "0.1.0"
+    pub evkey: Option<String>,
+    /// Kernel release, e.g. "6.8.0-45-generic"
+    pub kernel: Option<String>,
+    /// Devices recorded from
+    pub devices: Vec<RecordedDevice>,
+}
+
+impl Environment {
+    pub fn is_empty(&self) -> bool {
+        self.evkey.is_none() && self.kernel.is_none() && self.devices.is_empty()
+    }
+}
+
+/// A device a macro was recorded from, as `Name (vendor:product)` with the
+/// USB ids in hex
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedDevice {
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl RecordedDevice {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid device '{}', expected e.g. 'Keyboard (046d:c52b)'", s.trim());
+        let (name, ids) = s.trim().strip_suffix(')').and_then(|s| s.rsplit_once(" (")).ok_or_else(invalid)?;
+        let (vendor, product) = ids.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            name: name.to_string(),
+            vendor: u16::from_str_radix(vendor, 16).map_err(|_| invalid())?,
+            product: u16::from_str_radix(product, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for RecordedDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:04x}:{:04x})", self.name, self.vendor, self.product)
+    }
+}
+
+/// A named position in a macro, placed before the state at `index`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub index: usize,
+    pub name: String,
+}
+
+/// An action placed before the state at `index`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionStep {
+    pub index: usize,
+    pub action: Action,
+}
+
+/// Where the pointer was on screen for the click starting in the state at
+/// `index`
+///
+/// Only informational: playback still moves the pointer by the recorded
+/// deltas until [`Macro::anchor_clicks`] turns these into `move to` actions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClickPosition {
+    pub index: usize,
+    pub x: i32,
+    pub y: i32,
+}
+
+/// `times` copies of the `len` states starting at `index`, written once in
+/// a `repeat` block
+///
+/// The states stay expanded, so playback and editing see every copy. Saving
+/// only folds the copies back into a block while they're still identical,
+/// actions and click positions included, and there's no marker between them;
+/// otherwise they're written out one after another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Repeat {
+    pub index: usize,
+    pub len: usize,
+    pub times: usize,
+}
+
+impl Repeat {
+    /// Index of the first state after the last copy
+    pub fn end(&self) -> usize {
+        self.index + self.len * self.times
+    }
+}
+
+/// A macro as stored in a DSL file
+#[derive(Debug, Clone, Default)]
+pub struct Macro {
+    pub metadata: Metadata,
+    pub states: Vec<MacroState>,
+    pub markers: Vec<Marker>,
+    pub actions: Vec<ActionStep>,
+    pub clicks: Vec<ClickPosition>,
+    pub repeats: Vec<Repeat>,
+}
+
+impl Macro {
+    /// Build a macro from a recording, anchoring markers to the nearest state
+    pub fn from_recording(
+        events: &[RecordedEvent],
+        markers: &[RecordedMarker],
+        metadata: Metadata,
+    ) -> Self {
+        let states = events_to_states(events);
+        let markers = markers
+            .iter()
+            .map(|marker| Marker {
+                index: state_index_at(&states, marker.timestamp_us / 1000),
+                name: marker.name.clone(),
+            })
+            .collect();
+        let actions = indicator_changes(events)
+            .map(|(timestamp_us, action)| ActionStep {
+                index: state_index_at(&states, timestamp_us / 1000),
+                action,
+            })
+            .collect();
+
+        Self {
+            metadata,
+            states,
+            markers,
+            actions,
+            clicks: Vec::new(),
+            repeats: Vec::new(),
+        }
+    }
+
+    /// Attach pointer positions captured during recording to the states
+    /// their clicks start
+    pub fn place_clicks(&mut self, clicks: &[RecordedClick]) {
+        self.clicks.extend(clicks.iter().map(|click| ClickPosition {
+            index: state_index_at(&self.states, click.timestamp_us / 1000),
+            x: click.x,
+            y: click.y,
+        }));
+        self.clicks.sort_by_key(|click| click.index);
+    }
+
+    /// State index of the first marker with this name
+    pub fn marker(&self, name: &str) -> Option<usize> {
+        self.markers
+            .iter()
+            .find(|marker| marker.name == name)
+            .map(|marker| marker.index)
+    }
+
+    /// Time (in milliseconds) at which the named marker sits
+    pub fn marker_time_ms(&self, name: &str) -> Option<u64> {
+        Some(self.time_at_ms(self.marker(name)?))
+    }
+
+    /// Time (in milliseconds) at which the state at `index` starts
+    fn time_at_ms(&self, index: usize) -> u64 {
+        self.states[..index].iter().map(|s| s.duration_ms).sum()
+    }
+
+    /// Start time of every state (in microseconds)
+    pub fn state_starts_us(&self) -> Vec<u64> {
+        self.states
+            .iter()
+            .scan(0u64, |start_ms, state| {
+                let this_start = *start_ms;
+                *start_ms += state.duration_ms;
+                Some(this_start * 1000)
+            })
+            .collect()
+    }
+
+    /// Actions with the time (in microseconds) they run at during playback
+    pub fn timed_actions(&self) -> Vec<(u64, Action)> {
+        self.actions
+            .iter()
+            .map(|step| (self.time_at_ms(step.index) * 1000, step.action.clone()))
+            .collect()
+    }
+
+    /// Keep only the states in `from..to`, along with the markers inside it
+    pub fn slice(&self, from: usize, to: usize) -> Macro {
+        let to = to.min(self.states.len());
+        let from = from.min(to);
+
+        Macro {
+            metadata: self.metadata.clone(),
+            states: self.states[from..to].to_vec(),
+            markers: self
+                .markers
+                .iter()
+                .filter(|marker| (from..=to).contains(&marker.index))
+                .map(|marker| Marker {
+                    index: marker.index - from,
+                    name: marker.name.clone(),
+                })
+                .collect(),
+            actions: self
+                .actions
+                .iter()
+                .filter(|step| (from..=to).contains(&step.index))
+                .map(|step| ActionStep {
+                    index: step.index - from,
+                    action: step.action.clone(),
+                })
+                .collect(),
+            clicks: self
+                .clicks
+                .iter()
+                .filter(|click| (from..to).contains(&click.index))
+                .map(|click| ClickPosition {
+                    index: click.index - from,
+                    ..*click
+                })
+                .collect(),
+            repeats: self
+                .repeats
+                .iter()
+                .filter(|repeat| repeat.index >= from && repeat.end() <= to)
+                .map(|repeat| Repeat {
+                    index: repeat.index - from,
+                    ..*repeat
+                })
+                .collect(),
+        }
+    }
+
+    /// The states in `from..to` as a macro of their own that can be inserted
+    /// anywhere: keys still held at the end are let go, in a 1ms pause (the
+    /// shortest a file can hold)
+    pub fn clip(&self, from: usize, to: usize) -> Macro {
+        let mut clip = self.slice(from, to);
+        let end = clip.states.len();
+        if clip.states.last().is_some_and(|state| !state.keys_pressed.is_empty()) {
+            clip.states.push(MacroState::new(1));
+            // What was at the end stays there
+            for marker in clip.markers.iter_mut().filter(|marker| marker.index == end) {
+                marker.index += 1;
+            }
+            for step in clip.actions.iter_mut().filter(|step| step.index == end) {
+                step.index += 1;
+            }
+        }
+        clip
+    }
+
+    /// Insert `clip` before the state at `index`, with its markers, actions
+    /// and click positions
+    ///
+    /// Keys held across that point stay held through the clip, unless the
+    /// clip uses them itself; then they're let go for the clip and pressed
+    /// again after it.
+    pub fn insert_clip(&mut self, index: usize, clip: &Macro) {
+        let index = index.min(self.states.len());
+        let before = index.checked_sub(1).map(|i| &self.states[i].keys_pressed);
+        let after = self.states.get(index).map(|state| &state.keys_pressed);
+        let clip_keys: KeySet = clip.states.iter().flat_map(|state| state.keys_pressed.iter()).collect();
+        let held: Vec<u16> = match (before, after) {
+            (Some(before), Some(after)) => before
+                .codes()
+                .filter(|&key| after.contains(key) && !clip_keys.contains(key))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let count = clip.states.len();
+        for marker in &mut self.markers {
+            if marker.index > index {
+                marker.index += count;
+            }
+        }
+        for step in &mut self.actions {
+            if step.index > index {
+                step.index += count;
+            }
+        }
+        for click in &mut self.clicks {
+            if click.index >= index {
+                click.index += count;
+            }
+        }
+        // A clip in the middle of a repeated stretch breaks it up
+        self.repeats.retain(|repeat| repeat.index >= index || repeat.end() <= index);
+        for repeat in &mut self.repeats {
+            if repeat.index >= index {
+                repeat.index += count;
+            }
+        }
+        self.repeats.extend(clip.repeats.iter().map(|repeat| Repeat {
+            index: repeat.index + index,
+            ..*repeat
+        }));
+        self.repeats.sort_by_key(|repeat| repeat.index);
+
+        self.states.splice(
+            index..index,
+            clip.states.iter().map(|state| {
+                let mut state = state.clone();
+                for &key in &held {
+                    state.keys_pressed.insert(key);
+                }
+                state
+            }),
+        );
+        // Markers and actions at `index` stay ahead of the clip
+        self.markers.extend(clip.markers.iter().map(|marker| Marker {
+            index: marker.index + index,
+            name: marker.name.clone(),
+        }));
+        self.markers.sort_by_key(|marker| marker.index);
+        self.actions.extend(clip.actions.iter().map(|step| ActionStep {
+            index: step.index + index,
+            action: step.action.clone(),
+        }));
+        self.actions.sort_by_key(|step| step.index);
+        self.clicks.extend(clip.clicks.iter().map(|click| ClickPosition {
+            index: click.index + index,
+            ..*click
+        }));
+        self.clicks.sort_by_key(|click| click.index);
+    }
+
+    /// Cap idle gaps longer than `max_ms`, optionally leaving an `idle-Ns`
+    /// marker where each gap was so it's easy to find afterwards
+    ///
+    /// Returns the number of gaps capped.
+    pub fn cap_idle(&mut self, max_ms: u64, mark: bool) -> usize {
+        let capped = state::cap_idle(&mut self.states, max_ms);
+
+        if mark {
+            for &(index, original_ms) in &capped {
+                self.markers.push(Marker {
+                    index,
+                    name: format!("idle-{}s", original_ms / 1000),
+                });
+            }
+            self.markers.sort_by_key(|marker| marker.index);
+        }
+
+        capped.len()
+    }
+
+    /// Run `pipeline` over the states
+    ///
+    /// Each stretch between markers, actions and click positions goes through
+    /// it on its own, so those stay where they were however the passes split
+    /// or join states.
+    pub fn postprocess(&mut self, pipeline: &Pipeline) {
+        let mut bounds: Vec<usize> = self
+            .markers
+            .iter()
+            .map(|marker| marker.index)
+            .chain(self.actions.iter().map(|step| step.index))
+            .chain(self.clicks.iter().map(|click| click.index))
+            .chain([0, self.states.len()])
+            .filter(|&index| index <= self.states.len())
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut states = Vec::with_capacity(self.states.len());
+        // Where each bound ended up
+        let mut moved = vec![0];
+        for stretch in bounds.windows(2) {
+            states.extend(pipeline.run(self.states[stretch[0]..stretch[1]].to_vec()));
+            moved.push(states.len());
+        }
+        let remap = |index: usize| bounds.binary_search(&index).map_or(states.len(), |at| moved[at]);
+
+        for marker in &mut self.markers {
+            marker.index = remap(marker.index);
+        }
+        for step in &mut self.actions {
+            step.index = remap(step.index);
+        }
+        for click in &mut self.clicks {
+            click.index = remap(click.index);
+        }
+        self.states = states;
+    }
+
+    /// Rescale mouse movement and screen coordinates from the screen the
+    /// macro was recorded on to `screen`
+    ///
+    /// Returns false (changing nothing) if the recording screen is unknown or
+    /// the same size.
+    pub fn scale_to_screen(&mut self, screen: ScreenSize) -> bool {
+        let Some(recorded) = self.metadata.screen.filter(|&recorded| recorded != screen) else {
+            return false;
+        };
+        let (scale_x, scale_y) = recorded.scale_to(screen);
+        let scale = |value: i32, factor: f64| (f64::from(value) * factor).round() as i32;
+
+        // Carry rounding errors along so long movements end up in the right place
+        let mut carry = (0.0, 0.0);
+        for state in &mut self.states {
+            let (dx, dy) = state.mouse_delta;
+            let x = f64::from(dx) * scale_x + carry.0;
+            let y = f64::from(dy) * scale_y + carry.1;
+            state.mouse_delta = (x.round() as i32, y.round() as i32);
+            carry = (x - x.round(), y - y.round());
+        }
+
+        for step in &mut self.actions {
+            match &mut step.action {
+                Action::MoveTo { x, y } => (*x, *y) = (scale(*x, scale_x), scale(*y, scale_y)),
+                Action::WaitPixel(wait) => (wait.x, wait.y) = (scale(wait.x, scale_x), scale(wait.y, scale_y)),
+                Action::WaitText(TextWait { region: Some(region), .. }) => {
+                    let scale_size = |size: u32, factor: f64| (f64::from(size) * factor).round() as u32;
+                    *region = Region {
+                        x: scale(region.x, scale_x),
+                        y: scale(region.y, scale_y),
+                        width: scale_size(region.width, scale_x).max(1),
+                        height: scale_size(region.height, scale_y).max(1),
+                    };
+                }
+                Action::WaitText(_) => {}
+                Action::SetClipboard(_) | Action::Paste(_) | Action::Type(_) | Action::Snippet(_) => {}
+                Action::Led { .. } | Action::Switch { .. } => {}
+            }
+        }
+        for click in &mut self.clicks {
+            (click.x, click.y) = (scale(click.x, scale_x), scale(click.y, scale_y));
+        }
+
+        self.metadata.screen = Some(screen);
+        true
+    }
+
+    /// Replace the pointer travel leading up to each click with a `move to`
+    /// the click's recorded position, so clicks land in the right place
+    /// whatever the pointer acceleration or starting position
+    ///
+    /// Motion while a mouse button is held (dragging) is kept. Returns the
+    /// number of clicks anchored.
+    pub fn anchor_clicks(&mut self) -> usize {
+        let clicks = std::mem::take(&mut self.clicks);
+        let mut travel_from = 0;
+
+        for click in &clicks {
+            for state in &mut self.states[travel_from..click.index] {
+                if !state.keys_pressed.iter().any(InputKey::is_mouse_button) {
+                    state.mouse_delta = (0, 0);
+                }
+            }
+            travel_from = click.index;
+            self.actions.push(ActionStep {
+                index: click.index,
+                action: Action::MoveTo { x: click.x, y: click.y },
+            });
+        }
+
+        // Stable, so each `move to` runs after actions already at its state
+        self.actions.sort_by_key(|step| step.index);
+        self.drop_idle_states();
+        clicks.len()
+    }
+
+    /// The text the macro types, one stretch of typing per line
+    pub fn typed_text(&self) -> Result<String, String> {
+        let chars = CharMap::for_recording(self.metadata.keyboard.as_ref())?;
+        let caps_lock = self.metadata.locks.is_some_and(|locks| locks.caps);
+        Ok(typing::join_runs(&typing::typed_runs(&self.states, caps_lock, &chars)))
+    }
+
+    /// Replace each stretch of typing at least `min_chars` long with a
+    /// `type` action typing the same text
+    ///
+    /// Stretches with an action in the middle are left alone. Returns the
+    /// number of stretches replaced.
+    pub fn replace_typing(&mut self, min_chars: usize) -> Result<usize, String> {
+        let chars = CharMap::for_recording(self.metadata.keyboard.as_ref())?;
+        let caps_lock = self.metadata.locks.is_some_and(|locks| locks.caps);
+        let mut replaced = 0;
+
+        for run in typing::typed_runs(&self.states, caps_lock, &chars) {
+            let inside = |index: usize| index > run.start && index < run.end;
+            if run.text.chars().count() < min_chars || self.actions.iter().any(|step| inside(step.index)) {
+                continue;
+            }
+            for state in &mut self.states[run.start..run.end] {
+                *state = MacroState::new(0);
+            }
+            self.actions.push(ActionStep {
+                index: run.start,
+                action: Action::Type(run.text),
+            });
+            replaced += 1;
+        }
+
+        // Stable, so each `type` runs after actions already at its state
+        self.actions.sort_by_key(|step| step.index);
+        self.drop_idle_states();
+        Ok(replaced)
+    }
+
+    /// Remove states that do nothing and take no time, keeping markers,
+    /// actions and clicks where they were
+    fn drop_idle_states(&mut self) {
+        let idle = |state: &MacroState| state.is_empty() && state.duration_ms == 0;
+        // New index of every state boundary
+        let mut new_index = Vec::with_capacity(self.states.len() + 1);
+        let mut kept = 0;
+        for state in &self.states {
+            new_index.push(kept);
+            if !idle(state) {
+                kept += 1;
+            }
+        }
+        new_index.push(kept);
+
+        self.states.retain(|state| !idle(state));
+        for marker in &mut self.markers {
+            marker.index = new_index[marker.index];
+        }
+        for step in &mut self.actions {
+            step.index = new_index[step.index];
+        }
+        for click in &mut self.clicks {
+            click.index = new_index[click.index];
+        }
+    }
+
+    /// Stretches done several times over in a row, their copies' durations
+    /// up to `tolerance_ms` apart (see [`loops`])
+    ///
+    /// Copies need the same actions and click positions, and no stretch
+    /// crosses a marker. Ones where nothing is pressed, moved or scrolled
+    /// aren't worth a block and are left out.
+    pub fn find_loops(&self, tolerance_ms: u64) -> Vec<Repeat> {
+        let count = self.states.len();
+        let mut actions: Vec<Vec<&Action>> = vec![Vec::new(); count + 1];
+        for step in self.actions.iter().filter(|step| step.index <= count) {
+            actions[step.index].push(&step.action);
+        }
+        let mut clicks: Vec<Vec<(i32, i32)>> = vec![Vec::new(); count + 1];
+        for click in self.clicks.iter().filter(|click| click.index <= count) {
+            clicks[click.index].push((click.x, click.y));
+        }
+        let same = |a: usize, b: usize| {
+            loops::similar(&self.states[a], &self.states[b], tolerance_ms)
+                && actions[a] == actions[b]
+                && clicks[a].len() == clicks[b].len()
+                && clicks[a].iter().zip(&clicks[b]).all(|(a, b)| {
+                    (a.0 - b.0).abs() <= loops::MOTION_TOLERANCE && (a.1 - b.1).abs() <= loops::MOTION_TOLERANCE
+                })
+        };
+
+        let mut bounds: Vec<usize> = self
+            .markers
+            .iter()
+            .map(|marker| marker.index)
+            .chain([0, count])
+            .filter(|&index| index <= count)
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let mut found = Vec::new();
+        for stretch in bounds.windows(2) {
+            let from = stretch[0];
+            for (start, len, times) in loops::find(stretch[1] - from, |a, b| same(from + a, from + b)) {
+                let index = from + start;
+                if self.states[index..index + len].iter().any(|state| !state.is_empty()) {
+                    found.push(Repeat { index, len, times });
+                }
+            }
+        }
+        found
+    }
+
+    /// Make the copies of each stretch in `repeats` identical, so saving
+    /// writes them once in a `repeat` block
+    ///
+    /// Each copy plays like the average of the recorded ones, so the macro
+    /// takes about as long and moves the pointer about as far as before.
+    /// Click positions are the first copy's.
+    pub fn fold_loops(&mut self, repeats: &[Repeat]) {
+        for repeat in repeats {
+            let times = repeat.times as f64;
+            for at in repeat.index..repeat.index + repeat.len {
+                let copies: Vec<usize> = (0..repeat.times).map(|copy| at + copy * repeat.len).collect();
+                let average = |value: &dyn Fn(&MacroState) -> f64| {
+                    copies.iter().map(|&index| value(&self.states[index])).sum::<f64>() / times
+                };
+                let mut state = self.states[at].clone();
+                state.duration_ms = average(&|state| state.duration_ms as f64).round() as u64;
+                state.mouse_delta = (
+                    average(&|state| f64::from(state.mouse_delta.0)).round() as i32,
+                    average(&|state| f64::from(state.mouse_delta.1)).round() as i32,
+                );
+                for &index in &copies {
+                    self.states[index] = state.clone();
+                }
+            }
+
+            let first_end = repeat.index + repeat.len;
+            let first: Vec<ClickPosition> = self
+                .clicks
+                .iter()
+                .filter(|click| (repeat.index..first_end).contains(&click.index))
+                .copied()
+                .collect();
+            for copy in 1..repeat.times {
+                let from = repeat.index + copy * repeat.len;
+                let later = self.clicks.iter_mut().filter(|click| (from..from + repeat.len).contains(&click.index));
+                for (click, first) in later.zip(&first) {
+                    (click.x, click.y) = (first.x, first.y);
+                }
+            }
+        }
+        self.repeats = repeats.to_vec();
+    }
+
+    /// Whether the copies of `repeat` are identical, with no marker between
+    /// them, so they can be written as one block
+    fn folds(&self, repeat: &Repeat) -> bool {
+        let end = repeat.len.checked_mul(repeat.times).and_then(|states| states.checked_add(repeat.index));
+        if repeat.len == 0 || repeat.times < 2 || end.is_none_or(|end| end > self.states.len()) {
+            return false;
+        }
+        let inside = repeat.index..repeat.end();
+        let body = &self.states[repeat.index..repeat.index + repeat.len];
+        // Which copy something at `index` is in, and where in it
+        let place = |index: usize| ((index - repeat.index) / repeat.len, (index - repeat.index) % repeat.len);
+
+        let mut actions: Vec<Vec<(usize, &Action)>> = vec![Vec::new(); repeat.times];
+        for step in self.actions.iter().filter(|step| inside.contains(&step.index)) {
+            let (copy, at) = place(step.index);
+            actions[copy].push((at, &step.action));
+        }
+        let mut clicks: Vec<Vec<(usize, i32, i32)>> = vec![Vec::new(); repeat.times];
+        for click in self.clicks.iter().filter(|click| inside.contains(&click.index)) {
+            let (copy, at) = place(click.index);
+            clicks[copy].push((at, click.x, click.y));
+        }
+
+        self.states[inside.clone()].chunks(repeat.len).all(|copy| copy == body)
+            && actions.iter().all(|copy| *copy == actions[0])
+            && clicks.iter().all(|copy| *copy == clicks[0])
+            && !self.markers.iter().any(|marker| marker.index > repeat.index && inside.contains(&marker.index))
+    }
+
+    /// How many states at the start and at the end do nothing, like the
+    /// wait before the first input of a recording
+    pub fn idle_ends(&self) -> (usize, usize) {
+        let leading = self.states.iter().take_while(|state| state.is_empty()).count();
+        let trailing = self.states[leading..].iter().rev().take_while(|state| state.is_empty()).count();
+        (leading, trailing)
+    }
 
-    writeln!(file, "# EvKey Macro")?;
-    writeln!(file, "# Layout: QWERTY")?;
-    writeln!(file)?;
+    /// What playing the macro takes from the device it's played on
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::of_playback(&self.events(), &self.timed_actions())
+    }
+
+    /// Check if there's nothing in the macro at all
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty() && self.markers.is_empty() && self.actions.is_empty()
+    }
+
+    /// Convert the states to events for playback, moving the pointer at the
+    /// recorded mouse's polling rate if known, and along easing curves, and
+    /// smoothing scroll if the macro asks for it
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        let easing = self.metadata.easing.unwrap_or_default();
+        let eased = self.metadata.easing.is_some() || self.states.iter().any(|state| state.easing.is_some());
+        let motion = match self.metadata.polling_hz {
+            Some(hz) => Some((1_000_000 / u64::from(hz.max(1)), easing)),
+            None if eased => Some((1_000_000 / EASED_POLLING_HZ, easing)),
+            None => None,
+        };
+        state::states_to_smoothed_events(&self.states, motion, self.metadata.scroll)
+    }
+}
+
+/// A named part of a macro, e.g. everything recorded from the keyboard
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    /// Empty for the part of a file before its first `track` line
+    pub name: String,
+    pub macro_: Macro,
+}
+
+/// A macro file split into tracks that play back together
+///
+/// Files without `track` lines hold a single unnamed track.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub metadata: Metadata,
+    pub tracks: Vec<Track>,
+}
+
+impl Session {
+    /// A session holding just `macro_`, as an unnamed track
+    pub fn single(macro_: Macro) -> Self {
+        Self {
+            metadata: macro_.metadata.clone(),
+            tracks: vec![Track {
+                name: String::new(),
+                macro_,
+            }],
+        }
+    }
+
+    /// Build a session from a recording split by `Recorder::stop_tracks`
+    ///
+    /// Markers aren't tied to a device, so they all go on the first track.
+    pub fn from_recording(
+        tracks: &[(String, Vec<RecordedEvent>)],
+        markers: &[RecordedMarker],
+        metadata: Metadata,
+    ) -> Self {
+        Self {
+            metadata,
+            tracks: tracks
+                .iter()
+                .enumerate()
+                .map(|(index, (name, events))| Track {
+                    name: name.clone(),
+                    macro_: Macro::from_recording(events, if index == 0 { markers } else { &[] }, Metadata::default()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Attach click positions to the track with the mouse in it (or the
+    /// first track)
+    pub fn place_clicks(&mut self, clicks: &[RecordedClick]) {
+        let index = self.tracks.iter().position(|track| track.name.contains("mouse")).unwrap_or(0);
+        if let Some(track) = self.tracks.get_mut(index) {
+            track.macro_.place_clicks(clicks);
+        }
+    }
+
+    /// Names of the named tracks, in file order
+    pub fn track_names(&self) -> Vec<&str> {
+        self.tracks
+            .iter()
+            .map(|track| track.name.as_str())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    /// All tracks merged into one macro
+    pub fn merged(&self) -> Macro {
+        merge_tracks(&self.tracks.iter().collect::<Vec<_>>(), &self.metadata)
+    }
+
+    /// Merge the chosen tracks into one macro
+    ///
+    /// `include` lists the tracks to keep (all of them if empty) and `exclude`
+    /// the ones to leave out.
+    pub fn select(&self, include: &[&str], exclude: &[&str]) -> Result<Macro, String> {
+        let names = self.track_names();
+        if let Some(unknown) = include.iter().chain(exclude).find(|name| !names.contains(name)) {
+            return Err(format!(
+                "No track named '{}' (tracks: {})",
+                unknown,
+                if names.is_empty() { "none".to_string() } else { names.join(", ") }
+            ));
+        }
+
+        let tracks: Vec<&Track> = self
+            .tracks
+            .iter()
+            .filter(|track| include.is_empty() || include.contains(&track.name.as_str()))
+            .filter(|track| !exclude.contains(&track.name.as_str()))
+            .collect();
+        Ok(merge_tracks(&tracks, &self.metadata))
+    }
+}
+
+/// Interleave tracks by time into a single macro
+fn merge_tracks(tracks: &[&Track], metadata: &Metadata) -> Macro {
+    if let [track] = tracks {
+        return Macro {
+            metadata: metadata.clone(),
+            ..track.macro_.clone()
+        };
+    }
+
+    let mut events = Vec::new();
+    let mut markers = Vec::new();
+    let mut actions = Vec::new();
+    let mut clicks = Vec::new();
+    for track in tracks {
+        let macro_ = &track.macro_;
+        events.extend(macro_.events());
+        markers.extend(macro_.markers.iter().map(|marker| RecordedMarker {
+            timestamp_us: macro_.time_at_ms(marker.index) * 1000,
+            name: marker.name.clone(),
+        }));
+        actions.extend(macro_.timed_actions());
+        clicks.extend(macro_.clicks.iter().map(|click| RecordedClick {
+            timestamp_us: macro_.time_at_ms(click.index) * 1000,
+            x: click.x,
+            y: click.y,
+        }));
+    }
+
+    // Stable sorts, so things at the same time keep their track order
+    events.sort_by_key(|event| event.timestamp_us);
+    markers.sort_by_key(|marker| marker.timestamp_us);
+    actions.sort_by_key(|(at_us, _)| *at_us);
+
+    let mut macro_ = Macro::from_recording(&events, &markers, metadata.clone());
+    macro_.actions = actions
+        .into_iter()
+        .map(|(at_us, action)| ActionStep {
+            index: state_index_at(&macro_.states, at_us / 1000),
+            action,
+        })
+        .collect();
+    macro_.place_clicks(&clicks);
+    macro_
+}
+
+/// LED and switch changes in `events`, as `led`/`switch` actions with when
+/// they happened; a change every keyboard reports is kept once
+fn indicator_changes(events: &[RecordedEvent]) -> impl Iterator<Item = (u64, Action)> + '_ {
+    let mut current: HashMap<(u16, u16), bool> = HashMap::new();
+    events.iter().filter_map(move |recorded| {
+        let event = recorded.event;
+        let (kind, code, on) = (event.event_type(), event.code(), event.value() != 0);
+        if kind != EventType::LED && kind != EventType::SWITCH {
+            return None;
+        }
+        if current.insert((kind.0, code), on) == Some(on) {
+            return None;
+        }
+        let action = if kind == EventType::LED { Action::Led { code, on } } else { Action::Switch { code, on } };
+        Some((recorded.timestamp_us, action))
+    })
+}
+
+/// Index of the state boundary closest to `time_ms`
+fn state_index_at(states: &[MacroState], time_ms: u64) -> usize {
+    let mut start_ms = 0u64;
+    for (index, state) in states.iter().enumerate() {
+        let end_ms = start_ms + state.duration_ms;
+        if time_ms < end_ms {
+            // Snap to whichever edge of this state is closer
+            return if time_ms - start_ms <= end_ms - time_ms {
+                index
+            } else {
+                index + 1
+            };
+        }
+        start_ms = end_ms;
+    }
+    states.len()
+}
+
+/// Save a recording as human-readable DSL
+///
+/// Paths ending in `.evkb` are written in the compact binary format instead,
+/// which keeps every event (including LED and switch events) but no metadata
+/// or markers.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    events: &[RecordedEvent],
+    markers: &[RecordedMarker],
+    metadata: &Metadata,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == binary::EXTENSION) {
+        return binary::save(path, events);
+    }
 
     // Convert events to states
-    let states = events_to_states(events);
+    save_macro(path, &Macro::from_recording(events, markers, metadata.clone()))
+}
+
+/// Save a macro as human-readable DSL
+pub fn save_macro<P: AsRef<Path>>(path: P, macro_: &Macro) -> io::Result<()> {
+    File::create(path)?.write_all(format_macro(macro_).as_bytes())
+}
 
-    // Write each state in DSL format
-    for state in &states {
-        let line = format_state(state);
-        writeln!(file, "{}", line)?;
+/// Save a session with all its tracks as human-readable DSL
+///
+/// The binary format has no tracks, so `.evkb` paths are rejected.
+pub fn save_session<P: AsRef<Path>>(path: P, session: &Session) -> io::Result<()> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == binary::EXTENSION) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Tracks can only be saved in the text format",
+        ));
     }
+    File::create(path)?.write_all(format_session(session).as_bytes())
+}
 
-    Ok(())
+/// Format a macro as DSL text, header included
+pub fn format_macro(macro_: &Macro) -> String {
+    let mut text = format_header(&macro_.metadata, macro_.capabilities());
+    format_body(macro_, &mut text);
+    text
+}
+
+/// Format a session as DSL text, with a `track` line ahead of each named track
+pub fn format_session(session: &Session) -> String {
+    let mut text = format_header(&session.metadata, session.merged().capabilities());
+    for track in &session.tracks {
+        if !track.name.is_empty() {
+            text.push_str(&format!("track {}\n", track.name));
+        }
+        format_body(&track.macro_, &mut text);
+    }
+    text
+}
+
+/// The header lines for `metadata`, and for what the macro `needs` to play
+fn format_header(metadata: &Metadata, needs: Capabilities) -> String {
+    let mut text = String::new();
+    text.push_str("# EvKey Macro\n");
+    text.push_str(&format!("# Version: {}\n", migrations::CURRENT_VERSION));
+    text.push_str("# Layout: QWERTY\n");
+    if let Some(locks) = metadata.locks {
+        text.push_str(&format!("# Locks: {}\n", locks));
+    }
+    if let Some(screen) = metadata.screen {
+        text.push_str(&format!("# Screen: {}\n", screen));
+    }
+    if let Some(hz) = metadata.polling_hz {
+        text.push_str(&format!("# Polling: {}Hz\n", hz));
+    }
+    if let Some(keyboard) = &metadata.keyboard {
+        text.push_str(&format!("# Keyboard: {}\n", keyboard));
+    }
+    if let Some(easing) = metadata.easing {
+        text.push_str(&format!("# Easing: {}\n", easing));
+    }
+    if let Some(scroll) = metadata.scroll {
+        text.push_str(&format!("# Scroll: {}\n", scroll));
+    }
+    if let Some(evkey) = &metadata.environment.evkey {
+        text.push_str(&format!("# Recorded with: EvKey {}\n", evkey));
+    }
+    if let Some(kernel) = &metadata.environment.kernel {
+        text.push_str(&format!("# Kernel: {}\n", kernel));
+    }
+    for device in &metadata.environment.devices {
+        text.push_str(&format!("# Device: {}\n", device));
+    }
+    if !metadata.play.is_empty() {
+        text.push_str(&format!("# Play: {}\n", metadata.play.join(" ")));
+    }
+    if let Some(window) = &metadata.window {
+        text.push_str(&format!("# Window: {}\n", window.pattern));
+        if window.wait {
+            text.push_str("# Unfocused: wait\n");
+        }
+    }
+    if !needs.is_empty() {
+        text.push_str(&format!("# Needs: {}\n", needs));
+    }
+    text.push('\n');
+    text
+}
+
+fn format_body(macro_: &Macro, text: &mut String) {
+    // Write each state in DSL format, with markers and actions ahead of their
+    // state, and repeated stretches once in a `repeat` block
+    let mut index = 0;
+    let mut block: Option<Repeat> = None;
+    loop {
+        if let Some(repeat) = block.filter(|repeat| index == repeat.index + repeat.len) {
+            text.push_str("end\n");
+            index = repeat.end();
+            block = None;
+        }
+        for marker in macro_.markers.iter().filter(|m| m.index == index) {
+            text.push_str(&format!("mark {}\n", marker.name));
+        }
+        if block.is_none()
+            && let Some(repeat) = macro_.repeats.iter().find(|r| r.index == index && macro_.folds(r))
+        {
+            text.push_str(&format!("repeat {}\n", repeat.times));
+            block = Some(*repeat);
+        }
+        for step in macro_.actions.iter().filter(|a| a.index == index) {
+            text.push_str(&format!("{}\n", step.action));
+        }
+        for click in macro_.clicks.iter().filter(|c| c.index == index) {
+            text.push_str(&format!("at {} {}\n", click.x, click.y));
+        }
+        let Some(state) = macro_.states.get(index) else {
+            break;
+        };
+        text.push_str(&format_state(state));
+        text.push('\n');
+        index += 1;
+    }
+}
+
+/// Read only the header of a macro file; binary macros have none
+pub fn load_metadata<P: AsRef<Path>>(path: P) -> io::Result<Metadata> {
+    let path = path.as_ref();
+    if binary::is_binary(path) {
+        return Ok(Metadata::default());
+    }
+
+    let lines: Vec<String> = read_lines(path)?
+        .into_iter()
+        .take_while(|line| line.trim().is_empty() || line.trim_start().starts_with('#'))
+        .collect();
+    let version = migrations::detect_version(&lines).map_err(invalid_data)?;
+    parse_header(&migrations::migrate(lines, version).map_err(invalid_data)?).map_err(invalid_data)
 }
 
-/// Load macro from DSL format
+/// Load macro from DSL format (or the binary format, detected by its magic)
 pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedEvent>> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut states = Vec::new();
+    let path = path.as_ref();
+    if binary::is_binary(path) {
+        return binary::MappedMacro::open(path)?.iter().collect();
+    }
+
+    // Convert states back to events, with the LED and switch changes where
+    // they were recorded
+    let macro_ = load_macro(path)?;
+    let mut events = macro_.events();
+    events.extend(macro_.timed_actions().into_iter().filter_map(|(timestamp_us, action)| {
+        let (kind, code, on) = match action {
+            Action::Led { code, on } => (EventType::LED, code, on),
+            Action::Switch { code, on } => (EventType::SWITCH, code, on),
+            _ => return None,
+        };
+        Some(RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(kind.0, code, i32::from(on)),
+        })
+    }));
+    events.sort_by_key(|recorded| recorded.timestamp_us);
+    Ok(events)
+}
+
+/// Load a macro with its metadata and markers, migrating older format versions
+///
+/// Tracks are merged into one macro. Binary macros are converted to states and
+/// carry no metadata or markers.
+pub fn load_macro<P: AsRef<Path>>(path: P) -> io::Result<Macro> {
+    Ok(load_session(path)?.merged())
+}
 
-    for (line_num, line) in reader.lines().enumerate() {
-        let line = line?;
+/// Load a macro file keeping its tracks apart
+pub fn load_session<P: AsRef<Path>>(path: P) -> io::Result<Session> {
+    load_session_with_params(path, &[])
+}
+
+/// Load a macro file with some of its parameters set, as `(name, value)`
+pub fn load_session_with_params<P: AsRef<Path>>(path: P, params: &[(String, String)]) -> io::Result<Session> {
+    let path = path.as_ref();
+    if binary::is_binary(path) {
+        let events: Vec<RecordedEvent> = binary::MappedMacro::open(path)?.iter().collect::<io::Result<_>>()?;
+        return Ok(Session::single(Macro::from_recording(&events, &[], Metadata::default())));
+    }
+
+    parse_session_with_params(&std::fs::read_to_string(path)?, params).map_err(invalid_data)
+}
+
+/// Parse DSL text, migrating older format versions and merging tracks
+pub fn parse_macro(text: &str) -> Result<Macro, String> {
+    Ok(parse_session(text)?.merged())
+}
+
+/// Parse DSL text into its tracks, migrating older format versions
+pub fn parse_session(text: &str) -> Result<Session, String> {
+    parse_session_with_params(text, &[])
+}
+
+/// Parse DSL text with some of its parameters set, as `(name, value)`
+pub fn parse_session_with_params(text: &str, params: &[(String, String)]) -> Result<Session, String> {
+    let lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let version = migrations::detect_version(&lines)?;
+    let mut lines = migrations::migrate(lines, version)?;
+    apply_params(&mut lines, params)?;
+    let mut session = Session {
+        metadata: parse_header(&lines)?,
+        tracks: Vec::new(),
+    };
+    // Anything before the first `track` line goes in an unnamed track
+    let mut track = Track::default();
+    let mut total_ms = 0u64;
+    // The `repeat` block being read: its line, count, and how many states,
+    // actions and click positions the track had before it
+    let mut block: Option<(usize, usize, usize, usize, usize)> = None;
+
+    for (line_num, line) in lines.iter().enumerate() {
         let line = line.trim();
 
         // Skip empty lines and comments
@@ -49,28 +1190,305 @@ pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedEvent>> {
             continue;
         }
 
-        match parse_line(line) {
-            Ok(state) => states.push(state),
-            Err(e) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Line {}: {}", line_num + 1, e),
+        if let Some(name) = line.strip_prefix("track ") {
+            if block.is_some() {
+                return Err(format!("Line {}: Tracks can't start inside a repeat block", line_num + 1));
+            }
+            let name = name.trim();
+            if session.tracks.iter().chain([&track]).any(|t| t.name == name) {
+                return Err(format!("Line {}: Duplicate track '{}'", line_num + 1, name));
+            }
+            if !track.name.is_empty() || !track.macro_.is_empty() {
+                session.tracks.push(track);
+            }
+            track = Track {
+                name: name.to_string(),
+                macro_: Macro::default(),
+            };
+            total_ms = 0;
+            continue;
+        }
+
+        let macro_ = &mut track.macro_;
+
+        if let Some(times) = line.strip_prefix("repeat ") {
+            if block.is_some() {
+                return Err(format!("Line {}: Repeat blocks can't be nested", line_num + 1));
+            }
+            let times = times
+                .trim()
+                .parse()
+                .ok()
+                .filter(|&times| times >= 2)
+                .ok_or_else(|| format!("Line {}: Invalid repeat count '{}' (at least 2)", line_num + 1, times.trim()))?;
+            block = Some((line_num, times, macro_.states.len(), macro_.actions.len(), macro_.clicks.len()));
+            continue;
+        }
+
+        if line == "end" {
+            let Some((_, times, from, actions_from, clicks_from)) = block.take() else {
+                return Err(format!("Line {}: 'end' without a 'repeat'", line_num + 1));
+            };
+            let len = macro_.states.len() - from;
+            if len == 0 {
+                return Err(format!("Line {}: Empty repeat block", line_num + 1));
+            }
+            let last = macro_.states.len();
+            if macro_.actions[actions_from..].iter().any(|step| step.index == last)
+                || macro_.clicks[clicks_from..].iter().any(|click| click.index == last)
+            {
+                return Err(format!(
+                    "Line {}: A repeat block can't end with an action; start it with the action instead",
+                    line_num + 1
                 ));
             }
+            let body_ms: u64 = macro_.states[from..].iter().map(|state| state.duration_ms).sum();
+            total_ms = body_ms
+                .checked_mul(times as u64 - 1)
+                .and_then(|ms| ms.checked_add(total_ms))
+                .filter(|&ms| ms <= MAX_DURATION_MS)
+                .ok_or_else(|| format!("Line {}: Macro is longer than a year", line_num + 1))?;
+            if len.saturating_mul(times).saturating_add(from) > MAX_REPEATED_STATES {
+                return Err(format!("Line {}: Repeat block expands the macro past {} states", line_num + 1, MAX_REPEATED_STATES));
+            }
+
+            let body = macro_.states[from..].to_vec();
+            let actions = macro_.actions[actions_from..].to_vec();
+            let clicks = macro_.clicks[clicks_from..].to_vec();
+            for copy in 1..times {
+                let shift = copy * len;
+                macro_.states.extend_from_slice(&body);
+                macro_.actions.extend(actions.iter().map(|step| ActionStep {
+                    index: step.index + shift,
+                    action: step.action.clone(),
+                }));
+                macro_.clicks.extend(clicks.iter().map(|click| ClickPosition {
+                    index: click.index + shift,
+                    ..*click
+                }));
+            }
+            macro_.repeats.push(Repeat { index: from, len, times });
+            continue;
+        }
+
+        // Markers sit between states rather than being states themselves
+        if let Some(name) = line.strip_prefix("mark ") {
+            if block.is_some() {
+                return Err(format!("Line {}: Markers can't go inside a repeat block", line_num + 1));
+            }
+            macro_.markers.push(Marker {
+                index: macro_.states.len(),
+                name: name.trim().to_string(),
+            });
+            continue;
+        }
+
+        if let Some(position) = line.strip_prefix("at ") {
+            let (x, y) = parse_position(position).map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
+            macro_.clicks.push(ClickPosition {
+                index: macro_.states.len(),
+                x,
+                y,
+            });
+            continue;
+        }
+
+        if let Some(action) = Action::parse(line) {
+            let action = action.map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
+            macro_.actions.push(ActionStep {
+                index: macro_.states.len(),
+                action,
+            });
+            continue;
+        }
+
+        let state = parse_line(line).map_err(|e| format!("Line {}: {}", line_num + 1, e))?;
+        total_ms += state.duration_ms;
+        if total_ms > MAX_DURATION_MS {
+            return Err(format!("Line {}: Macro is longer than a year", line_num + 1));
+        }
+        macro_.states.push(state);
+    }
+
+    if let Some((line_num, ..)) = block {
+        return Err(format!("Line {}: 'repeat' without an 'end'", line_num + 1));
+    }
+
+    session.tracks.push(track);
+    Ok(session)
+}
+
+/// Rewrite a DSL macro file in the current format version
+///
+/// Returns the version the file was upgraded from, or `None` if it was
+/// already current.
+pub fn upgrade<P: AsRef<Path>>(path: P) -> io::Result<Option<u32>> {
+    let path = path.as_ref();
+    if binary::is_binary(path) {
+        // There is only one binary version so far; just check the file
+        binary::MappedMacro::open(path)?.iter().try_for_each(|recorded| recorded.map(drop))?;
+        return Ok(None);
+    }
+
+    let lines = read_lines(path)?;
+    let version = migrations::detect_version(&lines).map_err(invalid_data)?;
+    if version == migrations::CURRENT_VERSION {
+        return Ok(None);
+    }
+
+    // Saving a parsed macro would fill its parameters in for good, so macros
+    // with parameters are upgraded line by line
+    if lines.iter().any(|line| param_declaration(line).is_some()) {
+        let mut lines = migrations::migrate(lines, version).map_err(invalid_data)?;
+        let current = format!("# Version: {}", migrations::CURRENT_VERSION);
+        match lines.iter().position(|line| migrations::parse_version_line(line).is_some()) {
+            Some(index) => lines[index] = current,
+            None => lines.insert(0, current),
+        }
+        File::create(path)?.write_all((lines.join("\n") + "\n").as_bytes())?;
+        return Ok(Some(version));
+    }
+
+    save_session(path, &load_session(path)?)?;
+    Ok(Some(version))
+}
+
+/// Name and default value of a `param NAME VALUE` line
+fn param_declaration(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.trim().strip_prefix("param ")?.trim().split_once(char::is_whitespace)?;
+    Some((name, value.trim()))
+}
+
+fn is_param_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replace `$name` references with parameter values and blank out the
+/// `param` lines (keeping line numbers for error messages)
+///
+/// `overrides` take precedence over the defaults in the file. A `$` not
+/// followed by a declared parameter's name is left alone.
+fn apply_params(lines: &mut [String], overrides: &[(String, String)]) -> Result<(), String> {
+    let mut params: Vec<(String, String)> = Vec::new();
+    for (line_num, line) in lines.iter_mut().enumerate() {
+        if !line.trim_start().starts_with("param ") {
+            continue;
+        }
+        let (name, value) = param_declaration(line)
+            .ok_or_else(|| format!("Line {}: Expected 'param <name> <default value>'", line_num + 1))?;
+        if !is_param_name(name) {
+            return Err(format!("Line {}: Invalid parameter name '{}'", line_num + 1, name));
+        }
+        if params.iter().any(|(declared, _)| declared == name) {
+            return Err(format!("Line {}: Duplicate parameter '{}'", line_num + 1, name));
+        }
+        params.push((name.to_string(), value.to_string()));
+        line.clear();
+    }
+
+    for (name, value) in overrides {
+        match params.iter_mut().find(|(declared, _)| declared == name) {
+            Some(param) => param.1 = value.clone(),
+            None => return Err(format!("Macro has no parameter '{}'", name)),
+        }
+    }
+    if params.is_empty() {
+        return Ok(());
+    }
+
+    for line in lines.iter_mut().filter(|line| line.contains('$') && !line.trim_start().starts_with('#')) {
+        let mut replaced = String::with_capacity(line.len());
+        let mut rest = line.as_str();
+        while let Some(at) = rest.find('$') {
+            replaced.push_str(&rest[..at]);
+            let after = &rest[at + 1..];
+            let name_len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            match params.iter().find(|(name, _)| name == &after[..name_len]) {
+                Some((_, value)) => {
+                    replaced.push_str(value);
+                    rest = &after[name_len..];
+                }
+                None => {
+                    replaced.push('$');
+                    rest = after;
+                }
+            }
+        }
+        replaced.push_str(rest);
+        *line = replaced;
+    }
+    Ok(())
+}
+
+/// Parse `# Key: value` lines from the leading comment block
+fn parse_header(lines: &[String]) -> Result<Metadata, String> {
+    let mut metadata = Metadata::default();
+    let mut wait = None;
+
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(comment) = line.strip_prefix('#') else {
+            break;
+        };
+        let Some((key, value)) = comment.split_once(':') else {
+            continue;
+        };
+
+        match key.trim() {
+            "Locks" => metadata.locks = Some(LockState::parse(value)?),
+            "Screen" => metadata.screen = Some(ScreenSize::parse(value)?),
+            "Polling" => {
+                let hz = value.trim().strip_suffix("Hz").and_then(|hz| hz.parse().ok()).filter(|&hz| hz > 0);
+                metadata.polling_hz = Some(hz.ok_or_else(|| format!("Invalid polling rate '{}'", value.trim()))?);
+            }
+            "Scroll" => metadata.scroll = Some(value.trim().parse()?),
+            "Keyboard" => metadata.keyboard = Some(XkbLayout::parse(value)?),
+            "Easing" => metadata.easing = Some(value.trim().parse()?),
+            "Recorded with" => {
+                let version = value.trim();
+                metadata.environment.evkey = Some(version.strip_prefix("EvKey ").unwrap_or(version).to_string());
+            }
+            "Kernel" => metadata.environment.kernel = Some(value.trim().to_string()),
+            "Device" => metadata.environment.devices.push(RecordedDevice::parse(value)?),
+            "Play" => metadata.play = value.split_whitespace().map(String::from).collect(),
+            "Window" => metadata.window = Some(WindowGuard::parse(value.trim())?),
+            "Unfocused" => {
+                wait = Some(match value.trim() {
+                    "refuse" => false,
+                    "wait" => true,
+                    other => return Err(format!("Invalid Unfocused '{}', use refuse/wait", other)),
+                });
+            }
+            _ => {}
         }
     }
 
-    // Convert states back to events
-    Ok(states_to_events(&states))
+    match (&mut metadata.window, wait) {
+        (Some(window), Some(wait)) => window.wait = wait,
+        (None, Some(_)) => return Err("An Unfocused header needs a Window header to go with it".to_string()),
+        _ => {}
+    }
+    Ok(metadata)
+}
+
+fn read_lines<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    BufReader::new(File::open(path)?).lines().collect()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
 }
 
 /// Format a MacroState as a DSL line
 fn format_state(state: &MacroState) -> String {
     // Handle empty state (just waiting)
-    if state.keys_pressed.is_empty()
-        && state.mouse_delta == (0, 0)
-        && state.scroll_delta == (0, 0)
-    {
+    if state.is_empty() {
         if state.duration_ms > 0 {
             return format!("wait {}ms", state.duration_ms);
         } else {
@@ -82,26 +1500,29 @@ fn format_state(state: &MacroState) -> String {
 
     // Format keys
     if !state.keys_pressed.is_empty() {
-        let mut keys: Vec<String> = state
-            .keys_pressed
-            .iter()
-            .filter_map(|&code| keymap::keycode_to_name(code))
-            .collect();
-        keys.sort(); // Consistent ordering
+        // Shortcut ordering (modifiers first) for consistent, readable output;
+        // keys without a name are written by number
+        let keys = keymap::format_combo(state.keys_pressed.codes());
 
         if state.duration_ms > 0 {
-            parts.push(format!("hold {} for {}ms", keys.join("+"), state.duration_ms));
+            parts.push(format!("hold {} for {}ms", keys, state.duration_ms));
         } else {
-            parts.push(format!("tap {}", keys.join("+")));
+            parts.push(format!("tap {}", keys));
         }
     }
 
-    // Format mouse movement
+    // Format mouse movement; eased movement glides over the state, so its
+    // time is part of the `move`
+    let glides = state.easing.is_some() && state.mouse_delta != (0, 0) && state.keys_pressed.is_empty();
     if state.mouse_delta != (0, 0) {
-        parts.push(format!(
-            "move {} {}",
-            state.mouse_delta.0, state.mouse_delta.1
-        ));
+        let mut motion = format!("move {} {}", state.mouse_delta.0, state.mouse_delta.1);
+        if glides && state.duration_ms > 0 {
+            motion.push_str(&format!(" over {}ms", state.duration_ms));
+        }
+        if let Some(easing) = state.easing {
+            motion.push_str(&format!(" {}", easing));
+        }
+        parts.push(motion);
     }
 
     // Format scroll
@@ -112,7 +1533,7 @@ fn format_state(state: &MacroState) -> String {
             } else {
                 "down"
             };
-            parts.push(format!("scroll {} {}", direction, state.scroll_delta.0.abs()));
+            parts.push(format!("scroll {} {}", direction, state.scroll_delta.0.unsigned_abs()));
         }
         if state.scroll_delta.1 != 0 {
             let direction = if state.scroll_delta.1 > 0 {
@@ -123,7 +1544,7 @@ fn format_state(state: &MacroState) -> String {
             parts.push(format!(
                 "scroll {} {}",
                 direction,
-                state.scroll_delta.1.abs()
+                state.scroll_delta.1.unsigned_abs()
             ));
         }
     }
@@ -137,7 +1558,7 @@ fn format_state(state: &MacroState) -> String {
         } else {
             "# empty state".to_string()
         }
-    } else if state.duration_ms > 0 && state.keys_pressed.is_empty() {
+    } else if state.duration_ms > 0 && state.keys_pressed.is_empty() && !glides {
         // Has actions (mouse/scroll) with duration
         format!("{}\nwait {}ms", result, state.duration_ms)
     } else {
@@ -145,6 +1566,17 @@ fn format_state(state: &MacroState) -> String {
     }
 }
 
+/// Parse the "X Y" of an `at` line
+fn parse_position(position: &str) -> Result<(i32, i32), String> {
+    let parts: Vec<&str> = position.split_whitespace().collect();
+    let [x, y] = parts[..] else {
+        return Err(format!("Invalid 'at' syntax: at {}", position));
+    };
+    let x = x.parse().map_err(|_| format!("Invalid X coordinate: {}", x))?;
+    let y = y.parse().map_err(|_| format!("Invalid Y coordinate: {}", y))?;
+    Ok((x, y))
+}
+
 /// Parse a DSL line into a MacroState
 fn parse_line(line: &str) -> Result<MacroState, String> {
     let line = line.trim();
@@ -170,6 +1602,7 @@ fn parse_line(line: &str) -> Result<MacroState, String> {
             keys_pressed: keys,
             mouse_delta: (0, 0),
             scroll_delta: (0, 0),
+            easing: None,
         });
     }
 
@@ -178,18 +1611,23 @@ fn parse_line(line: &str) -> Result<MacroState, String> {
         let duration_ms = parse_duration(rest)?;
         return Ok(MacroState {
             duration_ms,
-            keys_pressed: HashSet::new(),
+            keys_pressed: KeySet::new(),
             mouse_delta: (0, 0),
             scroll_delta: (0, 0),
+            easing: None,
         });
     }
 
-    // Parse "move X Y"
+    // Parse "move X Y [over DURATION] [EASING]"
     if let Some(rest) = line.strip_prefix("move ") {
         let parts: Vec<&str> = rest.split_whitespace().collect();
-        if parts.len() != 2 {
-            return Err(format!("Invalid 'move' syntax: {}", line));
-        }
+        let (parts, duration_ms, easing) = match parts[..] {
+            [x, y] => ([x, y], 0, None),
+            [x, y, "over", duration] => ([x, y], parse_duration(duration)?, None),
+            [x, y, "over", duration, easing] => ([x, y], parse_duration(duration)?, Some(easing.parse()?)),
+            [x, y, easing] => ([x, y], 0, Some(easing.parse()?)),
+            _ => return Err(format!("Invalid 'move' syntax: {}", line)),
+        };
 
         let x: i32 = parts[0]
             .parse()
@@ -199,10 +1637,11 @@ fn parse_line(line: &str) -> Result<MacroState, String> {
             .map_err(|_| format!("Invalid Y coordinate: {}", parts[1]))?;
 
         return Ok(MacroState {
-            duration_ms: 0,
-            keys_pressed: HashSet::new(),
+            duration_ms,
+            keys_pressed: KeySet::new(),
             mouse_delta: (x, y),
             scroll_delta: (0, 0),
+            easing,
         });
     }
 
@@ -216,7 +1655,9 @@ fn parse_line(line: &str) -> Result<MacroState, String> {
         let direction = parts[0];
         let amount: i32 = parts[1]
             .parse()
-            .map_err(|_| format!("Invalid scroll amount: {}", parts[1]))?;
+            .ok()
+            .filter(|amount: &i32| *amount != i32::MIN)
+            .ok_or_else(|| format!("Invalid scroll amount: {}", parts[1]))?;
 
         let scroll_delta = match direction {
             "up" => (amount, 0),
@@ -233,9 +1674,10 @@ fn parse_line(line: &str) -> Result<MacroState, String> {
 
         return Ok(MacroState {
             duration_ms: 0,
-            keys_pressed: HashSet::new(),
+            keys_pressed: KeySet::new(),
             mouse_delta: (0, 0),
             scroll_delta,
+            easing: None,
         });
     }
 
@@ -247,6 +1689,7 @@ fn parse_line(line: &str) -> Result<MacroState, String> {
             keys_pressed: keys,
             mouse_delta: (0, 0),
             scroll_delta: (0, 0),
+            easing: None,
         });
     }
 
@@ -254,25 +1697,26 @@ fn parse_line(line: &str) -> Result<MacroState, String> {
 }
 
 /// Parse duration string like "100ms" or "2s"
-fn parse_duration(s: &str) -> Result<u64, String> {
-    if let Some(ms_str) = s.strip_suffix("ms") {
-        ms_str
-            .parse::<u64>()
-            .map_err(|_| format!("Invalid duration: {}", s))
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let duration_ms = if let Some(ms_str) = s.strip_suffix("ms") {
+        ms_str.parse::<u64>().ok()
     } else if let Some(s_str) = s.strip_suffix('s') {
-        s_str
-            .parse::<u64>()
-            .map(|s| s * 1000)
-            .map_err(|_| format!("Invalid duration: {}", s))
+        s_str.parse::<u64>().ok().and_then(|s| s.checked_mul(1000))
     } else {
-        Err(format!("Duration must end with 'ms' or 's': {}", s))
+        return Err(format!("Duration must end with 'ms' or 's': {}", s));
+    };
+
+    match duration_ms {
+        Some(ms) if ms <= MAX_DURATION_MS => Ok(ms),
+        Some(_) => Err(format!("Duration too long (over a year): {}", s)),
+        None => Err(format!("Invalid duration: {}", s)),
     }
 }
 
 /// Parse key names like "W" or "W+A+SHIFT"
-fn parse_keys(s: &str) -> Result<HashSet<u16>, String> {
+fn parse_keys(s: &str) -> Result<KeySet, String> {
     let key_names: Vec<&str> = s.split('+').collect();
-    let mut keycodes = HashSet::new();
+    let mut keycodes = KeySet::new();
 
     for name in key_names {
         let name = name.trim();
@@ -294,15 +1738,15 @@ mod tests {
     fn test_parse_hold() {
         let state = parse_line("hold W for 100ms").unwrap();
         assert_eq!(state.duration_ms, 100);
-        assert!(state.keys_pressed.contains(&17)); // W = 17
+        assert!(state.keys_pressed.contains(17)); // W = 17
     }
 
     #[test]
     fn test_parse_hold_multiple() {
         let state = parse_line("hold W+A for 50ms").unwrap();
         assert_eq!(state.duration_ms, 50);
-        assert!(state.keys_pressed.contains(&17)); // W
-        assert!(state.keys_pressed.contains(&30)); // A
+        assert!(state.keys_pressed.contains(17)); // W
+        assert!(state.keys_pressed.contains(30)); // A
     }
 
     #[test]
@@ -316,6 +1760,12 @@ mod tests {
     fn test_parse_move() {
         let state = parse_line("move 10 -5").unwrap();
         assert_eq!(state.mouse_delta, (10, -5));
+
+        let state = parse_line("move 400 -120 over 300ms ease-in-out").unwrap();
+        assert_eq!((state.mouse_delta, state.duration_ms), ((400, -120), 300));
+        assert_eq!(state.easing, Some(Easing::EaseInOut));
+        assert_eq!(format_state(&state), "move 400 -120 over 300ms ease-in-out");
+        assert!(parse_line("move 1 2 wobble").unwrap_err().contains("Unknown easing"));
     }
 
     #[test]
@@ -340,18 +1790,350 @@ mod tests {
         assert!(parse_duration("100").is_err());
     }
 
+    #[test]
+    fn test_rejects_out_of_range_values() {
+        assert!(parse_duration("18446744073709551615s").is_err());
+        assert!(parse_duration(&format!("{}ms", MAX_DURATION_MS + 1)).is_err());
+        assert!(parse_line("scroll up -2147483648").is_err());
+        assert!(parse_macro(&"wait 17280000s\n".repeat(2)).unwrap_err().starts_with("Line 2:"));
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let lines: Vec<String> = ["# EvKey Macro", "# Version: 1", "# Locks: NUMLOCK", "# Polling: 500Hz", "", "wait 5ms"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        let metadata = parse_header(&lines).unwrap();
+        let locks = metadata.locks.unwrap();
+        assert!(locks.num);
+        assert!(!locks.caps);
+        assert_eq!(metadata.polling_hz, Some(500));
+        assert!(parse_header(&["# Polling: fast".to_string()]).is_err());
+
+        let keyboard = parse_header(&["# Keyboard: layout=us,de variant=,nodeadkeys".to_string()]).unwrap().keyboard;
+        assert_eq!(keyboard.as_ref().map(|k| k.variant.as_str()), Some(",nodeadkeys"));
+        assert!(format_header(&Metadata { keyboard, ..Metadata::default() }, Capabilities::default()).contains("# Keyboard: layout=us,de variant=,nodeadkeys\n"));
+
+        let lines = ["# Unfocused: wait".to_string(), "# Window: ^Minecraft .*".to_string()];
+        let window = parse_header(&lines).unwrap().window;
+        assert!(window.as_ref().is_some_and(|window| window.wait));
+        let header = format_header(&Metadata { window, ..Metadata::default() }, Capabilities::default());
+        assert!(header.contains("# Window: ^Minecraft .*\n# Unfocused: wait\n"));
+        assert!(parse_header(&["# Unfocused: wait".to_string()]).unwrap_err().contains("needs a Window header"));
+
+        let scroll = parse_header(&["# Scroll: 120Hz hires".to_string()]).unwrap().scroll;
+        assert_eq!(scroll, Some(ScrollPacing { rate_hz: 120, hires: true }));
+        assert!(format_header(&Metadata { scroll, ..Metadata::default() }, Capabilities::default()).contains("# Scroll: 120Hz hires\n"));
+
+        let lines = ["# Recorded with: EvKey 0.1.0", "# Kernel: 6.8.0-45-generic", "# Device: Logitech USB Receiver (046d:c52b)"];
+        let environment = parse_header(&lines.map(String::from)).unwrap().environment;
+        assert_eq!(environment.evkey.as_deref(), Some("0.1.0"));
+        assert_eq!(environment.devices[0], RecordedDevice { name: "Logitech USB Receiver".into(), vendor: 0x046d, product: 0xc52b });
+        let header = format_header(&Metadata { environment, ..Metadata::default() }, Capabilities::default());
+        assert!(header.ends_with(&format!("{}\n\n", lines.join("\n"))));
+        assert!(parse_header(&["# Device: Logitech".to_string()]).unwrap_err().contains("Invalid device"));
+
+        let play = parse_header(&["# Play: --min-hold 20ms  --loop".to_string()]).unwrap().play;
+        assert_eq!(play, ["--min-hold", "20ms", "--loop"]);
+        assert!(format_header(&Metadata { play, ..Metadata::default() }, Capabilities::default()).contains("# Play: --min-hold 20ms --loop\n"));
+    }
+
+    #[test]
+    fn test_params() {
+        let text = "param interval 50ms\nparam text \"Total: $5\"\ntap BTN_LEFT\nwait $interval\npaste $text\n";
+        let macro_ = parse_macro(text).unwrap();
+        assert_eq!(macro_.states[1].duration_ms, 50);
+        assert_eq!(macro_.actions[0].action, Action::Paste(Some("Total: $5".to_string())));
+
+        let overrides = [("interval".to_string(), "2s".to_string())];
+        let session = parse_session_with_params(text, &overrides).unwrap();
+        assert_eq!(session.merged().states[1].duration_ms, 2000);
+
+        let unknown = [("speed".to_string(), "1".to_string())];
+        assert_eq!(parse_session_with_params(text, &unknown).unwrap_err(), "Macro has no parameter 'speed'");
+        assert!(parse_macro("param interval\n").unwrap_err().starts_with("Line 1:"));
+        assert!(parse_macro("param a 1\nparam a 2\n").unwrap_err().contains("Duplicate parameter"));
+        assert!(parse_macro("wait $missing\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_and_format_macro() {
+        let macro_ = parse_macro("# Version: 2\n\nmark start\nhold A for 10ms\nwait 20ms\n").unwrap();
+        assert_eq!(macro_.states.len(), 2);
+        assert_eq!(macro_.idle_ends(), (0, 1));
+        assert_eq!(parse_macro("wait 5ms\nwait 5ms\n").unwrap().idle_ends(), (2, 0));
+        assert_eq!(macro_.markers[0], Marker { index: 0, name: "start".to_string() });
+
+        let text = format_macro(&macro_);
+        assert!(text.contains(&format!("# Version: {}", migrations::CURRENT_VERSION)));
+        assert!(text.contains("# Needs: keyboard\n"));
+        assert_eq!(parse_macro(&text).unwrap().states.len(), 2);
+        assert!(parse_macro("hold A for\n").unwrap_err().starts_with("Line 1:"));
+    }
+
+    #[test]
+    fn test_scale_to_screen() {
+        let mut macro_ = parse_macro("# Screen: 1280x720\n\nmove 3 0\nmove 3 0\nmove to 640 360\n").unwrap();
+        assert_eq!(macro_.metadata.screen, Some(ScreenSize { width: 1280, height: 720 }));
+        assert!(format_macro(&macro_).contains("# Screen: 1280x720\n"));
+
+        let uhd = ScreenSize { width: 3840, height: 2160 };
+        assert!(macro_.scale_to_screen(uhd));
+        let deltas: Vec<_> = macro_.states.iter().map(|s| s.mouse_delta).collect();
+        assert_eq!(deltas, vec![(9, 0), (9, 0)]);
+        assert_eq!(macro_.actions[0].action, Action::MoveTo { x: 1920, y: 1080 });
+        assert!(!macro_.scale_to_screen(uhd));
+    }
+
+    #[test]
+    fn test_anchor_clicks() {
+        let text = "move 5 5\nwait 20ms\nmark go\nmove 3 0\nat 640 360\nhold BTN_LEFT for 50ms\nmove 10 0\nat 700 360\ntap BTN_LEFT\n";
+        let mut macro_ = parse_macro(text).unwrap();
+        assert_eq!(
+            macro_.clicks,
+            vec![ClickPosition { index: 3, x: 640, y: 360 }, ClickPosition { index: 5, x: 700, y: 360 }]
+        );
+        assert_eq!(parse_macro(&format_macro(&macro_)).unwrap().clicks, macro_.clicks);
+
+        // Dragging with the button held
+        macro_.states[3].mouse_delta = (2, 0);
+        assert_eq!(macro_.anchor_clicks(), 2);
+        assert!(macro_.clicks.is_empty());
+        let states: Vec<String> = macro_.states.iter().map(|s| s.to_string()).collect();
+        assert_eq!(states, vec!["wait 20ms", "BTN_LEFT (held 50ms), move 2 0", "BTN_LEFT (tap)"]);
+        assert_eq!(macro_.marker("go"), Some(1));
+        let actions: Vec<_> = macro_.actions.iter().map(|step| (step.index, step.action.clone())).collect();
+        assert_eq!(
+            actions,
+            vec![(1, Action::MoveTo { x: 640, y: 360 }), (2, Action::MoveTo { x: 700, y: 360 })]
+        );
+    }
+
+    #[test]
+    fn test_clip_insertion() {
+        let source = parse_macro("hold A for 10ms\nmark start\nhold B for 20ms\nhold B+C for 30ms\nmark end\nwait 40ms\n").unwrap();
+        let clip = source.clip(source.marker("start").unwrap(), source.marker("end").unwrap());
+        let states: Vec<String> = clip.states.iter().map(|s| s.to_string()).collect();
+        assert_eq!(states, ["B (held 20ms)", "B+C (held 30ms)", "wait 1ms"]);
+        assert_eq!(clip.marker("start"), Some(0));
+
+        // W is held across the insertion point and stays held; C is let go
+        // for the clip and pressed again after it
+        let mut target = parse_macro("hold W for 50ms\nhold W+C for 50ms\nmark here\ntap W+C\n").unwrap();
+        target.insert_clip(target.marker("here").unwrap(), &clip);
+        let states: Vec<String> = target.states.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            states,
+            ["W (held 50ms)", "C+W (held 50ms)", "B+W (held 20ms)", "B+C+W (held 30ms)", "W (held 1ms)", "C+W (tap)"]
+        );
+        let markers: Vec<(&str, usize)> = target.markers.iter().map(|m| (m.name.as_str(), m.index)).collect();
+        assert_eq!(markers, [("here", 2), ("start", 2), ("end", 5)]);
+    }
+
+    #[test]
+    fn test_replace_typing() {
+        let text = "# Keyboard: layout=de\n\nmove 5 5\nhold SHIFT for 10ms\nhold SHIFT+Z for 40ms\nwait 30ms\nmark name\n\
+                    hold Y for 40ms\nwait 500ms\ntap CTRL+S\nwait 10ms\nhold A for 20ms\n";
+        let mut macro_ = parse_macro(text).unwrap();
+        assert_eq!(macro_.typed_text().unwrap(), "Yz\na\n");
+
+        assert_eq!(macro_.replace_typing(2), Ok(1));
+        let states: Vec<String> = macro_.states.iter().map(|s| s.to_string()).collect();
+        assert_eq!(states[..3], ["move 5 5", "wait 500ms", "CTRL+S (tap)"]);
+        assert_eq!(macro_.actions, vec![ActionStep { index: 1, action: Action::Type("Yz".to_string()) }]);
+        assert_eq!(macro_.marker("name"), Some(1));
+
+        macro_.metadata.keyboard = Some(XkbLayout::parse("layout=neo").unwrap());
+        assert!(macro_.typed_text().is_err());
+    }
+
+    #[test]
+    fn test_session_tracks() {
+        let text = "# Version: 4\n\ntrack keyboard\nmark start\nhold A for 100ms\ntrack mouse\nwait 50ms\nmove 40 0\n";
+        let session = parse_session(text).unwrap();
+        assert_eq!(session.track_names(), vec!["keyboard", "mouse"]);
+        assert_eq!(session.tracks[1].macro_.states.len(), 2);
+
+        // Round trip keeps the tracks apart
+        let session = parse_session(&format_session(&session)).unwrap();
+        assert_eq!(session.track_names(), vec!["keyboard", "mouse"]);
+
+        let keyboard = session.select(&["keyboard"], &[]).unwrap();
+        assert_eq!(keyboard.states.len(), 1);
+        assert_eq!(keyboard.markers[0].name, "start");
+        let without_keyboard = session.select(&[], &["keyboard"]).unwrap();
+        assert!(without_keyboard.states.iter().all(|s| s.keys_pressed.is_empty()));
+
+        // Merged, the mouse move lands while A is still held
+        let merged = session.merged();
+        assert!(merged.states.iter().any(|s| s.mouse_delta == (40, 0) && !s.keys_pressed.is_empty()));
+
+        assert_eq!(
+            session.select(&["pen"], &[]).unwrap_err(),
+            "No track named 'pen' (tracks: keyboard, mouse)"
+        );
+        assert!(parse_session("track a\ntrack a\n").unwrap_err().contains("Duplicate track"));
+    }
+
+    #[test]
+    fn test_markers_anchor_to_nearest_state() {
+        let states = vec![MacroState::new(100), MacroState::new(100)];
+        assert_eq!(state_index_at(&states, 0), 0);
+        assert_eq!(state_index_at(&states, 40), 0);
+        assert_eq!(state_index_at(&states, 60), 1);
+        assert_eq!(state_index_at(&states, 500), 2);
+    }
+
+    #[test]
+    fn test_slice_between_markers() {
+        let macro_ = Macro {
+            states: (1..=4).map(|ms| MacroState::new(ms * 10)).collect(),
+            markers: vec![
+                Marker { index: 1, name: "start".to_string() },
+                Marker { index: 3, name: "end".to_string() },
+            ],
+            ..Default::default()
+        };
+
+        let from = macro_.marker("start").unwrap();
+        let to = macro_.marker("end").unwrap();
+        let sliced = macro_.slice(from, to);
+
+        assert_eq!(sliced.states.len(), 2);
+        assert_eq!(sliced.states[0].duration_ms, 20);
+        assert_eq!(sliced.markers[0], Marker { index: 0, name: "start".to_string() });
+        assert_eq!(sliced.markers[1].index, 2);
+    }
+
+    #[test]
+    fn test_timed_actions() {
+        let action = Action::parse("wait pixel 1 2 #000000").unwrap().unwrap();
+        let macro_ = Macro {
+            states: vec![MacroState::new(100), MacroState::new(50)],
+            actions: vec![ActionStep { index: 2, action: action.clone() }],
+            ..Default::default()
+        };
+
+        assert_eq!(macro_.timed_actions(), vec![(150_000, action)]);
+    }
+
     #[test]
     fn test_format_scroll_with_duration() {
         // State with scroll and duration should output scroll + wait
         let state = MacroState {
             duration_ms: 500,
-            keys_pressed: HashSet::new(),
+            keys_pressed: KeySet::new(),
             mouse_delta: (0, 0),
             scroll_delta: (-1, 0), // scroll down
+            easing: None,
         };
 
         let formatted = format_state(&state);
         assert!(formatted.contains("scroll down 1"));
         assert!(formatted.contains("wait 500ms"));
     }
+
+    #[test]
+    fn test_unnamed_key_roundtrip() {
+        let mut keys = KeySet::new();
+        keys.insert(42);
+        keys.insert(0x2F0);
+        let state = MacroState {
+            duration_ms: 20,
+            keys_pressed: keys.clone(),
+            mouse_delta: (0, 0),
+            scroll_delta: (0, 0),
+            easing: None,
+        };
+
+        let formatted = format_state(&state);
+        assert_eq!(formatted, "hold SHIFT+KEY_752 for 20ms");
+        assert_eq!(parse_keys("SHIFT+KEY_752").unwrap(), keys);
+        assert_eq!(parse_keys("KEY_0x2F0+42").unwrap(), keys);
+    }
+
+    #[test]
+    fn test_led_and_switch_changes_roundtrip() {
+        let event = |timestamp_us, kind: EventType, code, value| RecordedEvent {
+            timestamp_us,
+            event: InputEvent::new(kind.0, code, value),
+        };
+        // Caps lock tapped with two keyboards reporting the LED, then the lid closed
+        let events = [
+            event(0, EventType::KEY, 58, 1),
+            event(0, EventType::LED, 1, 1),
+            event(0, EventType::LED, 1, 1),
+            event(50_000, EventType::KEY, 58, 0),
+            event(80_000, EventType::SWITCH, 0, 1),
+        ];
+
+        let text = format_macro(&Macro::from_recording(&events, &[], Metadata::default()));
+        assert_eq!(text.matches("led CAPSL on").count(), 1);
+        assert_eq!(text.matches("switch LID on").count(), 1);
+        let parsed = parse_macro(&text).unwrap();
+        let actions: Vec<String> = parsed.actions.iter().map(|step| step.action.to_string()).collect();
+        assert_eq!(actions, ["led CAPSL on", "switch LID on"]);
+
+        // Loading the text gives the changes back as events, for `.evkb`
+        let path = std::env::temp_dir().join(format!("evkey-indicators-{}.macro", std::process::id()));
+        std::fs::write(&path, &text).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let kinds = [EventType::LED, EventType::SWITCH];
+        let indicators: Vec<(u64, u16)> = loaded
+            .iter()
+            .filter(|recorded| kinds.contains(&recorded.event.event_type()))
+            .map(|recorded| (recorded.timestamp_us, recorded.event.code()))
+            .collect();
+        assert_eq!(indicators, [(0, 1), (80_000, 0)]);
+    }
+
+    #[test]
+    fn test_repeat_blocks() {
+        let text = "mark craft\nrepeat 3\nwait pixel 10 10 #ffffff\ntap E\nwait 100ms\nend\nmark done\nwait 5ms\n";
+        let mut macro_ = parse_macro(text).unwrap();
+        assert_eq!(macro_.states.len(), 7);
+        assert_eq!(macro_.actions.iter().map(|step| step.index).collect::<Vec<_>>(), [0, 2, 4]);
+        assert_eq!(macro_.repeats, [Repeat { index: 0, len: 2, times: 3 }]);
+        assert_eq!(macro_.marker("done"), Some(6));
+        let body = format_macro(&macro_).split_once("\n\n").unwrap().1.to_string();
+        assert_eq!(body, text);
+
+        // Once the copies differ they're written out one by one
+        macro_.states[2].duration_ms = 30;
+        let flat = format_macro(&macro_);
+        assert!(!flat.contains("repeat"));
+        assert_eq!(parse_macro(&flat).unwrap().states, macro_.states);
+
+        assert!(parse_macro("repeat 2\nrepeat 2\n").unwrap_err().starts_with("Line 2:"));
+        assert!(parse_macro("repeat 1\ntap A\nend\n").unwrap_err().contains("Invalid repeat count"));
+        assert!(parse_macro("tap A\nend\n").unwrap_err().starts_with("Line 2:"));
+        assert!(parse_macro("repeat 2\nend\n").unwrap_err().contains("Empty"));
+        assert!(parse_macro("repeat 2\ntap A\nmark x\nend\n").unwrap_err().starts_with("Line 3:"));
+        assert!(parse_macro("repeat 2\ntap A\npaste\nend\n").unwrap_err().starts_with("Line 4:"));
+        assert!(parse_macro("repeat 2\ntap A\n").unwrap_err().starts_with("Line 1:"));
+        assert!(parse_macro("repeat 17280000\nwait 2s\nend\n").unwrap_err().contains("longer than a year"));
+        assert!(parse_macro("repeat 1000001\nwait 1ms\nend\n").unwrap_err().contains("past 1000000 states"));
+
+        // A recording of the same stretch done four times, a little
+        // differently each time, between an intro and an outro
+        let mut text = String::from("move 50 50\nwait 300ms\n");
+        for (wait, x) in [(200, 40), (210, 38), (195, 41), (205, 41)] {
+            text.push_str(&format!("tap B\nwait {}ms\nmove {} 0\nhold BTN_LEFT for 30ms\n", wait, x));
+        }
+        text.push_str("tap ESC\n");
+        let mut macro_ = parse_macro(&text).unwrap();
+        let repeats = macro_.find_loops(loops::DEFAULT_TOLERANCE_MS);
+        assert_eq!(repeats, [Repeat { index: 2, len: 4, times: 4 }]);
+        assert!(macro_.find_loops(2).is_empty());
+
+        macro_.fold_loops(&repeats);
+        assert_eq!(macro_.states[3].duration_ms, 203);
+        assert_eq!(macro_.states[4].mouse_delta, (40, 0));
+        let folded = parse_macro(&format_macro(&macro_)).unwrap();
+        assert_eq!(folded.repeats, repeats);
+        assert_eq!(folded.states, macro_.states);
+    }
 }
@@ -0,0 +1,192 @@
+//! Streaming events through a pipe as they happen
+//!
+//! `evkey record -` writes events to stdout while recording and `evkey play -`
+//! plays them from stdin as they arrive, so a recording can be forwarded
+//! live (`evkey record - | ssh host evkey play -`) or run through other
+//! programs on the way.
+//!
+//! Layout (little-endian):
+//!   magic    b"EVKS"
+//!   version  u16
+//!   flags    u16 (reserved, must be 0)
+//!   frames   tag u8, then for [`FRAME_EVENT`] a binary macro record
+//!            [timestamp_us: u64, type: u16, code: u16, value: i32]
+//!
+//! The stream ends with a [`FRAME_END`] frame, so the reading side can tell a
+//! finished recording from a sender that died halfway (and release whatever
+//! keys it left held).
+
+use crate::binary::{RECORD_LEN, decode_record, encode_record, invalid_data};
+use crate::event::{EventType, RecordedEvent};
+use crate::keymap;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"EVKS";
+const VERSION: u16 = 1;
+
+/// Frame carrying one event
+pub const FRAME_EVENT: u8 = 1;
+/// Frame marking the end of the stream
+pub const FRAME_END: u8 = 2;
+
+/// Writes events to a stream, flushing each one so it goes out right away
+pub struct StreamWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Start a stream by writing its header
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.flush()?;
+        Ok(Self { writer })
+    }
+
+    /// Send events and flush
+    pub fn send(&mut self, events: &[RecordedEvent]) -> io::Result<()> {
+        for recorded in events {
+            self.writer.write_all(&[FRAME_EVENT])?;
+            self.writer.write_all(&encode_record(recorded))?;
+        }
+        self.writer.flush()
+    }
+
+    /// The writer the stream goes to
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// End the stream
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.write_all(&[FRAME_END])?;
+        self.writer.flush()
+    }
+}
+
+/// Reads events from a stream as they arrive
+///
+/// Yields an error if the stream is cut off before its end frame, or holds
+/// something that isn't a valid event.
+pub struct StreamReader<R: Read> {
+    reader: R,
+    last_timestamp_us: u64,
+    done: bool,
+}
+
+impl<R: Read> StreamReader<R> {
+    /// Read and check the stream header
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid_data("Not an EvKey event stream"));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(invalid_data(format!("Unsupported stream version {}", version)));
+        }
+
+        Ok(Self {
+            reader,
+            last_timestamp_us: 0,
+            done: false,
+        })
+    }
+
+    fn next_event(&mut self) -> io::Result<Option<RecordedEvent>> {
+        let mut tag = [0u8];
+        match self.reader.read_exact(&mut tag) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(invalid_data("Stream ended without an end frame"));
+            }
+            result => result?,
+        }
+
+        match tag[0] {
+            FRAME_END => Ok(None),
+            FRAME_EVENT => {
+                let mut record = [0u8; RECORD_LEN];
+                self.reader.read_exact(&mut record)?;
+                let recorded = decode_record(&record);
+                if recorded.timestamp_us < self.last_timestamp_us {
+                    return Err(invalid_data("Stream goes back in time"));
+                }
+                if recorded.event.event_type() == EventType::KEY && recorded.event.code() > keymap::KEY_MAX {
+                    return Err(invalid_data(format!("Invalid key code {}", recorded.event.code())));
+                }
+                self.last_timestamp_us = recorded.timestamp_us;
+                Ok(Some(recorded))
+            }
+            other => Err(invalid_data(format!("Unknown frame type {}", other))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for StreamReader<R> {
+    type Item = io::Result<RecordedEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_event();
+        // Nothing after the end frame or an error is worth reading
+        if !matches!(next, Ok(Some(_))) {
+            self.done = true;
+        }
+        next.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::key;
+
+    fn write(batches: &[&[RecordedEvent]], finish: bool) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut writer = StreamWriter::new(&mut buffer).unwrap();
+        for batch in batches {
+            writer.send(batch).unwrap();
+        }
+        if finish {
+            writer.finish().unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let events = vec![key(0, 30, 1), key(50_000, 30, 0), key(80_000, 48, 1)];
+        let buffer = write(&[&events[..1], &events[1..]], true);
+
+        let read: Vec<RecordedEvent> = StreamReader::new(&buffer[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(read.len(), 3);
+        assert!(read.iter().zip(&events).all(|(a, b)| a.timestamp_us == b.timestamp_us && a.event == b.event));
+    }
+
+    #[test]
+    fn test_missing_end_frame_is_an_error() {
+        let buffer = write(&[&[key(0, 30, 1), key(50_000, 30, 0), key(80_000, 48, 1)]], true);
+        let cut = &buffer[..buffer.len() - 1];
+        let results: Vec<io::Result<RecordedEvent>> = StreamReader::new(cut).unwrap().collect();
+        assert_eq!(results.len(), 4);
+        assert!(results[..3].iter().all(Result::is_ok));
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn test_time_going_backwards_is_an_error() {
+        let buffer = write(&[&[key(10, 30, 1), key(5, 30, 0)]], false);
+        let results: Vec<io::Result<RecordedEvent>> = StreamReader::new(&buffer[..]).unwrap().collect();
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn test_binary_macro_is_not_a_stream() {
+        assert!(StreamReader::new(&b"EVKB\x01\x00\x00\x00"[..]).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! SVG visualizations of recordings
+
+use crate::keymap;
+use crate::stats::PathPoint;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Key size in pixels for one layout unit
+const KEY_UNIT: f64 = 48.0;
+const KEY_GAP: f64 = 4.0;
+
+/// Simplified ANSI keyboard: rows of (key name, width in units)
+const KEYBOARD_ROWS: &[&[(&str, f64)]] = &[
+    &[
+        ("ESC", 1.0), ("F1", 1.0), ("F2", 1.0), ("F3", 1.0), ("F4", 1.0), ("F5", 1.0),
+        ("F6", 1.0), ("F7", 1.0), ("F8", 1.0), ("F9", 1.0), ("F10", 1.0), ("F11", 1.0),
+        ("F12", 1.0),
+    ],
+    &[
+        ("GRAVE", 1.0), ("1", 1.0), ("2", 1.0), ("3", 1.0), ("4", 1.0), ("5", 1.0),
+        ("6", 1.0), ("7", 1.0), ("8", 1.0), ("9", 1.0), ("0", 1.0), ("MINUS", 1.0),
+        ("EQUAL", 1.0), ("BACKSPACE", 2.0),
+    ],
+    &[
+        ("TAB", 1.5), ("Q", 1.0), ("W", 1.0), ("E", 1.0), ("R", 1.0), ("T", 1.0),
+        ("Y", 1.0), ("U", 1.0), ("I", 1.0), ("O", 1.0), ("P", 1.0), ("LEFTBRACE", 1.0),
+        ("RIGHTBRACE", 1.0), ("BACKSLASH", 1.5),
+    ],
+    &[
+        ("CAPSLOCK", 1.75), ("A", 1.0), ("S", 1.0), ("D", 1.0), ("F", 1.0), ("G", 1.0),
+        ("H", 1.0), ("J", 1.0), ("K", 1.0), ("L", 1.0), ("SEMICOLON", 1.0),
+        ("APOSTROPHE", 1.0), ("ENTER", 2.25),
+    ],
+    &[
+        ("SHIFT", 2.25), ("Z", 1.0), ("X", 1.0), ("C", 1.0), ("V", 1.0), ("B", 1.0),
+        ("N", 1.0), ("M", 1.0), ("COMMA", 1.0), ("DOT", 1.0), ("SLASH", 1.0),
+        ("RIGHTSHIFT", 2.75),
+    ],
+    &[
+        ("CTRL", 1.5), ("META", 1.25), ("ALT", 1.25), ("SPACE", 6.25), ("RIGHTALT", 1.25),
+        ("RIGHTMETA", 1.25), ("RIGHTCTRL", 1.5),
+    ],
+];
+
+/// Render a keyboard heatmap where hotter keys were pressed more often
+pub fn keyboard_heatmap(press_counts: &BTreeMap<u16, usize>) -> String {
+    let max_count = press_counts.values().copied().max().unwrap_or(0).max(1);
+    let width = 15.0 * KEY_UNIT + KEY_GAP;
+    let height = KEYBOARD_ROWS.len() as f64 * KEY_UNIT + KEY_GAP;
+
+    let mut svg = String::new();
+    // Writing to a String can't fail
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif">"#,
+        w = width,
+        h = height
+    );
+    let _ = writeln!(svg, r##"<rect width="100%" height="100%" fill="#222"/>"##);
+
+    for (row_index, row) in KEYBOARD_ROWS.iter().enumerate() {
+        let y = row_index as f64 * KEY_UNIT + KEY_GAP;
+        let mut x = KEY_GAP;
+
+        for &(name, units) in row.iter() {
+            let count = keymap::name_to_keycode(name)
+                .and_then(|code| press_counts.get(&code))
+                .copied()
+                .unwrap_or(0);
+            let key_width = units * KEY_UNIT - KEY_GAP;
+            let key_height = KEY_UNIT - KEY_GAP;
+
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{}" rx="4" fill="{}"><title>{}: {}</title></rect>"#,
+                x,
+                y,
+                key_width,
+                key_height,
+                heat_color(count as f64 / max_count as f64),
+                name,
+                count
+            );
+            let _ = writeln!(
+                svg,
+                r##"<text x="{}" y="{}" font-size="9" text-anchor="middle" fill="#000">{}</text>"##,
+                x + key_width / 2.0,
+                y + key_height / 2.0 + 3.0,
+                name
+            );
+
+            x += units * KEY_UNIT;
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Largest side of a mouse path drawing, in pixels
+const PATH_SIZE: f64 = 800.0;
+const PATH_MARGIN: f64 = 20.0;
+
+/// Render a mouse path, colored from blue (start) to red (end) over time
+pub fn mouse_path(path: &[PathPoint]) -> String {
+    let min_x = path.iter().map(|p| p.x).min().unwrap_or(0);
+    let max_x = path.iter().map(|p| p.x).max().unwrap_or(0);
+    let min_y = path.iter().map(|p| p.y).min().unwrap_or(0);
+    let max_y = path.iter().map(|p| p.y).max().unwrap_or(0);
+
+    // Keep the aspect ratio and fit the longer side into PATH_SIZE
+    let span = ((max_x - min_x).max(max_y - min_y)).max(1) as f64;
+    let scale = (PATH_SIZE / span).min(1.0);
+    let width = (max_x - min_x) as f64 * scale + 2.0 * PATH_MARGIN;
+    let height = (max_y - min_y) as f64 * scale + 2.0 * PATH_MARGIN;
+    let project = |p: &PathPoint| {
+        (
+            (p.x - min_x) as f64 * scale + PATH_MARGIN,
+            (p.y - min_y) as f64 * scale + PATH_MARGIN,
+        )
+    };
+
+    let start_us = path.first().map_or(0, |p| p.timestamp_us);
+    let end_us = path.last().map_or(0, |p| p.timestamp_us);
+    let total_us = end_us.saturating_sub(start_us).max(1) as f64;
+
+    let mut svg = String::new();
+    // Writing to a String can't fail
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w:.0}" height="{h:.0}" viewBox="0 0 {w:.0} {h:.0}">"#,
+        w = width,
+        h = height
+    );
+    let _ = writeln!(svg, r##"<rect width="100%" height="100%" fill="#fff"/>"##);
+
+    for segment in path.windows(2) {
+        let (x1, y1) = project(&segment[0]);
+        let (x2, y2) = project(&segment[1]);
+        let t = (segment[1].timestamp_us - start_us) as f64 / total_us;
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="{}" stroke-width="2" stroke-linecap="round"/>"#,
+            x1,
+            y1,
+            x2,
+            y2,
+            time_color(t)
+        );
+    }
+
+    if let (Some(first), Some(last)) = (path.first(), path.last()) {
+        let (x, y) = project(first);
+        let _ = writeln!(svg, r#"<circle cx="{:.1}" cy="{:.1}" r="5" fill="{}"><title>start</title></circle>"#, x, y, time_color(0.0));
+        let (x, y) = project(last);
+        let _ = writeln!(svg, r#"<circle cx="{:.1}" cy="{:.1}" r="5" fill="{}"><title>end</title></circle>"#, x, y, time_color(1.0));
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Map 0.0..=1.0 onto a blue-to-red gradient
+fn time_color(t: f64) -> String {
+    let t = t.clamp(0.0, 1.0);
+    format!("#{:02x}40{:02x}", (t * 255.0).round() as u8, ((1.0 - t) * 255.0).round() as u8)
+}
+
+/// Map 0.0..=1.0 onto a grey-to-red gradient
+fn heat_color(t: f64) -> String {
+    let t = t.clamp(0.0, 1.0);
+    let lerp = |from: f64, to: f64| (from + (to - from) * t).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", lerp(200.0, 230.0), lerp(200.0, 30.0), lerp(200.0, 30.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heatmap_marks_pressed_keys() {
+        let counts = BTreeMap::from([(17, 10), (30, 5)]); // W, A
+        let svg = keyboard_heatmap(&counts);
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("<title>W: 10</title>"));
+        assert!(svg.contains("<title>A: 5</title>"));
+        assert!(svg.contains("<title>Q: 0</title>"));
+    }
+
+    #[test]
+    fn test_mouse_path_segments() {
+        let path = vec![
+            PathPoint { timestamp_us: 0, x: 0, y: 0 },
+            PathPoint { timestamp_us: 500, x: 100, y: 0 },
+            PathPoint { timestamp_us: 1000, x: 100, y: 50 },
+        ];
+        let svg = mouse_path(&path);
+
+        assert_eq!(svg.matches("<line").count(), 2);
+        assert!(svg.contains(r#"width="140" height="90""#));
+        assert!(svg.contains(&time_color(1.0)));
+    }
+
+    #[test]
+    fn test_heat_color_range() {
+        assert_eq!(heat_color(0.0), "#c8c8c8");
+        assert_eq!(heat_color(1.0), "#e61e1e");
+    }
+}
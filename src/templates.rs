@@ -0,0 +1,72 @@
+//! Ready-made macros for `evkey new --template`
+//!
+//! Each template is a regular macro whose knobs are parameters, so it can be
+//! used as is, tuned with `evkey play --param`, or edited.
+
+use crate::migrations;
+
+/// Names accepted by [`render`], in the order they're listed
+pub const NAMES: &[&str] = &["afk-walk", "autoclicker", "text-paste"];
+
+/// Macro text for a template, or `None` if there's no template by that name
+pub fn render(name: &str) -> Option<String> {
+    let (description, body) = match name {
+        "afk-walk" => (
+            "Walks forward and back to avoid idle kicks. Play with --loop.",
+            "\
+param step 2s
+param pause 500ms
+
+hold W for $step
+wait $pause
+hold S for $step
+wait $pause
+",
+        ),
+        "autoclicker" => (
+            "Clicks the left mouse button over and over. Play with --loop.",
+            "\
+param interval 100ms
+
+tap BTN_LEFT
+wait $interval
+",
+        ),
+        "text-paste" => (
+            "Pastes a piece of text through the clipboard.",
+            "\
+param text \"Hello, world\"
+
+paste $text
+",
+        ),
+        _ => return None,
+    };
+
+    Some(format!(
+        "# EvKey Macro\n# Version: {}\n# Layout: QWERTY\n# {}\n# Change the values below, or override them with `evkey play --param NAME=VALUE`.\n\n{}",
+        migrations::CURRENT_VERSION,
+        description,
+        body
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+
+    #[test]
+    fn test_templates_parse() {
+        for name in NAMES {
+            let text = render(name).unwrap();
+            let macro_ = storage::parse_macro(&text).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert!(!macro_.is_empty(), "{} is empty", name);
+        }
+        assert!(render("nope").is_none());
+
+        let fast = [("interval".to_string(), "10ms".to_string())];
+        let session = storage::parse_session_with_params(&render("autoclicker").unwrap(), &fast).unwrap();
+        assert_eq!(session.merged().states[1].duration_ms, 10);
+    }
+}
@@ -0,0 +1,237 @@
+//! Deterministic replays for tests
+//!
+//! [`replay`] plays a macro the way `evkey play` would, but into a
+//! [`MockBackend`] paced by a [`VirtualClock`]: nothing sleeps, and every
+//! event is captured with the virtual time it went out at. Applications can
+//! use it to test what their input handling receives, in milliseconds of
+//! test time however long the macro is:
+//!
+//! ```ignore
+//! let replay = testing::replay(&storage::parse_macro("hold A for 40ms\nwait 10ms\ntap B\n")?)?;
+//! replay.assert_keys(&["0ms A down", "40ms A up", "50ms B down", "50ms B up"]);
+//! assert_eq!(replay.duration(), Duration::from_millis(50));
+//! ```
+//!
+//! Actions run as they would in any playback; `wait pixel` and `wait text`
+//! still look at the real screen.
+//!
+//! The replay needs the `devices` feature; [`VirtualClock`] and [`key`] don't,
+//! so the format modules can use them in their own tests.
+
+#[cfg(feature = "devices")]
+use crate::backend::Backend;
+#[cfg(feature = "devices")]
+use crate::capabilities::Capabilities;
+use crate::clock::Clock;
+use crate::event::{EventType, InputEvent, RecordedEvent};
+#[cfg(feature = "devices")]
+use crate::input_key::InputKey;
+#[cfg(feature = "devices")]
+use crate::player::Player;
+#[cfg(feature = "devices")]
+use crate::storage::Macro;
+#[cfg(feature = "devices")]
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "devices")]
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A clock that only moves when it's slept on or advanced
+///
+/// Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+/// A key event at `timestamp_us`, as a recording would have it
+pub fn key(timestamp_us: u64, code: u16, value: i32) -> RecordedEvent {
+    RecordedEvent {
+        timestamp_us,
+        event: InputEvent::new(EventType::KEY.0, code, value),
+    }
+}
+
+#[cfg(feature = "devices")]
+/// What a [`MockBackend`] was given
+#[derive(Debug, Default)]
+struct Captured {
+    events: Vec<RecordedEvent>,
+    /// Pointer placements, as `(time in microseconds, x, y)`
+    moves: Vec<(u64, i32, i32)>,
+}
+
+#[cfg(feature = "devices")]
+/// A backend that keeps every event it's given, timestamped by a clock,
+/// and can deliver anything
+///
+/// Clones share what was captured.
+#[derive(Clone)]
+pub struct MockBackend {
+    clock: VirtualClock,
+    captured: Arc<Mutex<Captured>>,
+}
+
+#[cfg(feature = "devices")]
+impl MockBackend {
+    /// A backend timestamping events by `clock`
+    pub fn new(clock: &VirtualClock) -> Self {
+        Self {
+            clock: clock.clone(),
+            captured: Arc::default(),
+        }
+    }
+
+    /// Events emitted so far, with the clock's time in microseconds
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.captured.lock().unwrap().events.clone()
+    }
+
+    /// Pointer placements so far, as `(time in microseconds, x, y)`
+    pub fn moves(&self) -> Vec<(u64, i32, i32)> {
+        self.captured.lock().unwrap().moves.clone()
+    }
+
+    fn now_us(&self) -> u64 {
+        self.clock.now().as_micros() as u64
+    }
+}
+
+#[cfg(feature = "devices")]
+impl Backend for MockBackend {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let timestamp_us = self.now_us();
+        let mut captured = self.captured.lock().unwrap();
+        captured
+            .events
+            .extend(events.iter().map(|&event| RecordedEvent { timestamp_us, event }));
+        Ok(())
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> io::Result<()> {
+        let timestamp_us = self.now_us();
+        self.captured.lock().unwrap().moves.push((timestamp_us, x, y));
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::all()
+    }
+}
+
+#[cfg(feature = "devices")]
+/// Play `macro_` into a [`MockBackend`] on a [`VirtualClock`]
+pub fn replay(macro_: &Macro) -> io::Result<Replay> {
+    let clock = VirtualClock::new();
+    let backend = MockBackend::new(&clock);
+    let mut player = Player::new(Box::new(backend.clone()));
+    player.set_clock(clock.clone());
+    player.set_state_starts(macro_.state_starts_us());
+    player.play_with_actions(&macro_.events(), &macro_.timed_actions())?;
+    Ok(Replay {
+        events: backend.events(),
+        moves: backend.moves(),
+        duration: clock.now(),
+    })
+}
+
+#[cfg(feature = "devices")]
+/// What a [`replay`] emitted
+#[derive(Debug, Clone)]
+pub struct Replay {
+    /// Every event, timestamped with the virtual time it went out at
+    pub events: Vec<RecordedEvent>,
+    /// Pointer placements, as `(time in microseconds, x, y)`
+    pub moves: Vec<(u64, i32, i32)>,
+    duration: Duration,
+}
+
+#[cfg(feature = "devices")]
+impl Replay {
+    /// Virtual time the playback took
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Key and button events, one line each: "40ms A up", "50ms BTN_LEFT
+    /// down" (times are whole milliseconds, rounded down)
+    pub fn key_log(&self) -> Vec<String> {
+        self.events
+            .iter()
+            .filter(|recorded| recorded.event.event_type() == EventType::KEY)
+            .map(|recorded| {
+                let what = match recorded.event.value() {
+                    0 => "up",
+                    1 => "down",
+                    _ => "repeat",
+                };
+                let key = InputKey::from(recorded.event.code());
+                format!("{}ms {} {}", recorded.timestamp_us / 1000, key, what)
+            })
+            .collect()
+    }
+
+    /// Panic unless the [`Replay::key_log`] is `expected`
+    #[track_caller]
+    pub fn assert_keys(&self, expected: &[&str]) {
+        let log = self.key_log();
+        if log != expected {
+            panic!("Replayed keys differ\n  expected: {:?}\n  replayed: {:?}", expected, log);
+        }
+    }
+
+    /// Total pointer motion, as `(x, y)` counts
+    pub fn mouse_motion(&self) -> (i32, i32) {
+        self.events
+            .iter()
+            .filter(|recorded| recorded.event.event_type() == EventType::RELATIVE)
+            .fold((0, 0), |(x, y), recorded| match recorded.event.code() {
+                0 => (x + recorded.event.value(), y), // REL_X
+                1 => (x, y + recorded.event.value()), // REL_Y
+                _ => (x, y),
+            })
+    }
+}
+
+#[cfg(all(test, feature = "devices"))]
+mod tests {
+    use super::*;
+    use crate::storage::parse_macro;
+
+    #[test]
+    fn test_replay() {
+        let macro_ = parse_macro("hold A for 40ms\nwait 10ms\nmove 30 -5\nwait 3600s\ntap BTN_LEFT\nmove to 100 200\n").unwrap();
+        let replay = replay(&macro_).unwrap();
+        replay.assert_keys(&["0ms A down", "40ms A up", "3600050ms BTN_LEFT down", "3600050ms BTN_LEFT up"]);
+        assert_eq!(replay.mouse_motion(), (30, -5));
+        assert_eq!(replay.moves, vec![(3_600_050_000, 100, 200)]);
+        // An hour of macro, played in no time
+        assert_eq!(replay.duration(), Duration::from_millis(3_600_050));
+
+        let caught = std::panic::catch_unwind(|| replay.assert_keys(&["0ms A down"]));
+        assert!(caught.is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Limiting how fast events are injected
+//!
+//! Some applications (Electron apps, VNC sessions) drop or garble input that
+//! arrives as densely as it was recorded, e.g. a gaming mouse's 1000Hz of
+//! movement. A [`Throttle`] caps the events per second with a token bucket:
+//! up to `burst` events go out back to back, after which each one waits its
+//! turn. Events are held back, never dropped, and the player pushes the rest
+//! of the macro back by the time they waited, so dense stretches play slower
+//! and the timing around them is kept.
+
+use std::fmt;
+use std::time::Duration;
+
+/// Events allowed when `--burst` isn't given
+pub const DEFAULT_BURST: u32 = 10;
+
+/// Most events per second, and how many may go out at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventRate {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+impl EventRate {
+    /// Parse a rate like `500` or `500/s`, with `burst` events at once
+    pub fn parse(rate: &str, burst: u32) -> Result<Self, String> {
+        let per_second = rate
+            .trim()
+            .trim_end_matches("/s")
+            .parse()
+            .ok()
+            .filter(|&rate| rate > 0)
+            .ok_or_else(|| format!("Invalid event rate '{}', expected e.g. 500/s", rate.trim()))?;
+        if burst == 0 {
+            return Err("Burst must be at least 1 event".to_string());
+        }
+        Ok(Self { per_second, burst })
+    }
+}
+
+impl fmt::Display for EventRate {
+    /// "500/s burst 10"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/s burst {}", self.per_second, self.burst)
+    }
+}
+
+/// A token bucket holding events to an [`EventRate`]
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    rate: EventRate,
+    /// Events that may go out right away; starts full
+    tokens: f64,
+    /// Clock time the tokens were counted at
+    counted: Option<Duration>,
+}
+
+impl Throttle {
+    pub fn new(rate: EventRate) -> Self {
+        Self {
+            rate,
+            tokens: f64::from(rate.burst),
+            counted: None,
+        }
+    }
+
+    /// How long the event about to go out at `now` (clock time) has to wait
+    ///
+    /// The event is counted as sent once the wait is over.
+    pub fn delay(&mut self, now: Duration) -> Duration {
+        let per_second = f64::from(self.rate.per_second);
+        if let Some(counted) = self.counted {
+            let refill = now.saturating_sub(counted).as_secs_f64() * per_second;
+            self.tokens = (self.tokens + refill).min(f64::from(self.rate.burst));
+        }
+
+        let wait = if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / per_second)
+        };
+        self.tokens = (self.tokens - 1.0).max(0.0);
+        self.counted = Some(now + wait);
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let rate = EventRate::parse("100/s", 3).unwrap();
+        assert_eq!(rate.to_string(), "100/s burst 3");
+        assert_eq!(EventRate::parse("500", 1).unwrap().per_second, 500);
+        assert!(EventRate::parse("0/s", 1).is_err());
+        assert!(EventRate::parse("fast", 1).is_err());
+        assert!(EventRate::parse("100", 0).is_err());
+
+        // A burst of three goes out at once, then one every 10ms
+        let mut throttle = Throttle::new(rate);
+        let mut now = Duration::ZERO;
+        let mut waits = Vec::new();
+        for _ in 0..5 {
+            let wait = throttle.delay(now);
+            now += wait;
+            waits.push(wait.as_micros());
+        }
+        assert_eq!(waits, [0, 0, 0, 10_000, 10_000]);
+
+        // A pause fills the bucket back up, but only to the burst
+        now += Duration::from_secs(1);
+        let waits: Vec<_> = (0..4).map(|_| throttle.delay(now).as_micros()).collect();
+        assert_eq!(waits[..3], [0, 0, 0]);
+        assert!(waits[3] > 0);
+    }
+}
@@ -0,0 +1,436 @@
+//! The text a recording typed
+//!
+//! A recorded sentence is dozens of states pressing and releasing keys, which
+//! nobody wants to edit by hand and which type something else under another
+//! keyboard layout. [`typed_runs`] reads the text back out of the states,
+//! following shift, caps lock and backspace with the layout's [`CharMap`],
+//! so `evkey text` can show it and `evkey type-text` can replace the
+//! keystrokes with a single `type "..."` action.
+//!
+//! The other way round, [`CharMap::keystrokes_for`] finds the keys that type
+//! a character, for `type` actions: accented letters the layout has no key
+//! for are typed with its dead keys (`´` then `e` for é on a German layout),
+//! or with the compose key if the XKB options set one (`compose:ralt`, then
+//! `'` and `e`).
+//!
+//! Only the base layouts below are known. Reading text back doesn't follow
+//! dead keys (one ends the text at that point), and keypad keys aren't
+//! treated as text either.
+
+use crate::keyset::KeySet;
+use crate::layout::XkbLayout;
+use crate::locks::KEY_CAPSLOCK;
+use crate::state::MacroState;
+use std::collections::HashMap;
+
+const KEY_BACKSPACE: u16 = 14;
+const KEY_TAB: u16 = 15;
+const KEY_ENTER: u16 = 28;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_SPACE: u16 = 57;
+/// The extra key left of Z on ISO keyboards
+const KEY_102ND: u16 = 86;
+
+/// Longest pause (in milliseconds) that still counts as the same stretch of
+/// typing
+pub const MAX_PAUSE_MS: u64 = 2000;
+
+/// Keycodes of the rows of character keys, top to bottom
+const ROWS: [&[u16]; 4] = [
+    &[41, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13],
+    &[16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 43],
+    &[30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40],
+    &[KEY_102ND, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53],
+];
+
+/// Accents that dead keys and compose sequences put on letters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Accent {
+    Grave,
+    Acute,
+    Circumflex,
+    Tilde,
+    Diaeresis,
+}
+
+/// Each accent with the character it types on its own (a dead key then
+/// space), the character that stands for it in compose sequences, and the
+/// letters it goes on, plain then accented
+const ACCENTS: &[(Accent, char, char, &str, &str)] = &[
+    (Accent::Grave, '`', '`', "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    (Accent::Acute, '´', '\'', "aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+    (Accent::Circumflex, '^', '^', "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    (Accent::Tilde, '~', '~', "anoANO", "ãñõÃÑÕ"),
+    (Accent::Diaeresis, '¨', '"', "aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+];
+
+/// Keys XKB's `compose:` options turn into the compose key
+const COMPOSE_OPTIONS: &[(&str, u16)] = &[
+    ("ralt", 100),
+    ("lwin", 125),
+    ("rwin", 126),
+    ("menu", 127),
+    ("lctrl", 29),
+    ("rctrl", 97),
+    ("caps", 58),
+    ("102", KEY_102ND),
+    ("paus", 119),
+    ("prsc", 99),
+    ("sclk", 70),
+    ("ins", 110),
+];
+
+/// Characters of one layout: each row of [`ROWS`] unshifted and shifted, a
+/// space standing for a dead key
+struct LayoutTable {
+    name: &'static str,
+    rows: [(&'static str, &'static str); 4],
+    /// What the dead keys type with the `nodeadkeys` variant, as
+    /// `(keycode, unshifted, shifted)`
+    nodeadkeys: &'static [(u16, char, char)],
+    /// The accent of each dead key, as `(keycode, shifted, accent)`
+    dead_keys: &'static [(u16, bool, Accent)],
+}
+
+const LAYOUTS: &[LayoutTable] = &[
+    LayoutTable {
+        name: "us",
+        rows: [
+            ("`1234567890-=", "~!@#$%^&*()_+"),
+            ("qwertyuiop[]\\", "QWERTYUIOP{}|"),
+            ("asdfghjkl;'", "ASDFGHJKL:\""),
+            ("<zxcvbnm,./", ">ZXCVBNM<>?"),
+        ],
+        nodeadkeys: &[],
+        dead_keys: &[],
+    },
+    LayoutTable {
+        name: "gb",
+        rows: [
+            ("`1234567890-=", "¬!\"£$%^&*()_+"),
+            ("qwertyuiop[]#", "QWERTYUIOP{}~"),
+            ("asdfghjkl;'", "ASDFGHJKL:@"),
+            ("\\zxcvbnm,./", "|ZXCVBNM<>?"),
+        ],
+        nodeadkeys: &[],
+        dead_keys: &[],
+    },
+    LayoutTable {
+        name: "de",
+        rows: [
+            (" 1234567890ß ", "°!\"§$%&/()=? "),
+            ("qwertzuiopü+#", "QWERTZUIOPÜ*'"),
+            ("asdfghjklöä", "ASDFGHJKLÖÄ"),
+            ("<yxcvbnm,.-", ">YXCVBNM;:_"),
+        ],
+        nodeadkeys: &[(41, '^', '°'), (13, '´', '`')],
+        dead_keys: &[(41, false, Accent::Circumflex), (13, false, Accent::Acute), (13, true, Accent::Grave)],
+    },
+    LayoutTable {
+        name: "fr",
+        rows: [
+            ("²&é\"'(-è_çà)=", " 1234567890°+"),
+            ("azertyuiop $*", "AZERTYUIOP £µ"),
+            ("qsdfghjklmù", "QSDFGHJKLM%"),
+            ("<wxcvbn,;:!", ">WXCVBN?./§"),
+        ],
+        nodeadkeys: &[(26, '^', '¨')],
+        dead_keys: &[(26, false, Accent::Circumflex), (26, true, Accent::Diaeresis)],
+    },
+];
+
+/// What each key types under a keyboard layout
+#[derive(Debug, Clone)]
+pub struct CharMap {
+    /// Unshifted and shifted character of each key, `None` for dead keys
+    keys: HashMap<u16, (Option<char>, Option<char>)>,
+    /// Key and whether it needs shift, for each character
+    reverse: HashMap<char, (u16, bool)>,
+    /// Dead key and whether it needs shift, for each accent
+    dead_keys: HashMap<Accent, (u16, bool)>,
+    /// The compose key, if the XKB options set one
+    compose: Option<u16>,
+}
+
+impl CharMap {
+    /// The US layout, assumed when a recording doesn't say
+    pub fn us() -> Self {
+        Self::for_layout("us", "").expect("us layout is built in")
+    }
+
+    /// Characters of an XKB layout and variant, if it's one of the known ones
+    pub fn for_layout(layout: &str, variant: &str) -> Option<Self> {
+        let table = LAYOUTS.iter().find(|table| table.name == layout)?;
+        let nodeadkeys = match variant {
+            "" | "basic" => false,
+            "nodeadkeys" => true,
+            _ => return None,
+        };
+
+        let mut keys = HashMap::new();
+        for (codes, (plain, shifted)) in ROWS.iter().zip(table.rows) {
+            let typed = |c: char| (c != ' ').then_some(c);
+            for ((&code, plain), shifted) in codes.iter().zip(plain.chars()).zip(shifted.chars()) {
+                keys.insert(code, (typed(plain), typed(shifted)));
+            }
+        }
+        let mut dead_keys = HashMap::new();
+        if nodeadkeys {
+            for &(code, plain, shifted) in table.nodeadkeys {
+                keys.insert(code, (Some(plain), Some(shifted)));
+            }
+        } else {
+            for &(code, shift, accent) in table.dead_keys {
+                dead_keys.insert(accent, (code, shift));
+            }
+        }
+        keys.insert(KEY_SPACE, (Some(' '), Some(' ')));
+        keys.insert(KEY_ENTER, (Some('\n'), Some('\n')));
+        keys.insert(KEY_TAB, (Some('\t'), Some('\t')));
+
+        let mut reverse = HashMap::new();
+        let mut sorted: Vec<_> = keys.iter().collect();
+        sorted.sort_by_key(|(code, _)| **code);
+        for (&code, &(plain, shifted)) in sorted {
+            // Lower keycodes win, and unshifted over shifted, so the ISO key
+            // only types what no other key does
+            if let Some(c) = plain {
+                reverse.entry(c).or_insert((code, false));
+            }
+            if let Some(c) = shifted {
+                reverse.entry(c).or_insert((code, true));
+            }
+        }
+
+        Some(Self {
+            keys,
+            reverse,
+            dead_keys,
+            compose: None,
+        })
+    }
+
+    /// Use the compose key XKB `options` like `compose:ralt,caps:escape`
+    /// set, if any
+    pub fn with_options(mut self, options: &str) -> Self {
+        self.compose = options
+            .split(',')
+            .filter_map(|option| option.strip_prefix("compose:"))
+            .find_map(|key| COMPOSE_OPTIONS.iter().find(|(name, _)| *name == key))
+            .map(|&(_, code)| code);
+        self
+    }
+
+    /// Characters of the layout a macro was recorded with, or of the US
+    /// layout if it doesn't say; of several layouts, the first is used
+    pub fn for_recording(keyboard: Option<&XkbLayout>) -> Result<Self, String> {
+        let Some(keyboard) = keyboard else {
+            return Ok(Self::us());
+        };
+        let layout = keyboard.layout.split(',').next().unwrap_or_default();
+        let variant = keyboard.variant.split(',').next().unwrap_or_default();
+        let chars = Self::for_layout(layout, variant).ok_or_else(|| {
+            let known: Vec<&str> = LAYOUTS.iter().map(|table| table.name).collect();
+            format!(
+                "Don't know which characters keyboard layout {} types (known: {})",
+                keyboard.describe(),
+                known.join(", ")
+            )
+        })?;
+        Ok(chars.with_options(&keyboard.options))
+    }
+
+    /// Character `code` types, if any
+    pub fn char_for(&self, code: u16, shift: bool, caps_lock: bool) -> Option<char> {
+        let &(plain, shifted) = self.keys.get(&code)?;
+        // Caps lock swaps the case of letters only
+        let letter = plain.zip(shifted).is_some_and(|(plain, shifted)| plain.to_uppercase().eq([shifted]) && plain != shifted);
+        if shift != (caps_lock && letter) { shifted } else { plain }
+    }
+
+    /// Key typing `c` and whether it needs shift (with caps lock off)
+    pub fn key_for(&self, c: char) -> Option<(u16, bool)> {
+        self.reverse.get(&c).copied()
+    }
+
+    /// Keys to tap one after another to type `c`, each with whether it needs
+    /// shift: its own key, or else a dead key or the compose key followed by
+    /// the keys of what the accent goes on
+    pub fn keystrokes_for(&self, c: char) -> Option<Vec<(u16, bool)>> {
+        if let Some(key) = self.key_for(c) {
+            return Some(vec![key]);
+        }
+
+        ACCENTS.iter().find_map(|&(accent, alone, mark, plain, accented)| {
+            // The accent on its own is the accent on a space
+            let base = if c == alone { ' ' } else { plain.chars().zip(accented.chars()).find(|&(_, a)| a == c)?.0 };
+            let base_key = self.key_for(base)?;
+            if let Some(&dead_key) = self.dead_keys.get(&accent) {
+                return Some(vec![dead_key, base_key]);
+            }
+            let compose = self.compose?;
+            Some(vec![(compose, false), self.key_for(mark)?, base_key])
+        })
+    }
+}
+
+/// Text typed by the states in `start..end`; the state at `end` is the
+/// pause after the last key was let go
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypedRun {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// Find the stretches of `states` that do nothing but type text
+///
+/// A stretch starts when the first key goes down and ends when all keys are
+/// up again, carrying on over pauses up to [`MAX_PAUSE_MS`]. Anything else
+/// (another modifier, a key that isn't text, the mouse) ends it. `caps_lock`
+/// is the caps lock state when the recording started.
+pub fn typed_runs(states: &[MacroState], caps_lock: bool, chars: &CharMap) -> Vec<TypedRun> {
+    let mut runs = Vec::new();
+    let mut caps_lock = caps_lock;
+    let mut previous = KeySet::new();
+    // The stretch so far, ending at the state after its last key was released
+    let mut current: Option<TypedRun> = None;
+    // The stretch's text with the keys currently down applied; `None` once
+    // they've done something other than type
+    let mut burst: Option<(usize, Option<String>)> = None;
+
+    for (index, state) in states.iter().enumerate() {
+        let pressed: Vec<u16> = state.keys_pressed.codes().filter(|&key| !previous.contains(key)).collect();
+        let still = state.mouse_delta == (0, 0) && state.scroll_delta == (0, 0);
+
+        if !state.keys_pressed.is_empty() {
+            let (_, text) = burst.get_or_insert_with(|| {
+                let continues = current.as_ref().is_some_and(|run| {
+                    run.end + 1 == index && states[run.end].duration_ms <= MAX_PAUSE_MS && states[run.end].is_empty()
+                });
+                if !continues {
+                    runs.extend(current.take());
+                }
+                (index, Some(current.as_ref().map(|run| run.text.clone()).unwrap_or_default()))
+            });
+
+            let shift = state.keys_pressed.contains(KEY_LEFTSHIFT) || state.keys_pressed.contains(KEY_RIGHTSHIFT);
+            for &key in &pressed {
+                let typed = text.as_mut().filter(|_| still).and_then(|text| match key {
+                    KEY_LEFTSHIFT | KEY_RIGHTSHIFT | KEY_CAPSLOCK => Some(()),
+                    KEY_BACKSPACE => text.pop().map(drop),
+                    _ => chars.char_for(key, shift, caps_lock).map(|c| text.push(c)),
+                });
+                if typed.is_none() {
+                    *text = None;
+                }
+                if key == KEY_CAPSLOCK {
+                    caps_lock = !caps_lock;
+                }
+            }
+            if !still {
+                *text = None;
+            }
+        } else if let Some((start, text)) = burst.take() {
+            match text {
+                Some(text) => {
+                    let start = current.as_ref().map_or(start, |run| run.start);
+                    current = Some(TypedRun { start, end: index, text });
+                }
+                None => runs.extend(current.take()),
+            }
+        }
+
+        previous = state.keys_pressed.clone();
+    }
+
+    // The macro ends with keys still down, which playback lets go of
+    match burst {
+        Some((start, Some(text))) => {
+            let start = current.as_ref().map_or(start, |run| run.start);
+            runs.push(TypedRun { start, end: states.len(), text });
+        }
+        _ => runs.extend(current),
+    }
+    runs.retain(|run| !run.text.is_empty());
+    runs
+}
+
+/// All the text in `runs`, one stretch of typing per line
+pub fn join_runs(runs: &[TypedRun]) -> String {
+    let mut text = String::new();
+    for run in runs {
+        text.push_str(&run.text);
+        if !run.text.ends_with('\n') {
+            text.push('\n');
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap;
+
+    /// States pressing the given keys for 30ms each, "" being a pause
+    fn states(steps: &[&str]) -> Vec<MacroState> {
+        steps
+            .iter()
+            .map(|step| {
+                let mut state = MacroState::new(30);
+                for name in step.split('+').filter(|name| !name.is_empty()) {
+                    state.keys_pressed.insert(keymap::name_to_keycode(name).unwrap());
+                }
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_keystrokes() {
+        let de = CharMap::for_layout("de", "").unwrap();
+        assert_eq!(de.keystrokes_for('ü'), Some(vec![(26, false)]));
+        assert_eq!(de.keystrokes_for('é'), Some(vec![(13, false), (18, false)]));
+        assert_eq!(de.keystrokes_for('È'), Some(vec![(13, true), (18, true)]));
+        assert_eq!(de.keystrokes_for('^'), Some(vec![(41, false), (KEY_SPACE, false)]));
+        // Without dead keys there's no ê
+        assert_eq!(CharMap::for_layout("de", "nodeadkeys").unwrap().keystrokes_for('ê'), None);
+
+        let us = CharMap::us();
+        assert_eq!(us.keystrokes_for('ñ'), None);
+        let us = us.with_options("caps:escape,compose:ralt");
+        assert_eq!(us.keystrokes_for('ñ'), Some(vec![(100, false), (41, true), (49, false)]));
+        assert_eq!(us.keystrokes_for('Ö'), Some(vec![(100, false), (40, true), (24, true)]));
+        let layout = XkbLayout::parse("layout=fr options=compose:menu").unwrap();
+        let fr = CharMap::for_recording(Some(&layout)).unwrap();
+        // Dead keys win over compose
+        assert_eq!(fr.keystrokes_for('ï'), Some(vec![(26, true), (23, false)]));
+        assert_eq!(fr.keystrokes_for('ó'), Some(vec![(127, false), (5, false), (24, false)]));
+    }
+
+    #[test]
+    fn test_typed_runs() {
+        let us = CharMap::us();
+        let typed = states(&["SHIFT", "SHIFT+H", "", "I", "I+1", "1", "", "BACKSPACE", "", "SPACE", "", "CAPSLOCK", "", "A", ""]);
+        let runs = typed_runs(&typed, false, &us);
+        assert_eq!(runs, vec![TypedRun { start: 0, end: 14, text: "Hi A".to_string() }]);
+
+        // A shortcut and a long pause both split the text
+        let mut split = states(&["A", "", "CTRL+C", "", "B", "", "C", ""]);
+        split[5].duration_ms = MAX_PAUSE_MS + 1;
+        let texts: Vec<String> = typed_runs(&split, true, &us).into_iter().map(|run| run.text).collect();
+        assert_eq!(texts, ["A", "B", "C"]);
+        assert_eq!(join_runs(&typed_runs(&split, true, &us)), "A\nB\nC\n");
+
+        let de = CharMap::for_layout("de", "").unwrap();
+        let runs = typed_runs(&states(&["Y", "", "SHIFT+7", "", "GRAVE", ""]), false, &de);
+        assert_eq!(runs[0].text, "z/");
+        assert_eq!(de.key_for('z'), Some((21, false)));
+        assert_eq!(de.key_for('<'), Some((86, false)));
+        assert_eq!(us.key_for('<'), Some((51, true)));
+        assert_eq!(us.key_for('\n'), Some((KEY_ENTER, false)));
+        assert!(CharMap::for_layout("de", "neo").is_none());
+    }
+}
@@ -0,0 +1,91 @@
+//! Noticing changed files with inotify
+//!
+//! Directories are watched rather than the files themselves: most editors save
+//! by writing a new file and renaming it over the old one, which a watch on
+//! the old file would miss.
+
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Events meaning a file in a watched directory has new contents (or is gone)
+fn change_flags() -> AddWatchFlags {
+    AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_DELETE
+}
+
+/// Watches directories for files being written, replaced or deleted
+pub struct Watcher {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            inotify: Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?,
+            dirs: HashMap::new(),
+        })
+    }
+
+    /// Watch the directory holding `file`; watching it again does nothing
+    pub fn watch_file(&mut self, file: &Path) -> io::Result<()> {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if self.dirs.values().any(|watched| watched == dir) {
+            return Ok(());
+        }
+
+        let wd = self.inotify.add_watch(dir, change_flags())?;
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Files changed since the last call, without blocking
+    pub fn changed(&mut self) -> io::Result<Vec<PathBuf>> {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut changed: Vec<PathBuf> = Vec::new();
+        for event in events {
+            let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), event.name) else {
+                continue;
+            };
+            let path = dir.join(name);
+            if !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_replaced_files() {
+        let dir = std::env::temp_dir().join(format!("evkey-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.macro");
+
+        let mut watcher = Watcher::new().unwrap();
+        watcher.watch_file(&file).unwrap();
+        assert!(watcher.changed().unwrap().is_empty());
+
+        // Saved the way editors do: write elsewhere, then rename over it
+        std::fs::write(dir.join(".a.macro.tmp"), "wait 10ms\n").unwrap();
+        std::fs::rename(dir.join(".a.macro.tmp"), &file).unwrap();
+        let changed = watcher.changed().unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(changed, vec![dir.join(".a.macro.tmp"), file]);
+    }
+}
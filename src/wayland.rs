@@ -0,0 +1,372 @@
+//! Wayland injection backend
+//!
+//! Uses `zwp_virtual_keyboard_v1` and `zwlr_virtual_pointer_v1`, which
+//! wlroots-based compositors (Sway, Hyprland, river, ...) expose to clients.
+//! No access to `/dev/uinput` is needed, so this works inside Flatpak and on
+//! systems where uinput is locked down.
+//!
+//! Text is typed like `wtype` does it: the virtual keyboard gets a keymap
+//! with a key for each character of the text, so any Unicode character can
+//! be typed whatever the layout, and the regular keymap is put back after.
+
+use crate::backend::Backend;
+use crate::capabilities::Capabilities;
+use crate::event::{EventType, InputEvent};
+use crate::input_key::InputKey;
+use crate::screen::{self, ScreenSize};
+use std::io::{self, Seek, Write};
+use std::os::fd::AsFd;
+use std::thread;
+use std::time::{Duration, Instant};
+use wayland_client::globals::{registry_queue_init, GlobalListContents};
+use wayland_client::protocol::wl_pointer::{Axis, AxisSource, ButtonState};
+use wayland_client::protocol::{wl_registry, wl_seat::WlSeat};
+use wayland_client::{delegate_noop, Connection, Dispatch, EventQueue, QueueHandle};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
+    zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1,
+    zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1,
+};
+use wayland_protocols_wlr::virtual_pointer::v1::client::{
+    zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1,
+    zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1,
+};
+
+/// Keymap uploaded to the compositor; keycodes are evdev codes + 8
+const KEYMAP: &str = r#"xkb_keymap {
+    xkb_keycodes  { include "evdev+aliases(qwerty)" };
+    xkb_types     { include "complete" };
+    xkb_compat    { include "complete" };
+    xkb_symbols   { include "pc+us+inet(evdev)" };
+};
+"#;
+
+/// wl_keyboard keymap format for XKB v1 text keymaps
+const KEYMAP_FORMAT_XKB_V1: u32 = 1;
+
+/// Modifier masks in the keymap above
+const MOD_SHIFT: u32 = 1;
+const MOD_CAPS: u32 = 2;
+const MOD_CTRL: u32 = 4;
+const MOD_ALT: u32 = 8;
+const MOD_NUM: u32 = 16;
+const MOD_META: u32 = 64;
+
+/// Most characters typed with one text keymap, each on a key of its own
+const TEXT_KEYMAP_SIZE: usize = 200;
+/// Pause after each character of typed text
+const TEXT_KEY_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Scroll distance of one wheel detent, as libinput reports it
+const SCROLL_STEP: f64 = 15.0;
+
+/// Event dispatch target; the objects we create send no events we need
+struct State;
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut Self,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+delegate_noop!(State: ignore WlSeat);
+delegate_noop!(State: ZwpVirtualKeyboardManagerV1);
+delegate_noop!(State: ZwpVirtualKeyboardV1);
+delegate_noop!(State: ZwlrVirtualPointerManagerV1);
+delegate_noop!(State: ZwlrVirtualPointerV1);
+
+/// Virtual keyboard+pointer on the compositor's default seat
+pub struct WaylandBackend {
+    connection: Connection,
+    queue: EventQueue<State>,
+    keyboard: ZwpVirtualKeyboardV1,
+    pointer: ZwlrVirtualPointerV1,
+    start: Instant,
+    depressed: u32,
+    locked: u32,
+    /// Screen size absolute moves are relative to, looked up on first use
+    screen_size: Option<ScreenSize>,
+}
+
+impl WaylandBackend {
+    /// Connect to the compositor named by `WAYLAND_DISPLAY`
+    pub fn connect() -> io::Result<Self> {
+        let connection = Connection::connect_to_env().map_err(io::Error::other)?;
+        let (globals, mut queue) = registry_queue_init::<State>(&connection).map_err(io::Error::other)?;
+        let qh = queue.handle();
+
+        let missing = |name: &'static str| {
+            move |_| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Compositor doesn't support {}", name),
+                )
+            }
+        };
+        let seat: WlSeat = globals.bind(&qh, 1..=7, ()).map_err(missing("wl_seat"))?;
+        let keyboard_manager: ZwpVirtualKeyboardManagerV1 = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(missing("zwp_virtual_keyboard_v1"))?;
+        let pointer_manager: ZwlrVirtualPointerManagerV1 = globals
+            .bind(&qh, 1..=2, ())
+            .map_err(missing("zwlr_virtual_pointer_v1"))?;
+
+        let keyboard = keyboard_manager.create_virtual_keyboard(&seat, &qh, ());
+        let pointer = pointer_manager.create_virtual_pointer(Some(&seat), &qh, ());
+
+        upload_keymap(&keyboard, KEYMAP)?;
+
+        queue.roundtrip(&mut State).map_err(io::Error::other)?;
+
+        Ok(Self {
+            connection,
+            queue,
+            keyboard,
+            pointer,
+            start: Instant::now(),
+            depressed: 0,
+            locked: 0,
+            screen_size: None,
+        })
+    }
+
+    /// Update modifier state after a key press or release
+    fn update_modifiers(&mut self, code: u16, pressed: bool) -> bool {
+        let (mask, lock) = match code {
+            42 | 54 => (MOD_SHIFT, false),  // KEY_LEFTSHIFT, KEY_RIGHTSHIFT
+            29 | 97 => (MOD_CTRL, false),   // KEY_LEFTCTRL, KEY_RIGHTCTRL
+            56 | 100 => (MOD_ALT, false),   // KEY_LEFTALT, KEY_RIGHTALT
+            125 | 126 => (MOD_META, false), // KEY_LEFTMETA, KEY_RIGHTMETA
+            58 => (MOD_CAPS, true),         // KEY_CAPSLOCK
+            69 => (MOD_NUM, true),          // KEY_NUMLOCK
+            _ => return false,
+        };
+
+        if lock {
+            if pressed {
+                self.locked ^= mask;
+            }
+        } else if pressed {
+            self.depressed |= mask;
+        } else {
+            self.depressed &= !mask;
+        }
+        true
+    }
+
+    /// Type `chars` with a keymap that has a key for each of them
+    fn type_with_keymap(&mut self, chars: &[char]) -> io::Result<()> {
+        let (keys, typed) = text_keys(chars);
+        upload_keymap(&self.keyboard, &text_keymap(&keys))?;
+        self.queue.roundtrip(&mut State).map_err(io::Error::other)?;
+
+        for key in typed {
+            let time = self.start.elapsed().as_millis() as u32;
+            self.keyboard.key(time, key, 1);
+            self.keyboard.key(time, key, 0);
+            self.connection.flush().map_err(io::Error::other)?;
+            thread::sleep(TEXT_KEY_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+impl Backend for WaylandBackend {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let time = self.start.elapsed().as_millis() as u32;
+        let (mut dx, mut dy) = (0.0, 0.0);
+        let mut pointer_used = false;
+
+        for event in events {
+            let code = event.code();
+            let value = event.value();
+
+            match event.event_type() {
+                EventType::KEY if InputKey::from(code).is_mouse_button() => {
+                    let state = if value == 0 { ButtonState::Released } else { ButtonState::Pressed };
+                    self.pointer.button(time, code as u32, state);
+                    pointer_used = true;
+                }
+                EventType::KEY if value != 2 => {
+                    let pressed = value == 1;
+                    self.keyboard.key(time, code as u32, pressed as u32);
+                    if self.update_modifiers(code, pressed) {
+                        self.keyboard.modifiers(self.depressed, 0, self.locked, 0);
+                    }
+                }
+                EventType::RELATIVE => {
+                    pointer_used = true;
+                    match code {
+                        0 => dx += value as f64, // REL_X
+                        1 => dy += value as f64, // REL_Y
+                        6 | 8 => {
+                            // REL_HWHEEL, REL_WHEEL: wheel up is a negative scroll
+                            let (axis, discrete) = if code == 8 {
+                                (Axis::VerticalScroll, -value)
+                            } else {
+                                (Axis::HorizontalScroll, value)
+                            };
+                            self.pointer.axis_source(AxisSource::Wheel);
+                            self.pointer.axis_discrete(time, axis, discrete as f64 * SCROLL_STEP, discrete);
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if dx != 0.0 || dy != 0.0 {
+            self.pointer.motion(time, dx, dy);
+        }
+        if pointer_used {
+            self.pointer.frame();
+        }
+
+        self.connection.flush().map_err(io::Error::other)?;
+        self.queue.dispatch_pending(&mut State).map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> io::Result<()> {
+        let ScreenSize { width, height } = match self.screen_size {
+            Some(size) => size,
+            None => *self.screen_size.insert(screen::screen_size()?),
+        };
+
+        let time = self.start.elapsed().as_millis() as u32;
+        let x = x.clamp(0, width.saturating_sub(1) as i32) as u32;
+        let y = y.clamp(0, height.saturating_sub(1) as i32) as u32;
+        self.pointer.motion_absolute(time, x, y, width, height);
+        self.pointer.frame();
+
+        self.connection.flush().map_err(io::Error::other)?;
+        self.queue.dispatch_pending(&mut State).map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> io::Result<()> {
+        let chars: Vec<char> = text.chars().collect();
+        if let Some(c) = chars.iter().find(|&&c| c.is_control() && c != '\n' && c != '\t') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't type {:?}", c)));
+        }
+
+        // Shift and the like would change what the keys type
+        self.keyboard.modifiers(0, 0, 0, 0);
+        let typed = chars
+            .chunks(TEXT_KEYMAP_SIZE)
+            .try_for_each(|chunk| self.type_with_keymap(chunk));
+        upload_keymap(&self.keyboard, KEYMAP)?;
+        self.keyboard.modifiers(self.depressed, 0, self.locked, 0);
+        self.connection.flush().map_err(io::Error::other)?;
+        typed
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            keyboard: true,
+            pointer: true,
+            wheel: true,
+            absolute: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for WaylandBackend {
+    fn drop(&mut self) {
+        self.pointer.destroy();
+        let _ = self.connection.flush();
+    }
+}
+
+/// Hand `keymap` to the compositor, which reads it from a file descriptor
+fn upload_keymap(keyboard: &ZwpVirtualKeyboardV1, keymap: &str) -> io::Result<()> {
+    let mut keymap_file = tempfile()?;
+    keymap_file.write_all(keymap.as_bytes())?;
+    keymap_file.write_all(&[0])?;
+    keymap_file.flush()?;
+    keymap_file.rewind()?;
+    keyboard.keymap(KEYMAP_FORMAT_XKB_V1, keymap_file.as_fd(), keymap.len() as u32 + 1);
+    Ok(())
+}
+
+/// The distinct characters of `chars` to put in the keymap, and the key to send for each
+/// character typed. Key 1 is the first keycode of the keymap, 9.
+fn text_keys(chars: &[char]) -> (Vec<char>, Vec<u32>) {
+    let mut keys: Vec<char> = chars.to_vec();
+    keys.sort_unstable();
+    keys.dedup();
+    let typed = chars
+        .iter()
+        .map(|c| keys.binary_search(c).unwrap_or_default() as u32 + 1)
+        .collect();
+    (keys, typed)
+}
+
+/// A keymap with a key for each of `chars`, from keycode 9 on
+fn text_keymap(chars: &[char]) -> String {
+    let mut keycodes = String::new();
+    let mut symbols = String::new();
+    for (index, &c) in chars.iter().enumerate() {
+        let keysym = match c {
+            '\n' => "Return".to_string(),
+            '\t' => "Tab".to_string(),
+            c => format!("U{:04X}", c as u32),
+        };
+        keycodes.push_str(&format!("        <K{}> = {};\n", index, index + 9));
+        symbols.push_str(&format!("        key <K{}> {{ [ {} ] }};\n", index, keysym));
+    }
+    format!(
+        "xkb_keymap {{\n    xkb_keycodes \"evkey\" {{\n        minimum = 8;\n        maximum = {};\n{}    }};\n    \
+         xkb_types     {{ include \"complete\" }};\n    xkb_compat    {{ include \"complete\" }};\n    \
+         xkb_symbols \"evkey\" {{\n{}    }};\n}};\n",
+        chars.len() + 9,
+        keycodes,
+        symbols
+    )
+}
+
+/// Create an unlinked temporary file to pass the keymap through
+fn tempfile() -> io::Result<std::fs::File> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let path = dir.join(format!("evkey-keymap-{}", std::process::id()));
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_keys_share_repeated_chars() {
+        let (keys, typed) = text_keys(&['b', 'a', 'b', '\n']);
+        assert_eq!(keys, ['\n', 'a', 'b']);
+        assert_eq!(typed, [3, 2, 3, 1]);
+    }
+
+    #[test]
+    fn test_text_keymap() {
+        let keymap = text_keymap(&['\n', '\t', 'é', '😀']);
+        assert!(keymap.contains("maximum = 13;"));
+        assert!(keymap.contains("<K0> = 9;"));
+        assert!(keymap.contains("<K3> = 12;"));
+        assert!(keymap.contains("key <K0> { [ Return ] };"));
+        assert!(keymap.contains("key <K1> { [ Tab ] };"));
+        assert!(keymap.contains("key <K2> { [ U00E9 ] };"));
+        assert!(keymap.contains("key <K3> { [ U1F600 ] };"));
+    }
+}
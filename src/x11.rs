@@ -0,0 +1,161 @@
+//! X11 injection backend
+//!
+//! Uses the XTest extension to fake input through the X server, for X11
+//! users without access to `/dev/uinput`. Keys, mouse buttons, relative and
+//! absolute motion and wheels are supported.
+
+use crate::backend::Backend;
+use crate::capabilities::Capabilities;
+use crate::keymap;
+use crate::event::{EventType, InputEvent};
+use crate::input_key::InputKey;
+use std::io;
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::xproto::{
+    BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT, KEY_PRESS_EVENT, KEY_RELEASE_EVENT, MOTION_NOTIFY_EVENT,
+};
+use x11rb::protocol::xtest::{self, ConnectionExt};
+use x11rb::rust_connection::RustConnection;
+use x11rb::NONE;
+
+/// XTest motion details for moves to root window coordinates and relative
+/// to the current pointer position
+const MOTION_ABSOLUTE: u8 = 0;
+const MOTION_RELATIVE: u8 = 1;
+
+/// X core pointer buttons for wheel directions
+const WHEEL_UP: u8 = 4;
+const WHEEL_DOWN: u8 = 5;
+const WHEEL_LEFT: u8 = 6;
+const WHEEL_RIGHT: u8 = 7;
+
+/// Fake input on the X server named by `DISPLAY`
+pub struct X11Backend {
+    connection: RustConnection,
+}
+
+impl X11Backend {
+    /// Connect to the X server and check that it supports XTest
+    pub fn connect() -> io::Result<Self> {
+        let (connection, _) = x11rb::connect(None).map_err(io::Error::other)?;
+
+        let supported = connection
+            .extension_information(xtest::X11_EXTENSION_NAME)
+            .map_err(io::Error::other)?
+            .is_some();
+        if !supported {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "X server doesn't support the XTest extension",
+            ));
+        }
+
+        Ok(Self { connection })
+    }
+
+    fn fake(&self, kind: u8, detail: u8, x: i16, y: i16) -> io::Result<()> {
+        self.connection
+            .xtest_fake_input(kind, detail, x11rb::CURRENT_TIME, NONE, x, y, 0)
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn click(&self, button: u8, times: u32) -> io::Result<()> {
+        for _ in 0..times {
+            self.fake(BUTTON_PRESS_EVENT, button, 0, 0)?;
+            self.fake(BUTTON_RELEASE_EVENT, button, 0, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Map a Linux mouse button code to an X core pointer button
+fn x11_button(code: u16) -> Option<u8> {
+    match code {
+        0x110 => Some(1), // BTN_LEFT
+        0x112 => Some(2), // BTN_MIDDLE
+        0x111 => Some(3), // BTN_RIGHT
+        0x113 => Some(8), // BTN_SIDE
+        0x114 => Some(9), // BTN_EXTRA
+        _ => None,
+    }
+}
+
+fn unsupported(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("X11 backend can't emit {}", what))
+}
+
+impl Backend for X11Backend {
+    fn emit(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let (mut dx, mut dy) = (0i32, 0i32);
+
+        for event in events {
+            let code = event.code();
+            let value = event.value();
+
+            match event.event_type() {
+                // Autorepeat is generated by the X server itself
+                EventType::KEY if value == 2 => {}
+                EventType::KEY if !InputKey::from(code).is_keyboard() => {
+                    let button = x11_button(code).ok_or_else(|| unsupported(format!("button {:#x}", code)))?;
+                    let kind = if value == 1 { BUTTON_PRESS_EVENT } else { BUTTON_RELEASE_EVENT };
+                    self.fake(kind, button, 0, 0)?;
+                }
+                EventType::KEY => {
+                    let keycode = keymap::to_x11_keycode(code)
+                        .ok_or_else(|| unsupported(format!("key {}", code)))?;
+                    let kind = if value == 1 { KEY_PRESS_EVENT } else { KEY_RELEASE_EVENT };
+                    self.fake(kind, keycode, 0, 0)?;
+                }
+                EventType::RELATIVE => match code {
+                    0 => dx += value, // REL_X
+                    1 => dy += value, // REL_Y
+                    8 => {
+                        // REL_WHEEL: X has no scroll events, only button clicks
+                        let button = if value > 0 { WHEEL_UP } else { WHEEL_DOWN };
+                        self.click(button, value.unsigned_abs())?;
+                    }
+                    6 => {
+                        // REL_HWHEEL
+                        let button = if value > 0 { WHEEL_RIGHT } else { WHEEL_LEFT };
+                        self.click(button, value.unsigned_abs())?;
+                    }
+                    // REL_WHEEL_HI_RES, REL_HWHEEL_HI_RES: the notches sent along
+                    // with them are enough for clicks
+                    11 | 12 => {}
+                    _ => return Err(unsupported(format!("relative axis {}", code))),
+                },
+                EventType::SYNCHRONIZATION | EventType::MISC => {}
+                other => return Err(unsupported(format!("{:?} events", other))),
+            }
+        }
+
+        if dx != 0 || dy != 0 {
+            self.fake(MOTION_NOTIFY_EVENT, MOTION_RELATIVE, clamp(dx), clamp(dy))?;
+        }
+
+        self.connection.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn move_to(&mut self, x: i32, y: i32) -> io::Result<()> {
+        self.fake(MOTION_NOTIFY_EVENT, MOTION_ABSOLUTE, clamp(x), clamp(y))?;
+        self.connection.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            keyboard: true,
+            pointer: true,
+            wheel: true,
+            absolute: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Fit a coordinate into the protocol's 16 bits
+fn clamp(value: i32) -> i16 {
+    value.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}